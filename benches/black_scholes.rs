@@ -0,0 +1,65 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Black-Scholes Benchmarks
+//!
+//! Compares evaluating every strike of an expiry one at a time, as the
+//! heartbeat used to, against the batch evaluator.
+//!
+
+// The crate has no library target, but this module is self-contained.
+#[path = "../src/local_bs.rs"]
+#[allow(dead_code, unused_imports)]
+mod local_bs;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// A day with lots of listings: puts and calls at 300 strikes
+fn strikes() -> Vec<(bool, f64)> {
+    (0..300)
+        .flat_map(|n| {
+            let k = 10_000.0 + 250.0 * n as f64;
+            vec![(true, k), (false, k)]
+        })
+        .collect()
+}
+
+fn bench_expiry(c: &mut Criterion) {
+    let strikes = strikes();
+    let (s, r, t, vol) = (60_000.0, 0.04, 0.05, 0.8);
+
+    c.bench_function("per-option", |b| {
+        b.iter(|| {
+            for &(is_call, k) in &strikes {
+                if is_call {
+                    black_box(black_scholes::call(s, k, r, vol, t));
+                    black_box(local_bs::call_dual_delta(s, k, r, vol, t));
+                } else {
+                    black_box(black_scholes::put(s, k, r, vol, t));
+                    black_box(local_bs::put_dual_delta(s, k, r, vol, t));
+                }
+            }
+        })
+    });
+
+    c.bench_function("batch", |b| {
+        b.iter(|| {
+            let expiry = local_bs::Expiry::new(s, r, t);
+            black_box(expiry.evaluate_all(&strikes, vol))
+        })
+    });
+}
+
+criterion_group!(benches, bench_expiry);
+criterion_main!(benches);
@@ -0,0 +1,268 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Daily Activity
+//!
+//! Tracks what the main loop did over the course of a trading day: how long
+//! it was actively quoting, how many heartbeats it skipped and why, and how
+//! many orders it placed, had filled and cancelled. At market close this is
+//! summarized, sent as a notification, and appended to a CSV file so that we
+//! can look at trends over time.
+//!
+
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use std::path::Path;
+use std::{fmt, fs, io::Write as _};
+
+/// What the main loop decided to do on a heartbeat
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum HeartbeatDecision {
+    /// Market was open and we (re)opened our orders
+    Traded,
+    /// Market was closed, so we did nothing
+    MarketClosed,
+    /// Market was open but the kill switch was engaged
+    KillSwitch,
+    /// Market was open but the exchange was degraded or halted
+    ExchangeDegraded,
+    /// Market was open but our balances had not been synced recently
+    StaleBalances,
+    /// Market was open but we were outside of our trading windows
+    OutsideTradingWindow,
+    /// Market was open but we had hit our daily loss limit
+    LossLimit,
+}
+
+/// Header line of the daily activity CSV file
+const CSV_HEADER: &str = "Date,Hours Active,Heartbeats Traded,Heartbeats Market Closed,\
+                          Heartbeats Kill Switch,Orders Placed,Orders/Hour,Orders Filled,\
+                          Orders Cancelled,Premium Collected";
+
+/// Activity over a single trading day
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DailyActivity {
+    /// Time at which we started tracking this day
+    start: UtcTime,
+    /// If we are currently active, the time we became active
+    active_since: Option<UtcTime>,
+    /// Total time spent active, not including the current stretch
+    time_active: chrono::Duration,
+    heartbeats_traded: usize,
+    heartbeats_closed: usize,
+    heartbeats_killed: usize,
+    heartbeats_degraded: usize,
+    heartbeats_stale_balances: usize,
+    heartbeats_outside_window: usize,
+    heartbeats_loss_limit: usize,
+    orders_placed: usize,
+    orders_filled: usize,
+    orders_busted: usize,
+    orders_cancelled: usize,
+    premium: Price,
+}
+
+impl DailyActivity {
+    /// Starts tracking a new day
+    pub fn new(now: UtcTime) -> Self {
+        DailyActivity {
+            start: now,
+            active_since: None,
+            time_active: chrono::Duration::zero(),
+            heartbeats_traded: 0,
+            heartbeats_closed: 0,
+            heartbeats_killed: 0,
+            heartbeats_degraded: 0,
+            heartbeats_stale_balances: 0,
+            heartbeats_outside_window: 0,
+            heartbeats_loss_limit: 0,
+            orders_placed: 0,
+            orders_filled: 0,
+            orders_busted: 0,
+            orders_cancelled: 0,
+            premium: Price::ZERO,
+        }
+    }
+
+    /// Records whether we are currently active (market open, kill switch off,
+    /// exchange not degraded and loss limit not hit)
+    pub fn set_active(&mut self, active: bool, now: UtcTime) {
+        match (self.active_since, active) {
+            (None, true) => self.active_since = Some(now),
+            (Some(since), false) => {
+                self.time_active = self.time_active + (now - since);
+                self.active_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Total time spent active as of `now`
+    pub fn time_active(&self, now: UtcTime) -> chrono::Duration {
+        match self.active_since {
+            Some(since) => self.time_active + (now - since),
+            None => self.time_active,
+        }
+    }
+
+    /// Records the outcome of a heartbeat
+    pub fn record_heartbeat(&mut self, decision: HeartbeatDecision) {
+        match decision {
+            HeartbeatDecision::Traded => self.heartbeats_traded += 1,
+            HeartbeatDecision::MarketClosed => self.heartbeats_closed += 1,
+            HeartbeatDecision::KillSwitch => self.heartbeats_killed += 1,
+            HeartbeatDecision::ExchangeDegraded => self.heartbeats_degraded += 1,
+            HeartbeatDecision::StaleBalances => self.heartbeats_stale_balances += 1,
+            HeartbeatDecision::OutsideTradingWindow => self.heartbeats_outside_window += 1,
+            HeartbeatDecision::LossLimit => self.heartbeats_loss_limit += 1,
+        }
+    }
+
+    /// Records that we placed an order
+    pub fn record_order_placed(&mut self) {
+        self.orders_placed += 1;
+    }
+
+    /// Records that one of our orders was filled, for the given net premium
+    pub fn record_fill(&mut self, premium: Price) {
+        self.orders_filled += 1;
+        self.premium += premium;
+    }
+
+    /// Records that the exchange busted one of our fills, reversing the given
+    /// net premium
+    ///
+    /// The fill itself still counts towards the number of orders filled.
+    pub fn record_bust(&mut self, premium: Price) {
+        self.orders_busted += 1;
+        self.premium -= premium;
+    }
+
+    /// Records that we cancelled some number of open orders
+    pub fn record_cancellations(&mut self, n: usize) {
+        self.orders_cancelled += n;
+    }
+
+    /// Number of orders placed per hour of active time
+    pub fn orders_per_hour(&self, now: UtcTime) -> f64 {
+        let hours = self.time_active(now).num_seconds() as f64 / 3600.0;
+        if hours > 0.0 {
+            self.orders_placed as f64 / hours
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns a displayable summary of the day as of `now`
+    pub fn summary(&self, now: UtcTime) -> Summary<'_> {
+        Summary {
+            activity: self,
+            now,
+        }
+    }
+
+    /// Appends a line describing the day to the given CSV file, creating it
+    /// (with a header) if it does not exist
+    pub fn append_to_csv(&self, path: &Path, now: UtcTime) -> anyhow::Result<()> {
+        let exists = path.exists();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening activity file {}", path.display()))?;
+        if !exists {
+            writeln!(file, "{CSV_HEADER}")
+                .with_context(|| format!("writing header to {}", path.display()))?;
+        }
+        writeln!(
+            file,
+            "{},{:.2},{},{},{},{},{:.2},{},{},{}",
+            self.start.format("%F"),
+            self.time_active(now).num_seconds() as f64 / 3600.0,
+            self.heartbeats_traded,
+            self.heartbeats_closed,
+            self.heartbeats_killed,
+            self.orders_placed,
+            self.orders_per_hour(now),
+            self.orders_filled,
+            self.orders_cancelled,
+            self.premium,
+        )
+        .with_context(|| format!("writing to {}", path.display()))
+    }
+}
+
+/// Human-readable summary of a day's activity
+pub struct Summary<'a> {
+    activity: &'a DailyActivity,
+    now: UtcTime,
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let act = self.activity;
+        let active = act.time_active(self.now);
+        writeln!(f, "Daily summary for {}", act.start.format("%F"))?;
+        writeln!(
+            f,
+            "Active {}h{:02}m ({} heartbeats; skipped {} market closed, {} kill switch, \
+             {} exchange degraded, {} stale balances, {} outside trading window, \
+             {} loss limit)",
+            active.num_hours(),
+            active.num_minutes() % 60,
+            act.heartbeats_traded,
+            act.heartbeats_closed,
+            act.heartbeats_killed,
+            act.heartbeats_degraded,
+            act.heartbeats_stale_balances,
+            act.heartbeats_outside_window,
+            act.heartbeats_loss_limit,
+        )?;
+        writeln!(
+            f,
+            "Orders: {} placed ({:.1}/hour), {} filled, {} cancelled",
+            act.orders_placed,
+            act.orders_per_hour(self.now),
+            act.orders_filled,
+            act.orders_cancelled,
+        )?;
+        if act.orders_busted > 0 {
+            writeln!(f, "Fills busted by the exchange: {}", act.orders_busted)?;
+        }
+        write!(f, "Premium collected: {}", act.premium)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_active() {
+        let start = UtcTime::now();
+        let hour = chrono::Duration::hours(1);
+        let mut act = DailyActivity::new(start);
+        act.set_active(true, start + hour);
+        act.set_active(true, start + hour * 2);
+        assert_eq!(act.time_active(start + hour * 3), hour * 2);
+        act.set_active(false, start + hour * 3);
+        act.set_active(false, start + hour * 4);
+        assert_eq!(act.time_active(start + hour * 5), hour * 2);
+
+        for _ in 0..4 {
+            act.record_order_placed();
+        }
+        assert_eq!(act.orders_per_hour(start + hour * 5), 2.0);
+    }
+}
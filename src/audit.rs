@@ -0,0 +1,396 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Audit Log
+//!
+//! Every action we take which changes something -- submitting or cancelling
+//! orders, loading a configuration, flipping the kill switch, shutting down
+//! in an emergency -- is appended to the audit log, one JSON entry per line.
+//! Unlike the debug logs, which are noisy and rotated away, the audit log is
+//! only ever appended to.
+//!
+//! Each entry is numbered and commits to the hash of the entry before it, so
+//! that editing, inserting, reordering or removing any entry breaks the chain,
+//! which the `audit verify` command checks. This catches accidents and careless
+//! edits, but is not real tamper evidence: anyone who can write the file can
+//! also rehash every entry after the one they changed, or simply drop entries
+//! from the end. The chain only proves something when its head hash, as
+//! reported by `audit verify`, is compared against one noted somewhere else.
+//!
+//! A crash in the middle of a write can leave a truncated final line. This is
+//! reported rather than treated as a broken chain, and the next time the log
+//! is opened for writing the line is dropped, with an entry saying so.
+//!
+//! Like the event stream, the log is global, so that actions can be recorded
+//! wherever they are taken. Until [`open`] is called, recording does nothing.
+//!
+
+use crate::units::UtcTime;
+use anyhow::Context;
+use bitcoin::hashes::{sha256, Hash};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::{fmt, fs};
+
+/// The log, if one has been opened
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+/// A kind of mutating action
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// An order was submitted to LX
+    SubmitOrder,
+    /// A single order was cancelled
+    CancelOrder,
+    /// All of our orders were cancelled
+    CancelAllOrders,
+    /// A configuration file was loaded
+    LoadConfig,
+    /// The kill switch was engaged or released
+    KillSwitch,
+    /// The program shut down in an emergency
+    EmergencyShutdown,
+    /// A truncated final line was dropped from the log
+    RepairLog,
+}
+
+/// The hashed contents of an entry
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Body {
+    /// Position of the entry in the log, starting from 0
+    seq: u64,
+    /// Time of the action, in RFC 3339 format
+    time: String,
+    /// The part of the program which took the action
+    component: String,
+    /// What was done
+    action: Action,
+    /// Free-form details, including the outcome of the action
+    details: String,
+    /// Hash of the previous entry, or all zeros for the first one
+    prev: sha256::Hash,
+}
+
+/// An entry of the audit log
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    body: Body,
+    /// Hash of the JSON serialization of the body
+    hash: sha256::Hash,
+}
+
+impl Entry {
+    /// Creates a new entry, chained onto the one with the given number and hash
+    fn new(
+        seq: u64,
+        prev: sha256::Hash,
+        time: UtcTime,
+        component: &str,
+        action: Action,
+        details: &str,
+    ) -> Self {
+        let body = Body {
+            seq,
+            time: time.format("%FT%T%.3fZ").to_string(),
+            component: component.to_owned(),
+            action,
+            details: details.to_owned(),
+            prev,
+        };
+        Entry {
+            hash: body.hash(),
+            body,
+        }
+    }
+}
+
+impl Body {
+    fn hash(&self) -> sha256::Hash {
+        // unwrap ok since the body is just strings and numbers
+        sha256::Hash::hash(serde_json::to_string(self).unwrap().as_bytes())
+    }
+}
+
+/// An open audit log
+struct Log {
+    file: fs::File,
+    next_seq: u64,
+    head: sha256::Hash,
+}
+
+/// The result of checking an audit log
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Summary {
+    /// Number of entries
+    pub entries: u64,
+    /// Hash of the last entry, or all zeros if there are none
+    pub head: sha256::Hash,
+    /// Time of the last entry, if there are any
+    pub last_time: Option<String>,
+    /// A truncated final line following the entries, if any
+    pub torn_tail: Option<String>,
+    /// Length in bytes of the entries, up to any truncated final line
+    len: u64,
+    /// Whether the entries end with a newline, so that a new one can follow
+    ends_with_newline: bool,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.last_time {
+            Some(ref time) => write!(
+                f,
+                "{} entries, chain intact; last entry at {}, head {}",
+                self.entries, time, self.head,
+            )?,
+            None => f.write_str("no entries")?,
+        }
+        if let Some(ref tail) = self.torn_tail {
+            write!(f, "; truncated final line ignored: {tail}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the hash chain of an audit log read from `reader`
+///
+/// Lines are read as bytes, since a truncated final line may end partway
+/// through a character.
+fn verify_reader<R: BufRead>(mut reader: R) -> anyhow::Result<Summary> {
+    let mut ret = Summary {
+        entries: 0,
+        head: sha256::Hash::all_zeros(),
+        last_time: None,
+        torn_tail: None,
+        len: 0,
+        ends_with_newline: true,
+    };
+    let mut line = vec![];
+    for n in 0.. {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .context("reading audit log")?;
+        if read == 0 {
+            break;
+        }
+        let newline = line.last() == Some(&b'\n');
+        if line.iter().all(u8::is_ascii_whitespace) {
+            ret.len += read as u64;
+            ret.ends_with_newline = newline;
+            continue;
+        }
+        let entry: Entry = match serde_json::from_slice(&line) {
+            Ok(entry) => entry,
+            // Every entry is written with its newline, so a line without one
+            // can only be the last, left behind by an interrupted write
+            Err(_) if !newline => {
+                ret.torn_tail = Some(String::from_utf8_lossy(&line).into_owned());
+                break;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("parsing audit log entry on line {}", n + 1))
+            }
+        };
+        if entry.body.seq != ret.entries {
+            return Err(anyhow::Error::msg(format!(
+                "entry on line {} is number {}, expected {}",
+                n + 1,
+                entry.body.seq,
+                ret.entries,
+            )));
+        }
+        if entry.body.prev != ret.head {
+            return Err(anyhow::Error::msg(format!(
+                "entry {} follows {}, but the previous entry has hash {}",
+                entry.body.seq, entry.body.prev, ret.head,
+            )));
+        }
+        let hash = entry.body.hash();
+        if entry.hash != hash {
+            return Err(anyhow::Error::msg(format!(
+                "entry {} has hash {} but its contents hash to {}",
+                entry.body.seq, entry.hash, hash,
+            )));
+        }
+        ret.entries += 1;
+        ret.head = hash;
+        ret.last_time = Some(entry.body.time);
+        ret.len += read as u64;
+        ret.ends_with_newline = newline;
+    }
+    Ok(ret)
+}
+
+/// Checks the hash chain of the audit log at the given path
+pub fn verify(path: &Path) -> anyhow::Result<Summary> {
+    let file =
+        fs::File::open(path).with_context(|| format!("opening audit log {}", path.display()))?;
+    verify_reader(io::BufReader::new(file))
+        .with_context(|| format!("verifying audit log {}", path.display()))
+}
+
+/// Opens the audit log, creating it if it does not exist
+///
+/// An existing log is verified first, and not appended to if its chain is
+/// broken, since anything we appended would only obscure that. A truncated
+/// final line is dropped, and an entry recording it is appended.
+pub fn open(path: &Path) -> anyhow::Result<()> {
+    let summary = if path.exists() {
+        verify(path)?
+    } else {
+        verify_reader(io::empty())?
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening audit log {}", path.display()))?;
+    if let Some(ref tail) = summary.torn_tail {
+        warn!(
+            "Audit log {} has a truncated final line, which will be dropped: {}",
+            path.display(),
+            tail
+        );
+        file.set_len(summary.len)
+            .with_context(|| format!("truncating audit log {}", path.display()))?;
+    }
+    if !summary.ends_with_newline {
+        writeln!(file).with_context(|| format!("writing to audit log {}", path.display()))?;
+    }
+    *LOG.lock().unwrap() = Some(Log {
+        file,
+        next_seq: summary.entries,
+        head: summary.head,
+    });
+    if let Some(tail) = summary.torn_tail {
+        record(
+            "audit",
+            Action::RepairLog,
+            &format!("dropped truncated final line: {tail}"),
+        );
+    }
+    Ok(())
+}
+
+/// Appends an entry to the audit log, if it is open
+///
+/// Failures are logged but otherwise ignored: we would rather keep trading
+/// than stop because of a full disk, and every action is in the debug log too.
+pub fn record(component: &str, action: Action, details: &str) {
+    if let Some(ref mut log) = *LOG.lock().unwrap() {
+        let entry = Entry::new(
+            log.next_seq,
+            log.head,
+            UtcTime::now(),
+            component,
+            action,
+            details,
+        );
+        // unwrap ok since the entry is just strings and numbers
+        let line = serde_json::to_string(&entry).unwrap();
+        match writeln!(log.file, "{line}").and_then(|_| log.file.flush()) {
+            Ok(()) => {
+                log.next_seq += 1;
+                log.head = entry.hash;
+            }
+            Err(e) => warn!("Failed to write audit log entry {}: {}", line, e),
+        }
+    }
+}
+
+/// Describes the outcome of an action, for the details of an entry
+pub fn outcome<T>(result: &anyhow::Result<T>) -> String {
+    match *result {
+        Ok(_) => "ok".into(),
+        Err(ref e) => format!("failed: {e:#}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_chain() {
+        let time = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        let mut entries = vec![];
+        let mut head = sha256::Hash::all_zeros();
+        for (n, (action, details)) in [
+            (Action::LoadConfig, "config hash 00ff"),
+            (Action::SubmitOrder, "ask 5 @ $1250: ok"),
+            (Action::KillSwitch, "engaged (file exists)"),
+            (Action::CancelAllOrders, "1 attempts: ok"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let entry = Entry::new(n as u64, head, time, "connect", *action, details);
+            head = entry.hash;
+            entries.push(serde_json::to_string(&entry).unwrap());
+        }
+        assert!(entries[1].contains(r#""action":"submit-order""#));
+
+        let log = entries.join("\n");
+        let summary = verify_reader(log.as_bytes()).unwrap();
+        assert_eq!(summary.entries, 4);
+        assert_eq!(summary.head, head);
+        assert_eq!(
+            summary.last_time.as_deref(),
+            Some("2024-03-01T15:00:00.000Z")
+        );
+        assert_eq!(
+            verify_reader(io::empty()).unwrap().to_string(),
+            "no entries"
+        );
+
+        // Editing, removing or reordering entries all break the chain
+        let edited = log.replace("ask 5", "ask 50");
+        assert!(verify_reader(edited.as_bytes()).is_err());
+        let mut removed = entries.clone();
+        removed.remove(1);
+        assert!(verify_reader(removed.join("\n").as_bytes()).is_err());
+        let mut swapped = entries.clone();
+        swapped.swap(1, 2);
+        assert!(verify_reader(swapped.join("\n").as_bytes()).is_err());
+        // ...as does rehashing an edited entry, since the next one commits to it
+        let mut rehashed: Vec<Entry> = entries
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        rehashed[1].body.details = "ask 50 @ $1250: ok".into();
+        rehashed[1].hash = rehashed[1].body.hash();
+        let rehashed: Vec<String> = rehashed
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect();
+        assert!(verify_reader(rehashed.join("\n").as_bytes()).is_err());
+
+        // A truncated final line is reported, but does not break the chain...
+        let torn = format!("{}\n{}", log, &entries[0][..20]);
+        let summary = verify_reader(torn.as_bytes()).unwrap();
+        assert_eq!(summary.entries, 4);
+        assert_eq!(summary.head, head);
+        assert_eq!(summary.torn_tail.as_deref(), Some(&entries[0][..20]));
+        assert_eq!(summary.len, log.len() as u64 + 1);
+        // ...unless it is followed by anything else
+        let torn = format!("{}\n{}\n{}", log, &entries[0][..20], entries[3]);
+        assert!(verify_reader(torn.as_bytes()).is_err());
+    }
+}
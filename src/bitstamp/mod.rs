@@ -0,0 +1,138 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Bitstamp
+//!
+//! Data Structures etc for the Bitstamp Websockets API, which gives us a
+//! third price reference alongside Coinbase and Kraken.
+//!
+
+use crate::price::BitcoinPrice;
+use crate::queue::Sender;
+use crate::supervisor::Heartbeat;
+use crate::units::UtcTime;
+use crate::websocket::{self, Socket};
+use log::{info, warn};
+use serde::Deserialize;
+use std::ops::ControlFlow;
+use std::thread;
+
+/// Name of the Bitstamp trade feed as a price source
+pub const PRICE_SOURCE: &str = "Bitstamp";
+
+#[derive(Deserialize, Debug)]
+struct Trade {
+    #[serde(deserialize_with = "crate::units::deserialize_dollars")]
+    price_str: crate::units::Price,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "event")]
+enum BitstampMsg {
+    #[serde(rename = "trade")]
+    Trade { channel: String, data: Trade },
+    #[serde(rename = "bts:subscription_succeeded")]
+    Subscribed { channel: String },
+    #[serde(rename = "bts:request_reconnect")]
+    RequestReconnect,
+    #[serde(rename = "bts:error")]
+    Error { data: serde_json::Value },
+    #[serde(other)]
+    Other,
+}
+//{"data":{"id":325143851,"timestamp":"1709305200","amount":0.0123,"amount_str":"0.01230000","price":63000,"price_str":"63000","type":0,"microtimestamp":"1709305200123456","buy_order_id":1,"sell_order_id":2},"channel":"live_trades_btcusd","event":"trade"}
+
+/// Connects to Bitstamp and subscribes to the BTC/USD trade channel
+fn connect() -> anyhow::Result<Socket> {
+    let (mut sock, _) = tungstenite::client::connect("wss://ws.bitstamp.net")?;
+    sock.write_message(tungstenite::protocol::Message::Text(
+        "{\"event\":\"bts:subscribe\",\"data\":{\"channel\":\"live_trades_btcusd\"}}".to_string(),
+    ))?;
+    Ok(sock)
+}
+
+/// Starts a thread which feeds Bitstamp's BTC/USD trades into the main loop
+/// as price references
+///
+/// The thread exits once its heartbeat is retired. Bitstamp has no ticker
+/// channel, so we use the price of each trade, stamped with the time it is
+/// received.
+///
+/// As with Kraken, if Bitstamp cannot be reached we keep retrying with
+/// backoff (see [`websocket::run_reconnecting`]).
+pub fn spawn_trade_thread(
+    tx: Sender<crate::connect::Message>,
+    heartbeat: Heartbeat,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        websocket::run_reconnecting("Bitstamp", &heartbeat, connect, |msg| {
+            info!(target: "bs_datafeed", "{}", msg);
+            match serde_json::from_str(msg) {
+                Ok(BitstampMsg::Trade { channel, data }) if channel == "live_trades_btcusd" => {
+                    let new_price = BitcoinPrice {
+                        btc_price: data.price_str,
+                        timestamp: UtcTime::now(),
+                    };
+                    tx.send(crate::connect::Message::PriceReference(
+                        PRICE_SOURCE,
+                        new_price,
+                    ))
+                    .unwrap();
+                }
+                Ok(BitstampMsg::Subscribed { channel }) => {
+                    info!("Subscribed to Bitstamp channel {}", channel);
+                }
+                // Bitstamp asks us to reconnect before maintenance
+                Ok(BitstampMsg::RequestReconnect) => return ControlFlow::Break(()),
+                Ok(BitstampMsg::Error { data }) => warn!("Bitstamp reported error: {}", data),
+                Ok(BitstampMsg::Trade { .. }) | Ok(BitstampMsg::Other) => {}
+                Err(e) => warn!("Failed to parse Bitstamp message {}: {}", msg, e),
+            }
+            ControlFlow::Continue(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_messages() {
+        let trade = r#"{"data":{"id":325143851,"timestamp":"1709305200","amount":0.0123,"amount_str":"0.01230000","price":63000.5,"price_str":"63000.5","type":0,"microtimestamp":"1709305200123456","buy_order_id":1,"sell_order_id":2},"channel":"live_trades_btcusd","event":"trade"}"#;
+        match serde_json::from_str(trade).unwrap() {
+            BitstampMsg::Trade { channel, data } => {
+                assert_eq!(channel, "live_trades_btcusd");
+                assert_eq!(
+                    data.price_str,
+                    crate::units::Price::from_str("63000.5").unwrap()
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        let subscribed =
+            r#"{"event":"bts:subscription_succeeded","channel":"live_trades_btcusd","data":{}}"#;
+        assert!(matches!(
+            serde_json::from_str(subscribed).unwrap(),
+            BitstampMsg::Subscribed { .. }
+        ));
+        let reconnect = r#"{"event":"bts:request_reconnect","channel":"","data":""}"#;
+        assert!(matches!(
+            serde_json::from_str(reconnect).unwrap(),
+            BitstampMsg::RequestReconnect
+        ));
+    }
+}
@@ -0,0 +1,374 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Black-Scholes Cache
+//!
+//! Every heartbeat we reprice the whole board by inverting Black-Scholes:
+//! bisecting for the price at a target ARR or loss80, and root-finding for
+//! the IV of a given price, and the IV of every order on the book is
+//! recomputed each time the book changes. This module memoizes those
+//! inversions, keyed on the option and target along with the time to expiry
+//! and BTC price rounded into buckets.
+//!
+//! The buckets are relative: 0.5% of the time left to expiry and 0.02% of
+//! the BTC price. Each moves the results by well under the 1% tolerance the
+//! bisections already stop at, whatever the expiry or price. A far-dated
+//! option keeps its time bucket for hours, so its entries are reused across
+//! heartbeats as long as the BTC price has not moved out of its bucket; in
+//! practice most hits come from repeated lookups between heartbeats.
+//!
+//! Entries inserted more than [`MAX_AGE_MINUTES`] ago, longer than the
+//! interval between clock heartbeats, are dropped, and the cache is cleared
+//! outright if it ever grows past [`MAX_ENTRIES`].
+//!
+
+use crate::option;
+use crate::units::{Price, UtcTime};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Width of the time-to-expiry buckets, as a fraction of the time left
+const TIME_BUCKET_FRACTION: f64 = 0.005;
+/// Width of the BTC price buckets, as a fraction of the price
+const PRICE_BUCKET_FRACTION: f64 = 0.0002;
+/// Number of minutes after which an entry is considered stale
+pub const MAX_AGE_MINUTES: i64 = 3 * 60;
+/// Maximum number of entries in the cache
+pub const MAX_ENTRIES: usize = 50_000;
+
+/// The cache used by the free functions in this module, created on first use
+static GLOBAL: Mutex<Option<Cache>> = Mutex::new(None);
+
+/// Runs a function on the global cache, creating it if necessary
+fn with_global<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    f(GLOBAL.lock().unwrap().get_or_insert_with(Cache::new))
+}
+
+/// The quantity being solved for
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum Target {
+    /// IV at a given price
+    Iv(Price),
+    /// Price at a given ARR, as the bits of an `f64`
+    Arr(u64),
+    /// Price at a given loss80, as the bits of an `f64`
+    Loss80(u64),
+}
+
+/// Key of a cache entry
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+struct Key {
+    option: option::Option,
+    target: Target,
+    time_bucket: i64,
+    price_bucket: i64,
+}
+
+impl Key {
+    fn new(option: &option::Option, target: Target, now: UtcTime, btc_price: Price) -> Self {
+        Key {
+            option: *option,
+            target,
+            time_bucket: log_bucket(
+                (option.expiry - now).num_seconds() as f64,
+                TIME_BUCKET_FRACTION,
+            ),
+            price_bucket: log_bucket(btc_price.to_approx_f64(), PRICE_BUCKET_FRACTION),
+        }
+    }
+}
+
+/// Rounds a positive quantity into buckets each `fraction` wider than the last
+///
+/// Quantities below one (e.g. an expired option) all share a bucket.
+fn log_bucket(x: f64, fraction: f64) -> i64 {
+    (x.max(1.0).ln() / fraction.ln_1p()).floor() as i64
+}
+
+/// A cached result
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Value {
+    Iv(Result<f64, f64>),
+    Price(Option<Price>),
+}
+
+/// Hit and miss counts of a cache
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    /// Number of lookups answered from the cache
+    pub hits: u64,
+    /// Number of lookups which had to be computed
+    pub misses: u64,
+    /// Number of entries currently cached
+    pub entries: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.hits + self.misses;
+        let rate = if total == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / total as f64
+        };
+        write!(
+            f,
+            "{} entries, {:.1}% hit rate ({} hits, {} misses)",
+            self.entries, rate, self.hits, self.misses
+        )
+    }
+}
+
+/// A memoization cache for Black-Scholes inversions
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    /// Each result, along with the minute in which it was inserted
+    entries: HashMap<Key, (Value, i64)>,
+    /// The latest minute in which anything was inserted
+    latest: i64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    /// Constructs a new empty cache
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Looks up an entry, computing and inserting it if it is absent
+    fn get_or_insert_with<F: FnOnce() -> Value>(&mut self, key: Key, now: UtcTime, f: F) -> Value {
+        if let Some((value, _)) = self.entries.get(&key) {
+            self.hits += 1;
+            return *value;
+        }
+        self.misses += 1;
+
+        let minute = now.to_unix_i64().div_euclid(60);
+        if minute > self.latest {
+            self.latest = minute;
+            let cutoff = minute - MAX_AGE_MINUTES;
+            self.entries.retain(|_, (_, inserted)| *inserted >= cutoff);
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.clear();
+        }
+        let value = f();
+        self.entries.insert(key, (value, minute));
+        value
+    }
+
+    /// Cached version of [`option::Option::bs_iv`]
+    pub fn iv(
+        &mut self,
+        option: &option::Option,
+        now: UtcTime,
+        btc_price: Price,
+        price: Price,
+    ) -> Result<f64, f64> {
+        let key = Key::new(option, Target::Iv(price), now, btc_price);
+        match self.get_or_insert_with(key, now, || Value::Iv(option.bs_iv(now, btc_price, price))) {
+            Value::Iv(iv) => iv,
+            Value::Price(_) => unreachable!("IV target cached a price"),
+        }
+    }
+
+    /// Cached version of [`option::Option::bs_arr_price`]
+    pub fn arr_price(
+        &mut self,
+        option: &option::Option,
+        now: UtcTime,
+        btc_price: Price,
+        arr: f64,
+    ) -> Option<Price> {
+        let key = Key::new(option, Target::Arr(arr.to_bits()), now, btc_price);
+        match self.get_or_insert_with(key, now, || {
+            Value::Price(option.bs_arr_price(now, btc_price, arr))
+        }) {
+            Value::Price(price) => price,
+            Value::Iv(_) => unreachable!("ARR target cached an IV"),
+        }
+    }
+
+    /// Cached version of [`option::Option::bs_loss80_price`]
+    pub fn loss80_price(
+        &mut self,
+        option: &option::Option,
+        now: UtcTime,
+        btc_price: Price,
+        loss80: f64,
+    ) -> Option<Price> {
+        let key = Key::new(option, Target::Loss80(loss80.to_bits()), now, btc_price);
+        match self.get_or_insert_with(key, now, || {
+            Value::Price(option.bs_loss80_price(now, btc_price, loss80))
+        }) {
+            Value::Price(price) => price,
+            Value::Iv(_) => unreachable!("loss80 target cached an IV"),
+        }
+    }
+
+    /// Hit and miss counts since the cache was created
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+/// Computes the IV of an option at a given price, using the global cache
+pub fn iv(
+    option: &option::Option,
+    now: UtcTime,
+    btc_price: Price,
+    price: Price,
+) -> Result<f64, f64> {
+    with_global(|cache| cache.iv(option, now, btc_price, price))
+}
+
+/// Computes the price of an option at a given ARR, using the global cache
+pub fn arr_price(
+    option: &option::Option,
+    now: UtcTime,
+    btc_price: Price,
+    arr: f64,
+) -> Option<Price> {
+    with_global(|cache| cache.arr_price(option, now, btc_price, arr))
+}
+
+/// Computes the price of an option at a given loss80, using the global cache
+pub fn loss80_price(
+    option: &option::Option,
+    now: UtcTime,
+    btc_price: Price,
+    loss80: f64,
+) -> Option<Price> {
+    with_global(|cache| cache.loss80_price(option, now, btc_price, loss80))
+}
+
+/// Hit and miss counts of the global cache
+pub fn stats() -> Stats {
+    with_global(|cache| cache.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn within_tolerance() {
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let now = UtcTime::parse_coinbase("2030-06-03T15:00:00Z").unwrap();
+        let options: Vec<option::Option> = [
+            "2030-06-07C64000",
+            "2030-06-07P56000",
+            "2030-06-28C70000",
+            "2030-06-28P50000",
+            "2030-12-27P40000",
+        ]
+        .iter()
+        .map(|s| option::Option::from_str(s).unwrap())
+        .collect();
+
+        let mut cache = Cache::new();
+        let btc = p("60000");
+        for opt in &options {
+            cache.iv(opt, now, btc, p("1500")).ok();
+            cache.arr_price(opt, now, btc, 0.08);
+            cache.loss80_price(opt, now, btc, 0.05);
+        }
+        assert_eq!(cache.stats().misses, 15);
+
+        // Slightly later and slightly higher, but in the same buckets
+        let later = now + chrono::Duration::seconds(45);
+        let higher = p("60004.99");
+        let close = |a: f64, b: f64| (a - b).abs() / b < 0.01;
+        for opt in &options {
+            let fresh = opt.bs_iv(later, higher, p("1500"));
+            let cached = cache.iv(opt, later, higher, p("1500"));
+            match (fresh, cached) {
+                (Ok(a), Ok(b)) => assert!(close(b, a), "{}: IV {} vs fresh {}", opt, b, a),
+                (Err(_), Err(_)) => {}
+                _ => panic!("{}: fresh IV {:?} but cached {:?}", opt, fresh, cached),
+            }
+
+            let fresh = opt.bs_arr_price(later, higher, 0.08);
+            let cached = cache.arr_price(opt, later, higher, 0.08);
+            let (a, b) = (fresh.unwrap(), cached.unwrap());
+            assert!(
+                close(b.to_approx_f64(), a.to_approx_f64()),
+                "{}: {} vs {}",
+                opt,
+                b,
+                a
+            );
+
+            let fresh = opt.bs_loss80_price(later, higher, 0.05);
+            let cached = cache.loss80_price(opt, later, higher, 0.05);
+            let (a, b) = (fresh.unwrap(), cached.unwrap());
+            assert!(
+                close(b.to_approx_f64(), a.to_approx_f64()),
+                "{}: {} vs {}",
+                opt,
+                b,
+                a
+            );
+        }
+        assert_eq!(cache.stats().hits, 15);
+        assert_eq!(cache.stats().misses, 15);
+
+        // Crossing a bucket boundary is a miss
+        cache.iv(&options[0], now, p("60020"), p("1500")).ok();
+        assert_eq!(cache.stats().misses, 16);
+        let much_later = now + chrono::Duration::hours(1);
+        cache.iv(&options[0], much_later, btc, p("1500")).ok();
+        assert_eq!(cache.stats().misses, 17);
+
+        // ...but an hour is within the bucket of a six-month option
+        cache.iv(&options[4], much_later, btc, p("1500")).ok();
+        assert_eq!(cache.stats().hits, 16);
+    }
+
+    #[test]
+    fn eviction() {
+        let now = UtcTime::parse_coinbase("2030-06-03T15:00:00Z").unwrap();
+        let opt = option::Option::from_str("2030-06-28P50000").unwrap();
+        let btc = Price::from_str("60000").unwrap();
+
+        let mut cache = Cache::new();
+        for i in 0..5 {
+            cache
+                .iv(&opt, now, btc, Price::from_cents(100_000 + i))
+                .ok();
+        }
+        assert_eq!(cache.stats().entries, 5);
+
+        // Entries survive a few minutes, but not MAX_AGE_MINUTES
+        let soon = now + chrono::Duration::minutes(MAX_AGE_MINUTES);
+        cache.iv(&opt, soon, btc, Price::ONE_THOUSAND).ok();
+        assert_eq!(cache.stats().entries, 6);
+        let later = now + chrono::Duration::minutes(MAX_AGE_MINUTES + 1);
+        cache.iv(&opt, later, btc, Price::from_cents(200_000)).ok();
+        assert_eq!(cache.stats().entries, 2);
+
+        // The cache is bounded
+        for i in 0..MAX_ENTRIES as i64 {
+            cache.iv(&opt, later, btc, Price::from_cents(i)).ok();
+        }
+        assert!(cache.stats().entries <= MAX_ENTRIES);
+    }
+}
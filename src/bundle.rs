@@ -0,0 +1,322 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Output Bundles
+//!
+//! The tax output directory contains our complete financial history, as well
+//! as the debug and HTTP logs of the run that produced it. This module strips
+//! secrets from the logs as they are copied in, and packs the finished
+//! directory into a compressed archive encrypted to an age or GPG key, which
+//! is safe to store in cloud backups.
+//!
+//! Creating the archive requires the `bundle` feature; without it, only the
+//! redaction is available.
+//!
+
+use anyhow::Context;
+use log::info;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+#[cfg(feature = "bundle")]
+use std::path::Path;
+#[cfg(feature = "bundle")]
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+/// Key to encrypt an output bundle to
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Recipient {
+    /// An age X25519 public key, e.g. `age1...`
+    Age(String),
+    /// A GPG key ID, fingerprint or email, given as `gpg:<key>`
+    Gpg(String),
+}
+
+impl FromStr for Recipient {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(key) = s.strip_prefix("gpg:") {
+            if key.is_empty() {
+                return Err("empty GPG key ID".to_owned());
+            }
+            Ok(Recipient::Gpg(key.to_owned()))
+        } else {
+            // Without age support the key is never used, so we don't check it
+            #[cfg(feature = "bundle")]
+            age::x25519::Recipient::from_str(s)
+                .map_err(|e| format!("parsing age recipient {s}: {e}"))?;
+            Ok(Recipient::Age(s.to_owned()))
+        }
+    }
+}
+
+impl TryFrom<String> for Recipient {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Recipient::from_str(&s)
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Recipient::Age(ref key) => f.write_str(key),
+            Recipient::Gpg(ref key) => write!(f, "gpg:{key}"),
+        }
+    }
+}
+
+/// How to package the tax output directory
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+pub struct Settings {
+    /// Key to encrypt the bundle to
+    pub recipient: Recipient,
+    /// Whether to also redact Bitcoin addresses from the logs
+    #[serde(default)]
+    pub redact_addresses: bool,
+}
+
+/// Strips secrets from log lines
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Redactor {
+    /// Strings (e.g. API keys) to remove wherever they appear
+    secrets: Vec<String>,
+    /// Numeric account IDs, which are only removed where they form a whole token
+    account_ids: Vec<String>,
+    /// Whether to remove Bitcoin addresses
+    addresses: bool,
+}
+
+impl Redactor {
+    /// Creates a new redactor
+    pub fn new(secrets: Vec<String>, account_ids: Vec<usize>, addresses: bool) -> Self {
+        Redactor {
+            secrets: secrets.into_iter().filter(|s| !s.is_empty()).collect(),
+            account_ids: account_ids.into_iter().map(|id| id.to_string()).collect(),
+            addresses,
+        }
+    }
+
+    /// Redacts a single line
+    pub fn redact(&self, line: &str) -> String {
+        let mut line = line.to_owned();
+        for secret in &self.secrets {
+            line = line.replace(secret.as_str(), "[REDACTED SECRET]");
+        }
+
+        // Split the line into alphanumeric tokens and everything else
+        let mut ret = String::with_capacity(line.len());
+        let mut token_start = None;
+        for (idx, ch) in line.char_indices().chain(Some((line.len(), ' '))) {
+            if ch.is_ascii_alphanumeric() {
+                token_start.get_or_insert(idx);
+                continue;
+            }
+            if let Some(start) = token_start.take() {
+                ret.push_str(self.redact_token(&line[start..idx]));
+            }
+            if idx < line.len() {
+                ret.push(ch);
+            }
+        }
+        ret
+    }
+
+    /// Redacts a single alphanumeric token, returning it unchanged if it is harmless
+    fn redact_token<'s>(&self, token: &'s str) -> &'s str {
+        if self.account_ids.iter().any(|id| id == token) {
+            "[REDACTED ACCOUNT]"
+        } else if self.addresses && token.len() >= 26 && bitcoin::Address::from_str(token).is_ok() {
+            "[REDACTED ADDRESS]"
+        } else {
+            token
+        }
+    }
+}
+
+/// Copies a text file, redacting each line
+pub fn copy_redacted(source: &str, dest: &str, redactor: &Redactor) -> anyhow::Result<()> {
+    info!("Copying {} to {} (redacted)", source, dest);
+    if fs::metadata(dest).is_ok() {
+        return Err(anyhow::Error::msg(format!(
+            "File {dest} already exists. Refusing to overwrite."
+        )));
+    }
+    let input =
+        io::BufReader::new(fs::File::open(source).with_context(|| format!("opening {source}"))?);
+    let mut output =
+        io::BufWriter::new(fs::File::create(dest).with_context(|| format!("creating {dest}"))?);
+    for (lineno, line) in input.lines().enumerate() {
+        let line = line.with_context(|| format!("reading line {lineno} of {source}"))?;
+        writeln!(output, "{}", redactor.redact(&line))
+            .with_context(|| format!("writing {dest}"))?;
+    }
+    output.flush().with_context(|| format!("writing {dest}"))?;
+    Ok(())
+}
+
+/// Packs a directory into a gzipped tarball, in memory
+#[cfg(feature = "bundle")]
+fn tarball(dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let name = dir
+        .file_name()
+        .with_context(|| format!("directory {} has no name", dir.display()))?;
+    let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    tar.append_dir_all(name, dir)
+        .with_context(|| format!("archiving {}", dir.display()))?;
+    let gz = tar.into_inner().context("finishing tar archive")?;
+    gz.finish().context("finishing gzip stream")
+}
+
+/// Encrypts data to a recipient
+#[cfg(feature = "bundle")]
+fn encrypt(recipient: &Recipient, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match *recipient {
+        Recipient::Age(ref key) => {
+            let key = age::x25519::Recipient::from_str(key)
+                .map_err(|e| anyhow::Error::msg(format!("parsing age recipient {key}: {e}")))?;
+            age::encrypt(&key, data).context("encrypting with age")
+        }
+        Recipient::Gpg(ref key) => {
+            let mut child = Command::new("gpg")
+                .args([
+                    "--batch",
+                    "--yes",
+                    "--encrypt",
+                    "--recipient",
+                    key,
+                    "--output",
+                    "-",
+                ])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .context("running gpg")?;
+            // Write from another thread so that gpg can't deadlock on a full stdout pipe
+            let mut stdin = child.stdin.take().context("opening gpg stdin")?;
+            let data = data.to_vec();
+            let writer = std::thread::spawn(move || stdin.write_all(&data));
+            let output = child.wait_with_output().context("running gpg")?;
+            writer
+                .join()
+                .map_err(|_| anyhow::Error::msg("gpg writer thread panicked"))?
+                .context("writing to gpg")?;
+            if !output.status.success() {
+                return Err(anyhow::Error::msg(format!(
+                    "gpg exited with {}",
+                    output.status
+                )));
+            }
+            Ok(output.stdout)
+        }
+    }
+}
+
+/// Packs a directory into an encrypted archive next to it, returning the
+/// archive's filename
+#[cfg(feature = "bundle")]
+pub fn archive(dir: &str, settings: &Settings) -> anyhow::Result<String> {
+    let ext = match settings.recipient {
+        Recipient::Age(..) => "age",
+        Recipient::Gpg(..) => "gpg",
+    };
+    let dest = format!("{}.tar.gz.{ext}", dir.trim_end_matches('/'));
+    if fs::metadata(&dest).is_ok() {
+        return Err(anyhow::Error::msg(format!(
+            "File {dest} already exists. Refusing to overwrite."
+        )));
+    }
+    let data = tarball(Path::new(dir))?;
+    let encrypted = encrypt(&settings.recipient, &data)
+        .with_context(|| format!("encrypting to {}", settings.recipient))?;
+    info!(
+        "Creating encrypted archive {} for {}.",
+        dest, settings.recipient
+    );
+    fs::write(&dest, encrypted).with_context(|| format!("writing {dest}"))?;
+    Ok(dest)
+}
+
+/// Fails, since there is no support for creating archives
+#[cfg(not(feature = "bundle"))]
+pub fn archive(_: &str, _: &Settings) -> anyhow::Result<String> {
+    Err(anyhow::Error::msg(
+        "this build has no output bundle support; rebuild with `--features bundle`",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact() {
+        let redactor = Redactor::new(vec!["sekrit.jwt-key".into()], vec![1234], true);
+        assert_eq!(
+            redactor.redact("Authorization: JWT sekrit.jwt-key, user 1234, mid 51234"),
+            "Authorization: JWT [REDACTED SECRET], user [REDACTED ACCOUNT], mid 51234",
+        );
+        assert_eq!(
+            redactor.redact("{\"address\":\"bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq\"}"),
+            "{\"address\":\"[REDACTED ADDRESS]\"}",
+        );
+        let keep_addrs = Redactor::new(vec![], vec![], false);
+        let line = "deposit to bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq ✓";
+        assert_eq!(keep_addrs.redact(line), line);
+    }
+
+    #[test]
+    #[cfg(feature = "bundle")]
+    fn age_roundtrip() {
+        use std::io::Read;
+
+        let identity = age::x25519::Identity::generate();
+        let recipient: Recipient = identity.to_public().to_string().parse().unwrap();
+        assert!("age1notakey".parse::<Recipient>().is_err());
+        assert_eq!(
+            "gpg:me@example.com".parse::<Recipient>(),
+            Ok(Recipient::Gpg("me@example.com".into()))
+        );
+
+        let dir = std::env::temp_dir().join(format!("tt-bundle-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("2023.csv"), "secret,data\n").unwrap();
+
+        let settings = Settings {
+            recipient,
+            redact_addresses: false,
+        };
+        let dest = archive(dir.to_str().unwrap(), &settings).unwrap();
+        let decrypted = age::decrypt(&identity, &fs::read(&dest).unwrap()).unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&decrypted[..]));
+        let mut found = false;
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().ends_with("2023.csv") {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, "secret,data\n");
+                found = true;
+            }
+        }
+        assert!(found);
+        fs::remove_file(&dest).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
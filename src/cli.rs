@@ -14,8 +14,13 @@
 
 //! Command-line Argument Parsing
 //!
+//! Amounts may be given with units, to avoid mistaking e.g. a percentage for
+//! a fraction: USD amounts as `$1,234.56` or `1234.56usd`, order sizes as
+//! `50c` (contracts) or `0.5btc`, and percentages as `65%`.
+//!
 
-use crate::{option, units::Price};
+use crate::units::{ContractSize, Price, Quantity};
+use crate::{connect, ledgerx, logger, option, serve};
 use std::{env, ffi::OsString, fmt, path::PathBuf, process, str::FromStr};
 
 /// If no price feed URL is provided, use BitcoinCharts' CSV data.
@@ -23,46 +28,200 @@ use std::{env, ffi::OsString, fmt, path::PathBuf, process, str::FromStr};
 /// From <https://bitcoincharts.com/about/markets-api/>:
 ///     * Delayed 15 minutes. No guarantees about accuracy. Do not trade on this (lol)
 ///     * Do not query more than once every 15 minutes!
-static DEFAULT_PRICE_FEED_URL: &str =
+pub static DEFAULT_PRICE_FEED_URL: &str =
     "http://api.bitcoincharts.com/v1/trades.csv?symbol=bitstampUSD";
 
+/// Exit code when a command succeeds without logging any warnings
+pub const EXIT_OK: u8 = 0;
+/// Exit code when a command succeeds but logs warnings
+pub const EXIT_WARNINGS: u8 = 1;
+/// Exit code when a command fails, logs errors, or is invoked incorrectly
+pub const EXIT_ERROR: u8 = 2;
+
+/// Options which apply to every command, given before the command name
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct GlobalOptions {
+    /// Suppress info-level logging to stdout (log files are still written)
+    pub quiet: bool,
+    /// On failure, print the error chain as JSON rather than text
+    pub json_errors: bool,
+}
+
 /// Structure representing parsing of command-line options
 pub enum Command {
     /// Read a CSV file downloaded from Bitcoincharts, storing all its price data (at
     /// a ten-minute resolution rather than all of it)
-    InitializePriceData { csv: PathBuf },
+    InitializePriceData {
+        csv: PathBuf,
+        /// Only import prices within this range
+        range: ledgerx::history::DateRange,
+    },
     /// Ping bitcoincharts in real time to get recent price data
     UpdatePriceData { url: String },
+    /// Summarize the stored price data: format version, coverage and point counts
+    PriceDataInfo {},
     /// Return the latest stored price. Mainly useful as a test.
-    LatestPrice {},
+    LatestPrice {
+        /// If there is no price data, download some recent prices
+        bootstrap: bool,
+    },
     /// Print a list of potential orders for a given option near a given volatility, at various
     /// prices
     Price {
         option: option::Option,
         /// Specific volatility, if provided
         volatility: Option<f64>,
+        /// Whether to output a table of prices across a grid of BTC prices and IVs
+        sensitivities: bool,
+        /// If there is no price data, download some recent prices
+        bootstrap: bool,
     },
     /// Print a list of potential orders for a given option near a given price
     Iv {
         option: option::Option,
         /// Specific price, if provided
         price: Option<Price>,
+        /// If there is no price data, download some recent prices
+        bootstrap: bool,
+    },
+    /// Print a ladder of orders splitting a given size across a range of prices
+    Ladder {
+        option: option::Option,
+        /// Total size of the ladder, in contracts
+        contracts: i64,
+        /// Lowest price of the ladder
+        low: Price,
+        /// Highest price of the ladder
+        high: Price,
+        /// Spacing of the rungs, as a fraction of the previous rung's price
+        step: f64,
+        /// If there is no price data, download some recent prices
+        bootstrap: bool,
     },
     /// Connect to LedgerX API and monitor activity in real-time
     Connect {
-        api_key: String,
+        /// API key, or `None` to watch public data without trading
+        api_key: Option<String>,
         config_file: Option<PathBuf>,
+        /// How to rotate the high-volume logs during the session
+        log_rotation: logger::RotationPolicy,
+        /// Settings for the main loop
+        settings: Box<connect::Settings>,
     },
     /// Connect to LedgerX API and download complete transaction history, for a given year if
     /// supplied. Outputs in CSV.
     History {
         api_key: String,
         config_file: PathBuf,
+        /// Dates to limit the output to
+        range: ledgerx::history::DateRange,
+        /// Skip records which cannot be imported, rather than failing
+        lenient: bool,
+        /// Format to output events in
+        format: ledgerx::history::OutputFormat,
+        /// Where to get the BTC prices marked on CSV output from
+        price_source: crate::price::PriceSourceKind,
+        /// If set, merge split executions of an order within this window
+        merge_fills: Option<chrono::Duration>,
     },
     /// Connect to LedgerX API and attempt to recreate its tax CSV file for a given year
     TaxHistory {
         api_key: String,
         config_file: PathBuf,
+        /// Dates to limit the output to
+        range: ledgerx::history::DateRange,
+        /// Skip records which cannot be imported, rather than failing
+        lenient: bool,
+        /// Only check for problems, without writing any output
+        check: bool,
+        /// Also write each year's reports as an Excel workbook
+        xlsx: bool,
+    },
+    /// Connect to LedgerX API and list all open BTC lots, with holding period information
+    Lots {
+        api_key: String,
+        config_file: PathBuf,
+        /// Tax rates to use when estimating the benefit of waiting to sell
+        rates: ledgerx::history::tax::TaxRates,
+    },
+    /// Connect to LedgerX API and compare the tax outcome of each lot selection
+    /// strategy for a single year
+    CompareTaxStrategies {
+        api_key: String,
+        config_file: PathBuf,
+        year: i32,
+        /// Tax rates to use when estimating the liability
+        rates: ledgerx::history::tax::TaxRates,
+    },
+    /// Compute time- and money-weighted returns of the account, per year and overall
+    Performance {
+        api_key: String,
+        config_file: PathBuf,
+        range: ledgerx::history::DateRange,
+        lenient: bool,
+        /// Volatility at which to mark open options
+        iv: f64,
+    },
+    /// Compare the USD needed if all short puts are assigned against available funding
+    FundingPlan {
+        api_key: String,
+        /// File listing scheduled deposits
+        deposits_file: Option<PathBuf>,
+    },
+    /// Estimate the P&L of our open options at a horizon, under price shocks
+    /// and optionally by Monte Carlo simulation
+    Stress {
+        api_key: String,
+        settings: ledgerx::stress::Settings,
+    },
+    /// Report the pin risk of our short positions, by expiry and distance to strike
+    PinRisk {
+        api_key: String,
+        /// Only report expiries within this many days
+        max_days: Option<i64>,
+    },
+    /// Price a single order and submit it to LX, without starting the connect loop
+    Quote {
+        api_key: String,
+        /// The order to place
+        request: ledgerx::quote::Request,
+        /// Submit without asking for confirmation
+        yes: bool,
+    },
+    /// Buy back a short position by taking offers, up to a maximum price
+    Close {
+        api_key: String,
+        /// The contract to close, and the most to pay
+        request: ledgerx::close::Request,
+        /// Submit without asking for confirmation
+        yes: bool,
+    },
+    /// Chart the BTC price since we opened a short option, relative to its strike
+    Watch {
+        api_key: String,
+        /// The LX contract ID of the option
+        contract_id: ledgerx::ContractId,
+    },
+    /// Report on the slippage of our fills, as recorded during `connect`
+    Slippage { file: Option<PathBuf> },
+    /// Check the hash chain of the audit log
+    AuditVerify { file: Option<PathBuf> },
+    /// Compare the output directories of two `tax-history` runs
+    DiffTaxRuns { old: PathBuf, new: PathBuf },
+    /// Interactively create a skeleton configuration file for the history commands
+    InitConfig { output: PathBuf },
+    /// Load price data, the contract cache and optionally our LX history, and
+    /// answer queries about them at a prompt
+    Repl {
+        /// API key and config file, if our history should be loaded
+        account: Option<(String, PathBuf)>,
+    },
+    /// Serve read-only HTML views of our positions, the book, our net worth
+    /// and the latest tax summary
+    Serve {
+        api_key: String,
+        config_file: PathBuf,
+        settings: serve::Settings,
     },
 }
 
@@ -71,7 +230,7 @@ pub enum Command {
 static COMMANDS: &[(&str, &str, fn(&str, env::ArgsOs) -> Command)] = &[
     (
         "initialize-price-data",
-        "<csv filename>",
+        "<csv filename> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>]",
         initialize_price_data,
     ),
     (
@@ -79,20 +238,147 @@ static COMMANDS: &[(&str, &str, fn(&str, env::ArgsOs) -> Command)] = &[
         "[URL (default: bitcoincharts)]",
         update_price_data,
     ),
-    ("latest-price", "", latest_price),
-    ("price", "<option> [-v <volatility>]", price),
-    ("iv", "<option> [-p <price>]", iv),
-    ("connect", "<api key>", connect),
-    ("history", "<api key> <config file>", history),
-    ("tax-history", "<api key> <config file>", tax_history),
+    ("price-data", "info", price_data),
+    ("latest-price", "[--bootstrap]", latest_price),
+    (
+        "price",
+        "<option> [-v <volatility, e.g. 65%>] [--sensitivities] [--bootstrap]",
+        price,
+    ),
+    (
+        "iv",
+        "<option> [-p <price, e.g. $1,234.56>] [--bootstrap]",
+        iv,
+    ),
+    (
+        "ladder",
+        "<option> <total size, e.g. 10c or 0.1btc> <low price, e.g. $500> <high price> \
+         [--step <percent, e.g. 5%>] [--full] [--bootstrap]",
+        ladder,
+    ),
+    (
+        "connect",
+        "(<api key> [config file] | --watch-only) [--log-max-mb <n>] [--log-max-hours <n>] [--log-retain <n>] \
+         [--max-oi-share <percent>] [--itm-alerts] [--itm-buffer <percent>] \
+         [--itm-max-buyback <usd>] [--max-price-age <seconds>] [--max-price-divergence <bps>] [--max-balance-age <seconds>] \
+         [--kill-switch <file>] [--max-daily-loss <usd>] [--alert-webhook <url>] [--alert-command <program>] \
+         [--close-requests <file>] [--emit-events <file | ->] [--database <file>] [--scheduled-deposits <file>] [--exchange-status <url>] [--cancel-when-degraded] [--kraken] [--bitstamp] \
+         [--price-weight <source>:<n>]... [--fee-tier <volume>:<fee>]... \
+         [--roll] [--roll-days <n>] [--roll-otm <percent>] [--roll-max-premium <usd>] \
+         [--yield-threshold (usd:<dollars> | nlv-bps:<n> | collateral-bps:<n>)] \
+         [--call-dte [min]:[max]] [--put-dte [min]:[max]] \
+         [--arr-reference (now | last-trading-day | last-friday | weekly:<day>)] \
+         [--post-only (reject | adjust)] [--price-sample-secs <seconds>] [--no-market-data] [--book-refresh-secs <seconds>] \
+         [--trading-window [<days>@]<HH:MM>-<HH:MM>]... \
+         [--mispricing-alerts] [--mispricing-threshold <percent>] [--mispricing-iv <percent>] \
+         [--inventory-skew] [--skew-delta-bps <n>] [--skew-vega-bps <n>] [--skew-max <percent>] \
+         [--max-short-vega <usd>] [--max-expiry-vega <usd>] \
+         [--max-short-gamma <usd>] [--max-expiry-gamma <usd>] [--block-counterparty <username>]... \
+         [--replay <logfile>]... [--compare <record file>]...",
+        connect,
+    ),
+    (
+        "history",
+        "<api key> <config file> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>] [--lenient-import] \
+         [--output-format (csv | beancount | json)] [--price-source (historic | lx | csv:<file>)] \
+         [--merge-fills <seconds>]",
+        history,
+    ),
+    (
+        "tax-history",
+        "<api key> <config file> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>] [--lenient-import] \
+         [--check] [--xlsx]",
+        tax_history,
+    ),
+    ("diff-tax-runs", "<old output dir> <new output dir>", diff_tax_runs),
+    (
+        "lots",
+        "<api key> <config file> [--st-rate <percent>] [--lt-rate <percent>]",
+        lots,
+    ),
+    (
+        "compare-tax-strategies",
+        "<api key> <config file> <year> [--st-rate <percent>] [--lt-rate <percent>]",
+        compare_tax_strategies,
+    ),
+    (
+        "performance",
+        "<api key> <config file> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>] [--lenient-import] \
+         [--iv <percent>]",
+        performance,
+    ),
+    (
+        "funding-plan",
+        "<api key> [scheduled deposits file]",
+        funding_plan,
+    ),
+    (
+        "stress",
+        "<api key> [--horizon <days>] [--vol <percent>] [--drift <percent>] [--monte-carlo] \
+         [--paths <n>] [--steps-per-day <n>] [--seed <n>]",
+        stress,
+    ),
+    ("pin-risk", "<api key> [--days <n>]", pin_risk),
+    (
+        "quote",
+        "<api key> <option> <bid|ask> <size, e.g. 5c or 0.05btc> \
+         (-p <price> | --iv <percent> | --arr <percent>) \
+         [--arr-reference (now | last-trading-day | last-friday | weekly:<day>)] [--full] [--yes]",
+        quote,
+    ),
+    (
+        "close",
+        "<api key> <contract id> [--max-price <usd>] [--yes]",
+        close,
+    ),
+    ("watch", "<api key> <contract id>", watch),
+    ("slippage", "[fill file]", slippage),
+    ("audit", "verify [audit log file]", audit),
+    ("init-config", "<output config file>", init_config),
+    ("repl", "[<api key> <config file>]", repl),
+    (
+        "serve",
+        "<api key> <config file> [--bind <address:port>] [--token-file <file>] [--iv <percent>]",
+        serve,
+    ),
 ];
 
 /// Parse the "initialize-price-data" command
 fn initialize_price_data(invocation: &str, mut args: env::ArgsOs) -> Command {
-    match args.next() {
-        Some(x) => Command::InitializePriceData { csv: x.into() },
+    let csv = match args.next() {
+        Some(x) => x.into(),
         None => usage(invocation),
+    };
+    let mut range = ledgerx::history::DateRange::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--from") => {
+                range.from = Some(parse_os_string_required(
+                    args.next(),
+                    "start date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            Some("--to") => {
+                range.to = Some(parse_os_string_required(
+                    args.next(),
+                    "end date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    if let (Some(from), Some(to)) = (range.from, range.to) {
+        if from > to {
+            eprintln!("Start date {from} is after end date {to}.");
+            usage(invocation);
+        }
     }
+    Command::InitializePriceData { csv, range }
 }
 
 /// Parse the "update-price-data" command
@@ -109,75 +395,1115 @@ fn update_price_data(invocation: &str, mut args: env::ArgsOs) -> Command {
     }
 }
 
+/// Parse the "price-data" command
+fn price_data(invocation: &str, mut args: env::ArgsOs) -> Command {
+    match args.next().as_ref().and_then(|s| s.to_str()) {
+        Some("info") => Command::PriceDataInfo {},
+        Some(sub) => {
+            eprintln!("Unknown price-data subcommand {sub}");
+            usage(invocation);
+        }
+        None => usage(invocation),
+    }
+}
+
 /// Parse the "latest-price" command
-fn latest_price(_: &str, _: env::ArgsOs) -> Command {
-    Command::LatestPrice {}
+fn latest_price(invocation: &str, args: env::ArgsOs) -> Command {
+    let mut bootstrap = false;
+    for arg in args {
+        match arg.to_str() {
+            Some("--bootstrap") => bootstrap = true,
+            _ => {
+                eprintln!("Unrecognized argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::LatestPrice { bootstrap }
 }
 
 /// Parse the "price" command
 fn price(invocation: &str, mut args: env::ArgsOs) -> Command {
     let option = parse_os_string_required(args.next(), "option ID", invocation);
-    let vol = parse_os_string(args.next(), "-v flag", invocation).map(|dashv: DashOpt| {
-        if dashv.0 == b'v' {
-            parse_os_string_required(args.next(), "volatility", invocation)
-        } else {
-            eprintln!("Unrecognized flag -{}", char::from(dashv.0));
-            usage(invocation);
+    let mut volatility = None;
+    let mut sensitivities = false;
+    let mut bootstrap = false;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-v") => {
+                let vol: Percent = parse_os_string_required(args.next(), "volatility", invocation);
+                // Bare numbers are fractions here, for compatibility
+                volatility = Some(vol.fraction(false));
+                if vol.fraction(false) > MAX_PLAUSIBLE_VOL {
+                    if vol.has_sign {
+                        eprintln!("Volatility {vol} is implausibly high.");
+                    } else {
+                        eprintln!("Volatility {vol} is implausibly high; did you mean {vol}%?");
+                    }
+                    usage(invocation);
+                }
+            }
+            Some("--sensitivities") => sensitivities = true,
+            Some("--bootstrap") => bootstrap = true,
+            _ => {
+                eprintln!("Unrecognized argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
         }
-    });
+    }
     Command::Price {
         option,
-        volatility: vol,
+        volatility,
+        sensitivities,
+        bootstrap,
     }
 }
 
 /// Parse the "iv" command
 fn iv(invocation: &str, mut args: env::ArgsOs) -> Command {
     let option = parse_os_string_required(args.next(), "option ID", invocation);
-    let price = parse_os_string(args.next(), "-v flag", invocation).map(|dashv: DashOpt| {
-        if dashv.0 == b'p' {
-            parse_os_string_required(args.next(), "price", invocation)
-        } else {
-            eprintln!("Unrecognized flag -{}", char::from(dashv.0));
+    let mut price = None;
+    let mut bootstrap = false;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--bootstrap") => bootstrap = true,
+            _ => {
+                let dashp: DashOpt = parse_os_string_required(Some(arg), "-p flag", invocation);
+                if dashp.0 != b'p' {
+                    eprintln!("Unrecognized flag -{}", char::from(dashp.0));
+                    usage(invocation);
+                }
+                let amount: UsdAmount = parse_os_string_required(args.next(), "price", invocation);
+                price = Some(amount.0);
+            }
+        }
+    }
+    Command::Iv {
+        option,
+        price,
+        bootstrap,
+    }
+}
+
+/// Spacing of the rungs of a ladder, if not given
+const DEFAULT_LADDER_STEP: f64 = 0.1;
+
+/// Longest log age we accept before rotating, ten years, which keeps the
+/// rotation time well within the range of a timestamp
+const MAX_LOG_HOURS: i64 = 10 * 366 * 24;
+
+/// Parse the "ladder" command
+fn ladder(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let option = parse_os_string_required(args.next(), "option ID", invocation);
+    let size: OrderSize = parse_os_string_required(args.next(), "total size", invocation);
+    let low: UsdAmount = parse_os_string_required(args.next(), "low price", invocation);
+    let high: UsdAmount = parse_os_string_required(args.next(), "high price", invocation);
+    if high.0 < low.0 {
+        eprintln!("High price {} is below low price {}.", high.0, low.0);
+        usage(invocation);
+    }
+    let mut step = DEFAULT_LADDER_STEP;
+    let mut contract_size = ContractSize::Mini;
+    let mut bootstrap = false;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--step") => {
+                let pct: Percent = parse_os_string_required(args.next(), "step", invocation);
+                step = pct.fraction(true);
+                if step <= 0.0 {
+                    eprintln!("Step must be positive.");
+                    usage(invocation);
+                }
+            }
+            Some("--full") => contract_size = ContractSize::Full,
+            Some("--bootstrap") => bootstrap = true,
+            _ => {
+                eprintln!("Unrecognized argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    let contracts = match size.contracts(contract_size) {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{e}");
             usage(invocation);
         }
-    });
-    Command::Iv { option, price }
+    };
+    Command::Ladder {
+        option,
+        contracts,
+        low: low.0,
+        high: high.0,
+        step,
+        bootstrap,
+    }
 }
 
 /// Parse the "connect" command
 fn connect(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key: String = parse_os_string_required(args.next(), "API key", invocation);
+    let api_key = if api_key == "--watch-only" {
+        None
+    } else {
+        Some(api_key)
+    };
+    let mut config_file = None;
+    let mut log_rotation = logger::RotationPolicy::default();
+    let mut settings = connect::Settings::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--log-max-mb") => {
+                let mb: u64 = parse_os_string_required(args.next(), "log size (MB)", invocation);
+                log_rotation.max_bytes = match mb.checked_mul(1024 * 1024) {
+                    Some(0) => {
+                        eprintln!("Log size must be positive.");
+                        usage(invocation);
+                    }
+                    Some(bytes) => bytes,
+                    None => {
+                        eprintln!("Log size {mb} MB is too large.");
+                        usage(invocation);
+                    }
+                };
+            }
+            Some("--log-max-hours") => {
+                let hours: i64 =
+                    parse_os_string_required(args.next(), "log age (hours)", invocation);
+                if !(1..=MAX_LOG_HOURS).contains(&hours) {
+                    eprintln!("Log age must be between 1 and {MAX_LOG_HOURS} hours.");
+                    usage(invocation);
+                }
+                log_rotation.max_age = chrono::Duration::hours(hours);
+            }
+            Some("--log-retain") => {
+                log_rotation.retain =
+                    parse_os_string_required(args.next(), "log retention count", invocation);
+                if log_rotation.retain == 0 {
+                    eprintln!("Log retention count must be positive.");
+                    usage(invocation);
+                }
+            }
+            Some("--max-oi-share") => {
+                settings.max_oi_share_pct =
+                    parse_os_string_required(args.next(), "open interest share", invocation);
+            }
+            Some("--max-price-age") => {
+                settings.max_price_age_secs =
+                    parse_os_string_required(args.next(), "price age (seconds)", invocation);
+            }
+            Some("--max-price-divergence") => {
+                settings.max_price_divergence_bps = parse_os_string_required(
+                    args.next(),
+                    "price divergence (basis points)",
+                    invocation,
+                );
+            }
+            Some("--emit-events") => {
+                settings.emit_events = Some(parse_os_string_required(
+                    args.next(),
+                    "event stream filename, or - for stdout",
+                    invocation,
+                ));
+            }
+            Some("--replay") => {
+                settings.replay_files.push(parse_os_string_required(
+                    args.next(),
+                    "log filename",
+                    invocation,
+                ));
+            }
+            Some("--compare") => {
+                settings.compare_files.push(parse_os_string_required(
+                    args.next(),
+                    "record filename",
+                    invocation,
+                ));
+            }
+            Some("--database") => {
+                if cfg!(not(feature = "sqlite")) {
+                    eprintln!(
+                        "This build has no SQLite support; rebuild with `--features sqlite` \
+                         to use --database."
+                    );
+                    usage(invocation);
+                }
+                settings.database_file = Some(parse_os_string_required(
+                    args.next(),
+                    "database filename",
+                    invocation,
+                ));
+            }
+            Some("--max-balance-age") => {
+                settings.max_balance_age_secs =
+                    parse_os_string_required(args.next(), "balance age (seconds)", invocation);
+            }
+            Some("--alert-webhook") => {
+                settings.emergency.alert_webhook = Some(parse_os_string_required(
+                    args.next(),
+                    "alert webhook URL",
+                    invocation,
+                ));
+            }
+            Some("--alert-command") => {
+                settings.emergency.alert_command = Some(parse_os_string_required(
+                    args.next(),
+                    "alert command",
+                    invocation,
+                ));
+            }
+            Some("--kill-switch") => {
+                settings.kill_switch_file = Some(parse_os_string_required(
+                    args.next(),
+                    "kill switch filename",
+                    invocation,
+                ));
+            }
+            Some("--max-daily-loss") => {
+                settings.max_daily_loss = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum daily loss (USD)",
+                    invocation,
+                ));
+            }
+            Some("--close-requests") => {
+                settings.close_request_file = Some(parse_os_string_required(
+                    args.next(),
+                    "close request filename",
+                    invocation,
+                ));
+            }
+            Some("--scheduled-deposits") => {
+                settings.deposits_file = Some(parse_os_string_required(
+                    args.next(),
+                    "scheduled deposits filename",
+                    invocation,
+                ));
+            }
+            Some("--exchange-status") => {
+                settings.exchange_status.url = Some(parse_os_string_required(
+                    args.next(),
+                    "status page URL",
+                    invocation,
+                ));
+            }
+            Some("--cancel-when-degraded") => settings.exchange_status.cancel_orders = true,
+            Some("--kraken") => settings.kraken = true,
+            Some("--bitstamp") => settings.bitstamp = true,
+            Some("--price-weight") => {
+                settings.price_weights.push(parse_os_string_required(
+                    args.next(),
+                    "price source weight",
+                    invocation,
+                ));
+            }
+            Some("--fee-tier") => {
+                settings.fee_tiers.push(parse_os_string_required(
+                    args.next(),
+                    "fee tier",
+                    invocation,
+                ));
+            }
+            Some("--itm-alerts") => settings.itm.enabled = true,
+            Some("--itm-buffer") => {
+                settings.itm.buffer_pct =
+                    parse_os_string_required(args.next(), "ITM buffer (percent)", invocation);
+            }
+            Some("--itm-max-buyback") => {
+                settings.itm.max_buyback = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum buy-back cost (USD)",
+                    invocation,
+                ));
+            }
+            Some("--yield-threshold") => {
+                settings.yield_threshold =
+                    parse_os_string_required(args.next(), "yield threshold (per day)", invocation);
+            }
+            Some("--call-dte") => {
+                settings.dte_filter.calls =
+                    parse_os_string_required(args.next(), "call days to expiry", invocation);
+            }
+            Some("--put-dte") => {
+                settings.dte_filter.puts =
+                    parse_os_string_required(args.next(), "put days to expiry", invocation);
+            }
+            Some("--arr-reference") => {
+                settings.arr_reference =
+                    parse_os_string_required(args.next(), "ARR reference", invocation);
+            }
+            Some("--post-only") => {
+                settings.post_only =
+                    parse_os_string_required(args.next(), "post-only policy", invocation);
+            }
+            Some("--trading-window") => {
+                settings.trading_windows.push(parse_os_string_required(
+                    args.next(),
+                    "trading window",
+                    invocation,
+                ));
+            }
+            Some("--price-sample-secs") => {
+                settings.price_sample_secs = parse_os_string_required(
+                    args.next(),
+                    "price sample interval (seconds)",
+                    invocation,
+                );
+            }
+            Some("--no-market-data") => settings.archive_market_data = false,
+            Some("--book-refresh-secs") => {
+                settings.book_refresh_secs = parse_os_string_required(
+                    args.next(),
+                    "book refresh interval (seconds; 0 to disable)",
+                    invocation,
+                );
+            }
+            Some("--mispricing-alerts") => settings.mispricing.enabled = true,
+            Some("--mispricing-threshold") => {
+                settings.mispricing.threshold_pct = parse_os_string_required(
+                    args.next(),
+                    "mispricing threshold (percent)",
+                    invocation,
+                );
+            }
+            Some("--mispricing-iv") => {
+                settings.mispricing.model_iv_pct =
+                    parse_os_string_required(args.next(), "model IV (percent)", invocation);
+                if settings.mispricing.model_iv_pct == 0 {
+                    eprintln!("Model IV must be positive.");
+                    usage(invocation);
+                }
+            }
+            Some("--inventory-skew") => settings.skew.enabled = true,
+            Some("--skew-delta-bps") => {
+                settings.skew.delta_bps_per_btc = parse_os_string_required(
+                    args.next(),
+                    "skew per BTC of net delta (basis points)",
+                    invocation,
+                );
+            }
+            Some("--skew-vega-bps") => {
+                settings.skew.vega_bps_per_100usd = parse_os_string_required(
+                    args.next(),
+                    "skew per $100 of short vega (basis points)",
+                    invocation,
+                );
+            }
+            Some("--skew-max") => {
+                settings.skew.max_pct =
+                    parse_os_string_required(args.next(), "maximum skew (percent)", invocation);
+                if settings.skew.max_pct >= 100 {
+                    eprintln!("Maximum skew must be less than 100%.");
+                    usage(invocation);
+                }
+            }
+            Some("--block-counterparty") => {
+                settings.block_counterparties.push(parse_os_string_required(
+                    args.next(),
+                    "chat username",
+                    invocation,
+                ));
+            }
+            Some("--max-short-vega") => {
+                settings.greek_limits.max_vega_per_underlying = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum short vega (USD per vol point)",
+                    invocation,
+                ));
+            }
+            Some("--max-expiry-vega") => {
+                settings.greek_limits.max_vega_per_expiry = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum short vega per expiry (USD per vol point)",
+                    invocation,
+                ));
+            }
+            Some("--max-short-gamma") => {
+                settings.greek_limits.max_gamma_per_underlying = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum short gamma (USD per 1% move)",
+                    invocation,
+                ));
+            }
+            Some("--max-expiry-gamma") => {
+                settings.greek_limits.max_gamma_per_expiry = Some(parse_os_string_required(
+                    args.next(),
+                    "maximum short gamma per expiry (USD per 1% move)",
+                    invocation,
+                ));
+            }
+            Some("--roll") => settings.roll.enabled = true,
+            Some("--roll-days") => {
+                settings.roll.max_days =
+                    parse_os_string_required(args.next(), "roll window (days)", invocation);
+            }
+            Some("--roll-otm") => {
+                settings.roll.min_otm_pct =
+                    parse_os_string_required(args.next(), "roll distance (percent)", invocation);
+            }
+            Some("--roll-max-premium") => {
+                settings.roll.max_remaining = parse_os_string_required(
+                    args.next(),
+                    "maximum roll buy-back price (USD)",
+                    invocation,
+                );
+            }
+            _ if config_file.is_none() => config_file = Some(arg.into()),
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    if settings.exchange_status.cancel_orders && settings.exchange_status.url.is_none() {
+        eprintln!("--cancel-when-degraded requires --exchange-status.");
+        usage(invocation);
+    }
+    if settings.itm.max_buyback.is_some() && !settings.itm.enabled {
+        eprintln!("--itm-max-buyback requires --itm-alerts.");
+        usage(invocation);
+    }
+    if settings
+        .max_daily_loss
+        .is_some_and(|loss| loss <= Price::ZERO)
+    {
+        eprintln!("--max-daily-loss must be positive.");
+        usage(invocation);
+    }
+    let mispricing_tuned = settings.mispricing
+        != ledgerx::mispricing::Settings {
+            enabled: settings.mispricing.enabled,
+            ..Default::default()
+        };
+    if mispricing_tuned && !settings.mispricing.enabled {
+        eprintln!("--mispricing-threshold and --mispricing-iv require --mispricing-alerts.");
+        usage(invocation);
+    }
+    let skew_tuned = settings.skew
+        != ledgerx::skew::Settings {
+            enabled: settings.skew.enabled,
+            ..Default::default()
+        };
+    if skew_tuned && !settings.skew.enabled {
+        eprintln!("--skew-delta-bps, --skew-vega-bps and --skew-max require --inventory-skew.");
+        usage(invocation);
+    }
+    let roll_tuned = settings.roll
+        != ledgerx::roll::Settings {
+            enabled: settings.roll.enabled,
+            ..Default::default()
+        };
+    if roll_tuned && !settings.roll.enabled {
+        eprintln!("--roll-days, --roll-otm and --roll-max-premium require --roll.");
+        usage(invocation);
+    }
+    if api_key.is_none() && (config_file.is_some() || settings.roll.enabled) {
+        eprintln!("--watch-only cannot be used with a config file or --roll.");
+        usage(invocation);
+    }
+    if !settings.replay_files.is_empty() && config_file.is_some() {
+        eprintln!("--replay cannot be used with a config file.");
+        usage(invocation);
+    }
+    if !settings.compare_files.is_empty() && settings.replay_files.is_empty() {
+        eprintln!("--compare requires --replay.");
+        usage(invocation);
+    }
+    if !settings.compare_files.is_empty() && api_key.is_none() {
+        eprintln!("--compare cannot be used with --watch-only, which places no orders.");
+        usage(invocation);
+    }
     Command::Connect {
-        api_key: parse_os_string_required(args.next(), "API key", invocation),
-        config_file: args.next().map(From::from),
+        api_key,
+        config_file,
+        log_rotation,
+        settings: Box::new(settings),
     }
 }
 
+/// Parse the arguments common to the "history" and "tax-history" commands
+///
+/// Returns the API key, config file, date range, whether `--lenient-import`,
+/// `--check` and `--xlsx` were given, the output format, the price source and
+/// the fill-merging window. `--check` and `--xlsx` are only accepted if `tax`
+/// is set, and `--output-format`, `--price-source` and `--merge-fills` if not.
+fn history_args(
+    invocation: &str,
+    mut args: env::ArgsOs,
+    tax: bool,
+) -> (
+    String,
+    PathBuf,
+    ledgerx::history::DateRange,
+    bool,
+    bool,
+    bool,
+    ledgerx::history::OutputFormat,
+    crate::price::PriceSourceKind,
+    Option<chrono::Duration>,
+) {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let config_file = match args.next() {
+        Some(x) => x.into(),
+        None => {
+            eprintln!("Missing configuration filename");
+            usage(invocation)
+        }
+    };
+    let mut range = ledgerx::history::DateRange::default();
+    let mut lenient = false;
+    let mut check = false;
+    let mut xlsx = false;
+    let mut format = ledgerx::history::OutputFormat::default();
+    let mut price_source = None;
+    let mut merge_fills = None;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--lenient-import") => lenient = true,
+            Some("--output-format") if !tax => {
+                format = parse_os_string_required(args.next(), "output format", invocation);
+            }
+            Some("--price-source") if !tax => {
+                price_source = Some(parse_os_string_required(
+                    args.next(),
+                    "price source",
+                    invocation,
+                ));
+            }
+            Some("--merge-fills") if !tax => {
+                let secs: u32 =
+                    parse_os_string_required(args.next(), "merge window (seconds)", invocation);
+                merge_fills = Some(chrono::Duration::seconds(secs.into()));
+            }
+            Some("--check") if tax => check = true,
+            Some("--xlsx") if tax => xlsx = true,
+            Some("--from") => {
+                range.from = Some(parse_os_string_required(
+                    args.next(),
+                    "start date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            Some("--to") => {
+                range.to = Some(parse_os_string_required(
+                    args.next(),
+                    "end date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    if let (Some(from), Some(to)) = (range.from, range.to) {
+        if from > to {
+            eprintln!("Start date {from} is after end date {to}.");
+            usage(invocation);
+        }
+    }
+    if check && xlsx {
+        eprintln!("--check does not write any output, so cannot be combined with --xlsx.");
+        usage(invocation);
+    }
+    if xlsx && cfg!(not(feature = "xlsx")) {
+        eprintln!("This build has no xlsx support; rebuild with `--features xlsx` to use --xlsx.");
+        usage(invocation);
+    }
+    if price_source.is_some() && format != ledgerx::history::OutputFormat::Csv {
+        eprintln!("--price-source only affects CSV output.");
+        usage(invocation);
+    }
+    if merge_fills.is_some() && format == ledgerx::history::OutputFormat::Beancount {
+        eprintln!("--merge-fills only affects CSV and JSON output.");
+        usage(invocation);
+    }
+    let price_source = price_source.unwrap_or_default();
+    (
+        api_key,
+        config_file,
+        range,
+        lenient,
+        check,
+        xlsx,
+        format,
+        price_source,
+        merge_fills,
+    )
+}
+
 /// Parse the "history" command
-fn history(invocation: &str, mut args: env::ArgsOs) -> Command {
+fn history(invocation: &str, args: env::ArgsOs) -> Command {
+    let (api_key, config_file, range, lenient, _, _, format, price_source, merge_fills) =
+        history_args(invocation, args, false);
     Command::History {
-        api_key: parse_os_string_required(args.next(), "API key", invocation),
-        config_file: match args.next() {
-            Some(x) => x.into(),
-            None => {
-                eprintln!("Missing configuration filename");
-                usage(invocation)
-            }
-        },
+        api_key,
+        config_file,
+        range,
+        lenient,
+        format,
+        price_source,
+        merge_fills,
     }
 }
 
 /// Parse the "tax-history" command
-fn tax_history(invocation: &str, mut args: env::ArgsOs) -> Command {
+fn tax_history(invocation: &str, args: env::ArgsOs) -> Command {
+    let (api_key, config_file, range, lenient, check, xlsx, _, _, _) =
+        history_args(invocation, args, true);
     Command::TaxHistory {
-        api_key: parse_os_string_required(args.next(), "API key", invocation),
-        config_file: match args.next() {
-            Some(x) => x.into(),
+        api_key,
+        config_file,
+        range,
+        lenient,
+        check,
+        xlsx,
+    }
+}
+
+/// Parse the "diff-tax-runs" command
+fn diff_tax_runs(invocation: &str, mut args: env::ArgsOs) -> Command {
+    match (args.next(), args.next()) {
+        (Some(old), Some(new)) => Command::DiffTaxRuns {
+            old: old.into(),
+            new: new.into(),
+        },
+        _ => {
+            eprintln!("Missing output directories to compare");
+            usage(invocation)
+        }
+    }
+}
+
+/// Parse the "lots" command
+fn lots(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let config_file = match args.next() {
+        Some(x) => x.into(),
+        None => {
+            eprintln!("Missing configuration filename");
+            usage(invocation)
+        }
+    };
+    let mut rates = ledgerx::history::tax::TaxRates::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--st-rate") => {
+                rates.short_term_pct =
+                    parse_os_string_required(args.next(), "short-term rate (percent)", invocation);
+            }
+            Some("--lt-rate") => {
+                rates.long_term_pct =
+                    parse_os_string_required(args.next(), "long-term rate (percent)", invocation);
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::Lots {
+        api_key,
+        config_file,
+        rates,
+    }
+}
+
+/// Parse the "compare-tax-strategies" command
+fn compare_tax_strategies(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let config_file = match args.next() {
+        Some(x) => x.into(),
+        None => {
+            eprintln!("Missing configuration filename");
+            usage(invocation)
+        }
+    };
+    let year = parse_os_string_required(args.next(), "year", invocation);
+    let mut rates = ledgerx::history::tax::TaxRates::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--st-rate") => {
+                rates.short_term_pct =
+                    parse_os_string_required(args.next(), "short-term rate (percent)", invocation);
+            }
+            Some("--lt-rate") => {
+                rates.long_term_pct =
+                    parse_os_string_required(args.next(), "long-term rate (percent)", invocation);
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::CompareTaxStrategies {
+        api_key,
+        config_file,
+        year,
+        rates,
+    }
+}
+
+/// Parse the "performance" command
+fn performance(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let config_file = match args.next() {
+        Some(x) => x.into(),
+        None => {
+            eprintln!("Missing configuration filename");
+            usage(invocation)
+        }
+    };
+    let mut range = ledgerx::history::DateRange::default();
+    let mut lenient = false;
+    let mut iv = ledgerx::interesting::STANDING_IV;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--lenient-import") => lenient = true,
+            Some("--from") => {
+                range.from = Some(parse_os_string_required(
+                    args.next(),
+                    "start date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            Some("--to") => {
+                range.to = Some(parse_os_string_required(
+                    args.next(),
+                    "end date (YYYY-MM-DD)",
+                    invocation,
+                ));
+            }
+            Some("--iv") => {
+                let pct: Percent = parse_os_string_required(args.next(), "volatility", invocation);
+                if pct.fraction(true) <= 0.0 {
+                    eprintln!("Volatility must be positive.");
+                    usage(invocation);
+                }
+                iv = pct.fraction(true);
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    if let (Some(from), Some(to)) = (range.from, range.to) {
+        if from > to {
+            eprintln!("Start date {from} is after end date {to}.");
+            usage(invocation);
+        }
+    }
+    Command::Performance {
+        api_key,
+        config_file,
+        range,
+        lenient,
+        iv,
+    }
+}
+
+/// Parse the "quote" command
+fn quote(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let option = parse_os_string_required(args.next(), "option ID", invocation);
+    let side = parse_os_string_required(args.next(), "side (bid or ask)", invocation);
+    let size: OrderSize = parse_os_string_required(args.next(), "order size", invocation);
+    let mut target = None;
+    let mut contract_size = ContractSize::Mini;
+    let mut arr_reference = ledgerx::interesting::ArrReference::Now;
+    let mut yes = false;
+    while let Some(arg) = args.next() {
+        let new_target = match arg.to_str() {
+            Some("-p") => {
+                let price: UsdAmount = parse_os_string_required(args.next(), "price", invocation);
+                ledgerx::quote::Target::Price(price.0)
+            }
+            Some("--iv") => {
+                let pct: Percent = parse_os_string_required(args.next(), "IV", invocation);
+                ledgerx::quote::Target::Iv(pct.fraction(true))
+            }
+            Some("--arr") => {
+                let pct: Percent = parse_os_string_required(args.next(), "ARR", invocation);
+                ledgerx::quote::Target::Arr(pct.fraction(true))
+            }
+            Some("--arr-reference") => {
+                arr_reference = parse_os_string_required(args.next(), "ARR reference", invocation);
+                continue;
+            }
+            Some("--full") => {
+                contract_size = ContractSize::Full;
+                continue;
+            }
+            Some("--yes") => {
+                yes = true;
+                continue;
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        };
+        if target.replace(new_target).is_some() {
+            eprintln!("Only one of -p, --iv and --arr may be given.");
+            usage(invocation);
+        }
+    }
+    let target = match target {
+        Some(target) => target,
+        None => {
+            eprintln!("One of -p, --iv or --arr is required.");
+            usage(invocation);
+        }
+    };
+    let size = match size.contracts(contract_size) {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("{e}");
+            usage(invocation);
+        }
+    };
+    Command::Quote {
+        api_key,
+        request: ledgerx::quote::Request {
+            option,
+            side,
+            size,
+            target,
+            contract_size,
+            arr_reference,
+        },
+        yes,
+    }
+}
+
+/// Parse the "close" command
+fn close(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let contract_id: usize = parse_os_string_required(args.next(), "contract ID", invocation);
+    let mut max_price = None;
+    let mut yes = false;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--max-price") => {
+                let price: UsdAmount =
+                    parse_os_string_required(args.next(), "maximum price", invocation);
+                max_price = Some(price.0);
+            }
+            Some("--yes") => yes = true,
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::Close {
+        api_key,
+        request: ledgerx::close::Request {
+            contract_id: contract_id.into(),
+            max_price,
+        },
+        yes,
+    }
+}
+
+/// Parse the "watch" command
+fn watch(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let contract_id: usize = parse_os_string_required(args.next(), "contract ID", invocation);
+    Command::Watch {
+        api_key,
+        contract_id: contract_id.into(),
+    }
+}
+
+/// Parse the "slippage" command
+fn slippage(_: &str, mut args: env::ArgsOs) -> Command {
+    Command::Slippage {
+        file: args.next().map(PathBuf::from),
+    }
+}
+
+/// Parse the "audit" command
+fn audit(invocation: &str, mut args: env::ArgsOs) -> Command {
+    match args.next().as_ref().and_then(|s| s.to_str()) {
+        Some("verify") => Command::AuditVerify {
+            file: args.next().map(PathBuf::from),
+        },
+        Some(sub) => {
+            eprintln!("Unknown audit subcommand {sub}");
+            usage(invocation);
+        }
+        None => usage(invocation),
+    }
+}
+
+/// Parse the "funding-plan" command
+fn funding_plan(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    Command::FundingPlan {
+        api_key,
+        deposits_file: args.next().map(PathBuf::from),
+    }
+}
+
+/// Parse the "stress" command
+fn stress(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let mut settings = ledgerx::stress::Settings::default();
+    let mut mc_only = None;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--horizon") => {
+                settings.horizon_days =
+                    parse_os_string_required(args.next(), "horizon (days)", invocation);
+                if settings.horizon_days <= 0 {
+                    eprintln!("Horizon must be at least one day.");
+                    usage(invocation);
+                }
+            }
+            Some("--vol") => {
+                let pct: Percent = parse_os_string_required(args.next(), "volatility", invocation);
+                if pct.fraction(true) <= 0.0 {
+                    eprintln!("Volatility must be positive.");
+                    usage(invocation);
+                }
+                settings.model.vol = pct.fraction(true);
+            }
+            Some("--drift") => {
+                let pct: Percent = parse_os_string_required(args.next(), "drift", invocation);
+                settings.model.drift = pct.fraction(true);
+            }
+            Some("--monte-carlo") => settings.monte_carlo = true,
+            Some("--paths") => {
+                settings.model.paths =
+                    parse_os_string_required(args.next(), "number of paths", invocation);
+                mc_only = Some("--paths");
+            }
+            Some("--steps-per-day") => {
+                settings.model.steps_per_day =
+                    parse_os_string_required(args.next(), "steps per day", invocation);
+                mc_only = Some("--steps-per-day");
+            }
+            Some("--seed") => {
+                settings.model.seed = parse_os_string_required(args.next(), "seed", invocation);
+                mc_only = Some("--seed");
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    if let Some(flag) = mc_only {
+        if !settings.monte_carlo {
+            eprintln!("{flag} only makes sense with --monte-carlo.");
+            usage(invocation);
+        }
+    }
+    if settings.model.paths == 0 || settings.model.steps_per_day == 0 {
+        eprintln!("Number of paths and steps per day must be positive.");
+        usage(invocation);
+    }
+    Command::Stress { api_key, settings }
+}
+
+/// Parse the "pin-risk" command
+fn pin_risk(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let mut max_days = None;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--days") => {
+                let days: i64 = parse_os_string_required(args.next(), "days", invocation);
+                if days < 0 {
+                    eprintln!("Number of days must not be negative.");
+                    usage(invocation);
+                }
+                max_days = Some(days);
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::PinRisk { api_key, max_days }
+}
+
+/// Parse the "init-config" command
+fn init_config(invocation: &str, mut args: env::ArgsOs) -> Command {
+    match args.next() {
+        Some(x) => Command::InitConfig { output: x.into() },
+        None => {
+            eprintln!("Missing output filename");
+            usage(invocation)
+        }
+    }
+}
+
+/// Parse the "repl" command
+fn repl(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let account = match parse_os_string(args.next(), "API key", invocation) {
+        Some(api_key) => match args.next() {
+            Some(config_file) => Some((api_key, config_file.into())),
             None => {
                 eprintln!("Missing configuration filename");
                 usage(invocation)
             }
         },
+        None => None,
+    };
+    if let Some(arg) = args.next() {
+        eprintln!("Unexpected argument {}", arg.to_string_lossy());
+        usage(invocation);
+    }
+    Command::Repl { account }
+}
+
+/// Parse the "serve" command
+fn serve(invocation: &str, mut args: env::ArgsOs) -> Command {
+    let api_key = parse_os_string_required(args.next(), "API key", invocation);
+    let config_file = match args.next() {
+        Some(x) => x.into(),
+        None => {
+            eprintln!("Missing configuration filename");
+            usage(invocation)
+        }
+    };
+    let mut settings = serve::Settings::default();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--bind") => {
+                settings.bind = parse_os_string_required(args.next(), "address:port", invocation);
+            }
+            Some("--token-file") => {
+                settings.token_file = Some(parse_os_string_required(
+                    args.next(),
+                    "token file",
+                    invocation,
+                ));
+            }
+            Some("--iv") => {
+                let pct: Percent = parse_os_string_required(args.next(), "volatility", invocation);
+                if pct.fraction(true) <= 0.0 {
+                    eprintln!("Volatility must be positive.");
+                    usage(invocation);
+                }
+                settings.iv = pct.fraction(true);
+            }
+            _ => {
+                eprintln!("Unexpected argument {}", arg.to_string_lossy());
+                usage(invocation);
+            }
+        }
+    }
+    Command::Serve {
+        api_key,
+        config_file,
+        settings,
     }
 }
 
@@ -187,7 +1513,9 @@ impl Command {
     /// If this fails, it will output a usage text to stderr and then
     /// terminate the process. It should not be called once the program
     /// is "really" running.
-    pub fn from_args() -> Self {
+    ///
+    /// Returns the command along with any global options given before it.
+    pub fn from_args() -> (Self, GlobalOptions) {
         let mut args = env::args_os();
         // Obtain name we were called with
         let invocation = match args.next().map(OsString::into_string) {
@@ -196,12 +1524,22 @@ impl Command {
             None => panic!("called with no arguments, not even a command-line name"),
         };
 
-        // Obtain primary command
-        match args.next().map(OsString::into_string) {
+        // Obtain global options, then primary command
+        let mut options = GlobalOptions::default();
+        let mut next = args.next().map(OsString::into_string);
+        loop {
+            match next {
+                Some(Ok(ref opt)) if opt == "--quiet" => options.quiet = true,
+                Some(Ok(ref opt)) if opt == "--json-errors" => options.json_errors = true,
+                _ => break,
+            }
+            next = args.next().map(OsString::into_string);
+        }
+        match next {
             Some(Ok(inv)) => {
                 for (cmd, _, f) in COMMANDS {
                     if inv == *cmd {
-                        return f(&invocation, args);
+                        return (f(&invocation, args), options);
                     }
                 }
                 eprintln!("Unknown command {inv}");
@@ -215,17 +1553,46 @@ impl Command {
         }
     }
 
+    /// For the simple pricing commands, which can run off a few days of price
+    /// data, whether `--bootstrap` was given; `None` for every other command
+    pub fn bootstrap(&self) -> Option<bool> {
+        match *self {
+            Command::LatestPrice { bootstrap }
+            | Command::Price { bootstrap, .. }
+            | Command::Iv { bootstrap, .. }
+            | Command::Ladder { bootstrap, .. } => Some(bootstrap),
+            _ => None,
+        }
+    }
+
     /// The name to prefix log files with
     pub fn log_name(&self) -> &'static str {
         match *self {
             Command::InitializePriceData { .. } => "init-price-data",
             Command::UpdatePriceData { .. } => "update-price-data",
+            Command::PriceDataInfo { .. } => "price-data-info",
             Command::LatestPrice { .. } => "latest-price",
             Command::Price { .. } => "price",
             Command::Iv { .. } => "iv",
+            Command::Ladder { .. } => "ladder",
             Command::Connect { .. } => "connect",
             Command::History { .. } => "history",
             Command::TaxHistory { .. } => "tax-history",
+            Command::Lots { .. } => "lots",
+            Command::CompareTaxStrategies { .. } => "compare-tax-strategies",
+            Command::Performance { .. } => "performance",
+            Command::FundingPlan { .. } => "funding-plan",
+            Command::Stress { .. } => "stress",
+            Command::PinRisk { .. } => "pin-risk",
+            Command::Quote { .. } => "quote",
+            Command::Close { .. } => "close",
+            Command::Watch { .. } => "watch",
+            Command::Slippage { .. } => "slippage",
+            Command::AuditVerify { .. } => "audit-verify",
+            Command::DiffTaxRuns { .. } => "diff-tax-runs",
+            Command::InitConfig { .. } => "init-config",
+            Command::Repl { .. } => "repl",
+            Command::Serve { .. } => "serve",
         }
     }
 }
@@ -236,7 +1603,10 @@ fn usage(invocation: &str) -> ! {
     for (cmd, help, _) in COMMANDS {
         eprintln!("    {invocation} {cmd} {help}");
     }
-    process::exit(1)
+    eprintln!();
+    eprintln!("Global options, given before the command: [--quiet] [--json-errors]");
+    eprintln!("Exit codes: {EXIT_OK} ok, {EXIT_WARNINGS} warnings logged, {EXIT_ERROR} errors.");
+    process::exit(EXIT_ERROR.into())
 }
 
 struct DashOpt(u8);
@@ -285,3 +1655,174 @@ where
         }
     }
 }
+
+/// Volatility (as a fraction) above which a bare `-v` argument is assumed to
+/// be a percentage given without its `%` sign
+pub(crate) const MAX_PLAUSIBLE_VOL: f64 = 5.0;
+
+/// A positive USD amount: `1234.56`, `$1,234.56` or `1234.56usd`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct UsdAmount(pub(crate) Price);
+
+impl FromStr for UsdAmount {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let lower = s.trim().to_ascii_lowercase();
+        let number = lower.strip_suffix("usd").unwrap_or(&lower).trim();
+        let price = Price::from_str(number).map_err(|_| {
+            format!("invalid USD amount {s}; accepted formats: 1234.56, $1,234.56, 1234.56usd")
+        })?;
+        if price <= Price::ZERO {
+            return Err(format!("USD amount {s} must be positive"));
+        }
+        Ok(UsdAmount(price))
+    }
+}
+
+/// A positive order size: a number of contracts (`50`, `50c` or `50contracts`)
+/// or an amount of bitcoin (`0.5btc`)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct OrderSize(Quantity);
+
+impl OrderSize {
+    /// The number of contracts of the given size which make up the order
+    fn contracts(&self, contract_size: ContractSize) -> Result<i64, String> {
+        match self.0 {
+            Quantity::Bitcoin(btc) => {
+                let sats_per_contract = 1_000_000 * contract_size.in_minis();
+                if btc.to_sat() % sats_per_contract != 0 {
+                    return Err(format!(
+                        "{} is not a whole number of {} contracts",
+                        btc.display_in(bitcoin::Denomination::Bitcoin)
+                            .show_denomination(),
+                        if contract_size == ContractSize::Mini {
+                            "mini"
+                        } else {
+                            "full-size"
+                        },
+                    ));
+                }
+                Ok(btc.to_sat() / sats_per_contract)
+            }
+            Quantity::Contracts(n) => Ok(n),
+            Quantity::Zero
+            | Quantity::Cents(_)
+            | Quantity::Ether(_)
+            | Quantity::EthContracts(_) => unreachable!("not produced by from_str"),
+        }
+    }
+}
+
+impl FromStr for OrderSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let lower = s.trim().to_ascii_lowercase();
+        let err = || format!("invalid order size {s}; accepted formats: 50, 50c, 0.5btc");
+        let qty = if let Some(btc) = lower.strip_suffix("btc") {
+            let btc =
+                bitcoin::SignedAmount::from_str_in(btc.trim(), bitcoin::Denomination::Bitcoin)
+                    .map_err(|_| err())?;
+            if btc.is_positive() {
+                Quantity::Bitcoin(btc)
+            } else {
+                Quantity::Zero
+            }
+        } else {
+            let n = lower
+                .strip_suffix("contracts")
+                .or_else(|| lower.strip_suffix('c'))
+                .unwrap_or(&lower);
+            match i64::from_str(n.trim()).map_err(|_| err())? {
+                n if n > 0 => Quantity::Contracts(n),
+                _ => Quantity::Zero,
+            }
+        };
+        if qty == Quantity::Zero {
+            return Err(format!("order size {s} must be positive"));
+        }
+        Ok(OrderSize(qty))
+    }
+}
+
+/// A percentage, given as `65%` or as a bare number
+///
+/// Some flags have historically taken bare numbers as percentages and others
+/// as fractions, so the interpretation of a bare number is up to the caller.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) struct Percent {
+    value: f64,
+    has_sign: bool,
+}
+
+impl Percent {
+    /// The percentage as a fraction, interpreting a bare number as a percentage
+    /// if `bare_is_percent` is set and as a fraction otherwise
+    pub(crate) fn fraction(&self, bare_is_percent: bool) -> f64 {
+        if self.has_sign || bare_is_percent {
+            self.value / 100.0
+        } else {
+            self.value
+        }
+    }
+}
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.value, f)?;
+        if self.has_sign {
+            f.write_str("%")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Percent {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let trimmed = s.trim();
+        let (number, has_sign) = match trimmed.strip_suffix('%') {
+            Some(number) => (number.trim(), true),
+            None => (trimmed, false),
+        };
+        match f64::from_str(number) {
+            Ok(value) if value.is_finite() => Ok(Percent { value, has_sign }),
+            _ => Err(format!(
+                "invalid percentage {s}; accepted formats: 65%, or a bare number"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        let usd = |s: &str| UsdAmount::from_str(s).map(|amt| amt.0);
+        let expected = Price::from_str("1234.56").unwrap();
+        assert_eq!(usd("1234.56"), Ok(expected));
+        assert_eq!(usd("$1,234.56"), Ok(expected));
+        assert_eq!(usd("1234.56USD"), Ok(expected));
+        assert!(usd("-5").is_err());
+        assert!(usd("12btc").unwrap_err().contains("accepted formats"));
+
+        let size = |s: &str, cs| OrderSize::from_str(s).and_then(|sz| sz.contracts(cs));
+        assert_eq!(size("50", ContractSize::Mini), Ok(50));
+        assert_eq!(size("50c", ContractSize::Full), Ok(50));
+        assert_eq!(size("0.5btc", ContractSize::Mini), Ok(50));
+        assert_eq!(size("2 BTC", ContractSize::Full), Ok(2));
+        assert!(size("0.5btc", ContractSize::Full).is_err());
+        assert!(size("0.005btc", ContractSize::Mini).is_err());
+        assert!(size("0c", ContractSize::Mini).is_err());
+        assert!(size("1.5c", ContractSize::Mini).is_err());
+
+        let pct = |s: &str, bare| Percent::from_str(s).unwrap().fraction(bare);
+        assert_eq!(pct("65%", false), 0.65);
+        assert_eq!(pct("65%", true), 0.65);
+        assert_eq!(pct("0.65", false), 0.65);
+        assert_eq!(pct("65", true), 0.65);
+        assert!(Percent::from_str("sixty%").is_err());
+        assert!(Percent::from_str("NaN").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Clock Skew
+//!
+//! Price staleness and days-to-expiry are computed against the local clock,
+//! so if it is wrong, we raise bogus staleness warnings and misprice options
+//! close to expiry. To catch this, the threads reading the Coinbase and LX
+//! feeds record the difference between the timestamps on incoming messages
+//! and the local time at which they arrived. If the median difference stays
+//! beyond [`THRESHOLD_MS`], we assume the local clock is wrong and correct
+//! [`UtcTime::now`] by it. The correction then follows the estimate until it
+//! falls below [`RELEASE_MS`], so that an estimate hovering around the
+//! threshold does not switch the correction on and off.
+//!
+//! Network latency makes messages appear to come from slightly in the past,
+//! so the estimate is biased by a fraction of a second. This is well within
+//! the threshold.
+//!
+
+use crate::units::UtcTime;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Number of recent samples kept from each source
+const WINDOW: usize = 50;
+/// Minimum number of samples from a source before we use it
+const MIN_SAMPLES: usize = 10;
+/// Skew, in milliseconds, beyond which we correct the local clock
+pub const THRESHOLD_MS: i64 = 2_000;
+/// Skew, in milliseconds, below which we stop correcting the local clock
+pub const RELEASE_MS: i64 = 1_000;
+
+/// A source of remote timestamps
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Source {
+    /// The Coinbase ticker
+    Coinbase,
+    /// The LX datafeed
+    LedgerX,
+}
+
+/// Estimate of the local clock's skew
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Estimate {
+    /// Amount by which the local clock is behind the exchanges' (negative if
+    /// it is ahead)
+    pub skew: chrono::Duration,
+    /// Number of samples the estimate is based on
+    pub samples: usize,
+}
+
+impl Estimate {
+    /// The correction to apply to the local clock given this estimate and
+    /// the correction currently applied
+    pub fn correction(&self, current: chrono::Duration) -> chrono::Duration {
+        let limit = if current.is_zero() {
+            THRESHOLD_MS
+        } else {
+            RELEASE_MS
+        };
+        if self.skew.num_milliseconds().abs() > limit {
+            self.skew
+        } else {
+            chrono::Duration::zero()
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = self.skew.num_milliseconds();
+        write!(
+            f,
+            "local clock {} by {:.3}s ({} samples)",
+            if ms > 0 { "behind" } else { "ahead" },
+            ms.abs() as f64 / 1000.0,
+            self.samples,
+        )
+    }
+}
+
+/// Recent differences, in milliseconds, between remote and local time
+#[derive(Debug, Default)]
+struct Samples {
+    coinbase: VecDeque<i64>,
+    ledgerx: VecDeque<i64>,
+}
+
+/// Estimator of the local clock's skew, shared between the threads which
+/// record samples and the main loop
+#[derive(Clone, Debug, Default)]
+pub struct SkewEstimator {
+    samples: Arc<Mutex<Samples>>,
+}
+
+impl SkewEstimator {
+    /// Creates a new estimator with no samples
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records the timestamp of a message which has just arrived
+    pub fn record(&self, source: Source, remote: UtcTime) {
+        self.record_at(source, remote, UtcTime::now_uncorrected());
+    }
+
+    /// Records the timestamp of a message which arrived at `local`
+    fn record_at(&self, source: Source, remote: UtcTime, local: UtcTime) {
+        let mut samples = self.samples.lock().unwrap();
+        let queue = match source {
+            Source::Coinbase => &mut samples.coinbase,
+            Source::LedgerX => &mut samples.ledgerx,
+        };
+        if queue.len() == WINDOW {
+            queue.pop_front();
+        }
+        queue.push_back((remote - local).num_milliseconds());
+    }
+
+    /// Estimates the skew, as the average over each source with enough
+    /// samples of the median difference between remote and local time
+    pub fn estimate(&self) -> Option<Estimate> {
+        let samples = self.samples.lock().unwrap();
+        let mut medians = vec![];
+        let mut n = 0;
+        for queue in [&samples.coinbase, &samples.ledgerx] {
+            if queue.len() < MIN_SAMPLES {
+                continue;
+            }
+            let mut sorted: Vec<i64> = queue.iter().copied().collect();
+            sorted.sort_unstable();
+            medians.push(sorted[sorted.len() / 2]);
+            n += queue.len();
+        }
+        if medians.is_empty() {
+            return None;
+        }
+        let ms = medians.iter().sum::<i64>() / medians.len() as i64;
+        Some(Estimate {
+            skew: chrono::Duration::milliseconds(ms),
+            samples: n,
+        })
+    }
+
+    /// Re-estimates the skew and updates the correction applied by
+    /// [`UtcTime::now`], logging the estimate and any change in correction
+    ///
+    /// Only starting to correct the clock raises an alert; following the
+    /// estimate after that is just logged.
+    pub fn compensate(&self) {
+        let estimate = match self.estimate() {
+            Some(estimate) => estimate,
+            None => {
+                info!("Clock skew: not enough samples to estimate");
+                return;
+            }
+        };
+        info!("Clock skew: {}", estimate);
+        let old = UtcTime::clock_correction();
+        let new = estimate.correction(old);
+        if new != old {
+            if new.is_zero() {
+                info!(
+                    "Clock skew back within {}ms; no longer correcting local clock.",
+                    RELEASE_MS
+                );
+            } else if old.is_zero() {
+                let msg = format!("Clock skew: {estimate}; correcting local clock.");
+                warn!("{}", msg);
+                crate::http::post_to_prowl(&msg);
+            } else {
+                info!(
+                    "Adjusting local clock correction to {}ms.",
+                    new.num_milliseconds()
+                );
+            }
+            UtcTime::set_clock_correction(new);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let est = SkewEstimator::new();
+        let local = UtcTime::parse_coinbase("2024-03-29T20:00:00Z").unwrap();
+        let ms = chrono::Duration::milliseconds;
+
+        // Not enough samples
+        for _ in 0..MIN_SAMPLES - 1 {
+            est.record_at(Source::Coinbase, local + ms(5_000), local);
+        }
+        assert_eq!(est.estimate(), None);
+
+        // Coinbase alone, with an outlier which the median ignores
+        est.record_at(Source::Coinbase, local - ms(60_000), local);
+        let estimate = est.estimate().unwrap();
+        assert_eq!(estimate.skew, ms(5_000));
+        assert_eq!(estimate.samples, MIN_SAMPLES);
+        assert_eq!(estimate.correction(chrono::Duration::zero()), ms(5_000));
+        assert_eq!(
+            estimate.to_string(),
+            "local clock behind by 5.000s (10 samples)"
+        );
+
+        // LX disagrees, so the estimate is the average of both
+        for _ in 0..MIN_SAMPLES {
+            est.record_at(Source::LedgerX, local + ms(3_000), local);
+        }
+        let estimate = est.estimate().unwrap();
+        assert_eq!(estimate.skew, ms(4_000));
+        assert_eq!(estimate.samples, 2 * MIN_SAMPLES);
+
+        // Old samples fall out of the window, and small skews are not corrected
+        for _ in 0..WINDOW {
+            est.record_at(Source::Coinbase, local - ms(500), local);
+            est.record_at(Source::LedgerX, local - ms(300), local);
+        }
+        let estimate = est.estimate().unwrap();
+        assert_eq!(estimate.skew, ms(-400));
+        assert_eq!(
+            estimate.correction(chrono::Duration::zero()),
+            chrono::Duration::zero()
+        );
+
+        // Between the thresholds, whether we correct depends on whether we are
+        // already correcting
+        let estimate = Estimate {
+            skew: ms(1_500),
+            samples: 2 * WINDOW,
+        };
+        assert_eq!(
+            estimate.correction(chrono::Duration::zero()),
+            chrono::Duration::zero()
+        );
+        assert_eq!(estimate.correction(ms(2_500)), ms(1_500));
+    }
+}
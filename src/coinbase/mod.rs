@@ -14,15 +14,22 @@
 
 //! Coinbase
 //!
-//! Data Structures etc for the Coinbase Websockets API
+//! Data Structures etc for the Coinbase Websockets API, plus a one-shot REST
+//! lookup of the ticker for commands which do not run the websocket
 
+use crate::clock::{self, SkewEstimator};
 use crate::price::BitcoinPrice;
+use crate::queue::Sender;
+use crate::supervisor::Heartbeat;
 use crate::units::UtcTime;
+use anyhow::Context;
 use log::info;
 use serde::Deserialize;
-use std::sync::mpsc::Sender;
 use std::thread;
 
+/// Name of the Coinbase ticker as a price source
+pub const PRICE_SOURCE: &str = "Coinbase";
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 struct SubscriptionChannel {
@@ -38,7 +45,7 @@ enum CoinbaseMsg {
         best_bid: crate::units::Price,
         #[serde(deserialize_with = "crate::units::deserialize_dollars")]
         best_ask: crate::units::Price,
-        #[serde(deserialize_with = "crate::units::deserialize_datetime")]
+        #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
         time: UtcTime,
     },
     Subscriptions {
@@ -47,7 +54,63 @@ enum CoinbaseMsg {
 }
 //{"type":"subscriptions","channels":[{"name":"ticker","product_ids":["BTC-USD"]}]}
 
-pub fn spawn_ticker_thread(tx: Sender<crate::connect::Message>) {
+/// Price reference given by a ticker message: the midpoint of the best bid
+/// and best ask, at the time of the message
+fn ticker_price(
+    best_bid: crate::units::Price,
+    best_ask: crate::units::Price,
+    time: UtcTime,
+) -> BitcoinPrice {
+    BitcoinPrice {
+        btc_price: best_bid.half() + best_ask.half(),
+        timestamp: time,
+    }
+}
+
+/// Reply to a REST request for the BTC-USD ticker
+#[derive(Deserialize, Debug)]
+struct RestTicker {
+    #[serde(deserialize_with = "crate::units::deserialize_dollars")]
+    bid: crate::units::Price,
+    #[serde(deserialize_with = "crate::units::deserialize_dollars")]
+    ask: crate::units::Price,
+    #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
+    time: UtcTime,
+}
+
+/// Fetches the current price reference from the Coinbase REST API
+pub fn fetch_price() -> anyhow::Result<BitcoinPrice> {
+    let url = "https://api.exchange.coinbase.com/products/BTC-USD/ticker";
+    let ticker: RestTicker =
+        crate::http::get_json(url, None).context("getting ticker from Coinbase")?;
+    Ok(ticker_price(ticker.bid, ticker.ask, ticker.time))
+}
+
+/// Parses a message from the Coinbase websocket, e.g. as read back from the
+/// Coinbase log, returning the price reference if it is a ticker message
+///
+/// Returns an error if it is not a Coinbase message at all.
+pub fn parse_message(msg: &str) -> Result<Option<BitcoinPrice>, serde_json::Error> {
+    Ok(match serde_json::from_str(msg)? {
+        CoinbaseMsg::Ticker {
+            best_bid,
+            best_ask,
+            time,
+        } => Some(ticker_price(best_bid, best_ask, time)),
+        CoinbaseMsg::Subscriptions { .. } => None,
+    })
+}
+
+/// Starts a thread which feeds Coinbase's BTC-USD ticker into the main loop as
+/// price references
+///
+/// The thread exits once its heartbeat is retired. If a skew estimator is
+/// given, the timestamp of each ticker message is recorded in it.
+pub fn spawn_ticker_thread(
+    tx: Sender<crate::connect::Message>,
+    heartbeat: Heartbeat,
+    skew: Option<SkewEstimator>,
+) -> thread::JoinHandle<()> {
     thread::spawn(move || loop {
         let mut coinbase_sock = tungstenite::client::connect("wss://ws-feed.exchange.coinbase.com")
             .expect("failed to connect to Coinbase");
@@ -62,18 +125,12 @@ pub fn spawn_ticker_thread(tx: Sender<crate::connect::Message>) {
             "{\"type\":\"subscribe\",\"product_ids\": [\"BTC-USD\"],\"channels\": [\"ticker\"]}".to_string()
         )).unwrap();
 
-        // We maintain a "shutdown price reference" which is updated whenever the price
-        // moves by more than 5% in either direction. If such a movement happens too
-        // quickly then we do an emergency shutdown.
-        //
-        // This algorithm is not great: it allows, for example, the price to drop 4% (not
-        // triggering an update to the reference) and then increase 8% (staying within 5%
-        // of the reference despite actually moving much more). However, the goal of this
-        // is mainly to detect bad data from the ticker, which should show up as a massive
-        // instantaneous price movement. Natural volatility, as long as it doesn't go
-        // wildly out of range, is fine and probably even good for us.
-        let mut shutdown_price_ref: Option<BitcoinPrice> = None;
         while let Ok(tungstenite::protocol::Message::Text(msg)) = coinbase_sock.0.read_message() {
+            if heartbeat.is_retired() {
+                info!("Coinbase thread replaced; exiting.");
+                return;
+            }
+            heartbeat.beat();
             info!(target: "cb_datafeed", "{}", msg);
             match serde_json::from_str(&msg).unwrap() {
                 CoinbaseMsg::Subscriptions { channels } => {
@@ -86,37 +143,19 @@ pub fn spawn_ticker_thread(tx: Sender<crate::connect::Message>) {
                     best_ask,
                     time,
                 } => {
-                    let mid = best_bid.half() + best_ask.half();
-                    let new_price = BitcoinPrice {
-                        btc_price: mid,
-                        timestamp: time,
-                    };
-
-                    let ref_price = shutdown_price_ref.unwrap_or(new_price);
-                    let ratio = new_price.btc_price / ref_price.btc_price;
-                    // 5% in 5 minutes is an "emergency shutdown" situation. Either the
-                    // price feed has glitched out or the price is doing something wild
-                    // and we don't want to be automatically trading anyway.
-                    if ratio < 0.95 || ratio > 1.05 {
-                        if new_price.timestamp - ref_price.timestamp
-                            > chrono::Duration::seconds(300)
-                        {
-                            tx.send(crate::connect::Message::EmergencyShutdown {
-                                msg: format!(
-                                    "Rapid price movement: from {ref_price} to {new_price}"
-                                ),
-                            })
-                            .unwrap();
-                        }
-                        shutdown_price_ref = Some(new_price);
+                    if let Some(ref skew) = skew {
+                        skew.record(clock::Source::Coinbase, time);
                     }
-                    tx.send(crate::connect::Message::PriceReference(new_price))
-                        .unwrap();
+                    tx.send(crate::connect::Message::PriceReference(
+                        PRICE_SOURCE,
+                        ticker_price(best_bid, best_ask, time),
+                    ))
+                    .unwrap();
                 }
             }
         }
         info!("Restarting connection to coinbase.");
-    });
+    })
 }
 
 #[cfg(test)]
@@ -129,4 +168,26 @@ mod tests {
         let parsed = UtcTime::parse_coinbase(cb_datetime).unwrap();
         assert_eq!(parsed.to_string(), "2024-01-08 04:07:11.750237 UTC",);
     }
+
+    #[test]
+    fn parse_messages() {
+        let ticker = r#"{"type":"ticker","sequence":75286457201,"product_id":"BTC-USD","price":"46931.45","open_24h":"43938.47","volume_24h":"23133.95707161","low_24h":"43626.01","high_24h":"47261.39","volume_30d":"395624.57004427","best_bid":"46931.44","best_bid_size":"0.07432451","best_ask":"46931.46","best_ask_size":"0.01160000","side":"buy","time":"2024-01-08T04:07:11.750237Z","trade_id":594563377,"last_size":"0.00011472"}"#;
+        let price = parse_message(ticker).unwrap().unwrap();
+        assert_eq!(price.btc_price.to_string(), "46931.45");
+        assert_eq!(
+            price.timestamp,
+            UtcTime::parse_coinbase("2024-01-08T04:07:11.750237Z").unwrap()
+        );
+
+        let subscriptions =
+            r#"{"type":"subscriptions","channels":[{"name":"ticker","product_ids":["BTC-USD"]}]}"#;
+        assert_eq!(parse_message(subscriptions).unwrap(), None);
+        // LX messages are not Coinbase messages
+        assert!(parse_message(r#"{"type": "heartbeat", "ticks": 3535409}"#).is_err());
+
+        let rest = r#"{"ask":"46931.46","bid":"46931.44","volume":"23133.95707161","trade_id":594563377,"price":"46931.45","size":"0.00011472","time":"2024-01-08T04:07:11.750237Z"}"#;
+        let ticker: RestTicker = serde_json::from_str(rest).unwrap();
+        let price = ticker_price(ticker.bid, ticker.ask, ticker.time);
+        assert_eq!(price.btc_price.to_string(), "46931.45");
+    }
 }
@@ -17,16 +17,58 @@
 //! When calling `trade-tracker connect` the tool will run indefinitely,
 //! talking to LX and to other services. This is its main loop.
 //!
+//! Without an API key, the loop runs in "watch-only" mode, using only LX's
+//! public endpoints: it tracks contracts and books, logs interesting contracts
+//! and records prices, but never looks at balances or positions, and drops
+//! every order, roll and cancellation.
+//!
+//! In replay mode, the loop instead reads the LX datafeed and Coinbase logs
+//! of an earlier session (see [`crate::replay`]), in simulated time. Nothing
+//! is sent to the network: orders, rolls and cancellations are logged, and
+//! the balances come from the datafeed rather than from balance syncs.
+//! Contracts come from the contract cache, and books only from the datafeed,
+//! since their historical state cannot be fetched. With `--compare`, the
+//! orders placed are then compared against those which the earlier session
+//! actually placed (see [`crate::resimulation`]).
+//!
 
+use crate::activity::{DailyActivity, HeartbeatDecision};
+use crate::audit;
+use crate::clock;
+use crate::emergency;
+use crate::events;
 use crate::http;
-use crate::ledgerx::{self, datafeed, LedgerX};
-use crate::price::BitcoinPrice;
-use crate::units::{Price, Quantity, Underlying, UtcTime};
+use crate::ledgerx::{
+    self, contract_cache::ContractCache, datafeed, funding, goals, listings, LedgerX,
+};
+use crate::loss_limit::LossLimit;
+use crate::price::{self, BitcoinPrice, PriceBoard};
+use crate::queue::{self, Prioritize, Priority};
+use crate::replay::Replay;
+use crate::resimulation;
+use crate::schema;
+use crate::storage::{self, Storage};
+use crate::supervisor::{Heartbeat, Supervised};
+use crate::trading_window::{self, TradingWindow};
+use crate::units::{Price, Underlying, UtcTime};
 use anyhow::Context as _;
-use log::{info, warn};
-use std::sync::mpsc::{channel, Sender};
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
 
+/// Maximum number of droppable messages to queue for the main loop
+const MESSAGE_QUEUE_CAPACITY: usize = 10_000;
+/// Seconds without a ticker message after which a price feed thread is restarted
+const TICKER_MAX_SILENCE_SECS: i64 = 300;
+/// Seconds without a beat after which the other helper threads are restarted
+const HELPER_MAX_SILENCE_SECS: i64 = 600;
+/// Consecutive balance sync failures after which we send a notification
+const BALANCE_FAILURE_ALERT: u32 = 3;
+/// Minutes between the heartbeats which the clock thread always triggers
+pub const CLOCK_HEARTBEAT_MINS: i64 = 120;
+
 // Because of DST we can't be super precise about when the market is actually
 // open, without importing a timezone database and doing a bunch of crap. So
 // we just swag that it's open from 1300 to 2100.
@@ -37,6 +79,151 @@ fn market_is_open(now: UtcTime) -> bool {
     nyt >= open && nyt < close
 }
 
+/// Settings for the main loop, mostly set from the command line
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Percentage of a contract's open interest above which we warn about our position
+    pub max_oi_share_pct: u32,
+    /// Settings for alerts on short positions going in the money
+    pub itm: ledgerx::itm::Settings,
+    /// Settings for rolling expiring short positions into the next expiry
+    pub roll: ledgerx::roll::Settings,
+    /// Yield below which interesting contracts are not logged
+    pub yield_threshold: ledgerx::interesting::YieldThreshold,
+    /// Settings for alerts on bids far above the model price
+    pub mispricing: ledgerx::mispricing::Settings,
+    /// Settings for skewing standing asks based on our inventory
+    pub skew: ledgerx::skew::Settings,
+    /// Caps on our aggregate short vega and gamma
+    pub greek_limits: ledgerx::greek_limits::Settings,
+    /// Days-to-expiry limits on the options we trade
+    pub dte_filter: ledgerx::interesting::DteFilter,
+    /// The time from which the return of a standing ask is annualized
+    pub arr_reference: ledgerx::interesting::ArrReference,
+    /// What to do with non-taker orders which would cross the book
+    pub post_only: ledgerx::post_only::Policy,
+    /// Times within market hours during which we may open orders, other than
+    /// orders which only reduce our positions; if empty, we may open orders
+    /// whenever the market is open
+    pub trading_windows: Vec<TradingWindow>,
+    /// Age (in seconds) beyond which we will not quote based on a price reference
+    pub max_price_age_secs: u32,
+    /// Divergence between price sources (in basis points) beyond which the
+    /// outlying source is dropped from their consensus, or, if too few would
+    /// remain, we will not quote at all
+    ///
+    /// By default Coinbase is our only source, so this has no effect. With two
+    /// sources there is no telling which of them is wrong, so any divergence
+    /// halts quoting until they agree again; enable both Kraken and Bitstamp
+    /// for a third source which lets us drop a single bad feed.
+    pub max_price_divergence_bps: u32,
+    /// Age (in seconds) beyond which we will not quote based on our last
+    /// successful balance sync
+    pub max_balance_age_secs: u32,
+    /// If set, a file whose existence disables all quoting and taking, other
+    /// than orders which only reduce our positions
+    pub kill_switch_file: Option<PathBuf>,
+    /// If set, the loss since the start of a session beyond which we stop
+    /// quoting and cancel our orders, other than closes, until the next session
+    pub max_daily_loss: Option<Price>,
+    /// If set, a file which is polled for requests to close short positions
+    pub close_request_file: Option<PathBuf>,
+    /// Settings for monitoring the exchange's status page
+    pub exchange_status: ledgerx::exchange_status::Settings,
+    /// Whether to take a price reference from Kraken's ticker; off by default
+    pub kraken: bool,
+    /// Whether to take a price reference from Bitstamp's trades; off by default
+    pub bitstamp: bool,
+    /// Weights of price sources in the consensus, if not 1
+    pub price_weights: Vec<price::SourceWeight>,
+    /// Our fee schedule, if not the base fee at every volume
+    pub fee_tiers: Vec<ledgerx::fees::FeeTier>,
+    /// If set, a CSV file to which daily activity summaries are appended
+    pub activity_file: Option<PathBuf>,
+    /// If set, a CSV file to which fills are appended, for slippage analysis
+    pub fill_file: Option<PathBuf>,
+    /// If set, a file to which heartbeat decisions and fills are appended as
+    /// JSON records
+    pub record_file: Option<PathBuf>,
+    /// If set, where to stream JSON records of everything that happens
+    pub emit_events: Option<events::Destination>,
+    /// If set, a SQLite database in which every order update, fill and
+    /// balance is stored
+    pub database_file: Option<PathBuf>,
+    /// If set, a CSV file listing USD deposits we expect to arrive
+    pub deposits_file: Option<PathBuf>,
+    /// If set, a JSON file in which our BTC reacquisition goal is kept
+    pub goal_file: Option<PathBuf>,
+    /// If set, the price data directory to which live prices are appended
+    pub price_data_dir: Option<PathBuf>,
+    /// If set, the directory in which LX market data is archived at each
+    /// market close and at shutdown
+    pub market_data_dir: Option<PathBuf>,
+    /// Whether to archive LX market data in the data directory
+    pub archive_market_data: bool,
+    /// Minimum interval (in seconds) between price samples recorded to the
+    /// price data; 0 (the default) to not record them
+    pub price_sample_secs: u32,
+    /// Age (in seconds) within which every book should be refreshed from the
+    /// book state endpoint during market hours; 0 to refresh only after gaps
+    pub book_refresh_secs: u32,
+    /// Where to send alerts, and where to record orders left live by a failed cancel
+    pub emergency: emergency::Settings,
+    /// Chat usernames whose block trade proposals we evaluate
+    pub block_counterparties: Vec<String>,
+    /// If nonempty, logs of the LX datafeed and Coinbase ticker to replay in
+    /// simulated time, rather than connecting to anything
+    pub replay_files: Vec<PathBuf>,
+    /// If nonempty, record files or event streams of the session being
+    /// replayed, against which to compare the orders we would have placed
+    pub compare_files: Vec<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_oi_share_pct: 25,
+            itm: ledgerx::itm::Settings::default(),
+            roll: ledgerx::roll::Settings::default(),
+            yield_threshold: ledgerx::interesting::YieldThreshold::default(),
+            mispricing: ledgerx::mispricing::Settings::default(),
+            skew: ledgerx::skew::Settings::default(),
+            greek_limits: ledgerx::greek_limits::Settings::default(),
+            dte_filter: ledgerx::interesting::DteFilter::default(),
+            arr_reference: ledgerx::interesting::ArrReference::default(),
+            post_only: ledgerx::post_only::Policy::default(),
+            trading_windows: vec![],
+            max_price_age_secs: 300,
+            max_price_divergence_bps: 100,
+            max_balance_age_secs: 300,
+            kill_switch_file: None,
+            max_daily_loss: None,
+            close_request_file: None,
+            exchange_status: ledgerx::exchange_status::Settings::default(),
+            kraken: false,
+            bitstamp: false,
+            price_weights: vec![],
+            fee_tiers: vec![],
+            activity_file: None,
+            fill_file: None,
+            record_file: None,
+            emit_events: None,
+            database_file: None,
+            deposits_file: None,
+            goal_file: None,
+            price_data_dir: None,
+            market_data_dir: None,
+            archive_market_data: true,
+            price_sample_secs: 0,
+            book_refresh_secs: 3600,
+            emergency: emergency::Settings::default(),
+            block_counterparties: vec![],
+            replay_files: vec![],
+            compare_files: vec![],
+        }
+    }
+}
+
 /// A message to the main loop
 #[derive(Debug)]
 pub enum Message {
@@ -44,85 +231,167 @@ pub enum Message {
     LedgerX(datafeed::Object),
     /// A request to open an order.
     OpenOrder(ledgerx::json::CreateOrder),
+    /// A request to roll a short position, starting with its first leg.
+    Roll(ledgerx::roll::Roll),
+    /// A request to close a short position by taking offers.
+    ClosePosition(ledgerx::close::Request),
+    /// A request to cancel one of our open orders.
+    CancelOrder {
+        message_id: ledgerx::MessageId,
+        contract_id: ledgerx::ContractId,
+    },
     /// A new book state has been retrieved from the contract lookup thread.
     BookState(ledgerx::json::BookStateMessage),
-    /// An update from a price reference websocket
-    PriceReference(BitcoinPrice),
+    /// An update from a price reference websocket, with the name of its source
+    PriceReference(&'static str, BitcoinPrice),
     /// "Heartbeat" to wakes up the main thread for housekeeping
     Heartbeat,
     /// If heartbeats come in too quickly they are accumulated into a "delayed
     /// heartbeat", as a rate-limiting mechanism. This is because heartbeats
     /// happen on a timer but are also triggered by orderbook actions.
     DelayedHeartbeat { delay_til: UtcTime, ready: bool },
+    /// The kill switch has been flipped. While engaged, we continue to track
+    /// the market but do not open any orders, other than closes.
+    KillSwitch { engaged: bool, reason: String },
+    /// The exchange has reported an incident or trading halt. While degraded,
+    /// we continue to track the market but do not open any orders.
+    ExchangeDegraded { reason: String },
+    /// The exchange has recovered from an incident.
+    ExchangeRecovered,
     /// Something bad has happened elsewhere in the program and we need to
     /// cancel all open orders and shut down.
     EmergencyShutdown { msg: String },
 }
 
-/// Helper function to construct an initial LX tracker with all current contracts
-fn recreate_tracker(
-    initial_price: BitcoinPrice,
-    contract_thread_tx: &Sender<ledgerx::ContractId>,
-) -> LedgerX {
-    let all_contracts: Vec<ledgerx::Contract> =
-        http::get_json_from_data_field("https://api.ledgerx.com/trading/contracts", None)
-            .context("looking up list of contracts")
-            .expect("retrieving and parsing json from contract endpoint");
-    let mut tracker = LedgerX::new(initial_price);
-    for contr in all_contracts {
-        // For expired or non-BTC options, fetch the full book. Otherwise
-        // just record the contract's existence.
-        if contr.active() && contr.underlying() == Underlying::Btc {
-            contract_thread_tx
-                .send(contr.id())
-                .expect("book-states endpoint thread has not panicked");
+/// Identifies messages to the main loop which supersede each other in its queue
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum SupersedeKey {
+    PriceReference(&'static str),
+    BookTop(ledgerx::ContractId),
+    CancelOrder(ledgerx::MessageId),
+    Heartbeat,
+}
+
+impl Prioritize for Message {
+    type Key = SupersedeKey;
+
+    fn priority(&self) -> Priority {
+        match *self {
+            Message::EmergencyShutdown { .. }
+            | Message::KillSwitch { .. }
+            | Message::ExchangeDegraded { .. }
+            | Message::ExchangeRecovered => Priority::Urgent,
+            Message::LedgerX(datafeed::Object::Other) => Priority::Droppable,
+            // Heartbeats drive our cancels and limit checks, so are never
+            // dropped; they supersede each other instead, so do not pile up
+            Message::Heartbeat
+            | Message::LedgerX(..)
+            | Message::OpenOrder(..)
+            | Message::Roll(..)
+            | Message::ClosePosition(..)
+            | Message::CancelOrder { .. }
+            | Message::BookState(..)
+            | Message::PriceReference(..)
+            | Message::DelayedHeartbeat { .. } => Priority::Normal,
         }
-        tracker.add_contract(contr);
     }
-    info!("Loaded contracts. Watching feed.");
-    tracker
-}
 
-/// Helper function to attempt cancelling all orders, sending a text
-/// and panicking if this fails.
-fn cancel_all_orders(api_key: &str) {
-    if let Err(e) = http::lx_cancel_all_orders(api_key) {
-        http::post_to_prowl(&format!("Tried to cancel all orders and failed: {e}"));
-        panic!("Tried to cancel all orders and failed: {}", e);
+    fn supersede_key(&self) -> Option<SupersedeKey> {
+        match *self {
+            // Each price source's latest price supersedes only its own
+            Message::PriceReference(src, _) => Some(SupersedeKey::PriceReference(src)),
+            // Likewise each contract's latest top of book, which is not
+            // droppable, so that the newest top is never lost
+            Message::LedgerX(datafeed::Object::BookTop { contract_id, .. }) => {
+                Some(SupersedeKey::BookTop(contract_id))
+            }
+            // A cancellation may be requested twice in one heartbeat, e.g. for
+            // an abandoned roll leg; the second request would only fail
+            Message::CancelOrder { message_id, .. } => Some(SupersedeKey::CancelOrder(message_id)),
+            Message::Heartbeat => Some(SupersedeKey::Heartbeat),
+            _ => None,
+        }
     }
 }
 
-/// Starts the main loop and a couple utility threads. Returns a single `Sender`
-/// for control messages.
-///
-/// # Panics
-///
-/// Will panic if anything goes wrong during startup.
-pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) -> ! {
-    let (tx, rx) = channel();
-    let initial_time = UtcTime::now();
+/// Starts the clock thread, which triggers a heartbeat every couple of hours
+/// even if nothing else does
+fn spawn_clock_thread(tx: queue::Sender<Message>, hb: Heartbeat) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_heartbeat = UtcTime::now();
+        while !hb.is_retired() {
+            // Wake up regularly to beat, rather than sleeping for the full
+            // interval, so that the supervisor can tell we are alive.
+            thread::sleep(std::time::Duration::from_secs(60));
+            hb.beat();
+            let now = UtcTime::now();
+            if now - last_heartbeat >= chrono::Duration::minutes(CLOCK_HEARTBEAT_MINS) {
+                last_heartbeat = now;
+                tx.send(Message::Heartbeat).unwrap();
+            }
+        }
+    })
+}
 
-    // Before doing anything else, connect to a price reference and
-    // get an initial price. Otherwise we can't initialize our trade
-    // tracker etc.
-    crate::coinbase::spawn_ticker_thread(tx.clone());
-    let initial_price = match rx.recv() {
-        Ok(Message::PriceReference(price)) => price,
-        Ok(_) => unreachable!(),
-        Err(e) => panic!("Failed to get initial price reference: {}", e),
-    };
-    info!(target: "lx_btcprice", "{}", initial_price);
-    info!("BTC price: {}", initial_price);
-    info!("Risk-free rate: 4% (assumed)");
+/// Starts the contract lookup thread, which fetches the book state of each
+/// contract sent to it and passes it to the main loop
+fn spawn_contract_thread(
+    tx: queue::Sender<Message>,
+    rx: Receiver<ledgerx::ContractId>,
+    api_key: Option<String>,
+    hb: Heartbeat,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while !hb.is_retired() {
+            hb.beat();
+            let contract_id = match rx.recv_timeout(std::time::Duration::from_secs(60)) {
+                Ok(id) => id,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let reply: anyhow::Result<ledgerx::json::BookStateMessage> = http::get_json(
+                &format!("https://trade.ledgerx.com/api/book-states/{contract_id}"),
+                api_key.as_deref(),
+            )
+            .context("getting data from trading/contracts endpoint");
+            // Without credentials, a missing book is not worth dying over; we
+            // will still see the book fill in from the data feed.
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(e) if api_key.is_none() => {
+                    warn!("Failed to get book state for {}: {:#}", contract_id, e);
+                    continue;
+                }
+                Err(e) => panic!(
+                    "retreiving and parsing json from book-states endpoint: {:#}",
+                    e
+                ),
+            };
+            tx.send(Message::BookState(reply)).unwrap();
+        }
+    })
+}
 
-    // LedgerX websocket thread
-    let lx_tx = tx.clone();
-    let lx_api_key = api_key.clone();
+/// Starts a stand-in for the contract lookup thread during a replay, which
+/// discards every request, since historical book states cannot be fetched
+fn spawn_discard_thread(rx: Receiver<ledgerx::ContractId>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for contract_id in rx {
+            debug!("Replay; not fetching book state for {}", contract_id);
+        }
+    })
+}
+
+/// Starts the LX websocket thread, which passes every datafeed message to the
+/// main loop, reconnecting whenever the connection drops
+fn spawn_datafeed_thread(
+    tx: queue::Sender<Message>,
+    skew: clock::SkewEstimator,
+    url: String,
+) -> thread::JoinHandle<()> {
     thread::spawn(move || loop {
         let mut sock = loop {
-            match tungstenite::client::connect(format!(
-                "wss://api.ledgerx.com/ws?token={lx_api_key}",
-            )) {
+            match tungstenite::client::connect(&url) {
                 Ok(sock) => break sock,
                 Err(e) => {
                     warn!(
@@ -144,127 +413,714 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
                     break;
                 }
             };
-            lx_tx.send(Message::LedgerX(obj)).unwrap();
+            if let datafeed::Object::Order(ref order) = obj {
+                skew.record(clock::Source::LedgerX, order.updated_timestamp);
+            }
+            tx.send(Message::LedgerX(obj)).unwrap();
         }
-    });
+    })
+}
 
-    // Clock thread
-    let heartbeat_tx = tx.clone();
-    thread::spawn(move || loop {
-        thread::sleep(std::time::Duration::from_secs(120 * 60));
-        heartbeat_tx.send(Message::Heartbeat).unwrap();
-    });
+/// Helper function to ask the contract lookup thread for a contract's book state
+///
+/// If the thread has died, the supervisor will restart it on the next heartbeat;
+/// meanwhile the book will fill in from the data feed.
+fn request_book_state(contract_thread_tx: &Sender<ledgerx::ContractId>, cid: ledgerx::ContractId) {
+    if contract_thread_tx.send(cid).is_err() {
+        warn!(
+            "Contract lookup thread is down; not fetching book state for {}",
+            cid
+        );
+    }
+}
 
-    // Contract lookup thread
-    let contract_tx = tx.clone();
-    let contract_tx_api_key = api_key.clone();
-    let (contract_thread_tx, contract_thread_rx) = channel();
-    thread::spawn(move || {
-        for contract_id in contract_thread_rx.iter() {
-            let reply: ledgerx::json::BookStateMessage = http::get_json(
-                &format!("https://trade.ledgerx.com/api/book-states/{contract_id}"),
-                Some(&contract_tx_api_key),
-            )
-            .context("getting data from trading/contracts endpoint")
-            .expect("retreiving and parsing json from book-states endpoint");
-            contract_tx.send(Message::BookState(reply)).unwrap();
+/// Checks the consensus price for a movement too rapid to keep trading through
+///
+/// We maintain a "shutdown price reference" which is updated whenever the price
+/// moves by more than 5% in either direction. If such a movement happens too
+/// quickly then we do an emergency shutdown.
+///
+/// This algorithm is not great: it allows, for example, the price to drop 4% (not
+/// triggering an update to the reference) and then increase 8% (staying within 5%
+/// of the reference despite actually moving much more). However, the goal of this
+/// is mainly to detect bad data from the price feeds, which should show up as a
+/// massive instantaneous price movement. Natural volatility, as long as it doesn't
+/// go wildly out of range, is fine and probably even good for us.
+fn check_rapid_move(
+    shutdown_ref: &mut Option<BitcoinPrice>,
+    price: BitcoinPrice,
+) -> Option<String> {
+    let ref_price = shutdown_ref.unwrap_or(price);
+    let ratio = price.btc_price / ref_price.btc_price;
+    // 5% in 5 minutes is an "emergency shutdown" situation. Either the
+    // price feeds have glitched out or the price is doing something wild
+    // and we don't want to be automatically trading anyway.
+    if ratio < 0.95 || ratio > 1.05 {
+        *shutdown_ref = Some(price);
+        if price.timestamp - ref_price.timestamp > chrono::Duration::seconds(300) {
+            return Some(format!("Rapid price movement: from {ref_price} to {price}"));
         }
-    });
+    }
+    None
+}
 
-    // Get history to determine past BTC transactions. We attempt to "undo" any
-    // BTC sales by selling puts at a discount, and we use this history to
-    // determine how best to do that.
-    //
-    // This is not rational in terms of total account value (which optimally
-    // would require a memoryless strategy), but is rational if our goal is to
-    // minimize the amount if time we spend holding fewer bitcoins than we
-    // started with.
-    //
-    let mut net_btc = bitcoin::SignedAmount::ZERO;
-    let mut net_usd = Price::ZERO;
-    let mut recent_net_btc = bitcoin::SignedAmount::ZERO;
-    let mut recent_net_usd = Price::ZERO;
-    let mut min_average_price = Price::MAX;
-    if let Some(hist) = history {
-        for (time, event) in hist.events() {
-            use crate::ledgerx::history::Event;
-            use crate::units::BudgetAsset;
-
-            let delta_btc;
-            let delta_usd;
-            match event {
-                Event::Trade {
-                    asset, price, size, ..
-                } => {
-                    delta_usd = -*price * *size;
-                    if BudgetAsset::from(*asset) == BudgetAsset::Btc {
-                        delta_btc = size.btc_equivalent();
-                    } else {
-                        delta_btc = bitcoin::SignedAmount::ZERO;
-                    }
+/// Helper function to construct an initial LX tracker with all current contracts
+///
+/// During a replay, the contracts come from the contract cache instead.
+fn recreate_tracker(
+    prices: PriceBoard,
+    contract_thread_tx: &Sender<ledgerx::ContractId>,
+    api_key: Option<&str>,
+    settings: &Settings,
+    contract_cache: &mut ContractCache,
+) -> LedgerX {
+    // Note which contracts we knew about before refreshing the listing, so
+    // that we can report on new ones. If we knew about none, this is the
+    // first run and everything would be "new".
+    let first_run = contract_cache.is_empty();
+    let known: HashSet<ledgerx::ContractId> = contract_cache.ids().collect();
+    let all_contracts = if settings.replay_files.is_empty() {
+        contract_cache
+            .fetch_all_active()
+            .expect("retrieving and parsing json from contract endpoint")
+    } else {
+        contract_cache.unexpired_at(UtcTime::now())
+    };
+    if let Err(e) = contract_cache.save() {
+        warn!("Failed to save contract cache: {:#}", e);
+    }
+    if !first_run {
+        let (new, existing): (Vec<_>, Vec<_>) = all_contracts
+            .iter()
+            .cloned()
+            .partition(|contr| !known.contains(&contr.id()));
+        report_new_listings(&new, &existing, prices.last());
+    }
+
+    // Heartbeats only cancel orders the tracker has seen, so clear out any
+    // left live by an earlier process or an earlier tracker before we start
+    // afresh. During a replay there is nothing to cancel.
+    if settings.replay_files.is_empty() {
+        info!("Cancelling any orders left from before this tracker.");
+        cancel_orders(api_key, &[], settings);
+    }
+
+    let mut tracker = LedgerX::new(
+        prices,
+        settings.max_oi_share_pct,
+        settings.itm,
+        settings.roll,
+        settings.yield_threshold,
+        settings.mispricing,
+        settings.skew,
+        settings.greek_limits,
+        settings.dte_filter,
+        settings.arr_reference,
+    );
+    for contr in all_contracts {
+        // For expired or non-BTC options, fetch the full book. Otherwise
+        // just record the contract's existence.
+        if contr.active() && contr.underlying() == Underlying::Btc {
+            request_book_state(contract_thread_tx, contr.id());
+        }
+        tracker.add_contract(contr);
+    }
+
+    // Load our current positions, so that we can track our share of open interest
+    let mut next_url =
+        api_key.map(|_| "https://api.ledgerx.com/trading/positions?limit=200".to_string());
+    while let Some(url) = next_url {
+        let positions: ledgerx::history::Positions = http::get_json(&url, api_key)
+            .context("looking up current positions")
+            .expect("retrieving and parsing json from positions endpoint");
+        for (cid, size) in positions.open_positions() {
+            tracker.set_own_position(cid, size);
+        }
+        next_url = positions.next_url();
+    }
+
+    info!("Loaded contracts. Watching feed.");
+    tracker
+}
+
+/// Helper function to log and send a notification about newly-listed options
+fn report_new_listings<'a, I>(new: &[ledgerx::Contract], existing: I, price: BitcoinPrice)
+where
+    I: IntoIterator<Item = &'a ledgerx::Contract>,
+{
+    let report = listings::Report::new(new, existing, price, UtcTime::now());
+    if report.is_empty() {
+        return;
+    }
+    for line in report.to_string().lines() {
+        info!("{}", line);
+    }
+    http::post_to_prowl(&report.notification());
+}
+
+/// Helper function to report the day's activity at market close
+fn report_daily_activity(activity: &DailyActivity, now: UtcTime, settings: &Settings) {
+    let summary = activity.summary(now).to_string();
+    for line in summary.lines() {
+        info!("{}", line);
+    }
+    http::post_to_prowl(&summary);
+    if let Some(ref path) = settings.activity_file {
+        if let Err(e) = activity.append_to_csv(path, now) {
+            warn!("Failed to record daily activity: {:#}", e);
+        }
+    }
+}
+
+/// Helper function for heartbeats during which we may only close positions,
+/// i.e. while the kill switch is engaged or the loss limit is hit
+///
+/// As outside the trading windows, we keep tracking the market; any taker
+/// orders other than closes are dropped when they reach the main loop. Our
+/// open orders other than closes are cancelled.
+fn closes_only_heartbeat(
+    snapshot: &mut ledgerx::snapshot::Snapshot,
+    tracker: &mut LedgerX,
+    activity: &mut DailyActivity,
+    tx: &queue::Sender<Message>,
+) {
+    snapshot.log_open_orders();
+    let (usd, btc) = snapshot.log_interesting_contracts(tx);
+    tracker.dock_balances(usd, btc);
+    let n_cancelled = snapshot.cancel_opening_orders(tx);
+    activity.record_cancellations(n_cancelled);
+}
+
+/// Helper function to record a heartbeat decision, both in the day's activity
+/// and in the record file
+fn record_heartbeat(
+    activity: &mut DailyActivity,
+    tracker: &LedgerX,
+    decision: HeartbeatDecision,
+    now: UtcTime,
+    settings: &Settings,
+) {
+    activity.record_heartbeat(decision);
+    let record = schema::Record::decision(
+        now,
+        decision,
+        tracker.prices().last().btc_price,
+        tracker.open_order_count(),
+        if decision == HeartbeatDecision::Traded {
+            Some(settings.arr_reference)
+        } else {
+            None
+        },
+    );
+    append_record(&record, settings);
+}
+
+/// Helper function to append a JSON record to the record file, if any, and
+/// to the event stream
+fn append_record(record: &schema::Record, settings: &Settings) {
+    events::emit(record);
+    if let Some(ref path) = settings.record_file {
+        if let Err(e) = record.append_to(path) {
+            warn!("Failed to write record: {:#}", e);
+        }
+    }
+}
+
+/// Helper function to get the next message for the main loop, either from the
+/// queue or, during a replay, from the replay, in which case the simulated
+/// time is moved up to the message's
+fn next_message(rx: &queue::Receiver<Message>, replay: &mut Option<Replay>) -> Option<Message> {
+    match replay {
+        Some(ref mut replay) => {
+            let msg = replay.next_message(rx)?;
+            if let Some(now) = replay.now() {
+                UtcTime::set_simulated(now);
+            }
+            Some(msg)
+        }
+        None => rx.recv().ok(),
+    }
+}
+
+/// Helper function to send an order to LX
+///
+/// During a replay we have no API key, and just log the order.
+fn post_order(api_key: Option<&str>, order: &ledgerx::json::CreateOrder) -> anyhow::Result<()> {
+    match api_key {
+        Some(key) => {
+            let result = http::post_json("https://trade.ledgerx.com/api/orders", key, order);
+            audit::record(
+                "connect",
+                audit::Action::SubmitOrder,
+                &format!("{}: {}", order, audit::outcome(&result)),
+            );
+            result
+        }
+        None => {
+            info!("Replay; not sending order {}", order);
+            Ok(())
+        }
+    }
+}
+
+/// Helper function to write to the database, if we have one
+fn store<F>(storage: &Option<Storage>, write: F)
+where
+    F: FnOnce(&Storage) -> anyhow::Result<()>,
+{
+    if let Some(ref storage) = storage {
+        if let Err(e) = write(storage) {
+            warn!("Failed to write to database: {:#}", e);
+        }
+    }
+}
+
+/// Helper function to archive LX market data, at market close or shutdown
+fn archive_market_data(tracker: &LedgerX, now: UtcTime, settings: &Settings) {
+    if let Some(ref dir) = settings.market_data_dir {
+        match ledgerx::market_data::archive(dir, &tracker.snapshot(now)) {
+            Ok(n) => info!("Archived market data for {} contracts.", n),
+            Err(e) => warn!("Failed to archive market data: {:#}", e),
+        }
+    }
+}
+
+/// Helper function to write out any pending price samples at shutdown
+fn flush_price_samples(recorder: &mut Option<price::Recorder>) {
+    if let Some(ref mut recorder) = recorder {
+        if let Err(e) = recorder.flush() {
+            warn!("Failed to record price samples: {:#}", e);
+        }
+    }
+}
+
+/// Helper function to report the biggest mispricings of the day at market close
+fn report_mispricing_digest(tracker: &mut LedgerX) {
+    let digest = tracker.take_mispricing_digest();
+    if digest.is_empty() {
+        info!("No mispriced bids today.");
+        return;
+    }
+    let mut summary = String::from("Biggest mispriced bids today:");
+    for mispricing in &digest {
+        summary.push_str(&format!("\n    {mispricing}"));
+    }
+    for line in summary.lines() {
+        info!("{}", line);
+    }
+    http::post_to_prowl(&summary);
+}
+
+/// Helper function to check that we could pay for all our short puts if they
+/// were assigned, alerting (once per expiry) about any shortfall in time to
+/// deposit more USD
+fn check_funding(
+    tracker: &LedgerX,
+    usd_balance: Price,
+    settings: &Settings,
+    now: UtcTime,
+    alerted: &mut HashSet<UtcTime>,
+) {
+    let deposits = match settings.deposits_file {
+        Some(ref path) => funding::read_deposits(path).unwrap_or_else(|e| {
+            warn!("Ignoring scheduled deposits: {:#}", e);
+            vec![]
+        }),
+        None => vec![],
+    };
+    for plan in funding::plan(&tracker.portfolio(), usd_balance, &deposits) {
+        if plan.is_urgent(now) {
+            warn!("Funding shortfall: {}", plan);
+            if alerted.insert(plan.expiry) {
+                http::post_to_prowl(&format!("Funding shortfall, initiate a deposit: {plan}"));
+            }
+        } else {
+            debug!("Funding: {}", plan);
+        }
+    }
+}
+
+/// Helper function to act on the result of applying a datafeed order
+fn handle_order_response(
+    response: ledgerx::OrderResponse,
+    tracker: &mut LedgerX,
+    activity: &mut DailyActivity,
+    goal: &mut Option<goals::Goal>,
+    storage: &Option<Storage>,
+    settings: &Settings,
+    tx: &queue::Sender<Message>,
+) {
+    match response {
+        ledgerx::OrderResponse::OursOk
+        | ledgerx::OrderResponse::OtherTracked
+        | ledgerx::OrderResponse::OtherUntracked => {
+            // Don't do anything
+        }
+        ledgerx::OrderResponse::OursFilled {
+            order,
+            premium,
+            fill,
+        } => {
+            store(storage, |db| {
+                db.record_fill(UtcTime::now(), &order, premium, fill.as_ref())
+            });
+            activity.record_fill(premium);
+            if let Some(ref mut goal) = goal {
+                goal.record_fill(premium);
+                save_goal(goal, settings);
+            }
+            if let Some(fill) = fill {
+                if let Some(pct) = fill.slippage_pct() {
+                    info!("Slippage on fill: {:.2}%", pct);
                 }
-                Event::Assignment {
-                    option,
-                    underlying,
-                    size,
-                    ..
-                } => {
-                    if *underlying == Underlying::Btc {
-                        match option.pc {
-                            crate::option::PutCall::Call => {
-                                delta_usd = option.strike * *size;
-                                delta_btc = size.btc_equivalent() * -1;
-                            }
-                            crate::option::PutCall::Put => {
-                                delta_usd = -option.strike * *size;
-                                delta_btc = size.btc_equivalent();
-                            }
-                        }
-                    } else {
-                        delta_usd = Price::ZERO;
-                        delta_btc = bitcoin::SignedAmount::ZERO;
+                if let Some(ref path) = settings.fill_file {
+                    if let Err(e) = fill.append_to_csv(path) {
+                        warn!("Failed to record fill: {:#}", e);
                     }
                 }
-                _ => {
-                    delta_usd = Price::ZERO;
-                    delta_btc = bitcoin::SignedAmount::ZERO;
-                }
+                append_record(&schema::Record::fill(&fill), settings);
             }
-            net_usd += delta_usd;
-            net_btc += delta_btc;
-            if initial_time - time < chrono::Duration::days(500) {
-                recent_net_usd += delta_usd;
-                recent_net_btc += delta_btc;
+            for order in tracker.take_roll_orders() {
+                info!("Opening second leg of roll: {}", order);
+                tx.send(Message::OpenOrder(order)).unwrap();
             }
+            info!("Triggering heartbeat since an order was filled.");
+            tx.send(Message::Heartbeat).unwrap();
+        }
+        ledgerx::OrderResponse::UnknownContract(order) => {
+            warn!("unknown contract ID {}", order.contract_id);
+            warn!("full order data {}", order);
+        }
+    }
+}
 
-            if net_btc != bitcoin::SignedAmount::ZERO {
-                let average = net_usd / Quantity::from(net_btc).abs();
-                if average < min_average_price {
-                    info!(
-                        "At {} sold {} for {} (average price {})",
-                        time, net_btc, net_usd, average
-                    );
-                    min_average_price = average;
-                }
-            }
+/// Helper function to save our goal, if we have somewhere to save it
+fn save_goal(goal: &goals::Goal, settings: &Settings) {
+    if let Some(ref path) = settings.goal_file {
+        if let Err(e) = goal.save(path) {
+            warn!("Failed to save goal: {:#}", e);
         }
     }
+}
+
+/// Helper function to attempt cancelling all orders, retrying with backoff.
+///
+/// If this fails, records that orders may be live, sends alerts through every
+/// backend we have, and panics.
+///
+/// In watch-only mode we have no orders, so this does nothing.
+fn cancel_all_orders(api_key: Option<&str>, tracker: &LedgerX, settings: &Settings) {
+    cancel_orders(api_key, &tracker.open_order_ids(), settings);
+}
+
+/// Helper function to cancel all orders, whether or not we know about them
+///
+/// Uses LX's cancel-all endpoint, falling back to cancelling the `known`
+/// orders individually; as with [`cancel_all_orders`], panics on failure.
+fn cancel_orders(
+    api_key: Option<&str>,
+    known: &[(ledgerx::MessageId, ledgerx::ContractId)],
+    settings: &Settings,
+) {
+    let api_key = match api_key {
+        Some(key) => key,
+        None => return,
+    };
+    let outcome = emergency::cancel_all_orders(api_key, known);
+    let now = UtcTime::now();
+    for order in &outcome.cancelled {
+        events::emit(&schema::Record::order_cancelled(now, Some(*order)));
+    }
+    match outcome.error {
+        None => events::emit(&schema::Record::order_cancelled(now, None)),
+        Some(e) => {
+            let msg = format!(
+                "Tried to cancel all orders {} times and failed ({} known orders left): {:#}",
+                outcome.attempts,
+                outcome.remaining.len(),
+                e,
+            );
+            emergency::write_marker(&settings.emergency, &msg, &outcome.remaining);
+            emergency::alert(&settings.emergency, &msg);
+            panic!("{}", msg);
+        }
+    }
+}
+
+/// Starts the main loop and a couple utility threads. Runs forever, unless
+/// replaying logs, in which case it returns once they are exhausted.
+///
+/// Returns an error if the logs to replay cannot be opened or contain no
+/// price reference to start from, or if the event stream or database cannot
+/// be opened.
+///
+/// # Panics
+///
+/// Will panic if anything else goes wrong during startup.
+pub fn main_loop(
+    api_key: Option<String>,
+    history: Option<ledgerx::history::History>,
+    mut settings: Settings,
+    mut contract_cache: ContractCache,
+) -> anyhow::Result<()> {
+    let (tx, rx) = queue::bounded(MESSAGE_QUEUE_CAPACITY);
 
-    if net_btc != bitcoin::SignedAmount::ZERO {
+    // When replaying, start the simulated clock at the first logged price,
+    // and cut ourselves off from the world.
+    let mut replay = None;
+    let mut replay_price = None;
+    if !settings.replay_files.is_empty() {
+        let mut logs = Replay::open(&settings.replay_files).context("opening logs to replay")?;
+        let (source, price) = logs.initial_price().context(
+            "no BTC price reference in the logs to replay; they must include \
+             a log with price references, not just LX messages",
+        )?;
+        UtcTime::set_simulated(price.timestamp);
+        http::set_offline();
         info!(
-            "History: sold a total of {} for {} (average price {})",
-            net_btc,
-            net_usd,
-            net_usd / Quantity::from(net_btc).abs()
+            "Replaying {} logs from {}; not connecting to anything.",
+            settings.replay_files.len(),
+            price.timestamp,
         );
+        // None of these would do anything meaningful in simulated time
+        settings.kill_switch_file = None;
+        settings.close_request_file = None;
+        settings.exchange_status.url = None;
+        settings.kraken = false;
+        settings.bitstamp = false;
+        settings.emergency.alert_command = None;
+        if !settings.compare_files.is_empty() {
+            events::capture();
+        }
+        replay = Some(logs);
+        replay_price = Some((source, price));
+    }
+    let replaying = replay.is_some();
+
+    let initial_time = UtcTime::now();
+    let watch_only = api_key.is_none();
+    // During a replay we do not touch the API, and so have no use for the key
+    let api_key = if replaying { None } else { api_key.as_deref() };
+    if watch_only {
+        info!("Watch-only mode: using public data only, and not trading.");
+    }
+    if let Some(ref dest) = settings.emit_events {
+        events::open(dest).context("opening event stream")?;
+        info!("Streaming events to {}", dest);
     }
-    if recent_net_btc != bitcoin::SignedAmount::ZERO {
+    let storage = match settings.database_file {
+        Some(ref path) => {
+            info!(
+                "Storing order updates, fills and balances in {}",
+                path.display()
+            );
+            Some(Storage::open(path)?)
+        }
+        None => None,
+    };
+    if let Some(key) = api_key {
+        emergency::reconcile(key, &settings.emergency)
+            .expect("cancelling orders left live by a previous session");
+    }
+
+    // Before doing anything else, connect to a price reference and
+    // get an initial price. Otherwise we can't initialize our trade
+    // tracker etc.
+    let clock_skew = clock::SkewEstimator::new();
+    let ticker_tx = tx.clone();
+    let ticker_skew = clock_skew.clone();
+    let mut ticker_thread = (!replaying).then(|| {
+        Supervised::spawn("Coinbase", TICKER_MAX_SILENCE_SECS, move |hb| {
+            (
+                crate::coinbase::spawn_ticker_thread(
+                    ticker_tx.clone(),
+                    hb,
+                    Some(ticker_skew.clone()),
+                ),
+                (),
+            )
+        })
+    });
+    let mut kraken_thread = if settings.kraken {
+        let kraken_tx = tx.clone();
+        Some(Supervised::spawn(
+            "Kraken",
+            TICKER_MAX_SILENCE_SECS,
+            move |hb| {
+                (
+                    crate::kraken::spawn_ticker_thread(kraken_tx.clone(), hb),
+                    (),
+                )
+            },
+        ))
+    } else {
+        None
+    };
+    let mut bitstamp_thread = if settings.bitstamp {
+        let bitstamp_tx = tx.clone();
+        Some(Supervised::spawn(
+            "Bitstamp",
+            TICKER_MAX_SILENCE_SECS,
+            move |hb| {
+                (
+                    crate::bitstamp::spawn_trade_thread(bitstamp_tx.clone(), hb),
+                    (),
+                )
+            },
+        ))
+    } else {
+        None
+    };
+    let (initial_source, initial_price) = match replay_price {
+        Some(price) => price,
+        None => match rx.recv() {
+            Ok(Message::PriceReference(source, price)) => (source, price),
+            Ok(_) => unreachable!(),
+            Err(e) => panic!("Failed to get initial price reference: {}", e),
+        },
+    };
+    info!(target: "lx_btcprice", "{}", initial_price);
+    info!("BTC price: {}", initial_price);
+    info!("Risk-free rate: 4% (assumed)");
+    info!(
+        "Interesting contract yield threshold: {}",
+        settings.yield_threshold
+    );
+    info!(
+        "Post-only policy for crossing orders: {}",
+        settings.post_only
+    );
+    info!(
+        "Days to expiry: calls {}, puts {}",
+        settings.dte_filter.calls, settings.dte_filter.puts
+    );
+    info!(
+        "Annualizing standing ask returns from: {}",
+        settings.arr_reference
+    );
+    let mut price_recorder = settings.price_data_dir.as_ref().map(|dir| {
         info!(
-            "History: in last 500 days sold {} for {} (average price {})",
-            recent_net_btc,
-            recent_net_usd,
-            recent_net_usd / Quantity::from(recent_net_btc).abs()
+            "Recording a price sample every {}s to {}",
+            settings.price_sample_secs,
+            dir.display()
         );
+        price::Recorder::new(
+            dir.clone(),
+            chrono::Duration::seconds(settings.price_sample_secs.into()),
+        )
+    });
+
+    // LedgerX websocket thread
+    if !replaying {
+        let lx_url = match api_key {
+            Some(key) => format!("wss://api.ledgerx.com/ws?token={key}"),
+            None => "wss://api.ledgerx.com/ws".to_string(),
+        };
+        spawn_datafeed_thread(tx.clone(), clock_skew.clone(), lx_url);
+    }
+
+    // Clock thread; during a replay, the replay's clock stands in for it
+    let heartbeat_tx = tx.clone();
+    let mut clock_thread = (!replaying).then(|| {
+        Supervised::spawn("clock", HELPER_MAX_SILENCE_SECS, move |hb| {
+            (spawn_clock_thread(heartbeat_tx.clone(), hb), ())
+        })
+    });
+
+    // Kill switch thread
+    if let Some(path) = settings.kill_switch_file.clone() {
+        let kill_tx = tx.clone();
+        info!("Watching kill switch file {}", path.display());
+        thread::spawn(move || {
+            let mut engaged = false;
+            loop {
+                let exists = path.exists();
+                if exists != engaged {
+                    engaged = exists;
+                    let reason = if exists {
+                        format!("file {} created", path.display())
+                    } else {
+                        format!("file {} removed", path.display())
+                    };
+                    kill_tx
+                        .send(Message::KillSwitch { engaged, reason })
+                        .unwrap();
+                }
+                thread::sleep(std::time::Duration::from_secs(10));
+            }
+        });
+    }
+
+    // Close request thread
+    if let Some(path) = settings.close_request_file.clone() {
+        let close_tx = tx.clone();
+        info!("Watching close request file {}", path.display());
+        thread::spawn(move || loop {
+            if path.exists() {
+                match ledgerx::close::take_requests(&path) {
+                    Ok(requests) => {
+                        for request in requests {
+                            close_tx.send(Message::ClosePosition(request)).unwrap();
+                        }
+                    }
+                    Err(e) => warn!("Failed to read close requests: {:#}", e),
+                }
+            }
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+    }
+
+    // Exchange status thread
+    if let Some(ref url) = settings.exchange_status.url {
+        info!("Monitoring exchange status page {}.", url);
+        ledgerx::exchange_status::spawn_monitor_thread(url.clone(), tx.clone());
+    }
+
+    // Contract lookup thread
+    let contract_tx = tx.clone();
+    let contract_tx_api_key = api_key.map(str::to_owned);
+    let mut contract_thread =
+        Supervised::spawn("contract lookup", HELPER_MAX_SILENCE_SECS, move |hb| {
+            let (contract_thread_tx, contract_thread_rx) = channel();
+            let handle = if replaying {
+                spawn_discard_thread(contract_thread_rx)
+            } else {
+                spawn_contract_thread(
+                    contract_tx.clone(),
+                    contract_thread_rx,
+                    contract_tx_api_key.clone(),
+                    hb,
+                )
+            };
+            (handle, contract_thread_tx)
+        });
+
+    // Get history to determine our recent trading volume, and hence fee tier
+    let mut volume = history
+        .as_ref()
+        .map(|hist| ledgerx::fees::Volume::from_history(hist, initial_time))
+        .unwrap_or_default();
+    volume.set_schedule(&settings.fee_tiers);
+
+    // ...and past BTC transactions, and from this our goal for reacquiring
+    // coins. Without history, fall back to the saved goal.
+    let mut goal = match history {
+        Some(hist) => goals::Goal::from_history(&hist, initial_time),
+        None if watch_only => None,
+        None => match settings.goal_file {
+            Some(ref path) => goals::Goal::load(path).unwrap_or_else(|e| {
+                warn!("Ignoring saved goal: {:#}", e);
+                None
+            }),
+            None => None,
+        },
+    };
+    match goal {
+        Some(ref goal) => {
+            info!("{}", goal);
+            save_goal(goal, &settings);
+        }
+        None => info!("No BTC reacquisition goal."),
     }
 
     // ...and output
@@ -272,29 +1128,122 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
     // Setup
     let mut last_heartbeat_time = initial_time - chrono::Duration::hours(48);
     let mut last_market_open = market_is_open(initial_time);
+    let mut last_in_window = trading_window::any_contains(&settings.trading_windows, initial_time);
     let mut heartbeat_price_ref = initial_price;
     let mut current_price = initial_price;
+    let mut kill_switch_engaged = false;
+    let mut exchange_degraded: Option<String> = None;
+    let mut funding_alerted = HashSet::new();
+    let mut last_balance_sync: Option<UtcTime> = None;
+    let mut balance_failures = 0;
+    let mut activity = DailyActivity::new(initial_time);
+    let mut loss_limit = LossLimit::new(settings.max_daily_loss);
+    if let Some(max_loss) = settings.max_daily_loss {
+        info!("Daily loss limit: {}", max_loss);
+    }
 
-    let mut tracker = recreate_tracker(initial_price, &contract_thread_tx);
+    let mut shutdown_price_ref = None;
+    let mut prices_diverged = false;
+    let mut ignored_price_source = None;
+    let mut prices = PriceBoard::new(
+        initial_source,
+        initial_price,
+        chrono::Duration::seconds(settings.max_price_age_secs.into()),
+        settings.max_price_divergence_bps,
+    );
+    for weight in &settings.price_weights {
+        info!("Price source weight: {}", weight);
+        prices.set_weight(weight.source, weight.weight);
+    }
+    let mut tracker = recreate_tracker(
+        prices,
+        contract_thread.get(),
+        api_key,
+        &settings,
+        &mut contract_cache,
+    );
+    tracker.set_volume(volume);
+    info!("Fee tier: {}", tracker.fee_tier(initial_time));
 
     // Wait 30 seconds for LX to pile up some messages (in particular,
     // the balances) and for the contract lookup thread to finish all
     // initial lookups. Then push an initial heartbeat message, and
     // start the main loop to process everything in order.
     //
-    // In theory we could be DoSsed here if LX or Coinbase or whatever
-    // floods us with messages and causes our message queue to use a
-    // ton of memory. But this happens only at startup so it's ok.
-    thread::sleep(std::time::Duration::from_secs(30));
-    tx.send(Message::Heartbeat).unwrap();
+    // The message queue is bounded, so if LX or Coinbase or whatever
+    // floods us with messages during this time, uninteresting ones will
+    // be dropped rather than using a ton of memory. During a replay, we
+    // wait in simulated time instead.
+    match replay {
+        Some(ref mut replay) => replay.schedule(
+            initial_time + chrono::Duration::seconds(30),
+            Message::Heartbeat,
+        ),
+        None => {
+            thread::sleep(std::time::Duration::from_secs(30));
+            tx.send(Message::Heartbeat).unwrap();
+        }
+    }
 
     // Main thread
-    for msg in rx.iter() {
+    while let Some(msg) = next_message(&rx, &mut replay) {
         let now = UtcTime::now();
         if market_is_open(now) && !last_market_open {
-            tracker = recreate_tracker(current_price, &contract_thread_tx);
+            // Carry over the price references so that their ages are preserved,
+            // and our traded volume, which we cannot recover from LX
+            let prices = tracker.prices().clone();
+            let volume = tracker.volume().clone();
+            tracker = recreate_tracker(
+                prices,
+                contract_thread.get(),
+                api_key,
+                &settings,
+                &mut contract_cache,
+            );
+            tracker.set_volume(volume);
+        }
+        if !market_is_open(now) && last_market_open {
+            activity.set_active(false, now);
+            if !watch_only {
+                report_daily_activity(&activity, now, &settings);
+            }
+            if settings.mispricing.enabled {
+                report_mispricing_digest(&mut tracker);
+            }
+            archive_market_data(&tracker, now, &settings);
+            activity = DailyActivity::new(now);
+            loss_limit.reset();
         }
         last_market_open = market_is_open(now);
+        // Data capture carries on outside of the trading windows; only our
+        // orders come and go with them.
+        let in_window = trading_window::any_contains(&settings.trading_windows, now);
+        if in_window != last_in_window && market_is_open(now) && !watch_only {
+            if in_window {
+                info!("Trading window opened; resuming trading.");
+                tx.send(Message::Heartbeat).unwrap();
+            } else {
+                info!("Trading window closed; cancelling all orders except closes.");
+                let n_cancelled = tracker.snapshot(now).cancel_opening_orders(&tx);
+                activity.record_cancellations(n_cancelled);
+            }
+        }
+        last_in_window = in_window;
+        if settings.book_refresh_secs > 0 && market_is_open(now) {
+            let max_age = chrono::Duration::seconds(settings.book_refresh_secs.into());
+            if let Some(cid) = tracker.schedule_book_refresh(now, max_age) {
+                debug!("Periodic refresh of book {}", cid);
+                request_book_state(contract_thread.get(), cid);
+            }
+        }
+        activity.set_active(
+            market_is_open(now)
+                && in_window
+                && !kill_switch_engaged
+                && exchange_degraded.is_none()
+                && !loss_limit.is_tripped(),
+            now,
+        );
 
         match msg {
             Message::LedgerX(obj) => {
@@ -302,31 +1251,93 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
                     datafeed::Object::Other => { /* ignore */ }
                     datafeed::Object::BookTop { .. } => { /* ignore */ }
                     datafeed::Object::Order(order) => {
-                        match tracker.insert_order(order) {
-                            ledgerx::OrderResponse::OursOk
-                            | ledgerx::OrderResponse::OtherTracked
-                            | ledgerx::OrderResponse::OtherUntracked => {
-                                // Don't do anything
-                            }
-                            ledgerx::OrderResponse::OursFilled => {
-                                info!("Triggering heartbeat since an order was filled.");
-                                tx.send(Message::Heartbeat).unwrap();
-                            }
-                            ledgerx::OrderResponse::UnknownContract(order) => {
-                                warn!("unknown contract ID {}", order.contract_id);
-                                warn!("full order data {}", order);
-                            }
+                        store(&storage, |db| db.record_order(now, &order));
+                        for response in tracker.insert_order(order) {
+                            handle_order_response(
+                                response,
+                                &mut tracker,
+                                &mut activity,
+                                &mut goal,
+                                &storage,
+                                &settings,
+                                &tx,
+                            );
+                        }
+                        for cid in tracker.take_book_refreshes() {
+                            request_book_state(contract_thread.get(), cid);
                         }
                     }
                     datafeed::Object::AvailableBalances { usd, btc } => {
+                        store(&storage, |db| {
+                            db.record_balances(
+                                now,
+                                storage::BalanceSource::DataFeed,
+                                &storage::Balances {
+                                    usd_available: usd,
+                                    btc_available: btc,
+                                    locked: None,
+                                },
+                            )
+                        });
                         tracker.set_balances(usd, btc);
+                        // There are no balance syncs during a replay, so
+                        // the datafeed's balances are as fresh as it gets
+                        if replaying {
+                            last_balance_sync = Some(now);
+                        }
                     }
                     datafeed::Object::ContractAdded(contr) => {
-                        contract_thread_tx
-                            .send(contr.id())
-                            .expect("book-states endpoint thread has not panicked");
+                        if !replaying && !contract_cache.contains(contr.id()) {
+                            report_new_listings(
+                                std::slice::from_ref(&contr),
+                                tracker.contracts(),
+                                current_price,
+                            );
+                            // Record the contract in the cache, so that we don't
+                            // report it again when the tracker is recreated.
+                            if let Err(e) = contract_cache.fetch(contr.id()) {
+                                warn!("Failed to cache new contract {}: {:#}", contr.id(), e);
+                            } else if let Err(e) = contract_cache.save() {
+                                warn!("Failed to save contract cache: {:#}", e);
+                            }
+                        }
+                        request_book_state(contract_thread.get(), contr.id());
                         tracker.add_contract(contr);
                     }
+                    datafeed::Object::TradeBusted(bust) => match tracker.bust_trade(&bust) {
+                        ledgerx::BustResponse::OursReversed { premium, size } => {
+                            store(&storage, |db| db.record_bust(now, &bust));
+                            append_record(&schema::Record::bust(&bust), &settings);
+                            let message = format!(
+                                "LX busted our fill of {} on contract {} (order {}); \
+                                 reversed premium {}",
+                                size, bust.contract_id, bust.message_id, premium,
+                            );
+                            warn!("{}", message);
+                            http::post_to_prowl(&message);
+                            activity.record_bust(premium);
+                            if let Some(ref mut goal) = goal {
+                                goal.record_fill(-premium);
+                                save_goal(goal, &settings);
+                            }
+                            info!("Triggering heartbeat to refresh balances after bust.");
+                            tx.send(Message::Heartbeat).unwrap();
+                        }
+                        ledgerx::BustResponse::OursUnmatched => {
+                            store(&storage, |db| db.record_bust(now, &bust));
+                            append_record(&schema::Record::bust(&bust), &settings);
+                            let message = format!(
+                                "LX busted one of our trades, but we have no record of the \
+                                 fill: {bust}",
+                            );
+                            warn!("{}", message);
+                            http::post_to_prowl(&message);
+                            tx.send(Message::Heartbeat).unwrap();
+                        }
+                        ledgerx::BustResponse::Other => {
+                            info!("LX busted a trade: {}", bust);
+                        }
+                    },
                     datafeed::Object::ContractRemoved(cid) => {
                         tracker.remove_contract(cid);
                     }
@@ -334,33 +1345,242 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
                         message,
                         initiator,
                         counterparty,
+                        sender,
                         chat_id,
                     } => {
                         info!(
-                            "New message (chat {}) between {} and {}: {}",
-                            chat_id, initiator, counterparty, message
+                            "New message (chat {}) from {} between {} and {}: {}",
+                            chat_id,
+                            sender.as_deref().unwrap_or("unknown sender"),
+                            initiator,
+                            counterparty,
+                            message
                         );
+                        // Only proposals sent by the counterparty are theirs to
+                        // evaluate; our own messages would be read backwards.
+                        let allowed = sender.as_ref().and_then(|sender| {
+                            settings
+                                .block_counterparties
+                                .iter()
+                                .find(|name| name.eq_ignore_ascii_case(sender))
+                        });
+                        if let Some(name) = allowed {
+                            match message.parse::<ledgerx::block_trade::Proposal>() {
+                                Ok(proposal) => match tracker.evaluate_block_trade(&proposal) {
+                                    Ok(eval) => {
+                                        eval.log(name);
+                                        http::post_to_prowl(&format!(
+                                            "Block trade from {}: {} ({})",
+                                            name, proposal, eval.recommendation
+                                        ));
+                                    }
+                                    Err(e) => warn!(
+                                        "Failed to evaluate block trade from {} ({}): {:#}",
+                                        name, proposal, e
+                                    ),
+                                },
+                                Err(e) => debug!("No block trade proposal in message: {}", e),
+                            }
+                        }
                     }
                 }
             }
+            Message::OpenOrder(order) if watch_only => {
+                debug!("Watch-only; dropping order {}", order);
+            }
             Message::OpenOrder(order) => {
-                if let Err(e) =
-                    http::post_json("https://trade.ledgerx.com/api/orders", &api_key, &order)
-                {
+                // As with the loss limit, closes (e.g. ITM buy-backs) go through
+                if kill_switch_engaged && !tracker.reduces_position(&order) {
+                    warn!("Kill switch engaged; dropping order {}", order);
+                    continue;
+                }
+                if let Some(ref reason) = exchange_degraded {
+                    warn!("Exchange degraded ({}); dropping order {}", reason, order);
+                    continue;
+                }
+                // Once the loss limit is hit we may still close out positions
+                if let Some(breach) = loss_limit.breach() {
+                    if !tracker.reduces_position(&order) {
+                        warn!("{}; dropping order {}", breach, order);
+                        continue;
+                    }
+                }
+                // Likewise closes are allowed outside of the trading windows
+                let reduces = tracker.reduces_position(&order);
+                if !trading_window::permits(&settings.trading_windows, now, reduces) {
+                    warn!("Outside trading window; dropping order {}", order);
+                    continue;
+                }
+                let order = match tracker.validate_order(order, settings.post_only) {
+                    Some(order) => order,
+                    None => continue,
+                };
+                info!(
+                    "Opening order {} (price reference {})",
+                    order,
+                    tracker.prices()
+                );
+                if let Err(e) = post_order(api_key, &order) {
                     // A failed order open is just a warning; all our orders
                     // are asks at not-quite-reasonable prices and if we fail
                     // to open one it's maybe a lost profit opportunity but
                     // not an emergency.
                     warn!("Failed to open order {}: {}", order, e);
+                } else {
+                    activity.record_order_placed();
+                    events::emit(&schema::Record::order_opened(now, &order));
+                }
+            }
+            Message::Roll(roll) if watch_only => {
+                debug!("Watch-only; dropping {}", roll);
+            }
+            Message::Roll(roll) => {
+                // The first leg only buys back a short, so is allowed past the
+                // loss limit; the second leg will be dropped while it is hit.
+                if kill_switch_engaged || exchange_degraded.is_some() || !in_window {
+                    warn!("Not trading; dropping {}", roll);
+                    continue;
+                }
+                let cid = roll.from.id();
+                let order = roll.close_order();
+                if let Err(e) = order.check() {
+                    warn!("Not starting {}: {}", roll, e);
+                    continue;
+                }
+                tracker.start_roll(roll.clone(), now);
+                info!("Opening first leg of roll: {}", order);
+                if let Err(e) = post_order(api_key, &order) {
+                    // Nothing has happened yet, so we can just give up
+                    let message = format!("Aborting {roll}: failed to open first leg: {e}");
+                    warn!("{}", message);
+                    http::post_to_prowl(&message);
+                    tracker.abort_roll(cid);
+                } else {
+                    http::post_to_prowl(&format!("Started {roll}"));
+                    activity.record_order_placed();
+                    events::emit(&schema::Record::order_opened(now, &order));
+                }
+            }
+            Message::ClosePosition(request) if watch_only => {
+                debug!("Watch-only; dropping close of {}", request.contract_id);
+            }
+            Message::ClosePosition(request) => {
+                // Closes only reduce risk, so are allowed past the kill switch
+                // and outside of the trading windows
+                if exchange_degraded.is_some() {
+                    warn!("Not trading; dropping close of {}", request.contract_id);
+                    continue;
+                }
+                let plan = match tracker.snapshot(now).plan_close(request) {
+                    Ok(plan) => plan,
+                    Err(e) => {
+                        warn!("Not closing {}: {:#}", request.contract_id, e);
+                        continue;
+                    }
+                };
+                plan.log();
+                if plan.levels.is_empty() {
+                    continue;
+                }
+                let record = schema::Record::close(now, &plan, tracker.prices().last().btc_price);
+                append_record(&record, &settings);
+                http::post_to_prowl(&format!("Starting {plan}"));
+                for order in plan.orders() {
+                    tx.send(Message::OpenOrder(order)).unwrap();
+                }
+            }
+            Message::CancelOrder { .. } if watch_only => {}
+            Message::CancelOrder {
+                message_id,
+                contract_id,
+            } => {
+                info!(
+                    "Cancelling order {} on contract {}",
+                    message_id, contract_id
+                );
+                let result = match api_key {
+                    Some(key) => {
+                        let result = http::lx_cancel_order(key, message_id, contract_id);
+                        audit::record(
+                            "connect",
+                            audit::Action::CancelOrder,
+                            &format!(
+                                "order {} on contract {}: {}",
+                                message_id,
+                                contract_id,
+                                audit::outcome(&result),
+                            ),
+                        );
+                        result
+                    }
+                    None => {
+                        info!("Replay; not sending cancellation");
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    // Unlike a failed open, a failed cancel may leave us with a stale
+                    // order, so fall back to cancelling everything.
+                    warn!("Failed to cancel order {}: {}", message_id, e);
+                    cancel_all_orders(api_key, &tracker, &settings);
+                } else {
+                    events::emit(&schema::Record::order_cancelled(
+                        now,
+                        Some((message_id, contract_id)),
+                    ));
                 }
             }
             Message::BookState(book_state) => {
                 tracker.initialize_orderbooks(book_state, now, &tx);
             }
-            Message::PriceReference(price) => {
-                info!(target: "lx_btcprice", "{}", price);
-                tracker.set_current_price(price);
-                current_price = price;
+            Message::PriceReference(source, price) => {
+                info!(target: "lx_btcprice", "{} {}", source, price);
+                tracker.set_current_price(source, price);
+
+                // If one source disagrees with the others, it is left out of the
+                // consensus; if we cannot tell which to believe, the consensus
+                // is not served and we stop quoting, as for a stale price. Say
+                // so once.
+                let consensus = tracker.prices().get_at(now);
+                let divergence = tracker
+                    .prices()
+                    .divergence_at(now)
+                    .filter(|div| div.bps > settings.max_price_divergence_bps);
+                let outlier = divergence
+                    .filter(|_| consensus.is_ok())
+                    .map(|div| div.source);
+                if outlier.is_some() && outlier != ignored_price_source {
+                    let msg = format!("Ignoring outlying price source: {}", tracker.prices());
+                    warn!("{}", msg);
+                    http::post_to_prowl(&msg);
+                }
+                ignored_price_source = outlier;
+                let diverged = divergence.is_some() && consensus.is_err();
+                if diverged && !prices_diverged {
+                    let msg = format!("Price sources diverge: {}", tracker.prices());
+                    warn!("{}; pausing quotes.", msg);
+                    http::post_to_prowl(&msg);
+                } else if !diverged && prices_diverged {
+                    warn!("Price sources agree again: {}", tracker.prices());
+                    http::post_to_prowl("Price sources agree again.");
+                }
+                prices_diverged = diverged;
+
+                current_price = match consensus {
+                    Ok(price) => price,
+                    Err(_) => continue,
+                };
+                events::price(now, current_price.btc_price);
+                tracker.check_short_strikes(&tx);
+                if let Some(msg) = check_rapid_move(&mut shutdown_price_ref, current_price) {
+                    tx.send(Message::EmergencyShutdown { msg }).unwrap();
+                }
+
+                if let Some(ref mut recorder) = price_recorder {
+                    if let Err(e) = recorder.sample(current_price) {
+                        warn!("Failed to record price sample: {:#}", e);
+                    }
+                }
 
                 // If the price has drifted by 1% since the last heartbeat,
                 // then force a heartbeat so that we reprice our orders.
@@ -389,39 +1609,268 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
                 last_heartbeat_time = now;
                 heartbeat_price_ref = current_price;
 
-                // Update balances to make sure we're in sync with LX
-                let balances: ledgerx::json::GetBalancesResponse = http::get_json_from_data_field(
-                    "https://api.ledgerx.com/funds/balances",
-                    Some(&api_key),
-                )
-                .context("looking up current balances")
-                .expect("retrieving and parsing json from contract endpoint");
-                info!(
-                    "Balance details (available/position locked/settlement locked/deliverable locked): {}/{}/{}/{}, {}/{}/{}/{}",
-                    balances.usd.available_balance,
-                    balances.usd.position_locked,
-                    balances.usd.settlement_locked,
-                    balances.usd.deliverable_locked,
-                    balances.btc.available_balance,
-                    balances.btc.position_locked,
-                    balances.btc.settlement_locked,
-                    balances.btc.deliverable_locked,
-                );
-                tracker.set_balances(
-                    balances.usd.available_balance,
-                    balances.btc.available_balance,
-                );
+                // Thread liveness means nothing in simulated time
+                let problems = [
+                    ticker_thread.as_mut().and_then(|thread| thread.check(now)),
+                    kraken_thread.as_mut().and_then(|thread| thread.check(now)),
+                    bitstamp_thread
+                        .as_mut()
+                        .and_then(|thread| thread.check(now)),
+                    clock_thread.as_mut().and_then(|thread| thread.check(now)),
+                    (!replaying).then(|| contract_thread.check(now)).flatten(),
+                ];
+                for problem in problems.iter().flatten() {
+                    warn!("{}", problem);
+                    http::post_to_prowl(problem);
+                }
+                clock_skew.compensate();
+                info!("Black-Scholes cache: {}", crate::bs_cache::stats());
+                for response in tracker.flush_pending_clocks() {
+                    handle_order_response(
+                        response,
+                        &mut tracker,
+                        &mut activity,
+                        &mut goal,
+                        &storage,
+                        &settings,
+                        &tx,
+                    );
+                }
+                for cid in tracker.take_book_refreshes() {
+                    request_book_state(contract_thread.get(), cid);
+                }
+                info!("Datafeed clocks: {}", tracker.clock_stats());
+                info!("Fee tier: {}", tracker.fee_tier(now));
+                if settings.book_refresh_secs > 0 {
+                    let max_age = chrono::Duration::seconds(settings.book_refresh_secs.into());
+                    tracker.log_book_ages(now, max_age);
+                }
+
+                if watch_only {
+                    info!("Message queue: {}", rx.stats());
+                    let mut snapshot = tracker.snapshot(now);
+                    snapshot.log_moneyness();
+                    snapshot.log_pin_risk();
+                    if market_is_open(now) {
+                        let (usd, btc) = snapshot.log_interesting_contracts(&tx);
+                        tracker.dock_balances(usd, btc);
+                    } else {
+                        info!("Market closed.");
+                        tracker.clear_orderbooks();
+                    }
+                    continue;
+                }
+
+                // Update balances to make sure we're in sync with LX. During a
+                // replay we can't, and rely on the datafeed's balances.
+                let sync = (!replaying).then(|| {
+                    http::get_json_from_data_field::<ledgerx::json::GetBalancesResponse>(
+                        "https://api.ledgerx.com/funds/balances",
+                        api_key,
+                    )
+                    .context("looking up current balances")
+                });
+                match sync {
+                    None => {}
+                    Some(Ok(balances)) => {
+                        info!(
+                            "Balance details (available/position locked/settlement locked/deliverable locked): {}/{}/{}/{}, {}/{}/{}/{}",
+                            balances.usd.available_balance,
+                            balances.usd.position_locked,
+                            balances.usd.settlement_locked,
+                            balances.usd.deliverable_locked,
+                            balances.btc.available_balance,
+                            balances.btc.position_locked,
+                            balances.btc.settlement_locked,
+                            balances.btc.deliverable_locked,
+                        );
+                        store(&storage, |db| {
+                            db.record_balances(
+                                now,
+                                storage::BalanceSource::Sync,
+                                &storage::Balances {
+                                    usd_available: balances.usd.available_balance,
+                                    btc_available: balances.btc.available_balance,
+                                    locked: Some((
+                                        balances.usd.position_locked,
+                                        balances.btc.position_locked,
+                                    )),
+                                },
+                            )
+                        });
+                        tracker.set_balances(
+                            balances.usd.available_balance,
+                            balances.btc.available_balance,
+                        );
+                        tracker.reconcile_collateral(
+                            balances.usd.position_locked,
+                            balances.btc.position_locked,
+                        );
+                        check_funding(
+                            &tracker,
+                            balances.usd.available_balance + balances.usd.position_locked,
+                            &settings,
+                            now,
+                            &mut funding_alerted,
+                        );
+                        if balance_failures >= BALANCE_FAILURE_ALERT {
+                            http::post_to_prowl(&format!(
+                                "Balance sync recovered after {balance_failures} failures"
+                            ));
+                        }
+                        balance_failures = 0;
+                        last_balance_sync = Some(now);
+                    }
+                    Some(Err(e)) => {
+                        balance_failures += 1;
+                        warn!(
+                            "Failed to sync balances ({} in a row): {:#}",
+                            balance_failures, e
+                        );
+                        if balance_failures == BALANCE_FAILURE_ALERT {
+                            http::post_to_prowl(&format!(
+                                "Balance sync failed {balance_failures} times in a row: {e:#}"
+                            ));
+                        }
+                    }
+                }
+                let balances_stale = match last_balance_sync {
+                    Some(time) => {
+                        now - time > chrono::Duration::seconds(settings.max_balance_age_secs.into())
+                    }
+                    None => true,
+                };
+                if let Some(ref goal) = goal {
+                    goal.log_progress(&tracker.portfolio());
+                }
+                info!("Message queue: {}", rx.stats());
+
+                // Everything the strategy does from here on is based on a
+                // single consistent view of the tracker.
+                for (roll, remaining) in tracker.expire_rolls(now, &tx) {
+                    http::post_to_prowl(&format!(
+                        "Abandoned {roll}: {remaining} contracts not bought back"
+                    ));
+                }
+                let mut snapshot = tracker.snapshot(now);
+                snapshot.log_moneyness();
+                snapshot.log_pin_risk();
 
-                if market_is_open(now) {
-                    tracker.log_open_orders();
-                    tracker.log_interesting_contracts(&tx);
-                    cancel_all_orders(&api_key);
+                // Mark the account against the daily loss limit. Without fresh
+                // balances the mark would be meaningless, so skip it.
+                if market_is_open(now) && !balances_stale {
+                    match snapshot.account_equity() {
+                        Ok(equity) => {
+                            if let Some(breach) = loss_limit.record(now, equity) {
+                                emergency::alert(
+                                    &settings.emergency,
+                                    &format!(
+                                        "Daily loss limit hit, cancelling all orders which \
+                                         do not close positions: {breach}"
+                                    ),
+                                );
+                            }
+                            info!("Account equity {}: {}", equity, loss_limit);
+                        }
+                        Err(e) => warn!("Not marking account against loss limit: {:#}", e),
+                    }
+                }
+
+                if market_is_open(now) && kill_switch_engaged {
+                    info!("Kill switch engaged; not opening any orders except closes.");
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::KillSwitch,
+                        now,
+                        &settings,
+                    );
+                    closes_only_heartbeat(&mut snapshot, &mut tracker, &mut activity, &tx);
+                } else if market_is_open(now) && loss_limit.is_tripped() {
+                    info!("Daily loss limit hit; not opening any orders except closes.");
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::LossLimit,
+                        now,
+                        &settings,
+                    );
+                    closes_only_heartbeat(&mut snapshot, &mut tracker, &mut activity, &tx);
+                } else if market_is_open(now) && exchange_degraded.is_some() {
+                    info!("Exchange degraded; not opening any orders.");
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::ExchangeDegraded,
+                        now,
+                        &settings,
+                    );
+                    snapshot.log_open_orders();
+                    if settings.exchange_status.cancel_orders {
+                        activity.record_cancellations(tracker.open_order_count());
+                        cancel_all_orders(api_key, &tracker, &settings);
+                    }
+                } else if market_is_open(now) && balances_stale {
+                    match last_balance_sync {
+                        Some(time) => info!(
+                            "Balances last synced at {}; not opening or taking any orders.",
+                            time
+                        ),
+                        None => info!("Balances never synced; not opening or taking any orders."),
+                    }
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::StaleBalances,
+                        now,
+                        &settings,
+                    );
+                    snapshot.log_open_orders();
+                } else if market_is_open(now) && !in_window {
+                    info!("Outside trading window; not opening any orders.");
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::OutsideTradingWindow,
+                        now,
+                        &settings,
+                    );
+                    snapshot.log_open_orders();
+                    let (usd, btc) = snapshot.log_interesting_contracts(&tx);
+                    tracker.dock_balances(usd, btc);
+                } else if market_is_open(now) {
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::Traded,
+                        now,
+                        &settings,
+                    );
+                    snapshot.log_open_orders();
+                    let (usd, btc) = snapshot.log_interesting_contracts(&tx);
+                    tracker.dock_balances(usd, btc);
                     // THIS LINE is currently the entirety of my trading algo. It
-                    // may push "open order" requests onto the message queue, which
-                    // we execute obediently.
-                    tracker.open_standing_orders(&tx);
+                    // may push "cancel order" and "open order" requests onto the
+                    // message queue, which we execute obediently.
+                    let n_cancelled = snapshot.open_standing_orders(&tx, goal.as_ref());
+                    activity.record_cancellations(n_cancelled);
+                    // Rolls go after the standing orders, so that their first
+                    // legs are not cancelled along with our stale orders.
+                    if tracker.roll_settings().enabled {
+                        let planned = snapshot.plan_rolls(tracker.roll_settings());
+                        for roll in tracker.choose_rolls(planned) {
+                            tx.send(Message::Roll(roll)).unwrap();
+                        }
+                    }
                 } else {
                     info!("Market closed.");
+                    record_heartbeat(
+                        &mut activity,
+                        &tracker,
+                        HeartbeatDecision::MarketClosed,
+                        now,
+                        &settings,
+                    );
                     tracker.clear_orderbooks();
                 }
             }
@@ -433,15 +1882,102 @@ pub fn main_loop(api_key: String, history: Option<ledgerx::history::History>) ->
                 })
                 .unwrap();
             }
+            Message::KillSwitch { engaged, reason } => {
+                if engaged == kill_switch_engaged {
+                    continue;
+                }
+                kill_switch_engaged = engaged;
+                audit::record(
+                    "kill switch",
+                    audit::Action::KillSwitch,
+                    &format!(
+                        "{} ({})",
+                        if engaged { "engaged" } else { "released" },
+                        reason
+                    ),
+                );
+                activity.set_active(
+                    market_is_open(now)
+                        && in_window
+                        && !engaged
+                        && exchange_degraded.is_none()
+                        && !loss_limit.is_tripped(),
+                    now,
+                );
+                if engaged {
+                    warn!(
+                        "Kill switch ENGAGED ({}); cancelling all orders except closes.",
+                        reason
+                    );
+                    http::post_to_prowl(&format!("Kill switch engaged: {reason}"));
+                    let n_cancelled = tracker.snapshot(now).cancel_opening_orders(&tx);
+                    activity.record_cancellations(n_cancelled);
+                } else {
+                    warn!("Kill switch released ({}); resuming trading.", reason);
+                    http::post_to_prowl(&format!("Kill switch released: {reason}"));
+                    tx.send(Message::Heartbeat).unwrap();
+                }
+            }
+            Message::ExchangeDegraded { reason } => {
+                warn!("Exchange DEGRADED ({}); pausing new orders.", reason);
+                http::post_to_prowl(&format!("Exchange degraded: {reason}"));
+                activity.set_active(false, now);
+                if settings.exchange_status.cancel_orders {
+                    warn!("Cancelling all orders while exchange is degraded.");
+                    activity.record_cancellations(tracker.open_order_count());
+                    cancel_all_orders(api_key, &tracker, &settings);
+                }
+                exchange_degraded = Some(reason);
+            }
+            Message::ExchangeRecovered => {
+                if let Some(reason) = exchange_degraded.take() {
+                    warn!("Exchange recovered (was: {}); resuming trading.", reason);
+                    http::post_to_prowl(&format!("Exchange recovered (was: {reason})"));
+                    tx.send(Message::Heartbeat).unwrap();
+                }
+            }
             Message::EmergencyShutdown { msg } => {
-                http::post_to_prowl(&format!("Emergency shutdown: {msg}"));
-                cancel_all_orders(&api_key);
+                audit::record("connect", audit::Action::EmergencyShutdown, &msg);
+                emergency::alert(&settings.emergency, &format!("Emergency shutdown: {msg}"));
+                cancel_all_orders(api_key, &tracker, &settings);
+                archive_market_data(&tracker, now, &settings);
+                flush_price_samples(&mut price_recorder);
                 panic!("Emergency shutdown: {}", msg);
             }
         }
     }
 
-    http::post_to_prowl("Main loop stopped receiving messages; shutting down.");
-    cancel_all_orders(&api_key);
+    let now = UtcTime::now();
+    if let Some(replay) = replay {
+        info!("Replay finished: {}", replay);
+        if !settings.compare_files.is_empty() {
+            let simulated = events::take_captured();
+            let report = settings
+                .compare_files
+                .iter()
+                .map(|path| schema::Record::read_all(path))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .and_then(|actual| {
+                    resimulation::Report::new(&actual.concat(), &simulated, initial_time, now)
+                });
+            match report {
+                Ok(report) => report.log(),
+                Err(e) => warn!("Failed to compare against the replayed session: {:#}", e),
+            }
+        }
+        if !watch_only {
+            report_daily_activity(&activity, now, &settings);
+        }
+        archive_market_data(&tracker, now, &settings);
+        flush_price_samples(&mut price_recorder);
+        return Ok(());
+    }
+    emergency::alert(
+        &settings.emergency,
+        "Main loop stopped receiving messages; shutting down.",
+    );
+    cancel_all_orders(api_key, &tracker, &settings);
+    archive_market_data(&tracker, now, &settings);
+    flush_price_samples(&mut price_recorder);
     panic!("Main loop stopped receiving messages.");
 }
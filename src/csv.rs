@@ -14,7 +14,8 @@
 
 //! CSV
 //!
-//! Basic support for printing data in comma-separated-value format
+//! Basic support for printing data in comma-separated-value format, and for
+//! reading back the files we print
 //!
 
 use crate::units::UtcTime;
@@ -25,6 +26,22 @@ pub trait PrintCsv {
     fn print(&self, f: &mut fmt::Formatter) -> fmt::Result;
 }
 
+/// Splits a line of CSV into fields, removing any quotes
+pub fn split_line(line: &str) -> Vec<String> {
+    let mut ret = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for ch in line.chars() {
+        match ch {
+            '"' => quoted = !quoted,
+            ',' if !quoted => ret.push(std::mem::take(&mut current)),
+            _ => current.push(ch),
+        }
+    }
+    ret.push(current);
+    ret
+}
+
 /// Wrapper around a `PrintCsv` used for println! etc
 pub struct CsvPrinter<P: PrintCsv>(pub P);
 
@@ -86,12 +103,20 @@ impl PrintCsv for crate::units::BudgetAsset {
             crate::units::BudgetAsset::Btc => f.write_str(",BTC,"),
             crate::units::BudgetAsset::Eth => f.write_str(",ETH,"),
             crate::units::BudgetAsset::Usd => f.write_str(",USD,"),
-            crate::units::BudgetAsset::Option { underlying, option } => {
+            crate::units::BudgetAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => {
                 assert_eq!(
                     underlying,
                     crate::units::Underlying::Btc,
                     "non-BTC budget asset ID (do you need to update your spreadsheet?)",
                 );
+                // `History::print_csv` rejects full-size contracts up front
+                if contract_size != crate::units::ContractSize::Mini {
+                    return Err(fmt::Error);
+                }
                 DateTime(option.expiry).print(f)?;
                 write!(f, ",{},{}", option.pc.to_char(), option.strike)
             }
@@ -106,6 +131,10 @@ impl PrintCsv for crate::units::Quantity {
             crate::units::Quantity::Bitcoin(btc) => fmt::Display::fmt(&btc.display_in(Bitcoin), f),
             crate::units::Quantity::Cents(n) => write!(f, "{}.{:02}", n / 100, n % 100),
             crate::units::Quantity::Contracts(n) => fmt::Display::fmt(&n, f),
+            crate::units::Quantity::Ether(n) => {
+                fmt::Display::fmt(&rust_decimal::Decimal::new(n, 9), f)
+            }
+            crate::units::Quantity::EthContracts(n) => fmt::Display::fmt(&n, f),
             crate::units::Quantity::Zero => f.write_str("0"),
         }
     }
@@ -189,6 +218,8 @@ impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10);
 impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10 L 11);
 impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10 L 11 M 12);
 impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10 L 11 M 12 N 13);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10 L 11 M 12 N 13 O 14);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7 I 8 J 9 K 10 L 11 M 12 N 13 O 14 P 15);
 
 impl<P: PrintCsv> PrintCsv for Option<P> {
     fn print(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -0,0 +1,313 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Emergency Paths
+//!
+//! When something goes wrong badly enough that we stop trading, we need our
+//! orders gone and we need to hear about it. Both of these used to be a
+//! single HTTP request: one failed cancel-all panicked, and the only word of
+//! it went to Prowl. When the network is flaky, both tend to fail together,
+//! leaving orders live with nobody watching them.
+//!
+//! So cancellation is retried with backoff, falling back to cancelling each
+//! of our known orders individually. If cancel-all never succeeds, a marker
+//! file records that orders may still be live, and the next `connect`
+//! refuses to start until it has cancelled everything. Alerts go to every
+//! configured backend, not just Prowl.
+//!
+
+use crate::audit;
+use crate::http;
+use crate::ledgerx::{ContractId, MessageId};
+use crate::units::UtcTime;
+use anyhow::Context;
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::{fs, process, thread, time};
+
+/// Number of times to try cancelling all orders before giving up
+pub const CANCEL_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of a failed cancel; doubled for each retry after
+const CANCEL_BACKOFF: time::Duration = time::Duration::from_millis(500);
+
+/// Settings for alerting and for recovering from failed cancels
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Settings {
+    /// If set, a URL to which alerts are POSTed as plain text, in addition to Prowl
+    pub alert_webhook: Option<String>,
+    /// If set, a program which is run with each alert as its only argument
+    pub alert_command: Option<PathBuf>,
+    /// If set, a file created when orders may have been left live
+    pub live_orders_file: Option<PathBuf>,
+}
+
+/// Sends an alert to every configured backend
+///
+/// Returns the number of backends which accepted the alert. The alert is
+/// also logged as an error, so that it at least reaches the logs if every
+/// backend fails.
+pub fn alert(settings: &Settings, msg: &str) -> usize {
+    error!("ALERT: {}", msg);
+    crate::events::emit(&crate::schema::Record::alert(UtcTime::now(), msg));
+
+    let mut delivered = 0;
+    match http::try_post_to_prowl(msg) {
+        Ok(()) => delivered += 1,
+        Err(e) => warn!("Alert backend Prowl failed: {:#}", e),
+    }
+    if let Some(ref url) = settings.alert_webhook {
+        match http::post_text(url, msg) {
+            Ok(()) => delivered += 1,
+            Err(e) => warn!("Alert backend {} failed: {:#}", url, e),
+        }
+    }
+    if let Some(ref program) = settings.alert_command {
+        match run_alert_command(program, msg) {
+            Ok(()) => delivered += 1,
+            Err(e) => warn!("Alert backend {} failed: {:#}", program.display(), e),
+        }
+    }
+    if delivered == 0 {
+        error!("No alert backend accepted the alert.");
+    }
+    delivered
+}
+
+/// Runs the alert command with the message as its argument
+fn run_alert_command(program: &Path, msg: &str) -> anyhow::Result<()> {
+    let status = process::Command::new(program)
+        .arg(msg)
+        .status()
+        .with_context(|| format!("running {}", program.display()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "{} exited with {}",
+            program.display(),
+            status
+        )))
+    }
+}
+
+/// The result of trying to cancel all our orders
+#[derive(Debug)]
+pub struct Outcome {
+    /// Number of cancel-all requests made
+    pub attempts: u32,
+    /// The error from the last cancel-all request, if none succeeded
+    pub error: Option<anyhow::Error>,
+    /// Orders which were cancelled individually after a cancel-all failed
+    pub cancelled: Vec<(MessageId, ContractId)>,
+    /// Known orders which could not be cancelled individually either
+    pub remaining: Vec<(MessageId, ContractId)>,
+}
+
+/// Cancels all orders, retrying with backoff
+///
+/// `orders` are the orders we know to be open. Whenever a cancel-all fails,
+/// each of these which has not already been cancelled is cancelled
+/// individually before the next retry. Only a successful cancel-all counts as
+/// success, since there may be live orders we do not know about.
+pub fn cancel_all_orders(api_key: &str, orders: &[(MessageId, ContractId)]) -> Outcome {
+    let outcome = cancel_with(
+        orders,
+        CANCEL_BACKOFF,
+        || http::lx_cancel_all_orders(api_key),
+        |message_id, contract_id| http::lx_cancel_order(api_key, message_id, contract_id),
+    );
+    let result = match outcome.error {
+        None => "ok".to_owned(),
+        Some(ref e) => format!("failed: {e:#}"),
+    };
+    audit::record(
+        "emergency",
+        audit::Action::CancelAllOrders,
+        &format!(
+            "{} attempts, {} orders cancelled individually, {} known orders left: {}",
+            outcome.attempts,
+            outcome.cancelled.len(),
+            outcome.remaining.len(),
+            result,
+        ),
+    );
+    outcome
+}
+
+/// Implementation of [`cancel_all_orders`], with the requests abstracted out
+fn cancel_with<A, O>(
+    orders: &[(MessageId, ContractId)],
+    backoff: time::Duration,
+    mut cancel_all: A,
+    mut cancel_one: O,
+) -> Outcome
+where
+    A: FnMut() -> anyhow::Result<()>,
+    O: FnMut(MessageId, ContractId) -> anyhow::Result<()>,
+{
+    let mut outcome = Outcome {
+        attempts: 0,
+        error: None,
+        cancelled: vec![],
+        remaining: orders.to_vec(),
+    };
+    let mut delay = backoff;
+    while outcome.attempts < CANCEL_ATTEMPTS {
+        if outcome.attempts > 0 {
+            thread::sleep(delay);
+            delay *= 2;
+        }
+        outcome.attempts += 1;
+        match cancel_all() {
+            Ok(()) => {
+                outcome.error = None;
+                outcome.remaining.clear();
+                return outcome;
+            }
+            Err(e) => {
+                warn!(
+                    "Cancel-all attempt {}/{} failed: {:#}",
+                    outcome.attempts, CANCEL_ATTEMPTS, e
+                );
+                outcome.error = Some(e);
+            }
+        }
+        let mut remaining = vec![];
+        for (message_id, contract_id) in outcome.remaining.drain(..) {
+            match cancel_one(message_id, contract_id) {
+                Ok(()) => outcome.cancelled.push((message_id, contract_id)),
+                Err(e) => {
+                    warn!("Failed to cancel order {}: {:#}", message_id, e);
+                    remaining.push((message_id, contract_id));
+                }
+            }
+        }
+        outcome.remaining = remaining;
+    }
+    outcome
+}
+
+/// Records that orders may have been left live
+///
+/// Failures are logged rather than returned, since this is called on the way
+/// to aborting anyway.
+pub fn write_marker(settings: &Settings, reason: &str, orders: &[(MessageId, ContractId)]) {
+    let path = match settings.live_orders_file {
+        Some(ref path) => path,
+        None => return,
+    };
+    let mut contents = format!("# {} {}\n", UtcTime::now(), reason);
+    for (message_id, contract_id) in orders {
+        contents.push_str(&format!("{message_id} {contract_id}\n"));
+    }
+    match fs::write(path, contents) {
+        Ok(()) => warn!("Recorded possibly-live orders in {}", path.display()),
+        Err(e) => error!(
+            "Failed to record possibly-live orders in {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// At startup, cancels any orders a previous session may have left live
+///
+/// Does nothing if there is no marker file. Otherwise cancels all orders and,
+/// if that succeeds, removes the marker. Fails if the orders could not be
+/// cancelled, in which case we should not start trading.
+pub fn reconcile(api_key: &str, settings: &Settings) -> anyhow::Result<()> {
+    let path = match settings.live_orders_file {
+        Some(ref path) if path.exists() => path,
+        _ => return Ok(()),
+    };
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("reading live-order marker {}", path.display()))?;
+    warn!(
+        "Previous session may have left orders live ({}):",
+        path.display()
+    );
+    for line in contents.lines() {
+        warn!("    {}", line);
+    }
+    let outcome = cancel_all_orders(api_key, &[]);
+    if let Some(e) = outcome.error {
+        alert(
+            settings,
+            &format!("Orders from a previous session may be live and could not be cancelled: {e}"),
+        );
+        return Err(e.context("cancelling orders left live by a previous session"));
+    }
+    fs::remove_file(path)
+        .with_context(|| format!("removing live-order marker {}", path.display()))?;
+    info!("Cancelled all orders; removed {}.", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn cancel_retries() {
+        let order = |n: u8| (MessageId::from([n; 16]), ContractId::from(usize::from(n)));
+        let orders = [order(1), order(2)];
+        let no_wait = time::Duration::ZERO;
+
+        // Succeeds first time
+        let outcome = cancel_with(&orders, no_wait, || Ok(()), |_, _| unreachable!());
+        assert_eq!(outcome.attempts, 1);
+        assert!(outcome.error.is_none());
+        assert!(outcome.cancelled.is_empty());
+
+        // Succeeds on the third try; individual cancels are tried in between,
+        // and the one that succeeds is not retried
+        let calls = Cell::new(0);
+        let outcome = cancel_with(
+            &orders,
+            no_wait,
+            || {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(anyhow::Error::msg("down"))
+                } else {
+                    Ok(())
+                }
+            },
+            |id, _| {
+                if id == orders[0].0 {
+                    Ok(())
+                } else {
+                    Err(anyhow::Error::msg("down"))
+                }
+            },
+        );
+        assert_eq!(outcome.attempts, 3);
+        assert!(outcome.error.is_none());
+        assert_eq!(outcome.cancelled, vec![orders[0]]);
+        assert!(outcome.remaining.is_empty());
+
+        // Never succeeds
+        let outcome = cancel_with(
+            &orders,
+            no_wait,
+            || Err(anyhow::Error::msg("down")),
+            |_, _| Err(anyhow::Error::msg("down")),
+        );
+        assert_eq!(outcome.attempts, CANCEL_ATTEMPTS);
+        assert!(outcome.error.is_some());
+        assert!(outcome.cancelled.is_empty());
+        assert_eq!(outcome.remaining, orders.to_vec());
+    }
+}
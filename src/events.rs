@@ -0,0 +1,145 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Event Stream
+//!
+//! With `connect --emit-events`, everything of interest which happens during
+//! the session is written as it happens, one JSON record per line, to a file
+//! or to stdout. This lets a dashboard or a separate recorder follow the live
+//! state of the session without parsing the human-oriented logs.
+//!
+//! The records are those of the `schema` module: the heartbeat decisions,
+//! closes and fills which also go to the record file, along with price
+//! updates (at most one every [`PRICE_INTERVAL_SECS`]), our orders being
+//! opened and cancelled, changes in our balances, and phone notifications.
+//!
+//! The stream is global, so that notifications can be emitted from wherever
+//! they are sent. Until [`open`] is called, emitting does nothing.
+//!
+//! Separately, the records can be captured in memory, so that a replay can
+//! compare what it would have done against what was actually done.
+//!
+
+use crate::schema::Record;
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{fmt, fs, str};
+
+/// Minimum interval, in seconds, between emitted price updates
+pub const PRICE_INTERVAL_SECS: i64 = 10;
+
+/// The stream, if one has been opened
+static STREAM: Mutex<Option<Stream>> = Mutex::new(None);
+
+/// Records emitted since [`capture`] was called, if it has been
+static CAPTURED: Mutex<Option<Vec<Record>>> = Mutex::new(None);
+
+/// Where to write events
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Destination {
+    /// Standard output
+    Stdout,
+    /// A file, which is appended to
+    File(PathBuf),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Destination::Stdout => f.write_str("stdout"),
+            Destination::File(ref path) => fmt::Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+impl str::FromStr for Destination {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            Ok(Destination::Stdout)
+        } else {
+            Ok(Destination::File(s.into()))
+        }
+    }
+}
+
+/// An open stream of events
+struct Stream {
+    output: Box<dyn Write + Send>,
+    last_price: Option<UtcTime>,
+}
+
+/// Opens the event stream
+pub fn open(dest: &Destination) -> anyhow::Result<()> {
+    let output: Box<dyn Write + Send> = match *dest {
+        Destination::Stdout => Box::new(io::stdout()),
+        Destination::File(ref path) => Box::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening event file {}", path.display()))?,
+        ),
+    };
+    *STREAM.lock().unwrap() = Some(Stream {
+        output,
+        last_price: None,
+    });
+    Ok(())
+}
+
+/// Writes a record to the event stream, if it is open
+///
+/// Failures are ignored, since logging them would likely produce a flood of
+/// further failures if, say, the reader at the other end of a pipe has gone.
+pub fn emit(record: &Record) {
+    if let Some(ref mut captured) = *CAPTURED.lock().unwrap() {
+        captured.push(record.clone());
+    }
+    if let Some(ref mut stream) = *STREAM.lock().unwrap() {
+        let _ = writeln!(stream.output, "{record}");
+        let _ = stream.output.flush();
+    }
+}
+
+/// Starts keeping a copy of every record emitted, other than price updates,
+/// whether or not the stream is open
+pub fn capture() {
+    *CAPTURED.lock().unwrap() = Some(vec![]);
+}
+
+/// Returns the records captured since [`capture`] was called, and stops
+/// capturing
+pub fn take_captured() -> Vec<Record> {
+    CAPTURED.lock().unwrap().take().unwrap_or_default()
+}
+
+/// Writes a price update to the event stream, unless one was written too
+/// recently
+pub fn price(time: UtcTime, btc_price: Price) {
+    if let Some(ref mut stream) = *STREAM.lock().unwrap() {
+        if stream
+            .last_price
+            .is_some_and(|last| time - last < chrono::Duration::seconds(PRICE_INTERVAL_SECS))
+        {
+            return;
+        }
+        stream.last_price = Some(time);
+        let _ = writeln!(stream.output, "{}", Record::price(time, btc_price));
+        let _ = stream.output.flush();
+    }
+}
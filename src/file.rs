@@ -20,7 +20,7 @@
 
 use anyhow::Context;
 use log::info;
-use std::{fmt, fs, io};
+use std::{fmt, fs, io, path::Path};
 
 /// A text file
 pub struct TextFile {
@@ -67,3 +67,29 @@ pub fn copy_file(source: &str, dest: &str) -> anyhow::Result<()> {
     fs::copy(source, dest).with_context(|| format!("Copying {source} to {dest}"))?;
     Ok(())
 }
+
+/// Helper function to replace a file's contents atomically
+///
+/// The data is written to a temporary file alongside the target, which is
+/// then renamed over it, so that a crash midway through never leaves a
+/// truncated file behind.
+pub fn write_atomic<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut io::BufWriter<fs::File>) -> anyhow::Result<()>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = Path::new(&tmp_path);
+
+    let file = fs::File::create(tmp_path)
+        .with_context(|| format!("creating file {}", tmp_path.display()))?;
+    let mut writer = io::BufWriter::new(file);
+    write(&mut writer)?;
+    let file = writer
+        .into_inner()
+        .with_context(|| format!("flushing {}", tmp_path.display()))?;
+    file.sync_all()
+        .with_context(|| format!("syncing {}", tmp_path.display()))?;
+    fs::rename(tmp_path, path)
+        .with_context(|| format!("renaming {} to {}", tmp_path.display(), path.display()))
+}
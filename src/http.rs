@@ -19,10 +19,33 @@
 
 use anyhow::Context;
 use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether network requests have been disabled
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Disables all network requests for the rest of the program; they will fail
+/// without being sent
+///
+/// Used when replaying logs, so that nothing done in simulated time reaches
+/// the exchange (or anybody's phone).
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+/// Returns an error if network requests have been disabled
+fn check_online(url: &str) -> Result<(), anyhow::Error> {
+    if OFFLINE.load(Ordering::Relaxed) {
+        Err(anyhow::Error::msg(format!("offline; not contacting {url}")))
+    } else {
+        Ok(())
+    }
+}
 
 /// Make a HTTP GET request, optionally with a LX API key, which will be
 /// used if provided, and return a byte vector.
 pub fn get_bytes(url: &str, api_key: Option<&str>) -> Result<Vec<u8>, anyhow::Error> {
+    check_online(url)?;
     let mut req = minreq::get(url).with_timeout(10);
     if let Some(key) = api_key {
         req = req.with_header("Authorization", format!("JWT {key}"));
@@ -61,6 +84,7 @@ pub fn post_json<S: serde::Serialize>(
     api_key: &str,
     data: S,
 ) -> Result<(), anyhow::Error> {
+    check_online(url)?;
     let data = serde_json::to_vec(&data).with_context(|| format!("serializing json for {url}"))?;
     info!(
         target: "lx_http_get",
@@ -112,6 +136,24 @@ pub fn get_json_from_data_field<D: serde::de::DeserializeOwned>(
 }
 
 pub fn post_to_prowl(data: &str) {
+    crate::events::emit(&crate::schema::Record::alert(
+        crate::units::UtcTime::now(),
+        data,
+    ));
+    if OFFLINE.load(Ordering::Relaxed) {
+        info!("Offline; not sending notification: {}", data);
+        return;
+    }
+    if let Err(e) = try_post_to_prowl(data) {
+        warn!("Sending message to Prowl failed: {:#}", e);
+        warn!("{}", data);
+    }
+}
+
+/// Send a message to Prowl, returning an error if it was not accepted
+///
+/// Unlike [`post_to_prowl`], does not emit the message to the event stream.
+pub fn try_post_to_prowl(data: &str) -> Result<(), anyhow::Error> {
     let encoded = urlencoding::encode(data);
     let body = format!(
         "apikey=71d4fa4bfa2a49c69ebb470594be2e079b05006d\
@@ -119,14 +161,40 @@ pub fn post_to_prowl(data: &str) {
         &event=filled-trade\
         &description={encoded}"
     );
-    if let Err(e) = minreq::post("https://api.prowlapp.com/publicapi/add")
+    let url = "https://api.prowlapp.com/publicapi/add";
+    check_online(url)?;
+    let resp = minreq::post(url)
         .with_timeout(10)
         .with_header("Content-type", "application/x-www-form-urlencoded")
-        .with_body(body.clone())
+        .with_body(body)
+        .send()
+        .context("POST to Prowl")?;
+    if resp.status_code == 200 {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "bad status code {} from Prowl",
+            resp.status_code
+        )))
+    }
+}
+
+/// Make a HTTP POST request with a plain-text message, e.g. to a webhook
+pub fn post_text(url: &str, data: &str) -> Result<(), anyhow::Error> {
+    check_online(url)?;
+    let resp = minreq::post(url)
+        .with_timeout(10)
+        .with_header("Content-type", "text/plain; charset=utf-8")
+        .with_body(data)
         .send()
-    {
-        warn!("Sending message to Prowl failed: {}", e);
-        warn!("{}", body);
+        .with_context(|| format!("POST data to {url}"))?;
+    if (200..300).contains(&resp.status_code) {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "bad status code {} for call to {url}",
+            resp.status_code
+        )))
     }
 }
 
@@ -135,12 +203,36 @@ pub fn post_to_prowl(data: &str) {
 /// This is only used by the "cancel all orders" API endpoint which
 /// takes an empty message, so we special case it.
 pub fn lx_cancel_all_orders(api_key: &str) -> Result<(), anyhow::Error> {
-    let url = "https://trade.ledgerx.com/api/orders";
+    lx_delete(
+        "https://trade.ledgerx.com/api/orders",
+        api_key,
+        "cancelling orders",
+    )
+}
+
+/// Make a HTTP DELETE request to cancel a single order.
+pub fn lx_cancel_order(
+    api_key: &str,
+    message_id: crate::ledgerx::MessageId,
+    contract_id: crate::ledgerx::ContractId,
+) -> Result<(), anyhow::Error> {
+    let url =
+        format!("https://trade.ledgerx.com/api/orders/{message_id}?contract_id={contract_id}");
+    lx_delete(&url, api_key, &format!("cancelling order {message_id}"))
+}
+
+/// Make a HTTP DELETE request to the LX API, which takes an empty message
+///
+/// `action` describes the request for use in error messages.
+fn lx_delete(url: &str, api_key: &str, action: &str) -> Result<(), anyhow::Error> {
+    check_online(url)?;
     let req = minreq::delete(url)
         .with_header("Authorization", format!("JWT {api_key}"))
         .with_timeout(10);
 
-    let resp = req.send().with_context(|| "Request data from api/orders")?;
+    let resp = req
+        .send()
+        .with_context(|| format!("Request data from {url}"))?;
 
     info!(
         target: "lx_http_get",
@@ -158,8 +250,8 @@ pub fn lx_cancel_all_orders(api_key: &str) -> Result<(), anyhow::Error> {
         Ok(())
     } else {
         Err(anyhow::Error::msg(format!(
-            "bad status code {} when cancelling orders",
-            resp.status_code
+            "bad status code {} when {}",
+            resp.status_code, action,
         )))
     }
 }
@@ -0,0 +1,166 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Kraken
+//!
+//! Data Structures etc for the Kraken Websockets API (v2), which gives us a
+//! second price reference independent of Coinbase.
+//!
+
+use crate::price::BitcoinPrice;
+use crate::queue::Sender;
+use crate::supervisor::Heartbeat;
+use crate::units::UtcTime;
+use crate::websocket::{self, Socket};
+use log::{info, warn};
+use serde::Deserialize;
+use std::ops::ControlFlow;
+use std::thread;
+
+/// Name of the Kraken ticker as a price source
+pub const PRICE_SOURCE: &str = "Kraken";
+
+#[derive(Deserialize, Debug)]
+struct Ticker {
+    symbol: String,
+    #[serde(deserialize_with = "crate::units::deserialize_dollars")]
+    bid: crate::units::Price,
+    #[serde(deserialize_with = "crate::units::deserialize_dollars")]
+    ask: crate::units::Price,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "channel")]
+enum ChannelMsg {
+    Ticker {
+        data: Vec<Ticker>,
+    },
+    #[serde(other)]
+    Other,
+}
+//{"channel":"ticker","type":"update","data":[{"symbol":"BTC/USD","bid":63000.1,"ask":63000.2,...}]}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum KrakenMsg {
+    Channel(ChannelMsg),
+    Response {
+        method: String,
+        success: bool,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+//{"method":"subscribe","result":{"channel":"ticker","symbol":"BTC/USD",...},"success":true,...}
+
+/// Connects to Kraken and subscribes to the BTC/USD ticker
+fn connect() -> anyhow::Result<Socket> {
+    let (mut sock, _) = tungstenite::client::connect("wss://ws.kraken.com/v2")?;
+    // Subscribe to the public BTC/USD ticker, which is pushed whenever the
+    // top of book changes. Kraken also sends a heartbeat every second when
+    // nothing else is happening, which keeps our supervisor happy.
+    sock.write_message(tungstenite::protocol::Message::Text(
+        "{\"method\":\"subscribe\",\"params\":{\"channel\":\"ticker\",\"symbol\":[\"BTC/USD\"]}}"
+            .to_string(),
+    ))?;
+    Ok(sock)
+}
+
+/// Starts a thread which feeds Kraken's BTC/USD ticker into the main loop as
+/// price references
+///
+/// The thread exits once its heartbeat is retired. Kraken's ticker carries no
+/// timestamp, so prices are stamped with the time they are received.
+///
+/// Kraken is only one of several price sources, so if it cannot be reached
+/// we keep retrying with backoff (see [`websocket::run_reconnecting`]).
+pub fn spawn_ticker_thread(
+    tx: Sender<crate::connect::Message>,
+    heartbeat: Heartbeat,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        websocket::run_reconnecting("Kraken", &heartbeat, connect, |msg| {
+            info!(target: "kr_datafeed", "{}", msg);
+            match serde_json::from_str(msg) {
+                Ok(KrakenMsg::Channel(ChannelMsg::Ticker { data })) => {
+                    for ticker in data.into_iter().filter(|t| t.symbol == "BTC/USD") {
+                        let mid = ticker.bid.half() + ticker.ask.half();
+                        let new_price = BitcoinPrice {
+                            btc_price: mid,
+                            timestamp: UtcTime::now(),
+                        };
+                        tx.send(crate::connect::Message::PriceReference(
+                            PRICE_SOURCE,
+                            new_price,
+                        ))
+                        .unwrap();
+                    }
+                }
+                Ok(KrakenMsg::Channel(ChannelMsg::Other)) => {}
+                Ok(KrakenMsg::Response {
+                    method,
+                    success,
+                    error,
+                }) => {
+                    if !success {
+                        warn!(
+                            "Kraken {} request failed: {}",
+                            method,
+                            error.as_deref().unwrap_or("no error given")
+                        );
+                    }
+                }
+                Err(e) => warn!("Failed to parse Kraken message {}: {}", msg, e),
+            }
+            ControlFlow::Continue(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_messages() {
+        let ticker = r#"{"channel":"ticker","type":"update","data":[{"symbol":"BTC/USD","bid":63000.1,"bid_qty":0.5,"ask":63000.2,"ask_qty":1.25,"last":63000.2,"volume":1234.5,"vwap":62800.0,"low":62000.0,"high":64000.0,"change":500.0,"change_pct":0.8}]}"#;
+        match serde_json::from_str(ticker).unwrap() {
+            KrakenMsg::Channel(ChannelMsg::Ticker { data }) => {
+                assert_eq!(data.len(), 1);
+                assert_eq!(data[0].symbol, "BTC/USD");
+                assert_eq!(
+                    data[0].bid,
+                    crate::units::Price::from_str("63000.1").unwrap()
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        let heartbeat = r#"{"channel":"heartbeat"}"#;
+        assert!(matches!(
+            serde_json::from_str(heartbeat).unwrap(),
+            KrakenMsg::Channel(ChannelMsg::Other)
+        ));
+
+        let response = r#"{"method":"subscribe","success":false,"error":"Currency pair not supported","time_in":"2024-03-01T15:00:00.000000Z","time_out":"2024-03-01T15:00:00.000100Z"}"#;
+        match serde_json::from_str(response).unwrap() {
+            KrakenMsg::Response { success, error, .. } => {
+                assert!(!success);
+                assert_eq!(error.as_deref(), Some("Currency pair not supported"));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,400 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Block Trades
+//!
+//! Counterparties sometimes propose block trades to us over LX chat. We used
+//! to just log these messages. Now, for counterparties on our allow-list, we
+//! try to parse a proposal out of the message, evaluate it with the same
+//! statistics we use for orders on the book, and log a recommendation along
+//! with a `quote` command which accepts the trade.
+//!
+//! A proposal names a side, a number of contracts, an option and a price, in
+//! any order, e.g. "buy 5x 2030-06-28C60000 @ $1,234" or "offering 10
+//! BTC-Mini-28JUN2030-60000-Put at 850". Options may be given by our usual
+//! option ID, in which case mini contracts are assumed, or by LX label. The
+//! side is the counterparty's: if they "buy", we would sell.
+//!
+//! Only messages whose sender is on the allow-list are evaluated, so that our
+//! own replies in the same chat are not mistaken for proposals to us. Messages
+//! which do not say who sent them are only logged.
+//!
+
+use super::collateral::{Portfolio, Requirement};
+use super::interesting::{BidStats, Interestingness};
+use super::quote::Side;
+use super::Contract;
+use crate::option;
+use crate::price::BitcoinPrice;
+use crate::units::{ContractSize, Price, Quantity, Underlying, UtcTime};
+use log::info;
+use std::{fmt, str::FromStr};
+
+/// A block trade proposed by a counterparty
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Proposal {
+    /// Our side of the trade
+    pub side: Side,
+    /// Number of contracts
+    pub size: i64,
+    /// The option to trade
+    pub option: option::Option,
+    /// Whether the contracts are full-size or minis
+    pub contract_size: ContractSize,
+    /// Price per unit of the underlying
+    pub price: Price,
+}
+
+/// Parses an LX contract label such as "BTC-Mini-28JUN2030-60000-Call"
+fn parse_label(s: &str) -> Option<(option::Option, ContractSize)> {
+    let mut parts = s.split('-');
+    if !parts.next()?.eq_ignore_ascii_case("btc") {
+        return None;
+    }
+    let mut date = parts.next()?;
+    let mut contract_size = ContractSize::Full;
+    if date.eq_ignore_ascii_case("mini") {
+        contract_size = ContractSize::Mini;
+        date = parts.next()?;
+    }
+    let date = chrono::NaiveDate::parse_from_str(date, "%d%b%Y").ok()?;
+    let strike = parts.next()?;
+    let pc = match parts.next()?.to_ascii_lowercase().as_str() {
+        "call" => 'C',
+        "put" => 'P',
+        _ => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    let opt = option::Option::from_str(&format!("{}{}{}", date.format("%F"), pc, strike)).ok()?;
+    Some((opt, contract_size))
+}
+
+/// Parses a number of contracts, such as "5", "5x", "x5" or "5c"
+fn parse_size(s: &str) -> Option<i64> {
+    let n = s
+        .strip_suffix('x')
+        .or_else(|| s.strip_prefix('x'))
+        .or_else(|| s.strip_suffix("contracts"))
+        .or_else(|| s.strip_suffix('c'))
+        .unwrap_or(s);
+    i64::from_str(n).ok().filter(|n| *n > 0)
+}
+
+/// Parses a price, such as "1234.56" or "$1,234"
+fn parse_price(s: &str) -> Option<Price> {
+    let s = s.trim_start_matches('$').replace(',', "");
+    Price::from_str(&s).ok().filter(|p| *p > Price::ZERO)
+}
+
+impl FromStr for Proposal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut side = None;
+        let mut size = None;
+        let mut instrument = None;
+        let mut price = None;
+
+        let lower = s.to_ascii_lowercase();
+        let mut words = lower
+            .split_whitespace()
+            .map(|word| word.trim_end_matches([',', '.', '!', '?', ';', ':']))
+            .peekable();
+        while let Some(word) = words.next() {
+            match word {
+                "buy" | "buying" | "bid" | "bidding" => {
+                    side.get_or_insert(Side::Ask);
+                }
+                "sell" | "selling" | "offer" | "offering" | "ask" | "asking" => {
+                    side.get_or_insert(Side::Bid);
+                }
+                "@" | "at" | "for" => {
+                    if let Some(p) = words.peek().and_then(|next| parse_price(next)) {
+                        price.get_or_insert(p);
+                        words.next();
+                    }
+                }
+                _ => {
+                    if let Some(p) = word.strip_prefix('@').and_then(parse_price) {
+                        price.get_or_insert(p);
+                    } else if word.starts_with('$') {
+                        if let Some(p) = parse_price(word) {
+                            price.get_or_insert(p);
+                        }
+                    } else if let Ok(opt) = option::Option::from_str(word) {
+                        instrument.get_or_insert((opt, ContractSize::Mini));
+                    } else if let Some(parsed) = parse_label(word) {
+                        instrument.get_or_insert(parsed);
+                    } else if let Some(n) = parse_size(word) {
+                        size.get_or_insert(n);
+                    }
+                }
+            }
+        }
+
+        let (option, contract_size) = instrument.ok_or("no option named")?;
+        Ok(Proposal {
+            side: side.ok_or("no side (buy or sell) given")?,
+            size: size.ok_or("no number of contracts given")?,
+            option,
+            contract_size,
+            price: price.ok_or("no price given")?,
+        })
+    }
+}
+
+impl fmt::Display for Proposal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {:?} {} at ${}",
+            match self.side {
+                Side::Bid => "buy",
+                Side::Ask => "sell",
+            },
+            self.size,
+            self.contract_size,
+            self.option,
+            self.price,
+        )
+    }
+}
+
+impl Proposal {
+    /// Whether a contract is the one the proposal is for
+    pub fn matches(&self, contract: &Contract) -> bool {
+        contract.active()
+            && contract.underlying() == Underlying::Btc
+            && contract.contract_size() == self.contract_size
+            && contract.as_option() == Some(self.option)
+    }
+
+    /// The command which places the order accepting the proposal
+    pub fn accept_command(&self) -> String {
+        format!(
+            "trade-tracker-cli quote <api key> {} {} {} -p {}{}",
+            self.option,
+            self.side,
+            self.size,
+            self.price,
+            if self.contract_size == ContractSize::Full {
+                " --full"
+            } else {
+                ""
+            },
+        )
+    }
+}
+
+/// What we think of a proposal
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Recommendation {
+    /// The trade meets the criteria we use to take orders from the book
+    Accept,
+    /// The trade is acceptable but not obviously good, or needs a human decision
+    Consider,
+    /// The trade should be declined, for the given reason
+    Decline(String),
+}
+
+impl fmt::Display for Recommendation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Recommendation::Accept => f.write_str("accept"),
+            Recommendation::Consider => f.write_str("consider"),
+            Recommendation::Decline(ref reason) => write!(f, "decline ({reason})"),
+        }
+    }
+}
+
+/// A proposal, along with our statistics on it and a recommendation
+#[derive(Clone, PartialEq, Debug)]
+pub struct Evaluation {
+    /// The proposal
+    pub proposal: Proposal,
+    /// Implied volatility at the proposed price, if it can be computed
+    pub iv: Option<f64>,
+    /// Annualized return on collateral of selling at the proposed price
+    pub arr: Option<f64>,
+    /// Probability, under an 80% volatility, that the short side loses money
+    pub loss80: Option<f64>,
+    /// Collateral which accepting would lock up
+    pub locked: Requirement,
+    /// Collateral which accepting would release
+    pub released: Requirement,
+    /// What we think of the proposal
+    pub recommendation: Recommendation,
+}
+
+/// Evaluates a proposal against our positions and balances
+///
+/// Sales are judged like bids on the book: they must be of OTM options, have
+/// good enough stats and be fundable. Since we only buy options to close our
+/// shorts, purchases are declined unless they do so, and are otherwise left
+/// to a human decision.
+pub fn evaluate(
+    proposal: &Proposal,
+    contract: &Contract,
+    btc_price: BitcoinPrice,
+    portfolio: &Portfolio,
+    available_usd: Price,
+    available_btc: bitcoin::Amount,
+    fee: Price,
+) -> Evaluation {
+    let now = UtcTime::now();
+    let opt = proposal.option;
+    let minis = proposal.size * proposal.contract_size.in_minis();
+
+    // OrderStats assumes that an IV exists, which is not true of absurd prices
+    let stats = if opt.bs_iv(now, btc_price.btc_price, proposal.price).is_ok() {
+        BidStats::from_order(
+            btc_price,
+            contract,
+            proposal.price,
+            Quantity::Contracts(minis),
+            fee,
+        )
+    } else {
+        None
+    };
+    let mut eval = Evaluation {
+        proposal: *proposal,
+        iv: stats.map(|s| s.iv()),
+        arr: stats.map(|s| s.arr()),
+        loss80: stats.map(|s| s.loss80()),
+        locked: Requirement::default(),
+        released: Requirement::default(),
+        recommendation: Recommendation::Consider,
+    };
+
+    eval.recommendation = match proposal.side {
+        Side::Ask => {
+            eval.locked = portfolio.marginal_requirement(opt, -minis);
+            let fundable =
+                match portfolio.max_short(opt, proposal.price, fee, available_usd, available_btc) {
+                    Quantity::Contracts(n) => n,
+                    _ => 0,
+                };
+            match stats {
+                None => Recommendation::Decline(
+                    "only OTM options with a fresh price reference are evaluated".to_owned(),
+                ),
+                Some(_) if fundable < minis => Recommendation::Decline(format!(
+                    "we can only fund {fundable} of the {minis} mini contracts"
+                )),
+                Some(stats) => match stats.interestingness() {
+                    Interestingness::Take => Recommendation::Accept,
+                    Interestingness::LogTake => Recommendation::Consider,
+                    _ => Recommendation::Decline(
+                        "IV, ARR or loss80 is worse than our thresholds".to_owned(),
+                    ),
+                },
+            }
+        }
+        Side::Bid => {
+            let held = portfolio
+                .positions()
+                .find(|(held, _)| *held == opt)
+                .map_or(0, |(_, n)| n);
+            let mut after = portfolio.clone();
+            after.add(opt, minis);
+            let (before, after) = (portfolio.requirement(), after.requirement());
+            eval.released = Requirement {
+                usd: (before.usd - after.usd).max(Price::ZERO),
+                btc: before
+                    .btc
+                    .checked_sub(after.btc)
+                    .unwrap_or(bitcoin::Amount::ZERO),
+            };
+            if held >= 0 {
+                Recommendation::Decline("we only buy options to close short positions".to_owned())
+            } else if minis > -held {
+                Recommendation::Decline(format!("we are only short {} mini contracts", -held))
+            } else {
+                Recommendation::Consider
+            }
+        }
+    };
+    eval
+}
+
+impl Evaluation {
+    /// Logs the evaluation, along with the command to accept it if we would
+    pub fn log(&self, counterparty: &str) {
+        info!(
+            "Block trade proposed by {}: {}",
+            counterparty, self.proposal
+        );
+        match (self.iv, self.arr, self.loss80) {
+            (Some(iv), Some(arr), Some(loss80)) => info!(
+                "    IV {:.2}%, ARR {:.2}%, loss80 {:.2}%",
+                iv * 100.0,
+                arr * 100.0,
+                loss80 * 100.0
+            ),
+            _ => info!("    No statistics available."),
+        }
+        if self.locked != Requirement::default() {
+            info!("    Locks up {}", self.locked);
+        }
+        if self.released != Requirement::default() {
+            info!("    Releases {}", self.released);
+        }
+        info!("    Recommendation: {}", self.recommendation);
+        if !matches!(self.recommendation, Recommendation::Decline(_)) {
+            info!("    To accept: {}", self.proposal.accept_command());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proposals() {
+        let opt = option::Option::from_str("2030-06-28C60000").unwrap();
+        let put = option::Option::from_str("2030-06-28P60000").unwrap();
+
+        let prop = Proposal::from_str("Hi! I'd buy 5x 2030-06-28C60000 @ $1,234.50, ok?").unwrap();
+        assert_eq!(prop.side, Side::Ask);
+        assert_eq!(prop.size, 5);
+        assert_eq!(prop.option, opt);
+        assert_eq!(prop.contract_size, ContractSize::Mini);
+        assert_eq!(prop.price, Price::from_str("1234.50").unwrap());
+        assert_eq!(
+            prop.accept_command(),
+            "trade-tracker-cli quote <api key> 2030-06-28C60000.00 ask 5 -p 1234.50",
+        );
+
+        let prop =
+            Proposal::from_str("offering BTC-28JUN2030-60000-Put 2 contracts at 850").unwrap();
+        assert_eq!(prop.side, Side::Bid);
+        assert_eq!(prop.size, 2);
+        assert_eq!(prop.option, put);
+        assert_eq!(prop.contract_size, ContractSize::Full);
+        assert!(prop.accept_command().ends_with("bid 2 -p 850.00 --full"));
+
+        let prop = Proposal::from_str("selling BTC-Mini-28JUN2030-60000-Call x10 @900").unwrap();
+        assert_eq!(prop.size, 10);
+        assert_eq!(prop.contract_size, ContractSize::Mini);
+        assert_eq!(prop.price, Price::from_str("900").unwrap());
+
+        assert!(Proposal::from_str("gm, how are you?").is_err());
+        assert!(Proposal::from_str("buy 5 2030-06-28C60000").is_err());
+        assert!(Proposal::from_str("buy 2030-06-28C60000 at 1000").is_err());
+        assert!(Proposal::from_str("5 2030-06-28C60000 at 1000").is_err());
+    }
+}
@@ -16,11 +16,66 @@
 //!
 //! Tracks the book state for a specific contract
 //!
+//! LX fills orders at the same price in time priority. Since we rarely
+//! reprice our quotes, it is useful to know where our own orders sit in the
+//! queue. We estimate this from the contract clock: each order remembers the
+//! clock at which it took its place, which it keeps across edits unless its
+//! price changes or its size increases (either of which sends it to the back
+//! of the queue).
+//!
+//! The clock also lets us check that datafeed messages are applied in order.
+//! It should increase by one with every change to the book (though several
+//! orders may change at the same tick), but reconnects and replays can
+//! deliver messages twice or out of order. Exact duplicates, and anything at
+//! or below a clock we have already moved past, are dropped; messages which
+//! arrive early are held back until the ones before them arrive. If the gap
+//! grows beyond [`MAX_REORDER_CLOCKS`] we give up waiting, apply what we have
+//! and ask for the whole book to be refreshed.
+//!
 
 use super::{datafeed, MessageId};
 use crate::option::{Call, Put};
 use crate::units::{Asset, Price, Quantity, UtcTime};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+/// Number of clock ticks we will wait for a missing message before giving up
+/// and refreshing the book
+pub const MAX_REORDER_CLOCKS: u64 = 20;
+
+/// Number of recently-applied datafeed messages kept for replaying over a
+/// book state snapshot
+const REPLAY_CAPACITY: usize = 200;
+
+/// Counts of anomalies in the contract clocks of datafeed messages
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Hash)]
+pub struct ClockStats {
+    /// Messages dropped because we had already applied them
+    pub duplicates: usize,
+    /// Messages which arrived early and were held back for earlier ones
+    pub reordered: usize,
+    /// Gaps which we gave up waiting to be filled
+    pub gaps: usize,
+}
+
+impl fmt::Display for ClockStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} duplicates dropped, {} reordered, {} gaps",
+            self.duplicates, self.reordered, self.gaps,
+        )
+    }
+}
+
+/// Datafeed messages which have passed the clock checks
+#[derive(Clone, PartialEq, Eq, Debug, Hash, Default)]
+pub struct Sequenced {
+    /// Messages to apply, in clock order
+    pub ready: Vec<datafeed::Order>,
+    /// Whether messages were skipped, so that the book should be refreshed
+    pub gap: bool,
+}
 
 /// Book state for a specific contract
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
@@ -28,6 +83,20 @@ pub struct BookState {
     asset: Asset,
     bids: BTreeMap<(Price, MessageId), Order>,
     asks: BTreeMap<(Price, MessageId), Order>,
+    /// The clock of the last message applied from the datafeed
+    clock: Option<u64>,
+    /// Messages already applied at `clock`, for detecting duplicates
+    applied: Vec<datafeed::Order>,
+    /// Clock of a book state snapshot, at or below which messages are stale
+    clock_floor: u64,
+    /// Messages held back until the ones before them arrive
+    pending: BTreeMap<u64, Vec<datafeed::Order>>,
+    /// The most recently applied messages, oldest first
+    recent: VecDeque<datafeed::Order>,
+    /// When the book was last loaded from a snapshot
+    refreshed: Option<UtcTime>,
+    /// When a snapshot was last requested for the book
+    refresh_requested: Option<UtcTime>,
 }
 
 impl BookState {
@@ -37,9 +106,179 @@ impl BookState {
             asset,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            clock: None,
+            applied: vec![],
+            clock_floor: 0,
+            pending: BTreeMap::new(),
+            recent: VecDeque::new(),
+            refreshed: None,
+            refresh_requested: None,
         }
     }
 
+    /// Empties the book ahead of loading a snapshot taken at time `now`
+    ///
+    /// Returns the messages recently applied to the book, which should be
+    /// passed to [`BookState::replay`] once the snapshot is loaded. Messages
+    /// held back for a gap are kept, since the snapshot may close the gap.
+    pub fn reset(&mut self, now: UtcTime) -> Vec<datafeed::Order> {
+        let recent = std::mem::take(&mut self.recent);
+        *self = BookState {
+            refreshed: Some(now),
+            refresh_requested: self.refresh_requested,
+            pending: std::mem::take(&mut self.pending),
+            ..BookState::new(self.asset)
+        };
+        recent.into()
+    }
+
+    /// Records that the book was loaded from a snapshot whose latest clock was
+    /// `floor`, and reapplies those of `recent` which came after it
+    ///
+    /// The snapshot may have been taken some time before we received it, in
+    /// which case the datafeed messages since have already been applied to
+    /// the old book and would otherwise be lost. Messages held back for a gap
+    /// which the snapshot closes are applied too. Returns the number replayed.
+    pub fn replay(
+        &mut self,
+        mut recent: Vec<datafeed::Order>,
+        floor: u64,
+        stats: &mut ClockStats,
+    ) -> usize {
+        recent.extend(self.set_clock_floor(floor));
+        recent.sort_by_key(|order| order.clock);
+        let mut n = 0;
+        for order in recent.into_iter().filter(|order| order.clock > floor) {
+            for order in self.sequence(order, stats).ready {
+                self.insert_order(order);
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// When the book was last loaded from a snapshot, if ever
+    pub fn refreshed(&self) -> Option<UtcTime> {
+        self.refreshed
+    }
+
+    /// When the book was last loaded or requested, whichever was later
+    pub fn last_refresh(&self) -> Option<UtcTime> {
+        self.refreshed.max(self.refresh_requested)
+    }
+
+    /// Records that a snapshot has been requested for the book
+    pub fn set_refresh_requested(&mut self, now: UtcTime) {
+        self.refresh_requested = Some(now);
+    }
+
+    /// Records that the book was loaded from a snapshot (e.g. from the book
+    /// state endpoint) whose latest clock was `floor`
+    ///
+    /// Datafeed messages at or below this clock are assumed to be reflected
+    /// in the snapshot already, and the next message after it is accepted
+    /// whatever its clock. Held-back messages at or below it are discarded;
+    /// those above it are returned, to be passed through [`Self::sequence`]
+    /// again.
+    pub fn set_clock_floor(&mut self, floor: u64) -> Vec<datafeed::Order> {
+        self.clock = None;
+        self.applied.clear();
+        self.clock_floor = floor;
+        std::mem::take(&mut self.pending)
+            .into_iter()
+            .filter(|(clock, _)| *clock > floor)
+            .flat_map(|(_, orders)| orders)
+            .collect()
+    }
+
+    /// Passes a datafeed message through the clock checks, returning the
+    /// messages which are ready to be applied
+    ///
+    /// Does not apply them, since our own orders also need to be tracked
+    /// elsewhere; the caller should pass each to [`BookState::insert_order`].
+    pub fn sequence(&mut self, order: datafeed::Order, stats: &mut ClockStats) -> Sequenced {
+        let mut ret = Sequenced::default();
+        let clock = order.clock;
+        let last = match self.clock {
+            Some(last) => last,
+            None if clock <= self.clock_floor => {
+                stats.duplicates += 1;
+                return ret;
+            }
+            None => {
+                self.accept(order, &mut ret);
+                return ret;
+            }
+        };
+
+        if clock < last
+            || self.applied.contains(&order)
+            || self.pending.get(&clock).is_some_and(|p| p.contains(&order))
+        {
+            stats.duplicates += 1;
+        } else if clock <= last + 1 {
+            self.accept(order, &mut ret);
+            self.drain_pending(&mut ret, stats);
+        } else {
+            self.pending.entry(clock).or_default().push(order);
+            if clock - last > MAX_REORDER_CLOCKS {
+                stats.gaps += 1;
+                self.flush_pending(&mut ret);
+            }
+        }
+        ret
+    }
+
+    /// Gives up on any held-back messages, returning them to be applied
+    ///
+    /// Call this periodically, so that a gap in a quiet contract is not waited
+    /// on forever.
+    pub fn flush_pending_clocks(&mut self, stats: &mut ClockStats) -> Sequenced {
+        let mut ret = Sequenced::default();
+        if !self.pending.is_empty() {
+            stats.gaps += 1;
+            self.flush_pending(&mut ret);
+        }
+        ret
+    }
+
+    /// Accepts a message, advancing the clock
+    fn accept(&mut self, order: datafeed::Order, ret: &mut Sequenced) {
+        if self.clock != Some(order.clock) {
+            self.clock = Some(order.clock);
+            self.applied.clear();
+        }
+        self.applied.push(order.clone());
+        if self.recent.len() == REPLAY_CAPACITY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(order.clone());
+        ret.ready.push(order);
+    }
+
+    /// Accepts any held-back messages which are now next in line
+    fn drain_pending(&mut self, ret: &mut Sequenced, stats: &mut ClockStats) {
+        while let Some(entry) = self.pending.first_entry() {
+            if self.clock.is_some_and(|last| *entry.key() > last + 1) {
+                break;
+            }
+            for order in entry.remove() {
+                stats.reordered += 1;
+                self.accept(order, ret);
+            }
+        }
+    }
+
+    /// Accepts all held-back messages, skipping over whatever is missing
+    fn flush_pending(&mut self, ret: &mut Sequenced) {
+        for (_, orders) in std::mem::take(&mut self.pending) {
+            for order in orders {
+                self.accept(order, ret);
+            }
+        }
+        ret.gap = true;
+    }
+
     /// Add an order to the book
     pub fn insert_order(&mut self, order: datafeed::Order) {
         let size = order.size.with_asset(self.asset);
@@ -55,13 +294,23 @@ impl BookState {
         // believe it will do the right thing for repeated edits.)
         //
         // So we have to scan the whole book to find the mid.
-        book.retain(|(_, mid), _| *mid != order.message_id);
+        let mut priority = order.clock;
+        book.retain(|(_, mid), old| {
+            if *mid != order.message_id {
+                return true;
+            }
+            if old.price == order.price && size.abs() <= old.size.abs() {
+                priority = old.priority;
+            }
+            false
+        });
         if size.is_nonzero() {
             let book_order = Order {
                 price: order.price,
                 size,
                 message_id: order.message_id,
                 timestamp: order.timestamp,
+                priority,
             };
             book.insert((order.price, order.message_id), book_order);
         }
@@ -97,16 +346,20 @@ impl BookState {
     }
 
     /// Returns the (cost in contracts, gain in USD) of selling into every bid
+    ///
+    /// The fee is LX's fee per 100 contracts, which on puts is locked up along
+    /// with the collateral.
     pub fn clear_bids(
         &self,
         option: &crate::option::Option,
+        fee: Price,
         mut max_usd: Price,
         mut max_btc: bitcoin::Amount,
     ) -> (Quantity, Price) {
         let mut ret_usd = Price::ZERO;
         let mut ret_contr = Quantity::Zero;
         for (_, order) in self.bids.iter() {
-            let (max_sale, usd_per_100) = option.max_sale(order.price, max_usd, max_btc);
+            let (max_sale, usd_per_100) = option.max_sale(order.price, fee, max_usd, max_btc);
             let sale = max_sale.min(order.size);
             if sale.is_zero() {
                 break;
@@ -126,6 +379,36 @@ impl BookState {
         (ret_contr, ret_usd)
     }
 
+    /// Estimate the position of an order in the queue at its price level
+    ///
+    /// Returns `None` if the order is not in the book.
+    pub fn queue_position(&self, mid: MessageId) -> Option<QueuePosition> {
+        let (book, order) = [&self.bids, &self.asks].iter().copied().find_map(|book| {
+            book.values()
+                .find(|order| order.message_id == mid)
+                .map(|order| (book, order))
+        })?;
+
+        let mut ret = QueuePosition {
+            orders_ahead: 0,
+            size_ahead: Quantity::Zero,
+            size_behind: Quantity::Zero,
+        };
+        for other in book.values() {
+            if other.price != order.price || other.message_id == mid {
+                continue;
+            }
+            // If we can't tell which came first, assume the worst
+            if other.priority <= order.priority {
+                ret.orders_ahead += 1;
+                ret.size_ahead += other.size.abs();
+            } else {
+                ret.size_behind += other.size.abs();
+            }
+        }
+        Some(ret)
+    }
+
     /// Yield an iterator over all bids, from best to worst
     pub fn bids(&self) -> impl Iterator<Item = &Order> {
         self.bids.values().rev()
@@ -148,4 +431,216 @@ pub struct Order {
     pub message_id: MessageId,
     /// Timestamp that the order occured on
     pub timestamp: UtcTime,
+    /// Contract clock at which the order took its place in the queue
+    pub priority: u64,
+}
+
+/// Estimated position of an order in the queue at its price level
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct QueuePosition {
+    /// Number of orders ahead of this one
+    pub orders_ahead: usize,
+    /// Total size of the orders ahead of this one
+    pub size_ahead: Quantity,
+    /// Total size of the orders behind this one
+    pub size_behind: Quantity,
+}
+
+impl QueuePosition {
+    /// Whether the order is first in line to be filled at its price level
+    pub fn is_first(&self) -> bool {
+        self.orders_ahead == 0
+    }
+}
+
+impl fmt::Display for QueuePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_first() {
+            f.write_str("first in queue")?;
+        } else {
+            write!(
+                f,
+                "{} ahead in {} orders",
+                self.size_ahead, self.orders_ahead
+            )?;
+        }
+        if self.size_behind.is_nonzero() {
+            write!(f, ", {} behind", self.size_behind)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledgerx::json;
+    use crate::ledgerx::ContractId;
+    use std::str::FromStr;
+
+    fn ask(mid: u8, price: &str, size: i64, clock: u64) -> datafeed::Order {
+        let state = json::BookState {
+            clock,
+            contract_id: ContractId::from(1),
+            mid: [mid; 16],
+            is_ask: true,
+            price: Price::from_str(price).unwrap(),
+            size,
+        };
+        datafeed::Order::from((state, UtcTime::from_unix_i64(1_700_000_000).unwrap()))
+    }
+
+    fn mid(mid: u8) -> MessageId {
+        ask(mid, "0", 0, 0).message_id
+    }
+
+    #[test]
+    fn queue_position() {
+        let opt = crate::option::Option::from_str("2024-03-29P40000").unwrap();
+        let mut book = BookState::new(Asset::Option {
+            underlying: crate::units::Underlying::Btc,
+            option: opt,
+            contract_size: Default::default(),
+        });
+        book.insert_order(ask(1, "1000", 5, 10));
+        book.insert_order(ask(2, "1000", 3, 11));
+        book.insert_order(ask(3, "1100", 4, 12));
+        book.insert_order(ask(4, "1000", 2, 13));
+
+        let pos = book.queue_position(mid(2)).unwrap();
+        assert_eq!(pos.orders_ahead, 1);
+        assert_eq!(pos.size_ahead, Quantity::Contracts(5));
+        assert_eq!(pos.size_behind, Quantity::Contracts(2));
+        assert!(book.queue_position(mid(3)).unwrap().is_first());
+        assert!(book.queue_position(mid(9)).is_none());
+
+        // Reducing the size of the first order keeps its place...
+        book.insert_order(ask(1, "1000", 4, 14));
+        assert!(book.queue_position(mid(1)).unwrap().is_first());
+        // ...but increasing it does not
+        book.insert_order(ask(1, "1000", 6, 15));
+        let pos = book.queue_position(mid(1)).unwrap();
+        assert_eq!(pos.orders_ahead, 2);
+        assert!(book.queue_position(mid(2)).unwrap().is_first());
+    }
+
+    #[test]
+    fn clock_sequencing() {
+        let mut book = BookState::new(Asset::Btc);
+        let mut stats = ClockStats::default();
+        let clocks = |seq: Sequenced| -> Vec<u64> { seq.ready.iter().map(|o| o.clock).collect() };
+
+        // Anything at or below a snapshot's clock is already reflected in it
+        book.set_clock_floor(100);
+        assert!(book
+            .sequence(ask(1, "1000", 1, 100), &mut stats)
+            .ready
+            .is_empty());
+        assert_eq!(stats.duplicates, 1);
+        // The first message after it is accepted, even if the clock skipped
+        assert_eq!(
+            clocks(book.sequence(ask(1, "1000", 1, 105), &mut stats)),
+            vec![105]
+        );
+
+        // Several orders can change at one tick, but exact repeats are dropped
+        assert_eq!(
+            clocks(book.sequence(ask(2, "1000", 1, 105), &mut stats)),
+            vec![105]
+        );
+        assert!(book
+            .sequence(ask(2, "1000", 1, 105), &mut stats)
+            .ready
+            .is_empty());
+        assert!(book
+            .sequence(ask(3, "1000", 1, 104), &mut stats)
+            .ready
+            .is_empty());
+        assert_eq!(stats.duplicates, 3);
+
+        // Early messages wait for the ones before them
+        assert!(book
+            .sequence(ask(1, "1000", 2, 108), &mut stats)
+            .ready
+            .is_empty());
+        assert!(book
+            .sequence(ask(1, "1000", 3, 107), &mut stats)
+            .ready
+            .is_empty());
+        assert!(book
+            .sequence(ask(1, "1000", 3, 107), &mut stats)
+            .ready
+            .is_empty());
+        assert_eq!(stats.duplicates, 4);
+        let seq = book.sequence(ask(1, "1000", 4, 106), &mut stats);
+        assert!(!seq.gap);
+        assert_eq!(clocks(seq), vec![106, 107, 108]);
+        assert_eq!(stats.reordered, 2);
+
+        // A big enough gap is given up on
+        let seq = book.sequence(ask(1, "1000", 1, 110), &mut stats);
+        assert!(seq.ready.is_empty());
+        let seq = book.sequence(ask(1, "1000", 1, 109 + MAX_REORDER_CLOCKS), &mut stats);
+        assert!(seq.gap);
+        assert_eq!(clocks(seq), vec![110, 109 + MAX_REORDER_CLOCKS]);
+        assert_eq!(stats.gaps, 1);
+
+        // ...as is a small one, if we are told to stop waiting
+        book.sequence(ask(1, "1000", 1, 111 + MAX_REORDER_CLOCKS), &mut stats);
+        let seq = book.flush_pending_clocks(&mut stats);
+        assert!(seq.gap);
+        assert_eq!(clocks(seq), vec![111 + MAX_REORDER_CLOCKS]);
+        assert!(!book.flush_pending_clocks(&mut stats).gap);
+        assert_eq!(stats.gaps, 2);
+        assert_eq!(stats.reordered, 2);
+    }
+
+    #[test]
+    fn snapshot_replay() {
+        let mut book = BookState::new(Asset::Btc);
+        let mut stats = ClockStats::default();
+        book.set_clock_floor(100);
+        for order in [
+            ask(1, "1000", 1, 101),
+            ask(2, "1010", 2, 102),
+            ask(3, "1020", 3, 103),
+        ] {
+            for order in book.sequence(order, &mut stats).ready {
+                book.insert_order(order);
+            }
+        }
+        assert_eq!(book.asks().count(), 3);
+        assert_eq!(book.refreshed(), None);
+
+        // A snapshot taken at clock 101 arrives after 102 and 103 were applied
+        let now = UtcTime::from_unix_i64(1_700_000_000).unwrap();
+        let recent = book.reset(now);
+        assert_eq!(book.asks().count(), 0);
+        book.insert_order(ask(1, "1000", 1, 101));
+        assert_eq!(book.replay(recent, 101, &mut stats), 2);
+        assert_eq!(book.asks().count(), 3);
+        assert_eq!(book.refreshed(), Some(now));
+        assert_eq!(book.last_refresh(), Some(now));
+
+        // The live feed carries on from where the replay left off
+        assert!(book
+            .sequence(ask(3, "1020", 3, 103), &mut stats)
+            .ready
+            .is_empty());
+        let seq = book.sequence(ask(2, "1010", 0, 104), &mut stats);
+        assert!(!seq.gap);
+        assert_eq!(seq.ready.len(), 1);
+        assert_eq!(stats.duplicates, 1);
+
+        // A message held back for a gap is applied once a snapshot closes it
+        assert!(book
+            .sequence(ask(4, "1030", 4, 107), &mut stats)
+            .ready
+            .is_empty());
+        let recent = book.reset(now);
+        book.insert_order(ask(1, "1000", 1, 106));
+        assert_eq!(book.replay(recent, 106, &mut stats), 1);
+        assert_eq!(book.asks().count(), 2);
+        assert_eq!(stats.gaps, 0);
+    }
 }
@@ -0,0 +1,185 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! No-Arbitrage Bounds
+//!
+//! A final sanity check on the asks we are about to submit. Whatever our
+//! pricing pipeline does, no option price may be below its intrinsic value,
+//! no call may be worth more than the underlying, and no put more than its
+//! strike. Across strikes of the same expiry, calls must get cheaper as the
+//! strike rises and puts must get more expensive. A quote which violates any
+//! of these is absurd, and almost certainly the result of a bug, so rather
+//! than submit it we reject it and complain loudly.
+//!
+
+use crate::option::{self, PutCall};
+use crate::units::Price;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A way in which a quote violates the no-arbitrage bounds
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Violation {
+    /// The price is below the option's intrinsic value
+    BelowIntrinsic { price: Price, intrinsic: Price },
+    /// A call is priced above the underlying
+    AboveUnderlying { price: Price, btc_price: Price },
+    /// A put is priced above its strike
+    AboveStrike { price: Price, strike: Price },
+    /// The price is out of order relative to a quote on an adjacent strike
+    NotMonotonic {
+        price: Price,
+        adjacent: option::Option,
+        adjacent_price: Price,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::BelowIntrinsic { price, intrinsic } => {
+                write!(f, "price {price} is below intrinsic value {intrinsic}")
+            }
+            Violation::AboveUnderlying { price, btc_price } => {
+                write!(f, "price {price} is above the BTC price {btc_price}")
+            }
+            Violation::AboveStrike { price, strike } => {
+                write!(f, "price {price} is above the strike {strike}")
+            }
+            Violation::NotMonotonic {
+                price,
+                adjacent,
+                adjacent_price,
+            } => write!(
+                f,
+                "price {price} is out of order with {adjacent_price} quoted on {adjacent}"
+            ),
+        }
+    }
+}
+
+/// Checks a single quote, in dollars per BTC, against the bounds which do not
+/// depend on other quotes
+pub fn check(opt: &option::Option, price: Price, btc_price: Price) -> Option<Violation> {
+    let intrinsic = opt.intrinsic_value(btc_price);
+    if price < intrinsic {
+        return Some(Violation::BelowIntrinsic { price, intrinsic });
+    }
+    match opt.pc {
+        PutCall::Call if price > btc_price => Some(Violation::AboveUnderlying { price, btc_price }),
+        PutCall::Put if price > opt.strike => Some(Violation::AboveStrike {
+            price,
+            strike: opt.strike,
+        }),
+        _ => None,
+    }
+}
+
+/// Checks a set of quotes, in dollars per BTC, against all the bounds
+///
+/// Returns the first violation of each quote, in the same order as the input.
+/// When two adjacent strikes are out of order, both quotes are rejected, since
+/// we cannot tell which of them is wrong.
+pub fn check_all(quotes: &[(option::Option, Price)], btc_price: Price) -> Vec<Option<Violation>> {
+    let mut ret: Vec<_> = quotes
+        .iter()
+        .map(|(opt, price)| check(opt, *price, btc_price))
+        .collect();
+
+    // Group quotes by side and expiry, in strike order
+    let mut chains = HashMap::<_, Vec<usize>>::new();
+    for (idx, (opt, _)) in quotes.iter().enumerate() {
+        chains.entry((opt.pc, opt.expiry)).or_default().push(idx);
+    }
+    for ((pc, _), mut chain) in chains {
+        chain.sort_by_key(|&idx| quotes[idx].0.strike);
+        for pair in chain.windows(2) {
+            let (lo, hi) = (pair[0], pair[1]);
+            let (lo_opt, lo_price) = quotes[lo];
+            let (hi_opt, hi_price) = quotes[hi];
+            let in_order = match pc {
+                PutCall::Call => lo_price >= hi_price,
+                PutCall::Put => lo_price <= hi_price,
+            };
+            if !in_order {
+                ret[lo].get_or_insert(Violation::NotMonotonic {
+                    price: lo_price,
+                    adjacent: hi_opt,
+                    adjacent_price: hi_price,
+                });
+                ret[hi].get_or_insert(Violation::NotMonotonic {
+                    price: hi_price,
+                    adjacent: lo_opt,
+                    adjacent_price: lo_price,
+                });
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn bounds() {
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let opt = |s: &str| option::Option::from_str(s).unwrap();
+        let btc = p("60000");
+
+        // Single-quote bounds
+        let itm_call = opt("2030-06-28C50000");
+        assert_eq!(check(&itm_call, p("12000"), btc), None);
+        assert!(matches!(
+            check(&itm_call, p("9000"), btc),
+            Some(Violation::BelowIntrinsic { .. })
+        ));
+        assert!(matches!(
+            check(&itm_call, p("60001"), btc),
+            Some(Violation::AboveUnderlying { .. })
+        ));
+        let otm_put = opt("2030-06-28P50000");
+        assert_eq!(check(&otm_put, p("3000"), btc), None);
+        assert!(matches!(
+            check(&otm_put, p("50001"), btc),
+            Some(Violation::AboveStrike { .. })
+        ));
+
+        // Monotonicity across adjacent strikes of the same side and expiry
+        let quotes = [
+            (opt("2030-06-28C70000"), p("5000")),
+            (opt("2030-06-28C80000"), p("6000")),
+            (opt("2030-06-28C90000"), p("2000")),
+            (opt("2030-06-28P40000"), p("1000")),
+            (opt("2030-06-28P50000"), p("3000")),
+            // Different expiry, so not compared against the 2030-06-28 calls
+            (opt("2030-09-27C75000"), p("9000")),
+        ];
+        let result = check_all(&quotes, btc);
+        assert!(matches!(result[0], Some(Violation::NotMonotonic { .. })));
+        assert!(matches!(result[1], Some(Violation::NotMonotonic { .. })));
+        assert_eq!(result[2], None);
+        assert_eq!(result[3], None);
+        assert_eq!(result[4], None);
+        assert_eq!(result[5], None);
+
+        let swapped_puts = [
+            (opt("2030-06-28P40000"), p("3000")),
+            (opt("2030-06-28P50000"), p("1000")),
+        ];
+        assert!(check_all(&swapped_puts, btc).iter().all(Option::is_some));
+    }
+}
@@ -0,0 +1,545 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Closing Positions
+//!
+//! To exit a short quickly, we buy it back by taking the offers on the book.
+//! A close walks the asks from best to worst, up to a maximum price, until it
+//! has covered our open size, and submits a taker bid at each price level.
+//! Our own standing asks are skipped, since taking them would be a self-trade.
+//! Unless given explicitly, the maximum price is the model price (the
+//! Black-Scholes price at our standing IV) plus [`DEFAULT_MAX_SLIPPAGE_PCT`].
+//!
+//! A close can be made from the command line with `close`, which asks for
+//! confirmation, or from a running `connect` by writing lines of the form
+//! `<contract id> [max price]` to its close-request file.
+//!
+
+use super::book::BookState;
+use super::contract_cache::ContractCache;
+use super::json::{BookStateMessage, CreateOrder};
+use super::{datafeed, history, slippage, Contract, ContractId, MessageId};
+use crate::units::{Price, Quantity, UtcTime};
+use crate::{http, option, schema};
+use anyhow::Context;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::{fmt, fs, str::FromStr};
+
+/// Percentage above the model price which we will pay, if no maximum is given
+pub const DEFAULT_MAX_SLIPPAGE_PCT: u32 = 20;
+
+/// A request to close our position in a contract
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Request {
+    /// The contract to close
+    pub contract_id: ContractId,
+    /// The most we will pay per unit of the underlying, if not the default
+    pub max_price: Option<Price>,
+}
+
+impl FromStr for Request {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut words = s.split_whitespace();
+        let contract_id = words
+            .next()
+            .ok_or_else(|| "empty close request".to_string())?
+            .parse::<usize>()
+            .map_err(|e| format!("invalid contract ID in close request {s}: {e}"))?
+            .into();
+        let max_price = words
+            .next()
+            .map(|word| {
+                Price::from_str(word.trim_start_matches('$'))
+                    .map_err(|_| format!("invalid max price in close request {s}"))
+            })
+            .transpose()?;
+        if words.next().is_some() {
+            return Err(format!("trailing data in close request {s}"));
+        }
+        Ok(Request {
+            contract_id,
+            max_price,
+        })
+    }
+}
+
+/// Reads and deletes a file of close requests, one per line
+///
+/// Lines which cannot be parsed are logged and skipped.
+pub fn take_requests(path: &Path) -> anyhow::Result<Vec<Request>> {
+    let data = fs::read_to_string(path).context("reading close request file")?;
+    fs::remove_file(path).context("removing close request file")?;
+    let mut ret = vec![];
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        match Request::from_str(line) {
+            Ok(request) => ret.push(request),
+            Err(e) => warn!("Ignoring close request: {}", e),
+        }
+    }
+    Ok(ret)
+}
+
+/// A single price level of the book which we will take
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Level {
+    /// Price of the offers
+    pub price: Price,
+    /// Number of contracts we will buy at this price
+    pub size: i64,
+}
+
+/// A planned close of a short position
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Plan {
+    /// The contract to buy back
+    pub contract: Contract,
+    /// The option the contract is on
+    pub option: option::Option,
+    /// Number of contracts we are short
+    pub position: i64,
+    /// The Black-Scholes price of the option at our standing IV
+    pub model_price: Price,
+    /// The most we will pay per unit of the underlying
+    pub max_price: Price,
+    /// The price levels we will take, from best to worst
+    pub levels: Vec<Level>,
+}
+
+impl Plan {
+    /// Plans a close of a short position of `position` contracts, against the
+    /// current book, skipping the orders in `own_orders`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        contract: &Contract,
+        book: &BookState,
+        own_orders: &HashSet<MessageId>,
+        position: i64,
+        now: UtcTime,
+        btc_price: Price,
+        max_price: Option<Price>,
+    ) -> anyhow::Result<Self> {
+        let opt = contract
+            .as_option()
+            .with_context(|| format!("contract {contract} is not an option"))?;
+        if position >= 0 {
+            return Err(anyhow::Error::msg(format!(
+                "no short position in {contract} to close"
+            )));
+        }
+        let model_price = opt.bs_price(now, btc_price, super::interesting::STANDING_IV);
+        let max_price = max_price.unwrap_or_else(|| {
+            model_price + model_price.scale_ratio(DEFAULT_MAX_SLIPPAGE_PCT.into(), 100)
+        });
+
+        let mut remaining = -position;
+        let mut levels: Vec<Level> = vec![];
+        for ask in book.asks() {
+            if remaining == 0 || ask.price > max_price {
+                break;
+            }
+            if own_orders.contains(&ask.message_id) {
+                continue;
+            }
+            let size = match contract.contracts(-ask.size) {
+                Ok(n) => n.min(remaining),
+                Err(_) => continue,
+            };
+            remaining -= size;
+            match levels.last_mut() {
+                Some(last) if last.price == ask.price => last.size += size,
+                _ => levels.push(Level {
+                    price: ask.price,
+                    size,
+                }),
+            }
+        }
+        Ok(Plan {
+            contract: contract.clone(),
+            option: opt,
+            position: -position,
+            model_price,
+            max_price,
+            levels,
+        })
+    }
+
+    /// Number of contracts the plan buys back
+    pub fn size(&self) -> i64 {
+        self.levels.iter().map(|level| level.size).sum()
+    }
+
+    /// Total cost, in dollars, of the plan
+    pub fn cost(&self) -> Price {
+        let minis = self.contract.contract_size().in_minis();
+        self.levels
+            .iter()
+            .map(|level| level.price * Quantity::Contracts(level.size * minis))
+            .fold(Price::ZERO, |acc, cost| acc + cost)
+    }
+
+    /// Price of the worst level we take, if any
+    pub fn limit_price(&self) -> Option<Price> {
+        self.levels.last().map(|level| level.price)
+    }
+
+    /// Average price paid per unit of the underlying, if we buy anything
+    pub fn average_price(&self) -> Option<Price> {
+        let minis = self.contract.contract_size().in_minis();
+        match self.size() {
+            0 => None,
+            n => Some(self.cost() / Quantity::Contracts(n * minis)),
+        }
+    }
+
+    /// The slippage of the average price relative to the model price, in percent
+    ///
+    /// Positive values are against us (we pay more than the model price).
+    pub fn slippage_pct(&self) -> Option<f64> {
+        let avg = self.average_price()?;
+        if self.model_price == Price::ZERO {
+            return None;
+        }
+        let model = self.model_price.to_approx_f64();
+        Some((avg.to_approx_f64() - model) / model * 100.0)
+    }
+
+    /// The taker bids which carry out the plan, one per price level
+    pub fn orders(&self) -> Vec<CreateOrder> {
+        self.levels
+            .iter()
+            .map(|level| {
+                CreateOrder::new_bid(&self.contract, Quantity::Contracts(level.size), level.price)
+                    .into_taker()
+            })
+            .collect()
+    }
+
+    /// Logs the plan
+    pub fn log(&self) {
+        info!(
+            "Close {} (id {}): short {}, model price {}, max price {}",
+            self.contract.label(),
+            self.contract.id(),
+            self.position,
+            self.model_price,
+            self.max_price,
+        );
+        for level in &self.levels {
+            info!("    Buy {:4} at {}", level.size, level.price);
+        }
+        match (self.average_price(), self.slippage_pct()) {
+            (Some(avg), Some(pct)) => info!(
+                "    Total {} contracts for {} (average {}, {:+.2}% vs model)",
+                self.size(),
+                self.cost(),
+                avg,
+                pct,
+            ),
+            (Some(avg), None) => info!(
+                "    Total {} contracts for {} (average {})",
+                self.size(),
+                self.cost(),
+                avg,
+            ),
+            (None, _) => {}
+        }
+        if self.size() < self.position {
+            warn!(
+                "Only {} of {} contracts are offered at or below {}; the rest stay open.",
+                self.size(),
+                self.position,
+                self.max_price,
+            );
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "close of {} of {} short {}",
+            self.size(),
+            self.position,
+            self.contract.label(),
+        )?;
+        if let Some(limit) = self.limit_price() {
+            write!(f, " up to {} for {}", limit, self.cost())?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks up our position in a single contract
+fn fetch_position(api_key: &str, contract_id: ContractId) -> anyhow::Result<i64> {
+    let mut ret = 0;
+    let mut next_url = Some("https://api.ledgerx.com/trading/positions?limit=200".to_string());
+    while let Some(url) = next_url {
+        let positions: history::Positions =
+            http::get_json(&url, Some(api_key)).context("looking up current positions")?;
+        ret += positions
+            .open_positions()
+            .filter(|(cid, _)| *cid == contract_id)
+            .map(|(_, size)| size)
+            .sum::<i64>();
+        next_url = positions.next_url();
+    }
+    Ok(ret)
+}
+
+/// An order in the reply to an open-orders request
+#[derive(Deserialize, Debug)]
+struct OpenOrder {
+    #[serde(deserialize_with = "hex::serde::deserialize")]
+    mid: [u8; 16],
+}
+
+/// Looks up the IDs of all our orders resting on the exchange
+///
+/// These may have been placed by a running `connect`, which this process
+/// knows nothing about.
+fn fetch_own_orders(api_key: &str) -> anyhow::Result<HashSet<MessageId>> {
+    let orders: Vec<OpenOrder> =
+        http::get_json_from_data_field("https://trade.ledgerx.com/api/open-orders", Some(api_key))
+            .context("looking up open orders")?;
+    Ok(orders.into_iter().map(|order| order.mid.into()).collect())
+}
+
+/// Fetches the current book of a single contract
+pub fn fetch_book(api_key: &str, contract: &Contract, now: UtcTime) -> anyhow::Result<BookState> {
+    let reply: BookStateMessage = http::get_json(
+        &format!(
+            "https://trade.ledgerx.com/api/book-states/{}",
+            contract.id()
+        ),
+        Some(api_key),
+    )
+    .context("getting book state")?;
+    let mut book = BookState::new(contract.asset());
+    for order in reply.data.book_states {
+        book.insert_order(datafeed::Order::from((order, now)));
+    }
+    Ok(book)
+}
+
+/// Plans a close of our position in a contract, prints it and submits it to LX
+///
+/// Unless `yes` is set, asks for confirmation before submitting. The decision,
+/// and any fills we can find afterward, are appended to the record file and
+/// fill file.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    api_key: &str,
+    request: Request,
+    btc_price: crate::price::BitcoinPrice,
+    contract_cache: &mut ContractCache,
+    yes: bool,
+    record_file: &Path,
+    fill_file: &Path,
+) -> anyhow::Result<()> {
+    let now = UtcTime::now();
+    let contract = contract_cache
+        .fetch(request.contract_id)
+        .with_context(|| format!("looking up contract {}", request.contract_id))?;
+    if let Err(e) = contract_cache.save() {
+        warn!("Failed to save contract cache: {}", e);
+    }
+    let position = fetch_position(api_key, contract.id())?;
+    let book = fetch_book(api_key, &contract, now)?;
+    let own_orders = fetch_own_orders(api_key)?;
+
+    let btc = btc_price.btc_price;
+    info!("BTC price: {}", btc_price);
+    let plan = Plan::new(
+        &contract,
+        &book,
+        &own_orders,
+        position,
+        now,
+        btc,
+        request.max_price,
+    )?;
+    plan.log();
+    if plan.levels.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "no offers on {} at or below {}",
+            contract.label(),
+            plan.max_price
+        )));
+    }
+
+    if !yes && !super::quote::confirm(&format!("Submit {plan}?"))? {
+        info!("Not submitting close.");
+        return Ok(());
+    }
+    if let Err(e) = schema::Record::close(now, &plan, btc).append_to(record_file) {
+        warn!("Failed to write record: {:#}", e);
+    }
+    for order in plan.orders() {
+        let result = http::post_json("https://trade.ledgerx.com/api/orders", api_key, &order);
+        crate::audit::record(
+            "close",
+            crate::audit::Action::SubmitOrder,
+            &format!("{}: {}", order, crate::audit::outcome(&result)),
+        );
+        result.with_context(|| format!("submitting order {order}"))?;
+        info!("Submitted order {}", order);
+    }
+
+    // Taker orders fill (or fail to) immediately, so we can look up the fills now
+    let fills = history::fetch_fills(api_key, &contract)?;
+    let limit_price = plan.limit_price().unwrap_or(plan.max_price);
+    let mut n_filled = 0;
+    for (fill_time, size, fill_price) in fills {
+        if fill_time < now || size <= 0 {
+            continue;
+        }
+        n_filled += size;
+        let fill = slippage::Fill {
+            option: plan.option,
+            size,
+            limit_price,
+            fill_price,
+            decision_time: now,
+            decision_btc: btc,
+            fill_time,
+            fill_btc: btc,
+        };
+        info!("Filled {} at {}", size, fill_price);
+        if let Err(e) = fill.append_to_csv(fill_file) {
+            warn!("Failed to record fill: {:#}", e);
+        }
+        if let Err(e) = schema::Record::fill(&fill).append_to(record_file) {
+            warn!("Failed to write record: {:#}", e);
+        }
+    }
+    if n_filled < plan.size() {
+        warn!(
+            "Only {} of {} contracts filled so far; check for resting bids.",
+            n_filled,
+            plan.size()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan() {
+        let json = serde_json::json!({
+            "id": 7,
+            "active": true,
+            "collateral_asset": "USD",
+            "date_exercise": "2030-06-28 22:00:00+0000",
+            "date_expires": "2030-06-28 21:00:00+0000",
+            "date_live": "2030-01-01 05:00:00+0000",
+            "derivative_type": "options_contract",
+            "is_call": false,
+            "is_ecp_only": false,
+            "is_next_day": false,
+            "label": "BTC-Mini-28JUN2030-50000-Put",
+            "min_increment": 100,
+            "multiplier": 100,
+            "name": null,
+            "open_interest": null,
+            "strike_price": 5000000,
+            "type": "put",
+            "underlying_asset": "BTC",
+        });
+        let contract: Contract = serde_json::from_str(&json.to_string()).unwrap();
+        let now = UtcTime::parse_coinbase("2030-06-01T15:00:00Z").unwrap();
+        let btc = Price::from_str("60000").unwrap();
+
+        let mut book = BookState::new(contract.asset());
+        for (n, (price, size)) in [("1000", 3), ("1000", 2), ("1100", 4), ("5000", 10)]
+            .iter()
+            .enumerate()
+        {
+            book.insert_order(datafeed::Order::from((
+                crate::ledgerx::json::BookState {
+                    clock: n as u64,
+                    contract_id: contract.id(),
+                    mid: [n as u8; 16],
+                    is_ask: true,
+                    price: Price::from_str(price).unwrap(),
+                    size: *size,
+                },
+                now,
+            )));
+        }
+        let none = HashSet::new();
+        // Longs cannot be closed
+        assert!(Plan::new(&contract, &book, &none, 5, now, btc, None).is_err());
+
+        let max = Price::from_str("1100").unwrap();
+        let plan = Plan::new(&contract, &book, &none, -7, now, btc, Some(max)).unwrap();
+        assert_eq!(plan.position, 7);
+        assert_eq!(
+            plan.levels,
+            vec![
+                Level {
+                    price: Price::from_str("1000").unwrap(),
+                    size: 5
+                },
+                Level {
+                    price: max,
+                    size: 2
+                },
+            ]
+        );
+        assert_eq!(plan.cost(), Price::from_str("72").unwrap());
+        assert_eq!(plan.limit_price(), Some(max));
+        assert_eq!(plan.orders().len(), 2);
+
+        // Not enough offered below the maximum
+        let plan = Plan::new(&contract, &book, &none, -20, now, btc, Some(max)).unwrap();
+        assert_eq!(plan.size(), 9);
+
+        // Our own asks are not taken
+        let own = std::iter::once(MessageId::from([0; 16])).collect();
+        let plan = Plan::new(&contract, &book, &own, -7, now, btc, Some(max)).unwrap();
+        assert_eq!(
+            plan.levels,
+            vec![
+                Level {
+                    price: Price::from_str("1000").unwrap(),
+                    size: 2
+                },
+                Level {
+                    price: max,
+                    size: 4
+                },
+            ]
+        );
+
+        // Requests from the close-request file
+        assert_eq!(
+            Request::from_str("7 $1100"),
+            Ok(Request {
+                contract_id: 7.into(),
+                max_price: Some(max),
+            })
+        );
+        assert_eq!(Request::from_str(" 7 ").unwrap().max_price, None);
+        assert!(Request::from_str("seven").is_err());
+        assert!(Request::from_str("7 1100 extra").is_err());
+    }
+}
@@ -0,0 +1,292 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Collateral
+//!
+//! LX does not do margin in the usual sense; every short position must be
+//! fully collateralized. Short calls lock up the underlying BTC and short puts
+//! lock up their strike in USD. However, LX does recognize spreads: a short
+//! option which is paired with a long option of the same type and expiry only
+//! needs to lock up the maximum loss of the pair, which is the difference in
+//! strikes (or nothing, if the long leg is the more valuable one).
+//!
+//! This module models these rules so that we can tell how much a new order
+//! will actually lock up, given our existing positions, and so that we can
+//! check our understanding against the locked balances LX reports.
+//!
+
+use crate::ledgerx::{contract, Contract};
+use crate::option::{self, PutCall};
+use crate::units::{Price, Quantity, Underlying, UtcTime};
+use std::collections::{BTreeMap, HashMap};
+use std::{cmp, fmt};
+
+/// Largest order size, in contracts, that [`Portfolio::max_short`] will consider
+const MAX_CONTRACTS: i64 = 1_000_000_000;
+
+/// A list of (strike, number of contracts) pairs
+type Legs = Vec<(Price, i64)>;
+
+/// An amount of collateral which must be locked up
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Requirement {
+    /// USD locked up by short puts and by spreads
+    pub usd: Price,
+    /// BTC locked up by uncovered short calls
+    pub btc: bitcoin::Amount,
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, {}", self.usd, self.btc)
+    }
+}
+
+/// A set of option positions
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Portfolio {
+    /// Net number of contracts held in each option (negative for shorts)
+    positions: HashMap<option::Option, i64>,
+}
+
+impl Portfolio {
+    /// Creates a new empty portfolio
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a number of contracts (negative for shorts) of a given option
+    pub fn add(&mut self, opt: option::Option, size: i64) {
+        let entry = self.positions.entry(opt).or_insert(0);
+        *entry += size;
+        if *entry == 0 {
+            self.positions.remove(&opt);
+        }
+    }
+
+    /// Adds a position in a contract, if it is a BTC option
+    ///
+    /// Full-size contracts are converted to their equivalent number of minis.
+    pub fn add_contract(&mut self, contract: &Contract, size: i64) {
+        if contract.underlying() != Underlying::Btc {
+            return;
+        }
+        if let contract::Type::Option { opt, .. } = contract.ty() {
+            self.add(opt, size * contract.contract_size().in_minis());
+        }
+    }
+
+    /// Iterates over all positions, as (option, net number of contracts) pairs
+    pub fn positions(&self) -> impl Iterator<Item = (option::Option, i64)> + '_ {
+        self.positions.iter().map(|(opt, size)| (*opt, *size))
+    }
+
+    /// The amount of USD we would have to pay, for each expiry, if all our
+    /// short puts were assigned
+    pub fn short_put_obligations(&self) -> BTreeMap<UtcTime, Price> {
+        let mut ret = BTreeMap::new();
+        for (opt, size) in &self.positions {
+            if opt.pc == PutCall::Put && *size < 0 {
+                *ret.entry(opt.expiry).or_insert(Price::ZERO) +=
+                    opt.strike * Quantity::Contracts(-size);
+            }
+        }
+        ret
+    }
+
+    /// The total collateral required to hold the portfolio
+    ///
+    /// Positions are grouped by expiry and type. Within each group, short puts
+    /// are paired greedily with the highest-strike long puts, and short calls
+    /// with the lowest-strike long calls. Whatever is left unpaired is fully
+    /// collateralized.
+    pub fn requirement(&self) -> Requirement {
+        let mut groups: BTreeMap<(UtcTime, char), (Legs, Legs)> = BTreeMap::new();
+        for (opt, size) in &self.positions {
+            let (shorts, longs) = groups.entry((opt.expiry, opt.pc.to_char())).or_default();
+            if *size < 0 {
+                shorts.push((opt.strike, -size));
+            } else {
+                longs.push((opt.strike, *size));
+            }
+        }
+
+        let mut ret = Requirement::default();
+        for ((_, pc), (mut shorts, mut longs)) in groups {
+            let is_put = pc == PutCall::Put.to_char();
+            // For puts the most valuable longs are those with the highest strike,
+            // and the shorts with the highest strike are the most expensive to
+            // cover. For calls it is the other way around.
+            if is_put {
+                shorts.sort_by_key(|leg| cmp::Reverse(leg.0));
+                longs.sort_by_key(|leg| cmp::Reverse(leg.0));
+            } else {
+                shorts.sort_by_key(|leg| leg.0);
+                longs.sort_by_key(|leg| leg.0);
+            }
+
+            let mut longs = longs.into_iter().peekable();
+            for (short_strike, mut n) in shorts {
+                while n > 0 {
+                    let (long_strike, m) = match longs.peek_mut() {
+                        Some(long) => long,
+                        None => break,
+                    };
+                    let paired = cmp::min(n, *m);
+                    let width = if is_put {
+                        short_strike - *long_strike
+                    } else {
+                        *long_strike - short_strike
+                    };
+                    ret.usd += cmp::max(width, Price::ZERO) * Quantity::Contracts(paired);
+                    n -= paired;
+                    *m -= paired;
+                    if *m == 0 {
+                        longs.next();
+                    }
+                }
+                if n > 0 {
+                    if is_put {
+                        ret.usd += short_strike * Quantity::Contracts(n);
+                    } else {
+                        ret.btc += Quantity::btc_from_contracts(n).abs_btc_equivalent();
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// The additional collateral required to add a position to the portfolio
+    pub fn marginal_requirement(&self, opt: option::Option, size: i64) -> Requirement {
+        let before = self.requirement();
+        let mut after = self.clone();
+        after.add(opt, size);
+        let after = after.requirement();
+        Requirement {
+            usd: cmp::max(after.usd - before.usd, Price::ZERO),
+            btc: after
+                .btc
+                .checked_sub(before.btc)
+                .unwrap_or(bitcoin::Amount::ZERO),
+        }
+    }
+
+    /// The largest number of contracts of an option that we can sell at a
+    /// given price, given the available balances
+    ///
+    /// The premium of the sale is credited against the USD requirement, and
+    /// LX's fee, given per 100 contracts, is debited from it.
+    pub fn max_short(
+        &self,
+        opt: option::Option,
+        sale_price: Price,
+        fee: Price,
+        available_usd: Price,
+        available_btc: bitcoin::Amount,
+    ) -> Quantity {
+        if opt.pc == PutCall::Put && sale_price > opt.strike {
+            // See `option::Option::max_sale` for why we ignore these.
+            return Quantity::Zero;
+        }
+        let fits = |n: i64| {
+            let req = self.marginal_requirement(opt, -n);
+            let net_sale = (sale_price - fee) * Quantity::Contracts(n);
+            req.usd - net_sale <= available_usd && req.btc <= available_btc
+        };
+
+        // Exponential search for an upper bound, then bisect.
+        let mut lo = 0;
+        let mut hi = 1;
+        while hi < MAX_CONTRACTS && fits(hi) {
+            lo = hi;
+            hi *= 2;
+        }
+        if hi >= MAX_CONTRACTS && fits(MAX_CONTRACTS) {
+            return Quantity::Contracts(MAX_CONTRACTS);
+        }
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if fits(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Quantity::Contracts(lo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn opt(s: &str) -> option::Option {
+        option::Option::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn spreads() {
+        let mut port = Portfolio::new();
+        // 3 naked short puts lock up their strike
+        port.add(opt("2024-03-29P40000"), -3);
+        assert_eq!(port.requirement().usd, Price::from_str("1200").unwrap());
+        assert_eq!(port.requirement().btc, bitcoin::Amount::ZERO);
+
+        // Buying 2 lower-strike puts turns 2 of them into spreads
+        port.add(opt("2024-03-29P35000"), 2);
+        assert_eq!(port.requirement().usd, Price::from_str("500").unwrap());
+        // ...but puts of a different expiry don't help
+        port.add(opt("2024-04-26P45000"), 1);
+        assert_eq!(port.requirement().usd, Price::from_str("500").unwrap());
+
+        // Short calls lock up BTC unless covered by a lower-strike long call
+        port.add(opt("2024-03-29C50000"), -2);
+        assert_eq!(port.requirement().btc, bitcoin::Amount::from_sat(2_000_000));
+        port.add(opt("2024-03-29C45000"), 1);
+        assert_eq!(port.requirement().btc, bitcoin::Amount::from_sat(1_000_000));
+        assert_eq!(port.requirement().usd, Price::from_str("500").unwrap());
+
+        // Marginal requirement of selling more of the covered call
+        let marginal = port.marginal_requirement(opt("2024-03-29C45000"), -1);
+        assert_eq!(marginal.btc, bitcoin::Amount::from_sat(1_000_000));
+    }
+
+    #[test]
+    fn max_short() {
+        let mut port = Portfolio::new();
+        let put = opt("2024-03-29P40000");
+        // $400 lockup minus $24.75 net premium per contract
+        let max = port.max_short(
+            put,
+            Price::from_str("2500").unwrap(),
+            Price::TWENTY_FIVE,
+            crate::price!(3760),
+            bitcoin::Amount::ZERO,
+        );
+        assert_eq!(max, Quantity::Contracts(10));
+
+        // Holding long 38k puts, each spread locks up only $20, less than the premium
+        port.add(opt("2024-03-29P38000"), 5);
+        let max = port.max_short(
+            put,
+            Price::from_str("2500").unwrap(),
+            Price::TWENTY_FIVE,
+            Price::ZERO,
+            bitcoin::Amount::ZERO,
+        );
+        assert_eq!(max, Quantity::Contracts(5));
+    }
+}
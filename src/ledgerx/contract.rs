@@ -17,7 +17,9 @@
 //! Data Structures etc for the LedgerX API
 //!
 
-use crate::units::{Asset, BudgetAsset, TaxAsset, Underlying, UtcTime};
+use crate::units::{
+    Asset, BudgetAsset, ContractSize, Price, Quantity, TaxAsset, Underlying, UtcTime, GWEI_PER_ETH,
+};
 use crate::{ledgerx::json, option};
 use serde::{Deserialize, Serialize};
 use std::{convert::TryFrom, fmt};
@@ -44,15 +46,54 @@ pub enum Type {
     },
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+/// LX's identifier for a contract
+///
+/// Most endpoints give this as a number, but some (e.g. the trade history)
+/// give it as a string. Deserialization accepts either; it is always
+/// serialized as a number.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize)]
 pub struct ContractId(usize);
 
+impl<'de> Deserialize<'de> for ContractId {
+    fn deserialize<D: serde::Deserializer<'de>>(deser: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ContractId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a contract ID, as a number or string")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                usize::try_from(v).map(ContractId).map_err(E::custom)
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                usize::try_from(v).map(ContractId).map_err(E::custom)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse()
+                    .map(ContractId)
+                    .map_err(|e| E::custom(format_args!("parsing contract ID {v}: {e}")))
+            }
+        }
+        deser.deserialize_any(Visitor)
+    }
+}
+
 impl From<usize> for ContractId {
     fn from(u: usize) -> Self {
         ContractId(u)
     }
 }
 
+impl From<ContractId> for usize {
+    fn from(cid: ContractId) -> Self {
+        cid.0
+    }
+}
+
 impl fmt::Display for ContractId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -75,6 +116,10 @@ pub struct Contract {
     label: String,
     /// Multiplier (100 for BTC options, 10 for ETH options)
     multiplier: usize,
+    /// Minimum price increment, in cents
+    min_increment: usize,
+    /// Open interest at the time the contract data was fetched
+    open_interest: Option<usize>,
 }
 
 impl fmt::Display for Contract {
@@ -115,6 +160,7 @@ impl Contract {
             Type::Option { opt, .. } => Asset::Option {
                 underlying: self.underlying,
                 option: opt,
+                contract_size: self.contract_size(),
             },
             Type::NextDay { .. } => match self.underlying {
                 Underlying::Btc => Asset::Btc,
@@ -133,6 +179,7 @@ impl Contract {
             Type::Option { opt, .. } => Some(TaxAsset::Option {
                 underlying: self.underlying,
                 option: opt,
+                contract_size: self.contract_size(),
             }),
             Type::NextDay { expiry } => Some(TaxAsset::NextDay {
                 underlying: self.underlying,
@@ -148,10 +195,11 @@ impl Contract {
             Type::Option { opt, .. } => Some(BudgetAsset::Option {
                 underlying: self.underlying,
                 option: opt,
+                contract_size: self.contract_size(),
             }),
             Type::NextDay { .. } => match self.underlying {
                 Underlying::Btc => Some(BudgetAsset::Btc),
-                Underlying::Eth => None,
+                Underlying::Eth => Some(BudgetAsset::Eth),
             },
             _ => None,
         }
@@ -169,6 +217,53 @@ impl Contract {
     pub fn multiplier(&self) -> usize {
         self.multiplier
     }
+    /// Minimum price increment; every order price must be a multiple of this
+    pub fn tick_size(&self) -> Price {
+        // A zero increment would be meaningless; fall back to the whole-dollar
+        // ticks LX has always used for BTC
+        match self.min_increment {
+            0 => Price::ONE,
+            n => Price::from_cents(n as i64),
+        }
+    }
+    /// Converts a quantity to a number of contracts
+    ///
+    /// Quantities of the underlying are converted using the multiplier, and must
+    /// be a whole number of contracts.
+    pub fn contracts(&self, qty: Quantity) -> Result<i64, String> {
+        let (units, units_per_underlying) = match (qty, self.underlying) {
+            (Quantity::Contracts(n), _) => return Ok(n),
+            (Quantity::EthContracts(n), Underlying::Eth) => return Ok(n),
+            (Quantity::Zero, _) => return Ok(0),
+            (Quantity::Bitcoin(btc), Underlying::Btc) => (btc.to_sat(), 100_000_000),
+            (Quantity::Ether(gwei), Underlying::Eth) => (gwei, i128::from(GWEI_PER_ETH)),
+            _ => return Err(format!("cannot trade {qty} of {self}")),
+        };
+        let num = i128::from(units) * self.multiplier as i128;
+        if num % units_per_underlying != 0 {
+            return Err(format!(
+                "{qty} is not a whole number of {self} contracts (each is 1/{} {})",
+                self.multiplier, self.underlying,
+            ));
+        }
+        i64::try_from(num / units_per_underlying).map_err(|_| format!("{qty} is too large"))
+    }
+    /// Whether this is a mini or full-size contract
+    pub fn contract_size(&self) -> ContractSize {
+        ContractSize::from_multiplier(self.multiplier)
+    }
+    /// Open interest, as of when the contract data was fetched
+    pub fn open_interest(&self) -> Option<usize> {
+        self.open_interest
+    }
+
+    /// Exercise date, for options
+    pub fn exercise_date(&self) -> Option<UtcTime> {
+        match self.ty {
+            Type::Option { exercise_date, .. } => Some(exercise_date),
+            _ => None,
+        }
+    }
 
     /// Expiry date
     pub fn expiry(&self) -> UtcTime {
@@ -203,12 +298,14 @@ impl TryFrom<json::Contract> for Contract {
             json::DerivativeType::DayAheadSwap => Type::NextDay { expiry },
         };
         Ok(Contract {
-            id: ContractId(js.id),
+            id: js.id,
             active: js.active,
             ty,
             underlying: js.underlying_asset,
             multiplier: js.multiplier,
+            min_increment: js.min_increment,
             label: js.label,
+            open_interest: js.open_interest,
         })
     }
 }
@@ -228,6 +325,17 @@ mod tests {
     use super::*;
     use chrono::DateTime;
 
+    #[test]
+    fn parse_contract_id() {
+        let id: ContractId = serde_json::from_str("22256321").unwrap();
+        assert_eq!(id, ContractId(22256321));
+        let id: ContractId = serde_json::from_str("\"22256321\"").unwrap();
+        assert_eq!(id, ContractId(22256321));
+        assert_eq!(serde_json::to_string(&id).unwrap(), "22256321");
+        assert!(serde_json::from_str::<ContractId>("\"BTC\"").is_err());
+        assert!(serde_json::from_str::<ContractId>("-1").is_err());
+    }
+
     #[test]
     fn parse_contract_put() {
         let contract_s = "{ \"id\": 22256321, \"name\": null, \"is_call\": false, \"strike_price\": 400000, \"min_increment\": 10, \"date_live\": \"2023-01-12 05:00:00+0000\", \"date_expires\": \"2023-12-29 21:00:00+0000\", \"date_exercise\": \"2023-12-29 22:00:00+0000\", \"derivative_type\": \"options_contract\", \"open_interest\": null, \"multiplier\": 10, \"label\": \"ETH-29DEC2023-4000-Put\", \"active\": true, \"is_next_day\": false, \"is_ecp_only\": false, \"underlying_asset\": \"ETH\", \"collateral_asset\": \"USD\", \"type\": \"put\" }";
@@ -251,7 +359,9 @@ mod tests {
                 },
                 underlying: Underlying::Eth,
                 multiplier: 10,
+                min_increment: 10,
                 label: "ETH-29DEC2023-4000-Put".into(),
+                open_interest: None,
             },
         );
     }
@@ -279,7 +389,9 @@ mod tests {
                 },
                 underlying: Underlying::Btc,
                 multiplier: 100,
+                min_increment: 100,
                 label: "BTC-Mini-29DEC2023-25000-Call".into(),
+                open_interest: Some(674),
             },
         );
     }
@@ -301,7 +413,9 @@ mod tests {
                 },
                 underlying: Underlying::Btc,
                 multiplier: 100,
+                min_increment: 100,
                 label: "BTC-Mini-14FEB2023-NextDay".into(),
+                open_interest: None,
             },
         );
     }
@@ -323,7 +437,9 @@ mod tests {
                 },
                 underlying: Underlying::Btc,
                 multiplier: 100,
+                min_increment: 100,
                 label: "BTC-Mini-31MAR2023-Future".into(),
+                open_interest: None,
             },
         );
     }
@@ -0,0 +1,341 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Contract Cache
+//!
+//! Contract metadata basically never changes once a contract exists, but we
+//! re-fetch it on every startup. This module persists the raw JSON of every
+//! contract we've seen in the data directory, so that we only need to hit
+//! the API for contracts we don't know about.
+//!
+//! Inactive (expired) contracts are cached forever. Active contracts may still
+//! change (e.g. their open interest, or becoming inactive) so they are
+//! re-fetched once they are more than a day old. The complete list of active
+//! contracts is re-fetched if it is more than an hour old.
+//!
+
+use crate::http;
+use crate::units::UtcTime;
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{fs, io};
+
+use super::{Contract, ContractId};
+
+/// Age after which data about an active contract is considered stale
+const MAX_ACTIVE_AGE_HOURS: i64 = 24;
+/// Age after which the list of all active contracts is considered stale
+const MAX_LISTING_AGE_MINUTES: i64 = 60;
+
+/// A single cached contract
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "crate::units::serde_ts_seconds")]
+    fetched: UtcTime,
+    contract: serde_json::Value,
+}
+
+impl Entry {
+    /// Whether the contract data can still be used, as of the given time
+    fn is_fresh(&self, now: UtcTime) -> bool {
+        self.contract.get("active") == Some(&serde_json::Value::Bool(false))
+            || now - self.fetched < chrono::Duration::hours(MAX_ACTIVE_AGE_HOURS)
+    }
+}
+
+/// On-disk format of the cache
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(
+        default,
+        serialize_with = "crate::units::serde_ts_seconds::serialize_opt",
+        deserialize_with = "crate::units::serde_ts_seconds::deserialize_opt"
+    )]
+    last_listing: Option<UtcTime>,
+    contracts: Vec<(ContractId, Entry)>,
+}
+
+/// Persistent cache of contract metadata
+#[derive(Clone, PartialEq, Debug)]
+pub struct ContractCache {
+    path: PathBuf,
+    contracts: HashMap<ContractId, Entry>,
+    /// When we last fetched the complete list of active contracts
+    last_listing: Option<UtcTime>,
+    /// Whether there are changes which have not been saved
+    dirty: bool,
+}
+
+impl ContractCache {
+    /// Loads the cache from a file
+    ///
+    /// If the file does not exist, or cannot be parsed, starts with an empty
+    /// cache which will be written to the file on [`ContractCache::save`].
+    pub fn load(path: PathBuf) -> Self {
+        let file: CacheFile = match fs::File::open(&path) {
+            Ok(fh) => match serde_json::from_reader(io::BufReader::new(fh)) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Ignoring corrupt contract cache {}: {}", path.display(), e);
+                    CacheFile::default()
+                }
+            },
+            Err(_) => {
+                info!("No contract cache at {}; starting fresh.", path.display());
+                CacheFile::default()
+            }
+        };
+        info!(
+            "Loaded {} contracts from cache {}",
+            file.contracts.len(),
+            path.display(),
+        );
+        ContractCache {
+            path,
+            contracts: file.contracts.into_iter().collect(),
+            last_listing: file.last_listing,
+            dirty: false,
+        }
+    }
+
+    /// Writes the cache back to its file, if it has changed
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let path = &self.path;
+        let file = CacheFile {
+            last_listing: self.last_listing,
+            contracts: self
+                .contracts
+                .iter()
+                .map(|(id, entry)| (*id, entry.clone()))
+                .collect(),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating data directory")?;
+        }
+        let fh = fs::File::create(path)
+            .with_context(|| format!("creating contract cache {}", path.display()))?;
+        serde_json::to_writer(io::BufWriter::new(fh), &file)
+            .with_context(|| format!("writing contract cache {}", path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Whether the cache has ever seen a contract, fresh or not
+    pub fn contains(&self, id: ContractId) -> bool {
+        self.contracts.contains_key(&id)
+    }
+
+    /// Whether the cache is empty, e.g. because this is the first run
+    pub fn is_empty(&self) -> bool {
+        self.contracts.is_empty()
+    }
+
+    /// Iterator over the IDs of every contract the cache has seen
+    pub fn ids(&self) -> impl Iterator<Item = ContractId> + '_ {
+        self.contracts.keys().copied()
+    }
+
+    /// Looks up a contract by its LX label, without checking freshness or
+    /// hitting the API
+    ///
+    /// Labels are compared case-insensitively, and the "Mini" part of the
+    /// label of a mini contract may be omitted.
+    pub fn find_label(&self, label: &str) -> Option<Contract> {
+        let label = label.to_ascii_lowercase();
+        self.contracts.values().find_map(|entry| {
+            let cached = entry.contract.get("label")?.as_str()?.to_ascii_lowercase();
+            if cached == label || cached.replacen("-mini-", "-", 1) == label {
+                // Contract dates deserialize from borrowed strings, which a
+                // `serde_json::Value` cannot lend, so go via a string
+                serde_json::from_str(&entry.contract.to_string()).ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns every cached contract which had not expired at the given time,
+    /// ordered by ID, without checking freshness or hitting the API
+    ///
+    /// Used when replaying old logs, since the API would only tell us about
+    /// the contracts which are active today.
+    pub fn unexpired_at(&self, time: UtcTime) -> Vec<Contract> {
+        let mut ret: Vec<Contract> = self
+            .contracts
+            .values()
+            .filter_map(|entry| serde_json::from_value(entry.contract.clone()).ok())
+            .filter(|contract: &Contract| contract.expiry() > time)
+            .collect();
+        ret.sort_by_key(Contract::id);
+        ret
+    }
+
+    /// Looks up a contract in the cache, if it is present and fresh
+    fn get(&self, id: ContractId, now: UtcTime) -> Option<Contract> {
+        let entry = self
+            .contracts
+            .get(&id)
+            .filter(|entry| entry.is_fresh(now))?;
+        match serde_json::from_value(entry.contract.clone()) {
+            Ok(contract) => Some(contract),
+            Err(e) => {
+                warn!("Ignoring unparseable cached contract {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// Parses a contract from JSON and records it in the cache
+    fn insert(&mut self, json: serde_json::Value, now: UtcTime) -> anyhow::Result<Contract> {
+        let contract: Contract = serde_json::from_value(json.clone())
+            .with_context(|| format!("parsing contract json {json}"))?;
+        self.contracts.insert(
+            contract.id(),
+            Entry {
+                fetched: now,
+                contract: json,
+            },
+        );
+        self.dirty = true;
+        Ok(contract)
+    }
+
+    /// Looks up a contract, fetching it from the LX API if it is not cached
+    pub fn fetch(&mut self, id: ContractId) -> anyhow::Result<Contract> {
+        let now = UtcTime::now();
+        if let Some(contract) = self.get(id, now) {
+            return Ok(contract);
+        }
+        let json = http::get_json_from_data_field(
+            &format!("https://api.ledgerx.com/trading/contracts/{id}"),
+            None,
+        )
+        .with_context(|| format!("looking up contract {id}"))?;
+        self.insert(json, now)
+    }
+
+    /// Returns the list of all currently-active contracts
+    ///
+    /// Uses the cached list if it is recent enough, otherwise fetches the
+    /// whole list from the LX API.
+    pub fn fetch_all_active(&mut self) -> anyhow::Result<Vec<Contract>> {
+        let now = UtcTime::now();
+        if let Some(last) = self.last_listing {
+            if now - last < chrono::Duration::minutes(MAX_LISTING_AGE_MINUTES) {
+                let cached: Option<Vec<Contract>> = self
+                    .contracts
+                    .iter()
+                    .filter(|(_, entry)| {
+                        entry.contract.get("active") == Some(&serde_json::Value::Bool(true))
+                    })
+                    .map(|(id, _)| self.get(*id, now))
+                    .collect();
+                if let Some(cached) = cached {
+                    info!("Using {} cached active contracts.", cached.len());
+                    return Ok(cached);
+                }
+            }
+        }
+
+        let all_json: Vec<serde_json::Value> =
+            http::get_json_from_data_field("https://api.ledgerx.com/trading/contracts", None)
+                .context("looking up list of contracts")?;
+        // Anything we previously thought active, but which is not in the list,
+        // needs to be re-fetched before we trust it again.
+        self.contracts.retain(|_, entry| {
+            entry.contract.get("active") != Some(&serde_json::Value::Bool(true))
+        });
+        let mut ret = Vec::with_capacity(all_json.len());
+        for json in all_json {
+            ret.push(self.insert(json, now)?);
+        }
+        self.last_listing = Some(now);
+        self.dirty = true;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freshness() {
+        let now = UtcTime::now();
+        let old = now - chrono::Duration::days(30);
+        let active = Entry {
+            fetched: old,
+            contract: serde_json::json!({ "id": 1, "active": true }),
+        };
+        let inactive = Entry {
+            fetched: old,
+            contract: serde_json::json!({ "id": 2, "active": false }),
+        };
+        assert!(!active.is_fresh(now));
+        assert!(inactive.is_fresh(now));
+        assert!(active.is_fresh(old + chrono::Duration::hours(1)));
+    }
+
+    #[test]
+    fn find_label() {
+        let mut cache = ContractCache {
+            path: PathBuf::new(),
+            contracts: HashMap::new(),
+            last_listing: None,
+            dirty: false,
+        };
+        let json = serde_json::json!({
+            "id": 7,
+            "active": false,
+            "collateral_asset": "USD",
+            "date_exercise": "2030-06-28 22:00:00+0000",
+            "date_expires": "2030-06-28 21:00:00+0000",
+            "date_live": "2030-01-01 05:00:00+0000",
+            "derivative_type": "options_contract",
+            "is_call": false,
+            "is_ecp_only": false,
+            "is_next_day": false,
+            "label": "BTC-Mini-28JUN2030-50000-Put",
+            "min_increment": 100,
+            "multiplier": 100,
+            "name": null,
+            "open_interest": null,
+            "strike_price": 5000000,
+            "type": "put",
+            "underlying_asset": "BTC",
+        });
+        // Stale entries are still found, since contract labels never change
+        cache.contracts.insert(
+            ContractId::from(7),
+            Entry {
+                fetched: UtcTime::now() - chrono::Duration::days(30),
+                contract: json,
+            },
+        );
+
+        let id = |label| cache.find_label(label).map(|contract| contract.id());
+        assert_eq!(
+            id("BTC-Mini-28JUN2030-50000-Put"),
+            Some(ContractId::from(7))
+        );
+        assert_eq!(id("btc-28jun2030-50000-put"), Some(ContractId::from(7)));
+        assert_eq!(id("BTC-28JUN2030-50000-Call"), None);
+    }
+}
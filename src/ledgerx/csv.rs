@@ -80,8 +80,7 @@ fn price_ref_2021(fields: &[&str]) -> Result<Option<(UtcTime, Price)>, String> {
         .map_err(|e| format!("parsing price {}: {e}", desc_fields[5]))?;
 
     // Parse the date/basis of the exercise
-    let ex_date = chrono::DateTime::parse_from_rfc3339(fields[2])
-        .map(UtcTime::from)
+    let ex_date = crate::units::parse_lx_time(fields[2])
         .map_err(|e| format!("parsing exercise date {}: {e}", fields[2]))?;
     let ex_basis =
         Price::from_str(fields[5]).map_err(|e| format!("parsing exercise {}: {e}", fields[5]))?;
@@ -120,8 +119,7 @@ fn price_ref_2022(fields: &[&str]) -> Result<Option<(UtcTime, Price)>, String> {
     let qty_64 = qty.to_f64().unwrap();
 
     // Parse the date/basis of the exercise
-    let ex_date = chrono::DateTime::parse_from_rfc3339(fields[4])
-        .map(UtcTime::from)
+    let ex_date = crate::units::parse_lx_time(fields[4])
         .map_err(|e| format!("parsing exercise date {}: {e}", fields[4]))?;
     let ex_basis =
         Price::from_str(fields[7]).map_err(|e| format!("parsing exercise {}: {e}", fields[7]))?;
@@ -26,6 +26,18 @@ use std::fmt;
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct CustomerId(usize);
 
+impl From<usize> for CustomerId {
+    fn from(cid: usize) -> Self {
+        CustomerId(cid)
+    }
+}
+
+impl From<CustomerId> for usize {
+    fn from(cid: CustomerId) -> Self {
+        cid.0
+    }
+}
+
 impl fmt::Display for CustomerId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&self.0, f)
@@ -36,6 +48,18 @@ impl fmt::Display for CustomerId {
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct MessageId([u8; 16]);
 
+impl From<[u8; 16]> for MessageId {
+    fn from(mid: [u8; 16]) -> Self {
+        MessageId(mid)
+    }
+}
+
+impl From<MessageId> for [u8; 16] {
+    fn from(mid: MessageId) -> Self {
+        mid.0
+    }
+}
+
 impl fmt::Display for MessageId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use bitcoin::hex::DisplayHex as _;
@@ -63,6 +87,11 @@ pub struct Order {
     pub timestamp: UtcTime,
     /// Timestamp that the order was last updated on
     pub updated_timestamp: UtcTime,
+    /// Open interest in the contract, if provided (not provided for book states)
+    pub open_interest: Option<usize>,
+    /// The contract's clock as of this message, which increases with every
+    /// change to the contract's book
+    pub clock: u64,
 }
 
 impl fmt::Display for Order {
@@ -103,10 +132,40 @@ impl From<(json::BookState, UtcTime)> for Order {
             message_id: MessageId(data.0.mid),
             updated_timestamp: data.1,
             timestamp: data.1,
+            open_interest: None, // not provided for book states
+            clock: data.0.clock,
         }
     }
 }
 
+/// A trade which was busted (reversed) by the exchange
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Bust {
+    /// ID of the contract the trade was on
+    pub contract_id: ContractId,
+    /// ID of the customer, if provided (only provided for own trades)
+    pub customer_id: Option<CustomerId>,
+    /// ID of the order which was filled
+    pub message_id: MessageId,
+    /// Number of contracts whose fill was reversed (negative for asks,
+    /// positive for bids)
+    pub size: UnknownQuantity,
+    /// Price at which the reversed fill happened
+    pub price: Price,
+    /// Timestamp of the bust
+    pub timestamp: UtcTime,
+}
+
+impl fmt::Display for Bust {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bust of order {} (contract {}): {} @ {}",
+            self.message_id, self.contract_id, self.size, self.price,
+        )
+    }
+}
+
 /// Object from the data stream
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize)]
 #[serde(from = "json::DataFeedObject")]
@@ -126,10 +185,13 @@ pub enum Object {
     },
     ContractAdded(Contract),
     ContractRemoved(ContractId),
+    TradeBusted(Bust),
     ChatMessage {
         message: String,
         initiator: String,
         counterparty: String,
+        /// Chat username of whoever sent the message, if given
+        sender: Option<String>,
         chat_id: usize,
     },
     Other,
@@ -140,6 +202,7 @@ impl From<json::DataFeedObject> for Object {
         match js {
             json::DataFeedObject::ActionReport {
                 contract_id,
+                open_interest,
                 price,
                 size,
                 filled_size,
@@ -147,6 +210,7 @@ impl From<json::DataFeedObject> for Object {
                 is_ask,
                 cid,
                 mid,
+                clock,
                 timestamp,
                 updated_time,
                 ..
@@ -162,6 +226,8 @@ impl From<json::DataFeedObject> for Object {
                     price,
                     timestamp,
                     updated_timestamp: updated_time,
+                    open_interest: Some(open_interest),
+                    clock,
                 })
             }
             json::DataFeedObject::BookTop {
@@ -184,6 +250,25 @@ impl From<json::DataFeedObject> for Object {
                     usd: collateral.available_balances.usd,
                 }
             }
+            json::DataFeedObject::TradeBusted {
+                contract_id,
+                mid,
+                filled_price,
+                filled_size,
+                is_ask,
+                cid,
+                timestamp,
+            } => {
+                let ba_mult = if is_ask { -1 } else { 1 };
+                Object::TradeBusted(Bust {
+                    contract_id,
+                    customer_id: cid.map(CustomerId),
+                    message_id: MessageId(mid),
+                    size: UnknownQuantity::from(ba_mult * filled_size),
+                    price: filled_price,
+                    timestamp,
+                })
+            }
             json::DataFeedObject::ContractAdded { data } => Object::ContractAdded(data),
             json::DataFeedObject::ContractRemoved { data } => Object::ContractRemoved(data.id()),
             json::DataFeedObject::ConversationNewMessage {
@@ -193,6 +278,7 @@ impl From<json::DataFeedObject> for Object {
                 message: data.message.message,
                 initiator: data.message.initiator.chat_username,
                 counterparty: data.message.counterparty.chat_username,
+                sender: data.message.sender.map(|sender| sender.chat_username),
                 chat_id: conversation_id,
             },
             _ => Object::Other,
@@ -224,7 +310,55 @@ mod tests {
                 ]),
                 timestamp: UtcTime::from_unix_nanos_i64(1674839748016616735).unwrap(),
                 updated_timestamp: UtcTime::from_unix_nanos_i64(1674839748016616735).unwrap(),
+                open_interest: Some(248),
+                clock: 173827,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_bust() {
+        use std::str::FromStr;
+
+        let bust_s = "{\"type\": \"trade_busted\", \"contract_id\": 22256362, \"mid\": \"014aa5ad13564272a793c0582a776000\", \"filled_price\": 126400, \"filled_size\": 3, \"is_ask\": true, \"cid\": 1234, \"timestamp\": 1674839748016616735}";
+        let obj: Object = serde_json::from_str(bust_s).unwrap();
+
+        assert_eq!(
+            obj,
+            Object::TradeBusted(Bust {
+                contract_id: ContractId::from(22256362),
+                customer_id: Some(CustomerId(1234)),
+                message_id: MessageId([
+                    0x01, 0x4a, 0xa5, 0xad, 0x13, 0x56, 0x42, 0x72, 0xa7, 0x93, 0xc0, 0x58, 0x2a,
+                    0x77, 0x60, 0x00,
+                ]),
+                size: UnknownQuantity::from(-3),
+                price: Price::from_str("1264").unwrap(),
+                timestamp: UtcTime::from_unix_nanos_i64(1674839748016616735).unwrap(),
             })
         );
     }
+
+    #[test]
+    fn parse_chat_message() {
+        let chat_s = "{\"type\": \"conversation_new_message\", \"conversation_id\": 77, \"data\": {\"message\": {\"message\": \"buy 5x 2030-06-28C60000 @ $1,234\", \"initiator\": {\"chat_username\": \"alice\", \"is_online\": true}, \"counterparty\": {\"chat_username\": \"bob\", \"is_online\": false}, \"sender\": {\"chat_username\": \"bob\"}}}}";
+        let obj: Object = serde_json::from_str(chat_s).unwrap();
+        assert_eq!(
+            obj,
+            Object::ChatMessage {
+                message: "buy 5x 2030-06-28C60000 @ $1,234".into(),
+                initiator: "alice".into(),
+                counterparty: "bob".into(),
+                sender: Some("bob".into()),
+                chat_id: 77,
+            }
+        );
+
+        // Without a sender we cannot tell whose proposal it is
+        let chat_s = chat_s.replace(", \"sender\": {\"chat_username\": \"bob\"}", "");
+        match serde_json::from_str(&chat_s).unwrap() {
+            Object::ChatMessage { sender, .. } => assert_eq!(sender, None),
+            obj => panic!("unexpected object {:?}", obj),
+        }
+    }
 }
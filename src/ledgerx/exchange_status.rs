@@ -0,0 +1,182 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Exchange Status
+//!
+//! When LX halts trading or has an incident, any orders we have resting sit
+//! there unmanaged. This module polls a status page (a standard Statuspage
+//! summary, whose URL must be given explicitly) from a monitor thread, and
+//! tells the main loop when the exchange becomes degraded or recovers, so
+//! that it can stop placing orders (and optionally cancel the ones it has)
+//! in the meantime.
+//!
+//! Monitoring fails open: if the status page cannot be reached we alert, but
+//! keep trading as if the exchange were healthy.
+//!
+
+use crate::connect::Message;
+use crate::http;
+use crate::queue::Sender;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::thread;
+
+/// How often to poll the status page, in seconds
+const POLL_INTERVAL_SECS: u64 = 60;
+/// Number of consecutive failures to reach the status page before we alert
+const MAX_POLL_FAILURES: usize = 5;
+
+/// Settings for exchange status monitoring
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Settings {
+    /// URL of the status page summary, e.g. `https://<page>/api/v2/summary.json`;
+    /// if unset, the status page is not monitored
+    pub url: Option<String>,
+    /// Whether to cancel our resting orders while the exchange is degraded,
+    /// rather than just not placing new ones
+    pub cancel_orders: bool,
+}
+
+/// Overall status, as reported by the status page
+#[derive(Deserialize, Debug)]
+struct Status {
+    /// One of "none", "minor", "major" or "critical"
+    indicator: String,
+    /// Human-readable description
+    description: String,
+}
+
+/// An incident, as reported by the status page
+#[derive(Deserialize, Debug)]
+struct Incident {
+    name: String,
+    /// One of "investigating", "identified", "monitoring", "resolved" or "postmortem"
+    status: String,
+}
+
+/// The status page summary
+#[derive(Deserialize, Debug)]
+pub struct Summary {
+    status: Status,
+    #[serde(default)]
+    incidents: Vec<Incident>,
+}
+
+impl Summary {
+    /// If the summary indicates that trading is impaired, returns why
+    ///
+    /// Minor incidents (e.g. with the website or with deposits) are ignored,
+    /// unless they are unresolved and mention a trading halt.
+    pub fn degraded_reason(&self) -> Option<String> {
+        if let Some(halt) = self.incidents.iter().find(|inc| {
+            inc.status != "resolved"
+                && inc.status != "postmortem"
+                && inc.name.to_lowercase().contains("halt")
+        }) {
+            return Some(format!("incident: {}", halt.name));
+        }
+        match self.status.indicator.as_str() {
+            "major" | "critical" => Some(format!(
+                "status {}: {}",
+                self.status.indicator, self.status.description
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Spawns a thread which polls the status page, sending
+/// [`Message::ExchangeDegraded`] and [`Message::ExchangeRecovered`] to the main
+/// loop whenever the exchange's status changes
+pub fn spawn_monitor_thread(url: String, tx: Sender<Message>) {
+    thread::spawn(move || {
+        let mut degraded = false;
+        let mut failures = 0;
+        loop {
+            let reason = match http::get_json::<Summary>(&url, None) {
+                Ok(summary) => {
+                    if failures >= MAX_POLL_FAILURES {
+                        warn!("Exchange status page reachable again.");
+                        http::post_to_prowl("Exchange status page reachable again");
+                    }
+                    failures = 0;
+                    summary.degraded_reason()
+                }
+                Err(e) => {
+                    failures += 1;
+                    warn!(
+                        "Failed to check exchange status ({} in a row): {:#}",
+                        failures, e
+                    );
+                    // Fail open: an unreachable status page says nothing about
+                    // the exchange, so alert once but keep the current state
+                    if failures == MAX_POLL_FAILURES {
+                        http::post_to_prowl(&format!(
+                            "Exchange status page unreachable ({failures} attempts); \
+                             continuing without it"
+                        ));
+                    }
+                    thread::sleep(std::time::Duration::from_secs(POLL_INTERVAL_SECS));
+                    continue;
+                }
+            };
+            debug!("Exchange status: {:?}", reason);
+            match (degraded, reason) {
+                (false, Some(reason)) => {
+                    degraded = true;
+                    tx.send(Message::ExchangeDegraded { reason }).unwrap();
+                }
+                (true, None) => {
+                    degraded = false;
+                    tx.send(Message::ExchangeRecovered).unwrap();
+                }
+                _ => {}
+            }
+            thread::sleep(std::time::Duration::from_secs(POLL_INTERVAL_SECS));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degraded_reason() {
+        let summary = |indicator: &str, incident: &str, status: &str| -> Summary {
+            let json = serde_json::json!({
+                "page": { "id": "x", "name": "LedgerX" },
+                "status": { "indicator": indicator, "description": "Some text" },
+                "incidents": [{ "name": incident, "status": status }],
+            });
+            serde_json::from_str(&json.to_string()).unwrap()
+        };
+        assert_eq!(
+            summary("none", "Slow deposits", "investigating").degraded_reason(),
+            None
+        );
+        assert_eq!(
+            summary("minor", "Trading Halted", "resolved").degraded_reason(),
+            None
+        );
+        assert_eq!(
+            summary("minor", "Trading Halted", "identified").degraded_reason(),
+            Some("incident: Trading Halted".into())
+        );
+        assert_eq!(
+            summary("major", "Slow deposits", "investigating").degraded_reason(),
+            Some("status major: Some text".into())
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Expiry Times
+//!
+//! Resolution of the various timestamps associated with option expiry. The LX
+//! API gives us `date_expires` (4PM New York) and `date_exercise` (an hour later)
+//! for every contract, which we treat as ground truth. However, LX's own tax
+//! CSVs have not always agreed with their API, and to reproduce their numbers
+//! we need to reproduce their mistakes. The conventions of each year are
+//! described in the [`super::rules`] module; this one applies them to the
+//! timestamps of a particular contract.
+//!
+
+use super::rules::Rules;
+use crate::units::UtcTime;
+use log::debug;
+
+/// The various times associated with the settlement of an option
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settlement {
+    /// The time at which LX's price reference for assignment is taken
+    ///
+    /// This is the key into the price reference map extracted from the LX CSV,
+    /// and the time at which expiry/assignment events are recorded.
+    pub price_ref: UtcTime,
+    /// The date that LX uses in its 1099 CSV for the closing of the position
+    pub tax_date: UtcTime,
+    /// Whether expiries are recorded before assignments (true) or after (false)
+    pub expiry_before_assignment: bool,
+}
+
+impl Settlement {
+    /// Resolves the settlement times for an option given its contract metadata
+    ///
+    /// `exercise` should be the contract's `date_exercise` field, if available.
+    pub fn resolve(expiry: UtcTime, exercise: Option<UtcTime>) -> Self {
+        let api_exercise = match exercise {
+            Some(exercise) => {
+                if exercise != expiry + chrono::Duration::hours(1) {
+                    debug!(
+                        "Contract has unusual exercise time {} (expiry {})",
+                        exercise, expiry
+                    );
+                }
+                exercise
+            }
+            None => expiry + chrono::Duration::hours(1),
+        };
+
+        let price_ref = match Rules::for_year(expiry.year()).forced_price_ref_hour() {
+            // LedgerX's data has the time forced even when DST makes this wrong
+            Some(hour) => expiry.forced_to_hour(hour),
+            None => api_exercise,
+        };
+
+        Settlement {
+            price_ref,
+            tax_date: tax_date(expiry),
+            expiry_before_assignment: expiry_before_assignment(expiry),
+        }
+    }
+}
+
+/// Whether LX records expiries before assignments for an option with the given expiry
+pub fn expiry_before_assignment(expiry: UtcTime) -> bool {
+    Rules::for_year(expiry.year()).expiry_before_assignment()
+}
+
+/// The date that LX uses in its 1099 CSV for an option expiry or assignment
+///
+/// This is available separately from [`Settlement::resolve`] since the tax code
+/// does not have access to contract metadata.
+pub fn tax_date(expiry: UtcTime) -> UtcTime {
+    expiry.forced_to_hour(Rules::for_year(expiry.year()).tax_date_hour())
+}
+
+/// The date that LX uses in its 1099 CSV for the settlement of a next-day swap
+pub fn nextday_tax_date(expiry: UtcTime) -> UtcTime {
+    match Rules::for_year(expiry.year()).forced_nextday_hour() {
+        Some(hour) => expiry.forced_to_hour(hour),
+        None => expiry,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn parse(s: &str) -> UtcTime {
+        DateTime::parse_from_str(s, "%F %T%z").unwrap().into()
+    }
+
+    #[test]
+    fn settlement_2021() {
+        // Winter: 4PM NY is 21:00 UTC
+        let winter = Settlement::resolve(
+            parse("2021-12-31 21:00:00+0000"),
+            Some(parse("2021-12-31 22:00:00+0000")),
+        );
+        assert_eq!(winter.price_ref, parse("2021-12-31 22:00:00+0000"));
+        assert_eq!(winter.tax_date, parse("2021-12-31 22:00:00+0000"));
+        assert!(winter.expiry_before_assignment);
+
+        // Summer: 4PM NY is 20:00 UTC, but LX still used 22:00
+        let summer = Settlement::resolve(
+            parse("2021-06-25 20:00:00+0000"),
+            Some(parse("2021-06-25 21:00:00+0000")),
+        );
+        assert_eq!(summer.price_ref, parse("2021-06-25 22:00:00+0000"));
+        assert_eq!(summer.tax_date, parse("2021-06-25 22:00:00+0000"));
+        assert!(summer.expiry_before_assignment);
+
+        assert_eq!(
+            nextday_tax_date(parse("2021-06-25 20:00:00+0000")),
+            parse("2021-06-25 21:00:00+0000"),
+        );
+    }
+
+    #[test]
+    fn settlement_2022() {
+        let winter = Settlement::resolve(
+            parse("2022-12-30 21:00:00+0000"),
+            Some(parse("2022-12-30 22:00:00+0000")),
+        );
+        assert_eq!(winter.price_ref, parse("2022-12-30 22:00:00+0000"));
+        assert_eq!(winter.tax_date, parse("2022-12-30 22:00:00+0000"));
+        assert!(!winter.expiry_before_assignment);
+
+        let summer = Settlement::resolve(
+            parse("2022-06-24 20:00:00+0000"),
+            Some(parse("2022-06-24 21:00:00+0000")),
+        );
+        assert_eq!(summer.price_ref, parse("2022-06-24 21:00:00+0000"));
+        assert_eq!(summer.tax_date, parse("2022-06-24 22:00:00+0000"));
+        assert!(!summer.expiry_before_assignment);
+
+        assert_eq!(
+            nextday_tax_date(parse("2022-06-24 20:00:00+0000")),
+            parse("2022-06-24 20:00:00+0000"),
+        );
+    }
+
+    #[test]
+    fn settlement_2023() {
+        let winter = Settlement::resolve(
+            parse("2023-12-29 21:00:00+0000"),
+            Some(parse("2023-12-29 22:00:00+0000")),
+        );
+        assert_eq!(winter.price_ref, parse("2023-12-29 22:00:00+0000"));
+        assert_eq!(winter.tax_date, parse("2023-12-29 22:00:00+0000"));
+        assert!(!winter.expiry_before_assignment);
+
+        // Missing exercise date falls back to an hour after expiry
+        let summer = Settlement::resolve(parse("2023-06-30 20:00:00+0000"), None);
+        assert_eq!(summer.price_ref, parse("2023-06-30 21:00:00+0000"));
+        assert_eq!(summer.tax_date, parse("2023-06-30 22:00:00+0000"));
+        assert!(!summer.expiry_before_assignment);
+    }
+
+    #[test]
+    fn settlement_2024() {
+        let winter = Settlement::resolve(
+            parse("2024-01-26 21:00:00+0000"),
+            Some(parse("2024-01-26 22:00:00+0000")),
+        );
+        assert_eq!(winter.price_ref, parse("2024-01-26 22:00:00+0000"));
+        assert_eq!(winter.tax_date, parse("2024-01-26 22:00:00+0000"));
+        assert!(!winter.expiry_before_assignment);
+
+        // The API is ground truth even when it disagrees with our assumptions
+        let odd = Settlement::resolve(
+            parse("2024-03-29 20:00:00+0000"),
+            Some(parse("2024-03-29 20:30:00+0000")),
+        );
+        assert_eq!(odd.price_ref, parse("2024-03-29 20:30:00+0000"));
+        assert_eq!(odd.tax_date, parse("2024-03-29 22:00:00+0000"));
+    }
+}
@@ -0,0 +1,295 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Fee Tiers
+//!
+//! LX charges a fee per contract traded, which may drop as our traded notional
+//! over the trailing 30 days crosses each of a few thresholds. We track the
+//! notional of our fills, seeded from the history at startup, so that the
+//! collateral and return computations use the fee we will actually pay
+//! rather than assuming the base one.
+//!
+//! The thresholds depend on our agreement with LX, so the schedule is given
+//! on the command line. By default it is a single tier at the base fee.
+//!
+//! The notional of an option trade is its size times its strike. For any
+//! other trade it is simply the size times the price.
+//!
+
+use super::history;
+use crate::option;
+use crate::units::{Price, Quantity, TaxAsset, UtcTime};
+use std::collections::VecDeque;
+use std::{fmt, str::FromStr};
+
+/// Length of the window over which volume counts towards our tier
+pub const WINDOW_DAYS: i64 = 30;
+
+/// A tier of the fee schedule
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FeeTier {
+    /// The 30-day notional volume at which the tier starts
+    pub min_volume: Price,
+    /// Fee per 100 contracts
+    pub fee: Price,
+}
+
+impl FeeTier {
+    /// The base tier, which applies until we configure otherwise
+    pub const BASE: FeeTier = FeeTier {
+        min_volume: Price::ZERO,
+        fee: Price::TWENTY_FIVE,
+    };
+}
+
+impl fmt::Display for FeeTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.min_volume, self.fee)
+    }
+}
+
+impl FromStr for FeeTier {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. 1000000:20
+        let (min_volume, fee) = s
+            .split_once(':')
+            .ok_or_else(|| format!("bad fee tier {s} (expected <volume>:<fee>)"))?;
+        let parse = |word: &str| {
+            Price::from_str(word.trim_start_matches('$'))
+                .ok()
+                .filter(|price| *price >= Price::ZERO)
+                .ok_or_else(|| format!("bad amount {word} in fee tier {s}"))
+        };
+        Ok(FeeTier {
+            min_volume: parse(min_volume)?,
+            fee: parse(fee)?,
+        })
+    }
+}
+
+/// The notional value of a trade
+pub fn notional(option: Option<option::Option>, price: Price, size: Quantity) -> Price {
+    match option {
+        Some(opt) => opt.strike * size.abs(),
+        None => price * size.abs(),
+    }
+}
+
+/// A fee tier, along with how far we are from the next one
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Tier {
+    /// Index of the tier, starting from 1 for the base tier
+    pub index: usize,
+    /// Fee per 100 contracts
+    pub fee: Price,
+    /// Our notional volume over the window
+    pub volume: Price,
+    /// The additional volume needed to reach the next tier, and that tier's
+    /// fee, if we are not already in the top tier
+    pub next: Option<(Price, Price)>,
+}
+
+impl Tier {
+    /// The tier of a nonempty schedule, sorted by volume, that a given 30-day
+    /// volume puts us in
+    fn from_volume(schedule: &[FeeTier], volume: Price) -> Self {
+        let index = schedule
+            .iter()
+            .rposition(|tier| volume >= tier.min_volume)
+            .unwrap_or(0);
+        Tier {
+            index: index + 1,
+            fee: schedule[index].fee,
+            volume,
+            next: schedule
+                .get(index + 1)
+                .map(|tier| (tier.min_volume - volume, tier.fee)),
+        }
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tier {} (${}/100 contracts), {}-day volume ${}",
+            self.index, self.fee, WINDOW_DAYS, self.volume,
+        )?;
+        match self.next {
+            Some((distance, fee)) => write!(
+                f,
+                "; ${} to tier {} (${}/100 contracts)",
+                distance,
+                self.index + 1,
+                fee,
+            ),
+            None => f.write_str("; top tier"),
+        }
+    }
+}
+
+/// Rolling record of our traded notional
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Volume {
+    /// Notional of each fill, in time order
+    fills: VecDeque<(UtcTime, Price)>,
+    /// The fee schedule, sorted by volume, starting from zero volume
+    schedule: Vec<FeeTier>,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            fills: VecDeque::new(),
+            schedule: vec![FeeTier::BASE],
+        }
+    }
+}
+
+impl Volume {
+    /// Creates a new empty volume record
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Replaces the fee schedule
+    ///
+    /// If no tier starts from zero volume, the base tier is used below the
+    /// lowest one.
+    pub fn set_schedule(&mut self, tiers: &[FeeTier]) {
+        self.schedule = tiers.to_vec();
+        self.schedule.sort_by_key(|tier| tier.min_volume);
+        self.schedule.dedup_by_key(|tier| tier.min_volume);
+        if self.schedule.first().map(|tier| tier.min_volume) != Some(Price::ZERO) {
+            self.schedule.insert(0, FeeTier::BASE);
+        }
+    }
+
+    /// Creates a volume record from the trades in our history which are
+    /// still within the window
+    pub fn from_history(hist: &history::History, now: UtcTime) -> Self {
+        let mut ret = Volume::new();
+        for (time, event) in hist.events() {
+            if let history::Event::Trade {
+                asset, price, size, ..
+            } = *event
+            {
+                let option = match asset {
+                    TaxAsset::Option { option, .. } => Some(option),
+                    _ => None,
+                };
+                ret.record(time, notional(option, price, size));
+            }
+        }
+        ret.expire(now);
+        ret
+    }
+
+    /// Records a fill with the given notional
+    pub fn record(&mut self, time: UtcTime, notional: Price) {
+        self.fills.push_back((time, notional));
+        self.expire(time);
+    }
+
+    /// Forgets any fills which have fallen out of the window
+    fn expire(&mut self, now: UtcTime) {
+        let start = now - chrono::Duration::days(WINDOW_DAYS);
+        while matches!(self.fills.front(), Some(&(time, _)) if time <= start) {
+            self.fills.pop_front();
+        }
+    }
+
+    /// Our notional volume over the window ending at the given time
+    pub fn total(&self, now: UtcTime) -> Price {
+        let start = now - chrono::Duration::days(WINDOW_DAYS);
+        self.fills
+            .iter()
+            .filter(|&&(time, _)| time > start && time <= now)
+            .fold(Price::ZERO, |acc, &(_, notional)| acc + notional)
+    }
+
+    /// The fee tier we are in at the given time
+    pub fn tier(&self, now: UtcTime) -> Tier {
+        Tier::from_volume(&self.schedule, self.total(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn rolling_tiers() {
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let start = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        let day = |n: i64| start + chrono::Duration::days(n);
+
+        let put = option::Option::from_str("2024-06-28P50000").unwrap();
+        // 2 BTC worth of puts struck at $50k is $100k notional, whatever the premium
+        assert_eq!(
+            notional(Some(put), p("1250"), Quantity::Contracts(-200)),
+            p("100000"),
+        );
+        assert_eq!(
+            notional(None, p("60000"), Quantity::Contracts(50)),
+            p("30000")
+        );
+
+        // By default there is a single tier, whatever our volume
+        let mut volume = Volume::new();
+        volume.record(day(0), p("30000000"));
+        let tier = volume.tier(day(0));
+        assert_eq!(
+            (tier.index, tier.fee, tier.next),
+            (1, Price::TWENTY_FIVE, None)
+        );
+
+        let mut volume = Volume::new();
+        let tiers: Vec<FeeTier> = ["25000000:10", "1000000:20", "$5000000:$15"]
+            .iter()
+            .map(|s| FeeTier::from_str(s).unwrap())
+            .collect();
+        volume.set_schedule(&tiers);
+        assert!(FeeTier::from_str("1000000").is_err());
+        assert!(FeeTier::from_str("1000000:-5").is_err());
+        let tier = volume.tier(start);
+        assert_eq!(tier.index, 1);
+        assert_eq!(tier.fee, Price::TWENTY_FIVE);
+        assert_eq!(tier.next, Some((p("1000000"), p("20"))));
+
+        volume.record(day(0), p("600000"));
+        volume.record(day(10), p("500000"));
+        let tier = volume.tier(day(10));
+        assert_eq!(tier.index, 2);
+        assert_eq!(tier.fee, p("20"));
+        assert_eq!(tier.next, Some((p("3900000"), p("15"))));
+        assert_eq!(
+            tier.to_string(),
+            "tier 2 ($20.00/100 contracts), 30-day volume $1100000.00; $3900000.00 to tier 3 ($15.00/100 contracts)",
+        );
+
+        // The first fill rolls out of the window
+        assert_eq!(volume.tier(day(29)).index, 2);
+        assert_eq!(volume.tier(day(30)).index, 1);
+        assert_eq!(volume.total(day(30)), p("500000"));
+
+        volume.record(day(31), p("30000000"));
+        assert_eq!(volume.fills.len(), 2);
+        let tier = volume.tier(day(31));
+        assert_eq!((tier.index, tier.fee, tier.next), (4, p("10"), None));
+        assert!(tier.to_string().ends_with("; top tier"));
+    }
+}
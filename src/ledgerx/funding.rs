@@ -0,0 +1,203 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Funding
+//!
+//! If all our short puts are assigned we need to pay their strike in USD.
+//! This module compares, for each upcoming expiry, the amount we would owe
+//! against the USD we have on hand plus any deposits we have scheduled, so
+//! that we can wire money (or initiate an ACH) before it is too late.
+//!
+//! Scheduled deposits are entered by hand in a CSV file, one per line, in
+//! the form `YYYY-MM-DD,amount`. Blank lines and lines starting with `#` are
+//! ignored.
+//!
+
+use crate::ledgerx::collateral;
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use log::{info, warn};
+use std::path::Path;
+use std::{cmp, fmt, fs, str};
+
+/// ACH transfers take up to 3 business days to clear. We alert about any
+/// shortfall this many days before expiry, which (accounting for weekends)
+/// leaves time to initiate one.
+pub const ALERT_HORIZON_DAYS: i64 = 7;
+
+/// A USD deposit which we expect to arrive on a given date
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ScheduledDeposit {
+    /// Date on which the deposit should be available
+    pub arrives: chrono::NaiveDate,
+    /// Amount of the deposit
+    pub amount: Price,
+}
+
+impl str::FromStr for ScheduledDeposit {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, amount) = s
+            .split_once(',')
+            .ok_or_else(|| anyhow::Error::msg(format!("expected date,amount; got {s}")))?;
+        Ok(ScheduledDeposit {
+            arrives: chrono::NaiveDate::parse_from_str(date.trim(), "%F")
+                .with_context(|| format!("parsing date {date}"))?,
+            amount: Price::from_str(amount.trim())
+                .with_context(|| format!("parsing amount {amount}"))?,
+        })
+    }
+}
+
+/// Reads scheduled deposits from a file
+///
+/// If the file does not exist, returns an empty list.
+pub fn read_deposits(path: &Path) -> anyhow::Result<Vec<ScheduledDeposit>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            return Err(e).with_context(|| format!("reading deposit file {}", path.display()))
+        }
+    };
+    let mut ret = vec![];
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        ret.push(
+            line.parse()
+                .with_context(|| format!("parsing line {} of {}", n + 1, path.display()))?,
+        );
+    }
+    Ok(ret)
+}
+
+/// Funding position as of a single expiry
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ExpiryPlan {
+    /// The expiry date
+    pub expiry: UtcTime,
+    /// Amount we would owe if all short puts expiring at this date were assigned
+    pub obligation: Price,
+    /// USD available to meet the obligation, assuming all earlier obligations
+    /// were also met
+    pub funding: Price,
+}
+
+impl ExpiryPlan {
+    /// Amount by which the obligation exceeds the funding, if any
+    pub fn shortfall(&self) -> Price {
+        cmp::max(self.obligation - self.funding, Price::ZERO)
+    }
+
+    /// Whether there is a shortfall which we should act on now
+    pub fn is_urgent(&self, now: UtcTime) -> bool {
+        self.shortfall() > Price::ZERO
+            && self.expiry - now <= chrono::Duration::days(ALERT_HORIZON_DAYS)
+    }
+}
+
+impl fmt::Display for ExpiryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: obligation {}, funding {}",
+            self.expiry.format("%F"),
+            self.obligation,
+            self.funding,
+        )?;
+        if self.shortfall() > Price::ZERO {
+            write!(f, ", SHORTFALL {}", self.shortfall())?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes the funding position for each upcoming expiry
+///
+/// `usd_balance` should include any USD locked as collateral for the puts,
+/// since that is exactly what it would be used for.
+pub fn plan(
+    portfolio: &collateral::Portfolio,
+    usd_balance: Price,
+    deposits: &[ScheduledDeposit],
+) -> Vec<ExpiryPlan> {
+    let mut spent = Price::ZERO;
+    let mut ret = vec![];
+    for (expiry, obligation) in portfolio.short_put_obligations() {
+        let expiry_date =
+            chrono::NaiveDate::from_ymd_opt(expiry.year(), expiry.month(), expiry.day())
+                .expect("valid date");
+        let deposited = deposits
+            .iter()
+            .filter(|dep| dep.arrives <= expiry_date)
+            .map(|dep| dep.amount)
+            .fold(Price::ZERO, |acc, amt| acc + amt);
+        ret.push(ExpiryPlan {
+            expiry,
+            obligation,
+            funding: usd_balance + deposited - spent,
+        });
+        spent += obligation;
+    }
+    ret
+}
+
+/// Logs a funding plan
+pub fn log_plan(plans: &[ExpiryPlan], now: UtcTime) {
+    if plans.is_empty() {
+        info!("No short puts; nothing to fund.");
+    }
+    for plan in plans {
+        if plan.is_urgent(now) {
+            warn!("{} -- initiate a deposit now", plan);
+        } else {
+            info!("{}", plan);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::option;
+    use std::str::FromStr;
+
+    #[test]
+    fn shortfalls() {
+        let mut port = collateral::Portfolio::new();
+        port.add(option::Option::from_str("2024-03-22P40000").unwrap(), -5);
+        port.add(option::Option::from_str("2024-03-29P40000").unwrap(), -5);
+        // Long puts and calls do not create obligations
+        port.add(option::Option::from_str("2024-03-29P30000").unwrap(), 5);
+        port.add(option::Option::from_str("2024-03-29C40000").unwrap(), -5);
+
+        let deposits = [ScheduledDeposit::from_str("2024-03-25, 1500").unwrap()];
+        let plans = plan(&port, Price::from_str("2500").unwrap(), &deposits);
+        assert_eq!(plans.len(), 2);
+        assert_eq!(plans[0].obligation, Price::from_str("2000").unwrap());
+        assert_eq!(plans[0].shortfall(), Price::ZERO);
+        // $500 left over from the first expiry, plus the $1500 deposit
+        assert_eq!(plans[1].funding, Price::from_str("2000").unwrap());
+        assert_eq!(plans[1].shortfall(), Price::ZERO);
+
+        let plans = plan(&port, Price::from_str("2500").unwrap(), &[]);
+        assert_eq!(plans[1].shortfall(), Price::from_str("1500").unwrap());
+        let now = plans[1].expiry - chrono::Duration::days(10);
+        assert!(!plans[1].is_urgent(now));
+        assert!(plans[1].is_urgent(now + chrono::Duration::days(5)));
+    }
+}
@@ -0,0 +1,256 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Goals
+//!
+//! We attempt to "undo" any past BTC sales by selling puts at a discount,
+//! so that if they are assigned we reacquire the coins for less than we sold
+//! them for.
+//!
+//! This is not rational in terms of total account value (which optimally
+//! would require a memoryless strategy), but is rational if our goal is to
+//! minimize the amount if time we spend holding fewer bitcoins than we
+//! started with.
+//!
+//! The goal is set from the net BTC sold over the last [`LOOKBACK_DAYS`] of
+//! account history. Option premium we collect after that counts toward it,
+//! since it lowers the effective price at which we reacquire the coins. The
+//! goal is saved to a file so that it survives restarts without the history.
+//!
+
+use crate::ledgerx::{collateral, history};
+use crate::option::{self, PutCall};
+use crate::units::{BudgetAsset, Price, Quantity, Underlying, UtcTime};
+use anyhow::Context;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Number of days of history considered when setting the goal
+pub const LOOKBACK_DAYS: i64 = 500;
+
+/// Puts struck up to this percentage below the goal price are considered
+/// "near" it, and we are happy to be assigned on them
+pub const STRIKE_BAND_PCT: f64 = 10.0;
+
+/// A goal to reacquire BTC which we previously sold
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Goal {
+    /// Amount of BTC to reacquire
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub target: bitcoin::Amount,
+    /// USD we received for the BTC, plus any option premium collected since
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub proceeds: Price,
+}
+
+impl Goal {
+    /// Computes the goal from our account history
+    ///
+    /// Returns `None` if we have not been net sellers of BTC in the lookback period.
+    pub fn from_history(hist: &history::History, now: UtcTime) -> Option<Goal> {
+        let mut net_btc = bitcoin::SignedAmount::ZERO;
+        let mut net_usd = Price::ZERO;
+        let mut recent_net_btc = bitcoin::SignedAmount::ZERO;
+        let mut recent_net_usd = Price::ZERO;
+        let mut min_average_price = Price::MAX;
+        for (time, event) in hist.events() {
+            let (delta_btc, delta_usd) = event_delta(event);
+            net_usd += delta_usd;
+            net_btc += delta_btc;
+            if now - time < chrono::Duration::days(LOOKBACK_DAYS) {
+                recent_net_usd += delta_usd;
+                recent_net_btc += delta_btc;
+            }
+
+            if net_btc != bitcoin::SignedAmount::ZERO {
+                let average = net_usd / Quantity::from(net_btc).abs();
+                if average < min_average_price {
+                    info!(
+                        "At {} sold {} for {} (average price {})",
+                        time, net_btc, net_usd, average
+                    );
+                    min_average_price = average;
+                }
+            }
+        }
+
+        if net_btc != bitcoin::SignedAmount::ZERO {
+            info!(
+                "History: sold a total of {} for {} (average price {})",
+                net_btc,
+                net_usd,
+                net_usd / Quantity::from(net_btc).abs()
+            );
+        }
+        if recent_net_btc != bitcoin::SignedAmount::ZERO {
+            info!(
+                "History: in last {} days sold {} for {} (average price {})",
+                LOOKBACK_DAYS,
+                recent_net_btc,
+                recent_net_usd,
+                recent_net_usd / Quantity::from(recent_net_btc).abs()
+            );
+        }
+
+        if recent_net_btc.is_negative() && recent_net_usd > Price::ZERO {
+            Some(Goal {
+                target: Quantity::from(recent_net_btc).abs_btc_equivalent(),
+                proceeds: recent_net_usd,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Loads a goal from a file, returning `None` if the file does not exist
+    pub fn load(path: &Path) -> anyhow::Result<Option<Goal>> {
+        let fh = match fs::File::open(path) {
+            Ok(fh) => fh,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening goal file {}", path.display()))
+            }
+        };
+        serde_json::from_reader(io::BufReader::new(fh))
+            .map(Some)
+            .with_context(|| format!("parsing goal file {}", path.display()))
+    }
+
+    /// Saves the goal to a file
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating data directory")?;
+        }
+        let fh = fs::File::create(path)
+            .with_context(|| format!("creating goal file {}", path.display()))?;
+        serde_json::to_writer(io::BufWriter::new(fh), self)
+            .with_context(|| format!("writing goal file {}", path.display()))
+    }
+
+    /// The price below which reacquiring the BTC leaves us ahead
+    pub fn price(&self) -> Price {
+        self.proceeds / Quantity::from(self.target)
+    }
+
+    /// Updates the goal with the net premium from one of our fills
+    pub fn record_fill(&mut self, premium: Price) {
+        self.proceeds += premium;
+    }
+
+    /// Whether an option is a put struck near the goal price, which we are
+    /// happy to be assigned on
+    pub fn wants_assignment(&self, opt: &option::Option) -> bool {
+        let price = self.price();
+        opt.pc == PutCall::Put
+            && opt.strike <= price
+            && opt.strike >= price.scale_approx(1.0 - STRIKE_BAND_PCT / 100.0)
+    }
+
+    /// The amount of BTC we would reacquire, at or below the goal price, if
+    /// all our short puts were assigned
+    pub fn covered(&self, portfolio: &collateral::Portfolio) -> bitcoin::Amount {
+        let price = self.price();
+        portfolio
+            .positions()
+            .filter(|(opt, size)| opt.pc == PutCall::Put && opt.strike <= price && *size < 0)
+            .map(|(_, size)| Quantity::btc_from_contracts(-size).abs_btc_equivalent())
+            .fold(bitcoin::Amount::ZERO, |acc, amt| acc + amt)
+    }
+
+    /// Logs our progress toward the goal
+    pub fn log_progress(&self, portfolio: &collateral::Portfolio) {
+        let covered = self.covered(portfolio);
+        info!(
+            "{}; short puts would reacquire {} ({:.1}%)",
+            self,
+            covered,
+            covered.to_sat() as f64 * 100.0 / self.target.to_sat() as f64,
+        );
+    }
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Goal: reacquire {} at or below {} (proceeds {})",
+            self.target,
+            self.price(),
+            self.proceeds,
+        )
+    }
+}
+
+/// The change in our BTC and USD balances due to a history event
+fn event_delta(event: &history::Event) -> (bitcoin::SignedAmount, Price) {
+    match event {
+        history::Event::Trade {
+            asset, price, size, ..
+        } => {
+            let delta_btc = if BudgetAsset::from(*asset) == BudgetAsset::Btc {
+                size.btc_equivalent()
+            } else {
+                bitcoin::SignedAmount::ZERO
+            };
+            (delta_btc, -*price * *size)
+        }
+        history::Event::Assignment {
+            option,
+            underlying: Underlying::Btc,
+            size,
+            fee,
+            ..
+        } => match option.pc {
+            PutCall::Call => (size.btc_equivalent() * -1, option.strike * *size - *fee),
+            PutCall::Put => (size.btc_equivalent(), -option.strike * *size - *fee),
+        },
+        _ => (bitcoin::SignedAmount::ZERO, Price::ZERO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn goal_price() {
+        let mut goal = Goal {
+            target: bitcoin::Amount::from_sat(50_000_000),
+            proceeds: Price::from_str("20000").unwrap(),
+        };
+        assert_eq!(goal.price(), Price::from_str("40000").unwrap());
+        goal.record_fill(Price::from_str("500").unwrap());
+        assert_eq!(goal.price(), Price::from_str("41000").unwrap());
+
+        let opt = |s| option::Option::from_str(s).unwrap();
+        assert!(goal.wants_assignment(&opt("2024-03-29P40000")));
+        assert!(!goal.wants_assignment(&opt("2024-03-29P42000")));
+        assert!(!goal.wants_assignment(&opt("2024-03-29P30000")));
+        assert!(!goal.wants_assignment(&opt("2024-03-29C40000")));
+
+        let mut port = collateral::Portfolio::new();
+        port.add(opt("2024-03-29P40000"), -10);
+        port.add(opt("2024-03-29P45000"), -10);
+        assert_eq!(goal.covered(&port), bitcoin::Amount::from_sat(10_000_000));
+
+        let json = serde_json::to_string(&goal).unwrap();
+        assert_eq!(serde_json::from_str::<Goal>(&json).unwrap(), goal);
+    }
+}
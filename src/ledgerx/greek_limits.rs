@@ -0,0 +1,370 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Greek Limits
+//!
+//! Collateral limits bound what we can lose at expiry, but not how much the
+//! value of our shorts can move with volatility, or how quickly our delta
+//! runs away from us on a large move. This module caps the aggregate short
+//! vega and short gamma of each expiry and of each underlying, counting both
+//! our existing positions and the asks we are quoting, so that we stop adding
+//! convexity once a cap is hit.
+//!
+
+use super::interesting::STANDING_IV;
+use crate::option;
+use crate::units::{Price, Underlying, UtcTime};
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Caps on our aggregate short Greeks; `None` means uncapped
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Settings {
+    /// Maximum short vega of a single expiry, in USD per volatility point
+    pub max_vega_per_expiry: Option<u32>,
+    /// Maximum short vega of a single underlying, in USD per volatility point
+    pub max_vega_per_underlying: Option<u32>,
+    /// Maximum short gamma of a single expiry, in USD of delta per 1% move
+    pub max_gamma_per_expiry: Option<u32>,
+    /// Maximum short gamma of a single underlying, in USD of delta per 1% move
+    pub max_gamma_per_underlying: Option<u32>,
+}
+
+impl Settings {
+    /// Whether any cap is set
+    pub fn enabled(&self) -> bool {
+        self.max_vega_per_expiry.is_some()
+            || self.max_vega_per_underlying.is_some()
+            || self.max_gamma_per_expiry.is_some()
+            || self.max_gamma_per_underlying.is_some()
+    }
+}
+
+/// Aggregate short Greeks of a group of options
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Exposure {
+    /// Short vega, in USD per volatility point
+    pub vega: f64,
+    /// Short gamma, as the USD value of the change in delta on a 1% move
+    pub gamma: f64,
+}
+
+impl Exposure {
+    /// Computes the exposure of a position of `size` BTC (negative when short)
+    ///
+    /// Greeks are computed at the IV at which we price standing asks.
+    fn new(opt: &option::Option, size: f64, now: UtcTime, btc_price: Price) -> Self {
+        let btc = btc_price.to_approx_f64();
+        Exposure {
+            // bs_vega is per unit of volatility, i.e. per 100 points
+            vega: -opt.bs_vega(now, btc_price, STANDING_IV) * size / 100.0,
+            // bs_gamma is per dollar; a 1% move changes delta by gamma * btc / 100
+            // BTC, which is worth btc times that
+            gamma: -opt.bs_gamma(now, btc_price, STANDING_IV) * size * btc * btc / 100.0,
+        }
+    }
+
+    fn add(&mut self, other: Exposure) {
+        self.vega += other.vega;
+        self.gamma += other.gamma;
+    }
+}
+
+/// A group of options which is subject to a cap
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Scope {
+    /// All options of an expiry
+    Expiry(UtcTime),
+    /// All options on an underlying
+    Underlying(Underlying),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Scope::Expiry(expiry) => write!(f, "expiry {}", expiry.format("%F")),
+            Scope::Underlying(underlying) => write!(f, "{underlying}"),
+        }
+    }
+}
+
+/// A quote which would take our exposure over a cap
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Breach {
+    /// The group of options whose cap would be exceeded
+    pub scope: Scope,
+    /// Which Greek would exceed its cap
+    pub greek: &'static str,
+    /// The exposure there would be with the quote
+    pub exposure: f64,
+    /// The cap
+    pub cap: u32,
+}
+
+impl fmt::Display for Breach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "short {} of {} would be ${:.0} (cap ${})",
+            self.greek, self.scope, self.exposure, self.cap,
+        )
+    }
+}
+
+/// Our short Greek exposure, by expiry and by underlying
+#[derive(Clone, PartialEq, Debug)]
+pub struct Limits {
+    settings: Settings,
+    now: UtcTime,
+    btc_price: Price,
+    expiries: BTreeMap<UtcTime, Exposure>,
+    underlyings: HashMap<Underlying, Exposure>,
+}
+
+impl Limits {
+    /// Computes our exposure from a list of positions, given as underlyings,
+    /// options and their sizes in BTC (negative for short positions)
+    ///
+    /// We only have a price reference for BTC, so options on any other
+    /// underlying are ignored.
+    pub fn new<I: IntoIterator<Item = (Underlying, option::Option, f64)>>(
+        settings: Settings,
+        positions: I,
+        now: UtcTime,
+        btc_price: Price,
+    ) -> Self {
+        let mut ret = Limits {
+            settings,
+            now,
+            btc_price,
+            expiries: BTreeMap::new(),
+            underlyings: HashMap::new(),
+        };
+        for (underlying, opt, size) in positions {
+            ret.add(underlying, &opt, size);
+        }
+        ret
+    }
+
+    /// Adds a position or quote to our exposure, without checking the caps
+    pub fn add(&mut self, underlying: Underlying, opt: &option::Option, size: f64) {
+        if underlying != Underlying::Btc || opt.expiry <= self.now {
+            return;
+        }
+        let exposure = Exposure::new(opt, size, self.now, self.btc_price);
+        self.expiries.entry(opt.expiry).or_default().add(exposure);
+        self.underlyings
+            .entry(underlying)
+            .or_default()
+            .add(exposure);
+    }
+
+    /// Checks whether a quote would take our exposure over any cap
+    pub fn check(
+        &self,
+        underlying: Underlying,
+        opt: &option::Option,
+        size: f64,
+    ) -> Result<(), Breach> {
+        if underlying != Underlying::Btc || opt.expiry <= self.now {
+            return Ok(());
+        }
+        let added = Exposure::new(opt, size, self.now, self.btc_price);
+        let mut expiry = self.expiries.get(&opt.expiry).copied().unwrap_or_default();
+        expiry.add(added);
+        let mut total = self
+            .underlyings
+            .get(&underlying)
+            .copied()
+            .unwrap_or_default();
+        total.add(added);
+
+        let checks = [
+            (
+                Scope::Expiry(opt.expiry),
+                "vega",
+                added.vega,
+                expiry.vega,
+                self.settings.max_vega_per_expiry,
+            ),
+            (
+                Scope::Underlying(underlying),
+                "vega",
+                added.vega,
+                total.vega,
+                self.settings.max_vega_per_underlying,
+            ),
+            (
+                Scope::Expiry(opt.expiry),
+                "gamma",
+                added.gamma,
+                expiry.gamma,
+                self.settings.max_gamma_per_expiry,
+            ),
+            (
+                Scope::Underlying(underlying),
+                "gamma",
+                added.gamma,
+                total.gamma,
+                self.settings.max_gamma_per_underlying,
+            ),
+        ];
+        for (scope, greek, added, exposure, cap) in checks {
+            // Quotes which reduce our exposure are always allowed
+            if let Some(cap) = cap {
+                if added > 0.0 && exposure > f64::from(cap) {
+                    return Err(Breach {
+                        scope,
+                        greek,
+                        exposure,
+                        cap,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a quote to our exposure, unless it would take it over any cap
+    pub fn try_add(
+        &mut self,
+        underlying: Underlying,
+        opt: &option::Option,
+        size: f64,
+    ) -> Result<(), Breach> {
+        self.check(underlying, opt, size)?;
+        self.add(underlying, opt, size);
+        Ok(())
+    }
+
+    /// Logs our exposure against the caps
+    pub fn log(&self) {
+        if self.expiries.is_empty() {
+            return;
+        }
+        info!("Short Greeks at BTC {}:", self.btc_price);
+        let mut underlyings: Vec<_> = self.underlyings.iter().collect();
+        underlyings.sort_by_key(|(underlying, _)| underlying.to_string());
+        for (underlying, exposure) in underlyings {
+            info!(
+                "    {}: {}",
+                underlying,
+                self.describe(
+                    exposure,
+                    self.settings.max_vega_per_underlying,
+                    self.settings.max_gamma_per_underlying
+                ),
+            );
+        }
+        for (expiry, exposure) in &self.expiries {
+            info!(
+                "    Expiry {}: {}",
+                expiry.format("%F"),
+                self.describe(
+                    exposure,
+                    self.settings.max_vega_per_expiry,
+                    self.settings.max_gamma_per_expiry
+                ),
+            );
+        }
+    }
+
+    /// Describes an exposure along with the applicable caps
+    fn describe(
+        &self,
+        exposure: &Exposure,
+        vega_cap: Option<u32>,
+        gamma_cap: Option<u32>,
+    ) -> String {
+        let cap = |cap: Option<u32>| match cap {
+            Some(cap) => format!("cap ${cap}"),
+            None => "no cap".to_owned(),
+        };
+        format!(
+            "vega ${:.0} per vol point ({}), gamma ${:.0} per 1% move ({})",
+            exposure.vega,
+            cap(vega_cap),
+            exposure.gamma,
+            cap(gamma_cap),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn caps() {
+        let now = UtcTime::parse_coinbase("2030-06-03T15:00:00Z").unwrap();
+        let btc = Price::from_str("60000").unwrap();
+        let near = option::Option::from_str("2030-06-28P55000").unwrap();
+        let near_call = option::Option::from_str("2030-06-28C65000").unwrap();
+        let far = option::Option::from_str("2030-12-27P50000").unwrap();
+
+        // Short one BTC of the near put, long a little of the call
+        let positions = vec![
+            (Underlying::Btc, near, -1.0),
+            (Underlying::Btc, near_call, 0.25),
+            (Underlying::Eth, far, -100.0),
+        ];
+        let one_put = Exposure::new(&near, -1.0, now, btc);
+        assert!(one_put.vega > 0.0);
+        assert!(one_put.gamma > 0.0);
+
+        // Without caps everything is allowed, and ETH options are ignored
+        let limits = Limits::new(Settings::default(), positions.clone(), now, btc);
+        assert!(limits.check(Underlying::Btc, &near, -100.0).is_ok());
+        assert_eq!(limits.underlyings.len(), 1);
+        let near_vega = limits.expiries[&near.expiry].vega;
+        assert!(near_vega < one_put.vega);
+
+        // Cap the near expiry's vega at a little more than we have
+        let settings = Settings {
+            max_vega_per_expiry: Some(near_vega.ceil() as u32 + 1),
+            ..Default::default()
+        };
+        let mut limits = Limits::new(settings, positions, now, btc);
+        let breach = limits.check(Underlying::Btc, &near, -1.0).unwrap_err();
+        assert_eq!(breach.scope, Scope::Expiry(near.expiry));
+        assert_eq!(breach.greek, "vega");
+        // ...but the far expiry is unaffected, as are quotes which reduce exposure
+        assert!(limits.check(Underlying::Btc, &far, -0.01).is_ok());
+        assert!(limits.check(Underlying::Btc, &near, 1.0).is_ok());
+
+        // An underlying-wide cap catches the far expiry once we add to it
+        limits.settings.max_gamma_per_underlying = Some(1);
+        let breach = limits.check(Underlying::Btc, &far, -0.01).unwrap_err();
+        assert_eq!(breach.scope, Scope::Underlying(Underlying::Btc));
+        assert_eq!(breach.greek, "gamma");
+        assert!(breach
+            .to_string()
+            .starts_with("short gamma of BTC would be $"));
+
+        limits.add(Underlying::Btc, &far, -1.0);
+        assert_eq!(limits.expiries.len(), 2);
+
+        // An order we would otherwise keep is checked at its own size, and
+        // only counted if it is within the caps
+        let mut limits = Limits::new(settings, vec![(Underlying::Btc, near, -0.5)], now, btc);
+        let before = limits.expiries[&near.expiry];
+        let breach = limits.try_add(Underlying::Btc, &near, -1.0).unwrap_err();
+        assert_eq!(breach.greek, "vega");
+        assert_eq!(limits.expiries[&near.expiry], before);
+        assert!(limits.try_add(Underlying::Btc, &near, -0.01).is_ok());
+        assert!(limits.expiries[&near.expiry].vega > before.vega);
+    }
+}
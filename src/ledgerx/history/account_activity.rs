@@ -0,0 +1,290 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Account Activity
+//!
+//! The LX API's pagination no longer returns some older account events, but
+//! they still appear in the "account activity" CSV export available from the
+//! LX website. This module parses that export so that the missing events can
+//! be backfilled.
+//!
+//! The export has a header line, and we locate columns by name, so that
+//! extra or re-ordered columns do not matter. We use the columns `Date`
+//! (RFC 3339), `Type` (`Deposit`, `Withdrawal` or `Trade`), `Asset`, `Amount`,
+//! `Status` and `Address` for deposits and withdrawals, and `Contract` (the
+//! LX label), `Side`, `Amount` (in contracts), `Price` and `Fee` for trades.
+//! Rows of type `Assignment Fee` or `Exercise Fee` which name a `Contract` are
+//! fees charged on assignment, which the API does not report; their `Amount`
+//! is in dollars. Rows of the bare type `Fee` do not say what they were charged
+//! for, so are ignored with a warning if they name a contract. Other types of
+//! row (e.g. expiries and assignments, which we get from the positions
+//! endpoint) are ignored.
+//!
+
+use super::{Deposit, Withdrawal};
+use crate::units::{DepositAsset, Price, UnknownQuantity, UtcTime};
+use anyhow::Context;
+use log::{debug, warn};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// A single record from the account activity export
+#[derive(Debug)]
+pub enum Record {
+    /// A deposit, in the same form as returned by the API
+    Deposit(Deposit),
+    /// A withdrawal, in the same form as returned by the API
+    Withdrawal(Withdrawal),
+    /// A trade
+    Trade {
+        /// LX's label for the contract which was traded
+        contract_label: String,
+        /// Time of execution
+        time: UtcTime,
+        /// Price per unit of the underlying
+        price: Price,
+        /// Number of contracts, negative for sales
+        size: i64,
+        /// Fee, with the same sign convention as the API
+        fee: Price,
+    },
+    /// A fee charged on the assignment of an option
+    AssignmentFee {
+        /// LX's label for the contract which was assigned
+        contract_label: String,
+        /// Time the fee was charged
+        time: UtcTime,
+        /// Amount charged
+        amount: Price,
+    },
+}
+
+/// Strips quotes, dollar signs and thousands separators from a field
+fn clean(field: &str) -> String {
+    field
+        .trim()
+        .chars()
+        .filter(|&c| c != '"' && c != ',' && c != '$')
+        .collect()
+}
+
+/// Parses a number of base units (cents, satoshis or wei) of an asset
+fn parse_amount(field: &str, asset: DepositAsset) -> anyhow::Result<UnknownQuantity> {
+    let mut dec = Decimal::from_str(&clean(field))
+        .with_context(|| format!("parsing amount {field}"))?
+        .abs();
+    dec.rescale(match asset {
+        DepositAsset::Usd => 2,
+        DepositAsset::Btc => 8,
+        DepositAsset::Eth => 18,
+    });
+    let n =
+        i64::try_from(dec.mantissa()).with_context(|| format!("amount {field} out of range"))?;
+    Ok(UnknownQuantity::from(n))
+}
+
+/// Parses the lines of an account activity export, including its header
+pub fn parse(lines: &[String]) -> anyhow::Result<Vec<Record>> {
+    let mut lines = lines.iter().filter(|line| !line.trim().is_empty());
+    let header = match lines.next() {
+        Some(header) => header,
+        None => return Ok(vec![]),
+    };
+    let columns: HashMap<String, usize> = split(header)
+        .into_iter()
+        .enumerate()
+        .map(|(n, name)| (clean(&name).to_lowercase(), n))
+        .collect();
+
+    let mut ret = vec![];
+    for (n, line) in lines.enumerate() {
+        let fields = split(line);
+        let get = |name: &str| -> anyhow::Result<&str> {
+            columns
+                .get(name)
+                .and_then(|&idx| fields.get(idx))
+                .map(|s| s.trim().trim_matches('"'))
+                .with_context(|| format!("missing field {name}"))
+        };
+        let record = (|| -> anyhow::Result<Option<Record>> {
+            let date = get("date")?;
+            let time = crate::units::parse_lx_time(date)
+                .with_context(|| format!("parsing date {date}"))?;
+            let status = get("status")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned);
+            let asset = || -> anyhow::Result<DepositAsset> {
+                let asset = get("asset")?;
+                serde_json::from_value(serde_json::Value::String(asset.to_uppercase()))
+                    .with_context(|| format!("parsing asset {asset}"))
+            };
+            let ty = get("type")?.to_lowercase();
+            match ty.as_str() {
+                "deposit" => {
+                    let asset = asset()?;
+                    Ok(Some(Record::Deposit(Deposit {
+                        amount: parse_amount(get("amount")?, asset)?,
+                        asset,
+                        address: get("address").unwrap_or("").to_owned(),
+                        status,
+                        created_at: time,
+                    })))
+                }
+                "withdrawal" => {
+                    let asset = asset()?;
+                    Ok(Some(Record::Withdrawal(Withdrawal {
+                        amount: parse_amount(get("amount")?, asset)?,
+                        asset,
+                        address: get("address").unwrap_or("").to_owned(),
+                        status,
+                        created_at: time,
+                    })))
+                }
+                "trade" => {
+                    let amount = clean(get("amount")?);
+                    let size: i64 = amount
+                        .parse()
+                        .with_context(|| format!("parsing trade size {amount}"))?;
+                    let side = get("side")?;
+                    let size = match side.to_lowercase().as_str() {
+                        "buy" | "bid" => size.abs(),
+                        "sell" | "ask" => -size.abs(),
+                        _ => return Err(anyhow::Error::msg(format!("unknown side {side}"))),
+                    };
+                    let price = get("price")?;
+                    let fee = get("fee")?;
+                    Ok(Some(Record::Trade {
+                        contract_label: get("contract")?.to_owned(),
+                        time,
+                        price: Price::from_str(price)
+                            .with_context(|| format!("parsing price {price}"))?,
+                        size,
+                        fee: Price::from_str(fee).with_context(|| format!("parsing fee {fee}"))?,
+                    }))
+                }
+                "assignment fee" | "exercise fee" => {
+                    let contract_label = get("contract")?;
+                    if contract_label.is_empty() {
+                        warn!("Ignoring {} not tied to a contract: {}", ty, line);
+                        return Ok(None);
+                    }
+                    let amount = clean(get("amount")?);
+                    Ok(Some(Record::AssignmentFee {
+                        contract_label: contract_label.to_owned(),
+                        time,
+                        amount: Price::from_str(&amount)
+                            .with_context(|| format!("parsing fee amount {amount}"))?
+                            .abs(),
+                    }))
+                }
+                "fee" if !get("contract")?.is_empty() => {
+                    warn!("Ignoring fee of unknown kind: {}", line);
+                    Ok(None)
+                }
+                other => {
+                    debug!("Ignoring account activity of type {}: {}", other, line);
+                    Ok(None)
+                }
+            }
+        })()
+        .with_context(|| format!("parsing account activity line {}: {line}", n + 2))?;
+        ret.extend(record);
+    }
+    Ok(ret)
+}
+
+/// Splits a CSV line into fields, respecting double quotes
+fn split(line: &str) -> Vec<String> {
+    let mut ret = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for ch in line.chars() {
+        match ch {
+            '"' => {
+                quoted = !quoted;
+                current.push(ch);
+            }
+            ',' if !quoted => ret.push(std::mem::take(&mut current)),
+            _ => current.push(ch),
+        }
+    }
+    ret.push(current);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_export() {
+        let lines: Vec<String> = [
+            "Date,Type,Asset,Amount,Contract,Side,Price,Fee,Status",
+            "2021-03-01T15:00:00Z,Deposit,USD,\"10,000.00\",,,,,completed",
+            "2021-03-02T15:00:00Z,Trade,,5,BTC-Mini-26MAR2021-40000-Put,Sell,\"1,200.00\",1.25,",
+            "2021-03-26T21:00:00Z,Expiry,,5,BTC-Mini-26MAR2021-40000-Put,,,,",
+            "2021-03-29T15:00:00Z,Withdrawal,BTC,0.015,,,,,",
+            "2021-03-26T22:00:00Z,Assignment Fee,USD,-2.50,BTC-Mini-26MAR2021-40000-Put,,,,",
+            "2021-03-31T00:00:00Z,Fee,USD,-10.00,,,,,",
+            "2021-03-31T00:00:00Z,Fee,USD,-1.00,BTC-Mini-26MAR2021-40000-Put,,,,",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let records = parse(&lines).unwrap();
+        assert_eq!(records.len(), 4);
+        match records[0] {
+            Record::Deposit(ref dep) => {
+                assert_eq!(dep.asset, DepositAsset::Usd);
+                assert_eq!(dep.amount, UnknownQuantity::from(1_000_000));
+                assert_eq!(dep.status.as_deref(), Some("completed"));
+            }
+            ref x => panic!("expected deposit, got {:?}", x),
+        }
+        match records[1] {
+            Record::Trade {
+                ref contract_label,
+                size,
+                price,
+                ..
+            } => {
+                assert_eq!(contract_label, "BTC-Mini-26MAR2021-40000-Put");
+                assert_eq!(size, -5);
+                assert_eq!(price, Price::from_str("1200").unwrap());
+            }
+            ref x => panic!("expected trade, got {:?}", x),
+        }
+        match records[2] {
+            Record::Withdrawal(ref withd) => {
+                assert_eq!(withd.amount, UnknownQuantity::from(1_500_000));
+            }
+            ref x => panic!("expected withdrawal, got {:?}", x),
+        }
+        match records[3] {
+            Record::AssignmentFee {
+                ref contract_label,
+                amount,
+                ..
+            } => {
+                assert_eq!(contract_label, "BTC-Mini-26MAR2021-40000-Put");
+                assert_eq!(amount, Price::from_str("2.50").unwrap());
+            }
+            ref x => panic!("expected assignment fee, got {:?}", x),
+        }
+    }
+}
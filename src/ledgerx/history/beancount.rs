@@ -0,0 +1,552 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Beancount Export
+//!
+//! Converts the event history into double-entry plain-text accounting
+//! directives in the Beancount format (which ledger-cli can import).
+//!
+//! BTC, ETH and options are held at cost. Reductions are booked FIFO by Beancount
+//! itself, with the realized gain or loss balanced against the P&L account,
+//! so these figures will not in general match the tax reports, which use the
+//! configured lot selection strategy. All amounts are rounded down to the cent.
+//!
+
+use super::Event;
+use crate::units::{ContractSize, DepositAsset, Price, Quantity, TaxAsset, UtcTime};
+use anyhow::Context as _;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+/// Account names used in the exported directives
+#[derive(Clone, PartialEq, Eq, Deserialize, Debug)]
+#[serde(default)]
+pub struct Accounts {
+    /// USD held at LX
+    pub usd: String,
+    /// BTC held at LX
+    pub btc: String,
+    /// ETH held at LX
+    pub eth: String,
+    /// Option positions
+    pub options: String,
+    /// The other side of deposits and withdrawals
+    pub transfers: String,
+    /// Trading fees
+    pub fees: String,
+    /// Realized gains and losses, including premium on expired options
+    pub pnl: String,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Accounts {
+            usd: "Assets:LedgerX:USD".into(),
+            btc: "Assets:LedgerX:BTC".into(),
+            eth: "Assets:LedgerX:ETH".into(),
+            options: "Assets:LedgerX:Options".into(),
+            transfers: "Equity:LedgerX:Transfers".into(),
+            fees: "Expenses:LedgerX:Fees".into(),
+            pnl: "Income:LedgerX:PnL".into(),
+        }
+    }
+}
+
+/// The Beancount commodity name of an asset
+///
+/// Option names are built from the underlying, the expiry, put/call and the
+/// strike, e.g. `BTCM-240628P40000` for a mini, since LX's labels are too long
+/// to be commodity names. Next-day BTC is treated as BTC, and likewise ETH.
+fn commodity(asset: TaxAsset) -> String {
+    match asset {
+        TaxAsset::Bitcoin => "BTC".into(),
+        TaxAsset::Ether => "ETH".into(),
+        TaxAsset::NextDay { underlying, .. } => underlying.to_string(),
+        TaxAsset::Option {
+            underlying,
+            option,
+            contract_size,
+        } => format!(
+            "{}{}-{}{}{}",
+            underlying,
+            if contract_size == ContractSize::Mini {
+                "M"
+            } else {
+                ""
+            },
+            option.expiry.format("%y%m%d"),
+            option.pc.to_char(),
+            option.strike.to_int(),
+        ),
+    }
+}
+
+/// The number of decimal places in a Beancount number of the given commodity,
+/// whose base units are satoshis for BTC, gwei for ETH and otherwise contracts
+fn decimals(commodity: &str) -> u32 {
+    match commodity {
+        "BTC" => 8,
+        "ETH" => 9,
+        _ => 0,
+    }
+}
+
+/// Formats a number of base units (satoshis, gwei or contracts) as a Beancount number
+fn units_str(units: i64, decimals: u32) -> String {
+    if decimals > 0 {
+        let sign = if units < 0 { "-" } else { "" };
+        let abs = units.unsigned_abs();
+        let one = 10u64.pow(decimals);
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            abs / one,
+            abs % one,
+            width = decimals as usize
+        )
+    } else {
+        units.to_string()
+    }
+}
+
+/// Formats a number of cents as a Beancount number
+fn cents_str(cents: i64) -> String {
+    Price::from_cents(cents).to_string()
+}
+
+/// Converts events into Beancount transactions, tracking positions so that
+/// reductions can be distinguished from augmentations
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Exporter<'a> {
+    accounts: &'a Accounts,
+    /// Position in each commodity, in satoshis, gwei or contracts
+    positions: HashMap<String, i64>,
+}
+
+impl<'a> Exporter<'a> {
+    /// Creates a new exporter with no positions
+    pub fn new(accounts: &'a Accounts) -> Self {
+        Exporter {
+            accounts,
+            positions: HashMap::new(),
+        }
+    }
+
+    /// The options and account openings which must precede the transactions
+    pub fn header(&self, open_date: UtcTime) -> String {
+        let mut ret = String::new();
+        ret.push_str("option \"operating_currency\" \"USD\"\n");
+        ret.push_str("option \"booking_method\" \"FIFO\"\n\n");
+        let accounts = self.accounts;
+        for account in [
+            &accounts.usd,
+            &accounts.btc,
+            &accounts.eth,
+            &accounts.options,
+            &accounts.transfers,
+            &accounts.fees,
+            &accounts.pnl,
+        ] {
+            writeln!(ret, "{} open {}", open_date.format("%F"), account).unwrap();
+        }
+        ret
+    }
+
+    /// Postings which change the position in a held-at-cost commodity by
+    /// `delta` units, worth `total_cents` in all
+    ///
+    /// Any part of the change which reduces the existing position is booked
+    /// against existing lots at that price; any remainder opens a new lot at
+    /// that cost. Returns whether any lots were reduced.
+    fn holding_postings(
+        &mut self,
+        out: &mut String,
+        account: &str,
+        commodity: &str,
+        delta: i64,
+        total_cents: i64,
+    ) -> bool {
+        let decimals = decimals(commodity);
+        let position = self.positions.entry(commodity.into()).or_default();
+        let reduced = if *position != 0 && (*position > 0) != (delta > 0) {
+            if delta.abs() > position.abs() {
+                -*position
+            } else {
+                delta
+            }
+        } else {
+            0
+        };
+        let augmented = delta - reduced;
+        *position += delta;
+
+        let reduced_cents = if delta == 0 {
+            0
+        } else {
+            (i128::from(total_cents) * i128::from(reduced) / i128::from(delta)) as i64
+        };
+        if reduced != 0 {
+            writeln!(
+                out,
+                "  {}  {} {} {{}} @@ {} USD",
+                account,
+                units_str(reduced, decimals),
+                commodity,
+                cents_str(reduced_cents.abs()),
+            )
+            .unwrap();
+        }
+        if augmented != 0 {
+            writeln!(
+                out,
+                "  {}  {} {} {{{{{} USD}}}}",
+                account,
+                units_str(augmented, decimals),
+                commodity,
+                cents_str((total_cents - reduced_cents).abs()),
+            )
+            .unwrap();
+        }
+        reduced != 0
+    }
+
+    /// Converts an event into a Beancount transaction
+    ///
+    /// The exporter's positions are updated even if the transaction is not
+    /// output, so every event must be passed in order. `assignment_price` is
+    /// the price of the underlying used to value assigned options, and must be
+    /// given for assignments. As in the tax reports, an assignment closes the
+    /// options at their intrinsic value and trades the delivered coins at this
+    /// market price; between them the USD leg comes to the strike price.
+    /// Returns `None` for events with no accounting effect.
+    pub fn transaction(
+        &mut self,
+        date: UtcTime,
+        event: &Event,
+        assignment_price: Option<Price>,
+    ) -> anyhow::Result<Option<String>> {
+        let accounts = self.accounts;
+        let mut out = String::new();
+        let day = date.format("%F");
+        match *event {
+            // ACH reversals net to zero
+            Event::UsdDeposit {
+                reversal: Some(_), ..
+            }
+            | Event::Withdrawal {
+                reversal: Some(_), ..
+            } => return Ok(None),
+            // Self-transfers leave the coins, and their cost, in our hands
+            Event::Withdrawal {
+                self_transfer: true,
+                ..
+            }
+            | Event::BtcRedeposit { .. } => return Ok(None),
+            Event::UsdDeposit { amount, .. } => {
+                let cents = super::usd_value(amount)
+                    .with_context(|| format!("USD deposit of non-USD amount {amount}"))?
+                    .to_cents();
+                writeln!(out, "{day} * \"LedgerX\" \"USD deposit\"").unwrap();
+                writeln!(out, "  {}  {} USD", accounts.usd, cents_str(cents)).unwrap();
+                writeln!(out, "  {}", accounts.transfers).unwrap();
+            }
+            Event::BtcDeposit {
+                amount,
+                outpoint,
+                ref lot_info,
+            } => {
+                let basis = lot_info
+                    .basis_price(None)
+                    .with_context(|| format!("determining basis of deposit {outpoint}"))?;
+                let sats = amount.to_sat() as i64;
+                let cents = (basis * Quantity::from(amount)).to_cents();
+                writeln!(out, "{day} * \"LedgerX\" \"BTC deposit {outpoint}\"").unwrap();
+                self.holding_postings(&mut out, &accounts.btc, "BTC", sats, cents);
+                writeln!(out, "  {}", accounts.transfers).unwrap();
+            }
+            Event::EthDeposit {
+                amount,
+                ref lot_info,
+            } => {
+                let basis = lot_info
+                    .basis_price(None)
+                    .with_context(|| format!("determining basis of ETH deposit at {date}"))?;
+                let gwei = match amount {
+                    Quantity::Ether(n) => n,
+                    _ => {
+                        return Err(anyhow::Error::msg(format!(
+                            "ETH deposit of non-ETH amount {amount}"
+                        )))
+                    }
+                };
+                let cents = (basis * amount).to_cents();
+                writeln!(out, "{day} * \"LedgerX\" \"ETH deposit\"").unwrap();
+                self.holding_postings(&mut out, &accounts.eth, "ETH", gwei, cents);
+                writeln!(out, "  {}", accounts.transfers).unwrap();
+            }
+            Event::Withdrawal { amount, asset, .. } => match asset {
+                DepositAsset::Usd => {
+                    let cents = super::usd_value(amount)
+                        .with_context(|| format!("USD withdrawal of non-USD amount {amount}"))?
+                        .to_cents();
+                    writeln!(out, "{day} * \"LedgerX\" \"USD withdrawal\"").unwrap();
+                    writeln!(out, "  {}  -{} USD", accounts.usd, cents_str(cents.abs())).unwrap();
+                    writeln!(out, "  {}", accounts.transfers).unwrap();
+                }
+                DepositAsset::Btc => {
+                    let sats = match amount {
+                        Quantity::Bitcoin(btc) => btc.to_sat().abs(),
+                        _ => {
+                            return Err(anyhow::Error::msg(format!(
+                                "BTC withdrawal of non-BTC amount {amount}"
+                            )))
+                        }
+                    };
+                    writeln!(out, "{day} * \"LedgerX\" \"BTC withdrawal\"").unwrap();
+                    writeln!(
+                        out,
+                        "  {}  {} BTC {{}}",
+                        accounts.btc,
+                        units_str(-sats, decimals("BTC"))
+                    )
+                    .unwrap();
+                    *self.positions.entry("BTC".into()).or_default() -= sats;
+                    writeln!(out, "  {}", accounts.transfers).unwrap();
+                }
+                DepositAsset::Eth => {
+                    let gwei = match amount {
+                        Quantity::Ether(n) => n.abs(),
+                        _ => {
+                            return Err(anyhow::Error::msg(format!(
+                                "ETH withdrawal of non-ETH amount {amount}"
+                            )))
+                        }
+                    };
+                    writeln!(out, "{day} * \"LedgerX\" \"ETH withdrawal\"").unwrap();
+                    writeln!(
+                        out,
+                        "  {}  {} ETH {{}}",
+                        accounts.eth,
+                        units_str(-gwei, decimals("ETH"))
+                    )
+                    .unwrap();
+                    *self.positions.entry("ETH".into()).or_default() -= gwei;
+                    writeln!(out, "  {}", accounts.transfers).unwrap();
+                }
+            },
+            Event::Trade {
+                asset,
+                price,
+                size,
+                fee,
+            } => {
+                let name = commodity(asset);
+                let (units, account) = match size {
+                    Quantity::Bitcoin(btc) => (btc.to_sat(), &accounts.btc),
+                    Quantity::Ether(n) => (n, &accounts.eth),
+                    Quantity::Contracts(n) | Quantity::EthContracts(n) => (n, &accounts.options),
+                    _ => {
+                        return Err(anyhow::Error::msg(format!(
+                            "trade of {asset} has unexpected size {size}"
+                        )))
+                    }
+                };
+                let cents = (price * size).to_cents();
+                let fee_cents = fee.to_cents();
+                writeln!(
+                    out,
+                    "{} * \"LedgerX\" \"{} {} {} @ {}\"",
+                    day,
+                    if units > 0 { "Buy" } else { "Sell" },
+                    units_str(units.abs(), decimals(&name)),
+                    name,
+                    price,
+                )
+                .unwrap();
+                let reduced = self.holding_postings(&mut out, account, &name, units, cents);
+                writeln!(out, "  {}  {} USD", accounts.usd, cents_str(-cents)).unwrap();
+                if fee_cents != 0 {
+                    writeln!(out, "  {}  {} USD", accounts.fees, cents_str(fee_cents)).unwrap();
+                    writeln!(out, "  {}  {} USD", accounts.usd, cents_str(-fee_cents)).unwrap();
+                }
+                if reduced {
+                    writeln!(out, "  {}", accounts.pnl).unwrap();
+                }
+            }
+            Event::Expiry {
+                option,
+                underlying,
+                contract_size,
+                size,
+            }
+            | Event::Assignment {
+                option,
+                underlying,
+                contract_size,
+                size,
+                ..
+            } => {
+                let name = commodity(TaxAsset::Option {
+                    underlying,
+                    option,
+                    contract_size,
+                });
+                let units = match size {
+                    Quantity::Contracts(n) | Quantity::EthContracts(n) => n,
+                    _ => {
+                        return Err(anyhow::Error::msg(format!(
+                            "expiry of {name} has unexpected size {size}"
+                        )))
+                    }
+                };
+                let fee_cents = match *event {
+                    Event::Assignment { fee, .. } => fee.to_cents(),
+                    _ => 0,
+                };
+                let (what, cents, delivery) = if let Event::Assignment { .. } = event {
+                    let btc_price = assignment_price
+                        .with_context(|| format!("no BTC price given for assignment of {name}"))?;
+                    let intrinsic = option.intrinsic_value(btc_price).max(Price::ZERO);
+                    let coins = match option.pc {
+                        crate::option::Call => (-size).coin_equivalent(),
+                        crate::option::Put => size.coin_equivalent(),
+                    };
+                    let delivery = match coins {
+                        Quantity::Bitcoin(btc) => (&accounts.btc, "BTC", btc.to_sat()),
+                        Quantity::Ether(n) => (&accounts.eth, "ETH", n),
+                        _ => {
+                            return Err(anyhow::Error::msg(format!(
+                                "assignment of {name} delivers unexpected amount {coins}"
+                            )))
+                        }
+                    };
+                    let delivery_cents = (btc_price * coins).to_cents();
+                    (
+                        "assigned",
+                        (intrinsic * size).to_cents(),
+                        Some((delivery, delivery_cents)),
+                    )
+                } else {
+                    ("expired", 0, None)
+                };
+                writeln!(
+                    out,
+                    "{} * \"LedgerX\" \"{} {} {}\"",
+                    day,
+                    units.abs(),
+                    name,
+                    what
+                )
+                .unwrap();
+                self.holding_postings(&mut out, &accounts.options, &name, units, cents);
+                if let Some(((account, coin, coin_units), coin_cents)) = delivery {
+                    self.holding_postings(&mut out, account, coin, coin_units, coin_cents);
+                    let usd_cents = -cents - coin_cents;
+                    writeln!(out, "  {}  {} USD", accounts.usd, cents_str(usd_cents)).unwrap();
+                }
+                if fee_cents != 0 {
+                    writeln!(out, "  {}  {} USD", accounts.fees, cents_str(fee_cents)).unwrap();
+                    writeln!(out, "  {}  {} USD", accounts.usd, cents_str(-fee_cents)).unwrap();
+                }
+                writeln!(out, "  {}", accounts.pnl).unwrap();
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Underlying;
+    use std::str::FromStr;
+
+    #[test]
+    fn short_put_lifecycle() {
+        let accounts = Accounts::default();
+        let mut exporter = Exporter::new(&accounts);
+        let option = crate::option::Option::from_str("2030-06-28P40000").unwrap();
+        let asset = TaxAsset::Option {
+            underlying: Underlying::Btc,
+            option,
+            contract_size: ContractSize::Mini,
+        };
+        assert_eq!(commodity(asset), "BTCM-300628P40000");
+        let date = UtcTime::now();
+
+        // Open a short of 10 at $1000/BTC, i.e. $100 of premium
+        let sell = Event::Trade {
+            asset,
+            price: Price::from_str("1000").unwrap(),
+            size: Quantity::Contracts(-10),
+            fee: Price::from_str("2.50").unwrap(),
+        };
+        let tx = exporter.transaction(date, &sell, None).unwrap().unwrap();
+        assert!(tx.contains("Assets:LedgerX:Options  -10 BTCM-300628P40000 {{100.00 USD}}"));
+        assert!(tx.contains("Assets:LedgerX:USD  100.00 USD"));
+        assert!(tx.contains("Expenses:LedgerX:Fees  2.50 USD"));
+        assert!(!tx.contains("Income"));
+
+        // Buying back 15 closes the 10 and opens a long of 5
+        let buy = Event::Trade {
+            asset,
+            price: Price::from_str("600").unwrap(),
+            size: Quantity::Contracts(15),
+            fee: Price::ZERO,
+        };
+        let tx = exporter.transaction(date, &buy, None).unwrap().unwrap();
+        assert!(
+            tx.contains("Options  10 BTCM-300628P40000 {} @@ 60.00 USD"),
+            "{}",
+            tx
+        );
+        assert!(
+            tx.contains("Options  5 BTCM-300628P40000 {{30.00 USD}}"),
+            "{}",
+            tx
+        );
+        assert!(tx.contains("Assets:LedgerX:USD  -90.00 USD"), "{}", tx);
+        assert!(tx.ends_with("  Income:LedgerX:PnL\n"));
+
+        // ...which is assigned, ITM by $2000
+        let assign = Event::Assignment {
+            option,
+            underlying: Underlying::Btc,
+            contract_size: ContractSize::Mini,
+            size: Quantity::Contracts(-5),
+            price_ref: None,
+            fee: Price::from_str("1.25").unwrap(),
+        };
+        assert!(exporter.transaction(date, &assign, None).is_err());
+        let tx = exporter
+            .transaction(date, &assign, Some(Price::from_str("38000").unwrap()))
+            .unwrap()
+            .unwrap();
+        assert!(
+            tx.contains("Options  -5 BTCM-300628P40000 {} @@ 100.00 USD"),
+            "{}",
+            tx
+        );
+        assert!(tx.contains("Expenses:LedgerX:Fees  1.25 USD"), "{}", tx);
+        // ...delivering 0.05 BTC at $38000 for the strike of $2000
+        assert!(
+            tx.contains("Assets:LedgerX:BTC  -0.05000000 BTC {{1900.00 USD}}"),
+            "{}",
+            tx
+        );
+        assert!(tx.contains("Assets:LedgerX:USD  2000.00 USD"), "{}", tx);
+        assert_eq!(exporter.positions["BTCM-300628P40000"], 0);
+    }
+}
@@ -27,10 +27,12 @@
 //!        Be sure to delete the header line from the LX CSV.
 //!
 
-use crate::ledgerx::history::{tax::LotSelectionStrategy, LotId};
+use crate::ledgerx::history::{tax, tax::LotSelectionStrategy, LotId};
 use crate::units::{Price, UtcTime};
+use anyhow::Context;
 use serde::Deserialize;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{fmt, str::FromStr};
 
 /// The main configuration structure
 ///
@@ -55,6 +57,77 @@ pub struct Configuration {
     /// The software will complain if any necessary entries are missing, or if existing
     /// entries don't match the claimed TXID. So it's pretty hard to mess this one up.
     transactions: HashMap<bitcoin::Txid, String>,
+    /// Exports from wallet software, supplementing the raw transaction data
+    ///
+    /// These usually only identify individual outputs rather than full transactions,
+    /// so deposits found this way are always treated as a single lot.
+    #[serde(default)]
+    wallet_exports: Vec<crate::transaction::WalletExport>,
+    /// Addresses of our own wallets
+    ///
+    /// BTC withdrawals to these addresses, and deposits funded from them, are
+    /// transfers between our own venues rather than disposals and new lots. The
+    /// withdrawn lots keep their identity, basis and date when redeposited.
+    #[serde(default)]
+    internal_addresses: Vec<String>,
+    /// The lots which leave the account with each BTC withdrawal to an outside
+    /// address, keyed by the time LX reports for the withdrawal
+    ///
+    /// Lots are specifically identified, rather than chosen by the year's lot
+    /// selection strategy, and are taken in the order listed; the last may be
+    /// only partly withdrawn. Withdrawals with no entry leave our lots alone.
+    #[serde(default)]
+    withdrawal_lots: BTreeMap<String, Vec<LotId>>,
+    /// Thresholds below which the remainders of partly-closed BTC and ETH
+    /// lots are merged into other lots, rather than left as lots of their own
+    #[serde(default)]
+    dust: tax::DustSettings,
+    /// How to choose the BTC price used to compute assignment gains/losses
+    #[serde(default)]
+    assignment_price_policy: AssignmentPricePolicy,
+    /// Manual overrides for the assignment BTC price, keyed by expiry date (YYYY-MM-DD)
+    ///
+    /// Prices are given in cents, as with lot prices. An override takes precedence
+    /// over any other price source regardless of policy.
+    #[serde(default)]
+    assignment_price_overrides: BTreeMap<String, i64>,
+    /// ETH prices used to compute ETH option assignments, in cents, keyed by
+    /// expiry date (YYYY-MM-DD)
+    ///
+    /// LX's price references are for BTC and we have no historic ETH price data,
+    /// so every ETH assignment needs an entry here.
+    #[serde(default)]
+    eth_assignment_prices: BTreeMap<String, i64>,
+    /// Fees charged on assignment, in cents, keyed by LX contract label
+    ///
+    /// LX does not report these anywhere but in balance changes, so they may be
+    /// inferred from the balance around settlement and entered here. A fee given
+    /// here takes precedence over one in the account activity export.
+    #[serde(default)]
+    assignment_fees: BTreeMap<String, i64>,
+    /// LX's "account activity" CSV export (including its header), crammed into a
+    /// JSON string array, used to backfill events the API no longer returns
+    #[serde(default)]
+    account_activity: Vec<String>,
+    /// Which source to use when the API and the account activity export both
+    /// contain the same record
+    #[serde(default)]
+    account_activity_preference: SourcePreference,
+    /// Capital losses carried forward into the first configured year from
+    /// before the tracked period
+    #[serde(default)]
+    initial_carryforward: tax::Carryforward,
+    /// If present, the tax output directory is packed into an encrypted archive,
+    /// with secrets redacted from the copied logs
+    #[serde(default)]
+    bundle: Option<crate::bundle::Settings>,
+    /// Account names to use when exporting the history in Beancount format
+    #[serde(default)]
+    beancount_accounts: crate::ledgerx::history::beancount::Accounts,
+    /// Sign conventions for trade fees, for years in which LX reported them
+    /// differently from the default
+    #[serde(default)]
+    fee_sign_overrides: BTreeMap<i32, FeeSign>,
 }
 
 impl Configuration {
@@ -80,9 +153,251 @@ impl Configuration {
     /// (Attempts to) construct a transaction database from the tx map
     ///
     /// Will fail if any of the raw transactions fail to parse, or if their
-    /// TXIDs don't match the expected one.
+    /// TXIDs don't match the expected one, or if any wallet export is malformed.
     pub fn transaction_db(&self) -> anyhow::Result<crate::transaction::Database> {
-        crate::transaction::Database::from_string_map(&self.transactions)
+        let mut db = crate::transaction::Database::from_string_map(&self.transactions)?;
+        for (n, export) in self.wallet_exports.iter().enumerate() {
+            db.import_wallet_export(export)
+                .with_context(|| format!("importing wallet export {n}"))?;
+        }
+        Ok(db)
+    }
+
+    /// (Attempts to) parse the internal addresses into the scripts they pay to
+    pub fn internal_scripts(&self) -> anyhow::Result<HashSet<bitcoin::ScriptBuf>> {
+        self.internal_addresses
+            .iter()
+            .map(|addr| {
+                let parsed = bitcoin::Address::from_str(addr)
+                    .with_context(|| format!("parsing internal address {addr}"))?
+                    .require_network(bitcoin::Network::Bitcoin)
+                    .with_context(|| format!("parsing internal address as BTC address {addr}"))?;
+                Ok(parsed.script_pubkey())
+            })
+            .collect()
+    }
+
+    /// (Attempts to) construct a map of the lots leaving with each BTC withdrawal
+    ///
+    /// Will fail if any key is not a timestamp.
+    pub fn withdrawal_lots(&self) -> anyhow::Result<HashMap<UtcTime, Vec<LotId>>> {
+        self.withdrawal_lots
+            .iter()
+            .map(|(time, ids)| {
+                let parsed = crate::units::parse_lx_time(time)
+                    .with_context(|| format!("parsing withdrawal time {time}"))?;
+                Ok((parsed, ids.clone()))
+            })
+            .collect()
+    }
+
+    /// Accessor for the lines of the account activity export
+    pub fn account_activity(&self) -> &[String] {
+        &self.account_activity
+    }
+
+    /// Accessor for the preferred source of duplicated records
+    pub fn account_activity_preference(&self) -> SourcePreference {
+        self.account_activity_preference
+    }
+
+    /// Accessor for the dust thresholds
+    pub fn dust(&self) -> tax::DustSettings {
+        self.dust
+    }
+
+    /// Accessor for the carryforward entering the first configured year
+    pub fn initial_carryforward(&self) -> tax::Carryforward {
+        self.initial_carryforward
+    }
+
+    /// Accessor for the encrypted output bundle settings
+    pub fn bundle(&self) -> Option<&crate::bundle::Settings> {
+        self.bundle.as_ref()
+    }
+
+    /// Accessor for the Beancount export account names
+    pub fn beancount_accounts(&self) -> &crate::ledgerx::history::beancount::Accounts {
+        &self.beancount_accounts
+    }
+
+    /// Accessor for the per-year fee sign overrides
+    pub fn fee_sign_overrides(&self) -> &BTreeMap<i32, FeeSign> {
+        &self.fee_sign_overrides
+    }
+
+    /// Accessor for the assignment price policy
+    pub fn assignment_price_policy(&self) -> AssignmentPricePolicy {
+        self.assignment_price_policy
+    }
+
+    /// Map of assignment fees, keyed by LX contract label
+    pub fn assignment_fees(&self) -> HashMap<String, Price> {
+        self.assignment_fees
+            .iter()
+            .map(|(label, cents)| (label.clone(), Price::from_cents(*cents)))
+            .collect()
+    }
+
+    /// (Attempts to) construct a map of manual assignment price overrides
+    ///
+    /// The keys are normalized to `%F` format. Will fail if any key is not a
+    /// valid date.
+    pub fn assignment_price_overrides(&self) -> anyhow::Result<HashMap<String, Price>> {
+        prices_by_date(
+            &self.assignment_price_overrides,
+            "assignment price override",
+        )
+    }
+
+    /// (Attempts to) construct a map of ETH prices for assignments
+    ///
+    /// As with [Self::assignment_price_overrides], the keys are normalized to
+    /// `%F` format.
+    pub fn eth_assignment_prices(&self) -> anyhow::Result<HashMap<String, Price>> {
+        prices_by_date(&self.eth_assignment_prices, "ETH assignment price")
+    }
+}
+
+/// Parses a map of dates to prices in cents, normalizing the dates to `%F` format
+fn prices_by_date(
+    map: &BTreeMap<String, i64>,
+    what: &str,
+) -> anyhow::Result<HashMap<String, Price>> {
+    let mut ret = HashMap::with_capacity(map.len());
+    for (date, cents) in map {
+        let parsed = chrono::NaiveDate::parse_from_str(date, "%F")
+            .map_err(|e| anyhow::Error::msg(format!("bad {what} date {date}: {e}")))?;
+        ret.insert(
+            parsed.format("%F").to_string(),
+            Price::from(rust_decimal::Decimal::new(*cents, 2)),
+        );
+    }
+    Ok(ret)
+}
+
+/// Policy for choosing the BTC price reference used to compute assignments
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Deserialize, Debug, Default)]
+pub enum AssignmentPricePolicy {
+    /// Only accept LX's official price reference (or a manual override); error otherwise
+    #[serde(rename = "strict")]
+    Strict,
+    /// Use LX's price reference, falling back to our historic price data with a warning
+    #[default]
+    #[serde(rename = "prefer-lx")]
+    PreferLx,
+    /// Use our historic price data, ignoring LX's price reference
+    #[serde(rename = "prefer-historic")]
+    PreferHistoric,
+}
+
+impl fmt::Display for AssignmentPricePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssignmentPricePolicy::Strict => f.write_str("strict"),
+            AssignmentPricePolicy::PreferLx => f.write_str("prefer-lx"),
+            AssignmentPricePolicy::PreferHistoric => f.write_str("prefer-historic"),
+        }
+    }
+}
+
+/// How LX reported the sign of the fee on each trade
+///
+/// Takers pay a fee and makers may receive a rebate, so the fee field is
+/// signed either way; what differs is which sign means a charge.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Deserialize, Debug, Default)]
+pub enum FeeSign {
+    /// Fees charged to us are positive and rebates are negative
+    #[default]
+    #[serde(rename = "charge-positive")]
+    ChargePositive,
+    /// Fees charged to us are negative (debits to our account) and rebates
+    /// are positive
+    #[serde(rename = "charge-negative")]
+    ChargeNegative,
+}
+
+impl FeeSign {
+    /// Converts a fee as reported by LX to the amount charged to us, which is
+    /// negative for a rebate
+    pub fn charge(self, raw: Price) -> Price {
+        match self {
+            FeeSign::ChargePositive => raw,
+            FeeSign::ChargeNegative => -raw,
+        }
+    }
+
+    /// Converts an amount charged to us back to the fee as reported by LX
+    pub fn raw(self, charge: Price) -> Price {
+        // The conversion is its own inverse
+        self.charge(charge)
+    }
+}
+
+impl fmt::Display for FeeSign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FeeSign::ChargePositive => f.write_str("charge-positive"),
+            FeeSign::ChargeNegative => f.write_str("charge-negative"),
+        }
+    }
+}
+
+/// Which source of account data to trust when two sources have the same record
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Deserialize, Debug, Default)]
+pub enum SourcePreference {
+    /// Keep the record from the LX API, ignoring the one from the export
+    #[default]
+    #[serde(rename = "prefer-api")]
+    PreferApi,
+    /// Replace the record from the LX API with the one from the export
+    #[serde(rename = "prefer-export")]
+    PreferExport,
+}
+
+impl fmt::Display for SourcePreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourcePreference::PreferApi => f.write_str("prefer-api"),
+            SourcePreference::PreferExport => f.write_str("prefer-export"),
+        }
+    }
+}
+
+/// How the coins in a lot were acquired
+///
+/// This determines which of the fields of [`LotInfo`] give the basis and the
+/// start of the holding period.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Deserialize, Debug, Default)]
+pub enum AcquisitionType {
+    /// Bought; basis is the purchase price and holding starts on the purchase date
+    #[default]
+    #[serde(rename = "purchase")]
+    Purchase,
+    /// Gifted, using the donor's basis and (tacked-on) holding period
+    #[serde(rename = "gift-carryover")]
+    GiftCarryover,
+    /// Gifted when the market value was below the donor's basis, so that the basis
+    /// depends on the sale price: above the donor's basis, it is the donor's basis
+    /// with the donor's holding period; below the market value at receipt, it is
+    /// that market value with holding starting on receipt; and in between, there
+    /// is no gain or loss.
+    #[serde(rename = "gift-fmv")]
+    GiftFmv,
+    /// Received as income (e.g. mined); basis is the market value at receipt, as
+    /// reported as income, and holding starts on receipt
+    #[serde(rename = "income")]
+    Income,
+}
+
+impl fmt::Display for AcquisitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AcquisitionType::Purchase => f.write_str("purchase"),
+            AcquisitionType::GiftCarryover => f.write_str("gift (carryover basis)"),
+            AcquisitionType::GiftFmv => f.write_str("gift (dual basis)"),
+            AcquisitionType::Income => f.write_str("income"),
+        }
     }
 }
 
@@ -94,9 +409,91 @@ pub struct LotInfo {
     /// NOT the basis of the lot. You need to multiply this price by the quantity
     /// (not included in the lot information; comes from the transaction data)
     /// to get the basis.
+    ///
+    /// For gifts this is the donor's price, and for income it is the market price
+    /// at receipt.
     #[serde(deserialize_with = "crate::units::deserialize_cents")]
     pub price: Price,
     /// The ID of the lot in question
+    ///
+    /// For gifts this is the date that the donor acquired the coins.
     #[serde(with = "crate::units::serde_ts_seconds")]
     pub date: UtcTime,
+    /// How the coins were acquired
+    #[serde(default)]
+    pub acquisition: AcquisitionType,
+    /// For gifts, the date that we received the coins
+    #[serde(
+        default,
+        deserialize_with = "crate::units::serde_ts_seconds::deserialize_opt"
+    )]
+    pub received_date: Option<UtcTime>,
+    /// For gifts, the market price of bitcoin when we received the coins
+    #[serde(default, deserialize_with = "crate::units::deserialize_cents_opt")]
+    pub fmv_price: Option<Price>,
+}
+
+impl LotInfo {
+    /// The market price at receipt of a gift-fmv lot, checking that it is below
+    /// the donor's price
+    fn gift_fmv_price(&self) -> anyhow::Result<Price> {
+        let fmv = self
+            .fmv_price
+            .with_context(|| format!("gift-fmv lot (date {}) has no fmv_price", self.date))?;
+        if fmv >= self.price {
+            return Err(anyhow::Error::msg(format!(
+                "gift-fmv lot (date {}) has market price {fmv} at receipt which is not \
+                 below donor's price {}; use gift-carryover instead",
+                self.date, self.price,
+            )));
+        }
+        Ok(fmv)
+    }
+
+    /// The unit price to use as the basis of the lot, according to its acquisition type
+    ///
+    /// For gift-fmv lots this depends on the unit price the coins are sold at; if
+    /// `sale_price` is `None`, the donor's price is returned.
+    pub fn basis_price(&self, sale_price: Option<Price>) -> anyhow::Result<Price> {
+        match self.acquisition {
+            AcquisitionType::Purchase
+            | AcquisitionType::GiftCarryover
+            | AcquisitionType::Income => Ok(self.price),
+            AcquisitionType::GiftFmv => {
+                let fmv = self.gift_fmv_price()?;
+                Ok(match sale_price {
+                    Some(sale) if sale < fmv => fmv,
+                    Some(sale) if sale < self.price => sale,
+                    _ => self.price,
+                })
+            }
+        }
+    }
+
+    /// The start of the holding period of the lot, according to its acquisition type
+    ///
+    /// As with [`LotInfo::basis_price`], for gift-fmv lots this depends on the unit
+    /// price the coins are sold at.
+    pub fn holding_start(&self, sale_price: Option<Price>) -> anyhow::Result<UtcTime> {
+        match self.acquisition {
+            AcquisitionType::Purchase
+            | AcquisitionType::GiftCarryover
+            | AcquisitionType::Income => Ok(self.date),
+            AcquisitionType::GiftFmv => {
+                let fmv = self.gift_fmv_price()?;
+                let received = self.received_date.with_context(|| {
+                    format!("gift-fmv lot (date {}) has no received_date", self.date)
+                })?;
+                Ok(match sale_price {
+                    Some(sale) if sale < fmv => received,
+                    _ => self.date,
+                })
+            }
+        }
+    }
+
+    /// Whether the lot needs to be specially flagged in the tax output
+    pub fn is_special(&self) -> bool {
+        self.acquisition != AcquisitionType::Purchase || self.price == Price::ZERO
+    }
 }
@@ -0,0 +1,430 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Tax Run Diff
+//!
+//! Compares the output directories of two `tax-history` runs, so that the
+//! effect of a configuration change can be audited before anything is
+//! refiled. The totals are compared field by field from each run's
+//! `summary.json`, the metadata line by line (ignoring the time of the run),
+//! and the rows of each CSV file are matched up by their lot ID and date
+//! columns and compared field by field.
+//!
+
+use anyhow::Context;
+use log::info;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Prefix of the metadata line which records when the run was started
+const STARTED_ON: &str = "Started on: ";
+
+/// A single field which differs between two runs
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FieldChange {
+    /// Name of the field
+    pub field: String,
+    /// Value in the first run
+    pub old: String,
+    /// Value in the second run
+    pub new: String,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
+
+/// A difference between the rows of two CSV files
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RowChange {
+    /// A row only in the second run
+    Added(String),
+    /// A row only in the first run
+    Removed(String),
+    /// A row in both runs, some of whose fields differ
+    Changed {
+        /// The lot ID and date fields which identify the row
+        key: String,
+        /// The fields which differ
+        fields: Vec<FieldChange>,
+    },
+}
+
+impl fmt::Display for RowChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RowChange::Added(ref row) => write!(f, "+ {row}"),
+            RowChange::Removed(ref row) => write!(f, "- {row}"),
+            RowChange::Changed {
+                ref key,
+                ref fields,
+            } => {
+                write!(f, "~ {key}")?;
+                for (n, field) in fields.iter().enumerate() {
+                    f.write_str(if n == 0 { ": " } else { "; " })?;
+                    fmt::Display::fmt(field, f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The rows of a CSV file, keyed by their lot ID and date fields
+struct Rows<'s> {
+    header: Vec<String>,
+    rows: Vec<(String, &'s str, Vec<String>)>,
+}
+
+impl<'s> Rows<'s> {
+    fn new(data: &'s str) -> Self {
+        let mut lines = data.lines();
+        let header = lines.next().map(crate::csv::split_line).unwrap_or_default();
+        let key_columns: Vec<usize> = header
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| *name == "Lot ID" || name.to_lowercase().contains("date"))
+            .map(|(idx, _)| idx)
+            .collect();
+
+        // Rows with the same key are matched up in order of appearance
+        let mut seen = HashMap::<String, usize>::new();
+        let mut rows = vec![];
+        for line in lines.filter(|line| !line.is_empty()) {
+            let fields = crate::csv::split_line(line);
+            let mut key = if key_columns.is_empty() {
+                line.to_owned()
+            } else {
+                key_columns
+                    .iter()
+                    .map(|&idx| {
+                        let value = fields.get(idx).map(String::as_str).unwrap_or("");
+                        format!("{} {}", header[idx], value)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let count = seen.entry(key.clone()).or_default();
+            *count += 1;
+            if *count > 1 {
+                key = format!("{key} (#{count})");
+            }
+            rows.push((key, line, fields));
+        }
+        Rows { header, rows }
+    }
+
+    /// The value of a named field of a row
+    fn field<'a>(&self, fields: &'a [String], name: &str) -> &'a str {
+        self.header
+            .iter()
+            .position(|col| col == name)
+            .and_then(|idx| fields.get(idx))
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+}
+
+/// Compares the contents of two CSV files
+pub fn diff_csv(old: &str, new: &str) -> Vec<RowChange> {
+    let old = Rows::new(old);
+    let new = Rows::new(new);
+    let mut columns = new.header.clone();
+    for col in &old.header {
+        if !columns.contains(col) {
+            columns.push(col.clone());
+        }
+    }
+
+    let old_by_key: HashMap<&str, (&str, &[String])> = old
+        .rows
+        .iter()
+        .map(|(key, line, fields)| (key.as_str(), (*line, &fields[..])))
+        .collect();
+    let new_keys: BTreeSet<&str> = new.rows.iter().map(|(key, ..)| key.as_str()).collect();
+
+    let mut ret = vec![];
+    for (key, line, fields) in &new.rows {
+        match old_by_key.get(key.as_str()) {
+            None => ret.push(RowChange::Added((*line).to_owned())),
+            Some((old_line, _)) if old_line == line => {}
+            Some((_, old_fields)) => {
+                let fields = columns
+                    .iter()
+                    .filter_map(|col| {
+                        let (old, new) = (old.field(old_fields, col), new.field(fields, col));
+                        if old == new {
+                            None
+                        } else {
+                            Some(FieldChange {
+                                field: col.clone(),
+                                old: old.to_owned(),
+                                new: new.to_owned(),
+                            })
+                        }
+                    })
+                    .collect();
+                ret.push(RowChange::Changed {
+                    key: key.clone(),
+                    fields,
+                });
+            }
+        }
+    }
+    for (key, line, _) in &old.rows {
+        if !new_keys.contains(key.as_str()) {
+            ret.push(RowChange::Removed((*line).to_owned()));
+        }
+    }
+    ret
+}
+
+/// Compares two JSON values, recording each leaf which differs
+pub fn diff_json(
+    path: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    ret: &mut Vec<FieldChange>,
+) {
+    use serde_json::Value;
+
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let missing = Value::Null;
+                diff_json(
+                    &child(key),
+                    old.get(key).unwrap_or(&missing),
+                    new.get(key).unwrap_or(&missing),
+                    ret,
+                );
+            }
+        }
+        _ if old != new => ret.push(FieldChange {
+            field: path.to_owned(),
+            old: old.to_string(),
+            new: new.to_string(),
+        }),
+        _ => {}
+    }
+}
+
+/// Compares two metadata files, line by line, ignoring the time of the run
+pub fn diff_metadata(old: &str, new: &str) -> Vec<RowChange> {
+    let lines = |data: &str| -> BTreeSet<String> {
+        data.lines()
+            .filter(|line| !line.starts_with(STARTED_ON) && !line.trim().is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+    let (old, new) = (lines(old), lines(new));
+    let mut ret: Vec<_> = new
+        .difference(&old)
+        .map(|line| RowChange::Added(line.clone()))
+        .collect();
+    ret.extend(
+        old.difference(&new)
+            .map(|line| RowChange::Removed(line.clone())),
+    );
+    ret
+}
+
+/// Reads a file of a run, returning `None` if it does not exist
+fn read_optional(path: &Path) -> anyhow::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Names of the files in a run directory which we compare
+fn run_files(dir: &Path) -> anyhow::Result<BTreeSet<String>> {
+    let mut ret = BTreeSet::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".csv") || name == "summary.json" || name == "metadata.txt" {
+            ret.insert(name);
+        }
+    }
+    Ok(ret)
+}
+
+/// Compares two tax run directories, logging the differences
+///
+/// Returns the total number of differences found.
+pub fn run(old_dir: &Path, new_dir: &Path) -> anyhow::Result<usize> {
+    info!(
+        "Comparing tax run {} against {}",
+        new_dir.display(),
+        old_dir.display()
+    );
+    let mut names = run_files(old_dir)?;
+    names.extend(run_files(new_dir)?);
+
+    let mut total = 0;
+    for name in names {
+        let old = read_optional(&old_dir.join(&name))?;
+        let new = read_optional(&new_dir.join(&name))?;
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(_), None) => {
+                info!("{}: only in {}", name, old_dir.display());
+                total += 1;
+                continue;
+            }
+            (None, Some(_)) => {
+                info!("{}: only in {}", name, new_dir.display());
+                total += 1;
+                continue;
+            }
+            (None, None) => unreachable!("listed files exist in one directory"),
+        };
+
+        if name == "summary.json" {
+            let parse = |data: &str| -> anyhow::Result<serde_json::Value> {
+                serde_json::from_str(data).context("parsing summary.json")
+            };
+            let mut changes = vec![];
+            diff_json("", &parse(&old)?, &parse(&new)?, &mut changes);
+            if changes.is_empty() {
+                info!("{}: no differences", name);
+            } else {
+                info!("{}: {} fields differ", name, changes.len());
+            }
+            for change in &changes {
+                info!("    {}", change);
+            }
+            total += changes.len();
+        } else {
+            let changes = if name == "metadata.txt" {
+                diff_metadata(&old, &new)
+            } else {
+                diff_csv(&old, &new)
+            };
+            if changes.is_empty() {
+                info!("{}: no differences", name);
+                continue;
+            }
+            let count = |f: fn(&RowChange) -> bool| changes.iter().filter(|ch| f(ch)).count();
+            info!(
+                "{}: {} added, {} removed, {} changed",
+                name,
+                count(|ch| matches!(ch, RowChange::Added(..))),
+                count(|ch| matches!(ch, RowChange::Removed(..))),
+                count(|ch| matches!(ch, RowChange::Changed { .. })),
+            );
+            for change in &changes {
+                info!("    {}", change);
+            }
+            total += changes.len();
+        }
+    }
+    info!("{} differences in total.", total);
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let old = "Event,Date,Quantity,Lot ID,Proceeds\n\
+                   Buy Back,2023-01-06T21:00:00Z,5,lx-opt-1,10.00\n\
+                   Sell,2023-02-01T12:00:00Z,1,lx-btc-1,\"1,000.00\"\n\
+                   Sell,2023-02-01T12:00:00Z,1,lx-btc-1,2.00\n\
+                   Expired,2023-03-31T21:00:00Z,2,lx-opt-2,0.00\n";
+        let new = "Event,Date,Quantity,Lot ID,Proceeds\n\
+                   Buy Back,2023-01-06T21:00:00Z,5,lx-opt-1,10.00\n\
+                   Sell,2023-02-01T12:00:00Z,1,lx-btc-1,\"1,000.00\"\n\
+                   Sell,2023-02-01T12:00:00Z,1,lx-btc-1,3.00\n\
+                   Sell,2023-04-01T12:00:00Z,1,lx-btc-7,4.00\n";
+        let changes = diff_csv(old, new);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes[0],
+            RowChange::Changed {
+                key: "Date 2023-02-01T12:00:00Z, Lot ID lx-btc-1 (#2)".into(),
+                fields: vec![FieldChange {
+                    field: "Proceeds".into(),
+                    old: "2.00".into(),
+                    new: "3.00".into(),
+                }],
+            },
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "~ Date 2023-02-01T12:00:00Z, Lot ID lx-btc-1 (#2): Proceeds: 2.00 -> 3.00"
+        );
+        assert_eq!(
+            changes[1],
+            RowChange::Added("Sell,2023-04-01T12:00:00Z,1,lx-btc-7,4.00".into())
+        );
+        assert_eq!(
+            changes[2],
+            RowChange::Removed("Expired,2023-03-31T21:00:00Z,2,lx-opt-2,0.00".into())
+        );
+        assert!(diff_csv(old, old).is_empty());
+    }
+
+    #[test]
+    fn summary_and_metadata() {
+        let old = serde_json::json!({
+            "config_hash": "aa",
+            "years": { "2023": { "short_term": { "gain_loss": "1.00" } } },
+        });
+        let new = serde_json::json!({
+            "config_hash": "bb",
+            "years": {
+                "2023": { "short_term": { "gain_loss": "2.00" } },
+                "2024": {},
+            },
+        });
+        let mut changes = vec![];
+        diff_json("", &old, &new, &mut changes);
+        let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                "config_hash: \"aa\" -> \"bb\"",
+                "years.2023.short_term.gain_loss: \"1.00\" -> \"2.00\"",
+                "years.2024: null -> {}",
+            ],
+        );
+
+        let old = "Started on: 2024-01-01 00:00:00 UTC\nYear: 2023\n    Total: 1\n";
+        let new = "Started on: 2024-02-01 00:00:00 UTC\nYear: 2023\n    Total: 2\n";
+        assert_eq!(
+            diff_metadata(old, new),
+            [
+                RowChange::Added("    Total: 2".into()),
+                RowChange::Removed("    Total: 1".into()),
+            ],
+        );
+    }
+}
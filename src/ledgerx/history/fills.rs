@@ -0,0 +1,174 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Logical Fills
+//!
+//! A single resting order which is filled against several counterparties
+//! shows up as several trades, seconds apart and at the same price. In the
+//! budget and history views these inflate the event counts and clutter the
+//! output, so they can optionally be merged into a single logical fill. The
+//! raw executions are kept alongside the merged event, so that the output
+//! can say where it came from.
+//!
+//! The tax path never merges: lots are opened and closed by the raw trades,
+//! exactly as LX reports them.
+//!
+
+use super::Event;
+use crate::units::UtcTime;
+
+/// An event in the merged view of the history
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry<'a> {
+    /// Time of the event; for a merged fill, the time of its first execution
+    pub date: UtcTime,
+    /// The event, which may be a merged fill
+    pub event: Event,
+    /// For a merged fill, the raw trades it was merged from; otherwise empty
+    pub executions: Vec<(UtcTime, &'a Event)>,
+}
+
+/// Whether a trade can be merged into a logical fill started by another
+///
+/// Trades merge if they are of the same asset, on the same side, at the same
+/// price, and within `window` of the start of the fill.
+fn mergeable(fill: (UtcTime, &Event), next: (UtcTime, &Event), window: chrono::Duration) -> bool {
+    match (fill.1, next.1) {
+        (
+            Event::Trade {
+                asset, price, size, ..
+            },
+            Event::Trade {
+                asset: next_asset,
+                price: next_price,
+                size: next_size,
+                ..
+            },
+        ) => {
+            asset == next_asset
+                && price == next_price
+                && size.is_negative() == next_size.is_negative()
+                && next.0 - fill.0 <= window
+        }
+        _ => false,
+    }
+}
+
+/// Merges consecutive trades which are executions of the same order
+///
+/// If `window` is `None`, every event is passed through unchanged.
+pub fn merge<'a, I>(events: I, window: Option<chrono::Duration>) -> Vec<Entry<'a>>
+where
+    I: IntoIterator<Item = (UtcTime, &'a Event)>,
+{
+    let mut groups: Vec<Vec<(UtcTime, &'a Event)>> = vec![];
+    for (date, event) in events {
+        if let (Some(window), Some(group)) = (window, groups.last_mut()) {
+            if mergeable(group[0], (date, event), window) {
+                group.push((date, event));
+                continue;
+            }
+        }
+        groups.push(vec![(date, event)]);
+    }
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let (date, first) = group[0];
+            let mut event = first.clone();
+            if group.len() == 1 {
+                return Entry {
+                    date,
+                    event,
+                    executions: vec![],
+                };
+            }
+            for (_, next) in &group[1..] {
+                if let (
+                    Event::Trade { size, fee, .. },
+                    Event::Trade {
+                        size: next_size,
+                        fee: next_fee,
+                        ..
+                    },
+                ) = (&mut event, next)
+                {
+                    *size += *next_size;
+                    *fee += *next_fee;
+                }
+            }
+            Entry {
+                date,
+                event,
+                executions: group,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{ContractSize, Price, Quantity, TaxAsset, Underlying};
+    use std::str::FromStr;
+
+    #[test]
+    fn merge_split_fills() {
+        let time = |s: &str| UtcTime::parse_coinbase(&format!("2024-03-01T15:{s}Z")).unwrap();
+        let asset = TaxAsset::Option {
+            underlying: Underlying::Btc,
+            option: crate::option::Option::from_str("2024-03-29P50000").unwrap(),
+            contract_size: ContractSize::Mini,
+        };
+        let trade = |price: &str, n: i64| Event::Trade {
+            asset,
+            price: Price::from_str(price).unwrap(),
+            size: Quantity::Contracts(n),
+            fee: Price::from_cents(25 * n.abs()),
+        };
+        let events = [
+            (time("00:00"), trade("1250", -3)),
+            (time("00:02"), trade("1250", -5)),
+            (time("00:09"), trade("1250", -1)),
+            // Outside the window from the start of the fill
+            (time("00:20"), trade("1250", -2)),
+            // Different side
+            (time("00:21"), trade("1250", 2)),
+            // Different price
+            (time("00:22"), trade("1260", 2)),
+            (
+                time("00:23"),
+                Event::UsdDeposit {
+                    amount: Quantity::Cents(100),
+                    status: None,
+                    reversal: None,
+                },
+            ),
+        ];
+        let iter = || events.iter().map(|(date, event)| (*date, event));
+
+        let unmerged = merge(iter(), None);
+        assert_eq!(unmerged.len(), events.len());
+        assert!(unmerged.iter().all(|entry| entry.executions.is_empty()));
+
+        let merged = merge(iter(), Some(chrono::Duration::seconds(10)));
+        assert_eq!(merged.len(), 5);
+        assert_eq!(merged[0].date, time("00:00"));
+        assert_eq!(merged[0].event, trade("1250", -9));
+        assert_eq!(merged[0].executions.len(), 3);
+        assert_eq!(merged[0].executions[2], (time("00:09"), &events[2].1));
+        assert!(merged[1..].iter().all(|entry| entry.executions.is_empty()));
+    }
+}
@@ -0,0 +1,166 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Lifetime Ledger
+//!
+//! The per-year reports written by `tax-history` each stand alone. This module
+//! writes a single ledger of every close across all years, in date order, with
+//! running totals of the realized gain/loss of each type. The last line gives
+//! the lifetime realized P&L, and any year's totals can be traced back to the
+//! individual events which make it up.
+//!
+
+use super::lot::Close;
+use super::tax::{self, GainType};
+use crate::csv::CsvPrinter;
+use crate::units::Price;
+use std::fmt;
+
+/// Header line of the ledger
+pub const HEADER: &str = "Year,Date,Event,Quantity,Asset,Lot ID,Date Acquired,\
+    Proceeds,Basis,Gain/Loss,Gain/Loss Type,\
+    Cumulative ST,Cumulative LT,Cumulative 1256,Cumulative Total";
+
+/// Running totals of realized gain/loss
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Totals {
+    /// Short-term gain/loss
+    pub short_term: Price,
+    /// Long-term gain/loss
+    pub long_term: Price,
+    /// Section 1256 gain/loss
+    pub section_1256: Price,
+}
+
+impl Totals {
+    /// Adds a close to the totals
+    fn add(&mut self, close: &Close) {
+        match close.gain_loss_type() {
+            GainType::ShortTerm => self.short_term += close.gain_loss(),
+            GainType::LongTerm => self.long_term += close.gain_loss(),
+            GainType::Option1256 => self.section_1256 += close.gain_loss(),
+        }
+    }
+
+    /// Total gain/loss of all types
+    pub fn total(&self) -> Price {
+        self.short_term + self.long_term + self.section_1256
+    }
+}
+
+/// Writes the ledger of all closes among some tax events
+///
+/// Returns the totals after the final close.
+pub fn write<'a, W: fmt::Write, I: IntoIterator<Item = &'a tax::Event>>(
+    w: &mut W,
+    events: I,
+) -> Result<Totals, fmt::Error> {
+    writeln!(w, "{HEADER}")?;
+    let mut totals = Totals::default();
+    for event in events {
+        let close = match event.open_close {
+            tax::OpenClose::Open(..) => continue,
+            tax::OpenClose::Close(ref close) => close,
+        };
+        totals.add(close);
+        writeln!(
+            w,
+            "{}",
+            CsvPrinter((
+                close.close_date().year(),
+                close.close_date(),
+                close.ty(),
+                close.quantity(),
+                event.asset,
+                close.open_id(),
+                close.open_date(),
+                close.proceeds(),
+                close.basis(),
+                close.gain_loss(),
+                close.gain_loss_type(),
+                totals.short_term,
+                totals.long_term,
+                totals.section_1256,
+                totals.total(),
+            ))
+        )?;
+    }
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::lot::{CloseType, Lot, OpenType};
+    use super::*;
+    use crate::units::{Quantity, TaxAsset, UtcTime};
+    use std::str::FromStr;
+
+    #[test]
+    fn ledger() {
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let date = |s: &str| tax::TaxDate::from(UtcTime::parse_coinbase(s).unwrap());
+        let btc = Quantity::from(bitcoin::Amount::from_btc(0.5).unwrap());
+
+        let mut events = vec![];
+        let mut push = |open: &str, close: &str, open_price: &str, close_price: &str| {
+            let lot = Lot::new(
+                TaxAsset::Bitcoin,
+                btc,
+                p(open_price),
+                date(open),
+                OpenType::BuyToOpen,
+            );
+            events.push(tax::Event {
+                date: date(open),
+                asset: TaxAsset::Bitcoin,
+                open_close: tax::OpenClose::Open(lot.clone()),
+            });
+            let (close_event, _) = lot
+                .close(-btc, p(close_price), date(close), CloseType::Sell, None)
+                .unwrap();
+            events.push(tax::Event {
+                date: date(close),
+                asset: TaxAsset::Bitcoin,
+                open_close: tax::OpenClose::Close(close_event),
+            });
+        };
+        // Short-term gain of 1000, then long-term loss of 500 the next year
+        push(
+            "2022-01-03T00:00:00Z",
+            "2022-06-01T00:00:00Z",
+            "40000",
+            "42000",
+        );
+        push(
+            "2022-01-03T00:00:00Z",
+            "2023-06-01T00:00:00Z",
+            "30000",
+            "29000",
+        );
+
+        let mut out = String::new();
+        let totals = write(&mut out, &events).unwrap();
+        assert_eq!(totals.short_term, p("1000"));
+        assert_eq!(totals.long_term, p("-500"));
+        assert_eq!(totals.section_1256, Price::ZERO);
+        assert_eq!(totals.total(), p("500"));
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], HEADER);
+        assert!(lines[1].starts_with("2022,2022-06-01T00:00:00Z,Sell,"));
+        assert!(lines[2].starts_with("2023,2023-06-01T00:00:00Z,Sell,"));
+        assert!(lines[2].ends_with(",-500.00,Long-term,1000.00,-500.00,0.00,500.00"));
+    }
+}
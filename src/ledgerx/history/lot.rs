@@ -18,7 +18,9 @@
 //!
 
 use crate::csv;
+use crate::ledgerx::history::config::{AcquisitionType, LotInfo};
 use crate::ledgerx::history::tax::{GainType, TaxDate};
+use crate::ledgerx::rules::{CsvLayout, Rules};
 use crate::option::{Call, Put};
 use crate::units::{Price, Quantity, TaxAsset, TaxAsset2022, UtcTime};
 use rust_decimal::Decimal;
@@ -60,6 +62,12 @@ impl Id {
         Id(format!("lx-btc-{idx:04}"))
     }
 
+    /// Constructor for the next LX-generated ETH lot ID
+    fn next_eth() -> Id {
+        let idx = LOT_INDEX.fetch_add(1, Ordering::SeqCst);
+        Id(format!("lx-eth-{idx:04}"))
+    }
+
     /// Constructor for the next LX-generated BTC option ID
     fn next_opt() -> Id {
         let idx = LOT_INDEX.fetch_add(1, Ordering::SeqCst);
@@ -76,6 +84,37 @@ impl Id {
         let short_txid = outpoint.txid.to_string();
         Id(format!("{:.8}-{:02}", short_txid, outpoint.vout))
     }
+
+    /// Constructor for a lot ID that comes from an ETH deposit
+    ///
+    /// ETH deposits have no outpoint to identify them, so are instead
+    /// identified by the time LX reports for them, to the second.
+    pub fn from_eth_deposit(created_at: UtcTime) -> Id {
+        Id(format!("eth-{}", created_at.format("%Y%m%d%H%M%S")))
+    }
+}
+
+/// The option assignment which caused a synthetic BTC trade
+///
+/// Synthetic trades have no record of their own in the LX history, so the
+/// lots and closes they produce refer back to the option close behind them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Synthetic {
+    /// Whether the assigned option was a put or a call
+    pub pc: crate::option::PutCall,
+    /// ID of the option lot which was assigned
+    pub parent_id: Id,
+    /// Date of the assignment
+    pub parent_date: TaxDate,
+}
+
+impl csv::PrintCsv for Synthetic {
+    fn print(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("assignment of ")?;
+        self.parent_id.print(f)?;
+        f.write_str(" at ")?;
+        self.parent_date.print(f)
+    }
 }
 
 /// Tax Lot
@@ -88,6 +127,16 @@ pub struct Lot {
     date: TaxDate,
     open_ty: OpenType,
     sort_date: UtcTime,
+    /// For lots opened by a synthetic trade, the assignment behind it
+    synthetic: Option<Synthetic>,
+    /// For lots which have absorbed dust, the exact total basis
+    ///
+    /// The unit price of a merged lot is only the rounded average, so we
+    /// keep the total separately to avoid losing basis to rounding.
+    total_basis: Option<Price>,
+    /// For gifts with a dual basis, the lot information from which the basis
+    /// and holding period are chosen when the lot is closed
+    dual_basis: Option<LotInfo>,
 }
 
 impl fmt::Display for Lot {
@@ -116,6 +165,7 @@ impl Lot {
         Lot {
             id: match asset {
                 TaxAsset::Bitcoin => Id::next_btc(),
+                TaxAsset::Ether => Id::next_eth(),
                 TaxAsset::NextDay { .. } => unreachable!(
                     "dayaheads should be converted to their underlying, and are not tracked as lots by themselves",
                 ),
@@ -127,25 +177,74 @@ impl Lot {
             date,
             open_ty,
             sort_date: date.bare_time(),
+            synthetic: None,
+            total_basis: None,
+            dual_basis: None,
         }
     }
 
+    /// Marks the lot as opened by a synthetic trade caused by an assignment
+    pub fn with_synthetic(self, synthetic: Option<Synthetic>) -> Lot {
+        Lot { synthetic, ..self }
+    }
+
     /// Directly constructs a lot from a deposit
+    ///
+    /// The basis and holding period are determined by the lot's acquisition type.
     pub fn from_deposit(
         outpoint: bitcoin::OutPoint,
-        price: Price,
         quantity: bitcoin::Amount,
-        date: UtcTime,
-    ) -> Lot {
-        Lot {
-            id: Id::from_outpoint(outpoint),
-            asset: TaxAsset::Bitcoin,
-            quantity: quantity.into(),
-            price,
+        info: &LotInfo,
+    ) -> anyhow::Result<Lot> {
+        Lot::from_coin_deposit(
+            Id::from_outpoint(outpoint),
+            TaxAsset::Bitcoin,
+            quantity.into(),
+            info,
+        )
+    }
+
+    /// Directly constructs a lot from an ETH deposit
+    ///
+    /// As with BTC deposits, the basis and holding period are determined by
+    /// the lot's acquisition type.
+    pub fn from_eth_deposit(
+        created_at: UtcTime,
+        quantity: Quantity,
+        info: &LotInfo,
+    ) -> anyhow::Result<Lot> {
+        Lot::from_coin_deposit(
+            Id::from_eth_deposit(created_at),
+            TaxAsset::Ether,
+            quantity,
+            info,
+        )
+    }
+
+    /// Constructs a lot from a deposit of some coin, with a given ID
+    fn from_coin_deposit(
+        id: Id,
+        asset: TaxAsset,
+        quantity: Quantity,
+        info: &LotInfo,
+    ) -> anyhow::Result<Lot> {
+        let date = info.holding_start(None)?;
+        Ok(Lot {
+            id,
+            asset,
+            quantity,
+            price: info.basis_price(None)?,
             date: date.into(),
-            open_ty: OpenType::Deposit,
+            open_ty: OpenType::Deposit(info.acquisition),
             sort_date: date + chrono::Duration::days(365 * 100),
-        }
+            synthetic: None,
+            total_basis: None,
+            dual_basis: if info.acquisition == AcquisitionType::GiftFmv {
+                Some(info.clone())
+            } else {
+                None
+            },
+        })
     }
 
     /// Accessor for the ID
@@ -188,6 +287,23 @@ impl Lot {
         self.quantity
     }
 
+    /// The basis of the lot
+    pub fn basis(&self) -> Price {
+        self.total_basis.unwrap_or(self.price * self.quantity)
+    }
+
+    /// Whether the basis of the lot depends on the price it is sold at
+    ///
+    /// Such lots cannot be merged with others.
+    pub fn has_dual_basis(&self) -> bool {
+        self.dual_basis.is_some()
+    }
+
+    /// Whether a sale of the lot on `date` would be a long-term disposal
+    pub fn is_long_term_at(&self, date: TaxDate) -> bool {
+        date - self.date > chrono::Duration::days(365)
+    }
+
     /// Consume the lot by closing it. If this is a partial close, return
     /// the reduced-size log.
     pub fn close(
@@ -196,7 +312,7 @@ impl Lot {
         price: Price,
         date: TaxDate,
         ty: CloseType,
-        synthetic: Option<crate::option::PutCall>,
+        synthetic: Option<Synthetic>,
     ) -> anyhow::Result<(Close, Option<Self>)> {
         if self.quantity.has_same_sign(quantity) {
             return Err(anyhow::Error::msg(format!(
@@ -205,17 +321,32 @@ impl Lot {
         }
 
         let open_original_quantity = self.quantity; // record for tax records
+        let open_total_basis = self.total_basis;
+        let (open_price, open_date) = match self.dual_basis {
+            Some(ref info) => (
+                info.basis_price(Some(price))?,
+                info.holding_start(Some(price))?.into(),
+            ),
+            None => (self.price, self.date),
+        };
 
         let partial;
         let close_quantity;
+        let mut closed_basis = None;
         if self.quantity.abs() > quantity.abs() {
             // Partial close
+            if let Some(total) = self.total_basis {
+                let basis = total / self.quantity * -quantity;
+                self.total_basis = Some(total - basis);
+                closed_basis = Some(basis);
+            }
             self.quantity += quantity;
             close_quantity = quantity;
             partial = true;
         } else {
             // Full close
             close_quantity = -self.quantity;
+            closed_basis = self.total_basis;
             partial = false;
         }
 
@@ -224,18 +355,54 @@ impl Lot {
                 ty,
                 synthetic,
                 open_id: self.id.clone(),
+                open_ty: self.open_ty,
                 open_original_quantity,
-                open_price: self.price,
-                open_date: self.date,
+                open_price,
+                open_date,
                 close_price: price,
                 close_date: date,
                 asset: self.asset,
                 quantity: close_quantity,
+                exact_basis: open_total_basis.zip(closed_basis),
             },
             if partial { Some(self) } else { None },
         ))
     }
 
+    /// Splits `quantity` off the lot, without closing anything
+    ///
+    /// Both parts keep the lot's ID, basis and date, as happens when a lot is
+    /// moved between our own venues. Returns the split-off part and the
+    /// remainder, if any; if the lot is no larger than `quantity` it is
+    /// returned whole.
+    pub fn split(mut self, quantity: Quantity) -> (Lot, Option<Lot>) {
+        if self.quantity.abs() > quantity.abs() {
+            let mut part = self.clone();
+            part.quantity = quantity;
+            if let Some(total) = self.total_basis {
+                let basis = total / self.quantity * quantity;
+                part.total_basis = Some(basis);
+                self.total_basis = Some(total - basis);
+            }
+            self.quantity -= quantity;
+            (part, Some(self))
+        } else {
+            (self, None)
+        }
+    }
+
+    /// Merges another lot into this one, keeping this lot's ID and date
+    ///
+    /// The quantities and bases are added exactly, and the unit price is set
+    /// to the average. Since the date of `other` is lost, the caller must
+    /// check that both lots are in the same holding-period class.
+    pub fn absorb(&mut self, other: Lot) {
+        let basis = self.basis() + other.basis();
+        self.quantity += other.quantity;
+        self.price = basis / self.quantity;
+        self.total_basis = Some(basis);
+    }
+
     pub fn csv_printer(&self) -> csv::CsvPrinter<LotCsv> {
         csv::CsvPrinter(LotCsv { lot: self })
     }
@@ -260,11 +427,13 @@ impl<'lot> csv::PrintCsv for LotCsv<'lot> {
             "", // old lot size
             "", // old lot basis
             self.lot.quantity,
-            self.lot.price * self.lot.quantity,
+            self.lot.basis(),
             "", // basis
             "", // proceeds
             "", // gain/loss
             "", // gain/loss type
+            self.lot.open_ty.note(self.lot.price),
+            &self.lot.synthetic,
         );
         csv.print(f)
     }
@@ -275,12 +444,31 @@ impl<'lot> csv::PrintCsv for LotCsv<'lot> {
 pub enum OpenType {
     BuyToOpen,
     SellToOpen,
-    Deposit,
+    Deposit(AcquisitionType),
     /// Only used for expiries and assignments which are events
     /// that can't produce new lots, but our functions require
     /// an `OpenType` nonetheless.
     Unknown,
 }
+impl OpenType {
+    /// A note to flag lots whose basis or holding period is determined specially
+    ///
+    /// Takes the lot's unit basis, to flag zero-basis lots.
+    pub fn note(&self, price: Price) -> &'static str {
+        match self {
+            OpenType::Deposit(ty) => match (ty, price == Price::ZERO) {
+                (AcquisitionType::Purchase, false) => "",
+                (AcquisitionType::Purchase, true) => "zero-cost basis",
+                (AcquisitionType::GiftCarryover, _) => "gift; carryover basis and holding period",
+                (AcquisitionType::GiftFmv, _) => "gift; dual basis, chosen by sale price",
+                (AcquisitionType::Income, false) => "income; FMV-at-receipt basis",
+                (AcquisitionType::Income, true) => "income; zero-cost basis",
+            },
+            _ => "",
+        }
+    }
+}
+
 impl fmt::Display for OpenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         csv::PrintCsv::print(self, f)
@@ -291,7 +479,10 @@ impl csv::PrintCsv for OpenType {
         match self {
             OpenType::BuyToOpen => f.write_str("Buy To Open"),
             OpenType::SellToOpen => f.write_str("Sell To Open"),
-            OpenType::Deposit => f.write_str("Deposit"),
+            OpenType::Deposit(AcquisitionType::Purchase) => f.write_str("Deposit"),
+            OpenType::Deposit(AcquisitionType::GiftCarryover)
+            | OpenType::Deposit(AcquisitionType::GiftFmv) => f.write_str("Deposit (Gift)"),
+            OpenType::Deposit(AcquisitionType::Income) => f.write_str("Deposit (Income)"),
             OpenType::Unknown => f.write_str("UNKNOWN THIS IS A BUG"),
         }
     }
@@ -327,8 +518,10 @@ impl csv::PrintCsv for CloseType {
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Close {
     ty: CloseType,
-    synthetic: Option<crate::option::PutCall>,
+    /// For closes by a synthetic trade, the assignment behind it
+    synthetic: Option<Synthetic>,
     open_id: Id,
+    open_ty: OpenType,
     open_original_quantity: Quantity,
     open_price: Price,
     open_date: TaxDate,
@@ -336,6 +529,9 @@ pub struct Close {
     close_date: TaxDate,
     asset: TaxAsset,
     quantity: Quantity,
+    /// For closes of merged lots, the exact basis of the lot before the
+    /// close and of the closed quantity
+    exact_basis: Option<(Price, Price)>,
 }
 
 impl fmt::Display for Close {
@@ -366,7 +562,10 @@ impl Close {
 
     /// The basis of the lot at its size prior to this close
     pub fn old_lot_basis(&self) -> Price {
-        self.open_price * self.open_original_quantity
+        match self.exact_basis {
+            Some((old, _)) => old,
+            None => self.open_price * self.open_original_quantity,
+        }
     }
 
     /// The size of the lot *after* this close
@@ -384,7 +583,10 @@ impl Close {
     /// In other words, the difference between [Self::new_lot_basis] and
     /// [Self::old_lot_basis].
     pub fn basis(&self) -> Price {
-        self.open_price * -self.quantity
+        match self.exact_basis {
+            Some((_, closed)) => closed,
+            None => self.open_price * -self.quantity,
+        }
     }
 
     /// The amount the closed quantity actually closed for
@@ -408,6 +610,11 @@ impl Close {
         }
     }
 
+    /// The ID of the closed lot
+    pub fn open_id(&self) -> &Id {
+        &self.open_id
+    }
+
     /// The date the closed lot was created
     pub fn open_date(&self) -> TaxDate {
         self.open_date
@@ -474,24 +681,25 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
     fn print(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.mode {
             PrintMode::LedgerX | PrintMode::LedgerXAnnotated => {
+                let rules = Rules::for_year(self.close.close_date.year());
                 let mut proceeds = self.close.proceeds();
                 let mut basis = self.close.basis();
 
                 let mut close_date = self.close.close_date;
                 let mut open_date = self.close.open_date;
 
-                if self.close.quantity.is_negative() {
+                if rules.swaps_columns(self.close.quantity) {
                     // wtf
                     mem::swap(&mut close_date, &mut open_date);
                     mem::swap(&mut basis, &mut proceeds);
                 }
                 // also wtf
-                if self.close.close_date.year() < 2024 {
+                if rules.absolute_amounts() {
                     proceeds = proceeds.abs();
                     basis = basis.abs();
                 }
 
-                if self.close.close_date.year() == 2021 {
+                if rules.csv_layout() == CsvLayout::Description {
                     let description = match self.close.quantity {
                         Quantity::Bitcoin(btc) => {
                             let real_amount = Decimal::new(btc.to_sat(), 8);
@@ -505,7 +713,18 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
                                 format!("{}, {}", real_amount.abs(), self.asset)
                             }
                         }
-                        Quantity::Contracts(n) => format!("{}, {}", n.abs(), self.asset),
+                        Quantity::Ether(n) => {
+                            let real_amount = Decimal::new(n, 9);
+                            let round_amount = real_amount.round_dp(2);
+                            if real_amount == round_amount {
+                                format!("{}, {}", round_amount.abs(), self.asset)
+                            } else {
+                                format!("{}, {}", real_amount.normalize().abs(), self.asset)
+                            }
+                        }
+                        Quantity::Contracts(n) | Quantity::EthContracts(n) => {
+                            format!("{}, {}", n.abs(), self.asset)
+                        }
                         Quantity::Cents(_) => {
                             panic!("tried to write out a sale of dollars as a tax event")
                         }
@@ -527,8 +746,7 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
                     )
                         .print(f)?;
                 } else {
-                    // Tax years not 2021
-                    let ref_1 = if self.close.asset == TaxAsset::Bitcoin {
+                    let ref_1 = if self.close.asset.is_coin() {
                         "Exercise"
                     } else {
                         match self.close.ty {
@@ -539,11 +757,11 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
                             CloseType::TxFee => "TX Fee",
                         }
                     };
-                    let ref_2 = match self.close.synthetic {
+                    let ref_2 = match self.close.synthetic.as_ref().map(|syn| syn.pc) {
                         Some(Call) => "1256 Option - Call",
                         Some(Put) => "1256 Option - Put",
                         None => match self.close.asset {
-                            TaxAsset::Bitcoin => "Non-1256 - Future",
+                            TaxAsset::Bitcoin | TaxAsset::Ether => "Non-1256 - Future",
                             TaxAsset::NextDay { .. } => "Non-1256 - Future",
                             TaxAsset::Option { option, .. } => {
                                 if self.close.ty == CloseType::Expiry
@@ -571,7 +789,18 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
                                 real_amount
                             }
                         }
-                        Quantity::Contracts(n) => Decimal::new(n.abs() * 100, 2),
+                        Quantity::Ether(n) => {
+                            let real_amount = Decimal::new(n, 9).abs();
+                            let round_amount = real_amount.round_dp(2);
+                            if real_amount == round_amount {
+                                round_amount
+                            } else {
+                                real_amount.normalize()
+                            }
+                        }
+                        Quantity::Contracts(n) | Quantity::EthContracts(n) => {
+                            Decimal::new(n.abs() * 100, 2)
+                        }
                         Quantity::Cents(_) => {
                             panic!("tried to write out a sale of dollars as a tax event")
                         }
@@ -629,6 +858,8 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
                     self.close.proceeds(),
                     self.close.gain_loss(),
                     self.close.gain_loss_type(),
+                    self.close.open_ty.note(self.close.open_price),
+                    &self.close.synthetic,
                 );
                 csv.print(f)?;
             }
@@ -636,3 +867,63 @@ impl<'close> csv::PrintCsv for CloseCsv<'close> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn gift_dual_basis() {
+        let donor_date = UtcTime::parse_coinbase("2020-01-10T00:00:00Z").unwrap();
+        let received = UtcTime::parse_coinbase("2022-06-20T00:00:00Z").unwrap();
+        let sold = TaxDate::from(UtcTime::parse_coinbase("2022-09-01T00:00:00Z").unwrap());
+        let info = LotInfo {
+            price: Price::from_cents(3_000_000),
+            date: donor_date,
+            acquisition: AcquisitionType::GiftFmv,
+            received_date: Some(received),
+            fmv_price: Some(Price::from_cents(2_000_000)),
+        };
+        let outpoint = bitcoin::OutPoint::from_str(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0",
+        )
+        .unwrap();
+        let lot =
+            Lot::from_deposit(outpoint, bitcoin::Amount::from_sat(100_000_000), &info).unwrap();
+        assert!(lot.has_dual_basis());
+        let sell = |price: i64| {
+            lot.clone()
+                .close(
+                    -Quantity::from(bitcoin::Amount::from_sat(25_000_000)),
+                    Price::from_cents(price),
+                    sold,
+                    CloseType::Sell,
+                    None,
+                )
+                .unwrap()
+                .0
+        };
+
+        // Above the donor's basis: a gain, with the donor's basis and holding period
+        let close = sell(4_000_000);
+        assert_eq!(close.basis(), Price::from_cents(750_000));
+        assert_eq!(close.gain_loss(), Price::from_cents(250_000));
+        assert_eq!(close.gain_loss_type(), GainType::LongTerm);
+        // Below the market value at receipt: a loss, held from receipt
+        let close = sell(1_600_000);
+        assert_eq!(close.basis(), Price::from_cents(500_000));
+        assert_eq!(close.gain_loss(), Price::from_cents(-100_000));
+        assert_eq!(close.gain_loss_type(), GainType::ShortTerm);
+        // In between: neither
+        let close = sell(2_500_000);
+        assert_eq!(close.gain_loss(), Price::ZERO);
+
+        // The market value at receipt must be below the donor's basis
+        let bad = LotInfo {
+            fmv_price: Some(Price::from_cents(3_000_000)),
+            ..info
+        };
+        assert!(Lot::from_deposit(outpoint, bitcoin::Amount::from_sat(1), &bad).is_err());
+    }
+}
@@ -19,18 +19,31 @@
 
 use crate::csv::{self, CsvPrinter};
 use crate::file::create_text_file;
+use crate::ledgerx::contract_cache::ContractCache;
 use crate::units::{
-    BudgetAsset, DepositAsset, Price, Quantity, TaxAsset, Underlying, UnknownQuantity, UtcTime,
+    BudgetAsset, ContractSize, DepositAsset, Price, Quantity, TaxAsset, Underlying,
+    UnknownQuantity, UtcTime,
 };
+#[cfg(feature = "xlsx")]
+use crate::xlsx;
 use anyhow::Context;
 use log::{debug, info, warn};
 use serde::Deserialize;
-use std::collections::{hash_map, BTreeMap, HashMap};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::str::FromStr;
 
+mod account_activity;
+pub mod beancount;
 pub mod config;
+pub mod diff;
+pub mod fills;
+pub mod ledger;
 pub mod lot;
+pub mod performance;
+pub mod summary;
 pub mod tax;
+pub mod wizard;
 
 pub use self::config::Configuration;
 pub use self::lot::Id as LotId;
@@ -46,7 +59,9 @@ struct Deposit {
     amount: UnknownQuantity,
     asset: DepositAsset,
     address: String,
-    #[serde(deserialize_with = "crate::units::deserialize_datetime")]
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
     created_at: UtcTime,
 }
 
@@ -69,7 +84,12 @@ impl Deposits {
 struct Withdrawal {
     amount: UnknownQuantity,
     asset: DepositAsset,
-    #[serde(deserialize_with = "crate::units::deserialize_datetime")]
+    /// Destination address, if reported
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
     created_at: UtcTime,
 }
 
@@ -95,10 +115,46 @@ enum Side {
     Ask,
 }
 
+/// A fill of one of our orders which LX busted, as recorded by `connect`
+///
+/// LX may still report busted fills among our trades, so these are matched
+/// against the trades and the busted ones left out of the history.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BustedTrade {
+    contract_id: super::ContractId,
+    /// Number of contracts, in LX's base units (negative for asks)
+    size: i64,
+    price: Price,
+    /// Time of the bust, which is after the trade
+    time: UtcTime,
+}
+
+impl BustedTrade {
+    /// Collects the busts among a set of records
+    pub fn from_records(records: &[crate::schema::Record]) -> anyhow::Result<Vec<Self>> {
+        let mut ret = vec![];
+        for record in records {
+            if let crate::schema::Body::Bust(ref bust) = record.body {
+                let contract_id = usize::from_str(&bust.contract_id)
+                    .with_context(|| format!("parsing bust contract ID {}", bust.contract_id))?;
+                let price = Price::from_str(&bust.price)
+                    .with_context(|| format!("parsing bust price {}", bust.price))?;
+                ret.push(BustedTrade {
+                    contract_id: super::ContractId::from(contract_id),
+                    size: bust.size,
+                    price,
+                    time: record.parse_time()?,
+                });
+            }
+        }
+        Ok(ret)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct Trade {
-    contract_id: String,
-    #[serde(deserialize_with = "crate::units::deserialize_datetime")]
+    contract_id: super::ContractId,
+    #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
     execution_time: UtcTime,
     #[serde(deserialize_with = "crate::units::deserialize_cents")]
     filled_price: Price,
@@ -119,36 +175,150 @@ impl Trades {
     /// Request contract data for every unknown contract ID from LX
     pub fn fetch_contract_ids(
         &self,
-        map: &mut HashMap<String, super::Contract>,
+        map: &mut HashMap<super::ContractId, super::Contract>,
+        cache: &mut ContractCache,
     ) -> Result<(), anyhow::Error> {
         for trade in &self.data {
-            let id = trade.contract_id.clone();
+            let id = trade.contract_id;
             if map.get(&id).is_none() {
-                let contract = crate::http::get_json_from_data_field(
-                    &format!("https://api.ledgerx.com/trading/contracts/{id}"),
-                    None,
-                )
-                .context("lookup contract for trade history")?;
+                let contract = cache
+                    .fetch(id)
+                    .context("lookup contract for trade history")?;
                 map.insert(id, contract);
             }
         }
         Ok(())
     }
 
+    /// Our fills in a single option contract, as (time, signed number of contracts, price)
+    pub fn fills_for(&self, contract: &super::Contract) -> Vec<(UtcTime, i64, Price)> {
+        let asset = contract.asset();
+        self.data
+            .iter()
+            .filter(|trade| trade.contract_id == contract.id())
+            .filter_map(|trade| {
+                let size = match trade.filled_size.with_asset_trade(asset) {
+                    Quantity::Contracts(n) => n,
+                    _ => return None,
+                };
+                match trade.side {
+                    Side::Bid => Some((trade.execution_time, size, trade.filled_price)),
+                    Side::Ask => Some((trade.execution_time, -size, trade.filled_price)),
+                }
+            })
+            .collect()
+    }
+
     /// Returns the next URL, if any, to fetch
     pub fn next_url(&self) -> Option<String> {
         self.meta.as_ref().and_then(|meta| meta.next.clone())
     }
 }
 
+/// Fetches all our fills in a single option contract from the LX API, in time order
+pub fn fetch_fills(
+    api_key: &str,
+    contract: &super::Contract,
+) -> anyhow::Result<Vec<(UtcTime, i64, Price)>> {
+    let mut ret = vec![];
+    let mut next_url = Some("https://api.ledgerx.com/trading/trades?limit=200".to_string());
+    while let Some(url) = next_url {
+        info!(
+            "Fetching trades .. have {} fills in {}.",
+            ret.len(),
+            contract
+        );
+        let trades: Trades =
+            crate::http::get_json(&url, Some(api_key)).context("getting trades from LX API")?;
+        ret.extend(trades.fills_for(contract));
+        next_url = trades.next_url();
+    }
+    ret.sort_by_key(|fill| fill.0);
+    Ok(ret)
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Position {
+    /// Our customer ID, which LX also uses as the user ID on its tax CSV
+    #[serde(default)]
+    cid: Option<usize>,
     size: i64,
     assigned_size: i64,
     contract: super::Contract,
     has_settled: bool,
 }
 
+/// Events produced by a deposit, with the output which funded it, if known
+type DepositEvents = (Option<bitcoin::OutPoint>, Vec<(UtcTime, Event)>);
+
+/// Chooses which of the outputs matching a deposit's address and amount funded it
+///
+/// Outputs in `reserved` have already been matched to other deposits. If the
+/// remaining candidates are all outputs of the same transaction they are
+/// interchangeable, and the first is used; if they come from different
+/// transactions, the match is ambiguous and an error listing them is returned.
+fn choose_deposit_outpoint<'tx>(
+    candidates: Vec<(bitcoin::OutPoint, Option<&'tx bitcoin::Transaction>)>,
+    reserved: &HashMap<bitcoin::OutPoint, UtcTime>,
+) -> anyhow::Result<(bitcoin::OutPoint, Option<&'tx bitcoin::Transaction>)> {
+    if candidates.is_empty() {
+        return Err(anyhow::Error::msg("no txout matched address/amount"));
+    }
+    let (free, used): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(outpoint, _)| !reserved.contains_key(outpoint));
+    match free.first() {
+        None => {
+            let used: Vec<String> = used
+                .iter()
+                .map(|(outpoint, _)| format!("{} (deposit at {})", outpoint, reserved[outpoint]))
+                .collect();
+            Err(anyhow::Error::msg(format!(
+                "every matching txout was already matched to another deposit: {}",
+                used.join(", ")
+            )))
+        }
+        Some(&first) if free.iter().all(|(op, _)| op.txid == first.0.txid) => Ok(first),
+        Some(_) => {
+            let free: Vec<String> = free.iter().map(|(op, _)| op.to_string()).collect();
+            Err(anyhow::Error::msg(format!(
+                "ambiguous match; candidate outpoints are {}",
+                free.join(", ")
+            )))
+        }
+    }
+}
+
+impl Position {
+    /// The net change in the number of contracts held due to assignment and
+    /// expiry, respectively, of a settled position
+    ///
+    /// We do a bit of goofy sign-mangling here; the idea is that the assigned
+    /// and expired "sizes" represent the net change in number of contracts
+    /// held, such that after expiry we have net 0. So for long positions,
+    /// both numbers will be negative.
+    ///
+    /// On the input front, `size` will be positive or negative according to
+    /// whether we are long or short; `assigned_size` is always positive; and
+    /// the expired size is not encoded. So arguably it's LX that's mangling
+    /// signs in weird ways, and we're just unmangling them.
+    fn settled_sizes(&self) -> anyhow::Result<(i64, i64)> {
+        if self.assigned_size < 0 || self.assigned_size > self.size.abs() {
+            return Err(anyhow::Error::msg(format!(
+                "assigned size {} is inconsistent with position size {}",
+                self.assigned_size, self.size,
+            )));
+        }
+        Ok(if self.size > 0 {
+            // long positions
+            (-self.assigned_size, -self.size + self.assigned_size)
+        } else {
+            // short positions
+            (self.assigned_size, -self.size - self.assigned_size)
+        })
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Positions {
     data: Vec<Position>,
@@ -159,12 +329,33 @@ pub struct Positions {
 impl Positions {
     /// Position data, weirdly, contains full contract information. So store this to speed up
     /// trade lookups.
-    pub fn store_contract_ids(&self, map: &mut HashMap<String, super::Contract>) {
+    pub fn store_contract_ids(&self, map: &mut HashMap<super::ContractId, super::Contract>) {
         for pos in &self.data {
-            map.insert(pos.contract.id().to_string(), pos.contract.clone());
+            map.insert(pos.contract.id(), pos.contract.clone());
         }
     }
 
+    /// The customer ID attached to our positions, if we have any
+    pub fn customer_id(&self) -> Option<usize> {
+        self.data.iter().find_map(|pos| pos.cid)
+    }
+
+    /// Iterator over the sizes of all positions which have not yet settled
+    pub fn open_positions(&self) -> impl Iterator<Item = (super::ContractId, i64)> + '_ {
+        self.data
+            .iter()
+            .filter(|pos| !pos.has_settled)
+            .map(|pos| (pos.contract.id(), pos.size))
+    }
+
+    /// Iterator over all positions which have not yet settled, with their contracts
+    pub fn open_contracts(&self) -> impl Iterator<Item = (&super::Contract, i64)> + '_ {
+        self.data
+            .iter()
+            .filter(|pos| !pos.has_settled)
+            .map(|pos| (&pos.contract, pos.size))
+    }
+
     /// Returns the next URL, if any, to fetch
     pub fn next_url(&self) -> Option<String> {
         self.meta.as_ref().and_then(|meta| meta.next.clone())
@@ -175,44 +366,442 @@ impl Positions {
 pub enum Event {
     UsdDeposit {
         amount: Quantity,
+        /// Status reported by the LX API, if any
+        status: Option<String>,
+        /// If this deposit was reversed (or was itself the re-credit of a failed
+        /// withdrawal), the time of the paired withdrawal
+        reversal: Option<UtcTime>,
     },
     BtcDeposit {
         amount: bitcoin::Amount,
         outpoint: bitcoin::OutPoint,
         lot_info: config::LotInfo,
     },
+    /// A BTC deposit funded from one of our own addresses, which brings back
+    /// coins from earlier withdrawals to our own addresses
+    BtcRedeposit {
+        amount: bitcoin::Amount,
+        outpoint: bitcoin::OutPoint,
+    },
+    /// An ETH deposit, which is always a single lot
+    EthDeposit {
+        amount: Quantity,
+        lot_info: config::LotInfo,
+    },
     Withdrawal {
         amount: Quantity,
         asset: DepositAsset,
+        /// Status reported by the LX API, if any
+        status: Option<String>,
+        /// If this was the reversal of a USD deposit (or failed and was re-credited),
+        /// the time of the paired deposit
+        reversal: Option<UtcTime>,
+        /// Whether this was a BTC withdrawal to one of our own addresses, whose
+        /// lots are held aside until they are redeposited
+        self_transfer: bool,
     },
     Trade {
         asset: TaxAsset,
         price: Price,
         size: Quantity,
+        /// Fee charged to us, negative for a rebate, regardless of how LX
+        /// reported it
         fee: Price,
     },
     Assignment {
         option: crate::option::Option,
         underlying: Underlying,
+        contract_size: ContractSize,
         size: Quantity,
         price_ref: Option<Price>,
+        /// Fee charged to us for the assignment, which LX reports only as a
+        /// balance change
+        fee: Price,
     },
     Expiry {
         option: crate::option::Option,
         underlying: Underlying,
+        contract_size: ContractSize,
         size: Quantity,
     },
 }
 
+impl Event {
+    /// Whether the event moves ETH or an ETH contract
+    fn involves_eth(&self) -> bool {
+        match *self {
+            Event::EthDeposit { .. } => true,
+            Event::Withdrawal { asset, .. } => asset == DepositAsset::Eth,
+            Event::Trade { asset, .. } => match asset {
+                TaxAsset::Bitcoin => false,
+                TaxAsset::Ether => true,
+                TaxAsset::NextDay { underlying, .. } | TaxAsset::Option { underlying, .. } => {
+                    underlying == Underlying::Eth
+                }
+            },
+            Event::Assignment { underlying, .. } | Event::Expiry { underlying, .. } => {
+                underlying == Underlying::Eth
+            }
+            Event::UsdDeposit { .. } | Event::BtcDeposit { .. } | Event::BtcRedeposit { .. } => {
+                false
+            }
+        }
+    }
+
+    /// Whether the event moves a full-size option contract
+    fn involves_full_size(&self) -> bool {
+        match *self {
+            Event::Trade {
+                asset: TaxAsset::Option { contract_size, .. },
+                ..
+            }
+            | Event::Assignment { contract_size, .. }
+            | Event::Expiry { contract_size, .. } => contract_size == ContractSize::Full,
+            _ => false,
+        }
+    }
+}
+
+/// Window within which an API event and an account activity record with the same
+/// details are considered to be the same record
+const DEDUP_WINDOW_SECS: i64 = 60;
+
+/// The identifying details of an account record, used to match records between sources
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Signature {
+    UsdDeposit(Quantity),
+    BtcDeposit(bitcoin::Amount),
+    EthDeposit(Quantity),
+    Withdrawal(DepositAsset, Quantity),
+    Trade(TaxAsset, Price, Quantity),
+}
+
+impl Signature {
+    /// Computes the signature of a record consisting of the given events
+    ///
+    /// A single BTC deposit may be split into several events, one per lot, so
+    /// these are combined. Otherwise a record is expected to be a single event.
+    fn of_record<'a, I: IntoIterator<Item = &'a Event>>(events: I) -> Option<Signature> {
+        let mut ret = None;
+        for event in events {
+            let sig = match *event {
+                Event::UsdDeposit { amount, .. } => Signature::UsdDeposit(amount),
+                Event::BtcDeposit { amount, .. } | Event::BtcRedeposit { amount, .. } => {
+                    Signature::BtcDeposit(amount)
+                }
+                Event::EthDeposit { amount, .. } => Signature::EthDeposit(amount),
+                Event::Withdrawal { amount, asset, .. } => Signature::Withdrawal(asset, amount),
+                Event::Trade {
+                    asset, price, size, ..
+                } => Signature::Trade(asset, price, size),
+                Event::Assignment { .. } | Event::Expiry { .. } => return None,
+            };
+            ret = match (ret, sig) {
+                (None, sig) => Some(sig),
+                (Some(Signature::BtcDeposit(total)), Signature::BtcDeposit(amount)) => {
+                    Some(Signature::BtcDeposit(total + amount))
+                }
+                _ => return None,
+            };
+        }
+        ret
+    }
+}
+
+/// Window within which a USD deposit and withdrawal of the same amount may be an ACH reversal
+const ACH_REVERSAL_WINDOW_DAYS: i64 = 14;
+
+/// Whether an LX deposit/withdrawal status indicates a reversal or failure
+///
+/// Returns `None` if there is no status.
+fn reversal_status(status: Option<&str>) -> Option<bool> {
+    status.map(|s| {
+        let s = s.to_ascii_lowercase();
+        ["revers", "return", "fail", "cancel", "reject"]
+            .iter()
+            .any(|pat| s.contains(pat))
+    })
+}
+
+/// Converts a withdrawal into an event
+///
+/// BTC withdrawals to an address paying to one of `internal` are self-transfers.
+fn withdrawal_event(withd: &Withdrawal, internal: &HashSet<bitcoin::ScriptBuf>) -> Event {
+    let self_transfer = withd.asset == DepositAsset::Btc
+        && bitcoin::Address::from_str(&withd.address)
+            .map(|addr| internal.contains(&addr.assume_checked().script_pubkey()))
+            .unwrap_or(false);
+    Event::Withdrawal {
+        amount: withd.amount.with_asset(withd.asset.into()),
+        asset: withd.asset,
+        status: withd.status.clone(),
+        reversal: None,
+        self_transfer,
+    }
+}
+
+/// Describes the ACH reversal pairing of a deposit or withdrawal, if any
+fn reversal_note(date: UtcTime, reversal: Option<UtcTime>) -> String {
+    match reversal {
+        Some(other) if other < date => format!("ACH reversal of {other}"),
+        Some(other) => format!("ACH reversed by {other}"),
+        None => String::new(),
+    }
+}
+
+/// Converts a quantity of cents to a price, returning `None` for non-USD quantities
+fn usd_value(qty: Quantity) -> Option<Price> {
+    match qty {
+        Quantity::Cents(n) => Some(Price::from(rust_decimal::Decimal::new(n, 2))),
+        Quantity::Zero => Some(Price::ZERO),
+        _ => None,
+    }
+}
+
+/// Format in which the `history` command outputs events
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum OutputFormat {
+    /// One line of CSV per event
+    #[default]
+    Csv,
+    /// Beancount double-entry accounting directives
+    Beancount,
+    /// One JSON record per event, in the format of the `schema` module
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Csv => f.write_str("csv"),
+            OutputFormat::Beancount => f.write_str("beancount"),
+            OutputFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "beancount" => Ok(OutputFormat::Beancount),
+            "json" => Ok(OutputFormat::Json),
+            x => Err(format!(
+                "Invalid output format {x}; allowed values: csv, beancount, json"
+            )),
+        }
+    }
+}
+
+/// An inclusive range of (UTC) dates used to limit output of the history commands
+///
+/// Either end may be left open.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct DateRange {
+    /// First date to include
+    pub from: Option<chrono::NaiveDate>,
+    /// Last date to include
+    pub to: Option<chrono::NaiveDate>,
+}
+
+impl DateRange {
+    /// Whether the range is unbounded on both ends
+    pub fn is_full(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    /// Whether a given time falls within the range
+    pub fn contains(&self, time: UtcTime) -> bool {
+        let date = chrono::NaiveDate::from_ymd_opt(time.year(), time.month(), time.day())
+            .expect("UtcTime has a valid date");
+        self.from.map(|from| from <= date).unwrap_or(true)
+            && self.to.map(|to| date <= to).unwrap_or(true)
+    }
+
+    /// Whether a given time falls after the end of the range
+    pub fn ends_before(&self, time: UtcTime) -> bool {
+        let date = chrono::NaiveDate::from_ymd_opt(time.year(), time.month(), time.day())
+            .expect("UtcTime has a valid date");
+        self.to.map(|to| to < date).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.from, self.to) {
+            (None, None) => f.write_str("all dates"),
+            (Some(from), None) => write!(f, "{from} onward"),
+            (None, Some(to)) => write!(f, "through {to}"),
+            (Some(from), Some(to)) => write!(f, "{from} through {to}"),
+        }
+    }
+}
+
+/// Price source which uses LX's own price references, where we have one for
+/// the exact time, and historic price data otherwise
+pub struct LxPriceSource<'a> {
+    refs: &'a HashMap<UtcTime, Price>,
+    fallback: &'a crate::price::Historic,
+}
+
+impl crate::price::PriceSource for LxPriceSource<'_> {
+    fn name(&self) -> String {
+        "lx".into()
+    }
+
+    fn price_at(&self, time: UtcTime) -> crate::price::BitcoinPrice {
+        match self.refs.get(&time) {
+            Some(&btc_price) => crate::price::BitcoinPrice {
+                timestamp: time,
+                btc_price,
+            },
+            None => self.fallback.price_at(time),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct History {
     user_id: usize,
     years: BTreeMap<i32, tax::LotSelectionStrategy>,
+    initial_carryforward: tax::Carryforward,
     lot_db: HashMap<LotId, config::LotInfo>,
     transaction_db: crate::transaction::Database,
+    /// Scripts of our own addresses, between which and LX BTC moves without
+    /// being disposed of
+    internal_scripts: HashSet<bitcoin::ScriptBuf>,
+    /// Lots which leave the account with each BTC withdrawal to an outside
+    /// address, by the time of the withdrawal
+    withdrawal_lots: HashMap<UtcTime, Vec<LotId>>,
+    /// Thresholds below which residual coin lots are treated as dust
+    dust: tax::DustSettings,
     lx_price_ref: HashMap<UtcTime, Price>,
+    price_policy: config::AssignmentPricePolicy,
+    price_overrides: HashMap<String, Price>,
+    /// ETH prices for assignments, from the configuration file, by date
+    eth_prices: HashMap<String, Price>,
+    /// Fees charged on assignment, from the configuration file, by contract label
+    assignment_fees: HashMap<String, Price>,
     config_hash: bitcoin::hashes::sha256::Hash,
     events: crate::TimeMap<Event>,
+    /// Records which could not be imported and were skipped in lenient mode,
+    /// with the reason
+    skipped: Vec<String>,
+    /// Outputs which have been matched to BTC deposits, with the deposit time
+    deposit_outpoints: HashMap<bitcoin::OutPoint, UtcTime>,
+    beancount_accounts: beancount::Accounts,
+    /// Fee sign conventions for years in which they differ from the default
+    fee_signs: BTreeMap<i32, config::FeeSign>,
+    /// Busts of our fills which have not (yet) been matched to a trade
+    busts: Vec<BustedTrade>,
+}
+
+/// Totals of the trade fees in a single year
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct FeeTotals {
+    /// Sum of all fees, with the sign LX reported them with
+    pub raw: Price,
+    /// Sum of all fees charged to us
+    pub charged: Price,
+    /// Sum of all rebates paid to us
+    pub rebated: Price,
+    /// Number of trades
+    pub n_trades: usize,
+    /// Number of trades on which we were charged a fee
+    pub n_charged: usize,
+    /// Number of trades on which we received a rebate
+    pub n_rebated: usize,
+    /// Number of option contracts traded on trades with a rebate
+    pub rebated_contracts: i64,
+}
+
+impl FeeTotals {
+    /// The fee we would have been charged on the rebated trades, had they
+    /// been charged at the base rate
+    pub fn expected_on_rebated(&self) -> Price {
+        Price::from_cents(super::fees::FeeTier::BASE.fee.to_cents() * self.rebated_contracts / 100)
+    }
+
+    /// Whether the totals look like the fee sign convention is backward
+    ///
+    /// A maker-heavy year may well have more rebates than charges, but a
+    /// rebate is only ever a fraction of the taker fee. If the rebates come to
+    /// half or more of the base fee on the same contracts, they are more
+    /// likely to be fees with the wrong sign.
+    pub fn suspicious(&self) -> bool {
+        self.rebated > Price::ZERO
+            && 2 * self.rebated.to_cents() >= self.expected_on_rebated().to_cents()
+    }
+}
+
+/// The result of a dry run of the tax pipeline
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct TaxCheck {
+    /// Problems which may be expected, but should be looked at
+    pub warnings: Vec<String>,
+    /// Inconsistencies which would make the tax output wrong or impossible
+    pub errors: Vec<String>,
+}
+
+/// The totals for a single year under one lot selection strategy
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StrategyOutcome {
+    /// The strategy used for the year
+    pub strategy: tax::LotSelectionStrategy,
+    /// Whether this is the strategy in the configuration file
+    pub configured: bool,
+    /// Long-term gain or loss
+    pub long_term: Price,
+    /// Short-term gain or loss
+    pub short_term: Price,
+    /// Section 1256 gain or loss, before splitting 60/40
+    pub section_1256: Price,
+    /// The net gains after splitting, and their effect on the carryforward
+    pub carryforward: tax::CarryforwardYear,
+    /// Estimated tax owed for the year, after the carryforward
+    pub liability: Price,
+}
+
+/// The output of running all events through the tax engine
+struct TaxRun {
+    /// Tracker containing all tax events, and all remaining open lots
+    tracker: tax::PositionTracker,
+    /// Warnings which should be recorded in the metadata
+    warnings: Vec<String>,
+    /// The BTC prices used to compute each assignment, where they came from,
+    /// and the fee charged
+    assignment_sources: Vec<(
+        UtcTime,
+        crate::option::Option,
+        Quantity,
+        Price,
+        PriceRefSource,
+        Price,
+    )>,
+    /// Deposited lots with unusual basis or holding period rules
+    special_lots: Vec<(LotId, Quantity, config::LotInfo)>,
+}
+
+/// Where the BTC price used to compute an assignment came from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum PriceRefSource {
+    /// A manual override from the configuration file
+    Override,
+    /// The official LX price reference, parsed from their CSV
+    LedgerX,
+    /// Our own historic price data
+    Historic,
+}
+
+impl fmt::Display for PriceRefSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PriceRefSource::Override => f.write_str("manual override"),
+            PriceRefSource::LedgerX => f.write_str("LX price reference"),
+            PriceRefSource::Historic => f.write_str("historic price data"),
+        }
+    }
 }
 
 impl History {
@@ -238,25 +827,59 @@ impl History {
         let transaction_db = config
             .transaction_db()
             .context("extracting transaction database from config file")?;
+        let price_overrides = config
+            .assignment_price_overrides()
+            .context("extracting assignment price overrides from config file")?;
+        let eth_prices = config
+            .eth_assignment_prices()
+            .context("extracting ETH assignment prices from config file")?;
+        let internal_scripts = config
+            .internal_scripts()
+            .context("extracting internal addresses from config file")?;
+        let withdrawal_lots = config
+            .withdrawal_lots()
+            .context("extracting withdrawal lots from config file")?;
         // Return
         Ok(History {
             user_id: config.user,
             years: config.years().clone(),
+            initial_carryforward: config.initial_carryforward(),
             lot_db: config.lot_db().clone(),
             transaction_db,
+            internal_scripts,
+            withdrawal_lots,
+            dust: config.dust(),
             lx_price_ref,
+            price_policy: config.assignment_price_policy(),
+            price_overrides,
+            eth_prices,
+            assignment_fees: config.assignment_fees(),
             config_hash,
             events: Default::default(),
+            skipped: vec![],
+            deposit_outpoints: HashMap::new(),
+            beancount_accounts: config.beancount_accounts().clone(),
+            fee_signs: config.fee_sign_overrides().clone(),
+            busts: vec![],
         })
     }
 
     /// Construct a new history by calling the LX API
+    ///
+    /// If `lenient` is set, records which cannot be imported are logged and
+    /// skipped, rather than failing the whole import. Skipped records are
+    /// listed in the tax metadata. Trades matching one of `busts` are left
+    /// out.
     pub fn from_api(
         api_key: &str,
         config: &Configuration,
         config_hash: bitcoin::hashes::sha256::Hash,
+        contract_cache: &mut ContractCache,
+        lenient: bool,
+        busts: &[BustedTrade],
     ) -> anyhow::Result<Self> {
         let mut ret = History::new(config, config_hash)?;
+        ret.busts = busts.to_vec();
         let mut contracts = HashMap::new();
 
         let mut next_url = Some("https://api.ledgerx.com/trading/positions?limit=200".to_string());
@@ -269,7 +892,8 @@ impl History {
                 .context("getting positions from LX API")?;
             positions.store_contract_ids(&mut contracts);
 
-            ret.import_positions(&positions);
+            ret.import_positions(&positions, lenient)
+                .context("importing positions")?;
             next_url = positions.next_url();
         }
 
@@ -279,7 +903,7 @@ impl History {
             let deposits: Deposits = crate::http::get_json(&url, Some(api_key))
                 .context("getting deposits from LX API")?;
 
-            ret.import_deposits(&deposits)
+            ret.import_deposits(&deposits, lenient)
                 .context("importing deposits")?;
             next_url = deposits.next_url();
         }
@@ -303,249 +927,652 @@ impl History {
             let trades: Trades =
                 crate::http::get_json(&url, Some(api_key)).context("getting trades from LX API")?;
             trades
-                .fetch_contract_ids(&mut contracts)
+                .fetch_contract_ids(&mut contracts, contract_cache)
                 .with_context(|| "getting contract IDs")?;
 
-            ret.import_trades(&trades, &contracts)
+            ret.import_trades(&trades, &contracts, lenient)
                 .with_context(|| "importing trades")?;
             next_url = trades.next_url();
         }
-        Ok(ret)
-    }
-
-    /// Import a list of deposits into the history
-    fn import_deposits(&mut self, deposits: &Deposits) -> anyhow::Result<()> {
-        for dep in &deposits.data {
-            let amount = dep.amount.with_asset(dep.asset.into());
-            match dep.asset {
-                // ETH deposits are easy
-                DepositAsset::Eth => unimplemented!("we do not support eth deposits"),
-                // USD deposits almost as easy
-                DepositAsset::Usd => {
-                    self.events
-                        .insert(dep.created_at, Event::UsdDeposit { amount });
-                }
-                // BTC deposits are much more involved, as we need to sort out lots
-                DepositAsset::Btc => {
-                    let total_btc = dep.amount.as_sats().to_unsigned().with_context(|| {
-                        format!("negative deposit amount {}", dep.amount.as_sats())
-                    })?;
-                    let addr = bitcoin::Address::from_str(&dep.address)
-                        .with_context(|| format!("parsing BTC address {}", dep.address))?
-                        .require_network(bitcoin::Network::Bitcoin)
-                        .with_context(|| {
-                            format!("parsing address as BTC address {}", dep.address)
-                        })?;
 
-                    // Look up transaction based on address. If we can't find one, error out.
-                    let (tx, vout) = self
-                        .transaction_db
-                        .find_tx_for_deposit(&addr, total_btc)
-                        .with_context(|| {
-                            format!("no txout matched address/amount {addr}/{total_btc}")
-                        })?;
+        if let Err(e) = contract_cache.save() {
+            warn!("Failed to save contract cache: {:#}", e);
+        }
 
-                    if tx.output.len() == 1 {
-                        debug!(
-                            "Assuming that a single-output deposit is from Andrew's wallet \
-                                and that every input UXTO is a separate lot."
-                        );
-                        let mut total_btc = total_btc;
-                        for outpoint in tx.input.iter().map(|inp| inp.previous_output) {
-                            let txout =
-                                self.transaction_db.find_txout(outpoint).with_context(|| {
-                                    format!("config file did not have tx data for {outpoint}")
-                                })?;
-                            let id = LotId::from_outpoint(outpoint);
-                            let lot_info = self
-                                .lot_db
-                                .get(&id)
-                                .with_context(|| {
-                                    format!("config file did not have info for lot {id}")
-                                })?
-                                .clone();
-                            debug!(
-                                "Lot {}: price {} date {}",
-                                id, lot_info.price, lot_info.date
-                            );
-                            // Take fees away from the last input(s). We consider this a
-                            // partial loss of the lot corresponding to the input
-                            //
-                            // A future iteration may consider this to be a taxable loss but this
-                            // won't affect anything downstream, basically it'll just add an extra
-                            // log line. FIXME implement this.
-                            let mut amount = txout.value;
-                            if amount > total_btc {
-                                amount = total_btc;
-                            };
-                            total_btc -= amount;
-                            self.events.insert(
-                                dep.created_at,
-                                Event::BtcDeposit {
-                                    amount,
-                                    outpoint,
-                                    lot_info,
-                                },
-                            );
-                        }
-                    } else {
-                        debug!("Assuming that a multi-output deposit is constitutes a single lot.");
-                        let outpoint = bitcoin::OutPoint {
-                            txid: tx.txid(),
-                            vout,
-                        };
-                        let id = LotId::from_outpoint(outpoint);
-                        let lot_info = self
-                            .lot_db
-                            .get(&id)
-                            .with_context(|| format!("config file did not have info for lot {id}"))?
-                            .clone();
-                        debug!(
-                            "Lot {}: price {} date {}",
-                            id, lot_info.price, lot_info.date
-                        );
-                        self.events.insert(
-                            dep.created_at,
-                            Event::BtcDeposit {
-                                amount: total_btc,
-                                outpoint: bitcoin::OutPoint {
-                                    txid: tx.txid(),
-                                    vout,
-                                },
-                                lot_info,
-                            },
-                        );
-                    }
-                }
-            }
+        if !config.account_activity().is_empty() {
+            let records = account_activity::parse(config.account_activity())
+                .context("parsing account activity export")?;
+            ret.import_account_activity(
+                records,
+                &contracts,
+                config.account_activity_preference(),
+                lenient,
+            )
+            .context("importing account activity export")?;
         }
-        Ok(())
-    }
 
-    /// Import a list of withdrawals into the history
-    fn import_withdrawals(&mut self, withdrawals: &Withdrawals) {
-        for withd in &withdrawals.data {
-            self.events.insert(
-                withd.created_at,
-                Event::Withdrawal {
-                    amount: withd.amount.with_asset(withd.asset.into()),
-                    asset: withd.asset,
-                },
+        ret.link_ach_reversals();
+        if !ret.skipped.is_empty() {
+            warn!(
+                "Skipped {} records which could not be imported. The output will be incomplete.",
+                ret.skipped.len()
             );
         }
+        Ok(ret)
     }
 
-    /// Import a list of trades into the history
-    fn import_trades(
+    /// Handles a record which could not be imported
+    ///
+    /// In lenient mode the record is logged and skipped; otherwise the error
+    /// is returned, with the record attached.
+    fn skip_record(
         &mut self,
-        trades: &Trades,
-        contracts: &HashMap<String, super::Contract>,
-    ) -> Result<(), anyhow::Error> {
-        for trade in &trades.data {
-            let contract = match contracts.get(&trade.contract_id) {
-                Some(contract) => contract.clone(),
-                None => {
-                    return Err(anyhow::Error::msg(format!(
-                        "Unknown contract ID {}",
-                        trade.contract_id
-                    )))
-                }
-            };
-            let asset = contract.asset();
-            self.events.insert(
-                trade.execution_time,
-                Event::Trade {
-                    asset: contract
-                        .tax_asset()
-                        .with_context(|| format!("getting tax asset for {contract}"))?,
-                    price: trade.filled_price,
-                    size: match trade.side {
-                        Side::Bid => trade.filled_size.with_asset_trade(asset),
-                        Side::Ask => -trade.filled_size.with_asset_trade(asset),
-                    },
-                    fee: trade.fee,
-                },
-            );
+        lenient: bool,
+        record: &dyn fmt::Debug,
+        error: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        if !lenient {
+            return Err(error.context(format!("importing record {record:?}")));
         }
+        let msg = format!("{record:?}: {error:#}");
+        warn!("Skipping record {}", msg);
+        self.skipped.push(msg);
         Ok(())
     }
 
-    /// Import a list of positions into the history
-    fn import_positions(&mut self, positions: &Positions) {
-        for pos in &positions.data {
-            // Unsettled positions don't have any trade logs associated with them
-            if !pos.has_settled {
-                continue;
-            }
-            // Non-options are "expired" in a trivial sense (and are taxed at the time
-            // of sale, as a sale) and never assigned, so ignore them here.
-            let option = match pos.contract.as_option() {
-                Some(opt) => opt,
+    /// Merges records from an account activity export into the history
+    ///
+    /// A record which has the same details as an existing (API-derived) event,
+    /// at nearly the same time, is a duplicate, and `preference` determines which
+    /// of the two is kept. All other records are backfilled into the history.
+    ///
+    /// Assignment fees which cannot be attached to an assignment are skipped
+    /// in lenient mode.
+    fn import_account_activity(
+        &mut self,
+        records: Vec<account_activity::Record>,
+        contracts: &HashMap<super::ContractId, super::Contract>,
+        preference: config::SourcePreference,
+        lenient: bool,
+    ) -> anyhow::Result<()> {
+        // Group the existing events into records, as (time, signature, event indices, matched)
+        let mut existing: Vec<(UtcTime, Signature, Vec<usize>, bool)> = vec![];
+        for (n, (date, event)) in self.events.iter().enumerate() {
+            let sig = match Signature::of_record([event]) {
+                Some(sig) => sig,
                 None => continue,
             };
+            if let Some((last_date, last_sig, indices, _)) = existing.last_mut() {
+                if let (Signature::BtcDeposit(total), Signature::BtcDeposit(amount)) =
+                    (last_sig, sig)
+                {
+                    if *last_date == date && indices.last() == Some(&(n - 1)) {
+                        *total += amount;
+                        indices.push(n);
+                        continue;
+                    }
+                }
+            }
+            existing.push((date, sig, vec![n], false));
+        }
 
-            // We do a bit of goofy sign-mangling here; the idea is that the assigned
-            // and expipred "sizes" represent the net change in number of contracts
-            // held, such that after expiry we have net 0. So for long positions,
-            // both numbers will be negative.
-            //
-            // On the input front, pos.size will be positive or negative according to
-            // whether we are long or short; assigned_size is always positive; and
-            // the expired size is not encoded. So arguably it's LX that's mangling
-            // signs in weird ways, and we're just unmangling them.
-            let (assigned, expired) = if pos.size > 0 {
-                // long positions
-                (-pos.assigned_size, -pos.size + pos.assigned_size)
-            } else {
-                // short positions
-                (pos.assigned_size, -pos.size - pos.assigned_size)
+        let by_label: HashMap<&str, &super::Contract> =
+            contracts.values().map(|c| (c.label(), c)).collect();
+        let mut to_remove = std::collections::HashSet::new();
+        let mut to_insert = vec![];
+        let (mut n_duplicate, mut n_backfilled, mut n_fees) = (0, 0, 0);
+        for record in records {
+            // Deposits which duplicate API records will match outputs which have
+            // already been reserved, so don't exclude those here; instead check
+            // backfilled deposits below.
+            let (deposit_outpoint, events) = match record {
+                account_activity::Record::Deposit(dep) => self
+                    .deposit_events(&dep, &HashMap::new())
+                    .with_context(|| format!("importing deposit at {}", dep.created_at))?,
+                account_activity::Record::Withdrawal(withd) => {
+                    let event = withdrawal_event(&withd, &self.internal_scripts);
+                    (None, vec![(withd.created_at, event)])
+                }
+                account_activity::Record::AssignmentFee {
+                    ref contract_label,
+                    time,
+                    amount,
+                } => {
+                    let result = by_label
+                        .get(contract_label.as_str())
+                        .with_context(|| {
+                            format!("unknown contract {contract_label} in account activity")
+                        })
+                        .and_then(|contract| self.add_assignment_fee(contract, amount))
+                        .with_context(|| format!("importing fee at {time}"));
+                    match result {
+                        Ok(()) => n_fees += 1,
+                        Err(e) => self.skip_record(lenient, &record, e)?,
+                    }
+                    continue;
+                }
+                account_activity::Record::Trade {
+                    contract_label,
+                    time,
+                    price,
+                    size,
+                    fee,
+                } => {
+                    let contract = by_label.get(contract_label.as_str()).with_context(|| {
+                        format!("unknown contract {contract_label} in account activity")
+                    })?;
+                    let asset = contract
+                        .tax_asset()
+                        .with_context(|| format!("getting tax asset for {contract}"))?;
+                    let size = UnknownQuantity::from(size).with_asset_trade(contract.asset());
+                    (
+                        None,
+                        vec![(
+                            time,
+                            Event::Trade {
+                                asset,
+                                price,
+                                size,
+                                fee: self.fee_sign(time.year()).charge(fee),
+                            },
+                        )],
+                    )
+                }
             };
-            // This assertion maybe makes it clearer what we're doing.
-            assert_eq!(assigned + expired, -pos.size, "{pos:?}");
-
-            // LedgerX's data has the time forced to 22:00 even when DST makes this wrong
-            let price_ref_date = if option.expiry.year() == 2021 {
-                option.expiry.forced_to_hour(22)
-            } else {
-                option.expiry + chrono::Duration::hours(1)
+            let time = match events.first() {
+                Some((time, _)) => *time,
+                None => continue,
             };
 
-            // Insert the expiry event, if any (in 2021 this is BEFORE assignment, in 2022 AFTER)
-            if option.expiry.year() == 2021 && expired != 0 {
-                self.events.insert(
-                    price_ref_date,
-                    Event::Expiry {
-                        option,
-                        underlying: pos.contract.underlying(),
-                        size: UnknownQuantity::from(expired).with_asset(pos.contract.asset()),
-                    },
-                );
-            }
-            // Insert the assignment event, if any
-            if assigned != 0 {
-                let n_assigned = UnknownQuantity::from(assigned).with_asset(pos.contract.asset());
-                self.events.insert(
-                    price_ref_date,
-                    Event::Assignment {
-                        option,
-                        underlying: pos.contract.underlying(),
-                        size: n_assigned,
-                        price_ref: self.lx_price_ref.get(&price_ref_date).copied(),
-                    },
-                );
-            }
-            // Insert the expiry event, if any (in 2021 this is BEFORE assignment, in 2022 AFTER)
-            if option.expiry.year() > 2021 && expired != 0 {
-                self.events.insert(
-                    price_ref_date,
-                    Event::Expiry {
-                        option,
-                        underlying: pos.contract.underlying(),
-                        size: UnknownQuantity::from(expired).with_asset(pos.contract.asset()),
-                    },
-                );
+            let sig = Signature::of_record(events.iter().map(|(_, event)| event));
+            let duplicate = existing.iter_mut().find(|(date, esig, _, matched)| {
+                !*matched
+                    && Some(*esig) == sig
+                    && (*date - time).num_seconds().abs() <= DEDUP_WINDOW_SECS
+            });
+            match duplicate {
+                Some((date, _, indices, matched)) => {
+                    debug!(
+                        "Account activity record at {} duplicates API record at {}; {}",
+                        time, date, preference,
+                    );
+                    *matched = true;
+                    n_duplicate += 1;
+                    if preference == config::SourcePreference::PreferExport {
+                        to_remove.extend(indices.iter().copied());
+                        to_insert.extend(events);
+                    }
+                }
+                None => {
+                    debug!("Backfilling account activity record at {}", time);
+                    if let Some(outpoint) = deposit_outpoint {
+                        if let Some(other) = self.deposit_outpoints.insert(outpoint, time) {
+                            return Err(anyhow::Error::msg(format!(
+                                "account activity deposit at {time} matched output {outpoint}, \
+                                 which was already matched to the deposit at {other}"
+                            )));
+                        }
+                    }
+                    n_backfilled += 1;
+                    to_insert.extend(events);
+                }
             }
         }
+
+        let mut n = 0;
+        self.events.retain(|_, _| {
+            n += 1;
+            !to_remove.contains(&(n - 1))
+        });
+        for (date, event) in to_insert {
+            self.events.insert(date, event);
+        }
+        info!(
+            "Account activity export: backfilled {} records; {} duplicated API records ({}); \
+             {} assignment fees",
+            n_backfilled, n_duplicate, preference, n_fees,
+        );
+        Ok(())
+    }
+
+    /// Attaches a fee from the account activity export to the assignment it
+    /// was charged on
+    ///
+    /// Fees given in the configuration file take precedence, so are not added to.
+    fn add_assignment_fee(
+        &mut self,
+        contract: &super::Contract,
+        amount: Price,
+    ) -> anyhow::Result<()> {
+        if self.assignment_fees.contains_key(contract.label()) {
+            debug!(
+                "Ignoring exported fee {} on {}; using configured fee",
+                amount, contract
+            );
+            return Ok(());
+        }
+        let opt = contract
+            .as_option()
+            .with_context(|| format!("fee charged on non-option {contract}"))?;
+        let size = contract.contract_size();
+        let fee = self
+            .events
+            .iter_mut()
+            .find_map(|(_, event)| match event {
+                Event::Assignment {
+                    option,
+                    contract_size,
+                    fee,
+                    ..
+                } if *option == opt && *contract_size == size => Some(fee),
+                _ => None,
+            })
+            .with_context(|| format!("fee charged on {contract}, which was never assigned"))?;
+        *fee += amount;
+        Ok(())
+    }
+
+    /// Finds pairs of USD deposits and withdrawals which represent ACH reversals
+    ///
+    /// When an ACH deposit is reversed, or a withdrawal fails and is re-credited, we
+    /// see a deposit and withdrawal of the same amount in quick succession. These are
+    /// paired up and linked to each other so that reports can skip or annotate them.
+    ///
+    /// Only flows where LX gives a status indicating a reversal or failure for at
+    /// least one side are paired. Matching flows without such a status may well be
+    /// a genuine deposit and withdrawal, so they are only warned about.
+    fn link_ach_reversals(&mut self) {
+        // Collect all USD flows as (index, time, signed amount, status)
+        let mut flows = vec![];
+        for (n, (date, event)) in self.events.iter().enumerate() {
+            match event {
+                Event::UsdDeposit { amount, status, .. } => {
+                    flows.push((n, date, *amount, status.as_deref()));
+                }
+                Event::Withdrawal {
+                    amount,
+                    asset: DepositAsset::Usd,
+                    status,
+                    ..
+                } => {
+                    flows.push((n, date, -*amount, status.as_deref()));
+                }
+                _ => {}
+            }
+        }
+
+        let mut links = HashMap::new();
+        for (i, &(n1, t1, amount1, status1)) in flows.iter().enumerate() {
+            if links.contains_key(&n1) {
+                continue;
+            }
+            for &(n2, t2, amount2, status2) in &flows[i + 1..] {
+                if t2 - t1 > chrono::Duration::days(ACH_REVERSAL_WINDOW_DAYS) {
+                    break;
+                }
+                if links.contains_key(&n2) || amount1 != -amount2 {
+                    continue;
+                }
+                match (reversal_status(status1), reversal_status(status2)) {
+                    (Some(true), _) | (_, Some(true)) => {}
+                    (Some(false), Some(false)) => continue,
+                    _ => {
+                        warn!(
+                            "USD flows of {} at {} and {} at {} may be an ACH reversal, but \
+                             LX gives no reversal status (statuses {:?}/{:?}); not pairing them",
+                            amount1, t1, amount2, t2, status1, status2,
+                        );
+                        continue;
+                    }
+                }
+                info!(
+                    "Treating USD flows of {} at {} and {} at {} as an ACH reversal pair \
+                     (statuses {:?}/{:?})",
+                    amount1, t1, amount2, t2, status1, status2,
+                );
+                links.insert(n1, t2);
+                links.insert(n2, t1);
+                break;
+            }
+        }
+
+        for (n, (_, event)) in self.events.iter_mut().enumerate() {
+            if let Some(other) = links.get(&n) {
+                match event {
+                    Event::UsdDeposit { reversal, .. } | Event::Withdrawal { reversal, .. } => {
+                        *reversal = Some(*other);
+                    }
+                    _ => unreachable!("only deposits and withdrawals are linked"),
+                }
+            }
+        }
+    }
+
+    /// Import a list of deposits into the history
+    fn import_deposits(&mut self, deposits: &Deposits, lenient: bool) -> anyhow::Result<()> {
+        for dep in &deposits.data {
+            match self.deposit_events(dep, &self.deposit_outpoints) {
+                Ok((outpoint, events)) => {
+                    if let Some(outpoint) = outpoint {
+                        self.deposit_outpoints.insert(outpoint, dep.created_at);
+                    }
+                    for (date, event) in events {
+                        self.events.insert(date, event);
+                    }
+                }
+                Err(e) => self.skip_record(lenient, dep, e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts a deposit into events, working out which lots it consists of
+    ///
+    /// A BTC deposit may produce several events, one per lot. For BTC deposits,
+    /// also returns the output which funded it; outputs in `reserved` have
+    /// already been matched to other deposits and will not be used.
+    fn deposit_events(
+        &self,
+        dep: &Deposit,
+        reserved: &HashMap<bitcoin::OutPoint, UtcTime>,
+    ) -> anyhow::Result<DepositEvents> {
+        let mut ret = vec![];
+        let mut matched_outpoint = None;
+        let amount = dep.amount.with_asset(dep.asset.into());
+        match dep.asset {
+            // ETH deposits are easy, since without any transaction data each is a single lot
+            DepositAsset::Eth => {
+                if amount.is_negative() {
+                    return Err(anyhow::Error::msg(format!(
+                        "negative deposit amount {amount}"
+                    )));
+                }
+                let id = LotId::from_eth_deposit(dep.created_at);
+                let lot_info = self
+                    .lot_db
+                    .get(&id)
+                    .with_context(|| format!("config file did not have info for lot {id}"))?
+                    .clone();
+                debug!(
+                    "Lot {}: price {} date {}",
+                    id, lot_info.price, lot_info.date
+                );
+                ret.push((dep.created_at, Event::EthDeposit { amount, lot_info }));
+            }
+            // USD deposits almost as easy
+            DepositAsset::Usd => {
+                ret.push((
+                    dep.created_at,
+                    Event::UsdDeposit {
+                        amount,
+                        status: dep.status.clone(),
+                        reversal: None,
+                    },
+                ));
+            }
+            // BTC deposits are much more involved, as we need to sort out lots
+            DepositAsset::Btc => {
+                let total_btc =
+                    dep.amount.as_sats().to_unsigned().with_context(|| {
+                        format!("negative deposit amount {}", dep.amount.as_sats())
+                    })?;
+                let addr = bitcoin::Address::from_str(&dep.address)
+                    .with_context(|| format!("parsing BTC address {}", dep.address))?
+                    .require_network(bitcoin::Network::Bitcoin)
+                    .with_context(|| format!("parsing address as BTC address {}", dep.address))?;
+
+                // Look up transaction based on address. If we can't find one, error out.
+                let (deposit_outpoint, tx) = choose_deposit_outpoint(
+                    self.transaction_db.deposit_candidates(&addr, total_btc),
+                    reserved,
+                )
+                .with_context(|| format!("matching deposit of {total_btc} to {addr}"))?;
+                matched_outpoint = Some(deposit_outpoint);
+
+                // If we only know the deposit output (e.g. from a wallet export) then
+                // we can't see its inputs, and must treat it as a single lot.
+                if let Some(tx) = tx.filter(|tx| tx.output.len() == 1) {
+                    debug!(
+                        "Assuming that a single-output deposit is from Andrew's wallet \
+                                and that every input UXTO is a separate lot."
+                    );
+                    let mut total_btc = total_btc;
+                    for outpoint in tx.input.iter().map(|inp| inp.previous_output) {
+                        let txout =
+                            self.transaction_db.find_txout(outpoint).with_context(|| {
+                                format!("config file did not have tx data for {outpoint}")
+                            })?;
+                        // Take fees away from the last input(s). We consider this a
+                        // partial loss of the lot corresponding to the input
+                        //
+                        // A future iteration may consider this to be a taxable loss but this
+                        // won't affect anything downstream, basically it'll just add an extra
+                        // log line. FIXME implement this.
+                        let mut amount = txout.value;
+                        if amount > total_btc {
+                            amount = total_btc;
+                        };
+                        total_btc -= amount;
+                        // Coins from our own addresses bring back withdrawn lots
+                        if self.internal_scripts.contains(&txout.script_pubkey) {
+                            debug!("Input {} is from an internal address", outpoint);
+                            ret.push((dep.created_at, Event::BtcRedeposit { amount, outpoint }));
+                            continue;
+                        }
+                        let id = LotId::from_outpoint(outpoint);
+                        let lot_info = self
+                            .lot_db
+                            .get(&id)
+                            .with_context(|| format!("config file did not have info for lot {id}"))?
+                            .clone();
+                        debug!(
+                            "Lot {}: price {} date {}",
+                            id, lot_info.price, lot_info.date
+                        );
+                        ret.push((
+                            dep.created_at,
+                            Event::BtcDeposit {
+                                amount,
+                                outpoint,
+                                lot_info,
+                            },
+                        ));
+                    }
+                } else {
+                    debug!(
+                        "Assuming that a multi-output (or partially-known) deposit \
+                             constitutes a single lot."
+                    );
+                    let id = LotId::from_outpoint(deposit_outpoint);
+                    let lot_info = self
+                        .lot_db
+                        .get(&id)
+                        .with_context(|| format!("config file did not have info for lot {id}"))?
+                        .clone();
+                    debug!(
+                        "Lot {}: price {} date {}",
+                        id, lot_info.price, lot_info.date
+                    );
+                    ret.push((
+                        dep.created_at,
+                        Event::BtcDeposit {
+                            amount: total_btc,
+                            outpoint: deposit_outpoint,
+                            lot_info,
+                        },
+                    ));
+                }
+            }
+        }
+        Ok((matched_outpoint, ret))
+    }
+
+    /// The sign convention LX used for trade fees in the given year
+    fn fee_sign(&self, year: i32) -> config::FeeSign {
+        self.fee_signs.get(&year).copied().unwrap_or_default()
+    }
+
+    /// Totals of all trade fees in each year, as reported by LX
+    ///
+    /// These can be compared against the fee totals on LX's statements to
+    /// check that the sign convention for each year is right.
+    pub fn fee_totals(&self) -> BTreeMap<i32, FeeTotals> {
+        let mut ret = BTreeMap::<i32, FeeTotals>::new();
+        for (date, event) in self.events.iter() {
+            if let Event::Trade { fee, size, .. } = event {
+                let totals = ret.entry(date.year()).or_default();
+                totals.raw += self.fee_sign(date.year()).raw(*fee);
+                totals.n_trades += 1;
+                if *fee > Price::ZERO {
+                    totals.charged += *fee;
+                    totals.n_charged += 1;
+                } else if *fee < Price::ZERO {
+                    totals.rebated -= *fee;
+                    totals.n_rebated += 1;
+                    if let Quantity::Contracts(n) | Quantity::EthContracts(n) = *size {
+                        totals.rebated_contracts += n.abs();
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Import a list of withdrawals into the history
+    fn import_withdrawals(&mut self, withdrawals: &Withdrawals) {
+        for withd in &withdrawals.data {
+            let event = withdrawal_event(withd, &self.internal_scripts);
+            self.events.insert(withd.created_at, event);
+        }
+    }
+
+    /// Import a list of trades into the history
+    fn import_trades(
+        &mut self,
+        trades: &Trades,
+        contracts: &HashMap<super::ContractId, super::Contract>,
+        lenient: bool,
+    ) -> Result<(), anyhow::Error> {
+        for trade in &trades.data {
+            let size = match trade.side {
+                Side::Bid => trade.filled_size.to_i64(),
+                Side::Ask => -trade.filled_size.to_i64(),
+            };
+            if let Some(idx) = self.busts.iter().position(|bust| {
+                bust.contract_id == trade.contract_id
+                    && bust.size == size
+                    && bust.price == trade.filled_price
+                    && trade.execution_time <= bust.time
+            }) {
+                let bust = self.busts.remove(idx);
+                info!(
+                    "Leaving out trade of {} on contract {} at {}, which was busted at {}",
+                    size, trade.contract_id, trade.execution_time, bust.time,
+                );
+                continue;
+            }
+            let contract = match contracts.get(&trade.contract_id) {
+                Some(contract) => contract.clone(),
+                None => {
+                    return Err(anyhow::Error::msg(format!(
+                        "Unknown contract ID {}",
+                        trade.contract_id
+                    )))
+                }
+            };
+            let asset = contract.asset();
+            let tax_asset = match contract.tax_asset() {
+                Some(tax_asset) => tax_asset,
+                None => {
+                    let e = anyhow::Error::msg(format!("getting tax asset for {contract}"));
+                    self.skip_record(lenient, trade, e)?;
+                    continue;
+                }
+            };
+            self.events.insert(
+                trade.execution_time,
+                Event::Trade {
+                    asset: tax_asset,
+                    price: trade.filled_price,
+                    size: match trade.side {
+                        Side::Bid => trade.filled_size.with_asset_trade(asset),
+                        Side::Ask => -trade.filled_size.with_asset_trade(asset),
+                    },
+                    fee: self.fee_sign(trade.execution_time.year()).charge(trade.fee),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Import a list of positions into the history
+    fn import_positions(&mut self, positions: &Positions, lenient: bool) -> anyhow::Result<()> {
+        for pos in &positions.data {
+            match self.position_events(pos) {
+                Ok(events) => {
+                    for (date, event) in events {
+                        self.events.insert(date, event);
+                    }
+                }
+                Err(e) => self.skip_record(lenient, pos, e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts a settled option position into expiry and assignment events
+    fn position_events(&self, pos: &Position) -> anyhow::Result<Vec<(UtcTime, Event)>> {
+        let mut ret = vec![];
+        // Unsettled positions don't have any trade logs associated with them
+        if !pos.has_settled {
+            return Ok(ret);
+        }
+        // Non-options are "expired" in a trivial sense (and are taxed at the time
+        // of sale, as a sale) and never assigned, so ignore them here.
+        let option = match pos.contract.as_option() {
+            Some(opt) => opt,
+            None => return Ok(ret),
+        };
+        let (assigned, expired) = pos.settled_sizes()?;
+
+        let settlement =
+            super::expiry::Settlement::resolve(option.expiry, pos.contract.exercise_date());
+        let price_ref_date = settlement.price_ref;
+        let expiry_event = || Event::Expiry {
+            option,
+            underlying: pos.contract.underlying(),
+            contract_size: pos.contract.contract_size(),
+            size: UnknownQuantity::from(expired).with_asset(pos.contract.asset()),
+        };
+
+        // Insert the expiry event, if any (in 2021 this is BEFORE assignment, in 2022 AFTER)
+        if settlement.expiry_before_assignment && expired != 0 {
+            ret.push((price_ref_date, expiry_event()));
+        }
+        // Insert the assignment event, if any
+        if assigned != 0 {
+            let n_assigned = UnknownQuantity::from(assigned).with_asset(pos.contract.asset());
+            ret.push((
+                price_ref_date,
+                Event::Assignment {
+                    option,
+                    underlying: pos.contract.underlying(),
+                    contract_size: pos.contract.contract_size(),
+                    size: n_assigned,
+                    price_ref: self.lx_price_ref.get(&price_ref_date).copied(),
+                    fee: self
+                        .assignment_fees
+                        .get(pos.contract.label())
+                        .copied()
+                        .unwrap_or(Price::ZERO),
+                },
+            ));
+        }
+        // Insert the expiry event, if any (in 2021 this is BEFORE assignment, in 2022 AFTER)
+        if !settlement.expiry_before_assignment && expired != 0 {
+            ret.push((price_ref_date, expiry_event()));
+        }
+        Ok(ret)
     }
 
     /// Iterator over all events that have happened in the history
@@ -553,15 +1580,131 @@ impl History {
         self.events.iter()
     }
 
+    /// Determine the price of the underlying to use for an assignment
+    ///
+    /// For BTC this follows the configured policy. For ETH, for which there are
+    /// no price references, it must be given in the configuration file.
+    fn assignment_price(
+        &self,
+        date: UtcTime,
+        underlying: Underlying,
+        price_ref: Option<Price>,
+        price_history: &crate::price::Historic,
+    ) -> anyhow::Result<(Price, PriceRefSource)> {
+        if underlying == Underlying::Eth {
+            return match self.eth_prices.get(&date.format("%F").to_string()) {
+                Some(price) => Ok((*price, PriceRefSource::Override)),
+                None => Err(anyhow::Error::msg(format!(
+                    "no ETH price configured for assignment on {date} (see eth_assignment_prices)"
+                ))),
+            };
+        }
+        if let Some(price) = self.price_overrides.get(&date.format("%F").to_string()) {
+            return Ok((*price, PriceRefSource::Override));
+        }
+        match (self.price_policy, price_ref) {
+            (config::AssignmentPricePolicy::PreferHistoric, _) | (_, None) => {
+                if self.price_policy == config::AssignmentPricePolicy::Strict {
+                    return Err(anyhow::Error::msg(format!(
+                        "no LX price reference or manual override for assignment on {date} \
+                         (policy is strict)"
+                    )));
+                }
+                let btc_price = price_history.price_at(date);
+                Ok((btc_price.btc_price, PriceRefSource::Historic))
+            }
+            (_, Some(price)) => Ok((price, PriceRefSource::LedgerX)),
+        }
+    }
+
+    /// Fails if the history has any ETH activity, for outputs which need an ETH
+    /// price history that we do not have
+    ///
+    /// `what` names the output, for the error message.
+    fn require_btc_only(&self, what: &str) -> anyhow::Result<()> {
+        match self.events.iter().find(|(_, event)| event.involves_eth()) {
+            Some((date, event)) => Err(anyhow::Error::msg(format!(
+                "{what} does not support ETH activity, as we have no ETH price history \
+                 (first ETH event at {date}: {event:?})"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Price source using the LX price references in our config file
+    pub fn lx_price_source<'a>(
+        &'a self,
+        fallback: &'a crate::price::Historic,
+    ) -> LxPriceSource<'a> {
+        LxPriceSource {
+            refs: &self.lx_price_ref,
+            fallback,
+        }
+    }
+
     /// Dump the contents of the history in CSV format
-    pub fn print_csv(&self, price_history: &crate::price::Historic) {
-        for (date, event) in &self.events {
+    ///
+    /// Only events within `range` are output. Every event is marked with the
+    /// BTC price from `price_source`, whose name is given in the header. If
+    /// `merge_window` is set, split executions within it are merged into one
+    /// row, followed by a comment listing the raw trades.
+    ///
+    /// Every row is marked with a BTC price, so fails up front if there is any
+    /// ETH activity.
+    pub fn print_csv(
+        &self,
+        price_source: &dyn crate::price::PriceSource,
+        range: DateRange,
+        merge_window: Option<chrono::Duration>,
+    ) -> anyhow::Result<()> {
+        self.require_btc_only("the budget CSV")?;
+        // The budget spreadsheet identifies options by expiry, put/call and
+        // strike only, so it cannot tell full-size contracts from minis
+        if let Some((date, event)) = self.events.iter().find(|(date, event)| {
+            self.years.contains_key(&date.year())
+                && range.contains(*date)
+                && event.involves_full_size()
+        }) {
+            return Err(anyhow::Error::msg(format!(
+                "the budget CSV does not support full-size contracts \
+                 (first full-size event at {date}: {event:?})"
+            )));
+        }
+        println!("# Price source: {}", price_source.name());
+        if !range.is_full() {
+            println!("# Date range: {range}");
+        }
+        if let Some(window) = merge_window {
+            println!("# Merging fills within {}s", window.num_seconds());
+        }
+        for entry in fills::merge(self.events.iter(), merge_window) {
+            let (date, event) = (entry.date, &entry.event);
             // Skip years that we haven't set a tax strategy for
             if !self.years.contains_key(&date.year()) {
                 continue;
             }
+            if !range.contains(date) {
+                continue;
+            }
+            // Skip ACH reversals, which net to zero and would otherwise look
+            // like free money or phantom losses
+            if let Event::UsdDeposit {
+                reversal: Some(other),
+                ..
+            }
+            | Event::Withdrawal {
+                reversal: Some(other),
+                ..
+            } = event
+            {
+                warn!(
+                    "Skipping {:?} at {}, ACH reversal pair with {}",
+                    event, date, other
+                );
+                continue;
+            }
 
-            let btc_price = price_history.price_at(date);
+            let btc_price = price_source.price_at(date);
             let btc_price = btc_price.btc_price; // just discard exact price timestamp
             let date_fmt = csv::DateTime(date);
 
@@ -576,14 +1719,21 @@ impl History {
                     (None, *amount),
                     (btc_price, None, None),
                 ),
-                Event::BtcDeposit { amount, .. } => (
+                Event::BtcDeposit { amount, .. } | Event::BtcRedeposit { amount, .. } => (
                     "Deposit",
                     date_fmt,
                     BudgetAsset::Btc,
                     (None, (*amount).into()),
                     (btc_price, None, None),
                 ),
-                Event::Withdrawal { asset, amount } => (
+                Event::EthDeposit { amount, .. } => (
+                    "Deposit",
+                    date_fmt,
+                    BudgetAsset::Eth,
+                    (None, *amount),
+                    (btc_price, None, None),
+                ),
+                Event::Withdrawal { asset, amount, .. } => (
                     "Withdraw",
                     date_fmt,
                     BudgetAsset::from(*asset),
@@ -599,7 +1749,9 @@ impl History {
                     BudgetAsset::from(*asset),
                     (Some(*price), *size),
                     match asset {
-                        TaxAsset::Bitcoin | TaxAsset::NextDay { .. } => (btc_price, None, None),
+                        TaxAsset::Bitcoin | TaxAsset::Ether | TaxAsset::NextDay { .. } => {
+                            (btc_price, None, None)
+                        }
                         TaxAsset::Option { option, .. } => (
                             btc_price,
                             Some(csv::Iv(option.bs_iv(date, btc_price, *price))),
@@ -611,11 +1763,13 @@ impl History {
                 Event::Expiry {
                     option,
                     underlying,
+                    contract_size,
                     size,
                 }
                 | Event::Assignment {
                     option,
                     underlying,
+                    contract_size,
                     size,
                     ..
                 } => (
@@ -628,15 +1782,708 @@ impl History {
                     BudgetAsset::Option {
                         underlying: *underlying,
                         option: *option,
+                        contract_size: *contract_size,
                     },
                     (None, *size),
                     (btc_price, None, None),
                 ),
             };
 
-            // ...then output it
-            println!("{}", CsvPrinter(csv));
+            // ...then output it
+            println!("{}", CsvPrinter(csv));
+            if !entry.executions.is_empty() {
+                let trades: Vec<String> = entry
+                    .executions
+                    .iter()
+                    .filter_map(|(date, event)| match event {
+                        Event::Trade { size, .. } => Some(format!("{size} at {date}")),
+                        _ => None,
+                    })
+                    .collect();
+                println!(
+                    "# Merged from {} trades: {}",
+                    trades.len(),
+                    trades.join(", ")
+                );
+            }
+            // Assignment fees are a separate outflow of USD
+            if let Event::Assignment { fee, .. } = event {
+                if *fee != Price::ZERO {
+                    println!(
+                        "{}",
+                        CsvPrinter((
+                            "Fee",
+                            date_fmt,
+                            BudgetAsset::Usd,
+                            (None::<Price>, Quantity::Cents(fee.to_cents())),
+                            (btc_price, None::<csv::Iv>, None::<csv::Arr>),
+                        ))
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Dump the contents of the history as JSON records, one per line
+    ///
+    /// As with the CSV output, years without a tax strategy and ACH reversal
+    /// pairs are skipped, and split executions within `merge_window` merged.
+    pub fn print_json(&self, range: DateRange, merge_window: Option<chrono::Duration>) {
+        for entry in fills::merge(self.events.iter(), merge_window) {
+            let (date, event) = (entry.date, &entry.event);
+            if !self.years.contains_key(&date.year()) || !range.contains(date) {
+                continue;
+            }
+            if let Event::UsdDeposit {
+                reversal: Some(_), ..
+            }
+            | Event::Withdrawal {
+                reversal: Some(_), ..
+            } = event
+            {
+                continue;
+            }
+            println!("{}", crate::schema::Record::merged_event(&entry));
+        }
+    }
+
+    /// Dump the contents of the history as Beancount directives
+    ///
+    /// Only events within `range` are output, but every earlier event is still
+    /// used to track positions, so a partial range can be appended to a ledger
+    /// containing the earlier events. For that reason the options and account
+    /// openings are only output if no earlier event was exported. As with the
+    /// CSV output, years without a tax strategy are skipped.
+    pub fn print_beancount(
+        &self,
+        price_history: &crate::price::Historic,
+        range: DateRange,
+    ) -> anyhow::Result<()> {
+        let mut exporter = beancount::Exporter::new(&self.beancount_accounts);
+        let mut printed_header = false;
+        let mut exported_before = false;
+        for (date, event) in &self.events {
+            if !self.years.contains_key(&date.year()) {
+                continue;
+            }
+            let assignment_price = match *event {
+                Event::Assignment {
+                    underlying,
+                    price_ref,
+                    ..
+                } => Some(
+                    self.assignment_price(date, underlying, price_ref, price_history)
+                        .with_context(|| format!("pricing assignment at {date}"))?
+                        .0,
+                ),
+                _ => None,
+            };
+            let tx = exporter
+                .transaction(date, event, assignment_price)
+                .with_context(|| format!("exporting event at {date}"))?;
+            if let Some(tx) = tx {
+                if !range.contains(date) {
+                    exported_before = true;
+                    continue;
+                }
+                if !printed_header {
+                    if !range.is_full() {
+                        println!("; Date range: {range}");
+                    }
+                    // A ledger with the earlier events already has the header
+                    if !exported_before {
+                        println!("{}", exporter.header(date));
+                    }
+                    printed_header = true;
+                }
+                println!("{tx}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays the history into a daily series of account values, from the day
+    /// of the first event through the end of `range` (or today)
+    ///
+    /// Open options are marked at the Black-Scholes price at volatility `iv`.
+    /// Fails up front if there is any ETH activity, which we cannot mark.
+    pub fn nlv_series(
+        &self,
+        price_history: &crate::price::Historic,
+        range: DateRange,
+        iv: f64,
+    ) -> anyhow::Result<Vec<performance::Mark>> {
+        self.require_btc_only("the account value series")?;
+        let mut events = self.events.iter().peekable();
+        let mut day = match events.peek() {
+            Some((date, _)) => {
+                chrono::NaiveDate::from_ymd_opt(date.year(), date.month(), date.day())
+                    .expect("UtcTime has a valid date")
+            }
+            None => return Ok(vec![]),
+        };
+        let last_day = range
+            .to
+            .unwrap_or_else(|| chrono::offset::Utc::now().date_naive());
+
+        let mut account = performance::Account::new();
+        let mut ret = vec![];
+        while day <= last_day {
+            let end_of_day =
+                UtcTime::from(day.and_hms_opt(23, 59, 59).expect("valid time").and_utc());
+            let mut flow = Price::ZERO;
+            while let Some((date, event)) = events.next_if(|(date, _)| *date <= end_of_day) {
+                let assignment_price = match *event {
+                    Event::Assignment {
+                        underlying,
+                        price_ref,
+                        ..
+                    } => Some(
+                        self.assignment_price(date, underlying, price_ref, price_history)
+                            .with_context(|| format!("pricing assignment at {date}"))?
+                            .0,
+                    ),
+                    _ => None,
+                };
+                flow += account
+                    .apply(
+                        event,
+                        price_history.price_at(date).btc_price,
+                        assignment_price,
+                    )
+                    .with_context(|| format!("replaying event at {date}"))?;
+            }
+            let btc_price = price_history.price_at(end_of_day).btc_price;
+            ret.push(performance::Mark {
+                date: day,
+                btc_price,
+                flow,
+                nlv: account.nlv(end_of_day, btc_price, iv),
+            });
+            day = day.succ_opt().expect("date in range");
+        }
+        Ok(ret)
+    }
+
+    /// Outputs the time- and money-weighted returns of the account as CSV, for
+    /// each year and for the whole of `range`, alongside those of holding BTC
+    pub fn print_performance(
+        &self,
+        price_history: &crate::price::Historic,
+        range: DateRange,
+        iv: f64,
+    ) -> anyhow::Result<()> {
+        use chrono::Datelike as _;
+
+        let marks = self.nlv_series(price_history, range, iv)?;
+        let first = marks
+            .iter()
+            .position(|mark| range.from.map(|from| from <= mark.date).unwrap_or(true))
+            .context("no account history within date range")?;
+
+        // The starting value and BTC price of a period starting at index `n`
+        let start = |n: usize| match n.checked_sub(1) {
+            Some(prev) => (marks[prev].nlv, marks[prev].btc_price),
+            None => (Price::ZERO, Price::ZERO),
+        };
+        let line = |period: &str, from: usize, to: usize| {
+            let (start_nlv, start_price) = start(from);
+            let line = performance::Line {
+                period,
+                account: performance::Returns::compute(start_nlv, &marks[from..to]),
+                hodl: performance::Returns::hodl(start_nlv, start_price, &marks[from..to]),
+            };
+            println!("{line}");
+        };
+
+        info!("Marking open options at {:.0}% volatility.", iv * 100.0);
+        println!("# Date range: {range}");
+        println!("{}", performance::Line::HEADER);
+        let mut from = first;
+        while from < marks.len() {
+            let year = marks[from].date.year();
+            let to = marks[from..]
+                .iter()
+                .position(|mark| mark.date.year() != year)
+                .map(|n| from + n)
+                .unwrap_or(marks.len());
+            line(&year.to_string(), from, to);
+            from = to;
+        }
+        line(
+            if range.from.is_some() {
+                "Total"
+            } else {
+                "Since inception"
+            },
+            first,
+            marks.len(),
+        );
+        Ok(())
+    }
+
+    /// Runs every event through the tax engine, producing the complete set of
+    /// lot opens and closes
+    ///
+    /// Stops at the first year for which there is no lot selection strategy.
+    fn run_tax_engine(&self, price_history: &crate::price::Historic) -> anyhow::Result<TaxRun> {
+        self.run_tax_engine_with(price_history, &self.years)
+    }
+
+    /// Runs every event through the tax engine, using the given lot selection
+    /// strategies rather than the configured ones
+    fn run_tax_engine_with(
+        &self,
+        price_history: &crate::price::Historic,
+        years: &BTreeMap<i32, tax::LotSelectionStrategy>,
+    ) -> anyhow::Result<TaxRun> {
+        let mut tracker = tax::PositionTracker::new();
+        tracker.set_dust_settings(self.dust);
+        let mut assignment_sources = vec![];
+        let mut special_lots = vec![];
+        let mut warnings = vec![];
+        for (date, event) in &self.events {
+            debug!("Processing event {:?}", event);
+            if let Some(strat) = years.get(&date.year()) {
+                tracker.set_bitcoin_lot_strategy(*strat);
+            } else {
+                warn!(
+                    "Have no tax strategy for year {}. Stopping here.",
+                    date.year()
+                );
+                break;
+            }
+
+            match event {
+                // USD deposits are not tax-relevant
+                Event::UsdDeposit { .. } => continue,
+                // Deposits of BTC cause lots to be become accessible to our tax optimizer
+                Event::BtcDeposit {
+                    amount,
+                    outpoint,
+                    lot_info,
+                } => {
+                    debug!("[deposit] \"BTC\" {} outpoint {}", amount, outpoint);
+                    let lot = lot::Lot::from_deposit(*outpoint, *amount, lot_info)
+                        .with_context(|| format!("creating lot for deposit {outpoint}"))?;
+                    if lot_info.is_special() {
+                        special_lots.push((lot.id().clone(), (*amount).into(), lot_info.clone()));
+                    }
+                    tracker.push_lot(date.into(), lot);
+                }
+                // Deposits of ETH likewise, though without any outpoint
+                Event::EthDeposit { amount, lot_info } => {
+                    debug!("[deposit] \"ETH\" {}", amount);
+                    let lot = lot::Lot::from_eth_deposit(date, *amount, lot_info)
+                        .with_context(|| format!("creating lot for ETH deposit at {date}"))?;
+                    if lot_info.is_special() {
+                        special_lots.push((lot.id().clone(), *amount, lot_info.clone()));
+                    }
+                    tracker.push_lot(date.into(), lot);
+                }
+                // Redeposits from our own addresses bring back the lots we withdrew
+                Event::BtcRedeposit { amount, outpoint } => {
+                    debug!("[redeposit] \"BTC\" {} outpoint {}", amount, outpoint);
+                    tracker
+                        .push_transfer_in(
+                            *amount,
+                            price_history.price_at(date).btc_price,
+                            date.into(),
+                        )
+                        .with_context(|| format!("restoring lots for redeposit {outpoint}"))?;
+                }
+                // Withdrawals to our own addresses set lots aside until redeposited
+                Event::Withdrawal {
+                    amount,
+                    self_transfer: true,
+                    ..
+                } => {
+                    let btc = match amount {
+                        Quantity::Bitcoin(btc) => btc.abs().to_unsigned()?,
+                        _ => {
+                            return Err(anyhow::Error::msg(format!(
+                                "self-transfer of non-BTC amount {amount}"
+                            )))
+                        }
+                    };
+                    debug!("[self-transfer] \"BTC\" {}", btc);
+                    tracker
+                        .push_transfer_out(btc, date.into())
+                        .with_context(|| format!("setting aside lots for withdrawal at {date}"))?;
+                }
+                // Other BTC withdrawals take the lots named in the config file out of
+                // the account. This is not a taxable event.
+                Event::Withdrawal {
+                    amount: Quantity::Bitcoin(btc),
+                    asset: DepositAsset::Btc,
+                    ..
+                } => match self.withdrawal_lots.get(&date) {
+                    Some(ids) => {
+                        debug!("[withdrawal] \"BTC\" {} lots {:?}", btc, ids);
+                        tracker
+                            .push_withdrawal(btc.abs().to_unsigned()?, ids, date.into())
+                            .with_context(|| format!("removing lots for withdrawal at {date}"))?;
+                    }
+                    None => warn!(
+                        "No lots given for withdrawal of {} at {}; leaving them open.",
+                        btc, date
+                    ),
+                },
+                // Withdrawals of anything else are not taxable events.
+                Event::Withdrawal { .. } => {
+                    debug!("Ignore withdrawal");
+                }
+                // Trades may be
+                Event::Trade {
+                    asset,
+                    price,
+                    size,
+                    fee,
+                } => {
+                    debug!("[trade] \"{}\" {} @ {}; fee {}", asset, size, price, fee,);
+
+                    let adj_price = crate::ledgerx::rules::fee_adjusted_price(*price, *size, *fee);
+
+                    tracker
+                        .push_trade(*asset, *size, adj_price, date.into())
+                        .with_context(|| format!("pushing trade of {asset} size {size}"))?;
+                }
+                // Expiries are a simple tax event (a straight gain)
+                Event::Expiry {
+                    option,
+                    underlying,
+                    contract_size,
+                    size,
+                } => {
+                    debug!("[expiry] {} {} expired {}", underlying, option, size);
+                    tracker
+                        .push_expiry(*option, *underlying, *contract_size, *size)
+                        .with_context(|| format!("expiring option {option} n {size}"))?;
+                }
+                // Assignments are less simple because we need a price reference to compute
+                // the intrinsic price (with expiries this is assumed to be zero)
+                Event::Assignment {
+                    option,
+                    underlying,
+                    contract_size,
+                    size,
+                    price_ref,
+                    fee,
+                } => {
+                    debug!(
+                        "[expiry] {} {} assigned {} at date {}; fee {}",
+                        underlying, option, size, date, fee
+                    );
+                    let (btc_price, source) = self
+                        .assignment_price(date, *underlying, *price_ref, price_history)
+                        .with_context(|| format!("pricing assignment of {option} n {size}"))?;
+                    if source == PriceRefSource::Historic
+                        && self.price_policy == config::AssignmentPricePolicy::PreferLx
+                    {
+                        // We allow this because otherwise we can't possibly produce
+                        // files until LX gives us their shit, which they take
+                        // forever to do. Use the "strict" policy to make it a hard
+                        // error, since the result will not be so easily justifiable
+                        // to the IRS.
+                        warn!(
+                            "Do not have LX price reference for {}; using price {}",
+                            date, btc_price
+                        );
+                        warnings.push(format!(
+                            "used non-official price reference of {} on {} for calculating \
+                             assignment loss (strike {} size {})",
+                            btc_price, date, option.strike, size,
+                        ));
+                    }
+                    assignment_sources.push((date, *option, *size, btc_price, source, *fee));
+
+                    tracker
+                        .push_assignment(
+                            *option,
+                            *underlying,
+                            *contract_size,
+                            *size,
+                            btc_price,
+                            *fee,
+                        )
+                        .with_context(|| format!("assignment option {option} n {size}"))?;
+                }
+            };
+        }
+        tracker.lx_sort_events();
+        for lot in tracker.in_transit() {
+            warnings.push(format!(
+                "{} of lot {} was withdrawn to an internal address and never redeposited",
+                lot.quantity(),
+                lot.id(),
+            ));
+        }
+
+        Ok(TaxRun {
+            tracker,
+            warnings,
+            assignment_sources,
+            special_lots,
+        })
+    }
+
+    /// Runs the tax pipeline without producing any output, reporting any problems
+    ///
+    /// This covers gaps in the configured lot selection strategies, lots which are
+    /// configured but never deposited, assignments lacking an official price
+    /// reference, and any error from the tax engine itself.
+    pub fn check_tax(&self, price_history: &crate::price::Historic, range: DateRange) -> TaxCheck {
+        let mut ret = TaxCheck::default();
+
+        for skipped in &self.skipped {
+            ret.warnings.push(format!("skipped record {skipped}"));
+        }
+        for (year, totals) in self.fee_totals() {
+            if totals.suspicious() {
+                ret.warnings.push(format!(
+                    "{} of {} trades in {year} have rebates totalling {}, against a base \
+                     fee of {} on the same contracts; the fee sign convention for {year} \
+                     may be backward",
+                    totals.n_rebated,
+                    totals.n_trades,
+                    totals.rebated,
+                    totals.expected_on_rebated(),
+                ));
+            }
+        }
+
+        // Check for years with events but no strategy
+        let last_configured = self.years.keys().next_back().copied();
+        let mut missing_years: Vec<i32> = self
+            .events
+            .iter()
+            .map(|(date, _)| date.year())
+            .filter(|year| !self.years.contains_key(year))
+            .collect();
+        missing_years.dedup();
+        for year in missing_years {
+            if last_configured.map(|last| year < last).unwrap_or(false) {
+                ret.errors.push(format!(
+                    "no lot selection strategy for {year}, but later years are configured; \
+                     events from {year} onward would be skipped"
+                ));
+            } else {
+                ret.warnings.push(format!(
+                    "no lot selection strategy for {year}; its events will not be processed"
+                ));
+            }
+        }
+
+        // Check for configured lots which were never deposited
+        let deposited: std::collections::HashSet<LotId> = self
+            .events
+            .iter()
+            .filter_map(|(date, ev)| match ev {
+                Event::BtcDeposit { outpoint, .. } => Some(LotId::from_outpoint(*outpoint)),
+                Event::EthDeposit { .. } => Some(LotId::from_eth_deposit(date)),
+                _ => None,
+            })
+            .collect();
+        let mut unused: Vec<&LotId> = self
+            .lot_db
+            .keys()
+            .filter(|id| !deposited.contains(id))
+            .collect();
+        unused.sort_by_key(|id| id.to_string());
+        for id in unused {
+            ret.warnings.push(format!(
+                "lot {id} is in the configuration but was never deposited directly"
+            ));
+        }
+
+        // Run the tax engine itself
+        match self.run_tax_engine(price_history) {
+            Ok(run) => {
+                ret.warnings.extend(run.warnings);
+                for (id, _, info) in &run.special_lots {
+                    if info.basis_price(None).ok() == Some(Price::ZERO) {
+                        ret.warnings.push(format!("lot {id} has a zero-cost basis"));
+                    }
+                }
+                let n_events = run
+                    .tracker
+                    .events()
+                    .iter()
+                    .filter(|ev| range.contains(ev.date.bare_time()))
+                    .count();
+                info!(
+                    "Tax engine produced {} events in range {}.",
+                    n_events, range
+                );
+            }
+            Err(e) => ret.errors.push(format!("{e:#}")),
+        }
+        ret
+    }
+
+    /// Runs the tax engine once for each lot selection strategy, used for the
+    /// given year in place of the configured one, and totals up that year
+    ///
+    /// Other years keep their configured strategies. The year need not have a
+    /// strategy configured yet, but every year before it must.
+    pub fn compare_lot_strategies(
+        &self,
+        price_history: &crate::price::Historic,
+        year: i32,
+        rates: tax::TaxRates,
+    ) -> anyhow::Result<Vec<StrategyOutcome>> {
+        let mut ret = vec![];
+        for strategy in tax::LotSelectionStrategy::ALL {
+            let mut years = self.years.clone();
+            years.insert(year, *strategy);
+            let TaxRun { tracker, .. } = self
+                .run_tax_engine_with(price_history, &years)
+                .with_context(|| format!("running tax engine with strategy {strategy}"))?;
+
+            let (mut long_term, mut short_term, mut section_1256) =
+                (Price::ZERO, Price::ZERO, Price::ZERO);
+            for ev in tracker.events().iter().filter(|ev| ev.date.year() == year) {
+                if let tax::OpenClose::Close(ref close) = ev.open_close {
+                    match close.gain_loss_type() {
+                        tax::GainType::LongTerm => long_term += close.gain_loss(),
+                        tax::GainType::ShortTerm => short_term += close.gain_loss(),
+                        tax::GainType::Option1256 => section_1256 += close.gain_loss(),
+                    }
+                }
+            }
+            let carryforward = tax::carryforwards(
+                tracker.events(),
+                years.keys().filter(|y| **y <= year),
+                self.initial_carryforward,
+            )[&year];
+            ret.push(StrategyOutcome {
+                strategy: *strategy,
+                configured: self.years.get(&year) == Some(strategy),
+                long_term,
+                short_term,
+                section_1256,
+                liability: carryforward.liability(rates),
+                carryforward,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Outputs a table comparing the lot selection strategies for a year
+    pub fn print_strategy_comparison(
+        &self,
+        price_history: &crate::price::Historic,
+        year: i32,
+        rates: tax::TaxRates,
+    ) -> anyhow::Result<()> {
+        let outcomes = self.compare_lot_strategies(price_history, year, rates)?;
+        let best = outcomes.iter().map(|out| out.liability).min();
+
+        println!("# Year: {year}");
+        println!(
+            "# Tax rates: {}% short-term, {}% long-term",
+            rates.short_term_pct, rates.long_term_pct
+        );
+        println!(
+            "{:<16} {:>14} {:>14} {:>14} {:>14} {:>14} {:>14}",
+            "Strategy",
+            "LT Gain/Loss",
+            "ST Gain/Loss",
+            "1256 Gain/Loss",
+            "Net LT",
+            "Net ST",
+            "Liability",
+        );
+        for out in &outcomes {
+            let mut notes = vec![];
+            if out.configured {
+                notes.push("configured");
+            }
+            if Some(out.liability) == best {
+                notes.push("lowest");
+            }
+            println!(
+                "{:<16} {:>14} {:>14} {:>14} {:>14} {:>14} {:>14}  {}",
+                out.strategy.to_string(),
+                out.long_term.to_string(),
+                out.short_term.to_string(),
+                out.section_1256.to_string(),
+                out.carryforward.long_term.to_string(),
+                out.carryforward.short_term.to_string(),
+                out.liability.to_string(),
+                notes.join(", "),
+            );
+        }
+        Ok(())
+    }
+
+    /// Runs the tax engine over the whole history, returning every tax event
+    pub fn tax_events(
+        &self,
+        price_history: &crate::price::Historic,
+    ) -> anyhow::Result<Vec<tax::Event>> {
+        let TaxRun { tracker, .. } = self.run_tax_engine(price_history)?;
+        Ok(tracker.events().to_vec())
+    }
+
+    /// Dump all currently-open BTC lots in CSV format, for planning future sales
+    ///
+    /// For each lot, compares the tax owed on selling it now at `current_price`
+    /// to the tax owed if it were held until it became long-term. A positive
+    /// "Tax Saved By Waiting" means it is better to wait.
+    pub fn print_open_lots(
+        &self,
+        price_history: &crate::price::Historic,
+        current_price: Price,
+        rates: tax::TaxRates,
+    ) -> anyhow::Result<()> {
+        let TaxRun { tracker, .. } = self.run_tax_engine(price_history)?;
+        let now = UtcTime::now();
+
+        println!("# BTC price: {current_price}");
+        println!(
+            "# Tax rates: {}% short-term, {}% long-term",
+            rates.short_term_pct, rates.long_term_pct
+        );
+        println!(
+            "Lot ID,Acquired,Quantity,Unit Basis,Basis,Current Value,Gain/Loss,Long-Term Date,\
+             Days Until Long-Term,Tax If Sold Now,Tax If Sold Long-Term,Tax Saved By Waiting"
+        );
+        let mut lots: Vec<&lot::Lot> = tracker.open_lots(TaxAsset::Bitcoin).collect();
+        lots.sort_by_key(|lot| lot.date());
+        for lot in lots {
+            let basis = lot.basis();
+            let value = current_price * lot.quantity();
+            let gain = value - basis;
+            // Gains are long-term if held for strictly more than a year
+            let lt_date = lot.date().bare_time() + chrono::Duration::days(366);
+            let days_until_lt = (lt_date - now).num_days().max(0);
+            let tax_now = if days_until_lt > 0 {
+                rates.tax(gain, tax::GainType::ShortTerm)
+            } else {
+                rates.tax(gain, tax::GainType::LongTerm)
+            };
+            let tax_lt = rates.tax(gain, tax::GainType::LongTerm);
+            println!(
+                "{}",
+                CsvPrinter((
+                    lot.id(),
+                    lot.date(),
+                    lot.quantity(),
+                    lot.price(),
+                    basis,
+                    value,
+                    gain,
+                    csv::DateOnly(lt_date),
+                    days_until_lt,
+                    tax_now,
+                    tax_lt,
+                    tax_now - tax_lt,
+                ))
+            );
         }
+        Ok(())
     }
 
     /// Dump the contents of the history in CSV format, attempting to match the end-of-year
@@ -658,11 +2505,18 @@ impl History {
     ///
     /// The expiry timestamps are always UTC 22:00, which is 5PM in the winter but 6PM in the
     /// summer in new york. The assignment timestamps are always UTC 21:00.
+    ///
+    /// All events are processed, since lots carry over from before the start of `range`,
+    /// but only closes and opens within `range` are counted and output.
     pub fn print_tax_csv(
         &self,
         dir_path: &str,
         price_history: &crate::price::Historic,
+        range: DateRange,
+        xlsx: bool,
     ) -> anyhow::Result<()> {
+        use std::fmt::Write as _;
+
         // Write out metadata, in part to make sure we can create files before
         // we do too much heavy lifting.
         let mut metadata = create_text_file(
@@ -676,113 +2530,62 @@ impl History {
         )?;
         writeln!(metadata, "Configuration file hash: {}", self.config_hash)?;
 
-        let mut tracker = tax::PositionTracker::new();
-        for (date, event) in &self.events {
-            debug!("Processing event {:?}", event);
-            if let Some(strat) = self.years.get(&date.year()) {
-                tracker.set_bitcoin_lot_strategy(*strat);
-            } else {
-                warn!(
-                    "Have no tax strategy for year {}. Stopping here.",
-                    date.year()
-                );
-                break;
-            }
-
-            match event {
-                // USD deposits are not tax-relevant
-                Event::UsdDeposit { .. } => continue,
-                // Deposits of BTC cause lots to be become accessible to our tax optimizer
-                Event::BtcDeposit {
-                    amount,
-                    outpoint,
-                    lot_info,
-                } => {
-                    debug!("[deposit] \"BTC\" {} outpoint {}", amount, outpoint);
-                    let lot =
-                        lot::Lot::from_deposit(*outpoint, lot_info.price, *amount, lot_info.date);
-                    tracker.push_lot(date.into(), lot);
-                }
-                // Withdrawals of any kind are not taxable events.
-                //
-                // FIXME BTC withdrawals should take lots out of commission. Not sure how to
-                // choose this. Probably should make the user decide in config file.
-                Event::Withdrawal { .. } => {
-                    debug!("Ignore withdrawal");
-                }
-                // Trades may be
-                Event::Trade {
-                    asset,
-                    price,
-                    size,
-                    fee,
-                } => {
-                    debug!("[trade] \"{}\" {} @ {}; fee {}", asset, size, price, fee,);
-
-                    let adj_price = *price + *fee / *size; // nb `unit_fee` is a signed quantity
-
-                    tracker
-                        .push_trade(*asset, *size, adj_price, date.into())
-                        .with_context(|| format!("pushing trade of {asset} size {size}"))?;
-                }
-                // Expiries are a simple tax event (a straight gain)
-                Event::Expiry {
-                    option,
-                    underlying,
-                    size,
-                } => {
-                    debug!("[expiry] {} {} expired {}", underlying, option, size);
-                    tracker
-                        .push_expiry(*option, *underlying, *size)
-                        .with_context(|| format!("expiring option {option} n {size}"))?;
-                }
-                // Assignments are less simple because we need a price reference to compute
-                // the intrinsic price (with expiries this is assumed to be zero)
-                Event::Assignment {
-                    option,
-                    underlying,
-                    size,
-                    price_ref,
-                } => {
-                    debug!(
-                        "[expiry] {} {} assigned {} at date {}",
-                        underlying, option, size, date
-                    );
-                    let btc_price = match price_ref {
-                        Some(price) => *price,
-                        None => {
-                            // We allow this because otherwise we can't possibly produce
-                            // files until LX gives us their shit, which they take
-                            // forever to do. But arguably it should be a hard error
-                            // because the result will not be so easily justifiable to
-                            // the IRS.
-                            let btc_price = price_history.price_at(date);
-                            warn!(
-                                "Do not have LX price reference for {}; using price {}",
-                                date, btc_price
-                            );
-                            writeln!(
-                                metadata,
-                                "WARNING: used non-official price reference of {} on {} for calculating \
-                                 assignment loss (strike {} size {})",
-                                btc_price.btc_price, date, option.strike, size,
-                            )?;
-                            btc_price.btc_price
-                        }
-                    };
+        writeln!(metadata, "Assignment price policy: {}", self.price_policy)?;
+        writeln!(metadata, "Date range: {range}")?;
+        if self.dust != tax::DustSettings::default() {
+            writeln!(metadata, "Dust: {}", self.dust)?;
+        }
 
-                    tracker
-                        .push_assignment(*option, *underlying, *size, btc_price)
-                        .with_context(|| format!("assignment option {option} n {size}"))?;
-                }
-            };
+        let TaxRun {
+            tracker,
+            warnings,
+            assignment_sources,
+            special_lots,
+        } = self.run_tax_engine(price_history)?;
+        let mut n_warnings = warnings.len();
+        for warning in warnings {
+            writeln!(metadata, "WARNING: {warning}")?;
+        }
+        for skipped in &self.skipped {
+            writeln!(metadata, "SKIPPED RECORD: {skipped}")?;
+        }
+        for (year, totals) in self.fee_totals() {
+            writeln!(
+                metadata,
+                "Trade fees {year} ({}): {} as reported by LX over {} trades; \
+                 charged {} on {} trades, rebated {} on {} trades",
+                self.fee_sign(year),
+                totals.raw,
+                totals.n_trades,
+                totals.charged,
+                totals.n_charged,
+                totals.rebated,
+                totals.n_rebated,
+            )?;
+            if totals.suspicious() {
+                n_warnings += 1;
+                writeln!(
+                    metadata,
+                    "WARNING: rebates in {year} are as large as fees; check the fee sign convention"
+                )?;
+            }
         }
-        tracker.lx_sort_events();
 
+        let carryforwards = tax::carryforwards(
+            tracker.events(),
+            self.years.keys(),
+            self.initial_carryforward,
+        );
+        let mut summaries = BTreeMap::new();
+        let mut summary_years = BTreeMap::new();
         for (year, strat) in &self.years {
-            writeln!(metadata)?;
-            writeln!(metadata, "Year: {year}")?;
-            writeln!(metadata, "    Lot selection strategy: {strat}")?;
+            // Build the section as a string so that it can also go into the
+            // summary sheet of the XLSX output
+            let mut section = String::new();
+            writeln!(section)?;
+            writeln!(section, "Year: {year}")?;
+            writeln!(section, "    Lot selection strategy: {strat}")?;
+            let mut by_asset = BTreeMap::<String, Price>::new();
             let mut n_events = 0;
             let mut total_1256_proceeds = Price::ZERO;
             let mut total_1256_basis = Price::ZERO;
@@ -790,9 +2593,20 @@ impl History {
             let mut total_st_basis = Price::ZERO;
             let mut total_lt_proceeds = Price::ZERO;
             let mut total_lt_basis = Price::ZERO;
-            for ev in tracker.events().iter().filter(|ev| ev.date.year() == *year) {
+            for ev in tracker
+                .events()
+                .iter()
+                .filter(|ev| ev.date.year() == *year && range.contains(ev.date.bare_time()))
+            {
                 n_events += 1;
                 if let tax::OpenClose::Close(ref close) = ev.open_close {
+                    let asset_class = match ev.asset {
+                        TaxAsset::Bitcoin => "BTC".to_string(),
+                        TaxAsset::Ether => "ETH".to_string(),
+                        TaxAsset::NextDay { underlying, .. } => underlying.to_string(),
+                        TaxAsset::Option { underlying, .. } => format!("{underlying} options"),
+                    };
+                    *by_asset.entry(asset_class).or_default() += close.gain_loss();
                     match close.gain_loss_type() {
                         tax::GainType::Option1256 => {
                             total_1256_proceeds += close.proceeds();
@@ -812,37 +2626,143 @@ impl History {
             let total_1256 = total_1256_proceeds - total_1256_basis;
             let total_lt = total_lt_proceeds - total_lt_basis;
             let total_st = total_st_proceeds - total_st_basis;
-            writeln!(metadata, "    Number of events: {n_events}")?;
-            writeln!(metadata, "    Total LT gain/loss: {total_lt}")?;
-            writeln!(metadata, "             (Proceeds: {total_lt_proceeds}")?;
-            writeln!(metadata, "          minus Basis): {total_lt_basis}")?;
-            writeln!(metadata, "    Total ST gain/loss: {total_st}")?;
-            writeln!(metadata, "             (Proceeds: {total_st_proceeds}")?;
-            writeln!(metadata, "          minus Basis): {total_st_basis}")?;
-            writeln!(metadata, "    Total 1256 gain/loss: {total_1256}")?;
-            writeln!(metadata, "             (Proceeds: {total_1256_proceeds}")?;
-            writeln!(metadata, "          minus Basis): {total_1256_basis}")?;
-            let lt = total_lt + total_1256.sixty();
-            let st = total_st + total_1256.forty();
+            writeln!(section, "    Number of events: {n_events}")?;
+            writeln!(section, "    Total LT gain/loss: {total_lt}")?;
+            writeln!(section, "             (Proceeds: {total_lt_proceeds}")?;
+            writeln!(section, "          minus Basis): {total_lt_basis}")?;
+            writeln!(section, "    Total ST gain/loss: {total_st}")?;
+            writeln!(section, "             (Proceeds: {total_st_proceeds}")?;
+            writeln!(section, "          minus Basis): {total_st_basis}")?;
+            writeln!(section, "    Total 1256 gain/loss: {total_1256}")?;
+            writeln!(section, "             (Proceeds: {total_1256_proceeds}")?;
+            writeln!(section, "          minus Basis): {total_1256_basis}")?;
+            let (lt_1256, st_1256) = crate::ledgerx::rules::split_1256(total_1256);
+            let lt = total_lt + lt_1256;
+            let st = total_st + st_1256;
+            summary_years.insert(
+                *year,
+                summary::Year {
+                    lot_selection_strategy: strat.to_string(),
+                    events: n_events,
+                    short_term: summary::Totals::new(total_st_proceeds, total_st_basis),
+                    long_term: summary::Totals::new(total_lt_proceeds, total_lt_basis),
+                    section_1256: summary::Totals::new(total_1256_proceeds, total_1256_basis),
+                    net_long_term: lt.to_string(),
+                    net_short_term: st.to_string(),
+                },
+            );
             writeln!(
-                metadata,
+                section,
                 "Net after 60/40 splitting 1256 and adding to ST/LT: {lt} LT {st} ST"
             )?;
             if st < Price::ZERO {
                 let total = lt + st;
                 if total >= Price::ZERO {
                     // ST losses can cancel LT gains
-                    writeln!(metadata, "    Cancelling, total liability is {total} LT")?;
+                    writeln!(section, "    Cancelling, total liability is {total} LT")?;
                 } else {
                     // ...though once all the LT gains are cancelled, what's left is a ST loss
-                    writeln!(metadata, "    Cancelling, total liability is {total} ST")?;
+                    writeln!(section, "    Cancelling, total liability is {total} ST")?;
+                }
+            }
+            for (asset_class, gain) in &by_asset {
+                writeln!(section, "    Gain/loss from {asset_class}: {gain}")?;
+            }
+            // Carryforward is computed from the whole year even if the output
+            // is restricted to a date range.
+            let cf = &carryforwards[year];
+            writeln!(section, "    Carryforward entering year: {}", cf.entering)?;
+            writeln!(
+                section,
+                "    Net for full year with carryforward: {} LT {} ST",
+                cf.long_term - cf.entering.long_term,
+                cf.short_term - cf.entering.short_term,
+            )?;
+            if cf.ordinary_offset > Price::ZERO {
+                writeln!(
+                    section,
+                    "    Net loss offsetting ordinary income: {}",
+                    cf.ordinary_offset
+                )?;
+            }
+            writeln!(section, "    Carryforward leaving year: {}", cf.leaving)?;
+            write!(metadata, "{section}")?;
+            summaries.insert(*year, section);
+        }
+
+        if !assignment_sources.is_empty() {
+            writeln!(metadata)?;
+            writeln!(metadata, "Assignment price references:")?;
+            for (date, option, size, price, source, fee) in assignment_sources
+                .into_iter()
+                .filter(|(date, ..)| range.contains(*date))
+            {
+                write!(
+                    metadata,
+                    "    {date}: {option} n {size} at {price} (source: {source})"
+                )?;
+                if fee != Price::ZERO {
+                    write!(metadata, "; fee {fee}")?;
+                }
+                writeln!(metadata)?;
+            }
+        }
+
+        if !special_lots.is_empty() {
+            writeln!(metadata)?;
+            writeln!(metadata, "Lots with special basis/holding period rules:")?;
+            for (id, amount, info) in special_lots {
+                // unwraps ok since these succeeded when the lot was created
+                writeln!(
+                    metadata,
+                    "    {id}: {amount}, {}; basis price {} (listed price {}), holding from {}",
+                    info.acquisition,
+                    info.basis_price(None).unwrap(),
+                    info.price,
+                    info.holding_start(None).unwrap(),
+                )?;
+                if info.acquisition == config::AcquisitionType::GiftFmv {
+                    writeln!(
+                        metadata,
+                        "        dual basis: sales below {} use it as basis, holding from {}",
+                        info.fmv_price.unwrap(),
+                        info.received_date.unwrap(),
+                    )?;
+                }
+                if info.basis_price(None).unwrap() == Price::ZERO {
+                    n_warnings += 1;
+                    writeln!(metadata, "        WARNING: zero-cost basis")?;
                 }
             }
         }
 
+        let summary = summary::Summary {
+            version: summary::SUMMARY_VERSION,
+            code_version: env!("CARGO_PKG_VERSION").into(),
+            config_hash: self.config_hash.to_string(),
+            range: range.to_string(),
+            price_data: summary::PriceCoverage::new(price_history),
+            warnings: n_warnings,
+            skipped_records: self.skipped.len(),
+            years: summary_years,
+        };
+        let mut summary_file = create_text_file(
+            format!("{dir_path}/summary.json"),
+            "with a machine-readable summary of this run.",
+        )?;
+        writeln!(
+            summary_file,
+            "{}",
+            serde_json::to_string_pretty(&summary).context("encoding summary")?
+        )?;
+
         let mut reports_lx = HashMap::new();
         let mut reports_full = HashMap::new();
-        for event in tracker.events() {
+        for event in tracker
+            .events()
+            .iter()
+            .filter(|ev| range.contains(ev.date.bare_time()))
+        {
             let year = event.date.year();
             debug!("WRITING OUT date {} event: {:?}", event.date, event);
             // Open LX file for this year
@@ -878,7 +2798,8 @@ impl History {
                 writeln!(
                     new_full,
                     "Event,Date,Quantity,Asset,Price,Lot ID,Old Lot Size,Old Lot Basis,\
-                     New Lot Size,New Lot Basis,Basis,Proceeds,Gain/Loss,Gain/Loss Type"
+                     New Lot Size,New Lot Basis,Basis,Proceeds,Gain/Loss,Gain/Loss Type,Note,\
+                     Parent"
                 )?;
                 e.insert(new_full);
             }
@@ -899,6 +2820,645 @@ impl History {
                 }
             }
         }
+        // Flush the CSV files, since the XLSX output is read back from them
+        drop(reports_lx);
+        drop(reports_full);
+
+        let mut ledger = String::new();
+        let lifetime = ledger::write(
+            &mut ledger,
+            tracker
+                .events()
+                .iter()
+                .filter(|ev| range.contains(ev.date.bare_time())),
+        )?;
+        let mut ledger_file = create_text_file(
+            format!("{dir_path}/all-years-ledger.csv"),
+            "with every close across all years and running gain/loss totals.",
+        )?;
+        write!(ledger_file, "{ledger}")?;
+        info!(
+            "Lifetime realized gain/loss: {} ({} ST, {} LT, {} 1256)",
+            lifetime.total(),
+            lifetime.short_term,
+            lifetime.long_term,
+            lifetime.section_1256,
+        );
+
+        if xlsx {
+            #[cfg(feature = "xlsx")]
+            for (year, summary) in &summaries {
+                self.print_tax_xlsx(dir_path, *year, summary)?;
+            }
+            #[cfg(not(feature = "xlsx"))]
+            return Err(anyhow::Error::msg(
+                "this build has no xlsx support; rebuild with `--features xlsx`",
+            ));
+        }
+
+        self.print_transactions_csv(dir_path, range)
+    }
+
+    /// Write a workbook for a single year, containing the LX-match and full
+    /// reports already written as CSV, and a summary sheet with the metadata
+    /// totals
+    #[cfg(feature = "xlsx")]
+    fn print_tax_xlsx(&self, dir_path: &str, year: i32, summary: &str) -> anyhow::Result<()> {
+        use std::path::Path;
+        use std::{fs, io};
+
+        let mut sheets = vec![];
+        for (name, suffix) in [("LedgerX", "ledgerx"), ("Full", "full")] {
+            let csv_name = format!("{dir_path}/{year}-{suffix}.csv");
+            // If there were no events in the year, no CSV will have been written
+            let rows = match fs::read_to_string(&csv_name) {
+                Ok(data) => data.lines().map(xlsx::csv_row).collect(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e).with_context(|| format!("reading {csv_name}")),
+            };
+            sheets.push(xlsx::Sheet {
+                name: name.into(),
+                header: true,
+                rows,
+            });
+        }
+        sheets.push(xlsx::Sheet {
+            name: "Summary".into(),
+            header: false,
+            rows: summary
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(xlsx::summary_row)
+                .collect(),
+        });
+
+        let name = format!("{dir_path}/{year}.xlsx");
+        if fs::metadata(&name).is_ok() {
+            return Err(anyhow::Error::msg(format!(
+                "File {name} already exists. Refusing to overwrite."
+            )));
+        }
+        info!(
+            "Creating file {} with the reports for {} as a workbook.",
+            name, year
+        );
+        xlsx::write_workbook(Path::new(&name), &sheets)
+    }
+
+    /// Dump a per-year listing of all account transactions (deposits, withdrawals,
+    /// trades with their fees, and option settlements) into the tax output directory
+    ///
+    /// This is not a tax report, but accountants ask for it alongside the gains report.
+    /// Fees are positive when paid; "Net USD" is the change in our USD balance.
+    fn print_transactions_csv(&self, dir_path: &str, range: DateRange) -> anyhow::Result<()> {
+        let mut reports = HashMap::new();
+        for (date, event) in &self.events {
+            let year = date.year();
+            if !self.years.contains_key(&year) || !range.contains(date) {
+                continue;
+            }
+            if let hash_map::Entry::Vacant(e) = reports.entry(year) {
+                let mut new_report = create_text_file(
+                    format!("{dir_path}/{year}-transactions.csv"),
+                    "with a listing of all account transactions",
+                )?;
+                writeln!(
+                    new_report,
+                    "Date,Type,Asset,Quantity,Price,Fee,Net USD,Reference"
+                )?;
+                e.insert(new_report);
+            }
+            let report = reports.get_mut(&year).unwrap();
+
+            let no_price: Option<Price> = None;
+            let csv = match event {
+                Event::UsdDeposit {
+                    amount, reversal, ..
+                } => (
+                    csv::DateTime(date),
+                    "Deposit",
+                    BudgetAsset::Usd,
+                    *amount,
+                    no_price,
+                    no_price,
+                    usd_value(*amount),
+                    reversal_note(date, *reversal),
+                ),
+                Event::BtcDeposit {
+                    amount, outpoint, ..
+                } => (
+                    csv::DateTime(date),
+                    "Deposit",
+                    BudgetAsset::Btc,
+                    Quantity::from(*amount),
+                    no_price,
+                    no_price,
+                    no_price,
+                    format!("lot {}", LotId::from_outpoint(*outpoint)),
+                ),
+                Event::BtcRedeposit { amount, outpoint } => (
+                    csv::DateTime(date),
+                    "Deposit",
+                    BudgetAsset::Btc,
+                    Quantity::from(*amount),
+                    no_price,
+                    no_price,
+                    no_price,
+                    format!("redeposit from own address via {outpoint}"),
+                ),
+                Event::EthDeposit { amount, .. } => (
+                    csv::DateTime(date),
+                    "Deposit",
+                    BudgetAsset::Eth,
+                    *amount,
+                    no_price,
+                    no_price,
+                    no_price,
+                    format!("lot {}", LotId::from_eth_deposit(date)),
+                ),
+                Event::Withdrawal {
+                    amount,
+                    asset,
+                    reversal,
+                    self_transfer,
+                    ..
+                } => (
+                    csv::DateTime(date),
+                    "Withdrawal",
+                    BudgetAsset::from(*asset),
+                    *amount,
+                    no_price,
+                    no_price,
+                    usd_value(-*amount),
+                    if *self_transfer {
+                        "to own address".to_owned()
+                    } else {
+                        reversal_note(date, *reversal)
+                    },
+                ),
+                Event::Trade {
+                    asset,
+                    price,
+                    size,
+                    fee,
+                } => (
+                    csv::DateTime(date),
+                    if size.is_positive() { "Buy" } else { "Sell" },
+                    BudgetAsset::from(*asset),
+                    *size,
+                    Some(*price),
+                    Some(*fee),
+                    Some(-(*price * *size) - *fee),
+                    String::new(),
+                ),
+                Event::Expiry {
+                    option,
+                    underlying,
+                    contract_size,
+                    size,
+                } => (
+                    csv::DateTime(date),
+                    "Expiry",
+                    BudgetAsset::Option {
+                        underlying: *underlying,
+                        option: *option,
+                        contract_size: *contract_size,
+                    },
+                    *size,
+                    no_price,
+                    no_price,
+                    no_price,
+                    String::new(),
+                ),
+                Event::Assignment {
+                    option,
+                    underlying,
+                    contract_size,
+                    size,
+                    fee,
+                    ..
+                } => (
+                    csv::DateTime(date),
+                    "Assignment",
+                    BudgetAsset::Option {
+                        underlying: *underlying,
+                        option: *option,
+                        contract_size: *contract_size,
+                    },
+                    *size,
+                    Some(option.strike),
+                    Some(*fee).filter(|fee| *fee != Price::ZERO),
+                    no_price,
+                    String::new(),
+                ),
+            };
+            writeln!(report, "{}", CsvPrinter(csv))?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract_json() -> serde_json::Value {
+        serde_json::json!({
+                "id": 1,
+                "active": false,
+                "collateral_asset": "USD",
+                "date_exercise": "2021-06-25 22:00:00+0000",
+                "date_expires": "2021-06-25 21:00:00+0000",
+                "date_live": "2021-01-01 05:00:00+0000",
+                "derivative_type": "options_contract",
+                "is_call": false,
+                "is_ecp_only": false,
+                "is_next_day": false,
+                "label": "BTC-Mini-25JUN2021-30000-Put",
+                "min_increment": 100,
+                "multiplier": 100,
+                "name": null,
+                "open_interest": null,
+                "strike_price": 3000000,
+                "type": "put",
+                "underlying_asset": "BTC",
+        })
+    }
+
+    fn position(size: i64, assigned_size: i64) -> Position {
+        let json = serde_json::json!({
+            "size": size,
+            "assigned_size": assigned_size,
+            "has_settled": true,
+            "contract": contract_json(),
+        });
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn settled_sizes() {
+        assert_eq!(position(5, 2).settled_sizes().unwrap(), (-2, -3));
+        assert_eq!(position(-5, 2).settled_sizes().unwrap(), (2, 3));
+        assert_eq!(position(-5, 0).settled_sizes().unwrap(), (0, 5));
+        // Inconsistent records are errors rather than panics
+        assert!(position(-5, 6).settled_sizes().is_err());
+        assert!(position(5, -1).settled_sizes().is_err());
+    }
+
+    #[test]
+    fn choose_deposit_outpoint() {
+        use std::str::FromStr;
+
+        let op = |txid: &str, vout| bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_str(&txid.repeat(64)).unwrap(),
+            vout,
+        };
+        fn choose(
+            candidates: &[bitcoin::OutPoint],
+            reserved: &HashMap<bitcoin::OutPoint, UtcTime>,
+        ) -> anyhow::Result<bitcoin::OutPoint> {
+            let candidates = candidates.iter().map(|op| (*op, None)).collect();
+            super::choose_deposit_outpoint(candidates, reserved).map(|(op, _)| op)
+        }
+        let mut reserved = HashMap::new();
+
+        assert!(choose(&[], &reserved).is_err());
+        // Several outputs of one transaction: take them in order
+        let same_tx = [op("a", 0), op("a", 3)];
+        assert_eq!(choose(&same_tx, &reserved).unwrap(), op("a", 0));
+        reserved.insert(op("a", 0), UtcTime::now());
+        assert_eq!(choose(&same_tx, &reserved).unwrap(), op("a", 3));
+        reserved.insert(op("a", 3), UtcTime::now());
+        let err = choose(&same_tx, &reserved).unwrap_err().to_string();
+        assert!(err.contains(&op("a", 3).to_string()), "{}", err);
+
+        // Outputs of different transactions are ambiguous
+        let err = choose(&[op("b", 1), op("c", 0)], &reserved)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&op("b", 1).to_string()), "{}", err);
+        assert!(err.contains(&op("c", 0).to_string()), "{}", err);
+        // ...unless all but one are reserved
+        assert_eq!(
+            choose(&[op("a", 0), op("b", 1)], &reserved).unwrap(),
+            op("b", 1)
+        );
+    }
+
+    #[test]
+    fn fee_signs() {
+        use bitcoin::hashes::Hash as _;
+
+        let config: Configuration = serde_json::from_value(serde_json::json!({
+            "user": 1,
+            "years": {},
+            "lx_csv": [],
+            "lots": {},
+            "transactions": {},
+            "fee_sign_overrides": { "2021": "charge-negative" },
+        }))
+        .unwrap();
+        let mut history =
+            History::new(&config, bitcoin::hashes::sha256::Hash::all_zeros()).unwrap();
+        let contract: super::super::Contract =
+            serde_json::from_str(&contract_json().to_string()).unwrap();
+        let mut contracts = HashMap::new();
+        contracts.insert(contract.id(), contract);
+
+        // In 2021 fees were reported as debits; later, as charges. In both
+        // payloads the first trade is a taker sale and the second a maker
+        // purchase which got a rebate.
+        let trades_2021: Trades = serde_json::from_str(
+            r#"{"data":[
+                {"contract_id":1,"execution_time":"2021-03-01T15:04:05.123Z","filled_price":50000,"filled_size":4,"side":"ask","fee":-100},
+                {"contract_id":1,"execution_time":"2021-03-02T15:04:05.123Z","filled_price":40000,"filled_size":4,"side":"bid","fee":20}
+            ],"meta":{"next":null}}"#,
+        )
+        .unwrap();
+        let trades_2023: Trades = serde_json::from_str(
+            r#"{"data":[
+                {"contract_id":1,"execution_time":"2023-03-01T15:04:05.123Z","filled_price":50000,"filled_size":4,"side":"ask","fee":100},
+                {"contract_id":1,"execution_time":"2023-03-02T15:04:05.123Z","filled_price":40000,"filled_size":4,"side":"bid","fee":-20}
+            ],"meta":{"next":null}}"#,
+        )
+        .unwrap();
+        history
+            .import_trades(&trades_2021, &contracts, false)
+            .unwrap();
+        history
+            .import_trades(&trades_2023, &contracts, false)
+            .unwrap();
+
+        let fees: Vec<Price> = history
+            .events
+            .iter()
+            .filter_map(|(_, ev)| match ev {
+                Event::Trade { fee, .. } => Some(*fee),
+                _ => None,
+            })
+            .collect();
+        let (charge, rebate) = (Price::from_cents(100), Price::from_cents(-20));
+        assert_eq!(fees, [charge, rebate, charge, rebate]);
+
+        let totals = history.fee_totals();
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[&2021].raw, Price::from_cents(-80));
+        assert_eq!(totals[&2023].raw, Price::from_cents(80));
+        for year in [2021, 2023] {
+            assert_eq!(totals[&year].charged, charge);
+            assert_eq!(totals[&year].rebated, -rebate);
+            assert_eq!(totals[&year].n_trades, 2);
+            assert_eq!(totals[&year].rebated_contracts, 4);
+            assert!(!totals[&year].suspicious());
+        }
+
+        // A maker-heavy year is fine, but rebates the size of fees are not
+        let maker_heavy = FeeTotals {
+            charged: charge,
+            rebated: Price::from_cents(100),
+            n_trades: 6,
+            n_charged: 1,
+            n_rebated: 5,
+            rebated_contracts: 20,
+            ..Default::default()
+        };
+        assert!(!maker_heavy.suspicious());
+        let backward = FeeTotals {
+            rebated: Price::from_cents(500),
+            ..maker_heavy
+        };
+        assert!(backward.suspicious());
+    }
+
+    #[test]
+    fn busted_trades() {
+        use bitcoin::hashes::Hash as _;
+
+        let config: Configuration = serde_json::from_value(serde_json::json!({
+            "user": 1,
+            "years": {},
+            "lx_csv": [],
+            "lots": {},
+            "transactions": {},
+        }))
+        .unwrap();
+        let mut history =
+            History::new(&config, bitcoin::hashes::sha256::Hash::all_zeros()).unwrap();
+        let contract: super::super::Contract =
+            serde_json::from_str(&contract_json().to_string()).unwrap();
+        let mut contracts = HashMap::new();
+        contracts.insert(contract.id(), contract);
+
+        let bust = |size: i64, time: &str| {
+            crate::schema::Record::bust(&super::super::datafeed::Bust {
+                contract_id: super::super::ContractId::from(1),
+                customer_id: None,
+                message_id: super::super::MessageId::from([1; 16]),
+                size: UnknownQuantity::from_i64(size),
+                price: Price::from_cents(50000),
+                timestamp: UtcTime::parse_coinbase(time).unwrap(),
+            })
+        };
+        let records = [
+            // Busts before the trade, or of the other side, do not match it
+            bust(-4, "2023-03-01T15:00:00Z"),
+            bust(4, "2023-03-01T16:00:00Z"),
+            bust(-4, "2023-03-01T16:00:00Z"),
+        ];
+        history.busts = BustedTrade::from_records(&records).unwrap();
+
+        let trades: Trades = serde_json::from_str(
+            r#"{"data":[
+                {"contract_id":1,"execution_time":"2023-03-01T15:04:05.123Z","filled_price":50000,"filled_size":4,"side":"ask","fee":100},
+                {"contract_id":1,"execution_time":"2023-03-02T15:04:05.123Z","filled_price":40000,"filled_size":4,"side":"bid","fee":-20}
+            ],"meta":{"next":null}}"#,
+        )
+        .unwrap();
+        history.import_trades(&trades, &contracts, false).unwrap();
+
+        let sizes: Vec<Quantity> = history
+            .events
+            .iter()
+            .filter_map(|(_, ev)| match ev {
+                Event::Trade { size, .. } => Some(*size),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sizes.len(), 1);
+        assert!(sizes[0].is_positive());
+        assert_eq!(history.busts.len(), 2);
+    }
+
+    #[test]
+    fn assignment_fees() {
+        use bitcoin::hashes::Hash as _;
+
+        let history_with = |fees: serde_json::Value| {
+            let config: Configuration = serde_json::from_value(serde_json::json!({
+                "user": 1,
+                "years": {},
+                "lx_csv": [],
+                "lots": {},
+                "transactions": {},
+                "assignment_fees": fees,
+            }))
+            .unwrap();
+            History::new(&config, bitcoin::hashes::sha256::Hash::all_zeros()).unwrap()
+        };
+        let assignment_fee = |history: &History| {
+            history.events.values().find_map(|ev| match ev {
+                Event::Assignment { fee, .. } => Some(*fee),
+                _ => None,
+            })
+        };
+        let contract: super::super::Contract =
+            serde_json::from_str(&contract_json().to_string()).unwrap();
+        let exported = Price::from_cents(250);
+
+        // A fee from the export is attached to the assignment...
+        let mut history = history_with(serde_json::json!({}));
+        assert!(history.add_assignment_fee(&contract, exported).is_err());
+        for (date, event) in history.position_events(&position(-5, 2)).unwrap() {
+            history.events.insert(date, event);
+        }
+        assert_eq!(assignment_fee(&history), Some(Price::ZERO));
+        history.add_assignment_fee(&contract, exported).unwrap();
+        assert_eq!(assignment_fee(&history), Some(exported));
+
+        // ...unless the configuration file gives one
+        let mut history = history_with(serde_json::json!({ "BTC-Mini-25JUN2021-30000-Put": 300 }));
+        for (date, event) in history.position_events(&position(-5, 2)).unwrap() {
+            history.events.insert(date, event);
+        }
+        history.add_assignment_fee(&contract, exported).unwrap();
+        assert_eq!(assignment_fee(&history), Some(Price::from_cents(300)));
+    }
+
+    #[test]
+    fn ach_reversals() {
+        use bitcoin::hashes::Hash as _;
+
+        let config: Configuration = serde_json::from_value(serde_json::json!({
+            "user": 1,
+            "years": {},
+            "lx_csv": [],
+            "lots": {},
+            "transactions": {},
+        }))
+        .unwrap();
+        let mut history =
+            History::new(&config, bitcoin::hashes::sha256::Hash::all_zeros()).unwrap();
+        let time = |s: &str| UtcTime::parse_coinbase(s).unwrap();
+        let deposit = |status: Option<&str>| Event::UsdDeposit {
+            amount: Quantity::Cents(100_000),
+            status: status.map(String::from),
+            reversal: None,
+        };
+        let withdrawal = |status: Option<&str>| Event::Withdrawal {
+            amount: Quantity::Cents(100_000),
+            asset: DepositAsset::Usd,
+            status: status.map(String::from),
+            reversal: None,
+            self_transfer: false,
+        };
+        // Without statuses, a deposit and withdrawal of the same amount are
+        // not assumed to be a reversal...
+        history
+            .events
+            .insert(time("2023-03-01T15:00:00Z"), deposit(None));
+        history
+            .events
+            .insert(time("2023-03-03T15:00:00Z"), withdrawal(None));
+        // ...but they are if LX says so
+        history
+            .events
+            .insert(time("2023-06-01T15:00:00Z"), deposit(Some("complete")));
+        history.events.insert(
+            time("2023-06-03T15:00:00Z"),
+            withdrawal(Some("ACH returned")),
+        );
+        history.link_ach_reversals();
+
+        let reversals: Vec<Option<UtcTime>> = history
+            .events
+            .iter()
+            .map(|(_, ev)| match ev {
+                Event::UsdDeposit { reversal, .. } | Event::Withdrawal { reversal, .. } => {
+                    *reversal
+                }
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            reversals,
+            [
+                None,
+                None,
+                Some(time("2023-06-03T15:00:00Z")),
+                Some(time("2023-06-01T15:00:00Z")),
+            ]
+        );
+    }
+
+    #[test]
+    fn assignment_price_policy() {
+        use bitcoin::hashes::Hash as _;
+
+        let history_with = |policy: &str| {
+            let config: Configuration = serde_json::from_value(serde_json::json!({
+                "user": 1,
+                "years": {},
+                "lx_csv": [],
+                "lots": {},
+                "transactions": {},
+                "assignment_price_policy": policy,
+                "assignment_price_overrides": { "2023-03-10": 2_100_000 },
+            }))
+            .unwrap();
+            History::new(&config, bitcoin::hashes::sha256::Hash::all_zeros()).unwrap()
+        };
+        let mut price_history = crate::price::Historic::default();
+        price_history.record(crate::price::BitcoinPrice::from_csv("1677600000,20000,0.1").unwrap());
+        let date = UtcTime::parse_coinbase("2023-03-03T21:00:00Z").unwrap();
+        let overridden = UtcTime::parse_coinbase("2023-03-10T21:00:00Z").unwrap();
+        let lx_price = Price::from_cents(1_900_000);
+        let historic = Price::from_cents(2_000_000);
+        let price = |history: &History, date, price_ref| {
+            history.assignment_price(date, Underlying::Btc, price_ref, &price_history)
+        };
+
+        // The default prefers LX's price reference, falling back to our own
+        let history = history_with("prefer-lx");
+        assert_eq!(
+            price(&history, date, Some(lx_price)).unwrap(),
+            (lx_price, PriceRefSource::LedgerX)
+        );
+        assert_eq!(
+            price(&history, date, None).unwrap(),
+            (historic, PriceRefSource::Historic)
+        );
+
+        // Strict mode refuses to fall back
+        let history = history_with("strict");
+        assert_eq!(
+            price(&history, date, Some(lx_price)).unwrap(),
+            (lx_price, PriceRefSource::LedgerX)
+        );
+        assert!(price(&history, date, None).is_err());
+
+        // Historic mode ignores LX entirely
+        let history = history_with("prefer-historic");
+        assert_eq!(
+            price(&history, date, Some(lx_price)).unwrap(),
+            (historic, PriceRefSource::Historic)
+        );
+
+        // Overrides take precedence regardless of policy
+        for policy in ["strict", "prefer-lx", "prefer-historic"] {
+            assert_eq!(
+                price(&history_with(policy), overridden, Some(lx_price)).unwrap(),
+                (Price::from_cents(2_100_000), PriceRefSource::Override)
+            );
+        }
+    }
+}
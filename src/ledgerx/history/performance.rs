@@ -0,0 +1,433 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Performance Measurement
+//!
+//! Replays the history into a daily series of net liquidation values (NLV),
+//! marking BTC at the historic price and options at their Black-Scholes price
+//! at a fixed volatility, alongside the external flows (deposits and
+//! withdrawals). From this we compute the time-weighted return, which measures
+//! the strategy independently of when money was moved in and out, and the
+//! money-weighted return (IRR), which measures what we actually earned.
+//!
+//! Both are compared against a benchmark which puts every deposit into BTC and
+//! holds it.
+//!
+
+use super::{usd_value, Event};
+use crate::units::{DepositAsset, Price, Quantity, TaxAsset, UtcTime};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Cash, BTC and option positions of the account
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Account {
+    usd: Price,
+    btc: bitcoin::SignedAmount,
+    options: HashMap<TaxAsset, i64>,
+}
+
+impl Account {
+    /// Creates a new empty account
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Updates the account with an event, returning the external flow it
+    /// represents (positive for deposits), valued at `btc_price`
+    ///
+    /// Assignments are settled at the intrinsic value of the option, computed
+    /// at `assignment_price`, which must be given for assignments. This matches
+    /// the tax engine, which treats delivery of the underlying separately.
+    pub fn apply(
+        &mut self,
+        event: &Event,
+        btc_price: Price,
+        assignment_price: Option<Price>,
+    ) -> anyhow::Result<Price> {
+        match *event {
+            // ACH reversals net to zero, but we don't want them to look like
+            // flows on two separate days
+            Event::UsdDeposit {
+                reversal: Some(_), ..
+            }
+            | Event::Withdrawal {
+                reversal: Some(_), ..
+            } => Ok(Price::ZERO),
+            // Moves to and from our own addresses are not external flows, and
+            // the coins remain ours while they are away, so we ignore both legs
+            Event::Withdrawal {
+                self_transfer: true,
+                ..
+            }
+            | Event::BtcRedeposit { .. } => Ok(Price::ZERO),
+            Event::UsdDeposit { amount, .. } => {
+                let usd = usd_value(amount)
+                    .with_context(|| format!("USD deposit of non-USD amount {amount}"))?;
+                self.usd += usd;
+                Ok(usd)
+            }
+            Event::BtcDeposit { amount, .. } => {
+                self.btc += amount.to_signed().expect("deposit fits in a signed amount");
+                Ok(btc_price * Quantity::from(amount))
+            }
+            // We have no ETH price history to mark these at
+            Event::EthDeposit { .. } => Err(anyhow::Error::msg(
+                "ETH deposits are not supported, as we have no ETH price history",
+            )),
+            Event::Withdrawal { amount, asset, .. } => match (asset, amount) {
+                (DepositAsset::Usd, _) => {
+                    let usd = usd_value(amount)
+                        .with_context(|| format!("USD withdrawal of non-USD amount {amount}"))?
+                        .abs();
+                    self.usd -= usd;
+                    Ok(-usd)
+                }
+                (DepositAsset::Btc, Quantity::Bitcoin(btc)) => {
+                    self.btc -= btc.abs();
+                    Ok(-(btc_price * Quantity::Bitcoin(btc.abs())))
+                }
+                (DepositAsset::Btc, _) => Err(anyhow::Error::msg(format!(
+                    "BTC withdrawal of non-BTC amount {amount}"
+                ))),
+                (DepositAsset::Eth, _) => Err(anyhow::Error::msg(
+                    "ETH withdrawals are not supported, as we have no ETH price history",
+                )),
+            },
+            Event::Trade {
+                asset,
+                price,
+                size,
+                fee,
+            } => {
+                match size {
+                    Quantity::Bitcoin(btc) => self.btc += btc,
+                    Quantity::Contracts(n) => *self.options.entry(asset).or_default() += n,
+                    _ => {
+                        return Err(anyhow::Error::msg(format!(
+                            "trade of {asset} has unexpected size {size}"
+                        )))
+                    }
+                }
+                self.usd -= price * size + fee;
+                Ok(Price::ZERO)
+            }
+            Event::Expiry {
+                option,
+                underlying,
+                contract_size,
+                size,
+            }
+            | Event::Assignment {
+                option,
+                underlying,
+                contract_size,
+                size,
+                ..
+            } => {
+                let asset = TaxAsset::Option {
+                    underlying,
+                    option,
+                    contract_size,
+                };
+                let n = match size {
+                    Quantity::Contracts(n) => n,
+                    _ => {
+                        return Err(anyhow::Error::msg(format!(
+                            "settlement of {asset} has unexpected size {size}"
+                        )))
+                    }
+                };
+                if let Event::Assignment { fee, .. } = event {
+                    let btc_price = assignment_price
+                        .with_context(|| format!("no BTC price given for assignment of {asset}"))?;
+                    let intrinsic = option.intrinsic_value(btc_price).max(Price::ZERO);
+                    self.usd -= intrinsic * size + fee;
+                }
+                let remaining = self.options.entry(asset).or_default();
+                *remaining += n;
+                if *remaining == 0 {
+                    self.options.remove(&asset);
+                }
+                Ok(Price::ZERO)
+            }
+        }
+    }
+
+    /// The net liquidation value of the account, marking options at the
+    /// Black-Scholes price at volatility `iv`
+    pub fn nlv(&self, now: UtcTime, btc_price: Price, iv: f64) -> Price {
+        let mut ret = self.usd + btc_price * Quantity::Bitcoin(self.btc);
+        for (asset, n) in &self.options {
+            if let TaxAsset::Option { option, .. } = asset {
+                let value = if option.expiry > now {
+                    option.bs_price(now, btc_price, iv)
+                } else {
+                    option.intrinsic_value(btc_price).max(Price::ZERO)
+                };
+                ret += value * Quantity::Contracts(*n);
+            }
+        }
+        ret
+    }
+}
+
+/// The state of the account at the end of a single day
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Mark {
+    /// The day
+    pub date: chrono::NaiveDate,
+    /// BTC price at the end of the day
+    pub btc_price: Price,
+    /// Net external flows during the day, positive for deposits
+    pub flow: Price,
+    /// Net liquidation value at the end of the day
+    pub nlv: Price,
+}
+
+/// Returns over a period
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Returns {
+    /// Value at the start of the period
+    pub start: Price,
+    /// Value at the end of the period
+    pub end: Price,
+    /// Net external flows during the period, positive for deposits
+    pub flows: Price,
+    /// Cumulative time-weighted return, if the account had value at any point
+    pub twr: Option<f64>,
+    /// Annualized money-weighted return, if it could be determined
+    pub irr: Option<f64>,
+}
+
+impl Returns {
+    /// Computes the returns over a period given the value at its start and
+    /// the marks for each of its days
+    ///
+    /// Flows are treated as happening at the start of the day they were made.
+    pub fn compute(start: Price, marks: &[Mark]) -> Self {
+        let mut twr = None;
+        let mut prev = start.to_approx_f64();
+        // Cash flows from our point of view, as (day, amount)
+        let mut cash_flows = vec![(0.0, -prev)];
+        let mut flows = Price::ZERO;
+        for (n, mark) in marks.iter().enumerate() {
+            let (flow, nlv) = (mark.flow.to_approx_f64(), mark.nlv.to_approx_f64());
+            let invested = prev + flow;
+            if invested > 0.0 {
+                twr = Some(twr.unwrap_or(1.0) * nlv / invested);
+            }
+            cash_flows.push((n as f64, -flow));
+            flows += mark.flow;
+            prev = nlv;
+        }
+        cash_flows.push((marks.len() as f64, prev));
+
+        Returns {
+            start,
+            end: marks.last().map(|mark| mark.nlv).unwrap_or(start),
+            flows,
+            twr: twr.map(|growth| growth - 1.0),
+            irr: irr(&cash_flows),
+        }
+    }
+
+    /// The returns of a benchmark which converts the starting value, and every
+    /// flow, to BTC and holds it
+    ///
+    /// As flows are treated as happening at the start of the day, they are
+    /// converted at the previous day's closing price, or `start_btc_price`
+    /// for the first day. If that is zero, the first day's price is used.
+    pub fn hodl(start: Price, start_btc_price: Price, marks: &[Mark]) -> Self {
+        let mut btc = if start_btc_price > Price::ZERO {
+            start / start_btc_price
+        } else {
+            0.0
+        };
+        let mut prev_price = start_btc_price;
+        let hodl_marks: Vec<Mark> = marks
+            .iter()
+            .map(|mark| {
+                if prev_price == Price::ZERO {
+                    prev_price = mark.btc_price;
+                }
+                btc += mark.flow / prev_price;
+                prev_price = mark.btc_price;
+                Mark {
+                    nlv: mark.btc_price.scale_approx(btc),
+                    ..*mark
+                }
+            })
+            .collect();
+        Returns::compute(start, &hodl_marks)
+    }
+}
+
+/// Formats an optional ratio as a percentage
+struct Pct(Option<f64>);
+
+impl fmt::Display for Pct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(x) => write!(f, "{:.2}%", x * 100.0),
+            None => f.write_str("n/a"),
+        }
+    }
+}
+
+/// A line of the performance report
+pub struct Line<'s> {
+    /// Name of the period
+    pub period: &'s str,
+    /// Returns of the account
+    pub account: Returns,
+    /// Returns of the buy-and-hold benchmark
+    pub hodl: Returns,
+}
+
+impl Line<'_> {
+    /// Header for the report
+    pub const HEADER: &'static str = "Period,Start NLV,End NLV,Net flows,TWR,IRR (annualized),\
+                                      BTC hodl TWR,BTC hodl IRR (annualized)";
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},{}",
+            self.period,
+            self.account.start,
+            self.account.end,
+            self.account.flows,
+            Pct(self.account.twr),
+            Pct(self.account.irr),
+            Pct(self.hodl.twr),
+            Pct(self.hodl.irr),
+        )
+    }
+}
+
+/// Solves for the annualized rate at which a series of (day, amount) cash
+/// flows has zero net present value
+fn irr(cash_flows: &[(f64, f64)]) -> Option<f64> {
+    // Search over the continuously-compounded daily rate, so that any value
+    // is a valid rate
+    let npv = |rate: f64| -> f64 {
+        cash_flows
+            .iter()
+            .map(|(day, amount)| amount * (-rate * day).exp())
+            .sum()
+    };
+    let (mut lo, mut hi) = (-0.05, 0.05);
+    let (npv_lo, npv_hi) = (npv(lo), npv(hi));
+    if !npv_lo.is_finite() || !npv_hi.is_finite() || npv_lo.signum() == npv_hi.signum() {
+        return None;
+    }
+    for _ in 0..200 {
+        let mid = (lo + hi) / 2.0;
+        if npv(mid).signum() == npv_lo.signum() {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Some(((lo + hi) / 2.0 * 365.0).exp() - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn marks(series: &[(i64, i64, i64)]) -> Vec<Mark> {
+        let start = chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+        series
+            .iter()
+            .enumerate()
+            .map(|(n, &(btc_price, flow, nlv))| Mark {
+                date: start + chrono::Duration::days(n as i64),
+                btc_price: Price::from(rust_decimal::Decimal::from(btc_price)),
+                flow: Price::from(rust_decimal::Decimal::from(flow)),
+                nlv: Price::from(rust_decimal::Decimal::from(nlv)),
+            })
+            .collect()
+    }
+
+    fn close(x: Option<f64>, y: f64) -> bool {
+        x.is_some_and(|x| (x - y).abs() < 1e-6)
+    }
+
+    #[test]
+    fn returns() {
+        // Up 10% on 1000, then a deposit of 1000 which loses 10%: TWR ignores
+        // the size of the flows, IRR does not
+        let series = marks(&[(100, 1000, 1100), (100, 1000, 1890)]);
+        let ret = Returns::compute(Price::ZERO, &series);
+        assert_eq!(ret.flows, Price::from_str("2000").unwrap());
+        assert!(close(ret.twr, 1.1 * 0.9 - 1.0), "{:?}", ret);
+        assert!(ret.irr.unwrap() < 0.0, "{:?}", ret);
+
+        // With no flows, IRR is just the annualized TWR
+        let series = marks(&[(100, 0, 1010), (100, 0, 1020)]);
+        let ret = Returns::compute(Price::from_str("1000").unwrap(), &series);
+        assert!(close(ret.twr, 0.02), "{:?}", ret);
+        assert!(close(ret.irr, 1.02f64.powf(365.0 / 2.0) - 1.0), "{:?}", ret);
+
+        // An empty account has no returns
+        let ret = Returns::compute(Price::ZERO, &marks(&[(100, 0, 0)]));
+        assert_eq!(ret.twr, None);
+        assert_eq!(ret.irr, None);
+
+        // Holding BTC tracks the BTC price, including for later deposits
+        let series = marks(&[(100, 1000, 0), (110, 0, 0), (121, 1100, 0)]);
+        let hodl = Returns::hodl(Price::ZERO, Price::ZERO, &series);
+        assert!(close(hodl.twr, 0.21), "{:?}", hodl);
+        assert_eq!(hodl.end, Price::from_str("2420").unwrap());
+    }
+
+    #[test]
+    fn self_transfers() {
+        let price = Price::from_str("20000").unwrap();
+        let mut account = Account::new();
+        let deposit = Event::UsdDeposit {
+            amount: Quantity::Cents(2_000_000),
+            status: None,
+            reversal: None,
+        };
+        assert_eq!(account.apply(&deposit, price, None).unwrap(), price);
+
+        // Sending coins to our own address and bringing them back is not a flow
+        let withdrawal = Event::Withdrawal {
+            amount: Quantity::Bitcoin(bitcoin::SignedAmount::from_sat(-50_000_000)),
+            asset: DepositAsset::Btc,
+            status: None,
+            reversal: None,
+            self_transfer: true,
+        };
+        let redeposit = Event::BtcRedeposit {
+            amount: bitcoin::Amount::from_sat(50_000_000),
+            outpoint: bitcoin::OutPoint::null(),
+        };
+        assert_eq!(
+            account.apply(&withdrawal, price, None).unwrap(),
+            Price::ZERO
+        );
+        let now = UtcTime::parse_coinbase("2023-03-01T15:00:00Z").unwrap();
+        assert_eq!(account.nlv(now, price, 0.5), price);
+        assert_eq!(account.apply(&redeposit, price, None).unwrap(), Price::ZERO);
+        assert_eq!(account.nlv(now, price, 0.5), price);
+    }
+}
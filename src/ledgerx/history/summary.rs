@@ -0,0 +1,164 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Tax Run Summary
+//!
+//! A machine-readable summary of a `tax-history` run, written alongside the
+//! human-readable metadata as `summary.json`. It contains only the bottom-line
+//! figures for each year, along with enough about the inputs (configuration
+//! hash, code version, price data coverage) to explain why two runs differ.
+//!
+//! The summary deliberately omits the time of the run, so that two runs over
+//! the same inputs produce identical files. As with the records in the
+//! `schema` module, amounts are strings, and any change to the format must
+//! bump [`SUMMARY_VERSION`].
+//!
+
+use crate::price::Historic;
+use crate::units::Price;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version of the summary format
+pub const SUMMARY_VERSION: u32 = 1;
+
+/// Summary of a whole tax run
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    /// Version of the format of this summary
+    pub version: u32,
+    /// Version of the software which produced the run
+    pub code_version: String,
+    /// SHA256 hash of the configuration file
+    pub config_hash: String,
+    /// Date range the run was limited to
+    pub range: String,
+    /// Extent of the price data available to the run
+    pub price_data: PriceCoverage,
+    /// Number of warnings written to the metadata
+    pub warnings: usize,
+    /// Number of records skipped in lenient mode
+    pub skipped_records: usize,
+    /// Totals for each year
+    pub years: BTreeMap<i32, Year>,
+}
+
+/// Extent of the price data available to a run
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PriceCoverage {
+    /// Time of the earliest price, in RFC 3339 format
+    pub first: Option<String>,
+    /// Time of the latest price, in RFC 3339 format
+    pub last: Option<String>,
+    /// Number of price points
+    pub points: usize,
+}
+
+impl PriceCoverage {
+    /// Summarizes the extent of some price data
+    pub fn new(prices: &Historic) -> Self {
+        let (first, last) = match prices.first_last() {
+            Some((first, last)) => (
+                Some(first.format("%FT%TZ").to_string()),
+                Some(last.format("%FT%TZ").to_string()),
+            ),
+            None => (None, None),
+        };
+        PriceCoverage {
+            first,
+            last,
+            points: prices.len(),
+        }
+    }
+}
+
+/// Totals for a single tax year
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Year {
+    /// Lot selection strategy used for the year
+    pub lot_selection_strategy: String,
+    /// Number of tax events (lot opens and closes) in the year
+    pub events: usize,
+    /// Short-term gains
+    pub short_term: Totals,
+    /// Long-term gains
+    pub long_term: Totals,
+    /// Section 1256 gains
+    pub section_1256: Totals,
+    /// Net long-term gain, after adding 60% of the 1256 gain
+    pub net_long_term: String,
+    /// Net short-term gain, after adding 40% of the 1256 gain
+    pub net_short_term: String,
+}
+
+/// Proceeds, basis and gain of a single kind of gain
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Totals {
+    /// Total proceeds
+    pub proceeds: String,
+    /// Total basis
+    pub basis: String,
+    /// Proceeds minus basis
+    pub gain_loss: String,
+}
+
+impl Totals {
+    /// Constructs the totals from a proceeds and basis
+    pub fn new(proceeds: Price, basis: Price) -> Self {
+        Totals {
+            proceeds: proceeds.to_string(),
+            basis: basis.to_string(),
+            gain_loss: (proceeds - basis).to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn roundtrip() {
+        let price = |s: &str| Price::from_str(s).unwrap();
+        let mut years = BTreeMap::new();
+        years.insert(
+            2023,
+            Year {
+                lot_selection_strategy: "ledgerx-fifo".into(),
+                events: 12,
+                short_term: Totals::new(price("1000"), price("1250")),
+                long_term: Totals::new(Price::ZERO, Price::ZERO),
+                section_1256: Totals::new(price("5000"), price("3000")),
+                net_long_term: price("1200").to_string(),
+                net_short_term: price("550").to_string(),
+            },
+        );
+        let summary = Summary {
+            version: SUMMARY_VERSION,
+            code_version: "0.1.0".into(),
+            config_hash: "00".repeat(32),
+            range: "all dates".into(),
+            price_data: PriceCoverage::new(&Historic::default()),
+            warnings: 1,
+            skipped_records: 0,
+            years,
+        };
+        assert_eq!(summary.years[&2023].short_term.gain_loss, "-250.00");
+        assert_eq!(summary.price_data.first, None);
+
+        let json = serde_json::to_string(&summary).unwrap();
+        assert_eq!(serde_json::from_str::<Summary>(&json).unwrap(), summary);
+    }
+}
@@ -20,13 +20,19 @@
 
 use crate::{
     csv,
-    ledgerx::history::lot::{Close, CloseType, Lot, OpenType},
-    units::{Price, Quantity, TaxAsset, Underlying, UtcTime},
+    ledgerx::history::lot::{Close, CloseType, Id as LotId, Lot, OpenType, Synthetic},
+    ledgerx::rules::{split_1256, SECTION_1256_LONG_TERM},
+    units::{ContractSize, Price, Quantity, TaxAsset, Underlying, UtcTime},
 };
 use anyhow::Context;
 use log::debug;
 use serde::Deserialize;
-use std::{cmp, collections::HashMap, fmt, ops};
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryFrom,
+    fmt, ops,
+};
 
 /// Strategy used to choose Bitcoin lots
 ///
@@ -45,6 +51,14 @@ pub enum LotSelectionStrategy {
     HighestFirst,
 }
 
+impl LotSelectionStrategy {
+    /// Every strategy, for comparing them against each other
+    pub const ALL: &'static [LotSelectionStrategy] = &[
+        LotSelectionStrategy::LedgerXFifo,
+        LotSelectionStrategy::HighestFirst,
+    ];
+}
+
 impl Default for LotSelectionStrategy {
     /// Default to using LX's strategy
     fn default() -> Self {
@@ -61,6 +75,51 @@ impl fmt::Display for LotSelectionStrategy {
     }
 }
 
+/// Thresholds below which residual coin lots are treated as dust
+///
+/// We trade in hundredths of a coin, but deposit arbitrary amounts, so
+/// closes can leave behind tiny remainders of lots. By default these are
+/// left alone; with a threshold set, they are merged into the lot which
+/// would be chosen next by the lot selection strategy.
+///
+/// Dust is still in the account, so it cannot be closed without proceeds;
+/// coins which actually leave the account, e.g. as fees, are closed where
+/// they leave.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Deserialize)]
+pub struct DustSettings {
+    /// Threshold for BTC lots, in satoshis; 0 to leave BTC dust alone
+    #[serde(default)]
+    pub btc_sats: u64,
+    /// Threshold for ETH lots, in gwei; 0 to leave ETH dust alone
+    #[serde(default)]
+    pub eth_gwei: u64,
+}
+
+impl fmt::Display for DustSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "merge lots below {} sats (BTC) or {} gwei (ETH)",
+            self.btc_sats, self.eth_gwei,
+        )
+    }
+}
+
+impl DustSettings {
+    /// The threshold for lots of the given asset, if dust is handled for it
+    fn threshold(&self, asset: TaxAsset) -> Option<Quantity> {
+        match asset {
+            TaxAsset::Bitcoin if self.btc_sats > 0 => {
+                Some(bitcoin::Amount::from_sat(self.btc_sats).into())
+            }
+            TaxAsset::Ether if self.eth_gwei > 0 => Some(Quantity::Ether(
+                i64::try_from(self.eth_gwei).unwrap_or(i64::MAX),
+            )),
+            _ => None,
+        }
+    }
+}
+
 /// Wrapper around a date that will output time to the nearest second in 3339 format
 #[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Debug)]
 pub struct TaxDate(UtcTime);
@@ -109,6 +168,179 @@ impl From<UtcTime> for TaxDate {
     }
 }
 
+/// Marginal tax rates used for planning purposes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TaxRates {
+    /// Rate applied to short-term gains, in percent
+    pub short_term_pct: u32,
+    /// Rate applied to long-term gains, in percent
+    pub long_term_pct: u32,
+}
+
+impl Default for TaxRates {
+    fn default() -> Self {
+        TaxRates {
+            short_term_pct: 37,
+            long_term_pct: 20,
+        }
+    }
+}
+
+impl TaxRates {
+    /// The tax owed on a given gain (or saved on a loss)
+    pub fn tax(&self, gain: Price, ty: GainType) -> Price {
+        let pct = match ty {
+            GainType::ShortTerm => f64::from(self.short_term_pct),
+            GainType::LongTerm => f64::from(self.long_term_pct),
+            GainType::Option1256 => {
+                SECTION_1256_LONG_TERM * f64::from(self.long_term_pct)
+                    + (1.0 - SECTION_1256_LONG_TERM) * f64::from(self.short_term_pct)
+            }
+        };
+        gain.scale_approx(pct / 100.0)
+    }
+}
+
+/// Maximum net capital loss which may offset ordinary income each year, in dollars
+const ORDINARY_OFFSET_DOLLARS: i64 = 3000;
+
+/// Most of a withdrawal to our own address which may fail to come back
+/// before we take the shortfall to be fees, rather than a partial redeposit
+const MAX_TRANSFER_FEE: bitcoin::Amount = bitcoin::Amount::from_sat(100_000);
+
+/// Capital losses carried forward from previous years
+///
+/// Losses keep their character when carried forward, so these are tracked
+/// separately. Both are given as positive numbers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Deserialize)]
+pub struct Carryforward {
+    /// Short-term loss carried forward, in cents in the config file
+    #[serde(default, deserialize_with = "crate::units::deserialize_cents")]
+    pub short_term: Price,
+    /// Long-term loss carried forward, in cents in the config file
+    #[serde(default, deserialize_with = "crate::units::deserialize_cents")]
+    pub long_term: Price,
+}
+
+impl fmt::Display for Carryforward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ST {} LT", self.short_term, self.long_term)
+    }
+}
+
+/// The effect of a single year's gains and losses on the carryforward
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CarryforwardYear {
+    /// Carryforward entering the year
+    pub entering: Carryforward,
+    /// Net short-term gain for the year, after splitting 1256 gains 60/40
+    pub short_term: Price,
+    /// Net long-term gain for the year, after splitting 1256 gains 60/40
+    pub long_term: Price,
+    /// Net capital loss used to offset ordinary income, as a positive number
+    pub ordinary_offset: Price,
+    /// Carryforward leaving the year
+    pub leaving: Carryforward,
+}
+
+impl Carryforward {
+    /// Applies a year's net short- and long-term gains to the carryforward
+    ///
+    /// Follows the IRS capital loss carryover worksheet: carried losses are
+    /// netted against gains of the same character, then against each other,
+    /// and any remaining net loss offsets up to $3000 of ordinary income,
+    /// taken from short-term losses first.
+    pub fn apply(self, short_term: Price, long_term: Price) -> CarryforwardYear {
+        let net_st = short_term - self.short_term;
+        let net_lt = long_term - self.long_term;
+        let total = net_st + net_lt;
+
+        let mut ordinary_offset = Price::ZERO;
+        let mut leaving = Carryforward::default();
+        if total < Price::ZERO {
+            let max_offset = Price::from(rust_decimal::Decimal::from(ORDINARY_OFFSET_DOLLARS));
+            ordinary_offset = cmp::min(-total, max_offset);
+            if net_st < Price::ZERO && net_lt < Price::ZERO {
+                let st_offset = cmp::min(-net_st, ordinary_offset);
+                leaving.short_term = -net_st - st_offset;
+                leaving.long_term = -net_lt - (ordinary_offset - st_offset);
+            } else if net_st < Price::ZERO {
+                leaving.short_term = -total - ordinary_offset;
+            } else {
+                leaving.long_term = -total - ordinary_offset;
+            }
+        }
+
+        CarryforwardYear {
+            entering: self,
+            short_term,
+            long_term,
+            ordinary_offset,
+            leaving,
+        }
+    }
+}
+
+impl CarryforwardYear {
+    /// The estimated tax owed for the year, or saved if negative
+    ///
+    /// After the carryforward, a net loss of one character cancels a net gain
+    /// of the other, and the remainder is taxed at the rate of whichever is
+    /// larger. A net loss saves tax on the ordinary income it offsets, which
+    /// is taxed at the short-term rate.
+    pub fn liability(&self, rates: TaxRates) -> Price {
+        let net_st = self.short_term - self.entering.short_term;
+        let net_lt = self.long_term - self.entering.long_term;
+        let total = net_st + net_lt;
+        if total < Price::ZERO {
+            -rates.tax(self.ordinary_offset, GainType::ShortTerm)
+        } else if net_st < Price::ZERO {
+            rates.tax(total, GainType::LongTerm)
+        } else if net_lt < Price::ZERO {
+            rates.tax(total, GainType::ShortTerm)
+        } else {
+            rates.tax(net_st, GainType::ShortTerm) + rates.tax(net_lt, GainType::LongTerm)
+        }
+    }
+}
+
+/// Computes the carryforward through each of the given years, in order
+///
+/// The carryforward must be computed from complete years, so this considers
+/// every event regardless of any date range used for output.
+pub fn carryforwards<'a, I: IntoIterator<Item = &'a i32>>(
+    events: &[Event],
+    years: I,
+    initial: Carryforward,
+) -> BTreeMap<i32, CarryforwardYear> {
+    let mut gains = HashMap::<i32, (Price, Price)>::new();
+    for ev in events {
+        if let OpenClose::Close(ref close) = ev.open_close {
+            let entry = gains.entry(ev.date.year()).or_default();
+            let gain = close.gain_loss();
+            match close.gain_loss_type() {
+                GainType::ShortTerm => entry.0 += gain,
+                GainType::LongTerm => entry.1 += gain,
+                GainType::Option1256 => {
+                    let (lt, st) = split_1256(gain);
+                    entry.0 += st;
+                    entry.1 += lt;
+                }
+            }
+        }
+    }
+
+    let mut ret = BTreeMap::new();
+    let mut current = initial;
+    for year in years {
+        let (st, lt) = gains.get(year).copied().unwrap_or_default();
+        let result = current.apply(st, lt);
+        current = result.leaving;
+        ret.insert(*year, result);
+    }
+    ret
+}
+
 /// Whether cap gains are short or long term, or 1256 (60% long / 40% short)
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum GainType {
@@ -176,11 +408,12 @@ impl Position {
         date: TaxDate,
         open_ty: OpenType,
         close_ty: CloseType,
-        synthetic: Option<crate::option::PutCall>,
+        synthetic: Option<Synthetic>,
         lot_selection_strat: LotSelectionStrategy,
     ) -> anyhow::Result<(Vec<Close>, Option<Lot>)> {
         if self.has_same_direction(quantity) {
-            let new_lot = Lot::new(self.asset, quantity, price, date, open_ty);
+            let new_lot =
+                Lot::new(self.asset, quantity, price, date, open_ty).with_synthetic(synthetic);
             self.queue.insert(new_lot.sort_date(), new_lot.clone());
             Ok((vec![], Some(new_lot)))
         } else {
@@ -191,7 +424,7 @@ impl Position {
             } {
                 let existing_qty = existing_lot.quantity();
                 let (close, partial) = existing_lot
-                    .close(quantity, price, date, close_ty, synthetic)
+                    .close(quantity, price, date, close_ty, synthetic.clone())
                     .with_context(|| {
                         format!(
                             "Closing {} lot, qty {quantity} price {price} date {date}",
@@ -213,7 +446,8 @@ impl Position {
             // If we get to this point we ran out of things to close, so create
             // a new lot and return.
             if quantity.is_nonzero() {
-                let new_lot = Lot::new(self.asset, quantity, price, date, open_ty);
+                let new_lot =
+                    Lot::new(self.asset, quantity, price, date, open_ty).with_synthetic(synthetic);
                 self.queue.insert(new_lot.sort_date(), new_lot.clone());
                 Ok((closes, Some(new_lot)))
             } else {
@@ -243,7 +477,11 @@ pub struct Event {
 pub struct PositionTracker {
     positions: HashMap<TaxAsset, Position>,
     bitcoin_strat: LotSelectionStrategy,
+    dust: DustSettings,
     events: Vec<Event>,
+    /// BTC lots withdrawn to our own addresses and not yet redeposited, one
+    /// batch per withdrawal, in the order they were withdrawn
+    in_transit: VecDeque<VecDeque<Lot>>,
 }
 
 impl PositionTracker {
@@ -252,7 +490,7 @@ impl PositionTracker {
         Default::default()
     }
 
-    /// Update the lot-selection strategy for Bitcoin.
+    /// Update the lot-selection strategy for Bitcoin (and Ether).
     ///
     /// Note that this must be called *during creation of the tracker*, i.e.
     /// when you are calling the `push_*` functions. Once you are iterating
@@ -263,6 +501,66 @@ impl PositionTracker {
         self.bitcoin_strat = strat;
     }
 
+    /// Sets the thresholds below which residual coin lots are treated as dust
+    pub fn set_dust_settings(&mut self, dust: DustSettings) {
+        self.dust = dust;
+    }
+
+    /// Handles the dust, if any, left behind by a set of closes of a coin
+    ///
+    /// Only a lot which was partly closed can be left as dust, and only the
+    /// last of a set of closes can be partial.
+    ///
+    /// Dust is only merged into a lot in the same holding-period class, i.e.
+    /// if both lots are already long-term or both were opened at the same
+    /// time; otherwise it is left alone.
+    fn sweep_dust(&mut self, asset: TaxAsset, closes: &[Close], date: TaxDate) {
+        let (threshold, last) = match (self.dust.threshold(asset), closes.last()) {
+            (Some(threshold), Some(last)) => (threshold, last),
+            _ => return,
+        };
+        let strat = self.bitcoin_strat;
+        let pos = match self.positions.get_mut(&asset) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let (sort_date, dust) = match pos
+            .queue
+            .pop_first_where(|lot| lot.id() == last.open_id() && lot.quantity().abs() < threshold)
+        {
+            Some(dust) => dust,
+            None => return,
+        };
+        let next = match strat {
+            LotSelectionStrategy::HighestFirst => pos.queue.pop_max(|lot| lot.price()),
+            LotSelectionStrategy::LedgerXFifo => pos.queue.pop_first(),
+        };
+        match next {
+            Some((next_date, mut next))
+                if !next.has_dual_basis()
+                    && !dust.has_dual_basis()
+                    && (next.date() == dust.date()
+                        || (next.is_long_term_at(date) && dust.is_long_term_at(date))) =>
+            {
+                debug!("[dust] merging {} into {}", dust, next);
+                next.absorb(dust);
+                pos.queue.insert(next_date, next);
+            }
+            Some((next_date, next)) => {
+                debug!(
+                    "[dust] not merging {} into {}: different holding periods or bases",
+                    dust, next
+                );
+                pos.queue.insert(next_date, next);
+                pos.queue.insert(sort_date, dust);
+            }
+            None => {
+                // Nothing to merge into; the dust is all that is left
+                pos.queue.insert(sort_date, dust);
+            }
+        }
+    }
+
     /// Helper function to log a set of closes and opens
     ///
     /// Returns the number of loses
@@ -300,11 +598,11 @@ impl PositionTracker {
             lot.sort_date()
         );
         // Assert that deposits do not close any positions (since we cannot have
-        // a short BTC position)
+        // a short BTC or ETH position)
         let pos = self
             .positions
             .entry(lot.asset())
-            .or_insert(Position::new(TaxAsset::Bitcoin));
+            .or_insert(Position::new(lot.asset()));
         assert!(
             pos.has_same_direction(lot.quantity()),
             "Tried to directly insert {} but had an opposing position open",
@@ -324,17 +622,17 @@ impl PositionTracker {
         &mut self,
         option: crate::option::Option,
         underlying: Underlying,
+        contract_size: ContractSize,
         size: Quantity,
     ) -> anyhow::Result<usize> {
-        let asset = TaxAsset::Option { underlying, option };
+        let asset = TaxAsset::Option {
+            underlying,
+            option,
+            contract_size,
+        };
         debug!("[position-tracker] expiry of asset {} size {}", asset, size);
         // Force expiry date to match LX goofiness
-        let expiry: TaxDate = if option.expiry.year() <= 2022 {
-            option.expiry.forced_to_hour(22).into()
-        } else {
-            option.expiry.forced_to_hour(22).into()
-            //option.expiry.into()
-        };
+        let expiry: TaxDate = crate::ledgerx::expiry::tax_date(option.expiry).into();
         let pos = match self.positions.get_mut(&asset) {
             Some(pos) => pos,
             None => {
@@ -365,7 +663,8 @@ impl PositionTracker {
         }
         // In 2022+, expiries happen after assignments.
         // This is essentially just a sanity check.
-        if expiry.year() > 2021 && !pos.queue.is_empty() {
+        if !crate::ledgerx::expiry::expiry_before_assignment(option.expiry) && !pos.queue.is_empty()
+        {
             return Err(anyhow::Error::msg(format!(
                 "done expiry of {asset} but position not fully closed; remaining {}",
                 pos.total_size()
@@ -384,16 +683,22 @@ impl PositionTracker {
         &mut self,
         option: crate::option::Option,
         underlying: Underlying,
+        contract_size: ContractSize,
         size: Quantity,
         btc_price: Price,
+        fee: Price,
     ) -> anyhow::Result<usize> {
-        let asset = TaxAsset::Option { underlying, option };
+        let asset = TaxAsset::Option {
+            underlying,
+            option,
+            contract_size,
+        };
         debug!(
             "[position-tracker] assignment of asset {} size {}",
             asset, size
         );
         // Force expiry date to match LX goofiness
-        let expiry: TaxDate = option.expiry.forced_to_hour(22).into();
+        let expiry: TaxDate = crate::ledgerx::expiry::tax_date(option.expiry).into();
         let pos = match self.positions.get_mut(&asset) {
             Some(pos) => pos,
             None => {
@@ -436,7 +741,7 @@ impl PositionTracker {
             self.positions.remove(&asset);
         }
 
-        // Each close also triggers a synthetic BTC trade of the same amount. Notice that
+        // Each close also triggers a synthetic BTC (or ETH) trade of the same amount. Notice that
         // for tax purposes, after exercising we exchange Bitcoin **at the market price**,
         // not **at the strike price**. This is a bit confusing because of course, in the
         // trading interface, it appears that you get assigned and forced to trade at the
@@ -446,28 +751,40 @@ impl PositionTracker {
         // short-term loss) and getting Bitcoin at a favorable basis. The IRS instead
         // wants the loss to be taxed as 1256 and for the Bitcoin's basis to be the
         // actual market price. Ok, fair enough.
+        //
+        // Any fee charged on the assignment is spread over the synthetic trades, as
+        // with trade fees raising the basis of the BTC bought on a put assignment
+        // and lowering the proceeds of the BTC sold on a call assignment.
+        let coin = TaxAsset::from(underlying);
+        let total_coin = match option.pc {
+            crate::option::Call => (-size).coin_equivalent(),
+            crate::option::Put => size.coin_equivalent(),
+        };
+        let btc_price = if fee == Price::ZERO || total_coin.is_zero() {
+            btc_price
+        } else {
+            btc_price + fee / total_coin
+        };
         let n_closes = closes.len();
         for close in closes {
-            let btc_qty = match option.pc {
-                crate::option::Call => (-close.quantity()).btc_equivalent().into(),
-                crate::option::Put => close.quantity().btc_equivalent().into(),
+            let coin_qty = match option.pc {
+                crate::option::Call => (-close.quantity()).coin_equivalent(),
+                crate::option::Put => close.quantity().coin_equivalent(),
             };
             debug!(
-                "Because of assignment of {} units of {}, creating synthetic BTC trade of {}",
+                "Because of assignment of {} units of {}, creating synthetic {} trade of {}",
                 close.quantity(),
                 asset,
-                btc_qty,
+                coin,
+                coin_qty,
             );
-            // Note: anonyingly have to re-look-up bitcoin position on every loop
+            // Note: anonyingly have to re-look-up coin position on every loop
             // iteration because the borrowck complains about the self.push_events
             // below.
-            let bitcoin_pos = self
-                .positions
-                .entry(TaxAsset::Bitcoin)
-                .or_insert(Position::new(TaxAsset::Bitcoin));
-            let (btc_closes, btc_open) = bitcoin_pos
+            let coin_pos = self.positions.entry(coin).or_insert(Position::new(coin));
+            let (coin_closes, coin_open) = coin_pos
                 .add(
-                    btc_qty,
+                    coin_qty,
                     btc_price,
                     expiry,
                     OpenType::BuyToOpen,
@@ -476,13 +793,18 @@ impl PositionTracker {
                     } else {
                         CloseType::Sell
                     },
-                    Some(option.pc),
+                    Some(Synthetic {
+                        pc: option.pc,
+                        parent_id: close.open_id().clone(),
+                        parent_date: close.close_date(),
+                    }),
                     self.bitcoin_strat,
                 )
-                .with_context(|| format!("BTC trade b/c assigned {size} of {asset}"))?;
+                .with_context(|| format!("{coin} trade b/c assigned {size} of {asset}"))?;
+            self.sweep_dust(coin, &coin_closes, expiry);
 
             self.push_events("push_assignment [opt]", vec![close], None);
-            self.push_events("push_assignment [btc]", btc_closes, btc_open);
+            self.push_events("push_assignment [coin]", coin_closes, coin_open);
         }
 
         // Return the number of option closes that happened (nothing about the number
@@ -509,12 +831,11 @@ impl PositionTracker {
             (OpenType::SellToOpen, CloseType::Sell)
         };
 
-        // Dayaheads we have to convert to bitcoin to ensure they are tracked correctly.
-        // Furthermore, long positions we bump to the expiry date of the dayahead.
+        // Dayaheads we have to convert to their underlying to ensure they are tracked
+        // correctly. Furthermore, long positions we bump to the expiry date of the dayahead.
         if let TaxAsset::NextDay { underlying, expiry } = asset {
-            assert_eq!(underlying, Underlying::Btc);
-            // Lol, not the actual expiry date. The expiry date with its timestamp
-            // munged to be equal to 21:00.
+            // Lol, not the actual expiry date. In 2021 the expiry date with its
+            // timestamp munged to be equal to 21:00.
             //
             // Furthermore, note that the date is *always* forced, even for short positions,
             // even though in the LX trading interface, once you sell a next day, you
@@ -524,14 +845,12 @@ impl PositionTracker {
             // zero tax consequence since it's an exchange of cash for a cash contract
             // of equal value. It is only at expiry, when bitcoin changes hands, that
             // a taxable event occurs.
-            date = expiry.into();
-            if date.year() == 2021 {
-                date = expiry.forced_to_hour(21).into();
-            }
-            asset = TaxAsset::Bitcoin;
+            date = crate::ledgerx::expiry::nextday_tax_date(expiry).into();
+            asset = underlying.into();
         }
 
-        let strat = if asset == TaxAsset::Bitcoin {
+        // The configured strategy applies to ETH lots just as to BTC ones
+        let strat = if asset.is_coin() {
             self.bitcoin_strat
         } else {
             LotSelectionStrategy::LedgerXFifo
@@ -540,6 +859,7 @@ impl PositionTracker {
         let (closes, open) = pos
             .add(quantity, price, date, open_ty, close_ty, None, strat)
             .with_context(|| format!("adding {quantity} units of {asset} at {price} on {date}",))?;
+        self.sweep_dust(asset, &closes, date);
 
         Ok(self.push_events("push_trade", closes, open))
     }
@@ -577,8 +897,522 @@ impl PositionTracker {
         });
     }
 
+    /// Moves BTC out of the position, for a withdrawal to one of our own addresses
+    ///
+    /// Lots are chosen by the current lot selection strategy, as for a sale,
+    /// but no tax event is recorded. The lots are held in transit, keeping
+    /// their identity, basis and date, until [Self::push_transfer_in] restores
+    /// them.
+    pub fn push_transfer_out(
+        &mut self,
+        amount: bitcoin::Amount,
+        date: TaxDate,
+    ) -> anyhow::Result<()> {
+        let strat = self.bitcoin_strat;
+        let pos = self
+            .positions
+            .entry(TaxAsset::Bitcoin)
+            .or_insert(Position::new(TaxAsset::Bitcoin));
+        let mut remaining = Quantity::from(amount);
+        let mut batch = VecDeque::new();
+        while remaining.is_nonzero() {
+            let (sort_date, lot) = match strat {
+                LotSelectionStrategy::HighestFirst => pos.queue.pop_max(|lot| lot.price()),
+                LotSelectionStrategy::LedgerXFifo => pos.queue.pop_first(),
+            }
+            .with_context(|| {
+                format!("withdrew {amount} to own address at {date}, more than our open BTC lots")
+            })?;
+            let (part, rest) = lot.split(remaining);
+            if let Some(rest) = rest {
+                pos.queue.insert(sort_date, rest);
+            }
+            debug!("[transfer] withdrew {} at {}", part, date);
+            remaining -= part.quantity();
+            batch.push_back(part);
+        }
+        self.in_transit.push_back(batch);
+        Ok(())
+    }
+
+    /// Removes specific BTC lots from the account, for a withdrawal to an
+    /// outside address
+    ///
+    /// The lots are taken in the order given, and the last may be only partly
+    /// withdrawn. It is an error for any lot not to be open, for the lots not
+    /// to cover the withdrawal, or for any lot to be left over once they do.
+    pub fn push_withdrawal(
+        &mut self,
+        amount: bitcoin::Amount,
+        lot_ids: &[LotId],
+        date: TaxDate,
+    ) -> anyhow::Result<()> {
+        let pos = self
+            .positions
+            .entry(TaxAsset::Bitcoin)
+            .or_insert(Position::new(TaxAsset::Bitcoin));
+        let mut remaining = Quantity::from(amount);
+        for id in lot_ids {
+            if !remaining.is_nonzero() {
+                return Err(anyhow::Error::msg(format!(
+                    "lot {id} listed for withdrawal of {amount} at {date}, but the lots \
+                     before it already cover the withdrawal"
+                )));
+            }
+            let (sort_date, lot) = pos
+                .queue
+                .pop_first_where(|lot| lot.id() == id)
+                .with_context(|| {
+                    format!("lot {id} listed for withdrawal at {date} is not an open BTC lot")
+                })?;
+            let (part, rest) = lot.split(remaining);
+            if let Some(rest) = rest {
+                pos.queue.insert(sort_date, rest);
+            }
+            debug!("[withdrawal] withdrew {} at {}", part, date);
+            remaining -= part.quantity();
+        }
+        if remaining.is_nonzero() {
+            return Err(anyhow::Error::msg(format!(
+                "lots listed for withdrawal of {amount} at {date} only cover {}",
+                Quantity::from(amount) - remaining,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Restores BTC lots held in transit, for a deposit from one of our own addresses
+    ///
+    /// Lots are restored in the order they were withdrawn. If a deposit stops
+    /// short of the end of a withdrawal by no more than [`MAX_TRANSFER_FEE`],
+    /// the shortfall was paid in fees, and is closed at the given BTC price.
+    pub fn push_transfer_in(
+        &mut self,
+        amount: bitcoin::Amount,
+        btc_price: Price,
+        date: TaxDate,
+    ) -> anyhow::Result<()> {
+        let pos = self
+            .positions
+            .entry(TaxAsset::Bitcoin)
+            .or_insert(Position::new(TaxAsset::Bitcoin));
+        let mut remaining = Quantity::from(amount);
+        let mut closes = vec![];
+        while remaining.is_nonzero() {
+            let mut batch = self.in_transit.pop_front().with_context(|| {
+                format!(
+                    "deposited {amount} from own address at {date}, more than was withdrawn to \
+                     own addresses"
+                )
+            })?;
+            while remaining.is_nonzero() {
+                let lot = match batch.pop_front() {
+                    Some(lot) => lot,
+                    None => break,
+                };
+                let (part, rest) = lot.split(remaining);
+                if let Some(rest) = rest {
+                    batch.push_front(rest);
+                }
+                debug!("[transfer] redeposited {} at {}", part, date);
+                remaining -= part.quantity();
+                pos.queue.insert(part.sort_date(), part);
+            }
+
+            let shortfall = batch
+                .iter()
+                .fold(Quantity::Zero, |acc, lot| acc + lot.quantity());
+            if shortfall > Quantity::from(MAX_TRANSFER_FEE) {
+                self.in_transit.push_front(batch);
+                continue;
+            }
+            for lot in batch {
+                let qty = lot.quantity();
+                let (close, _) = lot
+                    .close(-qty, btc_price, date, CloseType::TxFee, None)
+                    .with_context(|| format!("closing transfer fee at {date}"))?;
+                closes.push(close);
+            }
+        }
+        self.push_events("push_transfer_in", closes, None);
+        Ok(())
+    }
+
+    /// Returns all BTC lots which were withdrawn to our own addresses and have
+    /// not been redeposited
+    pub fn in_transit(&self) -> impl Iterator<Item = &Lot> {
+        self.in_transit.iter().flatten()
+    }
+
     /// Returns a list of all the tax events that have been recorded
     pub fn events(&self) -> &[Event] {
         &self.events
     }
+
+    /// Returns all lots of the given asset which are still open
+    pub fn open_lots(&self, asset: TaxAsset) -> impl Iterator<Item = &Lot> {
+        self.positions
+            .get(&asset)
+            .into_iter()
+            .flat_map(|pos| pos.queue.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn carryforward() {
+        let p = |s| Price::from_str(s).unwrap();
+        let cf = |st, lt| Carryforward {
+            short_term: p(st),
+            long_term: p(lt),
+        };
+
+        let rates = TaxRates::default();
+
+        // Gains eat the carryforward
+        let year = cf("1000", "500").apply(p("2000"), p("100"));
+        assert_eq!(year.ordinary_offset, Price::ZERO);
+        assert_eq!(year.leaving, Carryforward::default());
+        // ...leaving $1000 ST taxed at 37%, and $400 of LT loss cancelling some of it
+        assert_eq!(year.liability(rates), p("222"));
+
+        // Both losses: $3000 taken from ST first
+        let year = Carryforward::default().apply(p("-2000"), p("-5000"));
+        assert_eq!(year.ordinary_offset, p("3000"));
+        assert_eq!(year.leaving, cf("0", "4000"));
+        assert_eq!(year.liability(rates), p("-1110"));
+
+        // ST loss partly cancelled by LT gain
+        let year = cf("10000", "0").apply(Price::ZERO, p("4000"));
+        assert_eq!(year.ordinary_offset, p("3000"));
+        assert_eq!(year.leaving, cf("3000", "0"));
+
+        // LT loss partly cancelled by ST gain
+        let year = Carryforward::default().apply(p("1000"), p("-2500"));
+        assert_eq!(year.ordinary_offset, p("1500"));
+        assert_eq!(year.leaving, Carryforward::default());
+    }
+
+    #[test]
+    fn self_transfer() {
+        let date = |s: &str| TaxDate::from(UtcTime::parse_coinbase(s).unwrap());
+        let btc = |s: &str| bitcoin::Amount::from_str(&format!("{s} BTC")).unwrap();
+        let mut tracker = PositionTracker::new();
+        tracker
+            .push_trade(
+                TaxAsset::Bitcoin,
+                Quantity::from(btc("1")),
+                Price::from_str("20000").unwrap(),
+                date("2022-01-03T15:00:00Z"),
+            )
+            .unwrap();
+        let lot = tracker.open_lots(TaxAsset::Bitcoin).next().unwrap().clone();
+
+        // Withdrawing is not a disposal, and leaves nothing to sell
+        tracker
+            .push_transfer_out(btc("1"), date("2022-06-01T15:00:00Z"))
+            .unwrap();
+        assert_eq!(tracker.open_lots(TaxAsset::Bitcoin).count(), 0);
+        assert_eq!(tracker.events().len(), 1);
+
+        // Less comes back, restoring the lot as it was...
+        let price = Price::from_str("25000").unwrap();
+        tracker
+            .push_transfer_in(btc("0.9999"), price, date("2023-06-01T15:00:00Z"))
+            .unwrap();
+        let restored = tracker.open_lots(TaxAsset::Bitcoin).next().unwrap();
+        assert_eq!(restored.id(), lot.id());
+        assert_eq!(restored.date(), lot.date());
+        assert_eq!(restored.price(), lot.price());
+        assert_eq!(restored.quantity(), Quantity::from(btc("0.9999")));
+        // ...and the rest went on fees, so nothing is left in transit
+        assert_eq!(tracker.in_transit().count(), 0);
+        match tracker.events()[1].open_close {
+            OpenClose::Close(ref close) => {
+                assert_eq!(close.ty(), CloseType::TxFee);
+                assert_eq!(close.quantity(), -Quantity::from(btc("0.0001")));
+                assert_eq!(close.proceeds(), Price::from_str("2.5").unwrap());
+            }
+            _ => panic!("expected a close"),
+        }
+        assert!(tracker
+            .push_transfer_in(btc("0.1"), price, date("2023-06-02T15:00:00Z"))
+            .is_err());
+
+        // A partial redeposit leaves the rest in transit until it comes back
+        tracker
+            .push_transfer_out(btc("0.5"), date("2023-06-03T15:00:00Z"))
+            .unwrap();
+        tracker
+            .push_transfer_in(btc("0.2"), price, date("2023-06-04T15:00:00Z"))
+            .unwrap();
+        assert_eq!(tracker.in_transit().count(), 1);
+        tracker
+            .push_transfer_in(btc("0.3"), price, date("2023-06-05T15:00:00Z"))
+            .unwrap();
+        assert_eq!(tracker.in_transit().count(), 0);
+        assert_eq!(tracker.events().len(), 2);
+
+        // A sale after the round trip is long-term
+        tracker
+            .push_trade(
+                TaxAsset::Bitcoin,
+                -Quantity::from(btc("0.5")),
+                Price::from_str("30000").unwrap(),
+                date("2023-07-01T15:00:00Z"),
+            )
+            .unwrap();
+        match tracker.events()[2].open_close {
+            OpenClose::Close(ref close) => {
+                assert_eq!(close.open_id(), lot.id());
+                assert_eq!(close.gain_loss_type(), GainType::LongTerm);
+            }
+            _ => panic!("expected a close"),
+        }
+    }
+
+    #[test]
+    fn specific_id_withdrawal() {
+        let date = |s: &str| TaxDate::from(UtcTime::parse_coinbase(s).unwrap());
+        let btc = |s: &str| bitcoin::Amount::from_str(&format!("{s} BTC")).unwrap();
+        let mut tracker = PositionTracker::new();
+        for (price, day) in [("20000", "03"), ("40000", "04"), ("30000", "05")].iter() {
+            tracker
+                .push_trade(
+                    TaxAsset::Bitcoin,
+                    Quantity::from(btc("1")),
+                    Price::from_str(price).unwrap(),
+                    date(&format!("2022-01-{day}T15:00:00Z")),
+                )
+                .unwrap();
+        }
+        let ids: Vec<LotId> = tracker
+            .open_lots(TaxAsset::Bitcoin)
+            .map(|lot| lot.id().clone())
+            .collect();
+        let withdrawal = date("2022-06-01T15:00:00Z");
+
+        // Unknown lots, too few lots and too many lots are all errors
+        let mut bad = tracker.clone();
+        let unknown = LotId::from_str("not-a-lot").unwrap();
+        assert!(bad
+            .push_withdrawal(btc("0.5"), &[unknown], withdrawal)
+            .is_err());
+        let mut bad = tracker.clone();
+        assert!(bad
+            .push_withdrawal(btc("1.5"), &ids[2..], withdrawal)
+            .is_err());
+        let mut bad = tracker.clone();
+        assert!(bad
+            .push_withdrawal(btc("0.5"), &ids[1..], withdrawal)
+            .is_err());
+
+        // Take all of the last lot and half of the first, leaving the middle
+        tracker
+            .push_withdrawal(btc("1.5"), &[ids[2].clone(), ids[0].clone()], withdrawal)
+            .unwrap();
+        assert_eq!(tracker.events().len(), 3);
+        let open: Vec<(LotId, Quantity)> = tracker
+            .open_lots(TaxAsset::Bitcoin)
+            .map(|lot| (lot.id().clone(), lot.quantity()))
+            .collect();
+        assert_eq!(
+            open,
+            vec![
+                (ids[0].clone(), Quantity::from(btc("0.5"))),
+                (ids[1].clone(), Quantity::from(btc("1"))),
+            ],
+        );
+
+        // A later sale under highest-first uses the basis of what is left
+        tracker.set_bitcoin_lot_strategy(LotSelectionStrategy::HighestFirst);
+        tracker
+            .push_trade(
+                TaxAsset::Bitcoin,
+                -Quantity::from(btc("1")),
+                Price::from_str("50000").unwrap(),
+                date("2022-07-01T15:00:00Z"),
+            )
+            .unwrap();
+        match tracker.events()[3].open_close {
+            OpenClose::Close(ref close) => assert_eq!(close.open_id(), &ids[1]),
+            _ => panic!("expected a close"),
+        }
+    }
+
+    #[test]
+    fn dust_lots() {
+        let date = |s: &str| TaxDate::from(UtcTime::parse_coinbase(s).unwrap());
+        let btc = |s: &str| Quantity::from(bitcoin::Amount::from_str(&format!("{s} BTC")).unwrap());
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let mut tracker = PositionTracker::new();
+        tracker
+            .push_trade(
+                TaxAsset::Bitcoin,
+                btc("0.12345678"),
+                p("50000"),
+                date("2022-01-03T15:00:00Z"),
+            )
+            .unwrap();
+        tracker
+            .push_trade(
+                TaxAsset::Bitcoin,
+                btc("0.5"),
+                p("60000"),
+                date("2022-01-04T15:00:00Z"),
+            )
+            .unwrap();
+        let sell = |tracker: &mut PositionTracker, qty: &str, d: &str| {
+            tracker
+                .push_trade(TaxAsset::Bitcoin, -btc(qty), p("70000"), date(d))
+                .unwrap()
+        };
+        let short_term = "2022-02-01T15:00:00Z";
+        let long_term = "2023-03-01T15:00:00Z";
+        let open = |tracker: &PositionTracker| -> Vec<(Quantity, Price)> {
+            tracker
+                .open_lots(TaxAsset::Bitcoin)
+                .map(|lot| (lot.quantity(), lot.price()))
+                .collect()
+        };
+        let dust = DustSettings {
+            btc_sats: 500_000,
+            eth_gwei: 0,
+        };
+
+        // By default the 0.00345678 BTC left over stays a lot of its own
+        let mut plain = tracker.clone();
+        assert_eq!(sell(&mut plain, "0.12", long_term), 1);
+        assert_eq!(
+            open(&plain),
+            vec![(btc("0.00345678"), p("50000")), (btc("0.5"), p("60000"))],
+        );
+
+        // Lots opened on different days are not merged while either is
+        // short-term, since merging would lose the dust's holding period
+        let mut unmerged = tracker.clone();
+        unmerged.set_dust_settings(dust);
+        assert_eq!(sell(&mut unmerged, "0.12", short_term), 1);
+        assert_eq!(open(&unmerged), open(&plain));
+
+        // Merged, it joins the next lot, keeping the total quantity and basis
+        let mut merged = tracker.clone();
+        merged.set_dust_settings(dust);
+        assert_eq!(sell(&mut merged, "0.12", long_term), 1);
+        let lots: Vec<&Lot> = merged.open_lots(TaxAsset::Bitcoin).collect();
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].quantity(), btc("0.50345678"));
+        assert_eq!(lots[0].basis(), p("30172.839"));
+        // ...and the basis is conserved exactly across later partial closes
+        let mut closed = Price::ZERO;
+        for _ in 0..3 {
+            sell(&mut merged, "0.1", long_term);
+            match merged.events().last().unwrap().open_close {
+                OpenClose::Close(ref close) => closed += close.basis(),
+                _ => panic!("expected a close"),
+            }
+        }
+        let rest = merged.open_lots(TaxAsset::Bitcoin).next().unwrap().basis();
+        assert_eq!(closed + rest, p("30172.839"));
+    }
+
+    #[test]
+    fn assignment_parent() {
+        let option = crate::option::Option::from_str("2024-03-29P50000").unwrap();
+        let asset = TaxAsset::Option {
+            underlying: Underlying::Btc,
+            option,
+            contract_size: ContractSize::Mini,
+        };
+        let date = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        let mut tracker = PositionTracker::new();
+        tracker
+            .push_trade(
+                asset,
+                Quantity::Contracts(-2),
+                Price::from_str("1000").unwrap(),
+                date.into(),
+            )
+            .unwrap();
+        tracker
+            .push_assignment(
+                option,
+                Underlying::Btc,
+                ContractSize::Mini,
+                Quantity::Contracts(2),
+                Price::from_str("45000").unwrap(),
+                Price::ZERO,
+            )
+            .unwrap();
+
+        let mut option_lot = None;
+        let mut btc_lot = None;
+        for event in tracker.events() {
+            match event.open_close {
+                OpenClose::Open(ref lot) if lot.asset() == TaxAsset::Bitcoin => {
+                    btc_lot = Some(lot.csv_printer().to_string());
+                }
+                OpenClose::Open(ref lot) => option_lot = Some(lot.id().clone()),
+                OpenClose::Close(ref close) => {
+                    // The option close itself is not synthetic
+                    assert!(close
+                        .csv_printer(
+                            event.asset,
+                            0,
+                            crate::ledgerx::history::lot::PrintMode::Full
+                        )
+                        .to_string()
+                        .ends_with(','));
+                }
+            }
+        }
+        let expected = format!(
+            ",assignment of {} at 2024-03-29T22:00:00Z",
+            option_lot.unwrap()
+        );
+        assert!(btc_lot.unwrap().ends_with(&expected));
+    }
+
+    #[test]
+    fn eth_assignment() {
+        let option = crate::option::Option::from_str("2024-03-29P2000").unwrap();
+        let asset = TaxAsset::Option {
+            underlying: Underlying::Eth,
+            option,
+            contract_size: ContractSize::Mini,
+        };
+        let date = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        // Each ETH contract is 1/10 ETH, priced per ETH
+        let premium = Price::from_str("50").unwrap();
+        assert_eq!(
+            premium * Quantity::EthContracts(3),
+            Price::from_str("15").unwrap()
+        );
+
+        let mut tracker = PositionTracker::new();
+        tracker
+            .push_trade(asset, Quantity::EthContracts(-3), premium, date.into())
+            .unwrap();
+        tracker
+            .push_assignment(
+                option,
+                Underlying::Eth,
+                ContractSize::Mini,
+                Quantity::EthContracts(3),
+                Price::from_str("1800").unwrap(),
+                Price::ZERO,
+            )
+            .unwrap();
+
+        let lots: Vec<_> = tracker.open_lots(TaxAsset::Ether).collect();
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].quantity(), Quantity::from_gwei(300_000_000));
+        assert_eq!(lots[0].quantity().to_string(), "0.3 ETH");
+        assert_eq!(lots[0].price(), Price::from_str("1800").unwrap());
+        assert_eq!(tracker.open_lots(TaxAsset::Bitcoin).count(), 0);
+    }
 }
@@ -0,0 +1,249 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! LedgerX History Configuration Wizard
+//!
+//! Interactively builds a skeleton configuration file for new users. Asks for
+//! an API key, pulls the user ID and deposit list from LX, and works out which
+//! transaction and lot entries the config file will need.
+//!
+//! If no API key is given, the wizard works offline: it asks for the user ID
+//! instead, and leaves the deposits to be filled in by hand.
+//!
+//! JSON does not have comments, so anything which still needs to be filled in
+//! by hand is listed in a "_todo" array at the top of the file. This field is
+//! ignored by the parser; delete it once everything has been dealt with.
+//!
+
+use super::{Deposits, LotId};
+use crate::units::DepositAsset;
+use anyhow::Context;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead as _};
+use std::str::FromStr;
+
+/// Prompt the user for a line of input, returning it trimmed
+fn prompt(question: &str) -> anyhow::Result<String> {
+    info!("{}", question);
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .context("reading from stdin")?;
+    Ok(line.trim().to_owned())
+}
+
+/// Prompt the user for their LX user ID, adding a TODO item if they do not know it
+fn prompt_user_id(todo: &mut Vec<String>) -> anyhow::Result<usize> {
+    let user = prompt("LX user ID (shown on the LX-provided tax CSV; leave blank if unknown):")?;
+    if user.is_empty() {
+        todo.push("Fill in the 'user' field with your LX user ID.".to_string());
+        Ok(0)
+    } else {
+        user.parse()
+            .with_context(|| format!("parsing user ID {user}"))
+    }
+}
+
+/// Look up our LX user ID, which LX attaches to each of our positions
+///
+/// If we have never held a position there is nothing to look it up from, but
+/// then there are also no trades to report, so we just add a TODO item.
+fn fetch_user_id(api_key: &str, todo: &mut Vec<String>) -> anyhow::Result<usize> {
+    info!("Fetching user ID");
+    let positions: super::Positions = crate::http::get_json(
+        "https://api.ledgerx.com/trading/positions?limit=1",
+        Some(api_key),
+    )
+    .context("getting positions from LX API")?;
+    match positions.customer_id() {
+        Some(user) => {
+            info!("LX user ID is {}.", user);
+            Ok(user)
+        }
+        None => {
+            warn!("No LX positions to take the user ID from.");
+            todo.push("Fill in the 'user' field with your LX user ID.".to_string());
+            Ok(0)
+        }
+    }
+}
+
+/// Run the wizard, writing the resulting skeleton config to `output`
+pub fn run(output: &str) -> anyhow::Result<()> {
+    let mut todo = vec![];
+
+    let api_key = prompt("LX API key (leave blank to work offline):")?;
+    let offline = api_key.is_empty();
+    let user = if offline {
+        prompt_user_id(&mut todo)?
+    } else {
+        fetch_user_id(&api_key, &mut todo)?
+    };
+    let year = prompt("First tax year to report on (e.g. 2023):")?;
+    let year: i32 = year
+        .parse()
+        .with_context(|| format!("parsing tax year {year}"))?;
+
+    // Pull deposits from LX
+    let mut btc_deposits = vec![];
+    let mut next_url = if offline {
+        todo.push(
+            "Add each BTC deposit to 'transactions' and 'lots', or re-run the wizard \
+             with an API key."
+                .to_string(),
+        );
+        None
+    } else {
+        Some("https://api.ledgerx.com/funds/deposits?limit=200".to_string())
+    };
+    while let Some(url) = next_url {
+        info!("Fetching deposits");
+        let deposits: Deposits =
+            crate::http::get_json(&url, Some(&api_key)).context("getting deposits from LX API")?;
+        for dep in &deposits.data {
+            match dep.asset {
+                DepositAsset::Btc => {
+                    let amount = dep.amount.as_sats().to_unsigned().with_context(|| {
+                        format!("negative deposit amount {}", dep.amount.as_sats())
+                    })?;
+                    btc_deposits.push((dep.created_at, dep.address.clone(), amount));
+                }
+                // ETH deposits have no transaction data, so are a single lot each
+                DepositAsset::Eth => todo.push(format!(
+                    "ETH deposit of {} on {}: add a 'lots' entry for {} with its \
+                     acquisition price (in cents) and date (UNIX timestamp).",
+                    dep.amount.with_asset(dep.asset.into()),
+                    dep.created_at,
+                    LotId::from_eth_deposit(dep.created_at),
+                )),
+                DepositAsset::Usd => {}
+            }
+        }
+        next_url = deposits.next_url();
+    }
+    info!("Found {} BTC deposits.", btc_deposits.len());
+
+    // For each deposit, ask for the transaction and work out which lots it needs
+    let mut transactions = BTreeMap::new();
+    for (date, address, amount) in btc_deposits {
+        let desc = format!("BTC deposit of {amount} to {address} on {date}");
+        let tx_hex = prompt(&format!(
+            "Raw hex of transaction for {desc} (leave blank to fill in later):"
+        ))?;
+        if tx_hex.is_empty() {
+            todo.push(format!(
+                "{desc}: add the raw transaction to 'transactions', then re-run \
+                 the wizard or add 'lots' entries by hand."
+            ));
+            continue;
+        }
+        match deposit_lots(&tx_hex, &address, amount) {
+            Ok((txid, parents, lots)) => {
+                transactions.insert(txid.to_string(), tx_hex);
+                for parent in parents {
+                    todo.push(format!(
+                        "{desc}: add the raw transaction {parent} (spent by {txid}) \
+                         to 'transactions'."
+                    ));
+                }
+                for lot in lots {
+                    todo.push(format!(
+                        "{desc}: add a 'lots' entry for {lot} with its acquisition price \
+                         (in cents) and date (UNIX timestamp)."
+                    ));
+                }
+            }
+            Err(e) => {
+                warn!("Could not use transaction for {}: {:#}", desc, e);
+                todo.push(format!(
+                    "{desc}: add the raw transaction to 'transactions' (the provided one did \
+                     not match: {e})."
+                ));
+            }
+        }
+    }
+    todo.push(
+        "Fill in 'lx_csv' with the lines of the LX-provided tax CSV (minus the header), \
+         each enclosed in quotes with internal quotes escaped."
+            .to_string(),
+    );
+
+    let mut years = BTreeMap::new();
+    years.insert(year.to_string(), "ledgerx-fifo");
+    let skeleton = serde_json::json!({
+        "_todo": todo,
+        "user": user,
+        "years": years,
+        "lx_csv": [],
+        "lots": {},
+        "transactions": transactions,
+    });
+
+    let mut file = crate::file::create_text_file(output.to_owned(), "with skeleton configuration")?;
+    writeln!(file, "{:#}", skeleton)?;
+    info!(
+        "Wrote configuration with {} TODO items. Once they are done, delete the '_todo' \
+         field and try `tax-history` with it; any missing entries will be reported.",
+        todo.len()
+    );
+    Ok(())
+}
+
+/// Parses a deposit transaction and works out which transactions and lots the config needs
+///
+/// Mirrors the logic in [`super::History::import_deposits`]: a single-output deposit is
+/// assumed to consist of one lot per input (and so needs the parent transactions), while
+/// a multi-output deposit is assumed to be a single lot.
+fn deposit_lots(
+    tx_hex: &str,
+    address: &str,
+    amount: bitcoin::Amount,
+) -> anyhow::Result<(bitcoin::Txid, Vec<bitcoin::Txid>, Vec<LotId>)> {
+    let bytes: Vec<u8> =
+        bitcoin::hashes::hex::FromHex::from_hex(tx_hex).context("decoding transaction as hex")?;
+    let tx: bitcoin::Transaction =
+        bitcoin::consensus::deserialize(&bytes).context("decoding transaction")?;
+    let addr = bitcoin::Address::from_str(address)
+        .with_context(|| format!("parsing BTC address {address}"))?
+        .require_network(bitcoin::Network::Bitcoin)
+        .with_context(|| format!("parsing address as BTC address {address}"))?;
+    let vout = tx
+        .output
+        .iter()
+        .position(|out| out.value == amount && out.script_pubkey == addr.script_pubkey())
+        .with_context(|| format!("no txout matched address/amount {addr}/{amount}"))?;
+
+    let txid = tx.txid();
+    if tx.output.len() == 1 {
+        let parents = tx
+            .input
+            .iter()
+            .map(|inp| inp.previous_output.txid)
+            .collect();
+        let lots = tx
+            .input
+            .iter()
+            .map(|inp| LotId::from_outpoint(inp.previous_output))
+            .collect();
+        Ok((txid, parents, lots))
+    } else {
+        let outpoint = bitcoin::OutPoint {
+            txid,
+            vout: vout as u32,
+        };
+        Ok((txid, vec![], vec![LotId::from_outpoint(outpoint)]))
+    }
+}
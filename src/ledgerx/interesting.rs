@@ -18,13 +18,246 @@
 //! a bid/ask on, or whether a certain standing order is worth taking
 //!
 
-use crate::ledgerx::{Contract, Underlying};
+use crate::bs_cache;
+use crate::ledgerx::{collateral, goals, skew, Contract, ContractId, Underlying};
 use crate::option;
 use crate::price::BitcoinPrice;
 use crate::units::{Price, Quantity, UtcTime};
-use log::{debug, warn};
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::{cmp, fmt, ops};
+use std::{cmp, fmt, ops, str};
+
+/// Implied volatility at which we start pricing standing asks
+pub const STANDING_IV: f64 = 0.85;
+/// Implied volatility at which we start pricing standing asks on puts we
+/// would like to be assigned on
+pub const GOAL_IV: f64 = 0.50;
+
+/// How to compute the absolute yield below which an otherwise-interesting
+/// contract is not worth logging
+///
+/// All variants are per day to expiry, so that longer-dated options must
+/// offer proportionally more premium.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum YieldThreshold {
+    /// A flat number of dollars per day
+    Flat(Price),
+    /// Basis points of the account's net liquidation value per day
+    NlvBps(u32),
+    /// Basis points of the collateral the trade would lock up, per day
+    CollateralBps(u32),
+}
+
+impl Default for YieldThreshold {
+    fn default() -> Self {
+        // Roughly $750/mo
+        YieldThreshold::Flat(Price::TWENTY_FIVE)
+    }
+}
+
+impl YieldThreshold {
+    /// Computes the threshold for an option with `dte` days to expiry, given
+    /// the account's net liquidation value and the collateral locked up by
+    /// the trade in question
+    pub fn threshold(&self, dte: f64, nlv: Price, collateral: Price) -> Price {
+        let per_day = match *self {
+            YieldThreshold::Flat(usd) => usd,
+            YieldThreshold::NlvBps(bps) => nlv.scale_ratio(bps.into(), 10_000),
+            YieldThreshold::CollateralBps(bps) => collateral.scale_ratio(bps.into(), 10_000),
+        };
+        per_day.scale_approx(dte)
+    }
+}
+
+impl fmt::Display for YieldThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            YieldThreshold::Flat(usd) => write!(f, "${usd}/day"),
+            YieldThreshold::NlvBps(bps) => write!(f, "{bps}bps of NLV/day"),
+            YieldThreshold::CollateralBps(bps) => write!(f, "{bps}bps of collateral/day"),
+        }
+    }
+}
+
+impl str::FromStr for YieldThreshold {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. usd:25, nlv-bps:3, collateral-bps:10
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("yield threshold {s} is not of the form <kind>:<value>"))?;
+        let bps = || {
+            value
+                .parse::<u32>()
+                .map_err(|e| format!("parsing basis points in {s}: {e}"))
+        };
+        match kind {
+            "usd" => Price::from_str(value)
+                .map(YieldThreshold::Flat)
+                .map_err(|e| format!("parsing dollars in {s}: {e}")),
+            "nlv-bps" => bps().map(YieldThreshold::NlvBps),
+            "collateral-bps" => bps().map(YieldThreshold::CollateralBps),
+            _ => Err(format!(
+                "unknown yield threshold kind {kind} (expected usd, nlv-bps or collateral-bps)"
+            )),
+        }
+    }
+}
+
+/// Range of days to expiry within which we are willing to trade an option
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct DteRange {
+    /// Minimum number of days to expiry
+    pub min_days: u32,
+    /// Maximum number of days to expiry, if any
+    pub max_days: Option<u32>,
+}
+
+impl DteRange {
+    /// Whether an option with the given (fractional) days to expiry is in range
+    pub fn contains(&self, dte: f64) -> bool {
+        let below_max = match self.max_days {
+            Some(max) => dte <= f64::from(max),
+            None => true,
+        };
+        dte >= f64::from(self.min_days) && below_max
+    }
+}
+
+impl fmt::Display for DteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max_days {
+            Some(max) => write!(f, "{}-{} days", self.min_days, max),
+            None => write!(f, "{}+ days", self.min_days),
+        }
+    }
+}
+
+impl str::FromStr for DteRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. 7:90, 7: or :90
+        let (min, max) = s
+            .split_once(':')
+            .ok_or_else(|| format!("days-to-expiry range {s} is not of the form <min>:<max>"))?;
+        let days = |x: &str| {
+            x.parse::<u32>()
+                .map_err(|e| format!("parsing days in {s}: {e}"))
+        };
+        let ret = DteRange {
+            min_days: if min.is_empty() { 0 } else { days(min)? },
+            max_days: if max.is_empty() {
+                None
+            } else {
+                Some(days(max)?)
+            },
+        };
+        if ret.max_days.is_some_and(|max| max < ret.min_days) {
+            return Err(format!(
+                "days-to-expiry range {s} has maximum below minimum"
+            ));
+        }
+        Ok(ret)
+    }
+}
+
+/// Days-to-expiry limits on the options we trade, for each side
+///
+/// Options outside these limits are neither quoted nor taken, though they
+/// are still logged (at debug level) if they have interesting bids.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct DteFilter {
+    /// Limits on calls
+    pub calls: DteRange,
+    /// Limits on puts
+    pub puts: DteRange,
+}
+
+impl DteFilter {
+    /// The limits which apply to a given option
+    pub fn range(&self, opt: &option::Option) -> DteRange {
+        match opt.pc {
+            option::PutCall::Call => self.calls,
+            option::PutCall::Put => self.puts,
+        }
+    }
+
+    /// Whether we are willing to trade a given option
+    pub fn allows(&self, opt: &option::Option, now: UtcTime) -> bool {
+        self.range(opt).contains(opt.years_to_expiry(now) * 365.0)
+    }
+}
+
+/// The time from which the return of a standing ask is annualized
+///
+/// When computing the minimum ARR of a standing ask, which represents "is
+/// this trade even worth doing" or "is it worth the opportunity cost of being
+/// unable to trade while the collateral is locked", annualizing from the
+/// current time gives weird effects on low-DTE options, whose high numeric
+/// returns are only available on specific days of the week. (For weekly
+/// options, now that LX is closed on weekends, it is impossible to get a
+/// return on Saturday and Sunday, so annualizing is always wrong!) So by
+/// default we round our reference "now" back to the most recent Friday.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ArrReference {
+    /// The current time
+    Now,
+    /// The same time on the most recent earlier weekday
+    LastTradingDay,
+    /// The same time on the most recent earlier Friday
+    #[default]
+    LastFriday,
+    /// The same time on the most recent earlier occurrence of a fixed day
+    Weekly(chrono::Weekday),
+}
+
+impl ArrReference {
+    /// The reference time corresponding to the current time
+    pub fn apply(&self, now: UtcTime) -> UtcTime {
+        match *self {
+            ArrReference::Now => now,
+            ArrReference::LastTradingDay => now.last_trading_day(),
+            ArrReference::LastFriday => now.last_friday(),
+            ArrReference::Weekly(day) => now.last_weekday(day),
+        }
+    }
+}
+
+impl fmt::Display for ArrReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArrReference::Now => f.write_str("now"),
+            ArrReference::LastTradingDay => f.write_str("last-trading-day"),
+            ArrReference::LastFriday => f.write_str("last-friday"),
+            ArrReference::Weekly(day) => {
+                write!(f, "weekly:{}", day.to_string().to_ascii_lowercase())
+            }
+        }
+    }
+}
+
+impl str::FromStr for ArrReference {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. now, last-trading-day, last-friday, weekly:thu
+        match s {
+            "now" => Ok(ArrReference::Now),
+            "last-trading-day" => Ok(ArrReference::LastTradingDay),
+            "last-friday" => Ok(ArrReference::LastFriday),
+            _ => match s.strip_prefix("weekly:") {
+                Some(day) => day
+                    .parse()
+                    .map(ArrReference::Weekly)
+                    .map_err(|_| format!("unknown day of the week {day} in {s}")),
+                None => Err(format!(
+                    "unknown ARR reference {s} (expected now, last-trading-day, \
+                     last-friday or weekly:<day>)"
+                )),
+            },
+        }
+    }
+}
 
 pub trait OrderType: Eq + fmt::Debug + Copy {}
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
@@ -147,6 +380,79 @@ pub fn extract_option(contract: &Contract, btc_price: BitcoinPrice) -> Option<op
     Some(opt)
 }
 
+/// Model values from which [`AskStats::standing_order`] starts pricing an ask
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ModelValues {
+    /// Black-Scholes price at [`STANDING_IV`]
+    pub standing_price: Price,
+    /// Black-Scholes price at [`GOAL_IV`]
+    pub goal_price: Price,
+    /// Dual delta at 80% volatility, i.e. the chance of landing in the money
+    pub dual_delta: f64,
+}
+
+impl ModelValues {
+    /// Computes model values for a single option
+    pub fn new(opt: &option::Option, now: UtcTime, btc: Price) -> Self {
+        Self::for_expiry(std::slice::from_ref(opt), now, btc)[0]
+    }
+
+    /// Computes model values for a list of options which all share an expiry,
+    /// computing the terms they have in common only once
+    pub fn for_expiry(opts: &[option::Option], now: UtcTime, btc: Price) -> Vec<Self> {
+        let expiry = match opts.first() {
+            Some(opt) => opt.bs_expiry(now, btc),
+            None => return vec![],
+        };
+        let strikes: Vec<(bool, f64)> = opts
+            .iter()
+            .map(|opt| {
+                debug_assert_eq!(opt.expiry, opts[0].expiry);
+                (opt.pc == option::PutCall::Call, opt.strike.to_approx_f64())
+            })
+            .collect();
+        let standing = expiry.evaluate_all(&strikes, STANDING_IV);
+        let goal = expiry.evaluate_all(&strikes, GOAL_IV);
+        let loss = expiry.evaluate_all(&strikes, 0.8);
+        standing
+            .iter()
+            .zip(&goal)
+            .zip(&loss)
+            .map(|((standing, goal), loss)| ModelValues {
+                standing_price: Price::from_approx_f64_or_zero(standing.price),
+                goal_price: Price::from_approx_f64_or_zero(goal.price),
+                dual_delta: loss.dual_delta,
+            })
+            .collect()
+    }
+
+    /// Computes model values for every contract we might quote, one expiry at
+    /// a time
+    ///
+    /// Contracts rejected by [`extract_option`] are skipped.
+    pub fn for_contracts<'a>(
+        contracts: impl IntoIterator<Item = &'a Contract>,
+        btc_price: BitcoinPrice,
+        now: UtcTime,
+    ) -> HashMap<ContractId, Self> {
+        let mut by_expiry: HashMap<UtcTime, (Vec<ContractId>, Vec<option::Option>)> =
+            HashMap::new();
+        for contract in contracts {
+            if let Some(opt) = extract_option(contract, btc_price) {
+                let entry = by_expiry.entry(opt.expiry).or_default();
+                entry.0.push(contract.id());
+                entry.1.push(opt);
+            }
+        }
+        let mut ret = HashMap::new();
+        for (cids, opts) in by_expiry.into_values() {
+            let values = Self::for_expiry(&opts, now, btc_price.btc_price);
+            ret.extend(cids.into_iter().zip(values));
+        }
+        ret
+    }
+}
+
 /// Statistics about an order that tell us whether it is worth making or matching.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub struct OrderStats<T: OrderType> {
@@ -159,6 +465,8 @@ pub struct OrderStats<T: OrderType> {
     order_price: Price,
     /// Size of the order in question
     order_size: Quantity,
+    /// LX's fee per 100 contracts
+    fee: Price,
 }
 
 pub type BidStats = OrderStats<Bid>;
@@ -187,11 +495,14 @@ impl<T: OrderType> ops::AddAssign for OrderStats<T> {
 
 impl<T: OrderType> OrderStats<T> {
     /// Creates an order statistics from an order and some context
+    ///
+    /// The fee is LX's fee per 100 contracts at our current fee tier.
     pub fn from_order(
         btc_price: BitcoinPrice,
         contract: &Contract,
         order_price: Price,
         order_size: Quantity,
+        fee: Price,
     ) -> Option<Self> {
         let opt = extract_option(contract, btc_price)?;
 
@@ -201,11 +512,12 @@ impl<T: OrderType> OrderStats<T> {
             btc_price,
             order_price,
             order_size,
+            fee,
         })
     }
 
-    /// Annualized rate of return on collateral of a short option, assuming
-    /// the option expires worthless
+    /// Annualized rate of return on collateral of a short option, net of
+    /// LX's fee, assuming the option expires worthless
     pub fn arr(&self) -> f64 {
         let now = UtcTime::now();
         assert!(
@@ -213,7 +525,7 @@ impl<T: OrderType> OrderStats<T> {
             "bitcoin price is not fresh",
         );
         self.option
-            .arr(now, self.btc_price.btc_price, self.order_price)
+            .arr(now, self.btc_price.btc_price, self.order_price - self.fee)
     }
 
     /// Assuming the Black-Scholes model with 80% volatility, the probability that
@@ -241,18 +553,32 @@ impl<T: OrderType> OrderStats<T> {
         //
         // We ignore such options at least for now, because claiming the free money
         // is a bit of a PITA on LX which has low liquidity for BTC.
-        self.option
-            .bs_iv(now, self.btc_price.btc_price, self.order_price)
-            .expect("computing IV for ITM option in place where OTM is assumed")
+        bs_cache::iv(
+            &self.option,
+            now,
+            self.btc_price.btc_price,
+            self.order_price,
+        )
+        .expect("computing IV for ITM option in place where OTM is assumed")
     }
 
     /// Reduce the order size by the available funds, taking LX fees into account.
-    pub fn limit_to_funds(&mut self, available_usd: Price, available_btc: bitcoin::Amount) {
-        self.order_size = self.order_size.min(
-            self.option
-                .max_sale(self.order_price, available_usd, available_btc)
-                .0,
-        );
+    ///
+    /// Uses the LX collateral rules, so that an order which forms a spread with
+    /// one of our existing positions only needs to be funded for its net lockup.
+    pub fn limit_to_funds(
+        &mut self,
+        portfolio: &collateral::Portfolio,
+        available_usd: Price,
+        available_btc: bitcoin::Amount,
+    ) {
+        self.order_size = self.order_size.min(portfolio.max_short(
+            self.option,
+            self.order_price,
+            self.fee,
+            available_usd,
+            available_btc,
+        ));
     }
 
     /// Amount of cash that will be locked up by taking the short side of this order.
@@ -263,16 +589,16 @@ impl<T: OrderType> OrderStats<T> {
     /// bidding more for a put than they'd be able to sell the coin for. This
     /// is free money but nonetheless people offer it on LX from time to time.
     ///
-    /// Note that the price of the sale is $25 less than you might expect because
-    /// LX charges a 25c/option fee. (It doesn't do this always, e.g. when this
-    /// would cause the sale price to go negative or too close to zero, but we
-    /// assume it does because we're so rarely messing with contracts for which
-    /// the fees matter.)
+    /// Note that the price of the sale is less than you might expect because
+    /// LX charges a per-contract fee, which depends on our fee tier. (It doesn't
+    /// do this always, e.g. when this would cause the sale price to go negative
+    /// or too close to zero, but we assume it does because we're so rarely
+    /// messing with contracts for which the fees matter.)
     pub fn lockup_usd(&self) -> Price {
         match self.option.pc {
             option::PutCall::Call => Price::ZERO,
             option::PutCall::Put => {
-                (self.option.strike - self.order_price + Price::TWENTY_FIVE) * self.order_size.abs()
+                (self.option.strike - self.order_price + self.fee) * self.order_size.abs()
             }
         }
     }
@@ -312,6 +638,7 @@ impl OrderStats<Bid> {
             option: self.option,
             order_price: self.order_price,
             order_size: self.order_size,
+            fee: self.fee,
             order_type: PhantomData,
         }
     }
@@ -352,6 +679,7 @@ impl OrderStats<Ask> {
             option: self.option,
             order_price: self.order_price,
             order_size: self.order_size,
+            fee: self.fee,
             order_type: PhantomData,
         }
     }
@@ -368,63 +696,86 @@ impl OrderStats<Ask> {
     }
 
     /// Attempts to construct a standing ask order with reasonable stats.
+    ///
+    /// The model values should be computed with [`ModelValues::for_contracts`],
+    /// so that all the strikes of an expiry are evaluated at once, or with
+    /// [`ModelValues::new`] for a single option. If an inventory is given, the model price is skewed by it before our
+    /// minimum-return and maximum-risk constraints are applied. The minimum
+    /// return is annualized from the time given by `arr_reference`.
+    #[allow(clippy::too_many_arguments)]
     pub fn standing_order(
         btc_price: BitcoinPrice,
         contract: &Contract,
+        model: &ModelValues,
+        portfolio: &collateral::Portfolio,
         available_usd: Price,
         available_btc: bitcoin::Amount,
         best_ask: Price,
+        goal: Option<&goals::Goal>,
+        inventory: Option<&skew::Inventory>,
+        dte_filter: &DteFilter,
+        arr_reference: ArrReference,
+        fee: Price,
     ) -> Option<Self> {
         let opt = extract_option(contract, btc_price)?;
         let btc = btc_price.btc_price;
         let now = UtcTime::now();
+        if !dte_filter.allows(&opt, now) {
+            debug!(
+                "Not quoting {}: outside days-to-expiry range {}",
+                contract.label(),
+                dte_filter.range(&opt),
+            );
+            return None;
+        }
 
         // Start with an 85% IV
-        let mut price = opt.bs_price(now, btc, 0.85);
+        let mut price = model.standing_price;
 
-        // SPECIAL CASE (should remove in the future) for 30k puts we are
-        // willing to take a much lower IV, since we want to buy coins at
-        // this price.
-        if opt.pc == crate::option::PutCall::Put && opt.strike.to_approx_f64() == 30_000.0 {
+        // Puts near our goal price are ones we'd like to be assigned on, so we
+        // are willing to take a much lower IV and a much higher risk of
+        // assignment.
+        let wants_assignment = matches!(goal, Some(goal) if goal.wants_assignment(&opt));
+        if wants_assignment {
             let old_price = price;
-            price = opt.bs_price(now, btc, 0.50);
+            price = model.goal_price;
             debug!(
-                "Special-casing 30k puts; starting with price {} rather than {}",
+                "Put near goal price; starting with price {} rather than {}",
                 price, old_price
             );
-        }
-
-        // SPECIAL CASE (should remove in the future) for 30k puts we are
-        // willing to take a much higher risk of assignment, since we want to buy coins at
-        // this price.
-        if opt.pc == crate::option::PutCall::Put && opt.strike.to_approx_f64() == 30_000.0 {
-            if opt.bs_dual_delta(now, btc, 0.8).abs() >= 0.25 {
-                price = cmp::max(price, opt.bs_loss80_price(now, btc, 0.05)?);
+            if model.dual_delta.abs() >= 0.25 {
+                price = cmp::max(price, bs_cache::loss80_price(&opt, now, btc, 0.05)?);
             }
         } else {
+            if let Some(inventory) = inventory {
+                let skew = inventory.skew(&opt);
+                if skew.bps != 0 {
+                    let old_price = price;
+                    price = skew.apply(price);
+                    info!(
+                        "Inventory skew for {}: {}; model price {} -> {}",
+                        contract.label(),
+                        skew,
+                        old_price,
+                        price,
+                    );
+                }
+            }
             // If the option has a >5% chance of landing in the money, increase
             // the price until it has a 5% chance of losing money, assuming 80%
             // volatility.
-            if opt.bs_dual_delta(now, btc, 0.8).abs() >= 0.05 {
-                price = cmp::max(price, opt.bs_loss80_price(now, btc, 0.05)?);
+            if model.dual_delta.abs() >= 0.05 {
+                price = cmp::max(price, bs_cache::loss80_price(&opt, now, btc, 0.05)?);
             }
         }
         // For puts, we want at least an 8% return. For calls, 3% is fine
         // because we're posting BTC which won't earn anything anyway.
-        //
-        // Specifically when computing ARR, which represents "is this trade
-        // even worth doing" or "is it worth the opportunity cost of being
-        // unable to trade while the collateral is locked", we take round
-        // our reference "now" date backward to the most recent Friday.
-        // This eliminates weird effects where we are "annualizing" the
-        // return on low-DTE options whose high numeric returns are only
-        // available on specific days of the week. (For weekly options,
-        // now that LX is closed on weekends, it is impossible to get a
-        // return on Saturday and Sunday, so annualizing is always wrong!)
+        // See [`ArrReference`] for the time we annualize from.
         price = cmp::max(
             price,
-            opt.bs_arr_price(
-                now.last_friday(),
+            bs_cache::arr_price(
+                &opt,
+                arr_reference.apply(now),
                 btc,
                 match opt.pc {
                     crate::option::PutCall::Call => 0.03,
@@ -441,18 +792,134 @@ impl OrderStats<Ask> {
         //
         // Similarly if our price is less than the best ask, that's also
         // not a shithead order.
-        let iv = opt.bs_iv(now, btc, price).ok()?;
+        let iv = bs_cache::iv(&opt, now, btc, price).ok()?;
         if price < Price::ONE_THOUSAND || price <= best_ask || iv < 2.5 {
             let mut stats = Self::from_order(
                 btc_price,
                 contract,
                 price,
                 Quantity::Contracts(1_000_000_000),
+                fee,
             )?;
-            stats.limit_to_funds(available_usd, available_btc);
+            stats.limit_to_funds(portfolio, available_usd, available_btc);
             Some(stats)
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn yield_threshold() {
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let flat: YieldThreshold = "usd:25".parse().unwrap();
+        assert_eq!(flat, YieldThreshold::default());
+        assert_eq!(flat.threshold(2.0, p("1000000"), p("1000")), p("50"));
+
+        let nlv: YieldThreshold = "nlv-bps:3".parse().unwrap();
+        assert_eq!(nlv.threshold(2.0, p("1000000"), p("1000")), p("600"));
+        assert_eq!(nlv.threshold(2.0, p("100000"), p("1000")), p("60"));
+
+        let collateral: YieldThreshold = "collateral-bps:10".parse().unwrap();
+        assert_eq!(
+            collateral.threshold(2.0, p("1000000"), p("50000")),
+            p("100")
+        );
+        assert_eq!(collateral.to_string(), "10bps of collateral/day");
+
+        assert!("bps:3".parse::<YieldThreshold>().is_err());
+        assert!("nlv-bps:x".parse::<YieldThreshold>().is_err());
+        assert!("25".parse::<YieldThreshold>().is_err());
+    }
+
+    #[test]
+    fn dte_filter() {
+        let range: DteRange = "7:90".parse().unwrap();
+        assert_eq!(range.to_string(), "7-90 days");
+        assert!(!range.contains(6.9));
+        assert!(range.contains(7.0));
+        assert!(range.contains(90.0));
+        assert!(!range.contains(90.5));
+        let open: DteRange = "2:".parse().unwrap();
+        assert_eq!(open.to_string(), "2+ days");
+        assert!(open.contains(365.0));
+        assert_eq!(":30".parse::<DteRange>().unwrap().min_days, 0);
+        assert!("30:7".parse::<DteRange>().is_err());
+        assert!("30".parse::<DteRange>().is_err());
+
+        let now = UtcTime::now();
+        let filter = DteFilter {
+            calls: range,
+            puts: DteRange::default(),
+        };
+        let mut call = option::Option::from_str("2030-06-28C70000").unwrap();
+        let mut put = option::Option::from_str("2030-06-28P50000").unwrap();
+        call.expiry = now + chrono::Duration::days(180);
+        put.expiry = call.expiry;
+        assert!(!filter.allows(&call, now));
+        assert!(filter.allows(&put, now));
+        call.expiry = now + chrono::Duration::days(30);
+        assert!(filter.allows(&call, now));
+    }
+
+    #[test]
+    fn arr_reference() {
+        // A Wednesday
+        let now = UtcTime::parse_coinbase("2030-06-26T15:00:00Z").unwrap();
+        let date = |s: &str| {
+            let reference: ArrReference = s.parse().unwrap();
+            assert_eq!(reference.to_string(), s);
+            reference.apply(now).format("%F %H").to_string()
+        };
+        assert_eq!(ArrReference::default(), ArrReference::LastFriday);
+        assert_eq!(date("now"), "2030-06-26 15");
+        assert_eq!(date("last-trading-day"), "2030-06-25 15");
+        assert_eq!(date("last-friday"), "2030-06-21 15");
+        assert_eq!(date("weekly:wed"), "2030-06-19 15");
+        assert_eq!(date("weekly:thu"), "2030-06-20 15");
+        assert_eq!(date("weekly:tue"), "2030-06-25 15");
+
+        // On a Monday, the last trading day is the Friday before
+        let monday = UtcTime::parse_coinbase("2030-06-24T15:00:00Z").unwrap();
+        assert_eq!(
+            ArrReference::LastTradingDay.apply(monday),
+            monday.last_friday()
+        );
+
+        assert!("weekly:someday".parse::<ArrReference>().is_err());
+        assert!("friday".parse::<ArrReference>().is_err());
+    }
+
+    #[test]
+    fn model_values() {
+        let now = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        let btc = Price::from_str("62000").unwrap();
+        let opts: Vec<option::Option> =
+            ["2024-03-29P50000", "2024-03-29C70000", "2024-03-29P61000"]
+                .iter()
+                .map(|s| option::Option::from_str(s).unwrap())
+                .collect();
+
+        let values = ModelValues::for_expiry(&opts, now, btc);
+        assert_eq!(values.len(), opts.len());
+        for (opt, value) in opts.iter().zip(&values) {
+            // The batch agrees with pricing each option on its own, to the cent
+            let cent = Price::from_str("0.01").unwrap();
+            let standing = opt.bs_price(now, btc, STANDING_IV);
+            let goal = opt.bs_price(now, btc, GOAL_IV);
+            assert!(
+                cmp::max(value.standing_price, standing) - cmp::min(value.standing_price, standing)
+                    <= cent
+            );
+            assert!(cmp::max(value.goal_price, goal) - cmp::min(value.goal_price, goal) <= cent);
+            assert!((value.dual_delta - opt.bs_dual_delta(now, btc, 0.8)).abs() < 1.0e-9);
+            assert_eq!(ModelValues::new(opt, now, btc), *value);
+        }
+        assert!(ModelValues::for_expiry(&[], now, btc).is_empty());
+    }
+}
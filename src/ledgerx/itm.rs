@@ -0,0 +1,185 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! In-the-Money Alerts
+//!
+//! We only ever sell OTM options, but the market can move through our strikes.
+//! This module tracks, for each short position, whether the current price is
+//! safely away from the strike, within a configurable buffer of it, or through
+//! it. Changes in this level (in either direction) are reported via Prowl, and
+//! on a breach we may optionally try to buy the position back.
+//!
+
+use crate::option;
+use crate::units::Price;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::ContractId;
+
+/// Settings for ITM alerts
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Whether to monitor short positions at all
+    pub enabled: bool,
+    /// Distance from the strike, as a percentage of the strike, within which we alert
+    pub buffer_pct: u32,
+    /// If set, on a breach we bid to buy back the position provided that the total
+    /// cost of doing so at the best ask does not exceed this amount
+    pub max_buyback: Option<Price>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: false,
+            buffer_pct: 5,
+            max_buyback: None,
+        }
+    }
+}
+
+/// How close a short option is to being in the money
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Level {
+    /// Out of the money by more than the buffer
+    Safe,
+    /// Out of the money, but within the buffer
+    Near,
+    /// In the money
+    Breached,
+}
+
+impl Level {
+    /// Classifies an option given the current BTC price and buffer
+    pub fn classify(opt: &option::Option, btc_price: Price, buffer_pct: u32) -> Self {
+        if opt.in_the_money(btc_price) {
+            return Level::Breached;
+        }
+        let buffer = f64::from(buffer_pct) / 100.0;
+        let near = match opt.pc {
+            option::PutCall::Call => btc_price >= opt.strike.scale_approx(1.0 - buffer),
+            option::PutCall::Put => btc_price <= opt.strike.scale_approx(1.0 + buffer),
+        };
+        if near {
+            Level::Near
+        } else {
+            Level::Safe
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Level::Safe => f.write_str("safe"),
+            Level::Near => f.write_str("near strike"),
+            Level::Breached => f.write_str("in the money"),
+        }
+    }
+}
+
+/// Tracker for the alert level of each short position
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Tracker {
+    settings: Settings,
+    levels: HashMap<ContractId, Level>,
+    /// Contracts for which we have already attempted a buy-back
+    bought_back: HashSet<ContractId>,
+}
+
+impl Tracker {
+    /// Creates a new tracker with no known positions
+    pub fn new(settings: Settings) -> Self {
+        Tracker {
+            settings,
+            levels: HashMap::new(),
+            bought_back: HashSet::new(),
+        }
+    }
+
+    /// Accessor for the settings
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Records the level of a short position, returning the previous level if it changed
+    ///
+    /// Positions we have not seen before are considered to have been safe.
+    pub fn update(&mut self, cid: ContractId, level: Level) -> Option<Level> {
+        let old = self.levels.insert(cid, level).unwrap_or(Level::Safe);
+        if old != level {
+            Some(old)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets about any positions which are no longer short
+    pub fn retain(&mut self, mut is_short: impl FnMut(ContractId) -> bool) {
+        self.levels.retain(|cid, _| is_short(*cid));
+        self.bought_back.retain(|cid| is_short(*cid));
+    }
+
+    /// Returns whether we should attempt a buy-back, marking it as attempted if so
+    pub fn should_buy_back(&mut self, cid: ContractId) -> bool {
+        self.settings.max_buyback.is_some() && self.bought_back.insert(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::UtcTime;
+    use std::str::FromStr;
+
+    #[test]
+    fn classify() {
+        let expiry = UtcTime::now();
+        let put = option::Option::new_put(Price::from_str("30000").unwrap(), expiry);
+        let call = option::Option::new_call(Price::from_str("50000").unwrap(), expiry);
+
+        let price = |s| Price::from_str(s).unwrap();
+        assert_eq!(Level::classify(&put, price("40000"), 5), Level::Safe);
+        assert_eq!(Level::classify(&put, price("31000"), 5), Level::Near);
+        assert_eq!(Level::classify(&put, price("31000"), 0), Level::Safe);
+        assert_eq!(Level::classify(&put, price("30000"), 5), Level::Breached);
+        assert_eq!(Level::classify(&put, price("29000"), 0), Level::Breached);
+
+        assert_eq!(Level::classify(&call, price("40000"), 5), Level::Safe);
+        assert_eq!(Level::classify(&call, price("48000"), 5), Level::Near);
+        assert_eq!(Level::classify(&call, price("51000"), 5), Level::Breached);
+    }
+
+    #[test]
+    fn tracker() {
+        let mut tracker = Tracker::new(Settings {
+            enabled: true,
+            buffer_pct: 5,
+            max_buyback: Some(Price::ONE_THOUSAND),
+        });
+        let cid = ContractId::from(1);
+        assert_eq!(tracker.update(cid, Level::Safe), None);
+        assert_eq!(tracker.update(cid, Level::Near), Some(Level::Safe));
+        assert_eq!(tracker.update(cid, Level::Near), None);
+        assert_eq!(tracker.update(cid, Level::Breached), Some(Level::Near));
+        assert!(tracker.should_buy_back(cid));
+        assert!(!tracker.should_buy_back(cid));
+        assert_eq!(tracker.update(cid, Level::Near), Some(Level::Breached));
+
+        tracker.retain(|_| false);
+        assert_eq!(tracker.update(cid, Level::Near), Some(Level::Safe));
+        assert!(tracker.should_buy_back(cid));
+    }
+}
@@ -18,45 +18,10 @@
 //!
 
 use crate::units::{Price, Quantity, Underlying, UtcTime};
-use serde::{de, Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::fmt;
 
-fn deserialize_datetime<'de, D>(deser: D) -> Result<Option<UtcTime>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    use chrono::DateTime;
-
-    let s: Option<&str> = Deserialize::deserialize(deser)?;
-    match s {
-        Some(s) => Ok(Some(
-            DateTime::parse_from_str(s, "%F %T%z")
-                .map_err(|_| {
-                    de::Error::invalid_value(
-                        de::Unexpected::Str(s),
-                        &"a datetime in %F %T%z format",
-                    )
-                })?
-                .into(),
-        )),
-        None => Ok(None),
-    }
-}
-
-fn deserialize_timestamp<'de, D>(deser: D) -> Result<UtcTime, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: i64 = Deserialize::deserialize(deser)?;
-    UtcTime::from_unix_nanos_i64(s).map_err(|_| {
-        de::Error::invalid_value(
-            de::Unexpected::Signed(s),
-            &"a timestamp in range for the datetime type",
-        )
-    })
-}
-
 /// The type of the derivative
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -142,14 +107,14 @@ impl TryFrom<usize> for StatusReason {
 /// Copy of the "contract" as returned from the /contracts endpoint
 #[derive(Deserialize, Debug)]
 pub struct Contract {
-    pub id: usize,
+    pub id: super::ContractId,
     pub active: bool,
     pub underlying_asset: Underlying,
-    #[serde(default, deserialize_with = "deserialize_datetime")]
+    #[serde(default, deserialize_with = "crate::units::deserialize_lx_time_opt")]
     pub date_exercise: Option<UtcTime>,
-    #[serde(default, deserialize_with = "deserialize_datetime")]
+    #[serde(default, deserialize_with = "crate::units::deserialize_lx_time_opt")]
     pub date_expires: Option<UtcTime>,
-    #[serde(default, deserialize_with = "deserialize_datetime")]
+    #[serde(default, deserialize_with = "crate::units::deserialize_lx_time_opt")]
     pub date_live: Option<UtcTime>,
     pub is_call: Option<bool>,
     pub is_next_day: Option<bool>,
@@ -229,11 +194,19 @@ pub struct ChatCounterparty {
     pub is_online: bool,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ChatSender {
+    pub chat_username: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MessageInner {
     pub message: String,
     pub counterparty: ChatCounterparty,
     pub initiator: ChatCounterparty,
+    /// Which of the two sent the message, if given
+    #[serde(default)]
+    pub sender: Option<ChatSender>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -275,11 +248,11 @@ pub enum DataFeedObject {
         status_reason: Option<StatusReason>,
         /// "The current clock for the entire contract"
         clock: u64,
-        #[serde(deserialize_with = "deserialize_timestamp")]
+        #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
         timestamp: UtcTime,
-        #[serde(deserialize_with = "deserialize_timestamp")]
+        #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
         inserted_time: UtcTime,
-        #[serde(deserialize_with = "deserialize_timestamp")]
+        #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
         updated_time: UtcTime,
         #[serde(default)]
         _meta: Option<DataFeedMeta>,
@@ -292,7 +265,21 @@ pub enum DataFeedObject {
     ContractRemoved {
         data: crate::ledgerx::Contract,
     },
-    TradeBusted {},
+    /// A trade has been reversed by the exchange. The fields match those of
+    /// the fill's `action_report`.
+    TradeBusted {
+        contract_id: super::ContractId,
+        #[serde(deserialize_with = "hex::serde::deserialize")]
+        mid: [u8; 16],
+        #[serde(deserialize_with = "crate::units::deserialize_cents")]
+        filled_price: Price,
+        filled_size: i64,
+        is_ask: bool,
+        #[serde(default)]
+        cid: Option<usize>,
+        #[serde(deserialize_with = "crate::units::deserialize_lx_time")]
+        timestamp: UtcTime,
+    },
     Meta {},
     OpenPositionsUpdate {},
     CollateralBalanceUpdate {
@@ -367,32 +354,39 @@ pub struct CreateOrder {
     size: i64,
     /// Price of the order, in cents
     price: i64,
+    /// Whether the order is meant to cross the book, i.e. is exempt from
+    /// post-only checks. Not sent to LX.
+    #[serde(skip)]
+    taker: bool,
+    /// Tick size of the contract, in cents. Not sent to LX.
+    #[serde(skip)]
+    tick: i64,
 }
 
 impl CreateOrder {
-    /// Constructs a new bid with the given price, rounded down to the nearest dollar.
+    /// Constructs a new bid with the given price, rounded down to the contract's tick size.
     ///
     /// # Panics
     ///
     /// Panics if the contract is not an option (futures I never intend to trade, and
     /// BTC I don't currently intend to trade automatically and am uncertain how to
     /// specify the quantity in the JSON API), or if the quantity is inconsistent
-    /// with the contract (meaning: it is neither Zero nor a number of contracts).
+    /// with the contract (meaning: it is not a whole number of contracts).
     pub fn new_bid(contract: &super::Contract, qty: Quantity, price: Price) -> Self {
-        let price = price.round_down();
+        let price = price.round_down_to(contract.tick_size());
         Self::new_internal(contract, qty, price, false)
     }
 
-    /// Constructs a new ask with the given price, rounded up to the nearest dollar.
+    /// Constructs a new ask with the given price, rounded up to the contract's tick size.
     ///
     /// # Panics
     ///
     /// Panics if the contract is not an option (futures I never intend to trade, and
     /// BTC I don't currently intend to trade automatically and am uncertain how to
     /// specify the quantity in the JSON API), or if the quantity is inconsistent
-    /// with the contract (meaning: it is neither Zero nor a number of contracts).
+    /// with the contract (meaning: it is not a whole number of contracts).
     pub fn new_ask(contract: &super::Contract, qty: Quantity, price: Price) -> Self {
-        let price = price.round_up();
+        let price = price.round_up_to(contract.tick_size());
         Self::new_internal(contract, qty, price, true)
     }
 
@@ -400,12 +394,9 @@ impl CreateOrder {
         if !matches!(contract.ty(), super::contract::Type::Option { .. }) {
             panic!("Tried to create bid for non-option contract {}", contract);
         }
-        let size = match qty {
-            Quantity::Contracts(n) => n,
-            _ => panic!(
-                "Tried to create option bid with invalid quantity type {}",
-                qty
-            ),
+        let size = match contract.contracts(qty) {
+            Ok(n) => n,
+            Err(e) => panic!("Tried to create option order with invalid quantity: {}", e),
         };
         CreateOrder {
             order_type: "limit",
@@ -414,7 +405,73 @@ impl CreateOrder {
             swap_purpose: "undisclosed",
             size,
             price: price.to_cents(),
+            taker: false,
+            tick: contract.tick_size().to_cents(),
+        }
+    }
+
+    /// Checks that the order is one LX will accept: a positive size, and a
+    /// positive price which is a multiple of the contract's tick size
+    pub fn check(&self) -> Result<(), String> {
+        if self.size <= 0 {
+            return Err(format!("order {self} has non-positive size"));
+        }
+        if self.price <= 0 {
+            return Err(format!("order {self} has non-positive price"));
+        }
+        if !self.price().is_multiple_of(self.tick()) {
+            return Err(format!(
+                "order {self} is not a multiple of the tick size {}",
+                self.tick()
+            ));
         }
+        Ok(())
+    }
+
+    /// Marks the order as one which is intended to cross the book
+    pub fn into_taker(self) -> Self {
+        CreateOrder {
+            taker: true,
+            ..self
+        }
+    }
+
+    /// Returns a copy of the order at a different price
+    pub fn with_price(&self, price: Price) -> Self {
+        CreateOrder {
+            price: price.to_cents(),
+            ..*self
+        }
+    }
+
+    /// ID of the contract being traded
+    pub fn contract_id(&self) -> super::ContractId {
+        self.contract_id
+    }
+
+    /// Whether this is an ask (otherwise it is a bid)
+    pub fn is_ask(&self) -> bool {
+        self.is_ask
+    }
+
+    /// Size of the order, in contracts
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+
+    /// Whether the order is intended to cross the book
+    pub fn is_taker(&self) -> bool {
+        self.taker
+    }
+
+    /// Limit price of the order
+    pub fn price(&self) -> Price {
+        Price::from_cents(self.price)
+    }
+
+    /// Tick size of the contract being traded
+    pub fn tick(&self) -> Price {
+        Price::from_cents(self.tick)
     }
 }
 
@@ -422,8 +479,11 @@ impl fmt::Display for CreateOrder {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "[ {}: {} @ {} ]",
-            self.contract_id, self.size, self.price,
+            "[ {}: {} @ {}{} ]",
+            self.contract_id,
+            self.size,
+            self.price,
+            if self.taker { " (taker)" } else { "" },
         )
     }
 }
@@ -449,10 +509,57 @@ mod tests {
                 swap_purpose: "undisclosed",
                 size: 100,
                 price: 10000,
+                taker: false,
+                tick: 10,
             },
         );
     }
 
+    #[test]
+    fn tick_sizes() {
+        let contract = |label: &str, underlying: &str, multiplier: usize, min_increment: usize| {
+            serde_json::from_str::<crate::ledgerx::Contract>(&format!(
+                "{{\"active\":true,\"collateral_asset\":\"USD\",\"date_exercise\":\"2030-06-28 22:00:00+0000\",\"date_expires\":\"2030-06-28 21:00:00+0000\",\"date_live\":\"2030-01-01 05:00:00+0000\",\"derivative_type\":\"options_contract\",\"id\":1,\"is_call\":false,\"is_ecp_only\":false,\"is_next_day\":false,\"label\":\"{label}\",\"min_increment\":{min_increment},\"multiplier\":{multiplier},\"name\":null,\"open_interest\":null,\"strike_price\":5000000,\"type\":\"put\",\"underlying_asset\":\"{underlying}\"}}"
+            ))
+            .unwrap()
+        };
+        let p = |s: &str| -> Price { s.parse().unwrap() };
+        let btc = bitcoin::SignedAmount::from_btc;
+
+        // BTC minis: whole-dollar ticks, 0.01 BTC per contract
+        let mini = contract("BTC-Mini-28JUN2030-50000-Put", "BTC", 100, 100);
+        assert_eq!(mini.tick_size(), p("1"));
+        let bid = CreateOrder::new_bid(&mini, Quantity::Contracts(3), p("123.45"));
+        let ask = CreateOrder::new_ask(&mini, Quantity::Contracts(3), p("123.45"));
+        assert_eq!((bid.price(), ask.price()), (p("123"), p("124")));
+        assert_eq!(mini.contracts(Quantity::Bitcoin(btc(0.05).unwrap())), Ok(5));
+        assert!(mini
+            .contracts(Quantity::Bitcoin(btc(0.005).unwrap()))
+            .is_err());
+
+        // Full-size BTC: one BTC per contract
+        let full = contract("BTC-28JUN2030-50000-Put", "BTC", 1, 100);
+        assert_eq!(full.contracts(Quantity::Bitcoin(btc(2.0).unwrap())), Ok(2));
+        assert!(full
+            .contracts(Quantity::Bitcoin(btc(0.5).unwrap()))
+            .is_err());
+
+        // ETH: ten-cent ticks, and BTC quantities make no sense
+        let eth = contract("ETH-28JUN2030-5000-Put", "ETH", 10, 10);
+        assert_eq!(eth.tick_size(), p("0.10"));
+        let bid = CreateOrder::new_bid(&eth, Quantity::Contracts(3), p("12.345"));
+        let ask = CreateOrder::new_ask(&eth, Quantity::Contracts(3), p("12.345"));
+        assert_eq!((bid.price(), ask.price()), (p("12.30"), p("12.40")));
+        assert!(eth.contracts(Quantity::Bitcoin(btc(1.0).unwrap())).is_err());
+
+        // Orders LX would reject
+        assert!(bid.check().is_ok());
+        assert!(bid.with_price(p("12.35")).check().is_err());
+        assert!(bid.with_price(Price::ZERO).check().is_err());
+        let empty = CreateOrder::new_bid(&mini, Quantity::Contracts(0), p("100"));
+        assert!(empty.check().is_err());
+    }
+
     #[test]
     fn fixed_vector_contracts() {
         let vecs = vec![
@@ -0,0 +1,201 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! New Listings
+//!
+//! When LX lists a new expiry, or a strike it doesn't usually offer, the
+//! books start out empty and spreads are wide, so it pays to quote early.
+//! This module summarizes newly-listed options, with their model prices at
+//! the IVs we normally quote at, so that they can be logged and sent as a
+//! notification.
+//!
+
+use super::interesting::{GOAL_IV, STANDING_IV};
+use super::Contract;
+use crate::option;
+use crate::price::BitcoinPrice;
+use crate::units::{Price, UtcTime};
+use std::collections::HashSet;
+use std::fmt;
+
+/// A single newly-listed option
+#[derive(Clone, PartialEq, Debug)]
+pub struct Listing {
+    /// The LX label of the contract
+    pub label: String,
+    /// The option itself
+    pub option: option::Option,
+    /// Whether no previously-listed option has this expiry
+    pub new_expiry: bool,
+    /// Whether no previously-listed option has this strike
+    pub unusual_strike: bool,
+    /// Model price at the IV we quote puts we want assigned on
+    pub goal_price: Price,
+    /// Model price at the IV we start standing asks at
+    pub standing_price: Price,
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} at {:.0}% IV, {} at {:.0}% IV",
+            self.label,
+            self.goal_price,
+            GOAL_IV * 100.0,
+            self.standing_price,
+            STANDING_IV * 100.0,
+        )?;
+        if self.new_expiry {
+            f.write_str(" (new expiry)")?;
+        }
+        if self.unusual_strike {
+            f.write_str(" (unusual strike)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Summary of a batch of newly-listed options
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Report {
+    /// The new options, sorted by expiry and strike
+    pub listings: Vec<Listing>,
+}
+
+impl Report {
+    /// Builds a report on the options among `new`, comparing them against
+    /// the previously-known contracts `existing`
+    ///
+    /// Contracts which are inactive, expired, or not options (e.g. the
+    /// day-ahead swaps, which are listed every day) are ignored.
+    pub fn new<'a, I>(new: &[Contract], existing: I, btc_price: BitcoinPrice, now: UtcTime) -> Self
+    where
+        I: IntoIterator<Item = &'a Contract>,
+    {
+        let mut expiries = HashSet::new();
+        let mut strikes = HashSet::new();
+        for opt in existing.into_iter().filter_map(Contract::as_option) {
+            expiries.insert(opt.expiry);
+            strikes.insert(opt.strike);
+        }
+
+        let btc = btc_price.btc_price;
+        let mut listings: Vec<Listing> = new
+            .iter()
+            .filter(|c| c.active())
+            .filter_map(|c| Some((c, c.as_option()?)))
+            .filter(|(_, opt)| opt.expiry > now)
+            .map(|(c, opt)| Listing {
+                label: c.label().to_owned(),
+                option: opt,
+                new_expiry: !expiries.contains(&opt.expiry),
+                unusual_strike: !strikes.contains(&opt.strike),
+                goal_price: opt.bs_price(now, btc, GOAL_IV),
+                standing_price: opt.bs_price(now, btc, STANDING_IV),
+            })
+            .collect();
+        listings.sort_by_key(|l| (l.option.expiry, l.option.strike, l.label.clone()));
+        Report { listings }
+    }
+
+    /// Whether there is anything to report
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_empty()
+    }
+
+    /// A one-paragraph summary, suitable for a notification
+    pub fn notification(&self) -> String {
+        let mut new_expiries: Vec<String> = self
+            .listings
+            .iter()
+            .filter(|l| l.new_expiry)
+            .map(|l| l.option.expiry.format("%F").to_string())
+            .collect();
+        new_expiries.dedup();
+        let mut ret = format!("{} new LX options listed", self.listings.len());
+        if !new_expiries.is_empty() {
+            ret.push_str(&format!(" (new expiries {})", new_expiries.join(", ")));
+        }
+        for listing in &self.listings {
+            ret.push_str(&format!("\n{listing}"));
+        }
+        ret
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "New listings ({}):", self.listings.len())?;
+        for listing in &self.listings {
+            writeln!(f, "    {listing}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn new_expiries_and_strikes() {
+        let contract = |id: usize, label: &str, date: &str, strike: usize| -> Contract {
+            let json = serde_json::json!({
+                "id": id,
+                "active": true,
+                "collateral_asset": "USD",
+                "date_exercise": format!("{date} 22:00:00+0000"),
+                "date_expires": format!("{date} 21:00:00+0000"),
+                "date_live": "2030-01-01 05:00:00+0000",
+                "derivative_type": "options_contract",
+                "is_call": false,
+                "is_ecp_only": false,
+                "is_next_day": false,
+                "label": label,
+                "min_increment": 100,
+                "multiplier": 100,
+                "name": null,
+                "open_interest": null,
+                "strike_price": strike * 100,
+                "type": "put",
+                "underlying_asset": "BTC",
+            });
+            serde_json::from_str(&json.to_string()).unwrap()
+        };
+        let existing = vec![
+            contract(1, "BTC-Mini-28JUN2030-60000-Put", "2030-06-28", 60000),
+            contract(2, "BTC-Mini-28JUN2030-50000-Put", "2030-06-28", 50000),
+        ];
+        let new = vec![
+            contract(3, "BTC-Mini-27SEP2030-60000-Put", "2030-09-27", 60000),
+            contract(4, "BTC-Mini-28JUN2030-55500-Put", "2030-06-28", 55500),
+        ];
+        let btc_price = BitcoinPrice::from_current(Price::from_str("60000").unwrap());
+        let report = Report::new(&new, &existing, btc_price, UtcTime::now());
+        assert_eq!(report.listings.len(), 2);
+        assert_eq!(report.listings[0].label, "BTC-Mini-28JUN2030-55500-Put");
+        assert!(!report.listings[0].new_expiry);
+        assert!(report.listings[0].unusual_strike);
+        assert_eq!(report.listings[1].label, "BTC-Mini-27SEP2030-60000-Put");
+        assert!(report.listings[1].new_expiry);
+        assert!(!report.listings[1].unusual_strike);
+        // Goal IV is lower than standing IV, so gives a lower price
+        assert!(report.listings[1].goal_price < report.listings[1].standing_price);
+        assert!(report
+            .notification()
+            .starts_with("2 new LX options listed (new expiries 2030-09-27)"));
+    }
+}
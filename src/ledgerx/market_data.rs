@@ -0,0 +1,349 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Market Data Archive
+//!
+//! Our historic price data only covers BTC itself. To study how LX's options
+//! were actually priced, we also archive LX's own market data: at each market
+//! close, and when `connect` shuts down, we record the open interest and top
+//! of book of every option we are tracking, along with the settlement outcome
+//! of any option which expired that day. Options which expired on an earlier
+//! day were settled in that day's file, so are left out.
+//!
+//! The archive is stored as one JSON file per day, keyed by contract ID. Later
+//! snapshots on the same day are merged into the file, replacing earlier data
+//! for the same contracts.
+//!
+//! Settlement outcomes are computed from our own price reference at the time
+//! of the snapshot, not LX's, so may differ slightly from LX's assignments.
+//!
+
+use super::snapshot::Snapshot;
+use super::ContractId;
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Version of the on-disk format
+pub const MARKET_DATA_VERSION: u32 = 1;
+
+/// The archived market data of a single day
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Day {
+    /// Version of the format this day was written in
+    pub version: u32,
+    /// Time of the most recent snapshot merged into this day
+    #[serde(with = "crate::units::serde_ts_seconds")]
+    pub time: UtcTime,
+    /// BTC price reference at that time
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub btc_price: Price,
+    /// Market data of each option
+    pub contracts: BTreeMap<ContractId, ContractData>,
+}
+
+/// The archived market data of a single option
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ContractData {
+    /// Contract label
+    pub label: String,
+    /// Expiry of the option
+    #[serde(with = "crate::units::serde_ts_seconds")]
+    pub expiry: UtcTime,
+    /// Most recently reported open interest, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_interest: Option<usize>,
+    /// Best bid (zero if none)
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub best_bid: Price,
+    /// Number of contracts at the best bid
+    pub bid_size: i64,
+    /// Best ask (zero if none)
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub best_ask: Price,
+    /// Number of contracts at the best ask
+    pub ask_size: i64,
+    /// If the option had expired, how it settled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settlement: Option<Settlement>,
+}
+
+/// The settlement outcome of an expired option
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Settlement {
+    /// BTC price reference at which the outcome was computed
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub btc_price: Price,
+    /// Whether the option expired in the money
+    pub in_the_money: bool,
+    /// Value of the option at expiry, per BTC
+    #[serde(
+        deserialize_with = "crate::units::deserialize_dollars",
+        serialize_with = "crate::units::serialize_dollars"
+    )]
+    pub intrinsic: Price,
+}
+
+impl Day {
+    /// Extracts the market data of every option in a snapshot, other than
+    /// those which expired before the snapshot's day
+    pub fn from_snapshot(snapshot: &Snapshot) -> Self {
+        let today = snapshot.timestamp.format("%F").to_string();
+        let btc_price = snapshot.prices.last().btc_price;
+        let mut contracts = BTreeMap::new();
+        for (cid, (contract, book)) in &snapshot.contracts {
+            let opt = match contract.as_option() {
+                Some(opt) => opt,
+                None => continue,
+            };
+            if opt.expiry <= snapshot.timestamp && opt.expiry.format("%F").to_string() != today {
+                continue;
+            }
+            let (best_bid, bid_size) = book.best_bid();
+            let (best_ask, ask_size) = book.best_ask();
+            let settlement = if opt.expiry <= snapshot.timestamp {
+                Some(Settlement {
+                    btc_price,
+                    in_the_money: opt.in_the_money(btc_price),
+                    intrinsic: opt.intrinsic_value(btc_price).max(Price::ZERO),
+                })
+            } else {
+                None
+            };
+            contracts.insert(
+                *cid,
+                ContractData {
+                    label: contract.label().into(),
+                    expiry: opt.expiry,
+                    open_interest: snapshot.open_interest.get(cid).copied(),
+                    best_bid,
+                    bid_size: contract.contracts(bid_size).unwrap_or(0),
+                    best_ask,
+                    ask_size: contract.contracts(ask_size).unwrap_or(0),
+                    settlement,
+                },
+            );
+        }
+        Day {
+            version: MARKET_DATA_VERSION,
+            time: snapshot.timestamp,
+            btc_price,
+            contracts,
+        }
+    }
+
+    /// Merges a later snapshot of the same day into this one
+    pub fn merge(&mut self, later: Day) {
+        self.version = later.version;
+        self.time = later.time;
+        self.btc_price = later.btc_price;
+        self.contracts.extend(later.contracts);
+    }
+}
+
+/// Path of the file in which a given day's data is stored
+fn day_path(datadir: &Path, time: UtcTime) -> std::path::PathBuf {
+    datadir.join(format!("{}.json", time.format("%F")))
+}
+
+/// Reads a single day's market data from disk
+fn read_day(path: &Path) -> anyhow::Result<Day> {
+    let data = fs::read_to_string(path).context("reading file")?;
+    let day: Day = serde_json::from_str(&data).context("decoding json")?;
+    if day.version == 0 || day.version > MARKET_DATA_VERSION {
+        return Err(anyhow::Error::msg(format!(
+            "market data has version {}, but we only understand up to {}; \
+             you may need to update this software",
+            day.version, MARKET_DATA_VERSION,
+        )));
+    }
+    Ok(day)
+}
+
+/// Archives the market data in a snapshot, merging it into the file for its day
+///
+/// Returns the number of contracts recorded.
+pub fn archive(datadir: &Path, snapshot: &Snapshot) -> anyhow::Result<usize> {
+    let new = Day::from_snapshot(snapshot);
+    let n_contracts = new.contracts.len();
+    if n_contracts == 0 {
+        return Ok(0);
+    }
+    fs::create_dir_all(datadir).context("creating market data directory")?;
+    let path = day_path(datadir, snapshot.timestamp);
+    let day = if path.exists() {
+        let mut day = read_day(&path).with_context(|| format!("reading {}", path.display()))?;
+        day.merge(new);
+        day
+    } else {
+        new
+    };
+    crate::file::write_atomic(&path, |w| {
+        serde_json::to_writer(w, &day).context("writing json")
+    })
+    .with_context(|| format!("writing {}", path.display()))?;
+    Ok(n_contracts)
+}
+
+/// Reads every day of archived market data, by date
+pub fn read_all(datadir: &Path) -> anyhow::Result<BTreeMap<String, Day>> {
+    let mut ret = BTreeMap::new();
+    if !datadir.exists() {
+        return Ok(ret);
+    }
+    for file in fs::read_dir(datadir).context("opening market data directory")? {
+        let path = file.context("getting file path")?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let day = read_day(&path).with_context(|| format!("reading {}", path.display()))?;
+            ret.insert(day.time.format("%F").to_string(), day);
+        }
+    }
+    Ok(ret)
+}
+
+/// Logs a summary of the archived market data
+pub fn log_info(datadir: &Path) -> anyhow::Result<()> {
+    let days = read_all(datadir)?;
+    let (first, last) = match (days.keys().next(), days.keys().next_back()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            info!("No LX market data recorded.");
+            return Ok(());
+        }
+    };
+    let n_snapshots: usize = days.values().map(|day| day.contracts.len()).sum();
+    let n_settlements = days
+        .values()
+        .flat_map(|day| day.contracts.values())
+        .filter(|data| data.settlement.is_some())
+        .count();
+    info!(
+        "LX market data: {} days from {} to {}; {} contract snapshots, {} settlements",
+        days.len(),
+        first,
+        last,
+        n_snapshots,
+        n_settlements,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledgerx::{BookState, Contract, LedgerX};
+    use crate::price::{BitcoinPrice, PriceBoard};
+    use std::str::FromStr;
+
+    #[test]
+    fn archive_roundtrip() {
+        let now = UtcTime::parse_coinbase("2024-03-29T20:00:00Z").unwrap();
+        let price = BitcoinPrice {
+            timestamp: now,
+            btc_price: Price::from_str("60000").unwrap(),
+        };
+        let prices = PriceBoard::new("test", price, chrono::Duration::seconds(300), 100);
+        let tracker = LedgerX::new(
+            prices,
+            25,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let mut snapshot = tracker.snapshot(now);
+
+        let contract = serde_json::json!({
+            "id": 1,
+            "active": false,
+            "collateral_asset": "USD",
+            "date_exercise": "2024-03-29 21:00:00+0000",
+            "date_expires": "2024-03-29 20:00:00+0000",
+            "date_live": "2024-01-01 05:00:00+0000",
+            "derivative_type": "options_contract",
+            "is_call": false,
+            "is_ecp_only": false,
+            "is_next_day": false,
+            "label": "BTC-Mini-29MAR2024-50000-Put",
+            "min_increment": 100,
+            "multiplier": 100,
+            "name": null,
+            "open_interest": null,
+            "strike_price": 5000000,
+            "type": "put",
+            "underlying_asset": "BTC",
+        });
+        let expired_put: Contract = serde_json::from_str(&contract.to_string()).unwrap();
+        let asset = expired_put.asset();
+        snapshot.open_interest.insert(expired_put.id(), 12);
+        snapshot
+            .contracts
+            .insert(expired_put.id(), (expired_put, BookState::new(asset)));
+
+        let day = Day::from_snapshot(&snapshot);
+        assert_eq!(day.contracts.len(), 1);
+        let data = &day.contracts[&ContractId::from(1)];
+        assert_eq!(data.open_interest, Some(12));
+        assert_eq!(data.best_bid, Price::ZERO);
+        assert_eq!(
+            data.settlement,
+            Some(Settlement {
+                btc_price: Price::from_str("60000").unwrap(),
+                in_the_money: false,
+                intrinsic: Price::ZERO,
+            })
+        );
+
+        // Once the day is over, the expired option is not recorded again
+        snapshot.timestamp = UtcTime::parse_coinbase("2024-03-30T20:00:00Z").unwrap();
+        assert!(Day::from_snapshot(&snapshot).contracts.is_empty());
+
+        let json = serde_json::to_string(&day).unwrap();
+        assert_eq!(serde_json::from_str::<Day>(&json).unwrap(), day);
+
+        // Merging replaces data for the same contract
+        let mut merged = day.clone();
+        let mut later = day.clone();
+        later
+            .contracts
+            .get_mut(&ContractId::from(1))
+            .unwrap()
+            .open_interest = Some(20);
+        merged.merge(later);
+        assert_eq!(
+            merged.contracts[&ContractId::from(1)].open_interest,
+            Some(20)
+        );
+    }
+}
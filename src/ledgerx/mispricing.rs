@@ -0,0 +1,232 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Mispricing Alerts
+//!
+//! Independently of whether a bid is "interesting" to us, which depends on our
+//! available collateral, a bid far above the model price is usually a fat
+//! finger or a panic buyer, and worth knowing about. This module compares the
+//! best bid of each option against its Black-Scholes price at a fixed model
+//! IV, alerting when it exceeds it by more than a threshold, and keeps the
+//! biggest mispricing seen in each contract for a daily digest.
+//!
+
+use super::ContractId;
+use crate::option;
+use crate::units::{Price, UtcTime};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Model price (per BTC) below which we don't consider bids, since tiny
+/// prices make the percentage excess meaningless
+const MIN_MODEL_PRICE: Price = Price::ONE_HUNDRED;
+/// Number of contracts to list in the daily digest
+const DIGEST_LEN: usize = 10;
+
+/// Settings for mispricing alerts
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Whether to monitor bids at all
+    pub enabled: bool,
+    /// Percentage above the model price at which a bid is considered mispriced
+    pub threshold_pct: u32,
+    /// Implied volatility, in percent, at which to compute the model price
+    pub model_iv_pct: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: false,
+            threshold_pct: 25,
+            model_iv_pct: (super::interesting::STANDING_IV * 100.0) as u32,
+        }
+    }
+}
+
+/// A bid observed above the model price
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Mispricing {
+    /// Label of the contract
+    pub label: String,
+    /// Time the bid was seen
+    pub time: UtcTime,
+    /// Price of the bid
+    pub bid: Price,
+    /// Model price at that time
+    pub model: Price,
+    /// Amount by which the bid exceeds the model price, in basis points
+    pub excess_bps: i64,
+}
+
+impl Mispricing {
+    /// Compares a bid against the model price of an option, returning a
+    /// mispricing if the bid exceeds it by more than the threshold
+    pub fn check(
+        label: &str,
+        opt: &option::Option,
+        bid: Price,
+        btc_price: Price,
+        now: UtcTime,
+        settings: &Settings,
+    ) -> Option<Self> {
+        if bid == Price::ZERO {
+            return None;
+        }
+        let model = opt.bs_price(now, btc_price, f64::from(settings.model_iv_pct) / 100.0);
+        if model < MIN_MODEL_PRICE {
+            return None;
+        }
+        let excess_bps = ((bid / model - 1.0) * 10_000.0) as i64;
+        if excess_bps <= i64::from(settings.threshold_pct) * 100 {
+            return None;
+        }
+        Some(Mispricing {
+            label: label.into(),
+            time: now,
+            bid,
+            model,
+            excess_bps,
+        })
+    }
+}
+
+impl fmt::Display for Mispricing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: bid {} is {:.1}% above model price {} ({})",
+            self.label,
+            self.bid,
+            self.excess_bps as f64 / 100.0,
+            self.model,
+            self.time.format("%H:%M"),
+        )
+    }
+}
+
+/// Tracker of mispriced bids, for alerting and for the daily digest
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Monitor {
+    settings: Settings,
+    /// For each currently-mispriced contract, the bid we last alerted about
+    alerted: HashMap<ContractId, Price>,
+    /// The biggest mispricing seen in each contract since the last digest
+    worst: HashMap<ContractId, Mispricing>,
+}
+
+impl Monitor {
+    /// Creates a new monitor which has seen nothing
+    pub fn new(settings: Settings) -> Self {
+        Monitor {
+            settings,
+            alerted: HashMap::new(),
+            worst: HashMap::new(),
+        }
+    }
+
+    /// Accessor for the settings
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Records the result of checking a contract's best bid, returning whether
+    /// to alert about it
+    ///
+    /// We alert when a contract becomes mispriced, and again only if its bid
+    /// rises further while it stays mispriced.
+    pub fn update(&mut self, cid: ContractId, mispricing: Option<Mispricing>) -> bool {
+        let mispricing = match mispricing {
+            Some(m) => m,
+            None => {
+                self.alerted.remove(&cid);
+                return false;
+            }
+        };
+        let alert = match self.alerted.get(&cid) {
+            Some(last) => mispricing.bid > *last,
+            None => true,
+        };
+        if alert {
+            self.alerted.insert(cid, mispricing.bid);
+        }
+        match self.worst.get(&cid) {
+            Some(worst) if worst.excess_bps >= mispricing.excess_bps => {}
+            _ => {
+                self.worst.insert(cid, mispricing);
+            }
+        }
+        alert
+    }
+
+    /// Returns the biggest mispricings seen since the last digest, largest
+    /// first, and starts a new digest
+    pub fn take_digest(&mut self) -> Vec<Mispricing> {
+        let mut ret: Vec<Mispricing> = self.worst.drain().map(|(_, m)| m).collect();
+        ret.sort_by_key(|m| std::cmp::Reverse(m.excess_bps));
+        ret.truncate(DIGEST_LEN);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn monitor() {
+        let now = UtcTime::now();
+        let mut opt = option::Option::from_str("2030-06-28P40000").unwrap();
+        opt.expiry = now + chrono::Duration::days(30);
+        let btc = Price::from_str("50000").unwrap();
+        let settings = Settings {
+            enabled: true,
+            threshold_pct: 25,
+            model_iv_pct: 60,
+        };
+        let model = opt.bs_price(now, btc, 0.6);
+        let check = |bid: Price| Mispricing::check("put", &opt, bid, btc, now, &settings);
+
+        assert_eq!(check(Price::ZERO), None);
+        assert_eq!(check(model.scale_approx(1.2)), None);
+        let m = check(model.scale_approx(1.5)).unwrap();
+        assert!((m.excess_bps - 5000).abs() <= 1, "{:?}", m);
+        // Deep OTM options with tiny model prices are ignored
+        let mut far = opt;
+        far.strike = Price::from_str("5000").unwrap();
+        assert_eq!(
+            Mispricing::check("far", &far, Price::ONE_THOUSAND, btc, now, &settings),
+            None
+        );
+
+        let mut monitor = Monitor::new(settings);
+        let (a, b) = (ContractId::from(1), ContractId::from(2));
+        assert!(monitor.update(a, check(model.scale_approx(1.5))));
+        // Same or lower bid: no new alert
+        assert!(!monitor.update(a, check(model.scale_approx(1.5))));
+        assert!(!monitor.update(a, check(model.scale_approx(1.4))));
+        assert!(monitor.update(a, check(model.scale_approx(2.0))));
+        // Back to normal, then mispriced again: alert again
+        assert!(!monitor.update(a, None));
+        assert!(monitor.update(a, check(model.scale_approx(1.3))));
+        assert!(monitor.update(b, check(model.scale_approx(1.6))));
+
+        let digest = monitor.take_digest();
+        assert_eq!(digest.len(), 2);
+        assert!((digest[0].excess_bps - 10_000).abs() <= 1);
+        assert!((digest[1].excess_bps - 6_000).abs() <= 1);
+        assert!(monitor.take_digest().is_empty());
+    }
+}
@@ -17,25 +17,48 @@
 //! Data Structures etc for the LedgerX API
 //!
 
+pub mod block_trade;
 pub mod book;
+pub mod bounds;
+pub mod close;
+pub mod collateral;
 pub mod contract;
+pub mod contract_cache;
 pub mod csv;
 pub mod datafeed;
+pub mod exchange_status;
+pub mod expiry;
+pub mod fees;
+pub mod funding;
+pub mod goals;
+pub mod greek_limits;
 pub mod history;
 pub mod interesting;
+pub mod itm;
 pub mod json;
+pub mod listings;
+pub mod market_data;
+pub mod mispricing;
+pub mod moneyness;
 pub mod own_orders;
+pub mod pin_risk;
+pub mod post_only;
+pub mod quote;
+pub mod roll;
+pub mod rules;
+pub mod skew;
+pub mod slippage;
+pub mod snapshot;
+pub mod stress;
 
-use self::interesting::{AskStats, BidStats};
 use self::json::CreateOrder;
-use crate::price::BitcoinPrice;
-use crate::terminal::ColorFormat;
+use crate::price::{BitcoinPrice, PriceBoard};
+use crate::queue::Sender;
 use crate::units::{Asset, Price, Quantity, Underlying, UtcTime};
 use log::{debug, info, warn};
 use serde::Deserialize;
 use serde_json;
-use std::collections::HashMap;
-use std::sync::mpsc::Sender;
+use std::collections::{HashMap, HashSet};
 
 pub use book::BookState;
 pub use contract::{Contract, ContractId};
@@ -54,6 +77,13 @@ pub enum Error {
     JsonDecoding {},
 }
 
+/// Largest repricing, as a percentage of an order's price, which is not worth
+/// giving up first place in the queue for
+const NEGLIGIBLE_REPRICE_PCT: f64 = 1.0;
+
+/// Minimum number of seconds between periodic book refreshes
+pub const MIN_BOOK_REFRESH_INTERVAL_SECS: i64 = 5;
+
 pub fn from_json_dot_data<'a, T: Deserialize<'a>>(
     data: &'a [u8],
 ) -> Result<Vec<T>, serde_json::Error> {
@@ -65,14 +95,95 @@ pub fn from_json_dot_data<'a, T: Deserialize<'a>>(
     Ok(json.data)
 }
 
+/// Our option positions, given our net position in each contract
+fn portfolio(
+    contracts: &HashMap<ContractId, (Contract, BookState)>,
+    own_positions: &HashMap<ContractId, i64>,
+) -> collateral::Portfolio {
+    let mut ret = collateral::Portfolio::new();
+    for (cid, size) in own_positions {
+        if let Some((contract, _)) = contracts.get(cid) {
+            ret.add_contract(contract, *size);
+        }
+    }
+    ret
+}
+
+/// Our share of the open interest of a contract, as (our position, open interest)
+fn oi_share(
+    open_interest: &HashMap<ContractId, usize>,
+    own_positions: &HashMap<ContractId, i64>,
+    cid: ContractId,
+) -> Option<(i64, usize)> {
+    let oi = *open_interest.get(&cid)?;
+    let ours = own_positions.get(&cid).copied().unwrap_or(0);
+    Some((ours, oi))
+}
+
+/// Whether an order of the given signed size (positive for bids, negative for
+/// asks) would only reduce our position in a contract, rather than open or
+/// flip one
+fn reduces_position(own_positions: &HashMap<ContractId, i64>, cid: ContractId, size: i64) -> bool {
+    let ours = own_positions.get(&cid).copied().unwrap_or(0);
+    ours.signum() == -size.signum() && size.abs() <= ours.abs()
+}
+
 /// Tracker for the state of the entire LX book
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct LedgerX {
     contracts: HashMap<ContractId, (Contract, BookState)>,
-    price_ref: BitcoinPrice,
+    prices: PriceBoard,
     own_orders: own_orders::Tracker,
     available_usd: Price,
     available_btc: bitcoin::Amount,
+    /// Our net position, in contracts, in each contract
+    own_positions: HashMap<ContractId, i64>,
+    /// Most recently reported open interest for each contract
+    open_interest: HashMap<ContractId, usize>,
+    /// Percentage of open interest above which we warn about concentration
+    max_oi_share_pct: u32,
+    /// Contracts which we have already warned about concentration in
+    oi_warned: HashSet<ContractId>,
+    /// Alert levels for our short positions
+    itm: itm::Tracker,
+    /// Rolls of expiring short positions which are in progress
+    roll: roll::Tracker,
+    /// Yield below which interesting contracts are not logged
+    yield_threshold: interesting::YieldThreshold,
+    /// Alerts and daily digest of bids far above the model price
+    mispricing: mispricing::Monitor,
+    /// Settings for skewing standing asks based on our inventory
+    skew: skew::Settings,
+    /// Caps on our aggregate short vega and gamma
+    greek_limits: greek_limits::Settings,
+    /// Days-to-expiry limits on the options we trade
+    dte_filter: interesting::DteFilter,
+    /// The time from which the return of a standing ask is annualized
+    arr_reference: interesting::ArrReference,
+    /// Counts of anomalies in the datafeed contract clocks
+    clock_stats: book::ClockStats,
+    /// Contracts whose books should be refreshed after a gap in the datafeed
+    book_refreshes: HashSet<ContractId>,
+    /// When we last scheduled a periodic book refresh
+    last_scheduled_refresh: Option<UtcTime>,
+    /// Our traded notional, which determines our fee tier
+    volume: fees::Volume,
+}
+
+/// The result of processing a busted trade
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub enum BustResponse {
+    /// One of our fills was reversed
+    OursReversed {
+        /// Net premium which we no longer have (negative if we had paid)
+        premium: Price,
+        /// Size of the reversed fill
+        size: Quantity,
+    },
+    /// The bust was of one of our trades, but we have no record of the fill
+    OursUnmatched,
+    /// The bust was of somebody else's trade
+    Other,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
@@ -80,7 +191,14 @@ pub enum OrderResponse {
     /// This order was our own
     OursOk,
     /// This order was our own and it was filled!
-    OursFilled,
+    OursFilled {
+        /// The order update which reported the fill
+        order: datafeed::Order,
+        /// Net premium received (negative if we paid)
+        premium: Price,
+        /// Slippage data, if we saw the order's creation
+        fill: Option<slippage::Fill>,
+    },
     /// Update was accepted into order book; no new interesting info
     OtherTracked,
     /// Order was ignored because it was a non-BTC order or otherwise
@@ -92,13 +210,89 @@ pub enum OrderResponse {
 
 impl LedgerX {
     /// Create a new empty LX tracker
-    pub fn new(btc_price: crate::price::BitcoinPrice) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prices: PriceBoard,
+        max_oi_share_pct: u32,
+        itm_settings: itm::Settings,
+        roll_settings: roll::Settings,
+        yield_threshold: interesting::YieldThreshold,
+        mispricing_settings: mispricing::Settings,
+        skew_settings: skew::Settings,
+        greek_limits: greek_limits::Settings,
+        dte_filter: interesting::DteFilter,
+        arr_reference: interesting::ArrReference,
+    ) -> Self {
         LedgerX {
             contracts: HashMap::new(),
             own_orders: own_orders::Tracker::new(),
-            price_ref: btc_price,
+            prices,
             available_usd: Price::ZERO,
             available_btc: bitcoin::Amount::ZERO,
+            own_positions: HashMap::new(),
+            open_interest: HashMap::new(),
+            max_oi_share_pct,
+            oi_warned: HashSet::new(),
+            itm: itm::Tracker::new(itm_settings),
+            roll: roll::Tracker::new(roll_settings),
+            yield_threshold,
+            mispricing: mispricing::Monitor::new(mispricing_settings),
+            skew: skew_settings,
+            greek_limits,
+            dte_filter,
+            arr_reference,
+            clock_stats: book::ClockStats::default(),
+            book_refreshes: HashSet::new(),
+            last_scheduled_refresh: None,
+            volume: fees::Volume::new(),
+        }
+    }
+
+    /// Sets our net position in a given contract, e.g. from the positions endpoint
+    pub fn set_own_position(&mut self, cid: ContractId, size: i64) {
+        self.own_positions.insert(cid, size);
+        self.check_oi_share(cid);
+    }
+
+    /// Our share of the open interest of a contract, as (our position, open interest)
+    ///
+    /// Returns `None` if we do not know the open interest.
+    pub fn oi_share(&self, cid: ContractId) -> Option<(i64, usize)> {
+        oi_share(&self.open_interest, &self.own_positions, cid)
+    }
+
+    /// Whether an order would only reduce one of our positions, e.g. buying
+    /// back part of a short
+    pub fn reduces_position(&self, order: &CreateOrder) -> bool {
+        let size = if order.is_ask() {
+            -order.size()
+        } else {
+            order.size()
+        };
+        reduces_position(&self.own_positions, order.contract_id(), size)
+    }
+
+    /// Warns if our share of the open interest in a contract exceeds the threshold
+    fn check_oi_share(&mut self, cid: ContractId) {
+        let (ours, oi) = match self.oi_share(cid) {
+            Some((ours, oi)) if oi > 0 => (ours, oi),
+            _ => return,
+        };
+        let pct = ours.unsigned_abs() * 100 / oi as u64;
+        if pct > u64::from(self.max_oi_share_pct) {
+            if self.oi_warned.insert(cid) {
+                let label = self
+                    .contracts
+                    .get(&cid)
+                    .map(|(c, _)| c.label())
+                    .unwrap_or("unknown contract");
+                warn!(
+                    "Our position of {} in {} is {}% of open interest {} (threshold {}%)",
+                    ours, label, pct, oi, self.max_oi_share_pct,
+                );
+            }
+        } else {
+            self.oi_warned.remove(&cid);
         }
     }
 
@@ -106,11 +300,117 @@ impl LedgerX {
     pub fn set_balances(&mut self, usd: Price, btc: bitcoin::Amount) {
         if self.available_usd != usd || self.available_btc != btc {
             info!("Update balances: ${}, {}", usd, btc);
+            crate::events::emit(&crate::schema::Record::balances(UtcTime::now(), usd, btc));
         }
         self.available_usd = usd;
         self.available_btc = btc;
     }
 
+    /// Our record of traded notional
+    pub fn volume(&self) -> &fees::Volume {
+        &self.volume
+    }
+
+    /// Replaces our record of traded notional, e.g. with one from the history
+    pub fn set_volume(&mut self, volume: fees::Volume) {
+        self.volume = volume;
+    }
+
+    /// The fee tier we are in at the given time
+    pub fn fee_tier(&self, now: UtcTime) -> fees::Tier {
+        self.volume.tier(now)
+    }
+
+    /// Our current option positions, for computing collateral requirements
+    pub fn portfolio(&self) -> collateral::Portfolio {
+        portfolio(&self.contracts, &self.own_positions)
+    }
+
+    /// Captures the state the strategy depends on, as of the given time
+    pub fn snapshot(&self, now: UtcTime) -> snapshot::Snapshot {
+        self.snapshot_with(now, self.contracts.clone())
+    }
+
+    /// Captures the state needed to check a single contract for interesting
+    /// bids, as of the given time
+    ///
+    /// Only the books of that contract and of those we hold positions or
+    /// orders in are copied, so unlike [`Self::snapshot`] this is cheap enough
+    /// to take for every book state we receive.
+    fn contract_snapshot(&self, now: UtcTime, cid: ContractId) -> snapshot::Snapshot {
+        let wanted: HashSet<ContractId> = std::iter::once(cid)
+            .chain(self.own_positions.keys().copied())
+            .chain(
+                self.own_orders
+                    .open_order_iter()
+                    .map(|order| order.contract_id),
+            )
+            .collect();
+        let contracts = wanted
+            .into_iter()
+            .filter_map(|cid| self.contracts.get(&cid).map(|data| (cid, data.clone())))
+            .collect();
+        self.snapshot_with(now, contracts)
+    }
+
+    /// Captures the state the strategy depends on, with the given contracts
+    fn snapshot_with(
+        &self,
+        now: UtcTime,
+        contracts: HashMap<ContractId, (Contract, BookState)>,
+    ) -> snapshot::Snapshot {
+        snapshot::Snapshot {
+            timestamp: now,
+            contracts,
+            prices: self.prices.clone(),
+            own_orders: self.own_orders.clone(),
+            roll_legs: self.roll.pending_contracts().collect(),
+            available_usd: self.available_usd,
+            available_btc: self.available_btc,
+            own_positions: self.own_positions.clone(),
+            open_interest: self.open_interest.clone(),
+            yield_threshold: self.yield_threshold,
+            skew: self.skew,
+            greek_limits: self.greek_limits,
+            dte_filter: self.dte_filter,
+            arr_reference: self.arr_reference,
+            fee: self.fee_tier(now).fee,
+        }
+    }
+
+    /// Compares the collateral we expect to have locked up against what LX reports
+    ///
+    /// Logs the expected requirement and warns if it differs from the reported
+    /// one. Returns whether the two matched.
+    pub fn reconcile_collateral(&self, locked_usd: Price, locked_btc: bitcoin::Amount) -> bool {
+        let expected = self.portfolio().requirement();
+        info!(
+            "Expected position-locked collateral: {} (LX reports {}, {})",
+            expected, locked_usd, locked_btc
+        );
+        if expected.usd != locked_usd || expected.btc != locked_btc {
+            warn!(
+                "Collateral mismatch: expected {}, LX reports {}, {}. Our model of the \
+                 LX rules may be wrong, or we may not know about all our positions.",
+                expected, locked_usd, locked_btc,
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Reduces our available balances by the funds committed to orders opened
+    /// from a snapshot
+    pub fn dock_balances(&mut self, usd: Price, btc: bitcoin::Amount) {
+        Self::preemptively_dock_balances(
+            &mut self.available_usd,
+            &mut self.available_btc,
+            usd,
+            btc,
+        );
+    }
+
     /// Reduces the available balances on the assumption that a recently-opened
     /// order will be taken.
     ///
@@ -139,258 +439,153 @@ impl LedgerX {
         }
     }
 
-    /// Updates the price reference.
-    pub fn set_current_price(&mut self, price: BitcoinPrice) {
-        self.price_ref = price;
-    }
-
-    /// Go through the list of all open orders and log them all
-    pub fn log_open_orders(&self) {
-        for order in self.own_orders.open_order_iter() {
-            if let Some((contract, _)) = self.contracts.get(&order.contract_id) {
-                let size = order.size.with_asset_trade(contract.asset());
-                match contract.ty() {
-                    contract::Type::Option { opt, .. } => {
-                        info!("Open order {}:", order.message_id);
-                        opt.log_option_data(
-                            "    ",
-                            self.price_ref.timestamp,
-                            self.price_ref.btc_price,
-                        );
-                        opt.log_order_data(
-                            "    ",
-                            self.price_ref.timestamp,
-                            self.price_ref.btc_price,
-                            order.price,
-                            Some(size),
-                        );
-                        info!("");
-                    }
-                    contract::Type::NextDay { .. } => {
-                        info!(
-                            "Open order {}: {} BTC @ {}",
-                            order.message_id, size, order.price
-                        );
-                    }
-                    contract::Type::Future { .. } => {
-                        info!(
-                            "Open order {}: {} future?? @ {}",
-                            order.message_id, size, order.price
-                        );
-                    }
-                }
-            } else {
-                warn!(
-                    "Have open order for CID {} that we're not tracking.",
-                    order.contract_id
-                );
-            }
-        }
+    /// Updates the price reference from the given source.
+    pub fn set_current_price(&mut self, source: &'static str, price: BitcoinPrice) {
+        self.prices.update(source, price);
     }
 
-    /// Go through the list of all contracts we're tracking and open standing orders on them.
-    ///
-    /// This function is the core (arguably, the entirety) of our trading algo. Currently
-    /// it opens limit asks on each contract subject to various constraints:
-    ///
-    /// 1. It must have a sufficiently high IV and ARR, and sufficiently low loss80.
-    /// 2. The IV must not be too high (otherwise the order is just dumb and LX will
-    ///    probably flag me for it).
+    /// Accessor for the price references and their consensus
+    pub fn prices(&self) -> &PriceBoard {
+        &self.prices
+    }
+
+    /// Checks all our short option positions for strikes that are breached or nearly so
     ///
-    /// If these conditions can't be simultaneously met, no order is opened.
-    pub fn open_standing_orders(&mut self, tx: &Sender<crate::connect::Message>) {
-        let mut order_count = 0;
-        let now = UtcTime::now();
-        for cid in self.contracts.keys() {
-            if let Some((c, book)) = self.contracts.get(cid) {
-                if let Some(stats) = AskStats::standing_order(
-                    self.price_ref,
-                    c,
-                    self.available_usd,
-                    self.available_btc,
-                    book.best_ask().0,
-                ) {
-                    // for now just log
-                    let opt = match interesting::extract_option(c, self.price_ref) {
-                        Some(opt) => opt,
-                        None => continue,
-                    };
-
-                    let msg;
-                    if stats.order_size().is_positive() {
-                        msg = ColorFormat::white("Sell to open: ");
-                        order_count += 1;
-                        let order =
-                            CreateOrder::new_ask(c, stats.order_size(), stats.order_price());
-                        tx.send(crate::connect::Message::OpenOrder(order)).unwrap();
-                    } else {
-                        msg = ColorFormat::pale_yellow("  Would sell: ");
-                    }
+    /// Alerts are sent whenever a position's level changes, in either direction. If
+    /// a buy-back limit is configured, the first time a position goes in the money
+    /// we bid for it at the best ask, provided that the total cost is within the limit.
+    pub fn check_short_strikes(&mut self, tx: &Sender<crate::connect::Message>) {
+        if !self.itm.settings().enabled {
+            return;
+        }
+        let settings = *self.itm.settings();
+        let btc_price = match self.prices.get() {
+            Ok(price) => price.btc_price,
+            Err(_) => return,
+        };
+
+        let own_positions = &self.own_positions;
+        self.itm
+            .retain(|cid| own_positions.get(&cid).copied().unwrap_or(0) < 0);
+        for (cid, size) in own_positions {
+            if *size >= 0 {
+                continue;
+            }
+            let (contract, book) = match self.contracts.get(cid) {
+                Some(data) => data,
+                None => continue,
+            };
+            let opt = match contract.ty() {
+                contract::Type::Option { opt, .. } => opt,
+                _ => continue,
+            };
+            let level = itm::Level::classify(&opt, btc_price, settings.buffer_pct);
+            let old = match self.itm.update(*cid, level) {
+                Some(old) => old,
+                None => continue,
+            };
 
-                    opt.log_option_data(&msg, now, self.price_ref.btc_price);
-                    opt.log_order_data(
-                        &msg,
-                        now,
-                        self.price_ref.btc_price,
-                        stats.order_price(),
-                        Some(stats.order_size()),
+            let message = format!(
+                "Short position {} {} went from {} to {} (BTC price {})",
+                size, contract, old, level, btc_price,
+            );
+            if level > old {
+                warn!("{}", message);
+            } else {
+                info!("{}", message);
+            }
+            crate::http::post_to_prowl(&message);
+
+            if level == itm::Level::Breached && self.itm.should_buy_back(*cid) {
+                // unwrap ok since should_buy_back only returns true if this is set
+                let max_buyback = settings.max_buyback.unwrap();
+                let qty = Quantity::Contracts(-size);
+                let (ask_price, _) = book.best_ask();
+                let cost = ask_price * qty;
+                if ask_price == Price::ZERO || cost > max_buyback {
+                    let message = format!(
+                        "Not buying back {} {}: best ask {} (total {}) exceeds limit {}",
+                        qty, contract, ask_price, cost, max_buyback,
                     );
-                    info!("");
+                    warn!("{}", message);
+                    crate::http::post_to_prowl(&message);
+                } else {
+                    warn!(
+                        "Buying back {} {} at {} (total {})",
+                        qty, contract, ask_price, cost
+                    );
+                    let order = CreateOrder::new_bid(contract, qty, ask_price).into_taker();
+                    tx.send(crate::connect::Message::OpenOrder(order)).unwrap();
                 }
             }
         }
-        info!("Opened {} orders.", order_count);
-    }
-
-    /// Go through the list of all contracts we're tracking and log the interesting ones
-    pub fn log_interesting_contracts(&mut self, tx: &Sender<crate::connect::Message>) {
-        for cid in self.contracts.keys() {
-            if let Some((c, book)) = self.contracts.get(cid) {
-                let (usd, btc) = self.log_interesting_contract(c, book, tx);
-                // Pre-emptively dock our balances based on
-                Self::preemptively_dock_balances(
-                    &mut self.available_usd,
-                    &mut self.available_btc,
-                    usd,
-                    btc,
-                );
-            }
-        }
     }
 
-    /// Log a single interesting contract
+    /// Chooses which of the rolls planned from a snapshot to start
     ///
-    /// This function may do more than log -- it may attempt to match bids that are
-    /// interesting. In this case, it returns the total USD and BTC committed.
-    fn log_interesting_contract(
-        &self,
-        c: &Contract,
-        book: &BookState,
-        tx: &Sender<crate::connect::Message>,
-    ) -> (Price, bitcoin::Amount) {
-        let btc_price = self.price_ref;
-        let now = UtcTime::now();
-        // Extract option, assuming it matches the relevant parameters
-        // (is an option, hasn't expired, BTC not ETH, etc)
-        let opt = match interesting::extract_option(c, self.price_ref) {
-            Some(opt) => opt,
-            None => return (Price::ZERO, bitcoin::Amount::ZERO),
-        };
-
-        // Compute the yield threshold below which the absolute return
-        // is too low to be worth logging (though it may be worth acting
-        // on autonomously). We set this to $25/day which is roughly $750/mo
-        // for now.
-        let dte = opt.years_to_expiry(now) * 365.0;
-        let yield_threshold = Price::TWENTY_FIVE.scale_approx(dte);
-
-        // Iterate through all open bids.
-        let mut available_usd = self.available_usd;
-        let mut available_btc = self.available_btc;
-
-        let mut best_bid = match BidStats::from_order(btc_price, c, Price::ZERO, Quantity::Zero) {
-            Some(stat) => stat,
-            None => return (Price::ZERO, bitcoin::Amount::ZERO),
-        };
-        let mut acc = best_bid;
-        let mut acc_current_funds = best_bid;
-
-        let mut asks_to_make = vec![];
-
-        for bid in book.bids() {
-            let mut stat = match BidStats::from_order(btc_price, c, bid.price, bid.size) {
-                Some(stat) => stat,
-                None => break,
-            };
-            // Once one order is uninteresting, the rest will be.
-            if stat.interestingness() <= interesting::Interestingness::No {
-                break;
-            }
-
-            // Skip 0-size bids which sometimes show up on LX
-            if bid.size.is_zero() {
-                continue;
-            }
+    /// Each position is only rolled once, even if the roll is abandoned, until
+    /// it is closed and reopened. For each roll returned, the caller should call
+    /// [`LedgerX::start_roll`] and then submit its first leg, calling
+    /// [`LedgerX::abort_roll`] if that fails.
+    pub fn choose_rolls(&mut self, planned: Vec<roll::Roll>) -> Vec<roll::Roll> {
+        let own_positions = &self.own_positions;
+        self.roll
+            .retain(|cid| own_positions.get(&cid).copied().unwrap_or(0) < 0);
+        planned
+            .into_iter()
+            .filter(|roll| self.roll.should_roll(roll.from.id()))
+            .collect()
+    }
 
-            // Record unadjusted values
-            if best_bid.order_size().is_zero() {
-                best_bid = stat;
-            }
-            acc += stat;
+    /// Records that the first leg of a roll has been submitted
+    pub fn start_roll(&mut self, roll: roll::Roll, now: UtcTime) {
+        self.roll.start(roll, now);
+    }
 
-            // Adjust for available funds
-            if available_usd < stat.lockup_usd() || available_btc < stat.lockup_btc() {
-                stat.limit_to_funds(available_usd, available_btc);
-            }
-            available_usd -= stat.lockup_usd();
-            available_btc -= stat.lockup_btc();
-            acc_current_funds += stat;
+    /// Abandons a roll whose first leg could not be submitted
+    pub fn abort_roll(&mut self, cid: ContractId) -> Option<roll::Roll> {
+        self.roll.abort(cid)
+    }
 
-            if stat.interestingness() >= interesting::Interestingness::Take
-                && stat.order_size().is_positive()
-            {
-                asks_to_make.push(stat.corresponding_ask());
-            }
+    /// Takes any second-leg roll orders which are ready to submit
+    pub fn take_roll_orders(&mut self) -> Vec<CreateOrder> {
+        self.roll.take_ready()
+    }
 
-            // Once we're out of money no point in continuing to loop through bids
-            if available_usd == Price::ZERO || available_btc == bitcoin::Amount::ZERO {
-                break;
+    /// Abandons any rolls whose first leg has not filled in time, requesting
+    /// cancellation of whatever is left of the first leg on the book
+    pub fn expire_rolls(
+        &mut self,
+        now: UtcTime,
+        tx: &Sender<crate::connect::Message>,
+    ) -> Vec<(roll::Roll, i64)> {
+        let expired = self.roll.expire(now);
+        for (roll, _) in &expired {
+            for order in self.own_orders.open_order_iter() {
+                if order.contract_id == roll.from.id() && order.size.to_i64() > 0 {
+                    info!(
+                        "Cancelling first leg {} of abandoned roll",
+                        order.message_id
+                    );
+                    tx.send(crate::connect::Message::CancelOrder {
+                        message_id: order.message_id,
+                        contract_id: order.contract_id,
+                    })
+                    .unwrap();
+                }
             }
         }
+        expired
+    }
 
-        // Once we've looped through the order book, log what we found.
-        let mut ret_usd = Price::ZERO;
-        let mut ret_btc = bitcoin::Amount::ZERO;
-        if best_bid.order_size().is_positive() && acc.total_value() > yield_threshold {
-            // Log the non-order-specific contract data.
-            opt.log_option_data(
-                ColorFormat::light_purple("Interesting contract: "),
-                now,
-                btc_price.btc_price,
-            );
+    /// Accessor for the roll settings
+    pub fn roll_settings(&self) -> &roll::Settings {
+        self.roll.settings()
+    }
 
-            if best_bid.total_value() > yield_threshold {
-                opt.log_order_data(
-                    "            Best Bid: ",
-                    now,
-                    btc_price.btc_price,
-                    best_bid.order_price(),
-                    Some(best_bid.order_size()),
-                );
-            }
-            if best_bid != acc {
-                opt.log_order_data(
-                    "     Accum. Good Bid: ",
-                    now,
-                    btc_price.btc_price,
-                    acc.order_price(),
-                    Some(acc.order_size()),
-                );
-            }
-            if acc_current_funds != acc {
-                opt.log_order_data(
-                    "With available funds: ",
-                    now,
-                    btc_price.btc_price,
-                    acc_current_funds.order_price(),
-                    Some(acc_current_funds.order_size()),
-                );
-            }
-            for ask in asks_to_make {
-                opt.log_order_data(
-                    ColorFormat::white("     Selling to take: "),
-                    now,
-                    btc_price.btc_price,
-                    ask.order_price(),
-                    Some(ask.order_size()),
-                );
-                let order = CreateOrder::new_ask(c, ask.order_size(), ask.order_price());
-                tx.send(crate::connect::Message::OpenOrder(order)).unwrap();
-                ret_usd += ask.lockup_usd();
-                ret_btc += ask.lockup_btc();
-            }
-        }
-        (ret_usd, ret_btc)
+    /// Iterator over all contracts we're tracking
+    pub fn contracts(&self) -> impl Iterator<Item = &Contract> + '_ {
+        self.contracts.values().map(|(c, _)| c)
     }
 
     /// Add a new contract to the tracker
@@ -400,6 +595,9 @@ impl LedgerX {
     pub fn add_contract(&mut self, c: Contract) {
         debug!("Add contract {}: {}", c.id(), c.label());
         let asset = c.asset();
+        if let Some(oi) = c.open_interest() {
+            self.open_interest.insert(c.id(), oi);
+        }
         self.contracts.insert(c.id(), (c, BookState::new(asset)));
     }
 
@@ -412,8 +610,152 @@ impl LedgerX {
         }
     }
 
-    /// Inserts a new order into the book
-    pub fn insert_order(&mut self, order: datafeed::Order) -> OrderResponse {
+    /// Inserts a new order from the datafeed into the book
+    ///
+    /// The order is first checked against the contract clock, so this may
+    /// apply no orders (if it was a duplicate) or several (if it filled in a
+    /// gap), and returns a response for each order applied.
+    pub fn insert_order(&mut self, order: datafeed::Order) -> Vec<OrderResponse> {
+        let cid = order.contract_id;
+        let sequenced = match self.contracts.get_mut(&cid) {
+            Some((contract, book_state)) if contract.underlying() == Underlying::Btc => {
+                book_state.sequence(order, &mut self.clock_stats)
+            }
+            _ => return vec![self.apply_order(order)],
+        };
+        if sequenced.gap {
+            warn!(
+                "Gap in datafeed clock for contract {}; will refresh its book.",
+                cid
+            );
+            self.book_refreshes.insert(cid);
+        }
+        sequenced
+            .ready
+            .into_iter()
+            .map(|order| self.apply_order(order))
+            .collect()
+    }
+
+    /// Gives up waiting for any missing datafeed messages, applying whatever
+    /// has been held back and scheduling refreshes of the affected books
+    pub fn flush_pending_clocks(&mut self) -> Vec<OrderResponse> {
+        let mut ready = vec![];
+        for (cid, (_, book_state)) in &mut self.contracts {
+            let sequenced = book_state.flush_pending_clocks(&mut self.clock_stats);
+            if sequenced.gap {
+                warn!(
+                    "Gave up waiting for missing datafeed messages for contract {}; \
+                     will refresh its book.",
+                    cid
+                );
+                self.book_refreshes.insert(*cid);
+            }
+            ready.extend(sequenced.ready);
+        }
+        ready
+            .into_iter()
+            .map(|order| self.apply_order(order))
+            .collect()
+    }
+
+    /// Takes the contracts whose books need to be refreshed
+    pub fn take_book_refreshes(&mut self) -> Vec<ContractId> {
+        self.book_refreshes.drain().collect()
+    }
+
+    /// Counts of anomalies in the datafeed contract clocks
+    pub fn clock_stats(&self) -> book::ClockStats {
+        self.clock_stats
+    }
+
+    /// Picks a book to refresh from the book state endpoint, if one is due
+    ///
+    /// Even without gaps in the datafeed, books can drift from missed
+    /// messages, so we cycle through them, stalest first. The rate is chosen
+    /// to get around every BTC book within `max_age`, but is never more than
+    /// one every [`MIN_BOOK_REFRESH_INTERVAL_SECS`] seconds.
+    pub fn schedule_book_refresh(
+        &mut self,
+        now: UtcTime,
+        max_age: chrono::Duration,
+    ) -> Option<ContractId> {
+        let min_interval = chrono::Duration::seconds(MIN_BOOK_REFRESH_INTERVAL_SECS);
+        // This is called for every message, so bail out cheaply if we can
+        if self
+            .last_scheduled_refresh
+            .is_some_and(|last| now - last < min_interval)
+        {
+            return None;
+        }
+        let n_books = self
+            .contracts
+            .values()
+            .filter(|(contract, _)| contract.underlying() == Underlying::Btc)
+            .count();
+        if n_books == 0 {
+            return None;
+        }
+        let interval = (max_age / n_books as i32).max(min_interval);
+        if self
+            .last_scheduled_refresh
+            .is_some_and(|last| now - last < interval)
+        {
+            return None;
+        }
+        let (cid, (_, book_state)) = self
+            .contracts
+            .iter_mut()
+            .filter(|(_, (contract, _))| contract.underlying() == Underlying::Btc)
+            .min_by_key(|(cid, (_, book_state))| (book_state.last_refresh(), **cid))?;
+        book_state.set_refresh_requested(now);
+        self.last_scheduled_refresh = Some(now);
+        Some(*cid)
+    }
+
+    /// Logs how long ago each BTC book was last loaded from a snapshot
+    ///
+    /// Books older than `max_age` are logged individually; the rest only at
+    /// debug level.
+    pub fn log_book_ages(&self, now: UtcTime, max_age: chrono::Duration) {
+        let mut books: Vec<_> = self
+            .contracts
+            .values()
+            .filter(|(contract, _)| contract.underlying() == Underlying::Btc)
+            .collect();
+        books.sort_by_key(|(contract, book_state)| (book_state.refreshed(), contract.id()));
+        let age = |book_state: &BookState| match book_state.refreshed() {
+            Some(time) => format!("{}s", (now - time).num_seconds()),
+            None => "never loaded".to_string(),
+        };
+        let mut n_stale = 0;
+        for (contract, book_state) in &books {
+            let stale = match book_state.refreshed() {
+                Some(time) => now - time > max_age,
+                None => true,
+            };
+            if stale {
+                n_stale += 1;
+                info!("Book age {}: {}", contract.label(), age(book_state));
+            } else {
+                debug!("Book age {}: {}", contract.label(), age(book_state));
+            }
+        }
+        match books.first() {
+            Some((contract, book_state)) => info!(
+                "Book ages: {} books, {} older than {}s; oldest {} ({})",
+                books.len(),
+                n_stale,
+                max_age.num_seconds(),
+                contract.label(),
+                age(book_state),
+            ),
+            None => info!("Book ages: no books."),
+        }
+    }
+
+    /// Applies an order to the book, and to our own orders if it is ours
+    fn apply_order(&mut self, order: datafeed::Order) -> OrderResponse {
         let (contract, book_state) = match self.contracts.get_mut(&order.contract_id) {
             Some(c) => (&mut c.0, &mut c.1),
             None => {
@@ -433,23 +775,184 @@ impl LedgerX {
         }
         // Insert into order book
         debug!("Inserting into contract {}: {}", contract.id(), order);
+        let cid = contract.id();
+        if let Some(oi) = order.open_interest {
+            self.open_interest.insert(cid, oi);
+        }
         // Before doing anything else, track this if it's an own-order
-        if order.customer_id.is_some() {
+        let ret = if order.customer_id.is_some() {
             book_state.insert_order(order.clone()); // line duplicated for borrowck
-            if self
+            let filled_size = order.filled_size.with_asset_trade(contract.asset());
+            let premium = -(order.filled_price * filled_size);
+            match self
                 .own_orders
-                .insert_order(contract, order, self.price_ref)
+                .insert_order(contract, order.clone(), self.prices.last())
             {
-                OrderResponse::OursFilled
-            } else {
-                OrderResponse::OursOk
+                own_orders::Insertion::Filled(fill) => {
+                    if let Quantity::Contracts(n) = filled_size {
+                        *self.own_positions.entry(cid).or_insert(0) += n;
+                        self.roll.record_fill(cid, n);
+                    }
+                    self.volume.record(
+                        UtcTime::now(),
+                        fees::notional(contract.as_option(), order.filled_price, filled_size),
+                    );
+                    OrderResponse::OursFilled {
+                        order,
+                        premium,
+                        fill,
+                    }
+                }
+                own_orders::Insertion::Other => OrderResponse::OursOk,
             }
         } else {
             book_state.insert_order(order); // line duplicated for borrowck
             OrderResponse::OtherTracked
+        };
+        self.check_oi_share(cid);
+        self.check_mispricing(cid);
+        ret
+    }
+
+    /// Checks the best bid of a contract against its model price, alerting if
+    /// it is too far above it
+    fn check_mispricing(&mut self, cid: ContractId) {
+        if !self.mispricing.settings().enabled {
+            return;
+        }
+        let btc_price = match self.prices.get() {
+            Ok(price) => price.btc_price,
+            Err(_) => return,
+        };
+        let (contract, book) = match self.contracts.get(&cid) {
+            Some(data) => data,
+            None => return,
+        };
+        let opt = match contract.ty() {
+            contract::Type::Option { opt, .. } => opt,
+            _ => return,
+        };
+        let now = UtcTime::now();
+        if opt.years_to_expiry(now) <= 0.0 {
+            return;
+        }
+        let (bid, _) = book.best_bid();
+        let settings = *self.mispricing.settings();
+        let mispricing =
+            mispricing::Mispricing::check(contract.label(), &opt, bid, btc_price, now, &settings);
+        let message = mispricing.as_ref().map(|m| format!("Mispriced bid: {m}"));
+        if self.mispricing.update(cid, mispricing) {
+            // unwrap ok since update only returns true for a mispricing
+            let message = message.unwrap();
+            warn!("{}", message);
+            crate::http::post_to_prowl(&message);
+        }
+    }
+
+    /// Returns the biggest mispricings seen since the last call, largest first
+    pub fn take_mispricing_digest(&mut self) -> Vec<mispricing::Mispricing> {
+        self.mispricing.take_digest()
+    }
+
+    /// Processes a busted trade, reversing its effect on our positions if it was ours
+    ///
+    /// Our balances are not adjusted, since we cannot tell exactly how LX will
+    /// unwind the collateral. The caller should instead refresh them.
+    pub fn bust_trade(&mut self, bust: &datafeed::Bust) -> BustResponse {
+        let contract = match self.contracts.get(&bust.contract_id) {
+            Some((c, _)) => c,
+            None if bust.customer_id.is_some() => return BustResponse::OursUnmatched,
+            None => return BustResponse::Other,
+        };
+        match self.own_orders.bust(contract, bust) {
+            Some((size, price)) => {
+                if let Quantity::Contracts(n) = size {
+                    *self.own_positions.entry(bust.contract_id).or_insert(0) -= n;
+                }
+                let cid = contract.id();
+                self.check_oi_share(cid);
+                BustResponse::OursReversed {
+                    premium: -(price * size),
+                    size,
+                }
+            }
+            None if bust.customer_id.is_some() => BustResponse::OursUnmatched,
+            None => BustResponse::Other,
+        }
+    }
+
+    /// Checks an order against the top of its contract's book before submission
+    ///
+    /// Returns the order to submit, which may have been repriced, or `None` if
+    /// it should be dropped. Orders which LX would reject outright are always
+    /// dropped. If we have no book for the contract, the order is otherwise
+    /// returned unchanged.
+    pub fn validate_order(
+        &self,
+        order: CreateOrder,
+        policy: post_only::Policy,
+    ) -> Option<CreateOrder> {
+        if let Err(e) = order.check() {
+            warn!("Dropping invalid order: {}", e);
+            return None;
+        }
+        match self.contracts.get(&order.contract_id()) {
+            Some((_, book)) => {
+                let (best_bid, _) = book.best_bid();
+                let (best_ask, _) = book.best_ask();
+                post_only::validate(order, best_bid, best_ask, policy)
+            }
+            None => {
+                warn!(
+                    "No order book for {}; cannot check order {} against it.",
+                    order.contract_id(),
+                    order
+                );
+                Some(order)
+            }
         }
     }
 
+    /// Evaluates a block trade proposed over chat, against our current
+    /// positions and balances
+    pub fn evaluate_block_trade(
+        &self,
+        proposal: &block_trade::Proposal,
+    ) -> anyhow::Result<block_trade::Evaluation> {
+        let btc_price = self.prices.get()?;
+        let contract = self
+            .contracts()
+            .find(|c| proposal.matches(c))
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "no active {:?} BTC contract found for option {}",
+                    proposal.contract_size, proposal.option
+                ))
+            })?;
+        Ok(block_trade::evaluate(
+            proposal,
+            contract,
+            btc_price,
+            &self.portfolio(),
+            self.available_usd,
+            self.available_btc,
+            self.fee_tier(UtcTime::now()).fee,
+        ))
+    }
+
+    /// Number of our own orders which are currently open
+    pub fn open_order_count(&self) -> usize {
+        self.own_orders.open_order_iter().count()
+    }
+
+    /// The message and contract IDs of our own open orders
+    pub fn open_order_ids(&self) -> Vec<(MessageId, ContractId)> {
+        self.own_orders
+            .open_order_iter()
+            .map(|order| (order.message_id, order.contract_id))
+            .collect()
+    }
+
     /// Deletes all open orders at the end of the day
     pub fn clear_orderbooks(&mut self) {
         self.contracts = HashMap::new();
@@ -462,20 +965,34 @@ impl LedgerX {
         timestamp: UtcTime,
         tx: &Sender<crate::connect::Message>,
     ) {
-        // Delete existing data
+        // Delete existing data, keeping the messages we may need to replay
+        let mut recent = vec![];
         if let Some((contract, ref mut book_state)) = self.contracts.get_mut(&data.data.contract_id)
         {
-            *book_state = BookState::new(contract.asset());
+            recent = book_state.reset(timestamp);
             if contract.asset() == Asset::Btc {
                 // We don't use the LX orderbook as a price reference at all
-                //self.price_ref.clear_book();
+                //self.prices.clear_book();
             }
         }
+        let mut floor = 0;
         for order in data.data.book_states {
-            self.insert_order(datafeed::Order::from((order, timestamp)));
+            floor = floor.max(order.clock);
+            self.apply_order(datafeed::Order::from((order, timestamp)));
+        }
+        if let Some((_, ref mut book_state)) = self.contracts.get_mut(&data.data.contract_id) {
+            let replayed = book_state.replay(recent, floor, &mut self.clock_stats);
+            if replayed > 0 {
+                debug!(
+                    "Replayed {} datafeed messages over book state for {}",
+                    replayed, data.data.contract_id
+                );
+            }
         }
         if let Some((c, book)) = self.contracts.get(&data.data.contract_id) {
-            let (usd, btc) = self.log_interesting_contract(c, book, tx);
+            let (usd, btc) = self
+                .contract_snapshot(UtcTime::now(), c.id())
+                .log_interesting_contract(c, book, tx);
             // Pre-emptively dock our balances based on
             Self::preemptively_dock_balances(
                 &mut self.available_usd,
@@ -0,0 +1,354 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Moneyness
+//!
+//! How far the BTC price is from the strikes of our short options, and
+//! a small terminal chart of the BTC price path since a short was opened,
+//! relative to its strike and break-even price.
+//!
+
+use super::contract_cache::ContractCache;
+use super::ContractId;
+use crate::connect::Message;
+use crate::option::{self, PutCall};
+use crate::price::{BitcoinPrice, Historic};
+use crate::queue;
+use crate::units::{Price, Quantity, UtcTime};
+use anyhow::Context;
+use log::{info, warn};
+use std::fmt;
+
+/// Default width, in columns, of a price path chart
+pub const CHART_WIDTH: usize = 72;
+/// Default height, in rows, of a price path chart
+pub const CHART_HEIGHT: usize = 16;
+/// Capacity of the queue of messages from the price ticker in `watch`
+const TICKER_QUEUE_CAPACITY: usize = 1_000;
+/// How often, in seconds, to redraw the chart in `watch`
+const REDRAW_INTERVAL_SECS: i64 = 60;
+/// Minimum spacing, in seconds, between live prices added to the chart
+const LIVE_PRICE_SPACING_SECS: i64 = 60;
+
+/// Distance from the BTC price to an option's strike
+///
+/// Positive distances mean the option is out of the money, i.e. safe for
+/// the short side.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Distance {
+    /// Distance in dollars
+    pub dollars: Price,
+    /// Distance as a percentage of the BTC price
+    pub pct: f64,
+}
+
+impl Distance {
+    /// Computes the distance from `btc_price` to the option's strike
+    pub fn to_strike(opt: &option::Option, btc_price: Price) -> Self {
+        let dollars = -opt.intrinsic_value(btc_price);
+        let pct = if btc_price > Price::ZERO {
+            dollars.to_approx_f64() / btc_price.to_approx_f64() * 100.0
+        } else {
+            0.0
+        };
+        Distance { dollars, pct }
+    }
+}
+
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = if self.dollars >= Price::ZERO {
+            "OTM"
+        } else {
+            "ITM"
+        };
+        write!(
+            f,
+            "{} ({:.2}%) {}",
+            self.dollars.abs(),
+            self.pct.abs(),
+            desc
+        )
+    }
+}
+
+/// An open short position in an option
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Short {
+    /// The option
+    pub option: option::Option,
+    /// Number of contracts short (positive)
+    pub size: i64,
+    /// Time of the first sale of the current position
+    pub opened: UtcTime,
+    /// Average premium received, per unit of the underlying
+    pub premium: Price,
+}
+
+impl Short {
+    /// Reconstructs our current short position from a chronological list of
+    /// fills, as (time, signed number of contracts, price)
+    ///
+    /// Returns `None` if the fills do not leave us short. If the position
+    /// has been closed and reopened, only the fills since it was last flat
+    /// are considered. Buys which reduce the position do not change the
+    /// average premium.
+    pub fn from_fills(
+        opt: option::Option,
+        fills: impl IntoIterator<Item = (UtcTime, i64, Price)>,
+    ) -> Option<Self> {
+        let mut ret: Option<Short> = None;
+        for (time, size, price) in fills {
+            ret = match ret {
+                None if size < 0 => Some(Short {
+                    option: opt,
+                    size: -size,
+                    opened: time,
+                    premium: price,
+                }),
+                None => None,
+                Some(short) if size < 0 => Some(Short {
+                    size: short.size - size,
+                    premium: short.premium.average(
+                        Quantity::Contracts(short.size),
+                        price,
+                        Quantity::Contracts(-size),
+                    ),
+                    ..short
+                }),
+                Some(short) if short.size > size => Some(Short {
+                    size: short.size - size,
+                    ..short
+                }),
+                Some(_) => None,
+            };
+        }
+        ret
+    }
+
+    /// The BTC price at expiry beyond which the position loses money
+    pub fn break_even(&self) -> Price {
+        match self.option.pc {
+            PutCall::Call => self.option.strike + self.premium,
+            PutCall::Put => self.option.strike - self.premium,
+        }
+    }
+
+    /// Renders a chart of the given BTC price path, relative to the strike
+    /// and break-even price
+    ///
+    /// Each column shows the last price in its slice of time, as `*`. The row
+    /// containing the strike is drawn with `-` and the row containing the
+    /// break-even with `.`; where these coincide, the strike wins.
+    pub fn chart(&self, path: &[BitcoinPrice], width: usize, height: usize) -> Vec<String> {
+        let (first, last) = match (path.first(), path.last()) {
+            (Some(first), Some(last)) if width > 0 && height > 1 => {
+                (first.timestamp, last.timestamp)
+            }
+            _ => return vec![],
+        };
+        let strike = self.option.strike.to_approx_f64();
+        let break_even = self.break_even().to_approx_f64();
+
+        // Pick one price per column
+        let span = (last - first).num_seconds().max(1) as f64;
+        let mut columns: Vec<Option<f64>> = vec![None; width];
+        for price in path {
+            let offset = (price.timestamp - first).num_seconds() as f64 / span;
+            let col = ((offset * (width - 1) as f64).round() as usize).min(width - 1);
+            columns[col] = Some(price.btc_price.to_approx_f64());
+        }
+        // Fill in any gaps with the previous column's price
+        let mut prev = None;
+        for col in &mut columns {
+            match *col {
+                Some(price) => prev = Some(price),
+                None => *col = prev,
+            }
+        }
+
+        let (mut lo, mut hi) = (strike.min(break_even), strike.max(break_even));
+        for price in columns.iter().flatten() {
+            lo = lo.min(*price);
+            hi = hi.max(*price);
+        }
+        let pad = ((hi - lo) * 0.05).max(1.0);
+        let (lo, hi) = (lo - pad, hi + pad);
+        let row_of = |price: f64| -> usize {
+            let frac = (hi - price) / (hi - lo);
+            ((frac * (height - 1) as f64).round() as usize).min(height - 1)
+        };
+
+        let strike_row = row_of(strike);
+        let break_even_row = row_of(break_even);
+        let mut ret = Vec::with_capacity(height + 1);
+        for row in 0..height {
+            let (fill, label) = if row == strike_row {
+                ('-', format!(" strike {}", self.option.strike))
+            } else if row == break_even_row {
+                ('.', format!(" break-even {}", self.break_even()))
+            } else {
+                (' ', String::new())
+            };
+            let row_price = hi - (hi - lo) * row as f64 / (height - 1) as f64;
+            let mut line = format!("{row_price:>10.0} |");
+            for col in &columns {
+                match *col {
+                    Some(price) if row_of(price) == row => line.push('*'),
+                    _ => line.push(fill),
+                }
+            }
+            line.push_str(&label);
+            ret.push(line);
+        }
+        ret.push(format!(
+            "{:>10} +{} {} to {}",
+            "",
+            "-".repeat(width.saturating_sub(1)),
+            first.format("%F %H:%M"),
+            last.format("%F %H:%M"),
+        ));
+        ret
+    }
+}
+
+impl fmt::Display for Short {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "short {} {} since {}, premium {} (break-even {})",
+            self.size,
+            self.option,
+            self.opened.format("%F"),
+            self.premium,
+            self.break_even(),
+        )
+    }
+}
+
+/// Logs a chart of the BTC price path since our short position in `contract_id`
+/// was opened, then keeps redrawing it with live prices until the option expires
+pub fn run(
+    api_key: &str,
+    contract_id: ContractId,
+    price_history: &Historic,
+    contract_cache: &mut ContractCache,
+) -> anyhow::Result<()> {
+    let contract = contract_cache
+        .fetch(contract_id)
+        .with_context(|| format!("looking up contract {contract_id}"))?;
+    if let Err(e) = contract_cache.save() {
+        warn!("Failed to save contract cache: {}", e);
+    }
+    let opt = contract
+        .as_option()
+        .with_context(|| format!("contract {contract} is not an option"))?;
+    let fills = super::history::fetch_fills(api_key, &contract)?;
+    let short = Short::from_fills(opt, fills)
+        .with_context(|| format!("no open short position in {contract}"))?;
+    info!("{}: {}", contract.label(), short);
+
+    let mut path: Vec<BitcoinPrice> = price_history
+        .prices_between(short.opened, UtcTime::now())
+        .copied()
+        .collect();
+    if path.is_empty() {
+        warn!(
+            "No stored price data since {}; consider running update-price-data.",
+            short.opened,
+        );
+    }
+
+    let (tx, rx) = queue::bounded(TICKER_QUEUE_CAPACITY);
+    crate::coinbase::spawn_ticker_thread(tx, crate::supervisor::Heartbeat::unsupervised(), None);
+    let mut last_draw: Option<UtcTime> = None;
+    for msg in rx.iter() {
+        let price = match msg {
+            Message::PriceReference(_, price) => price,
+            _ => continue,
+        };
+        let last_price = path.last().map(|last| last.timestamp);
+        if !matches!(last_price, Some(last) if price.timestamp - last < chrono::Duration::seconds(LIVE_PRICE_SPACING_SECS))
+        {
+            path.push(price);
+        }
+        if !matches!(last_draw, Some(last) if price.timestamp - last < chrono::Duration::seconds(REDRAW_INTERVAL_SECS))
+        {
+            info!("");
+            info!("{}: {}", contract.label(), short);
+            for line in short.chart(&path, CHART_WIDTH, CHART_HEIGHT) {
+                info!("{}", line);
+            }
+            info!(
+                "BTC {}: {} from strike",
+                price.btc_price,
+                Distance::to_strike(&opt, price.btc_price),
+            );
+            last_draw = Some(price.timestamp);
+        }
+        if price.timestamp >= opt.expiry {
+            info!("{} has expired.", contract.label());
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn short_from_fills() {
+        let opt = option::Option::from_str("2030-06-28P50000").unwrap();
+        let t0 = UtcTime::now();
+        let t = |h: i64| t0 + chrono::Duration::hours(h);
+        let p = |s: &str| Price::from_str(s).unwrap();
+
+        // Closed, then reopened in two pieces, then partially bought back
+        let fills = vec![
+            (t(0), -1, p("3000")),
+            (t(1), 1, p("2000")),
+            (t(2), -2, p("1000")),
+            (t(3), -2, p("2000")),
+            (t(4), 1, p("500")),
+        ];
+        let short = Short::from_fills(opt, fills).unwrap();
+        assert_eq!(short.size, 3);
+        assert_eq!(short.opened, t(2));
+        assert_eq!(short.premium, p("1500"));
+        assert_eq!(short.break_even(), p("48500"));
+        assert!(Short::from_fills(opt, vec![(t(0), -1, p("1")), (t(1), 1, p("1"))]).is_none());
+
+        let dist = Distance::to_strike(&opt, p("40000"));
+        assert_eq!(dist.dollars, p("-10000"));
+        assert_eq!(dist.to_string(), "10000.00 (25.00%) ITM");
+
+        let path: Vec<BitcoinPrice> = (0..10)
+            .map(|h| BitcoinPrice {
+                timestamp: t(h),
+                btc_price: p("50000") + p("1000").scale_approx(h as f64 - 5.0),
+            })
+            .collect();
+        let chart = short.chart(&path, 20, 8);
+        assert_eq!(chart.len(), 9);
+        assert!(chart.iter().any(|line| line.contains("strike")));
+        assert!(chart.iter().any(|line| line.contains("break-even")));
+        assert_eq!(
+            chart.iter().map(|l| l.matches('*').count()).sum::<usize>(),
+            20
+        );
+    }
+}
@@ -17,17 +17,46 @@
 //! Data about orders that belong to us
 //!
 
-use crate::ledgerx::{contract, datafeed::Order, Contract, CustomerId, MessageId};
+use crate::ledgerx::datafeed::{Bust, Order};
+use crate::ledgerx::{contract, slippage, Contract, CustomerId, MessageId};
 use crate::price::BitcoinPrice;
-use crate::units::{Price, Quantity, UnknownQuantity};
-use log::{info, warn};
+use crate::units::{Price, Quantity, UnknownQuantity, UtcTime};
+use log::{debug, info, warn};
 use std::collections::HashMap;
 
+/// How long after an order is done (fully filled or deleted) we keep its
+/// fills, in case LX busts one of them
+const BUST_WINDOW_HOURS: i64 = 24;
+
+/// The fills of one of our orders
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+struct Fills {
+    /// Each fill, as (size, price)
+    fills: Vec<(Quantity, Price)>,
+    /// If the order is done, i.e. fully filled or deleted, when this was seen
+    done: Option<UtcTime>,
+}
+
 /// Own-order tracker
 #[derive(Clone, PartialEq, Eq, Debug, Default)]
 pub struct Tracker {
     my_id: Option<CustomerId>,
     map: HashMap<MessageId, Order>,
+    /// For orders whose creation we saw, the creation time, BTC price reference
+    /// and limit price, used to measure slippage
+    decisions: HashMap<MessageId, (UtcTime, Price, Price)>,
+    /// Fills of each of our orders, so that they can be reversed if the
+    /// trade is busted
+    fills: HashMap<MessageId, Fills>,
+}
+
+/// The result of inserting an order into the own-order tracker
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Insertion {
+    /// The order was filled; if we saw its creation, contains slippage data
+    Filled(Option<slippage::Fill>),
+    /// The order was created, updated or deleted
+    Other,
 }
 
 impl Tracker {
@@ -38,14 +67,13 @@ impl Tracker {
 
     /// Inserts the order into the own-order tracker.
     ///
-    /// Returns a boolean indicating whether this was an order fill (true) or
-    /// something else (false).
+    /// Returns whether this was an order fill or something else.
     pub fn insert_order(
         &mut self,
         contract: &Contract,
         order: Order,
         price_ref: BitcoinPrice,
-    ) -> bool {
+    ) -> Insertion {
         // First log anything interesting about the CID.
         match (self.my_id, order.customer_id) {
             (_, None) => {
@@ -68,7 +96,9 @@ impl Tracker {
             }
         }
 
-        let mut ret = false;
+        self.prune_fills(price_ref.timestamp);
+
+        let mut ret = Insertion::Other;
         let mid = order.message_id;
         let (msg, size, price) = if order.size == UnknownQuantity::from(0) {
             // A deletion or fill?
@@ -87,9 +117,34 @@ impl Tracker {
                     price_ref.btc_price,
                 );
                 crate::http::post_to_prowl(message);
-                ret = true;
+                // Nothing is left of the order, so it is done
+                let fills = self.fills.entry(order.message_id).or_default();
+                fills.fills.push((filled_size, order.filled_price));
+                fills.done = Some(price_ref.timestamp);
+                let decision = self.decisions.remove(&order.message_id);
+                ret = Insertion::Filled(match (decision, contract.ty(), filled_size) {
+                    (
+                        Some((decision_time, decision_btc, limit_price)),
+                        contract::Type::Option { opt, .. },
+                        Quantity::Contracts(size),
+                    ) => Some(slippage::Fill {
+                        option: opt,
+                        size,
+                        limit_price,
+                        fill_price: order.filled_price,
+                        decision_time,
+                        decision_btc,
+                        fill_time: order.updated_timestamp,
+                        fill_btc: price_ref.btc_price,
+                    }),
+                    _ => None,
+                });
                 ("Filled ", filled_size, order.filled_price)
             } else if let Some(old_order) = self.map.remove(&order.message_id) {
+                self.decisions.remove(&order.message_id);
+                if let Some(fills) = self.fills.get_mut(&order.message_id) {
+                    fills.done = Some(price_ref.timestamp);
+                }
                 (
                     "Deleted ",
                     old_order.size.with_asset_trade(contract.asset()),
@@ -117,6 +172,10 @@ impl Tracker {
             data
         } else {
             // Or a new order?
+            self.decisions.insert(
+                order.message_id,
+                (order.timestamp, price_ref.btc_price, order.price),
+            );
             let data = (
                 "Created ",
                 order.size.with_asset_trade(contract.asset()),
@@ -152,8 +211,70 @@ impl Tracker {
         ret
     }
 
+    /// Matches a busted trade against the fills we have recorded, removing
+    /// the fill and returning its size and price if found
+    pub fn bust(&mut self, contract: &Contract, bust: &Bust) -> Option<(Quantity, Price)> {
+        let size = bust.size.with_asset_trade(contract.asset());
+        let fills = self.fills.get_mut(&bust.message_id)?;
+        let idx = fills
+            .fills
+            .iter()
+            .position(|&(fill_size, fill_price)| fill_size == size && fill_price == bust.price)?;
+        let ret = fills.fills.remove(idx);
+        if fills.fills.is_empty() {
+            self.fills.remove(&bust.message_id);
+        }
+        Some(ret)
+    }
+
+    /// Forgets the fills of orders which have been done for longer than the
+    /// bust window
+    fn prune_fills(&mut self, now: UtcTime) {
+        let window = chrono::Duration::hours(BUST_WINDOW_HOURS);
+        self.fills.retain(|mid, fills| match fills.done {
+            Some(done) if now - done > window => {
+                debug!("Forgetting fills of order {} past the bust window", mid);
+                false
+            }
+            _ => true,
+        });
+    }
+
     /// Get an iterator over all open orders
     pub fn open_order_iter(&self) -> impl Iterator<Item = &Order> {
         self.map.values()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_fills() {
+        let time = |s: &str| UtcTime::parse_coinbase(s).unwrap();
+        let fill = (Quantity::Contracts(-3), Price::from_cents(126_400));
+        let mut tracker = Tracker::new();
+        tracker.fills.insert(
+            MessageId::from([1; 16]),
+            Fills {
+                fills: vec![fill],
+                done: Some(time("2024-03-01T15:00:00Z")),
+            },
+        );
+        // Partly filled, but still open
+        tracker.fills.insert(
+            MessageId::from([2; 16]),
+            Fills {
+                fills: vec![fill],
+                done: None,
+            },
+        );
+
+        tracker.prune_fills(time("2024-03-02T14:00:00Z"));
+        assert_eq!(tracker.fills.len(), 2);
+        tracker.prune_fills(time("2024-03-02T16:00:00Z"));
+        assert_eq!(tracker.fills.len(), 1);
+        assert!(tracker.fills.contains_key(&MessageId::from([2; 16])));
+    }
+}
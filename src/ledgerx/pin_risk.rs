@@ -0,0 +1,237 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Pin Risk
+//!
+//! Close to expiry, a large short position with a strike near the BTC price
+//! can flip between expiring worthless and being assigned on a small move.
+//! This module groups our short positions by expiry and by distance from
+//! strike, and reports how much notional would flip into the money on a 1%
+//! move in either direction, so that concentrations can be reduced before
+//! the expiry afternoon.
+//!
+
+use super::collateral::Portfolio;
+use super::moneyness::Distance;
+use crate::option::PutCall;
+use crate::units::{Price, UtcTime};
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Upper edges, in percent out of the money, of the distance-to-strike buckets
+///
+/// There is an additional bucket for in-the-money shorts below the first, and
+/// one for everything beyond the last.
+const BUCKET_EDGES_PCT: [f64; 4] = [1.0, 2.0, 5.0, 10.0];
+/// Number of days ahead for which the heartbeat reports pin risk
+pub const HEARTBEAT_DAYS: i64 = 7;
+/// Size of the BTC move, in percent, for which we report flipping notional
+const FLIP_MOVE_PCT: f64 = 1.0;
+
+/// Notional amount of short options
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Notional {
+    /// Amount of the underlying, in BTC
+    pub btc: f64,
+    /// Value of the underlying at the strike
+    pub usd: Price,
+}
+
+impl Notional {
+    /// Adds a short of `btc` bitcoin at a given strike
+    fn add(&mut self, btc: f64, strike: Price) {
+        self.btc += btc;
+        self.usd += strike.scale_approx(btc);
+    }
+
+    /// Whether there is nothing here
+    fn is_zero(&self) -> bool {
+        self.btc == 0.0
+    }
+}
+
+impl fmt::Display for Notional {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} BTC ({})", self.btc, self.usd)
+    }
+}
+
+/// Short positions of a single expiry
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Expiry {
+    /// Notional in each distance bucket; the first is in the money, and the
+    /// rest are bounded above by [`BUCKET_EDGES_PCT`] in turn
+    pub buckets: [Notional; BUCKET_EDGES_PCT.len() + 2],
+    /// Notional of calls which would flip into the money on a 1% rise
+    pub flip_up: Notional,
+    /// Notional of puts which would flip into the money on a 1% fall
+    pub flip_down: Notional,
+}
+
+impl Expiry {
+    /// Total notional across all buckets
+    pub fn total(&self) -> Notional {
+        let mut ret = Notional::default();
+        for bucket in &self.buckets {
+            ret.btc += bucket.btc;
+            ret.usd += bucket.usd;
+        }
+        ret
+    }
+}
+
+/// Pin risk of all our short positions, by expiry
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    /// BTC price the report was computed at
+    pub btc_price: Price,
+    /// Short positions of each unexpired expiry
+    pub expiries: BTreeMap<UtcTime, Expiry>,
+}
+
+impl Report {
+    /// Computes the pin risk of a portfolio
+    pub fn new(portfolio: &Portfolio, now: UtcTime, btc_price: Price) -> Self {
+        let mut expiries = BTreeMap::<_, Expiry>::new();
+        for (opt, minis) in portfolio.positions() {
+            if minis >= 0 || opt.expiry <= now {
+                continue;
+            }
+            let btc = -minis as f64 / 100.0;
+            let pct = Distance::to_strike(&opt, btc_price).pct;
+            let idx = if pct < 0.0 {
+                0
+            } else {
+                1 + BUCKET_EDGES_PCT
+                    .iter()
+                    .take_while(|edge| pct >= **edge)
+                    .count()
+            };
+            let expiry = expiries.entry(opt.expiry).or_default();
+            expiry.buckets[idx].add(btc, opt.strike);
+            if (0.0..=FLIP_MOVE_PCT).contains(&pct) {
+                match opt.pc {
+                    PutCall::Call => expiry.flip_up.add(btc, opt.strike),
+                    PutCall::Put => expiry.flip_down.add(btc, opt.strike),
+                }
+            }
+        }
+        Report {
+            btc_price,
+            expiries,
+        }
+    }
+
+    /// Logs the report, limited to expiries within `max_days` of `now` if given
+    pub fn log(&self, now: UtcTime, max_days: Option<i64>) {
+        let expiries: Vec<_> = self
+            .expiries
+            .iter()
+            .filter(|(expiry, _)| match max_days {
+                Some(days) => **expiry <= now + chrono::Duration::days(days),
+                None => true,
+            })
+            .collect();
+        if expiries.is_empty() {
+            return;
+        }
+        info!("Pin risk at BTC {}:", self.btc_price);
+        for (expiry, data) in expiries {
+            info!(
+                "    Expiry {}: {} short",
+                expiry.format("%F %H:%M"),
+                data.total()
+            );
+            for (idx, bucket) in data.buckets.iter().enumerate() {
+                if bucket.is_zero() {
+                    continue;
+                }
+                info!("        {:>10}: {}", bucket_name(idx), bucket);
+            }
+            info!(
+                "        Flips ITM on +{}%: {}; on -{}%: {}",
+                FLIP_MOVE_PCT, data.flip_up, FLIP_MOVE_PCT, data.flip_down,
+            );
+        }
+    }
+}
+
+/// Human-readable name of a distance bucket
+fn bucket_name(idx: usize) -> String {
+    match idx {
+        0 => "ITM".into(),
+        _ if idx > BUCKET_EDGES_PCT.len() => {
+            format!("{}%+ OTM", BUCKET_EDGES_PCT[BUCKET_EDGES_PCT.len() - 1])
+        }
+        1 => format!("0-{}% OTM", BUCKET_EDGES_PCT[0]),
+        _ => format!(
+            "{}-{}% OTM",
+            BUCKET_EDGES_PCT[idx - 2],
+            BUCKET_EDGES_PCT[idx - 1]
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::option;
+    use std::str::FromStr;
+
+    #[test]
+    fn pin_risk() {
+        let now = UtcTime::now();
+        let btc = Price::from_str("60000").unwrap();
+        let near = now + chrono::Duration::days(1);
+        let far = now + chrono::Duration::days(30);
+        let opt = |s: &str, expiry| {
+            let mut opt = option::Option::from_str(s).unwrap();
+            opt.expiry = expiry;
+            opt
+        };
+
+        let mut portfolio = Portfolio::new();
+        // 0.5% OTM call and put, which both flip on a 1% move
+        portfolio.add(opt("2030-01-01C60300", near), -100);
+        portfolio.add(opt("2030-01-01P59700", near), -200);
+        // 3% OTM put, which does not
+        portfolio.add(opt("2030-01-01P58200", near), -50);
+        // ITM call
+        portfolio.add(opt("2030-01-01C59000", near), -10);
+        // Longs are ignored
+        portfolio.add(opt("2030-01-01C61000", near), 500);
+        // Far expiry, 20% OTM
+        portfolio.add(opt("2030-01-01P48000", far), -100);
+
+        let report = Report::new(&portfolio, now, btc);
+        assert_eq!(report.expiries.len(), 2);
+        let data = &report.expiries[&near];
+        assert_eq!(data.buckets[0].btc, 0.1);
+        assert_eq!(data.buckets[1].btc, 3.0);
+        assert_eq!(data.buckets[2].btc, 0.0);
+        assert_eq!(data.buckets[3].btc, 0.5);
+        assert_eq!(data.flip_up.btc, 1.0);
+        assert_eq!(data.flip_up.usd, Price::from_str("60300").unwrap());
+        assert_eq!(data.flip_down.btc, 2.0);
+        assert!((data.total().btc - 3.6).abs() < 1e-9);
+        assert_eq!(report.expiries[&far].buckets[5].btc, 1.0);
+        assert!(report.expiries[&far].flip_down.is_zero());
+
+        assert_eq!(bucket_name(0), "ITM");
+        assert_eq!(bucket_name(1), "0-1% OTM");
+        assert_eq!(bucket_name(3), "2-5% OTM");
+        assert_eq!(bucket_name(5), "10%+ OTM");
+    }
+}
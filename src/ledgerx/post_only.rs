@@ -0,0 +1,170 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Post-Only Orders
+//!
+//! LX has no post-only order type, so an ask priced at or below the best bid
+//! is filled immediately at the bid's price. Our quotes are meant to rest on
+//! the book, so before submitting an order we compare it against the top of
+//! the book, and either reprice it one tick away from the other side or drop
+//! it. Orders which are meant to cross must be marked as taker orders.
+//!
+
+use super::json::CreateOrder;
+use crate::units::Price;
+use log::{info, warn};
+use std::{fmt, str::FromStr};
+
+/// What to do with a non-taker order which would cross the book
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Policy {
+    /// Drop the order
+    #[default]
+    Reject,
+    /// Reprice the order to one tick away from the best price on the other side
+    Adjust,
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Policy::Reject => f.write_str("reject"),
+            Policy::Adjust => f.write_str("adjust"),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Policy::Reject),
+            "adjust" => Ok(Policy::Adjust),
+            x => Err(format!(
+                "Invalid post-only policy {x}; allowed values: reject, adjust"
+            )),
+        }
+    }
+}
+
+/// Checks an order against the top of the book, returning the order to submit,
+/// if any
+///
+/// `best_bid` and `best_ask` are zero if that side of the book is empty.
+pub fn validate(
+    order: CreateOrder,
+    best_bid: Price,
+    best_ask: Price,
+    policy: Policy,
+) -> Option<CreateOrder> {
+    let price = order.price();
+    let tick = order.tick();
+    let (crosses, opposite, adjusted) = if order.is_ask() {
+        (
+            best_bid > Price::ZERO && price <= best_bid,
+            best_bid,
+            best_bid + tick,
+        )
+    } else {
+        (
+            best_ask > Price::ZERO && price >= best_ask,
+            best_ask,
+            best_ask - tick,
+        )
+    };
+    let side = if order.is_ask() {
+        "best bid"
+    } else {
+        "best ask"
+    };
+
+    if !crosses {
+        return Some(order);
+    }
+    if order.is_taker() {
+        info!("Taker order {} crosses {} {}.", order, side, opposite);
+        return Some(order);
+    }
+    match policy {
+        Policy::Adjust if adjusted > Price::ZERO => {
+            let new_order = order.with_price(adjusted);
+            warn!(
+                "Order {} would cross {} {}; repriced to {}.",
+                order, side, opposite, adjusted,
+            );
+            Some(new_order)
+        }
+        Policy::Adjust | Policy::Reject => {
+            warn!(
+                "Dropping order {}: would cross {} {} and is not marked as a taker.",
+                order, side, opposite,
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledgerx::Contract;
+    use crate::units::Quantity;
+
+    #[test]
+    fn post_only() {
+        let contract: Contract = serde_json::from_str(
+            "{\"active\":true,\"collateral_asset\":\"USD\",\"date_exercise\":\"2030-06-28 22:00:00+0000\",\"date_expires\":\"2030-06-28 21:00:00+0000\",\"date_live\":\"2030-01-01 05:00:00+0000\",\"derivative_type\":\"options_contract\",\"id\":1,\"is_call\":false,\"is_ecp_only\":false,\"is_next_day\":false,\"label\":\"BTC-Mini-28JUN2030-50000-Put\",\"min_increment\":100,\"multiplier\":100,\"name\":null,\"open_interest\":null,\"strike_price\":5000000,\"type\":\"put\",\"underlying_asset\":\"BTC\"}",
+        )
+        .unwrap();
+        let p = |n: i64| Price::from_cents(n * 100);
+        let ask = |price| CreateOrder::new_ask(&contract, Quantity::Contracts(10), p(price));
+        let bid = |price| CreateOrder::new_bid(&contract, Quantity::Contracts(10), p(price));
+
+        // Passive orders, and orders against an empty side, are untouched
+        assert_eq!(
+            validate(ask(101), p(100), p(110), Policy::Reject),
+            Some(ask(101))
+        );
+        assert_eq!(
+            validate(bid(109), p(100), p(110), Policy::Reject),
+            Some(bid(109))
+        );
+        assert_eq!(
+            validate(ask(1), Price::ZERO, p(110), Policy::Reject),
+            Some(ask(1))
+        );
+        assert_eq!(
+            validate(bid(500), p(100), Price::ZERO, Policy::Reject),
+            Some(bid(500))
+        );
+
+        // Crossing orders are dropped or repriced
+        assert_eq!(validate(ask(100), p(100), p(110), Policy::Reject), None);
+        assert_eq!(
+            validate(ask(90), p(100), p(110), Policy::Adjust),
+            Some(ask(101))
+        );
+        assert_eq!(
+            validate(bid(120), p(100), p(110), Policy::Adjust),
+            Some(bid(109))
+        );
+        assert_eq!(validate(bid(5), p(0), p(1), Policy::Adjust), None);
+
+        // ...unless they are marked as takers
+        assert_eq!(
+            validate(ask(90).into_taker(), p(100), p(110), Policy::Reject),
+            Some(ask(90).into_taker())
+        );
+    }
+}
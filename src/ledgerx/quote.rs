@@ -0,0 +1,284 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Manual Quotes
+//!
+//! Places a single order from the command line, without starting the full
+//! `connect` loop. The order price can be given directly or as a target IV
+//! or ARR, which is converted to a price using the stored BTC price. By
+//! default an ARR is annualized from the current time, but like standing
+//! asks it can be annualized from an earlier reference time instead.
+//!
+
+use super::contract_cache::ContractCache;
+use super::interesting::ArrReference;
+use super::json::CreateOrder;
+use super::Contract;
+use crate::http;
+use crate::option;
+use crate::units::{ContractSize, Price, Quantity, Underlying, UtcTime};
+use anyhow::Context;
+use log::{info, warn};
+use std::io::{self, BufRead as _};
+use std::{fmt, str::FromStr};
+
+/// If the stored BTC price is older than this, warn that it may be stale
+const MAX_PRICE_AGE_MINUTES: i64 = 60;
+
+/// Which side of the book to place an order on
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+impl FromStr for Side {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "bid" | "buy" => Ok(Side::Bid),
+            "ask" | "sell" => Ok(Side::Ask),
+            x => Err(format!("Invalid side {x}; allowed values: bid, ask")),
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Side::Bid => f.write_str("bid"),
+            Side::Ask => f.write_str("ask"),
+        }
+    }
+}
+
+/// How the price of a manual order is specified
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Target {
+    /// An explicit price per unit of the underlying
+    Price(Price),
+    /// A target implied volatility, as a fraction
+    Iv(f64),
+    /// A target annualized rate of return, as a fraction
+    Arr(f64),
+}
+
+impl Target {
+    /// Converts the target into a price for the given option
+    ///
+    /// An ARR target is annualized from the time given by `arr_reference`.
+    pub fn resolve(
+        &self,
+        opt: &option::Option,
+        now: UtcTime,
+        btc_price: Price,
+        arr_reference: ArrReference,
+    ) -> anyhow::Result<Price> {
+        match *self {
+            Target::Price(price) => Ok(price),
+            Target::Iv(iv) => Ok(opt.bs_price(now, btc_price, iv)),
+            Target::Arr(arr) => opt
+                .bs_arr_price(arr_reference.apply(now), btc_price, arr)
+                .with_context(|| {
+                    format!(
+                        "no reasonable price gives {} an ARR of {:.2}%",
+                        opt,
+                        arr * 100.0
+                    )
+                }),
+        }
+    }
+}
+
+/// A manual order, as requested on the command line
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Request {
+    /// The option to trade
+    pub option: option::Option,
+    /// Whether to buy or sell
+    pub side: Side,
+    /// Number of contracts
+    pub size: i64,
+    /// How to price the order
+    pub target: Target,
+    /// Whether to trade the full-size contract rather than the mini
+    pub contract_size: ContractSize,
+    /// The time from which an ARR target is annualized
+    pub arr_reference: ArrReference,
+}
+
+/// Finds the live BTC contract corresponding to an option
+pub fn find_contract(
+    contracts: &[Contract],
+    opt: &option::Option,
+    size: ContractSize,
+) -> anyhow::Result<Contract> {
+    contracts
+        .iter()
+        .find(|c| {
+            c.active()
+                && c.underlying() == Underlying::Btc
+                && c.contract_size() == size
+                && c.as_option().as_ref() == Some(opt)
+        })
+        .cloned()
+        .with_context(|| format!("no active {size:?} BTC contract found for option {opt}"))
+}
+
+/// Prompt the user for confirmation, returning whether they said yes
+pub fn confirm(question: &str) -> anyhow::Result<bool> {
+    info!("{} [y/N]", question);
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .context("reading from stdin")?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+/// Prices a manual order, prints its stats and submits it to LX
+///
+/// Unless `yes` is set, asks for confirmation before submitting.
+pub fn run(
+    api_key: &str,
+    request: &Request,
+    btc_price: crate::price::BitcoinPrice,
+    contract_cache: &mut ContractCache,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let now = UtcTime::now();
+    if now - btc_price.timestamp > chrono::Duration::minutes(MAX_PRICE_AGE_MINUTES) {
+        warn!(
+            "Stored BTC price {} is more than {} minutes old; consider running update-price-data.",
+            btc_price, MAX_PRICE_AGE_MINUTES,
+        );
+    }
+    if request.option.expiry <= now {
+        return Err(anyhow::Error::msg(format!(
+            "option {} has already expired",
+            request.option
+        )));
+    }
+
+    let contracts = contract_cache
+        .fetch_all_active()
+        .context("looking up active contracts")?;
+    if let Err(e) = contract_cache.save() {
+        warn!("Failed to save contract cache: {}", e);
+    }
+    let contract = find_contract(&contracts, &request.option, request.contract_size)?;
+
+    let btc = btc_price.btc_price;
+    let price = request
+        .target
+        .resolve(&request.option, now, btc, request.arr_reference)?;
+    let qty = Quantity::Contracts(request.size);
+    let order = match request.side {
+        Side::Bid => CreateOrder::new_bid(&contract, qty, price),
+        Side::Ask => CreateOrder::new_ask(&contract, qty, price),
+    };
+
+    info!("BTC price: {}", btc_price);
+    info!("Contract: {} (id {})", contract.label(), contract.id());
+    request.option.log_option_data("", now, btc);
+    request
+        .option
+        .log_order_data(format!("{:3} ", request.side), now, btc, price, Some(qty));
+
+    order.check().map_err(anyhow::Error::msg)?;
+
+    if !yes && !confirm(&format!("Submit {} {}?", request.side, order))? {
+        info!("Not submitting order.");
+        return Ok(());
+    }
+    let result = http::post_json("https://trade.ledgerx.com/api/orders", api_key, &order);
+    crate::audit::record(
+        "quote",
+        crate::audit::Action::SubmitOrder,
+        &format!("{}: {}", order, crate::audit::outcome(&result)),
+    );
+    result.with_context(|| format!("submitting order {order}"))?;
+    info!("Submitted order {}", order);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contract_and_price() {
+        let contract = |id: usize, label: &str, multiplier: usize| -> Contract {
+            let json = serde_json::json!({
+                "id": id,
+                "active": true,
+                "collateral_asset": "BTC",
+                "date_exercise": "2030-06-28 22:00:00+0000",
+                "date_expires": "2030-06-28 21:00:00+0000",
+                "date_live": "2030-01-01 05:00:00+0000",
+                "derivative_type": "options_contract",
+                "is_call": true,
+                "is_ecp_only": false,
+                "is_next_day": false,
+                "label": label,
+                "min_increment": 100,
+                "multiplier": multiplier,
+                "name": null,
+                "open_interest": null,
+                "strike_price": 6000000,
+                "type": "call",
+                "underlying_asset": "BTC",
+            });
+            serde_json::from_str(&json.to_string()).unwrap()
+        };
+        let contracts = vec![
+            contract(1, "BTC-Mini-28JUN2030-60000-Call", 100),
+            contract(2, "BTC-28JUN2030-60000-Call", 1),
+        ];
+        let opt = option::Option::from_str("2030-06-28C60000").unwrap();
+        let mini = find_contract(&contracts, &opt, ContractSize::Mini).unwrap();
+        assert_eq!(mini.id(), 1.into());
+        let full = find_contract(&contracts, &opt, ContractSize::Full).unwrap();
+        assert_eq!(full.id(), 2.into());
+        let other = option::Option::from_str("2030-06-28P60000").unwrap();
+        assert!(find_contract(&contracts, &other, ContractSize::Mini).is_err());
+
+        assert_eq!(Side::from_str("SELL"), Ok(Side::Ask));
+        assert!(Side::from_str("hold").is_err());
+
+        let now = UtcTime::now();
+        let btc = Price::from_str("60000").unwrap();
+        let price = Target::Iv(0.6)
+            .resolve(&opt, now, btc, ArrReference::Now)
+            .unwrap();
+        let iv = opt.bs_iv(now, btc, price).unwrap();
+        assert!((iv - 0.6).abs() < 0.001);
+        let price = Target::Arr(0.1)
+            .resolve(&opt, now, btc, ArrReference::Now)
+            .unwrap();
+        let arr = opt.arr(now, btc, price);
+        assert!((arr - 0.1).abs() < 0.002);
+        // Annualizing a short-dated option from up to a week ago asks for a
+        // higher price
+        let mut short = opt;
+        short.expiry = now + chrono::Duration::days(10);
+        let daily = Target::Arr(0.1)
+            .resolve(&short, now, btc, ArrReference::Now)
+            .unwrap();
+        let weekly = Target::Arr(0.1)
+            .resolve(&short, now, btc, ArrReference::LastFriday)
+            .unwrap();
+        assert!(weekly > daily);
+    }
+}
@@ -0,0 +1,391 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Rolls
+//!
+//! A short option which is close to expiry and far out of the money has very
+//! little premium left in it, but its collateral stays locked up until it
+//! expires. If enabled, we "roll" such positions: buy back the expiring option
+//! at the best ask and, once that fills, sell the same strike (or the nearest
+//! listed strike) in the next expiry.
+//!
+//! The two legs are submitted one after the other. The second is only opened
+//! as the first fills, so if the buy-back fails or never fills, the roll is
+//! simply abandoned and we are never left with a doubled position. While the
+//! first leg is outstanding, any part of it resting on the book is left alone
+//! by the heartbeat's cancellations; when the roll is abandoned, it is
+//! cancelled.
+//!
+
+use super::json::CreateOrder;
+use super::moneyness::Distance;
+use super::{Contract, ContractId};
+use crate::option;
+use crate::units::{Price, Quantity, UtcTime};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::{fmt, mem};
+
+/// Time, in minutes, after which we give up on a buy-back which has not filled
+const ROLL_TIMEOUT_MINUTES: i64 = 5;
+
+/// Settings for the roll assistant
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Whether to roll positions at all
+    pub enabled: bool,
+    /// Number of days before expiry within which we consider rolling
+    pub max_days: u32,
+    /// Minimum distance from the strike, as a percentage of the BTC price
+    pub min_otm_pct: u32,
+    /// Maximum price (per unit of the underlying) we will pay to buy back
+    pub max_remaining: Price,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: false,
+            max_days: 3,
+            min_otm_pct: 10,
+            max_remaining: Price::TWENTY_FIVE,
+        }
+    }
+}
+
+impl Settings {
+    /// Whether a short option is close enough to expiry, and far enough out
+    /// of the money, to be rolled
+    pub fn is_candidate(&self, opt: &option::Option, now: UtcTime, btc_price: Price) -> bool {
+        opt.expiry > now
+            && opt.expiry - now <= chrono::Duration::days(self.max_days.into())
+            && Distance::to_strike(opt, btc_price).pct >= f64::from(self.min_otm_pct)
+    }
+}
+
+/// Chooses the contract to roll a short position in `from` into
+///
+/// This is an option of the same type and contract size, in the first expiry
+/// after that of `from`, with the same strike if one is listed and otherwise
+/// the nearest listed strike.
+pub fn target<'a, I>(from: &Contract, contracts: I) -> Option<&'a Contract>
+where
+    I: IntoIterator<Item = &'a Contract>,
+{
+    let opt = from.as_option()?;
+    let candidates: Vec<(&Contract, option::Option)> = contracts
+        .into_iter()
+        .filter(|c| c.active() && c.contract_size() == from.contract_size())
+        .filter(|c| c.underlying() == from.underlying())
+        .filter_map(|c| Some((c, c.as_option()?)))
+        .filter(|(_, cand)| cand.pc == opt.pc && cand.expiry > opt.expiry)
+        .collect();
+    let next_expiry = candidates.iter().map(|(_, cand)| cand.expiry).min()?;
+    candidates
+        .into_iter()
+        .filter(|(_, cand)| cand.expiry == next_expiry)
+        .min_by_key(|(c, cand)| ((cand.strike - opt.strike).abs(), c.id()))
+        .map(|(c, _)| c)
+}
+
+/// A planned roll of a short position from one contract to another
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Roll {
+    /// The expiring contract, which we buy back
+    pub from: Contract,
+    /// The contract in the next expiry, which we sell
+    pub to: Contract,
+    /// Number of contracts to roll
+    pub size: i64,
+    /// Price at which to buy back the expiring contract
+    pub close_price: Price,
+    /// Price at which to sell the new contract
+    pub open_price: Price,
+}
+
+impl Roll {
+    /// The order for the first leg, buying back the expiring contract
+    ///
+    /// This is priced at the best ask, so is a taker order.
+    pub fn close_order(&self) -> CreateOrder {
+        CreateOrder::new_bid(&self.from, Quantity::Contracts(self.size), self.close_price)
+            .into_taker()
+    }
+
+    /// The order for the second leg, selling `size` of the new contract
+    ///
+    /// This may be priced at the best bid, so is a taker order.
+    pub fn open_order(&self, size: i64) -> CreateOrder {
+        CreateOrder::new_ask(&self.to, Quantity::Contracts(size), self.open_price).into_taker()
+    }
+}
+
+impl fmt::Display for Roll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "roll {} from {} (buy @ {}) to {} (sell @ {})",
+            self.size,
+            self.from.label(),
+            self.close_price,
+            self.to.label(),
+            self.open_price,
+        )
+    }
+}
+
+/// A roll whose first leg has been submitted
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Pending {
+    roll: Roll,
+    started: UtcTime,
+    /// Number of contracts which have yet to be bought back
+    remaining: i64,
+}
+
+/// Tracker for rolls in progress
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Tracker {
+    settings: Settings,
+    /// Rolls whose first leg has been submitted, by the contract being bought back
+    pending: HashMap<ContractId, Pending>,
+    /// Contracts which we have already attempted to roll
+    attempted: HashSet<ContractId>,
+    /// Second legs which are ready to be submitted, as (roll, size)
+    ready: Vec<(Roll, i64)>,
+}
+
+impl Tracker {
+    /// Creates a new tracker with no rolls in progress
+    pub fn new(settings: Settings) -> Self {
+        Tracker {
+            settings,
+            pending: HashMap::new(),
+            attempted: HashSet::new(),
+            ready: vec![],
+        }
+    }
+
+    /// Accessor for the settings
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Returns whether we should attempt to roll a position, marking it as attempted if so
+    pub fn should_roll(&mut self, cid: ContractId) -> bool {
+        self.settings.enabled && self.attempted.insert(cid)
+    }
+
+    /// Records that the first leg of a roll has been submitted
+    pub fn start(&mut self, roll: Roll, now: UtcTime) {
+        info!("Starting {}", roll);
+        self.pending.insert(
+            roll.from.id(),
+            Pending {
+                remaining: roll.size,
+                roll,
+                started: now,
+            },
+        );
+    }
+
+    /// Abandons a roll, e.g. because its first leg could not be submitted
+    pub fn abort(&mut self, cid: ContractId) -> Option<Roll> {
+        self.pending.remove(&cid).map(|pending| pending.roll)
+    }
+
+    /// Records a fill of `size` contracts (positive for a buy) on a contract
+    ///
+    /// If this is the first leg of a pending roll, the corresponding second
+    /// leg becomes ready to submit.
+    pub fn record_fill(&mut self, cid: ContractId, size: i64) {
+        if size <= 0 {
+            return;
+        }
+        let pending = match self.pending.get_mut(&cid) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let size = size.min(pending.remaining);
+        pending.remaining -= size;
+        info!(
+            "Bought back {} of {}; selling {} of {}",
+            size,
+            pending.roll.from.label(),
+            size,
+            pending.roll.to.label(),
+        );
+        self.ready.push((pending.roll.clone(), size));
+        if pending.remaining == 0 {
+            self.pending.remove(&cid);
+        }
+    }
+
+    /// Iterator over the contracts being bought back by rolls in progress
+    pub fn pending_contracts(&self) -> impl Iterator<Item = ContractId> + '_ {
+        self.pending.keys().copied()
+    }
+
+    /// Takes the second-leg orders which are ready to be submitted
+    pub fn take_ready(&mut self) -> Vec<CreateOrder> {
+        mem::take(&mut self.ready)
+            .into_iter()
+            .map(|(roll, size)| roll.open_order(size))
+            .collect()
+    }
+
+    /// Abandons any rolls whose first leg has not completely filled in time
+    ///
+    /// Returns the abandoned rolls along with the number of contracts which
+    /// were not bought back. The caller must cancel whatever is left of their
+    /// first legs, which is no longer protected from the heartbeat.
+    pub fn expire(&mut self, now: UtcTime) -> Vec<(Roll, i64)> {
+        let timeout = chrono::Duration::minutes(ROLL_TIMEOUT_MINUTES);
+        let expired: Vec<ContractId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now - pending.started > timeout)
+            .map(|(cid, _)| *cid)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|cid| self.pending.remove(&cid))
+            .map(|pending| {
+                warn!(
+                    "Abandoning {}: {} contracts not bought back after {} minutes",
+                    pending.roll, pending.remaining, ROLL_TIMEOUT_MINUTES,
+                );
+                (pending.roll, pending.remaining)
+            })
+            .collect()
+    }
+
+    /// Forgets about any positions which are no longer short
+    pub fn retain(&mut self, mut is_short: impl FnMut(ContractId) -> bool) {
+        let pending = &self.pending;
+        self.attempted
+            .retain(|cid| is_short(*cid) || pending.contains_key(cid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn contract(id: usize, date: &str, strike: usize, size: &str) -> Contract {
+        let (label_size, multiplier) = match size {
+            "mini" => ("Mini-", 100),
+            _ => ("", 1),
+        };
+        let json = serde_json::json!({
+            "id": id,
+            "active": true,
+            "collateral_asset": "USD",
+            "date_exercise": format!("{date} 22:00:00+0000"),
+            "date_expires": format!("{date} 21:00:00+0000"),
+            "date_live": "2030-01-01 05:00:00+0000",
+            "derivative_type": "options_contract",
+            "is_call": false,
+            "is_ecp_only": false,
+            "is_next_day": false,
+            "label": format!("BTC-{label_size}{date}-{strike}-Put"),
+            "min_increment": 100,
+            "multiplier": multiplier,
+            "name": null,
+            "open_interest": null,
+            "strike_price": strike * 100,
+            "type": "put",
+            "underlying_asset": "BTC",
+        });
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn roll_target() {
+        let from = contract(1, "2030-06-28", 50000, "mini");
+        let contracts = vec![
+            from.clone(),
+            contract(2, "2030-07-05", 45000, "mini"),
+            contract(3, "2030-07-05", 52000, "mini"),
+            contract(4, "2030-07-12", 50000, "mini"),
+            contract(5, "2030-07-05", 50000, "full"),
+        ];
+        // Nearest strike in the next expiry, of the same contract size
+        assert_eq!(target(&from, &contracts).map(Contract::id), Some(3.into()));
+        // Same strike if listed
+        let mut with_same = contracts.clone();
+        with_same.push(contract(6, "2030-07-05", 50000, "mini"));
+        assert_eq!(target(&from, &with_same).map(Contract::id), Some(6.into()));
+        // Nothing later
+        assert_eq!(target(&contracts[3], &contracts), None);
+
+        let settings = Settings {
+            enabled: true,
+            ..Default::default()
+        };
+        let opt = from.as_option().unwrap();
+        let p = |s: &str| Price::from_str(s).unwrap();
+        let near = opt.expiry - chrono::Duration::days(1);
+        let far = opt.expiry - chrono::Duration::days(10);
+        assert!(settings.is_candidate(&opt, near, p("60000")));
+        assert!(!settings.is_candidate(&opt, near, p("52000")));
+        assert!(!settings.is_candidate(&opt, far, p("60000")));
+    }
+
+    #[test]
+    fn tracker() {
+        let roll = Roll {
+            from: contract(1, "2030-06-28", 50000, "mini"),
+            to: contract(2, "2030-07-05", 50000, "mini"),
+            size: 10,
+            close_price: Price::TWENTY_FIVE,
+            open_price: Price::ONE_THOUSAND,
+        };
+        let cid = roll.from.id();
+        let now = UtcTime::now();
+        let mut tracker = Tracker::new(Settings {
+            enabled: true,
+            ..Default::default()
+        });
+        assert!(tracker.should_roll(cid));
+        assert!(!tracker.should_roll(cid));
+        tracker.start(roll.clone(), now);
+        assert_eq!(tracker.pending_contracts().collect::<Vec<_>>(), vec![cid]);
+
+        // Sells, and fills on other contracts, are not roll legs
+        tracker.record_fill(cid, -3);
+        tracker.record_fill(roll.to.id(), 3);
+        assert!(tracker.take_ready().is_empty());
+
+        // Partial fill of the first leg opens a matching second leg
+        tracker.record_fill(cid, 4);
+        assert_eq!(tracker.take_ready(), vec![roll.open_order(4)]);
+        assert!(tracker.take_ready().is_empty());
+        assert!(tracker.expire(now).is_empty());
+
+        // The rest never fills
+        let expired = tracker.expire(now + chrono::Duration::minutes(10));
+        assert_eq!(expired, vec![(roll.clone(), 6)]);
+        assert_eq!(tracker.pending_contracts().count(), 0);
+        tracker.record_fill(cid, 6);
+        assert!(tracker.take_ready().is_empty());
+
+        // Once flat, the position may be rolled again if reopened
+        tracker.retain(|_| false);
+        assert!(tracker.should_roll(cid));
+        tracker.start(roll.clone(), now);
+        assert_eq!(tracker.abort(cid), Some(roll));
+    }
+}
@@ -0,0 +1,342 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! LX Rules
+//!
+//! A reference for the conventions LX has followed, year by year, in settling
+//! options and in writing its 1099 support files. To reproduce LX's numbers
+//! we have to reproduce its quirks, and most of the bugs in the tax code have
+//! come from getting one of them subtly wrong. The rest of the code asks this
+//! module rather than checking years itself, and the tests below generate
+//! random trade histories and check the tax pipeline against it, so that a
+//! regression shows up without needing real account data.
+//!
+//! Known quirks:
+//!   * In 2021, settlement price references were taken at 22:00 UTC, even
+//!     during DST when the actual exercise time was 21:00 UTC, and expiries
+//!     were recorded *before* assignments. From 2022 on, the real exercise
+//!     time is used and expiries are recorded after assignments.
+//!   * In every year so far, the expiry/assignment dates in the 1099 CSV are
+//!     forced to 22:00 UTC.
+//!   * In 2021, next-day settlements were dated 21:00 UTC regardless of DST.
+//!   * The 2021 CSV describes each close by its type and a "quantity, asset"
+//!     string; later years use a user ID and a reference built from the close
+//!     type and 1256 status, with millisecond timestamps.
+//!   * Before 2024, basis and proceeds are written as absolute values.
+//!   * For closes of short positions, the acquired/disposed dates and the
+//!     basis/proceeds columns are swapped.
+//!   * Trade fees are folded into the unit price of the trade, and section
+//!     1256 gains are split 60% long-term and 40% short-term.
+//!
+
+use crate::units::{Price, Quantity};
+
+/// Hour (UTC) at which expiries and assignments are dated in the 1099 CSV
+const TAX_DATE_HOUR: u32 = 22;
+/// Fraction of a section 1256 gain which is treated as long-term
+pub const SECTION_1256_LONG_TERM: f64 = 0.6;
+
+/// Layout of the rows of LX's 1099 support CSV
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CsvLayout {
+    /// Close type, a "quantity, asset" description, dates and amounts
+    Description,
+    /// User ID, a reference string, quantity, asset, millisecond dates and amounts
+    Reference,
+}
+
+/// The conventions LX followed in a single year
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Rules {
+    year: i32,
+}
+
+impl Rules {
+    /// The rules for a given year
+    ///
+    /// For settlements this is the year of expiry; for the CSV it is the year
+    /// of the close.
+    pub fn for_year(year: i32) -> Self {
+        Rules { year }
+    }
+
+    /// Hour (UTC) to which settlement price reference times are forced, if any
+    pub fn forced_price_ref_hour(&self) -> Option<u32> {
+        if self.year <= 2021 {
+            Some(22)
+        } else {
+            None
+        }
+    }
+
+    /// Whether expiries are recorded before assignments (true) or after (false)
+    pub fn expiry_before_assignment(&self) -> bool {
+        self.year <= 2021
+    }
+
+    /// Hour (UTC) at which expiries and assignments are dated in the 1099 CSV
+    pub fn tax_date_hour(&self) -> u32 {
+        TAX_DATE_HOUR
+    }
+
+    /// Hour (UTC) to which next-day settlement dates are forced, if any
+    pub fn forced_nextday_hour(&self) -> Option<u32> {
+        if self.year <= 2021 {
+            Some(21)
+        } else {
+            None
+        }
+    }
+
+    /// Layout of the rows of the 1099 support CSV
+    pub fn csv_layout(&self) -> CsvLayout {
+        if self.year == 2021 {
+            CsvLayout::Description
+        } else {
+            CsvLayout::Reference
+        }
+    }
+
+    /// Whether basis and proceeds are written as absolute values in the CSV
+    pub fn absolute_amounts(&self) -> bool {
+        self.year < 2024
+    }
+
+    /// Whether the dates and amounts of a close are swapped in the CSV, given
+    /// the (signed) quantity closed
+    pub fn swaps_columns(&self, close_quantity: Quantity) -> bool {
+        close_quantity.is_negative()
+    }
+}
+
+/// The unit price at which a trade enters the tax records, with its fee
+/// folded in
+///
+/// `fee` is the amount charged to us (negative for a rebate) and `size` is
+/// negative for sales, so this raises the basis of purchases and lowers the
+/// proceeds of sales by any fee charged.
+pub fn fee_adjusted_price(price: Price, size: Quantity, fee: Price) -> Price {
+    price + fee / size
+}
+
+/// Splits a section 1256 gain into its (long-term, short-term) parts
+pub fn split_1256(gain: Price) -> (Price, Price) {
+    (gain.sixty(), gain.forty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::CsvPrinter;
+    use crate::ledgerx::history::lot::{CloseType, PrintMode};
+    use crate::ledgerx::history::tax::{GainType, OpenClose, PositionTracker, TaxDate};
+    use crate::units::{ContractSize, TaxAsset, Underlying, UtcTime};
+    use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+    use std::str::FromStr;
+
+    /// The documented quirks of a year, written out by hand rather than read
+    /// from [`Rules`]
+    struct Quirks {
+        year: i32,
+        layout: CsvLayout,
+        absolute_amounts: bool,
+        price_ref_hour: Option<u32>,
+        nextday_hour: Option<u32>,
+        expiry_first: bool,
+    }
+
+    const QUIRKS: [Quirks; 4] = [
+        Quirks {
+            year: 2021,
+            layout: CsvLayout::Description,
+            absolute_amounts: true,
+            price_ref_hour: Some(22),
+            nextday_hour: Some(21),
+            expiry_first: true,
+        },
+        Quirks {
+            year: 2022,
+            layout: CsvLayout::Reference,
+            absolute_amounts: true,
+            price_ref_hour: None,
+            nextday_hour: None,
+            expiry_first: false,
+        },
+        Quirks {
+            year: 2023,
+            layout: CsvLayout::Reference,
+            absolute_amounts: true,
+            price_ref_hour: None,
+            nextday_hour: None,
+            expiry_first: false,
+        },
+        Quirks {
+            year: 2024,
+            layout: CsvLayout::Reference,
+            absolute_amounts: false,
+            price_ref_hour: None,
+            nextday_hour: None,
+            expiry_first: false,
+        },
+    ];
+
+    /// Looks up the hand-written quirks for a year
+    fn quirks(year: i32) -> &'static Quirks {
+        QUIRKS
+            .iter()
+            .find(|q| q.year == year)
+            .unwrap_or_else(|| panic!("no quirks written down for {}", year))
+    }
+
+    #[test]
+    fn quirks_by_year() {
+        for quirks in &QUIRKS {
+            let (year, rules) = (quirks.year, Rules::for_year(quirks.year));
+            assert_eq!(rules.csv_layout(), quirks.layout, "year {}", year);
+            assert_eq!(
+                rules.absolute_amounts(),
+                quirks.absolute_amounts,
+                "year {}",
+                year
+            );
+            assert_eq!(
+                rules.forced_price_ref_hour(),
+                quirks.price_ref_hour,
+                "year {}",
+                year
+            );
+            assert_eq!(
+                rules.forced_nextday_hour(),
+                quirks.nextday_hour,
+                "year {}",
+                year
+            );
+            assert_eq!(
+                rules.expiry_before_assignment(),
+                quirks.expiry_first,
+                "year {}",
+                year
+            );
+            assert_eq!(rules.tax_date_hour(), 22, "year {}", year);
+            assert!(rules.swaps_columns(Quantity::Contracts(-1)));
+            assert!(!rules.swaps_columns(Quantity::Contracts(1)));
+        }
+    }
+
+    /// Generates random option trade histories, runs them through the tax
+    /// pipeline, and checks the result against the documented quirks
+    #[test]
+    fn random_histories() {
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let year = rng.gen_range(2021..=2024);
+            let options = [
+                crate::option::Option::from_str(&format!("{year}-06-24C40000")).unwrap(),
+                crate::option::Option::from_str(&format!("{year}-06-24P30000")).unwrap(),
+            ];
+
+            let mut tracker = PositionTracker::new();
+            let mut cash_flow = Price::ZERO;
+            let mut positions = [0i64; 2];
+            let mut date = UtcTime::parse_coinbase(&format!("{year}-01-03T15:00:00Z")).unwrap();
+            for _ in 0..rng.gen_range(1..20) {
+                let idx = rng.gen_range(0..2);
+                let mut n = rng.gen_range(1..6);
+                if rng.gen_bool(0.5) {
+                    n = -n;
+                }
+                let size = Quantity::Contracts(n);
+                let price = Price::from_cents(rng.gen_range(100..50_000));
+                let fee = Price::from_cents(rng.gen_range(-20..100));
+                date += chrono::Duration::minutes(rng.gen_range(1..10_000));
+
+                cash_flow -= price * size + fee;
+                positions[idx] += n;
+                let asset = TaxAsset::Option {
+                    underlying: Underlying::Btc,
+                    option: options[idx],
+                    contract_size: ContractSize::Mini,
+                };
+                tracker
+                    .push_trade(
+                        asset,
+                        size,
+                        fee_adjusted_price(price, size, fee),
+                        TaxDate::from(date),
+                    )
+                    .unwrap();
+            }
+            for (option, position) in options.iter().zip(positions) {
+                if position != 0 {
+                    tracker
+                        .push_expiry(
+                            *option,
+                            Underlying::Btc,
+                            ContractSize::Mini,
+                            Quantity::Contracts(-position),
+                        )
+                        .unwrap();
+                }
+            }
+
+            let mut total = Price::ZERO;
+            for event in tracker.events() {
+                let close = match event.open_close {
+                    OpenClose::Open(..) => continue,
+                    OpenClose::Close(ref close) => close,
+                };
+                assert_eq!(close.gain_loss_type(), GainType::Option1256);
+                total += close.gain_loss();
+
+                if close.ty() == CloseType::Expiry {
+                    assert_eq!(close.close_date().bare_time().hour(), 22);
+                }
+
+                let quirks = quirks(close.close_date().year());
+                let (mut basis, mut proceeds) = (close.basis(), close.proceeds());
+                if close.quantity().is_negative() {
+                    std::mem::swap(&mut basis, &mut proceeds);
+                }
+                if quirks.absolute_amounts {
+                    basis = basis.abs();
+                    proceeds = proceeds.abs();
+                }
+                let row = close
+                    .csv_printer(event.asset, 0, PrintMode::LedgerX)
+                    .to_string();
+                match quirks.layout {
+                    CsvLayout::Description => {
+                        assert!(row.starts_with(&CsvPrinter(close.ty()).to_string()));
+                        assert!(row.contains(&CsvPrinter((basis, proceeds)).to_string()));
+                    }
+                    CsvLayout::Reference => {
+                        assert!(row.starts_with("0,"));
+                        assert!(row.contains(&format!("{:#},{:#}", basis, proceeds)));
+                    }
+                }
+            }
+
+            // Once everything has expired, our gains are exactly our cash flow
+            assert!(
+                (total - cash_flow).abs() < Price::from_cents(1),
+                "seed {}: gain {} cash flow {}",
+                seed,
+                total,
+                cash_flow,
+            );
+            let (long_term, short_term) = split_1256(total);
+            assert!((long_term + short_term - total).abs() < Price::from_cents(1));
+        }
+    }
+}
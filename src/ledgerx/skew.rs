@@ -0,0 +1,234 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Inventory Skew
+//!
+//! Standing asks are priced independently for each contract, so nothing stops
+//! us from piling up exposure on one side of the book. When enabled, the model
+//! price of each standing ask is skewed based on our current inventory: if we
+//! are net short delta (mostly short calls) then call asks are priced higher
+//! and put asks lower, and vice versa; and asks on a side where we are already
+//! short a lot of vega are priced higher.
+//!
+
+use super::interesting::STANDING_IV;
+use crate::option::{self, PutCall};
+use crate::units::{Price, UtcTime};
+use std::fmt;
+
+/// Settings for skewing standing asks based on our inventory
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Whether to skew prices at all
+    pub enabled: bool,
+    /// Skew, in basis points of the model price, per BTC of net delta
+    pub delta_bps_per_btc: u32,
+    /// Skew, in basis points of the model price, per $100 of short vega (per
+    /// volatility point) on the same side as the order
+    pub vega_bps_per_100usd: u32,
+    /// Maximum skew in either direction, in percent of the model price
+    pub max_pct: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: false,
+            delta_bps_per_btc: 200,
+            vega_bps_per_100usd: 50,
+            max_pct: 20,
+        }
+    }
+}
+
+/// Our option exposure, split by side
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Inventory {
+    settings: Settings,
+    /// Net delta of our calls, in BTC (negative when short)
+    call_delta: f64,
+    /// Net delta of our puts, in BTC (positive when short)
+    put_delta: f64,
+    /// Short vega of our calls, in USD per volatility point
+    call_vega: f64,
+    /// Short vega of our puts, in USD per volatility point
+    put_vega: f64,
+}
+
+impl Inventory {
+    /// Computes our exposure from a list of positions, given as options and
+    /// their sizes in BTC (negative for short positions)
+    ///
+    /// Greeks are computed at the IV at which we price standing asks.
+    pub fn new<I: IntoIterator<Item = (option::Option, f64)>>(
+        settings: Settings,
+        positions: I,
+        now: UtcTime,
+        btc_price: Price,
+    ) -> Self {
+        let mut ret = Inventory {
+            settings,
+            call_delta: 0.0,
+            put_delta: 0.0,
+            call_vega: 0.0,
+            put_vega: 0.0,
+        };
+        for (opt, size) in positions {
+            if opt.expiry <= now {
+                continue;
+            }
+            let delta = opt.bs_delta(now, btc_price, STANDING_IV) * size;
+            // bs_vega is per unit of volatility, i.e. per 100 points
+            let short_vega = -opt.bs_vega(now, btc_price, STANDING_IV) * size / 100.0;
+            match opt.pc {
+                PutCall::Call => {
+                    ret.call_delta += delta;
+                    ret.call_vega += short_vega;
+                }
+                PutCall::Put => {
+                    ret.put_delta += delta;
+                    ret.put_vega += short_vega;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Our net delta across both sides, in BTC
+    pub fn net_delta(&self) -> f64 {
+        self.call_delta + self.put_delta
+    }
+
+    /// Computes the skew to apply to a standing ask on a given option
+    pub fn skew(&self, opt: &option::Option) -> Skew {
+        // When net short delta, calls add to the imbalance and puts reduce it
+        let (delta_sign, side_vega) = match opt.pc {
+            PutCall::Call => (-1.0, self.call_vega),
+            PutCall::Put => (1.0, self.put_vega),
+        };
+        let raw = delta_sign * self.net_delta() * f64::from(self.settings.delta_bps_per_btc)
+            + side_vega / 100.0 * f64::from(self.settings.vega_bps_per_100usd);
+        let max = i64::from(self.settings.max_pct) * 100;
+        Skew {
+            bps: (raw.round() as i64).clamp(-max, max),
+            net_delta: self.net_delta(),
+            side_vega,
+        }
+    }
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "net delta {:+.2} BTC (calls {:+.2}, puts {:+.2}); short vega ${:.0} calls, ${:.0} puts per vol point",
+            self.net_delta(),
+            self.call_delta,
+            self.put_delta,
+            self.call_vega,
+            self.put_vega,
+        )
+    }
+}
+
+/// The skew applied to a single order
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Skew {
+    /// The skew, in basis points of the model price
+    pub bps: i64,
+    /// Our net delta when the skew was computed, in BTC
+    pub net_delta: f64,
+    /// Our short vega on the order's side, in USD per volatility point
+    pub side_vega: f64,
+}
+
+impl Skew {
+    /// Applies the skew to a model price
+    pub fn apply(&self, price: Price) -> Price {
+        price.scale_ratio(10_000 + self.bps, 10_000)
+    }
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:+.2}% (net delta {:+.2} BTC, short vega ${:.0} on this side)",
+            self.bps as f64 / 100.0,
+            self.net_delta,
+            self.side_vega,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn skew() {
+        let now = UtcTime::now();
+        let btc = Price::from_str("60000").unwrap();
+        let mut call = option::Option::from_str("2030-06-28C70000").unwrap();
+        call.expiry = now + chrono::Duration::days(30);
+        let mut put = option::Option::from_str("2030-06-28P50000").unwrap();
+        put.expiry = call.expiry;
+        let settings = Settings {
+            enabled: true,
+            delta_bps_per_btc: 200,
+            vega_bps_per_100usd: 0,
+            max_pct: 20,
+        };
+
+        // No positions, no skew
+        let flat = Inventory::new(settings, vec![], now, btc);
+        assert_eq!(flat.skew(&call).bps, 0);
+        assert_eq!(flat.skew(&put).bps, 0);
+
+        // Short calls: calls priced higher, puts lower, symmetrically
+        let short_calls = Inventory::new(settings, vec![(call, -3.0)], now, btc);
+        let delta = call.bs_delta(now, btc, STANDING_IV) * 3.0;
+        assert!((short_calls.net_delta() + delta).abs() < 1e-9);
+        let (c, p) = (short_calls.skew(&call), short_calls.skew(&put));
+        assert_eq!(c.bps, (delta * 200.0).round() as i64);
+        assert!(c.bps > 0);
+        assert_eq!(p.bps, -c.bps);
+        assert!(c.apply(btc) > btc);
+        assert!(p.apply(btc) < btc);
+
+        // Skew is capped
+        let huge = Inventory::new(settings, vec![(call, -1000.0)], now, btc);
+        assert_eq!(huge.skew(&call).bps, 2000);
+        assert_eq!(huge.skew(&put).bps, -2000);
+
+        // Short vega on a side raises prices on that side only
+        let vega_settings = Settings {
+            delta_bps_per_btc: 0,
+            vega_bps_per_100usd: 100,
+            ..settings
+        };
+        let short_puts = Inventory::new(vega_settings, vec![(put, -2.0)], now, btc);
+        assert_eq!(short_puts.skew(&call).bps, 0);
+        let vega = put.bs_vega(now, btc, STANDING_IV) * 2.0 / 100.0;
+        assert_eq!(short_puts.skew(&put).bps, vega.round() as i64);
+        assert!(short_puts.skew(&put).bps > 0);
+
+        // Expired positions are ignored
+        let mut expired = call;
+        expired.expiry = now - chrono::Duration::days(1);
+        let stale = Inventory::new(settings, vec![(expired, -3.0)], now, btc);
+        assert_eq!(stale.skew(&call).bps, 0);
+    }
+}
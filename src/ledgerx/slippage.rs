@@ -0,0 +1,290 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Slippage
+//!
+//! Our strategy is to quote and then wait. While we wait the BTC price moves,
+//! so by the time an order fills it may be worth more (or less) than when we
+//! priced it. For every fill of an order whose creation we saw, we record the
+//! BTC price at both times. From this we compute the implied volatility of
+//! our quote at decision time, and what the option would have been worth at
+//! that volatility at fill time. The difference between this model price and
+//! the actual fill price is the slippage.
+//!
+//! Fills are appended to a CSV file, which the `slippage` command reads to
+//! produce histograms by contract type and by time of day.
+//!
+
+use crate::option;
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use chrono::Timelike as _;
+use log::info;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::{fmt, fs, io, io::BufRead as _, io::Write as _, str};
+
+/// Width of each histogram bucket, in percent
+const BUCKET_WIDTH_PCT: f64 = 2.0;
+/// Number of buckets on each side of zero; anything beyond goes in the last bucket
+const N_BUCKETS: i64 = 5;
+
+/// A single fill of one of our own orders
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Fill {
+    /// The option which was traded
+    pub option: option::Option,
+    /// Number of contracts filled (negative for asks)
+    pub size: i64,
+    /// Limit price of the order
+    pub limit_price: Price,
+    /// Price at which the order filled
+    pub fill_price: Price,
+    /// Time at which the order was created
+    pub decision_time: UtcTime,
+    /// BTC price reference at order creation
+    pub decision_btc: Price,
+    /// Time at which the order was filled
+    pub fill_time: UtcTime,
+    /// BTC price reference at fill time
+    pub fill_btc: Price,
+}
+
+impl Fill {
+    /// The implied volatility of our limit price at decision time
+    pub fn decision_iv(&self) -> Option<f64> {
+        self.option
+            .bs_iv(self.decision_time, self.decision_btc, self.limit_price)
+            .ok()
+    }
+
+    /// What the option was worth at fill time, at the IV of our decision
+    pub fn model_price_at_fill(&self) -> Option<Price> {
+        let iv = self.decision_iv()?;
+        Some(self.option.bs_price(self.fill_time, self.fill_btc, iv))
+    }
+
+    /// The slippage, as a percentage of the model price at fill time
+    ///
+    /// Positive values are in our favor (we sold above, or bought below, the
+    /// model price). Returns `None` if the model price could not be computed.
+    pub fn slippage_pct(&self) -> Option<f64> {
+        let model = self.model_price_at_fill()?;
+        if model == Price::ZERO {
+            return None;
+        }
+        let diff = (self.fill_price - model).to_approx_f64() / model.to_approx_f64() * 100.0;
+        if self.size < 0 {
+            Some(diff)
+        } else {
+            Some(-diff)
+        }
+    }
+
+    /// Appends this fill to the given CSV file
+    pub fn append_to_csv(&self, path: &Path) -> anyhow::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening fill file {}", path.display()))?;
+        writeln!(file, "{self}").with_context(|| format!("writing to {}", path.display()))
+    }
+}
+
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},{},{}",
+            self.decision_time.format("%s"),
+            self.fill_time.format("%s"),
+            self.option,
+            self.size,
+            self.limit_price,
+            self.fill_price,
+            self.decision_btc,
+            self.fill_btc,
+        )
+    }
+}
+
+impl str::FromStr for Fill {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(',').collect();
+        if fields.len() != 8 {
+            return Err(anyhow::Error::msg(format!(
+                "expected 8 fields in fill, got {}: {s}",
+                fields.len()
+            )));
+        }
+        let price = |s: &str| Price::from_str(s).with_context(|| format!("parsing price {s}"));
+        Ok(Fill {
+            decision_time: UtcTime::from_unix_str(fields[0])
+                .with_context(|| format!("parsing decision time {}", fields[0]))?,
+            fill_time: UtcTime::from_unix_str(fields[1])
+                .with_context(|| format!("parsing fill time {}", fields[1]))?,
+            option: option::Option::from_str(fields[2]).map_err(anyhow::Error::msg)?,
+            size: fields[3]
+                .parse()
+                .with_context(|| format!("parsing size {}", fields[3]))?,
+            limit_price: price(fields[4])?,
+            fill_price: price(fields[5])?,
+            decision_btc: price(fields[6])?,
+            fill_btc: price(fields[7])?,
+        })
+    }
+}
+
+/// Reads all fills from a CSV file
+pub fn read_fills(path: &Path) -> anyhow::Result<Vec<Fill>> {
+    let file =
+        fs::File::open(path).with_context(|| format!("opening fill file {}", path.display()))?;
+    let mut ret = vec![];
+    for (n, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("reading {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        ret.push(
+            line.parse()
+                .with_context(|| format!("parsing line {} of {}", n + 1, path.display()))?,
+        );
+    }
+    Ok(ret)
+}
+
+/// Distribution of slippage values
+#[derive(Clone, PartialEq, Debug, Default)]
+struct Distribution {
+    values: Vec<f64>,
+}
+
+impl Distribution {
+    fn log(&self, label: &str) {
+        if self.values.is_empty() {
+            return;
+        }
+        let mut sorted = self.values.clone();
+        sorted.sort_by(f64::total_cmp);
+        let mean = sorted.iter().sum::<f64>() / sorted.len() as f64;
+        let median = sorted[sorted.len() / 2];
+        info!(
+            "{}: {} fills, mean {:.2}%, median {:.2}%",
+            label,
+            sorted.len(),
+            mean,
+            median
+        );
+
+        let mut buckets = BTreeMap::new();
+        for val in &sorted {
+            let idx = ((val / BUCKET_WIDTH_PCT).floor() as i64).clamp(-N_BUCKETS, N_BUCKETS - 1);
+            *buckets.entry(idx).or_insert(0usize) += 1;
+        }
+        let max = buckets.values().copied().max().unwrap_or(1);
+        for idx in -N_BUCKETS..N_BUCKETS {
+            let count = buckets.get(&idx).copied().unwrap_or(0);
+            let lo = idx as f64 * BUCKET_WIDTH_PCT;
+            let range = if idx == -N_BUCKETS {
+                format!("      < {:+5.1}%", lo + BUCKET_WIDTH_PCT)
+            } else if idx == N_BUCKETS - 1 {
+                format!("     >= {lo:+5.1}%")
+            } else {
+                format!("{:+5.1}% .. {:+5.1}%", lo, lo + BUCKET_WIDTH_PCT)
+            };
+            info!(
+                "    {} {:4} {}",
+                range,
+                count,
+                "#".repeat((count * 40).div_ceil(max))
+            );
+        }
+    }
+}
+
+/// Logs a report of slippage by contract type and by time of day
+pub fn log_report(fills: &[Fill]) {
+    let mut total = Distribution::default();
+    let mut by_type: BTreeMap<&str, Distribution> = BTreeMap::new();
+    let mut by_hour: BTreeMap<u32, Distribution> = BTreeMap::new();
+    let mut skipped = 0;
+    for fill in fills {
+        let pct = match fill.slippage_pct() {
+            Some(pct) => pct,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        total.values.push(pct);
+        by_type
+            .entry(fill.option.pc.as_str())
+            .or_default()
+            .values
+            .push(pct);
+        by_hour
+            .entry(fill.decision_time.new_york_time().hour())
+            .or_default()
+            .values
+            .push(pct);
+    }
+
+    info!(
+        "Slippage relative to model price at fill time (positive is in our favor); \
+         {} fills, {} skipped since no model price was available",
+        fills.len(),
+        skipped,
+    );
+    info!("");
+    total.log("All fills");
+    for (ty, dist) in &by_type {
+        info!("");
+        dist.log(ty);
+    }
+    for (hour, dist) in &by_hour {
+        info!("");
+        dist.log(&format!("Decided {hour:02}:00-{hour:02}:59 New York time"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn roundtrip_and_sign() {
+        let decision_time = UtcTime::from_unix_i64(1_700_000_000).unwrap();
+        let fill = Fill {
+            option: option::Option::from_str("2023-12-15P30000").unwrap(),
+            size: -2,
+            limit_price: Price::from_str("500").unwrap(),
+            fill_price: Price::from_str("500").unwrap(),
+            decision_time,
+            decision_btc: Price::from_str("35000").unwrap(),
+            fill_time: decision_time + chrono::Duration::hours(2),
+            fill_btc: Price::from_str("36000").unwrap(),
+        };
+        let parsed = Fill::from_str(&fill.to_string()).unwrap();
+        assert_eq!(parsed, fill);
+
+        // BTC went up, so the put we sold was worth less when it filled: good for us
+        assert!(fill.slippage_pct().unwrap() > 0.0);
+        // Had we been buying, this would be bad for us
+        let bid = Fill { size: 2, ..fill };
+        assert!(bid.slippage_pct().unwrap() < 0.0);
+    }
+}
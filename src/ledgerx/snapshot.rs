@@ -0,0 +1,862 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Snapshots
+//!
+//! The tracker is updated by every message the main loop receives, so if
+//! the strategy read it directly, different parts of a single heartbeat's
+//! decisions could see different books, balances or prices. Instead, at the
+//! start of each heartbeat we capture a [`Snapshot`] of everything the
+//! strategy looks at, including the time, and the strategy runs entirely
+//! off that. Given the same snapshot, it makes the same decisions.
+//!
+
+use super::interesting::{self, AskStats, BidStats, ModelValues};
+use super::json::CreateOrder;
+use super::moneyness::Distance;
+use super::{
+    book, bounds, close, collateral, contract, goals, greek_limits, own_orders, pin_risk, roll,
+    skew,
+};
+use super::{BookState, Contract, ContractId, LedgerX, MessageId, NEGLIGIBLE_REPRICE_PCT};
+use crate::connect::Message;
+use crate::option;
+use crate::price::PriceBoard;
+use crate::queue::Sender;
+use crate::terminal::ColorFormat;
+use crate::units::{Price, Quantity, Underlying, UtcTime};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+
+/// A consistent view of the tracker's state, as of a single moment
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snapshot {
+    /// The time at which the snapshot was taken
+    pub timestamp: UtcTime,
+    /// All contracts being tracked, with their order books
+    pub contracts: HashMap<ContractId, (Contract, BookState)>,
+    /// The price references and their consensus
+    pub prices: PriceBoard,
+    /// Our open orders
+    pub own_orders: own_orders::Tracker,
+    /// Contracts being bought back by the first leg of a roll, whose bids
+    /// are left alone by the heartbeat
+    pub roll_legs: HashSet<ContractId>,
+    /// Available USD balance
+    pub available_usd: Price,
+    /// Available BTC balance
+    pub available_btc: bitcoin::Amount,
+    /// Our net position, in contracts, in each contract
+    pub own_positions: HashMap<ContractId, i64>,
+    /// Most recently reported open interest for each contract
+    pub open_interest: HashMap<ContractId, usize>,
+    /// Yield below which interesting contracts are not logged
+    pub yield_threshold: interesting::YieldThreshold,
+    /// Settings for skewing standing asks based on our inventory
+    pub skew: skew::Settings,
+    /// Caps on our aggregate short vega and gamma
+    pub greek_limits: greek_limits::Settings,
+    /// Days-to-expiry limits on the options we trade
+    pub dte_filter: interesting::DteFilter,
+    /// The time from which the return of a standing ask is annualized
+    pub arr_reference: interesting::ArrReference,
+    /// LX's fee per 100 contracts, at our current fee tier
+    pub fee: Price,
+}
+
+impl Snapshot {
+    /// Our option positions, for computing collateral requirements
+    pub fn portfolio(&self) -> collateral::Portfolio {
+        super::portfolio(&self.contracts, &self.own_positions)
+    }
+
+    /// Our share of the open interest of a contract, as (our position, open interest)
+    ///
+    /// Returns `None` if we do not know the open interest.
+    pub fn oi_share(&self, cid: ContractId) -> Option<(i64, usize)> {
+        super::oi_share(&self.open_interest, &self.own_positions, cid)
+    }
+
+    /// Approximate net liquidation value of the account: available balances plus
+    /// the collateral locked up by our positions, ignoring the value of the
+    /// options themselves
+    pub fn net_liquidation_value(&self, btc_price: Price) -> Price {
+        let locked = self.portfolio().requirement();
+        let btc = Quantity::from(self.available_btc + locked.btc);
+        self.available_usd + locked.usd + btc_price * btc
+    }
+
+    /// Approximate equity of the account: the net liquidation value, plus the
+    /// model value of our option positions (negative for shorts) at the
+    /// volatility we quote standing asks at
+    ///
+    /// Marks at the consensus BTC price as of the snapshot, failing if there is
+    /// none. We have no ETH price reference, so also fails if we hold any ETH
+    /// contracts, rather than leaving them out of the mark.
+    pub fn account_equity(&self) -> anyhow::Result<Price> {
+        let btc_price = self.prices.get_at(self.timestamp)?.btc_price;
+        for (cid, size) in &self.own_positions {
+            if let Some((contract, _)) = self.contracts.get(cid) {
+                if contract.underlying() != Underlying::Btc && *size != 0 {
+                    return Err(anyhow::Error::msg(format!(
+                        "cannot mark position of {} in {}: no {} price reference",
+                        size,
+                        contract.label(),
+                        contract.underlying(),
+                    )));
+                }
+            }
+        }
+        // The portfolio counts BTC options in minis, whatever their contract size
+        let options = self
+            .portfolio()
+            .positions()
+            .map(|(opt, size)| {
+                opt.bs_price(self.timestamp, btc_price, interesting::STANDING_IV)
+                    * Quantity::Contracts(size)
+            })
+            .fold(Price::ZERO, |acc, value| acc + value);
+        Ok(self.net_liquidation_value(btc_price) + options)
+    }
+
+    /// Go through the list of all open orders and log them all
+    pub fn log_open_orders(&self) {
+        for order in self.own_orders.open_order_iter() {
+            if let Some((contract, book)) = self.contracts.get(&order.contract_id) {
+                let size = order.size.with_asset_trade(contract.asset());
+                let queue = match book.queue_position(order.message_id) {
+                    Some(pos) => pos.to_string(),
+                    None => "not in book".to_owned(),
+                };
+                match contract.ty() {
+                    contract::Type::Option { opt, .. } => {
+                        info!("Open order {} ({}):", order.message_id, queue);
+                        let price_ref = self.prices.last();
+                        opt.log_option_data("    ", price_ref.timestamp, price_ref.btc_price);
+                        opt.log_order_data(
+                            "    ",
+                            price_ref.timestamp,
+                            price_ref.btc_price,
+                            order.price,
+                            Some(size),
+                        );
+                        info!("");
+                    }
+                    contract::Type::NextDay { .. } => {
+                        info!(
+                            "Open order {} ({}): {} BTC @ {}",
+                            order.message_id, queue, size, order.price
+                        );
+                    }
+                    contract::Type::Future { .. } => {
+                        info!(
+                            "Open order {} ({}): {} future?? @ {}",
+                            order.message_id, queue, size, order.price
+                        );
+                    }
+                }
+            } else {
+                warn!(
+                    "Have open order for CID {} that we're not tracking.",
+                    order.contract_id
+                );
+            }
+        }
+    }
+
+    /// Go through the list of all contracts we're tracking and open standing orders on them.
+    ///
+    /// This function is the core (arguably, the entirety) of our trading algo. Currently
+    /// it opens limit asks on each contract subject to various constraints:
+    ///
+    /// 1. It must have a sufficiently high IV and ARR, and sufficiently low loss80.
+    /// 2. The IV must not be too high (otherwise the order is just dumb and LX will
+    ///    probably flag me for it).
+    ///
+    /// If these conditions can't be simultaneously met, no order is opened.
+    ///
+    /// Our existing open orders are cancelled and replaced, except that if one of
+    /// them is first in the queue at its price level, and the new price would be
+    /// within [`NEGLIGIBLE_REPRICE_PCT`] of its price, we keep it rather than give
+    /// up our place. Returns the number of orders cancelled.
+    ///
+    /// If we have a goal to reacquire BTC, puts near the goal price are priced
+    /// more aggressively. If caps on our short vega or gamma are set, asks which
+    /// would take our existing positions plus the orders we are keeping or
+    /// opening over a cap are not opened, and such existing asks are not kept.
+    pub fn open_standing_orders(
+        &mut self,
+        tx: &Sender<Message>,
+        goal: Option<&goals::Goal>,
+    ) -> usize {
+        let price_ref = match self.prices.get_at(self.timestamp) {
+            Ok(price_ref) => price_ref,
+            Err(e) => {
+                warn!("Not opening standing orders: {:#}", e);
+                return self.cancel_orders_except(&HashSet::new(), tx);
+            }
+        };
+        info!("Opening standing orders; price reference {}", self.prices);
+        let mut new_orders = vec![];
+        let mut keep = HashSet::new();
+        let now = self.timestamp;
+        let portfolio = self.portfolio();
+        let inventory = self.inventory(price_ref.btc_price);
+        if let Some(ref inventory) = inventory {
+            info!("Skewing asks for inventory: {}", inventory);
+        }
+        let mut greek_limits = self.greek_limits(price_ref.btc_price, false);
+        let models = ModelValues::for_contracts(
+            self.contracts.values().map(|(c, _)| c),
+            price_ref,
+            UtcTime::now(),
+        );
+        for cid in self.contracts.keys() {
+            if let (Some((c, book)), Some(model)) = (self.contracts.get(cid), models.get(cid)) {
+                if let Some(stats) = AskStats::standing_order(
+                    price_ref,
+                    c,
+                    model,
+                    &portfolio,
+                    self.available_usd,
+                    self.available_btc,
+                    book.best_ask().0,
+                    goal,
+                    inventory.as_ref(),
+                    &self.dte_filter,
+                    self.arr_reference,
+                    self.fee,
+                ) {
+                    // for now just log
+                    let opt = match interesting::extract_option(c, price_ref) {
+                        Some(opt) => opt,
+                        None => continue,
+                    };
+
+                    let msg;
+                    if stats.order_size().is_positive() {
+                        let btc_size = -stats.order_size().btc_equivalent().to_btc();
+                        if let Some((mid, pos, kept_size)) =
+                            self.keepable_order(c, book, stats.order_price())
+                        {
+                            // A kept order counts against the caps at its own
+                            // size; if it would breach one, it is cancelled and
+                            // we try to reprice as though we had no order
+                            let within_limits = match greek_limits {
+                                Some(ref mut limits) => {
+                                    limits.try_add(c.underlying(), &opt, kept_size)
+                                }
+                                None => Ok(()),
+                            };
+                            match within_limits {
+                                Ok(()) => {
+                                    info!(
+                                        "Keeping order {} ({}) rather than repricing to {}",
+                                        mid,
+                                        pos,
+                                        stats.order_price(),
+                                    );
+                                    keep.insert(mid);
+                                    continue;
+                                }
+                                Err(breach) => info!("Not keeping order {}: {}", mid, breach),
+                            }
+                        }
+                        if let Some(ref mut limits) = greek_limits {
+                            if let Err(breach) = limits.try_add(c.underlying(), &opt, btc_size) {
+                                info!("Not opening ask on {}: {}", opt, breach);
+                                continue;
+                            }
+                        }
+                        msg = ColorFormat::white("Sell to open: ");
+                        new_orders.push((
+                            opt,
+                            stats.order_price(),
+                            CreateOrder::new_ask(c, stats.order_size(), stats.order_price()),
+                        ));
+                    } else {
+                        msg = ColorFormat::pale_yellow("  Would sell: ");
+                    }
+
+                    opt.log_option_data(&msg, now, price_ref.btc_price);
+                    opt.log_order_data(
+                        &msg,
+                        now,
+                        price_ref.btc_price,
+                        stats.order_price(),
+                        Some(stats.order_size()),
+                    );
+                    info!("");
+                }
+            }
+        }
+
+        // Final sanity check against bugs in the pricing above
+        let quotes: Vec<_> = new_orders
+            .iter()
+            .map(|(opt, price, _)| (*opt, *price))
+            .collect();
+        let violations = bounds::check_all(&quotes, price_ref.btc_price);
+        let new_orders: Vec<_> = new_orders
+            .into_iter()
+            .zip(violations)
+            .filter_map(|((opt, _, order), violation)| match violation {
+                Some(violation) => {
+                    warn!("Rejecting ask on {}: {}", opt, violation);
+                    None
+                }
+                None => Some(order),
+            })
+            .collect();
+
+        // Cancel before opening, so that the cancellations free up collateral
+        let n_cancelled = self.cancel_orders_except(&keep, tx);
+        info!(
+            "Opened {} orders, kept {} and cancelled {}.",
+            new_orders.len(),
+            keep.len(),
+            n_cancelled,
+        );
+        for order in new_orders {
+            tx.send(Message::OpenOrder(order)).unwrap();
+        }
+        n_cancelled
+    }
+
+    /// Our BTC option exposure by side, if inventory skew is enabled
+    ///
+    /// We only quote BTC options, and their Greeks are in terms of the BTC
+    /// price, so options on other underlyings are left out.
+    fn inventory(&self, btc_price: Price) -> Option<skew::Inventory> {
+        if !self.skew.enabled {
+            return None;
+        }
+        let positions = self.own_positions.iter().filter_map(|(cid, size)| {
+            let (contract, _) = self.contracts.get(cid)?;
+            if contract.underlying() != Underlying::Btc {
+                return None;
+            }
+            let btc_size = *size as f64 / contract.multiplier() as f64;
+            Some((contract.as_option()?, btc_size))
+        });
+        Some(skew::Inventory::new(
+            self.skew,
+            positions,
+            self.timestamp,
+            btc_price,
+        ))
+    }
+
+    /// Our short Greek exposure, if any caps on it are set
+    ///
+    /// Includes our positions, and if `with_orders` is set, our open orders.
+    fn greek_limits(&self, btc_price: Price, with_orders: bool) -> Option<greek_limits::Limits> {
+        if !self.greek_limits.enabled() {
+            return None;
+        }
+        let positions = self.own_positions.iter().filter_map(|(cid, size)| {
+            let (contract, _) = self.contracts.get(cid)?;
+            let btc_size = *size as f64 / contract.multiplier() as f64;
+            Some((contract.underlying(), contract.as_option()?, btc_size))
+        });
+        let mut limits =
+            greek_limits::Limits::new(self.greek_limits, positions, self.timestamp, btc_price);
+        if with_orders {
+            for order in self.own_orders.open_order_iter() {
+                if let Some((contract, _)) = self.contracts.get(&order.contract_id) {
+                    if let Some(opt) = contract.as_option() {
+                        let size = order.size.with_asset_trade(contract.asset());
+                        limits.add(contract.underlying(), &opt, size.btc_equivalent().to_btc());
+                    }
+                }
+            }
+        }
+        Some(limits)
+    }
+
+    /// Find an open ask of ours on a contract that is worth keeping rather than
+    /// repricing to `new_price`, and return its ID, queue position and remaining
+    /// size in BTC (negative, since it is an ask)
+    fn keepable_order(
+        &self,
+        contract: &Contract,
+        book: &BookState,
+        new_price: Price,
+    ) -> Option<(MessageId, book::QueuePosition, f64)> {
+        self.own_orders
+            .open_order_iter()
+            .filter(|order| {
+                order.contract_id == contract.id()
+                    && order.size.with_asset_trade(contract.asset()).is_negative()
+            })
+            .find_map(|order| {
+                let reprice = (new_price - order.price).abs().to_approx_f64();
+                if reprice > order.price.to_approx_f64() * NEGLIGIBLE_REPRICE_PCT / 100.0 {
+                    return None;
+                }
+                let pos = book.queue_position(order.message_id)?;
+                if pos.is_first() {
+                    let size = order.size.with_asset_trade(contract.asset());
+                    Some((order.message_id, pos, size.btc_equivalent().to_btc()))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Request cancellation of all our open orders other than those in `keep`
+    /// and the first legs of rolls in progress
+    ///
+    /// Returns the number of orders cancelled.
+    fn cancel_orders_except(&self, keep: &HashSet<MessageId>, tx: &Sender<Message>) -> usize {
+        let mut count = 0;
+        for order in self.own_orders.open_order_iter() {
+            let roll_leg = self.roll_legs.contains(&order.contract_id) && order.size.to_i64() > 0;
+            if !keep.contains(&order.message_id) && !roll_leg {
+                tx.send(Message::CancelOrder {
+                    message_id: order.message_id,
+                    contract_id: order.contract_id,
+                })
+                .unwrap();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Request cancellation of all our open orders which do not only reduce one
+    /// of our positions, e.g. when we should stop taking on risk but still want
+    /// to close out
+    ///
+    /// Returns the number of orders cancelled.
+    pub fn cancel_opening_orders(&self, tx: &Sender<Message>) -> usize {
+        let keep = self
+            .own_orders
+            .open_order_iter()
+            .filter(|order| {
+                super::reduces_position(&self.own_positions, order.contract_id, order.size.to_i64())
+            })
+            .map(|order| order.message_id)
+            .collect();
+        self.cancel_orders_except(&keep, tx)
+    }
+
+    /// Plans rolls of any short positions which are close to expiry and far
+    /// out of the money
+    ///
+    /// We buy back at the best ask, provided it is no more than the configured
+    /// limit, as many contracts as are on offer there. The new option is priced
+    /// as a standing ask, so must pass the same IV, ARR and loss80 checks, but
+    /// if somebody is bidding more than that we sell at their price.
+    pub fn plan_rolls(&self, settings: &roll::Settings) -> Vec<roll::Roll> {
+        let price_ref = match self.prices.get_at(self.timestamp) {
+            Ok(price_ref) => price_ref,
+            Err(e) => {
+                warn!("Not planning rolls: {:#}", e);
+                return vec![];
+            }
+        };
+        let btc = price_ref.btc_price;
+        let portfolio = self.portfolio();
+        let mut ret = vec![];
+        for (cid, size) in &self.own_positions {
+            if *size >= 0 {
+                continue;
+            }
+            let (from, book) = match self.contracts.get(cid) {
+                Some(data) => data,
+                None => continue,
+            };
+            match from.as_option() {
+                Some(opt) if settings.is_candidate(&opt, self.timestamp, btc) => {}
+                _ => continue,
+            }
+            let (close_price, ask_size) = book.best_ask();
+            let size = match ask_size {
+                Quantity::Contracts(n) => n.min(-size),
+                _ => continue,
+            };
+            if close_price == Price::ZERO || close_price > settings.max_remaining || size <= 0 {
+                debug!(
+                    "Not rolling {}: best ask {} for {} (limit {})",
+                    from, close_price, ask_size, settings.max_remaining,
+                );
+                continue;
+            }
+            let to = match roll::target(from, self.contracts.values().map(|(c, _)| c)) {
+                Some(to) => to,
+                None => {
+                    info!("Not rolling {}: no later expiry listed", from);
+                    continue;
+                }
+            };
+            // unwrap ok since `target` only returns contracts we are tracking
+            let to_book = &self.contracts[&to.id()].1;
+            let model = match interesting::extract_option(to, price_ref) {
+                Some(opt) => ModelValues::new(&opt, UtcTime::now(), btc),
+                None => {
+                    info!("Not rolling {}: cannot quote {}", from, to);
+                    continue;
+                }
+            };
+            let stats = match AskStats::standing_order(
+                price_ref,
+                to,
+                &model,
+                &portfolio,
+                self.available_usd,
+                self.available_btc,
+                to_book.best_ask().0,
+                None,
+                // A roll moves exposure rather than adding to it, so is not skewed
+                None,
+                &self.dte_filter,
+                self.arr_reference,
+                self.fee,
+            ) {
+                Some(stats) => stats,
+                None => {
+                    info!("Not rolling {}: no acceptable price for {}", from, to);
+                    continue;
+                }
+            };
+            let open_price = stats.order_price().max(to_book.best_bid().0);
+            // unwrap ok since `standing_order` only prices options
+            if let Some(violation) = bounds::check(&to.as_option().unwrap(), open_price, btc) {
+                warn!(
+                    "Not rolling {}: rejecting ask on {}: {}",
+                    from, to, violation
+                );
+                continue;
+            }
+            ret.push(roll::Roll {
+                from: from.clone(),
+                to: to.clone(),
+                size,
+                close_price,
+                open_price,
+            });
+        }
+        ret.sort_by_key(|roll| roll.from.id());
+        ret
+    }
+
+    /// Plans a close of our short position in a contract against its book
+    pub fn plan_close(&self, request: close::Request) -> anyhow::Result<close::Plan> {
+        let price_ref = self.prices.get_at(self.timestamp)?;
+        let (contract, book) = self.contracts.get(&request.contract_id).ok_or_else(|| {
+            anyhow::Error::msg(format!("not tracking contract {}", request.contract_id))
+        })?;
+        let position = self
+            .own_positions
+            .get(&request.contract_id)
+            .copied()
+            .unwrap_or(0);
+        let own_orders = self
+            .own_orders
+            .open_order_iter()
+            .map(|order| order.message_id)
+            .collect();
+        close::Plan::new(
+            contract,
+            book,
+            &own_orders,
+            position,
+            self.timestamp,
+            price_ref.btc_price,
+            request.max_price,
+        )
+    }
+
+    /// Logs the pin risk of our short positions expiring within the next
+    /// [`pin_risk::HEARTBEAT_DAYS`] days, and our short Greeks (including open
+    /// orders) against their caps, if any are set
+    pub fn log_pin_risk(&self) {
+        let btc_price = self.prices.last().btc_price;
+        pin_risk::Report::new(&self.portfolio(), self.timestamp, btc_price)
+            .log(self.timestamp, Some(pin_risk::HEARTBEAT_DAYS));
+        if let Some(limits) = self.greek_limits(btc_price, true) {
+            limits.log();
+        }
+    }
+
+    /// Logs how far the BTC price is from the strike of each of our short options
+    pub fn log_moneyness(&self) {
+        let btc_price = self.prices.last().btc_price;
+        let mut shorts: Vec<(option::Option, &str, i64)> = self
+            .own_positions
+            .iter()
+            .filter(|(_, size)| **size < 0)
+            .filter_map(|(cid, size)| {
+                let (contract, _) = self.contracts.get(cid)?;
+                Some((contract.as_option()?, contract.label(), -size))
+            })
+            .collect();
+        if shorts.is_empty() {
+            return;
+        }
+        shorts.sort_by_key(|(opt, label, _)| (opt.expiry, opt.strike, label.to_string()));
+        info!("Short positions at BTC {}:", btc_price);
+        for (opt, label, size) in shorts {
+            info!(
+                "    {} x{}: {} from strike",
+                label,
+                size,
+                Distance::to_strike(&opt, btc_price),
+            );
+        }
+    }
+
+    /// Go through the list of all contracts we're tracking and log the interesting ones
+    ///
+    /// Returns the total USD and BTC committed to any bids we matched, which
+    /// are docked from the snapshot's balances and should be docked from the
+    /// tracker's too.
+    pub fn log_interesting_contracts(&mut self, tx: &Sender<Message>) -> (Price, bitcoin::Amount) {
+        let mut total_usd = Price::ZERO;
+        let mut total_btc = bitcoin::Amount::ZERO;
+        if let Err(e) = self.prices.get_at(self.timestamp) {
+            warn!("Not checking for interesting contracts: {:#}", e);
+            return (total_usd, total_btc);
+        }
+        for cid in self.contracts.keys() {
+            if let Some((c, book)) = self.contracts.get(cid) {
+                let (usd, btc) = self.log_interesting_contract(c, book, tx);
+                // Pre-emptively dock our balances based on
+                LedgerX::preemptively_dock_balances(
+                    &mut self.available_usd,
+                    &mut self.available_btc,
+                    usd,
+                    btc,
+                );
+                total_usd += usd;
+                total_btc += btc;
+            }
+        }
+        (total_usd, total_btc)
+    }
+
+    /// Log a single interesting contract
+    ///
+    /// This function may do more than log -- it may attempt to match bids that are
+    /// interesting. In this case, it returns the total USD and BTC committed.
+    pub(super) fn log_interesting_contract(
+        &self,
+        c: &Contract,
+        book: &BookState,
+        tx: &Sender<Message>,
+    ) -> (Price, bitcoin::Amount) {
+        let btc_price = match self.prices.get_at(self.timestamp) {
+            Ok(price) => price,
+            Err(e) => {
+                debug!("Not checking contract {}: {:#}", c.label(), e);
+                return (Price::ZERO, bitcoin::Amount::ZERO);
+            }
+        };
+        let now = self.timestamp;
+        // Extract option, assuming it matches the relevant parameters
+        // (is an option, hasn't expired, BTC not ETH, etc)
+        let opt = match interesting::extract_option(c, btc_price) {
+            Some(opt) => opt,
+            None => return (Price::ZERO, bitcoin::Amount::ZERO),
+        };
+
+        // Compute the yield threshold below which the absolute return
+        // is too low to be worth logging (though it may be worth acting
+        // on autonomously).
+        let dte = opt.years_to_expiry(now) * 365.0;
+        let nlv = self.net_liquidation_value(btc_price.btc_price);
+        let yield_threshold = |stat: &BidStats| {
+            let collateral =
+                stat.lockup_usd() + btc_price.btc_price * Quantity::from(stat.lockup_btc());
+            self.yield_threshold.threshold(dte, nlv, collateral)
+        };
+
+        // Iterate through all open bids.
+        let mut available_usd = self.available_usd;
+        let mut available_btc = self.available_btc;
+        let portfolio = self.portfolio();
+
+        let mut best_bid =
+            match BidStats::from_order(btc_price, c, Price::ZERO, Quantity::Zero, self.fee) {
+                Some(stat) => stat,
+                None => return (Price::ZERO, bitcoin::Amount::ZERO),
+            };
+        let mut acc = best_bid;
+        let mut acc_current_funds = best_bid;
+
+        let mut asks_to_make = vec![];
+
+        for bid in book.bids() {
+            let mut stat = match BidStats::from_order(btc_price, c, bid.price, bid.size, self.fee) {
+                Some(stat) => stat,
+                None => break,
+            };
+            // Once one order is uninteresting, the rest will be.
+            if stat.interestingness() <= interesting::Interestingness::No {
+                break;
+            }
+
+            // Skip 0-size bids which sometimes show up on LX
+            if bid.size.is_zero() {
+                continue;
+            }
+
+            // Record unadjusted values
+            if best_bid.order_size().is_zero() {
+                best_bid = stat;
+            }
+            acc += stat;
+
+            // Adjust for available funds
+            if available_usd < stat.lockup_usd() || available_btc < stat.lockup_btc() {
+                stat.limit_to_funds(&portfolio, available_usd, available_btc);
+            }
+            available_usd -= stat.lockup_usd();
+            available_btc -= stat.lockup_btc();
+            acc_current_funds += stat;
+
+            if stat.interestingness() >= interesting::Interestingness::Take
+                && stat.order_size().is_positive()
+            {
+                asks_to_make.push(stat.corresponding_ask());
+            }
+
+            // Once we're out of money no point in continuing to loop through bids
+            if available_usd == Price::ZERO || available_btc == bitcoin::Amount::ZERO {
+                break;
+            }
+        }
+
+        // Contracts outside our tenor limits are not traded, but may still be
+        // worth a (quieter) log message.
+        if !self.dte_filter.allows(&opt, now) {
+            if best_bid.order_size().is_positive() && acc.total_value() > yield_threshold(&acc) {
+                debug!(
+                    "Interesting contract {} outside days-to-expiry range {}: best bid {} for {}",
+                    c.label(),
+                    self.dte_filter.range(&opt),
+                    best_bid.order_price(),
+                    best_bid.order_size(),
+                );
+            }
+            return (Price::ZERO, bitcoin::Amount::ZERO);
+        }
+
+        // Once we've looped through the order book, log what we found.
+        let mut ret_usd = Price::ZERO;
+        let mut ret_btc = bitcoin::Amount::ZERO;
+        if best_bid.order_size().is_positive() && acc.total_value() > yield_threshold(&acc) {
+            // Log the non-order-specific contract data.
+            opt.log_option_data(
+                ColorFormat::light_purple("Interesting contract: "),
+                now,
+                btc_price.btc_price,
+            );
+            info!("     Price reference: {}", self.prices);
+            if let Some((ours, oi)) = self.oi_share(c.id()) {
+                let pct = if oi > 0 {
+                    ours.unsigned_abs() as f64 * 100.0 / oi as f64
+                } else {
+                    0.0
+                };
+                info!("     Our share of OI: {}/{} ({:.1}%)", ours, oi, pct);
+            }
+
+            if best_bid.total_value() > yield_threshold(&best_bid) {
+                opt.log_order_data(
+                    "            Best Bid: ",
+                    now,
+                    btc_price.btc_price,
+                    best_bid.order_price(),
+                    Some(best_bid.order_size()),
+                );
+            }
+            if best_bid != acc {
+                opt.log_order_data(
+                    "     Accum. Good Bid: ",
+                    now,
+                    btc_price.btc_price,
+                    acc.order_price(),
+                    Some(acc.order_size()),
+                );
+            }
+            if acc_current_funds != acc {
+                opt.log_order_data(
+                    "With available funds: ",
+                    now,
+                    btc_price.btc_price,
+                    acc_current_funds.order_price(),
+                    Some(acc_current_funds.order_size()),
+                );
+            }
+            for ask in asks_to_make {
+                opt.log_order_data(
+                    ColorFormat::white("     Selling to take: "),
+                    now,
+                    btc_price.btc_price,
+                    ask.order_price(),
+                    Some(ask.order_size()),
+                );
+                let order =
+                    CreateOrder::new_ask(c, ask.order_size(), ask.order_price()).into_taker();
+                tx.send(Message::OpenOrder(order)).unwrap();
+                ret_usd += ask.lockup_usd();
+                ret_btc += ask.lockup_btc();
+            }
+        }
+        (ret_usd, ret_btc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::BitcoinPrice;
+    use std::str::FromStr;
+
+    #[test]
+    fn snapshot_is_consistent() {
+        let now = UtcTime::now();
+        let price = BitcoinPrice::from_current(Price::from_str("60000").unwrap());
+        let prices = PriceBoard::new("test", price, chrono::Duration::seconds(300), 100);
+        let mut tracker = LedgerX::new(
+            prices,
+            25,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        tracker.set_balances(Price::from_str("1000").unwrap(), bitcoin::Amount::ZERO);
+
+        let snapshot = tracker.snapshot(now);
+        tracker.set_balances(Price::ZERO, bitcoin::Amount::ONE_BTC);
+        tracker.set_current_price(
+            "test",
+            BitcoinPrice::from_current(Price::from_str("1").unwrap()),
+        );
+        assert_eq!(snapshot.available_usd, Price::from_str("1000").unwrap());
+        assert_eq!(snapshot.available_btc, bitcoin::Amount::ZERO);
+
+        // Price staleness is judged as of the snapshot time, not the current time
+        assert_eq!(snapshot.prices.get_at(snapshot.timestamp).unwrap(), price);
+        let later = now + chrono::Duration::seconds(600);
+        assert!(tracker.snapshot(later).prices.get_at(later).is_err());
+    }
+}
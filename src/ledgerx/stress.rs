@@ -0,0 +1,146 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Stress Tests
+//!
+//! Estimates the P&L of our open option positions at some horizon, either
+//! under a fixed set of BTC price shocks, or by Monte Carlo simulation.
+//!
+
+use super::collateral::Portfolio;
+use crate::mc;
+use crate::units::{Price, UtcTime};
+use log::info;
+
+/// BTC price moves, in percent, to report P&L for
+const SHOCKS_PCT: [i64; 11] = [-50, -30, -20, -10, -5, 0, 5, 10, 20, 30, 50];
+
+/// Settings for a stress test
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// Number of days from now to measure P&L at
+    pub horizon_days: i64,
+    /// Volatility, drift and simulation parameters
+    pub model: mc::Settings,
+    /// Whether to run a Monte Carlo simulation in addition to the fixed shocks
+    pub monte_carlo: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            horizon_days: 7,
+            model: Default::default(),
+            monte_carlo: false,
+        }
+    }
+}
+
+/// Converts a portfolio into positions measured in BTC
+pub fn positions(portfolio: &Portfolio) -> Vec<mc::Position> {
+    portfolio
+        .positions()
+        .map(|(option, minis)| mc::Position {
+            option,
+            btc: minis as f64 / 100.0,
+        })
+        .collect()
+}
+
+/// The P&L at `horizon` if the BTC price moves by `shock_pct` percent
+pub fn shock_pnl(
+    positions: &[mc::Position],
+    now: UtcTime,
+    btc_price: Price,
+    horizon: UtcTime,
+    vol: f64,
+    shock_pct: i64,
+) -> f64 {
+    let btc_price = btc_price.to_approx_f64();
+    let shocked = btc_price * (1.0 + shock_pct as f64 / 100.0);
+    positions
+        .iter()
+        .map(|pos| {
+            pos.btc
+                * (mc::value(&pos.option, horizon, shocked, vol)
+                    - mc::value(&pos.option, now, btc_price, vol))
+        })
+        .sum()
+}
+
+/// Logs the results of a stress test of the given portfolio
+pub fn run(portfolio: &Portfolio, now: UtcTime, btc_price: Price, settings: &Settings) {
+    let positions = positions(portfolio);
+    if positions.is_empty() {
+        info!("No open option positions.");
+        return;
+    }
+    let horizon = now + chrono::Duration::days(settings.horizon_days);
+    info!(
+        "Stressing {} positions at {} ({} days), BTC {}, vol {:.0}%",
+        positions.len(),
+        horizon.format("%F %H:%M"),
+        settings.horizon_days,
+        btc_price,
+        settings.model.vol * 100.0,
+    );
+    for pos in &positions {
+        info!("    {:>8.2} BTC of {}", pos.btc, pos.option);
+    }
+    for shock in SHOCKS_PCT {
+        info!(
+            "BTC {:>+4}%: P&L {:.2}",
+            shock,
+            shock_pnl(
+                &positions,
+                now,
+                btc_price,
+                horizon,
+                settings.model.vol,
+                shock
+            ),
+        );
+    }
+    if settings.monte_carlo {
+        mc::simulate(&positions, now, btc_price, horizon, &settings.model).log();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::option;
+    use std::str::FromStr;
+
+    #[test]
+    fn short_put_shocks() {
+        let now = UtcTime::now();
+        let mut opt = option::Option::from_str("2030-06-28P40000").unwrap();
+        opt.expiry = now + chrono::Duration::days(3);
+        let mut portfolio = Portfolio::new();
+        portfolio.add(opt, -200);
+        let positions = positions(&portfolio);
+        assert_eq!(positions[0].btc, -2.0);
+
+        let btc = Price::from_str("50000").unwrap();
+        let horizon = now + chrono::Duration::days(7);
+        // Flat: the put expires worthless and we keep the premium
+        let premium = mc::value(&opt, now, 50000.0, 0.6);
+        let flat = shock_pnl(&positions, now, btc, horizon, 0.6, 0);
+        assert!((flat - 2.0 * premium).abs() < 1e-6);
+        // Down 30%: assigned 5000 ITM on 2 BTC
+        let down = shock_pnl(&positions, now, btc, horizon, 0.6, -30);
+        assert!((down - 2.0 * (premium - 5000.0)).abs() < 1e-6);
+    }
+}
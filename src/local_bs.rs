@@ -17,6 +17,14 @@
 //! Stuff that the `black_scholes_rust` crate doesn't do, and which I don't want to
 //! PR upstream since I'm too lazy to write unit tests etc
 //!
+//! Also a batch evaluator, [`Expiry`], for evaluating many strikes of the same
+//! expiry at once. On days with hundreds of listed strikes the per-option
+//! functions spend most of their time recomputing the same discount factors
+//! and square roots, which this computes only once.
+//!
+
+use special::Error as _;
+use std::f64::consts::SQRT_2;
 
 /// Computes the "dual delta" of an option
 ///
@@ -39,9 +47,227 @@ pub fn put_dual_delta(s: f64, k: f64, r: f64, sigma: f64, t: f64) -> f64 {
     call_dual_delta(s, k, r, sigma, t) - 1.0
 }
 
+/// Computes `d1` from the Black-Scholes formula
+fn d1(s: f64, k: f64, r: f64, sigma: f64, t: f64) -> f64 {
+    ((s / k).ln() + (r + 0.5 * sigma * sigma) * t) / (sigma * t.sqrt())
+}
+
+/// The standard normal probability density function
+fn norm_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+/// Computes the gamma of an option (the same for calls and puts)
+///
+/// This is the change in delta per dollar move in the underlying.
+pub fn gamma(s: f64, k: f64, r: f64, sigma: f64, t: f64) -> f64 {
+    let d1 = d1(s, k, r, sigma, t);
+    norm_pdf(d1) / (s * sigma * t.sqrt())
+}
+
+/// Computes the vega of an option (the same for calls and puts)
+///
+/// This is the change in price per unit change in volatility, i.e. you need
+/// to divide by 100 to get the change per vol point.
+pub fn vega(s: f64, k: f64, r: f64, sigma: f64, t: f64) -> f64 {
+    let d1 = d1(s, k, r, sigma, t);
+    s * norm_pdf(d1) * t.sqrt()
+}
+
+/// Computes the vanna of an option (the same for calls and puts)
+///
+/// This is the change in delta per unit change in volatility, or equivalently
+/// the change in vega per dollar move in the underlying.
+pub fn vanna(s: f64, k: f64, r: f64, sigma: f64, t: f64) -> f64 {
+    let d1 = d1(s, k, r, sigma, t);
+    let d2 = d1 - sigma * t.sqrt();
+    -norm_pdf(d1) * d2 / sigma
+}
+
+/// The standard normal cumulative distribution function
+fn norm_cdf(x: f64) -> f64 {
+    (x / SQRT_2).error() * 0.5 + 0.5
+}
+
+/// Black-Scholes values of a single option, as computed by [`Expiry::evaluate`]
+/// or [`Expiry::evaluate_all`]
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Evaluation {
+    /// The price of the option
+    pub price: f64,
+    /// The delta of the option
+    pub delta: f64,
+    /// The dual delta of the option (see [`call_dual_delta`])
+    pub dual_delta: f64,
+}
+
+/// Terms shared by every option of a single expiry, at a given underlying price
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Expiry {
+    s: f64,
+    ln_s: f64,
+    r: f64,
+    t: f64,
+    sqrt_t: f64,
+    discount: f64,
+}
+
+impl Expiry {
+    /// Precomputes the shared terms for an expiry `t` years away
+    pub fn new(s: f64, r: f64, t: f64) -> Self {
+        Expiry {
+            s,
+            ln_s: s.ln(),
+            r,
+            t,
+            sqrt_t: t.sqrt(),
+            discount: (-r * t).exp(),
+        }
+    }
+
+    /// Computes `(d1, d2)` given the log of the strike price
+    ///
+    /// Returns `None` if the option is at expiry (or the volatility is zero),
+    /// in which case these are undefined.
+    fn d1_d2(&self, ln_k: f64, sigma: f64) -> Option<(f64, f64)> {
+        let sqrt_t_sigma = self.sqrt_t * sigma;
+        if sqrt_t_sigma > 0.0 {
+            let d1 = (self.ln_s - ln_k + self.r * self.t) / sqrt_t_sigma + 0.5 * sqrt_t_sigma;
+            Some((d1, d1 - sqrt_t_sigma))
+        } else {
+            None
+        }
+    }
+
+    /// Evaluates a single option with strike `k` and volatility `sigma`
+    pub fn evaluate(&self, is_call: bool, k: f64, sigma: f64) -> Evaluation {
+        let (d1, d2) = match self.d1_d2(k.ln(), sigma) {
+            Some(d) => d,
+            None => {
+                // At expiry the option is worth its intrinsic value
+                let (price, itm) = if is_call {
+                    ((self.s - k).max(0.0), self.s > k)
+                } else {
+                    ((k - self.s).max(0.0), k > self.s)
+                };
+                let delta = match (itm, is_call) {
+                    (false, _) => 0.0,
+                    (true, true) => 1.0,
+                    (true, false) => -1.0,
+                };
+                return Evaluation {
+                    price,
+                    delta,
+                    dual_delta: delta,
+                };
+            }
+        };
+        let (n_d1, n_d2) = (norm_cdf(d1), norm_cdf(d2));
+        if is_call {
+            Evaluation {
+                price: self.s * n_d1 - k * self.discount * n_d2,
+                delta: n_d1,
+                dual_delta: n_d2,
+            }
+        } else {
+            Evaluation {
+                price: k * self.discount * (1.0 - n_d2) - self.s * (1.0 - n_d1),
+                delta: n_d1 - 1.0,
+                dual_delta: n_d2 - 1.0,
+            }
+        }
+    }
+
+    /// Computes the dual delta of an option with strike `k` and volatility `sigma`
+    pub fn dual_delta(&self, is_call: bool, k: f64, sigma: f64) -> f64 {
+        self.evaluate(is_call, k, sigma).dual_delta
+    }
+
+    /// Evaluates a list of `(is_call, strike)` pairs at a common volatility
+    pub fn evaluate_all(&self, options: &[(bool, f64)], sigma: f64) -> Vec<Evaluation> {
+        options
+            .iter()
+            .map(|&(is_call, k)| self.evaluate(is_call, k, sigma))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    fn d1(s: f64, k: f64, discount: f64, sqrt_maturity_sigma: f64) -> f64 {
+    use super::*;
+
+    #[test]
+    fn greeks_match_library() {
+        for &(s, k) in &[(1000.0, 1200.0), (30000.0, 25000.0), (40000.0, 40000.0)] {
+            for &vol in &[0.3, 0.8, 1.5] {
+                for &t in &[0.01, 0.1, 1.0] {
+                    let r = 0.04;
+                    let lib_gamma = black_scholes::call_gamma(s, k, r, vol, t);
+                    let lib_vega = black_scholes::call_vega(s, k, r, vol, t);
+                    let lib_vanna = black_scholes::call_vanna(s, k, r, vol, t);
+                    assert!((gamma(s, k, r, vol, t) - lib_gamma).abs() <= 1.0e-9 * lib_gamma.abs());
+                    assert!((vega(s, k, r, vol, t) - lib_vega).abs() <= 1.0e-9 * lib_vega.abs());
+                    assert!(
+                        (vanna(s, k, r, vol, t) - lib_vanna).abs()
+                            <= 1.0e-9 * lib_vanna.abs().max(1.0e-9)
+                    );
+                    // Puts and calls agree
+                    assert!(
+                        (black_scholes::put_gamma(s, k, r, vol, t) - lib_gamma).abs()
+                            <= 1.0e-9 * lib_gamma.abs()
+                    );
+                    assert!(
+                        (black_scholes::put_vega(s, k, r, vol, t) - lib_vega).abs()
+                            <= 1.0e-9 * lib_vega.abs()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batch_matches_library() {
+        let r = 0.04;
+        for &s in &[25000.0, 40000.0, 61000.0] {
+            for &t in &[0.0, 0.003, 0.1, 1.0] {
+                let expiry = Expiry::new(s, r, t);
+                let strikes: Vec<(bool, f64)> = (0..40)
+                    .flat_map(|n| {
+                        let k = 20000.0 + 1000.0 * n as f64;
+                        vec![(true, k), (false, k)]
+                    })
+                    .collect();
+                for &vol in &[0.3, 0.8, 1.5] {
+                    let evals = expiry.evaluate_all(&strikes, vol);
+                    for (&(is_call, k), eval) in strikes.iter().zip(&evals) {
+                        let (price, delta) = if is_call {
+                            (
+                                black_scholes::call(s, k, r, vol, t),
+                                black_scholes::call_delta(s, k, r, vol, t),
+                            )
+                        } else {
+                            (
+                                black_scholes::put(s, k, r, vol, t),
+                                black_scholes::put_delta(s, k, r, vol, t),
+                            )
+                        };
+                        assert!((eval.price - price).abs() <= 1.0e-6 * s);
+                        assert!((eval.delta - delta).abs() <= 1.0e-9);
+                        if t > 0.0 {
+                            let dual_delta = if is_call {
+                                call_dual_delta(s, k, r, vol, t)
+                            } else {
+                                put_dual_delta(s, k, r, vol, t)
+                            };
+                            assert!((eval.dual_delta - dual_delta).abs() <= 1.0e-9);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn d1_discount(s: f64, k: f64, discount: f64, sqrt_maturity_sigma: f64) -> f64 {
         (s / (k * discount)).ln() / sqrt_maturity_sigma + 0.5 * sqrt_maturity_sigma
     }
 
@@ -61,7 +287,7 @@ mod tests {
         let vanna = black_scholes::call_vanna(s, k, r, vol, maturity);
 
         let est_d1 = -vomma / vanna / s / maturity.sqrt();
-        let act_d1 = d1(s, k, (-r * maturity).exp(), maturity.sqrt() * vol);
+        let act_d1 = d1_discount(s, k, (-r * maturity).exp(), maturity.sqrt() * vol);
         assert!((est_d1 - act_d1).abs() < 1.0e-10);
     }
 
@@ -16,19 +16,62 @@
 //!
 //! Log infrastructure. This uses the traits and macros from the log 0.4 crate.
 //!
-//! Will write INFO and more urgent messages to stdout; will also log everthing
+//! Will write INFO and more urgent messages to stdout (or stderr, if stdout is
+//! being used for the `connect --emit-events` stream); will also log everthing
 //! DEBUG and up to a debug log (with more precise timestamp/severity information),
 //! and also routes LX data feed messages to its own logs.
 //!
 //! Any errors related to writing are simply dropped and the messages won't be
 //! logged. Errors related to initially opening the files should kill the program.
 //!
+//! The high-volume logs (Coinbase, datafeed and HTTP) may optionally be rotated.
+//! Rotated files are gzipped in a background thread, and an index file named
+//! `<log>.index` is kept next to each log, mapping time ranges to the rotated
+//! files and to the live log, whose end time is given as `-`.
+//! Rotation errors are treated like write errors, i.e. dropped.
+//!
 
 use crate::terminal::{set_color_off_thread_local, set_color_on_thread_local};
 use crate::units::UtcTime;
-use std::fs::File;
-use std::io::Write;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
+use std::thread;
+
+/// Number of warnings logged so far
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+/// Number of errors logged so far
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of warnings logged so far, for determining the exit code
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Number of errors logged so far, for determining the exit code
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// Counts a record towards the warning and error totals
+fn count(record: &log::Record) {
+    match record.level() {
+        log::Level::Error => ERRORS.fetch_add(1, Ordering::Relaxed),
+        log::Level::Warn => WARNINGS.fetch_add(1, Ordering::Relaxed),
+        _ => return,
+    };
+}
+
+/// The most verbose level which is logged to stdout
+fn stdout_level(quiet: bool) -> log::Level {
+    if quiet {
+        log::Level::Warn
+    } else {
+        log::Level::Info
+    }
+}
 
 /// Convenience struct for all the filenames that we need
 pub struct LogFilenames {
@@ -36,17 +79,168 @@ pub struct LogFilenames {
     pub debug_log: String,
     pub datafeed_log: String,
     pub http_get_log: String,
+    /// Only set if the Kraken feed is enabled
+    pub kraken_log: Option<String>,
+    /// Only set if the Bitstamp feed is enabled
+    pub bitstamp_log: Option<String>,
+}
+
+/// Policy for rotating the high-volume logs
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RotationPolicy {
+    /// Rotate a log once it exceeds this many bytes
+    pub max_bytes: u64,
+    /// Rotate a log once it has been open for this long
+    pub max_age: chrono::Duration,
+    /// Number of rotated files to keep for each log; older ones are deleted
+    pub retain: usize,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            max_bytes: 256 * 1024 * 1024,
+            max_age: chrono::Duration::days(1),
+            retain: 30,
+        }
+    }
+}
+
+/// An entry in the rotated-log index
+struct IndexEntry {
+    /// Time that the rotated file was opened
+    start: UtcTime,
+    /// Time that the rotated file was closed
+    end: UtcTime,
+    /// Filename of the (compressed) rotated file
+    filename: String,
 }
 
-/// Internal marker structure used to indicate that we only log to stdout
-struct StdoutOnly;
+/// A log file which may be rotated
+struct LogFile {
+    path: String,
+    file: File,
+    policy: Option<RotationPolicy>,
+    opened_at: UtcTime,
+    bytes_written: u64,
+    n_rotations: usize,
+    rotated: VecDeque<IndexEntry>,
+}
+
+impl LogFile {
+    /// Creates a new log file, truncating any existing one
+    ///
+    /// If the log is to be rotated, also creates its index.
+    fn create(path: &str, policy: Option<RotationPolicy>) -> io::Result<Self> {
+        let ret = LogFile {
+            path: path.to_owned(),
+            file: File::create(path)?,
+            policy,
+            opened_at: UtcTime::now(),
+            bytes_written: 0,
+            n_rotations: 0,
+            rotated: VecDeque::new(),
+        };
+        if policy.is_some() {
+            ret.write_index()?;
+        }
+        Ok(ret)
+    }
+
+    /// Checks whether the file is due for rotation, and if so, rotates it
+    fn maybe_rotate(&mut self) {
+        if let Some(policy) = self.policy {
+            let now = UtcTime::now();
+            if self.bytes_written >= policy.max_bytes || now - self.opened_at >= policy.max_age {
+                let _ = self.rotate(now, policy);
+            }
+        }
+    }
+
+    /// Moves the current log aside, starts a new one, and compresses the old one
+    fn rotate(&mut self, now: UtcTime, policy: RotationPolicy) -> io::Result<()> {
+        let rotated_name = format!("{}.{:04}", self.path, self.n_rotations);
+        self.file.flush()?;
+        fs::rename(&self.path, &rotated_name)?;
+        self.file = File::create(&self.path)?;
+        self.rotated.push_back(IndexEntry {
+            start: self.opened_at,
+            end: now,
+            filename: format!("{rotated_name}.gz"),
+        });
+        self.opened_at = now;
+        self.bytes_written = 0;
+        self.n_rotations += 1;
+
+        thread::spawn(move || {
+            let _ = compress_file(&rotated_name);
+        });
+
+        while self.rotated.len() > policy.retain {
+            let old = self.rotated.pop_front().unwrap();
+            let _ = fs::remove_file(&old.filename);
+            // In case compression never completed
+            let _ = fs::remove_file(old.filename.trim_end_matches(".gz"));
+        }
+        self.write_index()
+    }
+
+    /// (Re)writes the index file for this log, ending with the live log
+    fn write_index(&self) -> io::Result<()> {
+        let mut index = File::create(format!("{}.index", self.path))?;
+        for entry in &self.rotated {
+            writeln!(
+                index,
+                "{}\t{}\t{}",
+                entry.start.format("%F %T%z"),
+                entry.end.format("%F %T%z"),
+                entry.filename,
+            )?;
+        }
+        writeln!(
+            index,
+            "{}\t-\t{}",
+            self.opened_at.format("%F %T%z"),
+            self.path,
+        )
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Gzips a file, deleting the original on success
+fn compress_file(path: &str) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let output = File::create(format!("{path}.gz"))?;
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+/// Internal structure used to indicate that we only log to stdout
+struct StdoutOnly {
+    /// Whether to suppress info-level messages
+    quiet: bool,
+}
 
 impl log::Log for StdoutOnly {
-    fn enabled(&self, _: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= stdout_level(self.quiet)
     }
 
     fn log(&self, record: &log::Record) {
+        count(record);
         // Unless we have debug logging on, discard datafeed/json messages
         if log::max_level() < log::LevelFilter::Debug && record.target() == "lx_http_get" {
             return;
@@ -63,41 +257,77 @@ impl log::Log for StdoutOnly {
 
 /// Actual logging structure
 pub struct Logger {
+    /// Whether to suppress info-level messages on stdout
+    quiet: bool,
+    /// Whether to write human-readable output to stderr rather than stdout
+    to_stderr: bool,
     /// Most recent time that we logged something to stdout
     last_stdout_time: Mutex<UtcTime>,
     /// Log for general output (excluding json-encoded data)
     ///
     /// Log to dump messages from Coinbase to
-    coinbase_log: Mutex<File>,
+    coinbase_log: Mutex<LogFile>,
     /// Info and greater logs will also be put to stderr
     debug_log: Mutex<File>,
     /// Log to just dump websocket messages to
-    datafeed_log: Mutex<File>,
+    datafeed_log: Mutex<LogFile>,
     /// Log to just dump websocket messages to
-    http_get_log: Mutex<File>,
+    http_get_log: Mutex<LogFile>,
+    /// Log to dump messages from Kraken to, if enabled
+    kraken_log: Mutex<Option<LogFile>>,
+    /// Log to dump messages from Bitstamp to, if enabled
+    bitstamp_log: Mutex<Option<LogFile>>,
     /// Latest Bitcoin price
     price: Mutex<String>,
 }
 
 impl Logger {
     /// Initialize a global logger
-    pub fn init(filenames: &LogFilenames) -> Result<(), anyhow::Error> {
+    ///
+    /// If a rotation policy is given, it is applied to the Coinbase, Kraken,
+    /// Bitstamp, datafeed and HTTP logs. The debug log is never rotated. If `quiet` is set, only
+    /// warnings and errors are logged to stdout, though everything is still
+    /// logged to the files.
+    pub fn init(
+        filenames: &LogFilenames,
+        rotation: Option<RotationPolicy>,
+        quiet: bool,
+        to_stderr: bool,
+    ) -> Result<(), anyhow::Error> {
         log::set_max_level(log::LevelFilter::Debug);
         log::set_boxed_logger(Box::new(Logger {
+            quiet,
+            to_stderr,
             last_stdout_time: Mutex::new(UtcTime::now()),
-            coinbase_log: Mutex::new(File::create(&filenames.coinbase_log)?),
+            coinbase_log: Mutex::new(LogFile::create(&filenames.coinbase_log, rotation)?),
             debug_log: Mutex::new(File::create(&filenames.debug_log)?),
-            datafeed_log: Mutex::new(File::create(&filenames.datafeed_log)?),
-            http_get_log: Mutex::new(File::create(&filenames.http_get_log)?),
+            datafeed_log: Mutex::new(LogFile::create(&filenames.datafeed_log, rotation)?),
+            http_get_log: Mutex::new(LogFile::create(&filenames.http_get_log, rotation)?),
+            kraken_log: Mutex::new(
+                filenames
+                    .kraken_log
+                    .as_ref()
+                    .map(|name| LogFile::create(name, rotation))
+                    .transpose()?,
+            ),
+            bitstamp_log: Mutex::new(
+                filenames
+                    .bitstamp_log
+                    .as_ref()
+                    .map(|name| LogFile::create(name, rotation))
+                    .transpose()?,
+            ),
             price: Mutex::new("".into()),
         }))
         .map_err(From::from)
     }
 
     /// Initialize a global logger (without extra files)
-    pub fn init_stdout_only() -> Result<(), log::SetLoggerError> {
+    ///
+    /// If `quiet` is set, only warnings and errors are logged.
+    pub fn init_stdout_only(quiet: bool) -> Result<(), log::SetLoggerError> {
         log::set_max_level(log::LevelFilter::Info);
-        log::set_logger(&StdoutOnly)
+        log::set_boxed_logger(Box::new(StdoutOnly { quiet }))
     }
 }
 
@@ -108,23 +338,42 @@ impl log::Log for Logger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
+            count(record);
             if record.target() == "lx_http_get" {
                 // HTTP messages get their own log, but we do add timestamps etc to them
+                let mut lock = self.http_get_log.lock().unwrap();
                 let _ = writeln!(
-                    self.http_get_log.lock().unwrap(),
+                    lock,
                     "[{}] [{}] {}",
                     UtcTime::now(),
                     record.level(),
                     record.args()
                 );
+                lock.maybe_rotate();
             } else if record.target() == "cb_datafeed" {
                 // Messages targeted for the Coinbase go to the Coinbase log with no
                 // additional processing (no timestamps etc)
-                let _ = writeln!(self.coinbase_log.lock().unwrap(), "{}", record.args());
+                let mut lock = self.coinbase_log.lock().unwrap();
+                let _ = writeln!(lock, "{}", record.args());
+                lock.maybe_rotate();
+            } else if record.target() == "kr_datafeed" {
+                // Likewise messages from Kraken go to the Kraken log
+                if let Some(ref mut log) = *self.kraken_log.lock().unwrap() {
+                    let _ = writeln!(log, "{}", record.args());
+                    log.maybe_rotate();
+                }
+            } else if record.target() == "bs_datafeed" {
+                // Likewise messages from Bitstamp go to the Bitstamp log
+                if let Some(ref mut log) = *self.bitstamp_log.lock().unwrap() {
+                    let _ = writeln!(log, "{}", record.args());
+                    log.maybe_rotate();
+                }
             } else if record.target() == "lx_datafeed" {
                 // Messages targeted for the datafeed go to the datafeed log with no
                 // additional processing (no timestamps etc)
-                let _ = writeln!(self.datafeed_log.lock().unwrap(), "{}", record.args());
+                let mut lock = self.datafeed_log.lock().unwrap();
+                let _ = writeln!(lock, "{}", record.args());
+                lock.maybe_rotate();
             } else if record.target() == "lx_btcprice" {
                 // TODO maybe we should log the price somewhere as a personal price reference?
                 *self.price.lock().unwrap() = format!("{}", record.args());
@@ -132,18 +381,24 @@ impl log::Log for Logger {
                 let now = UtcTime::now();
 
                 // If it's more important than info, log to stdout
-                if record.level() <= log::Level::Info {
+                if record.level() <= stdout_level(self.quiet) {
                     set_color_on_thread_local();
+                    let mut out: Box<dyn Write> = if self.to_stderr {
+                        Box::new(io::stderr().lock())
+                    } else {
+                        Box::new(io::stdout().lock())
+                    };
                     let mut last_time_lock = self.last_stdout_time.lock().unwrap();
                     if now - *last_time_lock > chrono::Duration::minutes(10) {
-                        println!();
+                        let _ = writeln!(out);
                     }
                     if now - *last_time_lock > chrono::Duration::seconds(30) {
-                        println!();
+                        let _ = writeln!(out);
                     }
                     if now - *last_time_lock > chrono::Duration::seconds(1) {
-                        println!();
-                        println!(
+                        let _ = writeln!(out);
+                        let _ = writeln!(
+                            out,
                             "{}",
                             crate::terminal::ColorFormat::pale_yellow(format_args!(
                                 "Time: {}  BTC Price: {}",
@@ -153,7 +408,7 @@ impl log::Log for Logger {
                         );
                         *last_time_lock = now;
                     }
-                    println!("{}", record.args());
+                    let _ = writeln!(out, "{}", record.args());
                     set_color_off_thread_local();
                 }
                 // Regardless, log to debug log with more precise timestamp and log level
@@ -173,5 +428,43 @@ impl log::Log for Logger {
         let _ = self.debug_log.lock().unwrap().flush();
         let _ = self.datafeed_log.lock().unwrap().flush();
         let _ = self.http_get_log.lock().unwrap().flush();
+        if let Some(ref mut log) = *self.kraken_log.lock().unwrap() {
+            let _ = log.flush();
+        }
+        if let Some(ref mut log) = *self.bitstamp_log.lock().unwrap() {
+            let _ = log.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index() {
+        let dir = std::env::temp_dir().join(format!("tt-logger-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("datafeed.log").to_string_lossy().into_owned();
+        let index = |log: &LogFile| -> Vec<String> {
+            fs::read_to_string(format!("{}.index", log.path))
+                .unwrap()
+                .lines()
+                .map(|line| line.split_once('\t').unwrap().1.to_owned())
+                .collect()
+        };
+
+        // The index lists the live log as soon as it is opened...
+        let policy = RotationPolicy::default();
+        let mut log = LogFile::create(&path, Some(policy)).unwrap();
+        assert_eq!(index(&log), [format!("-\t{path}")]);
+        // ...and after each rotation, after the rotated files
+        log.rotate(UtcTime::now(), policy).unwrap();
+        let lines = index(&log);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!("\t{path}.0000.gz")));
+        assert_eq!(lines[1], format!("-\t{path}"));
+
+        let _ = fs::remove_dir_all(&dir);
     }
 }
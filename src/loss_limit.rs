@@ -0,0 +1,192 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Daily Loss Limit
+//!
+//! The emergency shutdown only reacts to jumps in the price feeds; it has no
+//! idea whether we are actually losing money. This module watches the
+//! account's equity instead. On each heartbeat during market hours, the main
+//! loop marks the account (balances, collateral locked by our positions, and
+//! the model value of the options themselves) and we compare it to the first
+//! mark of the session. Since fills and assignments land in the balances, the
+//! difference covers realized as well as mark-to-market P&L. (So do any
+//! deposits or withdrawals, which we do not try to separate out; a large
+//! withdrawal mid-session will trip the limit.) Without a fresh consensus BTC
+//! price, or while we hold contracts on another underlying, there is no mark.
+//!
+//! If the loss exceeds the configured maximum, the limit trips: we stop
+//! quoting and cancel our resting orders until the next session, which starts
+//! at the following market open. Restarting the program also starts a new
+//! session, so a restart is how to resume trading on the same day. Orders
+//! which only reduce our positions, such as closes and buy-backs, are still
+//! allowed through.
+//!
+
+use crate::units::{Price, UtcTime};
+use std::fmt;
+
+/// A breach of the daily loss limit
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Breach {
+    /// Time at which the limit tripped
+    pub time: UtcTime,
+    /// Account equity at the start of the session
+    pub start_equity: Price,
+    /// P&L since the start of the session (negative for a loss)
+    pub pnl: Price,
+    /// The maximum loss we were willing to take
+    pub max_loss: Price,
+}
+
+impl fmt::Display for Breach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "P&L {} since session start (equity {}) exceeds daily loss limit of {}",
+            self.pnl, self.start_equity, self.max_loss,
+        )
+    }
+}
+
+/// Tracks the session's P&L against the daily loss limit
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LossLimit {
+    /// The maximum loss to take in a session; if `None`, the limit never trips
+    max_loss: Option<Price>,
+    /// Time and account equity of the first mark of the session
+    start: Option<(UtcTime, Price)>,
+    /// Account equity of the most recent mark
+    last_equity: Option<Price>,
+    /// If the limit has tripped this session, the breach that tripped it
+    breach: Option<Breach>,
+}
+
+impl LossLimit {
+    /// Creates a new loss limit, starting a new session
+    pub fn new(max_loss: Option<Price>) -> Self {
+        LossLimit {
+            max_loss,
+            start: None,
+            last_equity: None,
+            breach: None,
+        }
+    }
+
+    /// Starts a new session, forgetting any breach of the previous one
+    pub fn reset(&mut self) {
+        *self = LossLimit::new(self.max_loss);
+    }
+
+    /// Records a mark of the account's equity
+    ///
+    /// The first mark of a session becomes its starting point. Returns the
+    /// breach if this mark tripped the limit; once tripped, later marks
+    /// return `None` until the session is reset.
+    pub fn record(&mut self, now: UtcTime, equity: Price) -> Option<Breach> {
+        let (_, start_equity) = *self.start.get_or_insert((now, equity));
+        self.last_equity = Some(equity);
+        let max_loss = self.max_loss?;
+        if self.breach.is_some() {
+            return None;
+        }
+        let pnl = equity - start_equity;
+        if -pnl > max_loss {
+            self.breach = Some(Breach {
+                time: now,
+                start_equity,
+                pnl,
+                max_loss,
+            });
+            self.breach
+        } else {
+            None
+        }
+    }
+
+    /// P&L since the start of the session, as of the most recent mark
+    pub fn pnl(&self) -> Option<Price> {
+        let (_, start_equity) = self.start?;
+        Some(self.last_equity? - start_equity)
+    }
+
+    /// The breach that tripped the limit this session, if any
+    pub fn breach(&self) -> Option<&Breach> {
+        self.breach.as_ref()
+    }
+
+    /// Whether the limit has tripped this session
+    pub fn is_tripped(&self) -> bool {
+        self.breach.is_some()
+    }
+}
+
+impl fmt::Display for LossLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.pnl(), self.start) {
+            (Some(pnl), Some((since, _))) => write!(f, "P&L {} since {}", pnl, since)?,
+            _ => f.write_str("no marks this session")?,
+        }
+        match self.max_loss {
+            Some(max_loss) => write!(f, " (limit {max_loss})"),
+            None => f.write_str(" (no limit)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn trip_and_reset() {
+        let p = |s| Price::from_str(s).unwrap();
+        let time = |s: &str| UtcTime::parse_coinbase(&format!("2024-03-01T{s}Z")).unwrap();
+
+        let mut limit = LossLimit::new(Some(p("1000")));
+        assert_eq!(limit.pnl(), None);
+        assert_eq!(limit.record(time("15:00:00"), p("50000")), None);
+        assert_eq!(limit.pnl(), Some(Price::ZERO));
+        // Gains and losses within the limit are fine
+        assert_eq!(limit.record(time("15:30:00"), p("51500")), None);
+        assert_eq!(limit.record(time("16:00:00"), p("49000")), None);
+        assert_eq!(limit.pnl(), Some(p("-1000")));
+        assert!(!limit.is_tripped());
+
+        // Exceeding it trips, once
+        let breach = limit.record(time("16:30:00"), p("48999")).unwrap();
+        assert_eq!(breach.pnl, p("-1001"));
+        assert_eq!(breach.start_equity, p("50000"));
+        assert!(limit.is_tripped());
+        assert_eq!(limit.record(time("17:00:00"), p("40000")), None);
+        assert_eq!(limit.breach(), Some(&breach));
+        // ...and recovering does not untrip it
+        assert_eq!(limit.record(time("17:30:00"), p("50000")), None);
+        assert!(limit.is_tripped());
+
+        // A new session starts from its own first mark
+        limit.reset();
+        assert!(!limit.is_tripped());
+        assert_eq!(limit.record(time("18:00:00"), p("45000")), None);
+        assert_eq!(limit.record(time("18:30:00"), p("44500")), None);
+        assert_eq!(limit.pnl(), Some(p("-500")));
+
+        // Without a limit, we only track P&L
+        let mut unlimited = LossLimit::new(None);
+        unlimited.record(time("15:00:00"), p("50000"));
+        assert_eq!(unlimited.record(time("16:00:00"), p("1000")), None);
+        assert_eq!(unlimited.pnl(), Some(p("-49000")));
+        assert!(!unlimited.is_tripped());
+    }
+}
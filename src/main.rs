@@ -19,36 +19,79 @@
 
 #![allow(clippy::manual_range_contains)] // this lint is bullshit
 
+pub mod activity;
+pub mod audit;
+pub mod bitstamp;
+pub mod bs_cache;
+pub mod bundle;
 pub mod cli;
+pub mod clock;
 pub mod coinbase;
 pub mod connect;
 pub mod csv;
+pub mod emergency;
+pub mod events;
 pub mod file;
 pub mod http;
+pub mod kraken;
 pub mod ledgerx;
 pub mod local_bs;
 pub mod logger;
+pub mod loss_limit;
+pub mod mc;
 pub mod option;
 pub mod price;
+pub mod queue;
+pub mod repl;
+pub mod replay;
+pub mod resimulation;
+pub mod schema;
+pub mod serve;
+pub mod storage;
+pub mod supervisor;
 pub mod terminal;
 pub mod timemap;
+pub mod trading_window;
 pub mod transaction;
 pub mod units;
+pub mod websocket;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
 
 use crate::cli::Command;
+use crate::ledgerx::contract_cache::ContractCache;
 pub use crate::timemap::TimeMap;
-use crate::units::UtcTime;
+use crate::units::{Price, Quantity, UtcTime};
 use anyhow::Context;
 use bitcoin::hashes::{sha256, Hash};
 use chrono::offset::Utc;
 use chrono::Datelike as _;
-use log::{info, warn};
+use log::{error, info, warn};
+use std::path::Path;
 use std::{fs, io, str::FromStr};
 
 use price::Historic;
 
 /// Don't bother loading historical price data from before this date
 const TAX_PRICE_MIN_YEAR: &str = "2021";
+/// Name of the contract cache file, within the data directory
+const CONTRACT_CACHE_FILE: &str = "contracts.json";
+/// Name of the daily activity summary file, within the data directory
+const ACTIVITY_FILE: &str = "daily-activity.csv";
+/// Name of the file recording our fills, within the data directory
+const FILL_FILE: &str = "fills.csv";
+/// Name of the file of JSON decision and fill records, within the data directory
+const RECORD_FILE: &str = "records.ndjson";
+/// Name of the file listing scheduled USD deposits, within the data directory
+const DEPOSITS_FILE: &str = "scheduled-deposits.csv";
+/// Name of the file recording our BTC reacquisition goal, within the data directory
+const GOAL_FILE: &str = "goal.json";
+/// Name of the file recording that orders may have been left live, within the data directory
+const LIVE_ORDERS_FILE: &str = "live-orders";
+/// Name of the audit log of our mutating actions, within the data directory
+const AUDIT_FILE: &str = "audit.ndjson";
+/// Age beyond which a recorded price is too stale to trade on
+const MAX_RECORDED_PRICE_AGE_MINS: i64 = 60;
 
 /// Mode indicating how much/what data to output from the tax-history command
 pub enum TaxHistoryMode {
@@ -83,11 +126,31 @@ fn newline() {
 fn initialize_logging(
     now: UtcTime,
     command: &Command,
+    quiet: bool,
 ) -> Result<Option<logger::LogFilenames>, anyhow::Error> {
+    // If the event stream is going to stdout, keep it free of anything else
+    let events_to_stdout = match command {
+        Command::Connect { settings, .. } => {
+            settings.emit_events == Some(events::Destination::Stdout)
+        }
+        _ => false,
+    };
     let ret = match command {
         // Commands that interact with the LX API should have full logging, including
         // debug logs and sending all json replies to log files.
-        Command::Connect { .. } | Command::History { .. } | Command::TaxHistory { .. } => {
+        Command::Connect { .. }
+        | Command::History { .. }
+        | Command::TaxHistory { .. }
+        | Command::Lots { .. }
+        | Command::CompareTaxStrategies { .. }
+        | Command::Performance { .. }
+        | Command::Quote { .. }
+        | Command::Close { .. }
+        | Command::Watch { .. }
+        | Command::Serve { .. }
+        | Command::Repl {
+            account: Some(..), ..
+        } => {
             let log_dir = format!("{}/log", env!("CARGO_MANIFEST_DIR"));
             if let Ok(metadata) = std::fs::metadata(&log_dir) {
                 if !metadata.is_dir() {
@@ -102,37 +165,120 @@ fn initialize_logging(
 
             let log_name = command.log_name();
             let log_time = now.format("%F_%H-%M-%S");
+            // The optional price feeds only get logs if they are enabled
+            let (kraken, bitstamp) = match command {
+                Command::Connect { settings, .. } => (settings.kraken, settings.bitstamp),
+                _ => (false, false),
+            };
             let filenames = logger::LogFilenames {
                 coinbase_log: format!("{log_dir}/{log_name}-coinbase_{log_time}.log"),
                 debug_log: format!("{log_dir}/{log_name}-debug_{log_time}.log"),
                 datafeed_log: format!("{log_dir}/{log_name}-datafeed_{log_time}.log"),
                 http_get_log: format!("{log_dir}/{log_name}-http_{log_time}.log"),
+                bitstamp_log: bitstamp
+                    .then(|| format!("{log_dir}/{log_name}-bitstamp_{log_time}.log")),
+                kraken_log: kraken.then(|| format!("{log_dir}/{log_name}-kraken_{log_time}.log")),
             };
-            logger::Logger::init(&filenames).with_context(|| {
-                format!(
-                    "initializing logger (datafeed_log {}, debug log {}, http_get_log {})",
-                    filenames.datafeed_log, filenames.debug_log, filenames.http_get_log,
-                )
-            })?;
+            // Only long-running sessions rotate their logs; the history commands
+            // copy their logs into the output directory, so must keep them whole.
+            let rotation = match command {
+                Command::Connect { log_rotation, .. } => Some(*log_rotation),
+                _ => None,
+            };
+            logger::Logger::init(&filenames, rotation, quiet, events_to_stdout).with_context(
+                || {
+                    format!(
+                        "initializing logger (datafeed_log {}, debug log {}, http_get_log {})",
+                        filenames.datafeed_log, filenames.debug_log, filenames.http_get_log,
+                    )
+                },
+            )?;
             Some(filenames)
         }
         // "One-off" commands just dump everything to stdout
         Command::InitializePriceData { .. }
         | Command::UpdatePriceData { .. }
-        | Command::LatestPrice {}
+        | Command::PriceDataInfo {}
+        | Command::LatestPrice { .. }
         | Command::Price { .. }
-        | Command::Iv { .. } => {
-            logger::Logger::init_stdout_only().context("initializing stdout logger")?;
+        | Command::Iv { .. }
+        | Command::Ladder { .. }
+        | Command::FundingPlan { .. }
+        | Command::Stress { .. }
+        | Command::PinRisk { .. }
+        | Command::Slippage { .. }
+        | Command::AuditVerify { .. }
+        | Command::DiffTaxRuns { .. }
+        | Command::InitConfig { .. }
+        | Command::Repl { account: None } => {
+            logger::Logger::init_stdout_only(quiet).context("initializing stdout logger")?;
             None
         }
     };
 
     info!("Trade tracker version {}", env!("CARGO_PKG_VERSION"));
     info!("Price data pulled from http://api.bitcoincharts.com/v1/trades.csv?symbol=bitstampUSD -- call `update-price-data` to update");
-    newline();
+    if !quiet && !events_to_stdout {
+        newline();
+    }
     Ok(ret)
 }
 
+/// Reads the busts of our fills recorded by `connect`, so that the history
+/// can leave the busted trades out
+fn recorded_busts(path: &Path) -> anyhow::Result<Vec<ledgerx::history::BustedTrade>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let records = schema::Record::read_type(path, "bust")?;
+    ledgerx::history::BustedTrade::from_records(&records)
+        .with_context(|| format!("reading busts from {}", path.display()))
+}
+
+/// Outputs a table of option prices over a grid of BTC prices (rows) and IVs (columns)
+///
+/// BTC prices range over +/- 20% of the current price in 5% steps, and IVs over
+/// +/- 20 vol points of the given volatility in 5 point steps.
+fn log_sensitivity_table(option: &option::Option, now: UtcTime, btc_price: Price, vol: f64) {
+    let vols: Vec<f64> = (-4..=4)
+        .map(|n| vol + 0.05 * f64::from(n))
+        .filter(|v| *v > 0.0)
+        .collect();
+
+    let mut header = String::from("     BTC \\ IV");
+    for v in &vols {
+        header.push_str(&format!(" {:9.2}", v * 100.0));
+    }
+    info!("{}", header);
+    for n in -4..=4 {
+        let btc = btc_price.scale_approx(1.0 + 0.05 * f64::from(n));
+        let mut row = format!(
+            "{}{:11.2}",
+            if n == 0 { "→" } else { " " },
+            btc.to_approx_f64()
+        );
+        for v in &vols {
+            row.push_str(&format!(" {:9.2}", option.bs_price(now, btc, *v)));
+        }
+        info!("{}", row);
+    }
+}
+
+/// Fetches all of our open positions from LX
+fn fetch_portfolio(api_key: &str) -> anyhow::Result<ledgerx::collateral::Portfolio> {
+    let mut portfolio = ledgerx::collateral::Portfolio::new();
+    let mut next_url = Some("https://api.ledgerx.com/trading/positions?limit=200".to_string());
+    while let Some(url) = next_url {
+        let positions: ledgerx::history::Positions =
+            http::get_json(&url, Some(api_key)).context("looking up current positions")?;
+        for (contract, size) in positions.open_contracts() {
+            portfolio.add_contract(contract, size);
+        }
+        next_url = positions.next_url();
+    }
+    Ok(portfolio)
+}
+
 fn parse_config_file(
     config_file: &std::path::Path,
 ) -> Result<(sha256::Hash, ledgerx::history::Configuration), anyhow::Error> {
@@ -154,44 +300,210 @@ fn parse_config_file(
     Ok((sha256::Hash::from_engine(hash_eng), config))
 }
 
-fn main() -> Result<(), anyhow::Error> {
+/// Formats an error and its chain of causes as a JSON object
+fn json_error(e: &anyhow::Error) -> serde_json::Value {
+    serde_json::json!({
+        "error": e.to_string(),
+        "causes": e.chain().skip(1).map(ToString::to_string).collect::<Vec<_>>(),
+        "exit_code": cli::EXIT_ERROR,
+    })
+}
+
+fn main() -> std::process::ExitCode {
     // Parse command-line args
-    let command = Command::from_args();
+    let (command, options) = Command::from_args();
+    let code = match run(command, options) {
+        Ok(()) if logger::error_count() > 0 => cli::EXIT_ERROR,
+        Ok(()) if logger::warning_count() > 0 => cli::EXIT_WARNINGS,
+        Ok(()) => cli::EXIT_OK,
+        Err(e) => {
+            if options.json_errors {
+                eprintln!("{}", json_error(&e));
+            } else {
+                eprintln!("Error: {e:?}");
+            }
+            cli::EXIT_ERROR
+        }
+    };
+    std::process::ExitCode::from(code)
+}
+
+/// Looks up the current BTC price for a command which trades on it
+///
+/// Asks Coinbase, falling back to the latest recorded price only if that is
+/// recent; price history is only as fresh as the last `update-price-data`.
+fn live_price(history: &Historic, now: UtcTime) -> anyhow::Result<price::BitcoinPrice> {
+    let e = match coinbase::fetch_price() {
+        Ok(price) => return Ok(price),
+        Err(e) => e,
+    };
+    let recorded = match history.first_last() {
+        Some(_) => history.price_at(now),
+        None => return Err(e.context("no price history to fall back on")),
+    };
+    if now - recorded.timestamp > chrono::Duration::minutes(MAX_RECORDED_PRICE_AGE_MINS) {
+        return Err(e.context(format!(
+            "latest recorded price {recorded} is stale; run update-price-data"
+        )));
+    }
+    warn!("Failed to fetch live price ({:#}); using {}", e, recorded);
+    Ok(recorded)
+}
+
+/// Downloads prices from `url` into `history` and writes it out
+fn update_price_data(
+    history: &mut Historic,
+    url: &str,
+    pricedata_path: &Path,
+) -> anyhow::Result<()> {
+    let data = http::get_bytes(url, None)?;
+    let options = price::CsvOptions {
+        total_bytes: Some(data.len() as u64),
+        ..Default::default()
+    };
+    history
+        .read_csv(&data[..], &options)
+        .with_context(|| format!("decoding CSV data from {url}"))?;
+
+    history.write_out(pricedata_path).with_context(|| {
+        format!(
+            "writing out price history to {}",
+            pricedata_path.to_string_lossy()
+        )
+    })?;
+    // Only recent months were read and rewritten above, so bring any older
+    // files up to date separately
+    let upgraded = price::upgrade_files(pricedata_path).context("upgrading price history")?;
+    if upgraded > 0 {
+        info!(
+            "Upgraded {} price data file(s) to version {}",
+            upgraded,
+            price::PRICE_DATA_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Called when a pricing command finds no price data for the current year
+///
+/// With `--bootstrap`, downloads a recent window of prices from the default
+/// feed, which is enough to price options now but not to compute taxes.
+/// Without, fails with a message saying what to do.
+fn bootstrap_price_data(pricedata_path: &Path, bootstrap: bool) -> anyhow::Result<Historic> {
+    if !bootstrap {
+        return Err(anyhow::Error::msg(format!(
+            "no price data for this year in {}; re-run with --bootstrap to download \
+             recent prices, or run update-price-data",
+            pricedata_path.to_string_lossy(),
+        )));
+    }
+
+    info!(
+        "No recent price data; bootstrapping from {}",
+        cli::DEFAULT_PRICE_FEED_URL
+    );
+    // Keep any older months we have, so that writing out doesn't clobber them
+    let mut history = if pricedata_path.exists() {
+        Historic::read_json(pricedata_path).context("reading price history")?
+    } else {
+        Historic::default()
+    };
+    update_price_data(&mut history, cli::DEFAULT_PRICE_FEED_URL, pricedata_path)?;
+    if history.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "bootstrapping downloaded no prices from {}",
+            cli::DEFAULT_PRICE_FEED_URL
+        )));
+    }
+    info!(
+        "Bootstrapped {} prices. Tax commands need the full history; \
+         load it with initialize-price-data.",
+        history.len()
+    );
+    newline();
+    Ok(history)
+}
+
+fn run(command: Command, options: cli::GlobalOptions) -> Result<(), anyhow::Error> {
     // Get data path
     let mut data_path = dirs::data_dir().context("getting XDG config directory")?;
     data_path.push("trade-tracker");
     data_path.push("pricedata");
+    let pricedata_missing = !data_path.exists();
 
     // Read price data history
     let history = match command {
         // unused when initializing price data, just pick something
         // Also unused for Connect, which uses a real-time ticker feed
-        Command::InitializePriceData { .. } | Command::Connect { .. } => Ok(Historic::default()),
+        // ...or for the config wizard, which doesn't need prices at all
+        // The price data summary reads the files itself
+        Command::InitializePriceData { .. }
+        | Command::PriceDataInfo {}
+        | Command::Connect { .. }
+        | Command::FundingPlan { .. }
+        | Command::Slippage { .. }
+        | Command::AuditVerify { .. }
+        | Command::DiffTaxRuns { .. }
+        | Command::InitConfig { .. } => Ok(Historic::default()),
+        // The simple pricing commands can bootstrap missing data, below
+        _ if pricedata_missing && command.bootstrap().is_some() => Ok(Historic::default()),
         // For tax stuff we have to load historic data going back a bit
-        Command::History { .. } | Command::TaxHistory { .. } => {
-            Historic::read_json_from(&data_path, TAX_PRICE_MIN_YEAR)
+        Command::History { .. }
+        | Command::TaxHistory { .. }
+        | Command::Lots { .. }
+        | Command::CompareTaxStrategies { .. }
+        | Command::Performance { .. }
+        | Command::Serve { .. }
+        | Command::Repl { .. } => Historic::read_json_from(&data_path, TAX_PRICE_MIN_YEAR),
+        // Open shorts may date from last year
+        Command::Watch { .. } => {
+            Historic::read_json_from(&data_path, &(Utc::now().year() - 1).to_string())
         }
         // For most everything else we can just use the current year
         _ => Historic::read_json_from(&data_path, &Utc::now().year().to_string()),
     }
-    .context("reading price history")?;
+    .with_context(|| {
+        if pricedata_missing {
+            format!(
+                "no price history at {}; run update-price-data to fetch recent prices, \
+                 or initialize-price-data with a full CSV for tax commands",
+                data_path.to_string_lossy(),
+            )
+        } else {
+            "reading price history".to_owned()
+        }
+    })?;
 
     data_path.pop(); // "pricedata"
 
     // Turn on logging
     let now = UtcTime::now();
-    let log_filenames = initialize_logging(now, &command).context("initializing logging")?;
+    let log_filenames =
+        initialize_logging(now, &command, options.quiet).context("initializing logging")?;
+
+    // Pricing commands with no prices for this year have nothing to go on
+    let history = match command.bootstrap() {
+        Some(bootstrap) if history.is_empty() => {
+            bootstrap_price_data(&data_path.join("pricedata"), bootstrap)?
+        }
+        _ => history,
+    };
 
     // Go
     match command {
-        Command::InitializePriceData { csv } => {
+        Command::InitializePriceData { csv, range } => {
             let mut history = Historic::default();
             let csv_name = csv.to_string_lossy();
 
             let input =
                 fs::File::open(&csv).with_context(|| format!("opening price data {csv_name}"))?;
+            let options = price::CsvOptions {
+                range,
+                total_bytes: input.metadata().ok().map(|meta| meta.len()),
+            };
+            info!("Importing prices from {} ({})", csv_name, range);
             history
-                .read_csv(input)
+                .read_csv(input, &options)
                 .with_context(|| format!("decoding CSV data from {csv_name}"))?;
 
             history.write_out(&data_path).with_context(|| {
@@ -203,24 +515,33 @@ fn main() -> Result<(), anyhow::Error> {
         }
         Command::UpdatePriceData { url } => {
             let mut history = history; // lol rust
-            let data = http::get_bytes(&url, None)?;
-            history
-                .read_csv(&data[..])
-                .with_context(|| format!("decoding CSV data from {url}"))?;
-
+            update_price_data(&mut history, &url, &data_path.join("pricedata"))?;
+        }
+        Command::PriceDataInfo {} => {
             data_path.push("pricedata");
-            history.write_out(&data_path).with_context(|| {
+            price::DataInfo::read(&data_path)
+                .with_context(|| {
+                    format!("reading price history from {}", data_path.to_string_lossy())
+                })?
+                .log();
+            data_path.pop();
+            let market_data_path = data_path.join("marketdata");
+            ledgerx::market_data::log_info(&market_data_path).with_context(|| {
                 format!(
-                    "writing out price history to {}",
-                    data_path.to_string_lossy()
+                    "reading market data from {}",
+                    market_data_path.to_string_lossy()
                 )
             })?;
-            data_path.pop();
         }
-        Command::LatestPrice {} => {
+        Command::LatestPrice { .. } => {
             info!("{}", history.price_at(now));
         }
-        Command::Price { option, volatility } => {
+        Command::Price {
+            option,
+            volatility,
+            sensitivities,
+            ..
+        } => {
             let yte = option.years_to_expiry(now);
             let current_price = history.price_at(now);
             info!("BTC price: {}", current_price);
@@ -232,6 +553,20 @@ fn main() -> Result<(), anyhow::Error> {
                 1.0 / yte
             );
             newline();
+            if sensitivities {
+                let vol = volatility.unwrap_or(0.5);
+                let btc = current_price.btc_price;
+                info!(
+                    "Gamma: {:.8}/$  Vega: ${:.2}/vol pt  Vanna: {:.4}%/vol pt (at vol {:3.2})",
+                    option.bs_gamma(now, btc, vol),
+                    option.bs_vega(now, btc, vol) / 100.0,
+                    option.bs_vanna(now, btc, vol),
+                    vol,
+                );
+                newline();
+                log_sensitivity_table(&option, now, btc, vol);
+                return Ok(());
+            }
             for vol in 0..51 {
                 let vol = volatility.unwrap_or(0.5) + 0.02 * (vol as f64);
                 info!(
@@ -244,51 +579,127 @@ fn main() -> Result<(), anyhow::Error> {
                 );
             }
         }
-        Command::Iv { option, price } => {
+        Command::Iv { option, price, .. } => {
             let current_price = history.price_at(now);
             info!("BTC price: {}", current_price);
             info!("Risk-free rate: 4% (assumed)");
             option.log_option_data("", now, current_price.btc_price);
             newline();
 
-            let center = match price {
-                Some(price) => price,
-                None => option.bs_price(now, current_price.btc_price, 0.75),
-            };
-            let mut price = center.half();
-            while price - center <= center.half() {
+            option.log_price_ladder(now, current_price.btc_price, price);
+        }
+        Command::Ladder {
+            option,
+            contracts,
+            low,
+            high,
+            step,
+            ..
+        } => {
+            let current_price = history.price_at(now);
+            info!("BTC price: {}", current_price);
+            info!("Risk-free rate: 4% (assumed)");
+            option.log_option_data("", now, current_price.btc_price);
+            newline();
+
+            let rungs = option::order_ladder(low, high, step, contracts);
+            info!(
+                "{} contracts over {} rungs from {} to {}, {:.1}% apart",
+                contracts,
+                rungs.len(),
+                low,
+                high,
+                step * 100.0,
+            );
+            for (price, size) in rungs {
                 option.log_order_data(
-                    if price == center { "→" } else { " " },
+                    " ",
                     now,
                     current_price.btc_price,
                     price,
-                    None,
+                    Some(Quantity::Contracts(size)),
                 );
-                price += center.scale_approx(1.0 / 40.0);
             }
         }
         Command::Connect {
             api_key,
             config_file,
+            mut settings,
+            ..
         } => {
+            // A replay must not mix simulated activity into our real records,
+            // so it only writes to files which were explicitly given
+            if settings.replay_files.is_empty() {
+                if settings.activity_file.is_none() {
+                    settings.activity_file = Some(data_path.join(ACTIVITY_FILE));
+                }
+                if settings.fill_file.is_none() {
+                    settings.fill_file = Some(data_path.join(FILL_FILE));
+                }
+                if settings.record_file.is_none() {
+                    settings.record_file = Some(data_path.join(RECORD_FILE));
+                }
+                if settings.deposits_file.is_none() {
+                    settings.deposits_file = Some(data_path.join(DEPOSITS_FILE));
+                }
+                if settings.goal_file.is_none() {
+                    settings.goal_file = Some(data_path.join(GOAL_FILE));
+                }
+                if settings.emergency.live_orders_file.is_none() {
+                    settings.emergency.live_orders_file = Some(data_path.join(LIVE_ORDERS_FILE));
+                }
+                if settings.price_sample_secs > 0 {
+                    settings.price_data_dir = Some(data_path.join("pricedata"));
+                }
+                if settings.archive_market_data {
+                    settings.market_data_dir = Some(data_path.join("marketdata"));
+                }
+                audit::open(&data_path.join(AUDIT_FILE))?;
+            }
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
             // Parse config file
-            if let Some(config_file) = config_file {
+            if let (Some(config_file), Some(api_key)) = (config_file, &api_key) {
                 let (config_hash, config) = parse_config_file(&config_file)?;
-                let hist = ledgerx::history::History::from_api(&api_key, &config, config_hash)
-                    .context("getting history from LX API")?;
-                connect::main_loop(api_key, Some(hist));
+                audit::record(
+                    "connect",
+                    audit::Action::LoadConfig,
+                    &format!("{} (hash {})", config_file.display(), config_hash),
+                );
+                let hist = ledgerx::history::History::from_api(
+                    api_key,
+                    &config,
+                    config_hash,
+                    &mut contract_cache,
+                    false,
+                    &recorded_busts(
+                        settings
+                            .record_file
+                            .as_deref()
+                            .unwrap_or(&data_path.join(RECORD_FILE)),
+                    )?,
+                )
+                .context("getting history from LX API")?;
+                connect::main_loop(Some(api_key.clone()), Some(hist), *settings, contract_cache)?;
             } else {
-                warn!("No configuration file passed; assuming fresh account/no history.");
-                connect::main_loop(api_key, None);
+                if api_key.is_some() && settings.replay_files.is_empty() {
+                    warn!("No configuration file passed; assuming fresh account/no history.");
+                }
+                connect::main_loop(api_key, None, *settings, contract_cache)?;
             }
         }
         Command::History {
             ref api_key,
             ref config_file,
+            range,
+            lenient,
+            ..
         }
         | Command::TaxHistory {
             ref api_key,
             ref config_file,
+            range,
+            lenient,
+            ..
         } => {
             // Assert we have the log filenames before doing anything complex
             // If this unwrap fails it's a bug.
@@ -296,12 +707,69 @@ fn main() -> Result<(), anyhow::Error> {
             // Parse config file
             let (config_hash, config) = parse_config_file(config_file)?;
             // Query LX to get all historic trade data
-            let hist = ledgerx::history::History::from_api(api_key, &config, config_hash)
-                .context("getting history from LX API")?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            let hist = ledgerx::history::History::from_api(
+                api_key,
+                &config,
+                config_hash,
+                &mut contract_cache,
+                lenient,
+                &recorded_busts(&data_path.join(RECORD_FILE))?,
+            )
+            .context("getting history from LX API")?;
             // ...and output
-            if let Command::History { .. } = command {
-                hist.print_csv(&history);
+            if let Command::History {
+                format,
+                ref price_source,
+                merge_fills,
+                ..
+            } = command
+            {
+                match format {
+                    ledgerx::history::OutputFormat::Csv => match *price_source {
+                        price::PriceSourceKind::Historic => {
+                            hist.print_csv(&history, range, merge_fills)?
+                        }
+                        price::PriceSourceKind::LedgerX => {
+                            hist.print_csv(&hist.lx_price_source(&history), range, merge_fills)?
+                        }
+                        price::PriceSourceKind::Csv(ref path) => {
+                            let source =
+                                price::CsvSource::read(path).context("reading price source")?;
+                            hist.print_csv(&source, range, merge_fills)?
+                        }
+                    },
+                    ledgerx::history::OutputFormat::Json => hist.print_json(range, merge_fills),
+                    ledgerx::history::OutputFormat::Beancount => hist
+                        .print_beancount(&history, range)
+                        .context("exporting history to Beancount")?,
+                }
+            } else if let Command::TaxHistory { check: true, .. } = command {
+                let check = hist.check_tax(&history, range);
+                for warning in &check.warnings {
+                    warn!("{}", warning);
+                }
+                for error in &check.errors {
+                    error!("{}", error);
+                }
+                info!(
+                    "Check complete: {} warnings, {} errors. No files written.",
+                    check.warnings.len(),
+                    check.errors.len(),
+                );
+                if !check.errors.is_empty() {
+                    return Err(anyhow::Error::msg(format!(
+                        "tax-history check found {} errors",
+                        check.errors.len()
+                    )));
+                }
             } else {
+                if config.bundle().is_some() && cfg!(not(feature = "bundle")) {
+                    return Err(anyhow::Error::msg(
+                        "Configuration requests an output bundle, but this build has no \
+                         bundle support; rebuild with `--features bundle`.",
+                    ));
+                }
                 let dir_path = format!("lx_tax_output_{}", now.format("%F-%H%M"));
                 if fs::metadata(&dir_path).is_ok() {
                     return Err(anyhow::Error::msg(format!(
@@ -314,14 +782,225 @@ fn main() -> Result<(), anyhow::Error> {
                 info!("Creating directory {} to hold output.", dir_path);
                 let config_name = config_file.to_string_lossy();
                 file::copy_file(&config_name, &format!("{dir_path}/configuration.json"))?;
-                hist.print_tax_csv(&dir_path, &history)
+                let xlsx = matches!(command, Command::TaxHistory { xlsx: true, .. });
+                hist.print_tax_csv(&dir_path, &history, range, xlsx)
                     .context("printing tax CSV")?;
-                file::copy_file(&log_filenames.debug_log, &format!("{dir_path}/debug.log"))?;
-                file::copy_file(
-                    &log_filenames.http_get_log,
-                    &format!("{dir_path}/http_get.log"),
-                )?;
+                let logs = [
+                    (&log_filenames.debug_log, format!("{dir_path}/debug.log")),
+                    (
+                        &log_filenames.http_get_log,
+                        format!("{dir_path}/http_get.log"),
+                    ),
+                ];
+                if let Some(bundle_settings) = config.bundle() {
+                    let redactor = bundle::Redactor::new(
+                        vec![api_key.clone()],
+                        vec![config.user],
+                        bundle_settings.redact_addresses,
+                    );
+                    for (src, dest) in &logs {
+                        bundle::copy_redacted(src, dest, &redactor)?;
+                    }
+                    let archive = bundle::archive(&dir_path, bundle_settings)
+                        .context("creating encrypted output bundle")?;
+                    info!("Wrote encrypted output bundle {}.", archive);
+                } else {
+                    for (src, dest) in &logs {
+                        file::copy_file(src, dest)?;
+                    }
+                }
+            }
+        }
+        Command::Lots {
+            ref api_key,
+            ref config_file,
+            rates,
+        } => {
+            let (config_hash, config) = parse_config_file(config_file)?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            let hist = ledgerx::history::History::from_api(
+                api_key,
+                &config,
+                config_hash,
+                &mut contract_cache,
+                false,
+                &recorded_busts(&data_path.join(RECORD_FILE))?,
+            )
+            .context("getting history from LX API")?;
+            let current_price = history.price_at(now);
+            info!("BTC price: {}", current_price);
+            hist.print_open_lots(&history, current_price.btc_price, rates)
+                .context("listing open lots")?;
+        }
+        Command::CompareTaxStrategies {
+            ref api_key,
+            ref config_file,
+            year,
+            rates,
+        } => {
+            let (config_hash, config) = parse_config_file(config_file)?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            let hist = ledgerx::history::History::from_api(
+                api_key,
+                &config,
+                config_hash,
+                &mut contract_cache,
+                false,
+                &recorded_busts(&data_path.join(RECORD_FILE))?,
+            )
+            .context("getting history from LX API")?;
+            hist.print_strategy_comparison(&history, year, rates)
+                .with_context(|| format!("comparing lot selection strategies for {year}"))?;
+        }
+        Command::Performance {
+            ref api_key,
+            ref config_file,
+            range,
+            lenient,
+            iv,
+        } => {
+            let (config_hash, config) = parse_config_file(config_file)?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            let hist = ledgerx::history::History::from_api(
+                api_key,
+                &config,
+                config_hash,
+                &mut contract_cache,
+                lenient,
+                &recorded_busts(&data_path.join(RECORD_FILE))?,
+            )
+            .context("getting history from LX API")?;
+            hist.print_performance(&history, range, iv)
+                .context("computing account performance")?;
+        }
+        Command::FundingPlan {
+            api_key,
+            deposits_file,
+        } => {
+            let deposits_file = deposits_file.unwrap_or_else(|| data_path.join(DEPOSITS_FILE));
+            let deposits = ledgerx::funding::read_deposits(&deposits_file)?;
+
+            let portfolio = fetch_portfolio(&api_key)?;
+            let balances: ledgerx::json::GetBalancesResponse = http::get_json_from_data_field(
+                "https://api.ledgerx.com/funds/balances",
+                Some(&api_key),
+            )
+            .context("looking up current balances")?;
+            let usd_balance = balances.usd.available_balance + balances.usd.position_locked;
+
+            info!(
+                "USD balance (including put collateral): {}; {} scheduled deposit(s)",
+                usd_balance,
+                deposits.len()
+            );
+            let plans = ledgerx::funding::plan(&portfolio, usd_balance, &deposits);
+            ledgerx::funding::log_plan(&plans, now);
+        }
+        Command::Stress { api_key, settings } => {
+            let portfolio = fetch_portfolio(&api_key)?;
+            let current_price = live_price(&history, now)?;
+            ledgerx::stress::run(&portfolio, now, current_price.btc_price, &settings);
+        }
+        Command::PinRisk { api_key, max_days } => {
+            let portfolio = fetch_portfolio(&api_key)?;
+            let current_price = live_price(&history, now)?;
+            let report = ledgerx::pin_risk::Report::new(&portfolio, now, current_price.btc_price);
+            if report.expiries.is_empty() {
+                info!("No open short positions.");
             }
+            report.log(now, max_days);
+        }
+        Command::Quote {
+            api_key,
+            request,
+            yes,
+        } => {
+            audit::open(&data_path.join(AUDIT_FILE))?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            ledgerx::quote::run(
+                &api_key,
+                &request,
+                live_price(&history, now)?,
+                &mut contract_cache,
+                yes,
+            )?;
+        }
+        Command::Close {
+            api_key,
+            request,
+            yes,
+        } => {
+            audit::open(&data_path.join(AUDIT_FILE))?;
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            ledgerx::close::run(
+                &api_key,
+                request,
+                live_price(&history, now)?,
+                &mut contract_cache,
+                yes,
+                &data_path.join(RECORD_FILE),
+                &data_path.join(FILL_FILE),
+            )?;
+        }
+        Command::Watch {
+            api_key,
+            contract_id,
+        } => {
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            ledgerx::moneyness::run(&api_key, contract_id, &history, &mut contract_cache)?;
+        }
+        Command::Slippage { file } => {
+            let file = file.unwrap_or_else(|| data_path.join(FILL_FILE));
+            let fills = ledgerx::slippage::read_fills(&file)?;
+            ledgerx::slippage::log_report(&fills);
+        }
+        Command::AuditVerify { file } => {
+            let file = file.unwrap_or_else(|| data_path.join(AUDIT_FILE));
+            let summary = audit::verify(&file)?;
+            info!("Audit log {}: {}", file.display(), summary);
+        }
+        Command::DiffTaxRuns { old, new } => {
+            ledgerx::history::diff::run(&old, &new).context("comparing tax runs")?;
+        }
+        Command::InitConfig { output } => {
+            ledgerx::history::wizard::run(&output.to_string_lossy())
+                .context("running configuration wizard")?;
+        }
+        Command::Repl { account } => {
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            let hist = match account {
+                Some((ref api_key, ref config_file)) => {
+                    let (config_hash, config) = parse_config_file(config_file)?;
+                    Some(
+                        ledgerx::history::History::from_api(
+                            api_key,
+                            &config,
+                            config_hash,
+                            &mut contract_cache,
+                            true,
+                            &recorded_busts(&data_path.join(RECORD_FILE))?,
+                        )
+                        .context("getting history from LX API")?,
+                    )
+                }
+                None => None,
+            };
+            repl::run(&history, &contract_cache, hist.as_ref())?;
+        }
+        Command::Serve {
+            ref api_key,
+            ref config_file,
+            ref settings,
+        } => {
+            let mut contract_cache = ContractCache::load(data_path.join(CONTRACT_CACHE_FILE));
+            serve::run(
+                api_key,
+                config_file,
+                &history,
+                &mut contract_cache,
+                &recorded_busts(&data_path.join(RECORD_FILE))?,
+                settings,
+            )?;
         }
     }
 
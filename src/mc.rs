@@ -0,0 +1,347 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Monte Carlo
+//!
+//! Simulates BTC price paths as a geometric Brownian motion, to estimate the
+//! distribution of P&L of a portfolio of options at some horizon before their
+//! expiry. Black-Scholes only tells us about prices at expiry; here we reprice
+//! unexpired options with Black-Scholes at the horizon, and settle options
+//! which expire along the way at the price the path reaches at their expiry.
+//!
+//! Paths are generated in antithetic pairs, and spread across threads using
+//! rayon. Each pair has its own RNG seeded from the pair index, so results
+//! are reproducible regardless of the number of threads.
+//!
+
+use crate::option::{self, PutCall};
+use crate::units::{Price, UtcTime};
+use log::info;
+use rand::{rngs::StdRng, SeedableRng as _};
+use rand_distr::{Distribution as _, StandardNormal};
+use rayon::prelude::*;
+
+/// Risk-free rate used when repricing options, matching `option::Option::bs_price`
+const RISK_FREE_RATE: f64 = 0.04;
+/// Percentiles of the P&L distribution to report
+const PERCENTILES: [f64; 5] = [1.0, 5.0, 50.0, 95.0, 99.0];
+
+/// Parameters of the simulation
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// Annualized volatility of BTC, used both for the paths and for repricing
+    pub vol: f64,
+    /// Annualized drift of BTC
+    pub drift: f64,
+    /// Number of paths to simulate (rounded up to an even number)
+    pub paths: usize,
+    /// Number of time steps per day
+    pub steps_per_day: usize,
+    /// Seed for the random number generator
+    pub seed: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            vol: 0.6,
+            drift: 0.0,
+            paths: 100_000,
+            steps_per_day: 4,
+            seed: 0,
+        }
+    }
+}
+
+/// A position in an option
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Position {
+    /// The option
+    pub option: option::Option,
+    /// Signed amount of the underlying, in BTC (negative for shorts)
+    pub btc: f64,
+}
+
+/// The estimated distribution of P&L at the horizon
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    /// Time that the P&L is measured at
+    pub horizon: UtcTime,
+    /// Number of paths simulated
+    pub paths: usize,
+    /// Mean P&L, in dollars
+    pub mean: f64,
+    /// Standard error of the mean, accounting for the antithetic pairing
+    pub std_err: f64,
+    /// P&L at each of `PERCENTILES`
+    pub percentiles: Vec<(f64, f64)>,
+    /// Fraction of paths with a loss
+    pub prob_loss: f64,
+    /// Fraction of paths on which some short option was in the money at some
+    /// point before its expiry or the horizon
+    pub prob_short_itm: f64,
+}
+
+impl Report {
+    /// Logs the report
+    pub fn log(&self) {
+        info!(
+            "Monte Carlo P&L at {} over {} paths: mean {:.2} (std. err. {:.2})",
+            self.horizon.format("%F %H:%M"),
+            self.paths,
+            self.mean,
+            self.std_err,
+        );
+        for (pct, pnl) in &self.percentiles {
+            info!("    {:>4.0}th percentile: {:.2}", pct, pnl);
+        }
+        info!("    Probability of loss: {:.2}%", self.prob_loss * 100.0);
+        info!(
+            "    Probability a short goes ITM: {:.2}%",
+            self.prob_short_itm * 100.0
+        );
+    }
+}
+
+/// The value, in dollars per unit of the underlying, of an option at a given
+/// time and BTC price
+///
+/// After expiry this is the intrinsic value (or zero).
+pub fn value(opt: &option::Option, now: UtcTime, btc_price: f64, vol: f64) -> f64 {
+    let strike = opt.strike.to_approx_f64();
+    let yte = opt.years_to_expiry(now);
+    if yte <= 0.0 {
+        return match opt.pc {
+            PutCall::Call => (btc_price - strike).max(0.0),
+            PutCall::Put => (strike - btc_price).max(0.0),
+        };
+    }
+    match opt.pc {
+        PutCall::Call => black_scholes::call(btc_price, strike, RISK_FREE_RATE, vol, yte),
+        PutCall::Put => black_scholes::put(btc_price, strike, RISK_FREE_RATE, vol, yte),
+    }
+}
+
+/// The P&L of a single path, and whether any short went into the money along it
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct PathResult {
+    pnl: f64,
+    short_itm: bool,
+}
+
+/// Everything about a simulation which is shared between paths
+struct Model<'a> {
+    positions: &'a [Position],
+    settings: &'a Settings,
+    /// Value of each position at the start
+    start_values: Vec<f64>,
+    /// For each position, the step at which it expires, or `None` if it
+    /// outlives the horizon
+    settle_steps: Vec<Option<usize>>,
+    btc_price: f64,
+    horizon: UtcTime,
+    /// Length of a step, in years
+    dt: f64,
+}
+
+impl Model<'_> {
+    /// Runs a single path, given the standard normal draws for each step
+    fn run_path(&self, draws: impl Iterator<Item = f64>) -> PathResult {
+        let vol = self.settings.vol;
+        let drift = (self.settings.drift - vol * vol / 2.0) * self.dt;
+        let diffusion = vol * self.dt.sqrt();
+
+        let mut settled: Vec<Option<f64>> = vec![None; self.positions.len()];
+        let mut short_itm = false;
+        let mut price = self.btc_price;
+        for (step, z) in draws.enumerate() {
+            price *= (drift + diffusion * z).exp();
+            for (n, pos) in self.positions.iter().enumerate() {
+                if settled[n].is_some() {
+                    continue;
+                }
+                if pos.btc < 0.0 && !short_itm {
+                    let strike = pos.option.strike.to_approx_f64();
+                    short_itm = match pos.option.pc {
+                        PutCall::Call => price >= strike,
+                        PutCall::Put => price <= strike,
+                    };
+                }
+                if self.settle_steps[n] == Some(step) {
+                    settled[n] = Some(value(&pos.option, pos.option.expiry, price, vol));
+                }
+            }
+        }
+
+        let mut pnl = 0.0;
+        for (n, pos) in self.positions.iter().enumerate() {
+            let end_value =
+                settled[n].unwrap_or_else(|| value(&pos.option, self.horizon, price, vol));
+            pnl += pos.btc * (end_value - self.start_values[n]);
+        }
+        PathResult { pnl, short_itm }
+    }
+}
+
+/// Simulates the P&L distribution of a portfolio at `horizon`
+///
+/// Positions are valued at the start using Black-Scholes at the configured
+/// volatility, so the P&L reflects only the modeled price movement and time
+/// decay, not any difference between the market price and the model.
+pub fn simulate(
+    positions: &[Position],
+    now: UtcTime,
+    btc_price: Price,
+    horizon: UtcTime,
+    settings: &Settings,
+) -> Report {
+    let btc_price = btc_price.to_approx_f64();
+    let days = ((horizon - now).num_seconds() as f64 / 86400.0).max(0.0);
+    let n_steps = ((days * settings.steps_per_day as f64).ceil() as usize).max(1);
+    let step_secs = (horizon - now).num_seconds().max(0) as f64 / n_steps as f64;
+    let dt = step_secs / (86400.0 * 365.0);
+
+    let model = Model {
+        positions,
+        settings,
+        start_values: positions
+            .iter()
+            .map(|pos| value(&pos.option, now, btc_price, settings.vol))
+            .collect(),
+        // The first step whose end is at or after each expiry
+        settle_steps: positions
+            .iter()
+            .map(|pos| {
+                if pos.option.expiry > horizon {
+                    return None;
+                }
+                let secs = (pos.option.expiry - now).num_seconds().max(0) as f64;
+                let step = (secs / step_secs.max(1.0)).ceil() as usize;
+                Some(step.saturating_sub(1).min(n_steps - 1))
+            })
+            .collect(),
+        btc_price,
+        horizon,
+        dt,
+    };
+
+    let n_pairs = settings.paths.div_ceil(2).max(1);
+    let pairs: Vec<(PathResult, PathResult)> = (0..n_pairs)
+        .into_par_iter()
+        .map(|i| {
+            let mut rng = StdRng::seed_from_u64(settings.seed.wrapping_add(i as u64));
+            let draws: Vec<f64> = (0..n_steps)
+                .map(|_| StandardNormal.sample(&mut rng))
+                .collect();
+            (
+                model.run_path(draws.iter().copied()),
+                model.run_path(draws.iter().map(|z| -z)),
+            )
+        })
+        .collect();
+
+    // Antithetic estimator: the pair averages are independent samples
+    let pair_means: Vec<f64> = pairs.iter().map(|(a, b)| (a.pnl + b.pnl) / 2.0).collect();
+    let mean = pair_means.iter().sum::<f64>() / n_pairs as f64;
+    let var = if n_pairs > 1 {
+        pair_means
+            .iter()
+            .map(|x| (x - mean) * (x - mean))
+            .sum::<f64>()
+            / (n_pairs - 1) as f64
+    } else {
+        0.0
+    };
+
+    let mut pnls: Vec<f64> = pairs.iter().flat_map(|(a, b)| [a.pnl, b.pnl]).collect();
+    pnls.sort_by(f64::total_cmp);
+    let n_paths = pnls.len();
+    let percentiles = PERCENTILES
+        .iter()
+        .map(|&pct| {
+            let idx = ((pct / 100.0) * (n_paths - 1) as f64).round() as usize;
+            (pct, pnls[idx])
+        })
+        .collect();
+    let n_itm = pairs
+        .iter()
+        .flat_map(|(a, b)| [a.short_itm, b.short_itm])
+        .filter(|itm| *itm)
+        .count();
+
+    Report {
+        horizon,
+        paths: n_paths,
+        mean,
+        std_err: (var / n_pairs as f64).sqrt(),
+        percentiles,
+        prob_loss: pnls.iter().filter(|pnl| **pnl < 0.0).count() as f64 / n_paths as f64,
+        prob_short_itm: n_itm as f64 / n_paths as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn short_put() {
+        let now = UtcTime::now();
+        let btc = Price::from_str("50000").unwrap();
+        let settings = Settings {
+            paths: 20_000,
+            ..Default::default()
+        };
+        let mut opt = option::Option::from_str("2030-06-28P40000").unwrap();
+        opt.expiry = now + chrono::Duration::days(30);
+        let positions = [Position {
+            option: opt,
+            btc: -1.0,
+        }];
+
+        // At the risk-free drift the discounted option price is a martingale, so
+        // the expected P&L of the short is minus the interest on its premium
+        let horizon = now + chrono::Duration::days(7);
+        let risk_neutral = Settings {
+            drift: RISK_FREE_RATE,
+            ..settings
+        };
+        let report = simulate(&positions, now, btc, horizon, &risk_neutral);
+        assert_eq!(report.paths, 20_000);
+        let premium = value(&opt, now, 50000.0, settings.vol);
+        let expected = -premium * ((RISK_FREE_RATE * 7.0 / 365.0).exp() - 1.0);
+        assert!(
+            (report.mean - expected).abs() < 4.0 * report.std_err,
+            "{:?} expected {}",
+            report,
+            expected
+        );
+        assert!(report.percentiles[0].1 < 0.0);
+        assert!(report.percentiles[4].1 > 0.0);
+        assert!(report.prob_short_itm > 0.0 && report.prob_short_itm < 0.5);
+        // Deterministic given the seed
+        assert_eq!(
+            report,
+            simulate(&positions, now, btc, horizon, &risk_neutral)
+        );
+
+        // Past expiry, the option is settled at intrinsic value along the path,
+        // so the worst case loss is bounded by the strike
+        let horizon = now + chrono::Duration::days(60);
+        let report = simulate(&positions, now, btc, horizon, &settings);
+        assert!(report.percentiles[4].1 <= premium + 1e-6);
+        assert!(report.percentiles[0].1 >= premium - 40000.0);
+    }
+}
@@ -22,6 +22,16 @@ use crate::units::{Price, Quantity, UtcTime};
 use log::info;
 use std::{fmt, str};
 
+/// Number of prices tried by the bisections in [`Option::bs_arr_price`] and
+/// [`Option::bs_loss80_price`] before giving up
+///
+/// The bisections start at $2^25 and step by half of that, halving the step
+/// each time, so every price they try is a multiple of their last step. With
+/// 46 tries that is 2^-21 dollars, well below a cent, and every price below
+/// $2^25 which is a multiple of it fits in a decimal; so the bisection has
+/// no rounding error at all.
+const BISECTION_TRIES: usize = 46;
+
 /// Whether an option is a put or a call
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum PutCall {
@@ -175,11 +185,13 @@ impl Option {
     /// Given a certain amount of BTC and USD, determine how many of this option
     /// we could short on LX without running out of cash/collateral.
     ///
-    /// Assumes a fee on puts of $25/100 contracts. Returns the number of contracts
-    /// that could be sold along with the cost in USD of every 100 contracts
+    /// Takes the fee LX charges per 100 contracts, which on puts reduces the cash
+    /// we receive. Returns the number of contracts that could be sold along with
+    /// the cost in USD of every 100 contracts
     pub fn max_sale(
         &self,
         sale_price: Price,
+        fee: Price,
         available_usd: Price,
         available_btc: bitcoin::Amount,
     ) -> (Quantity, Price) {
@@ -195,7 +207,7 @@ impl Option {
                     // it causing us grief we just return 0s rather than computing crazy numbers.
                     return (Quantity::Zero, Price::ZERO);
                 }
-                let locked_per_100 = self.strike - sale_price + crate::price!(25);
+                let locked_per_100 = self.strike - sale_price + fee;
                 (
                     Quantity::contracts_from_ratio(available_usd, locked_per_100),
                     locked_per_100,
@@ -227,7 +239,8 @@ impl Option {
 
     /// Compute the price of the option at a given ARR.
     ///
-    /// If the returned price would be unrealistically high, returns none.
+    /// If the returned price would be unrealistically high, or the ARR cannot
+    /// be hit to within 1% at any price, returns none.
     pub fn bs_arr_price(
         &self,
         now: UtcTime,
@@ -238,10 +251,10 @@ impl Option {
             return Some(Price::ZERO);
         }
 
-        let max = Price::ONE.scale_approx((1 << 25).into());
+        let max = Price::ONE.scale_ratio(1 << 25, 1);
         let mut price = max;
         let mut adj = price.half();
-        loop {
+        for _ in 0..BISECTION_TRIES {
             assert!(price > Price::ZERO);
 
             let actual = self.arr(now, btc_price, price);
@@ -258,13 +271,15 @@ impl Option {
             }
             adj = adj.half();
         }
+        None
     }
 
     /// Compute the price of the option at a given absolute loss80.
     ///
-    /// If the returned price would be unrealistically high, returns None.
-    /// However, if the price would be below a dollar, just returns a dollar,
-    /// on the assumption that it's okay to undershoot the loss80.
+    /// If the returned price would be unrealistically high, or the loss80
+    /// cannot be hit to within 1% at any price, returns None. However, if the
+    /// price would be below a dollar, just returns a dollar, on the assumption
+    /// that it's okay to undershoot the loss80.
     pub fn bs_loss80_price(
         &self,
         now: UtcTime,
@@ -275,13 +290,14 @@ impl Option {
             panic!("Cannot target a literal zero loss.");
         }
 
-        let max = Price::ONE.scale_approx((1 << 25).into());
+        let expiry = self.bs_expiry(now, btc_price);
+        let max = Price::ONE.scale_ratio(1 << 25, 1);
         let mut price = max;
         let mut adj = price.half();
-        loop {
+        for _ in 0..BISECTION_TRIES {
             assert!(price > Price::ZERO);
 
-            let actual = self.bs_loss80(now, btc_price, price).abs();
+            let actual = self.loss80_at(&expiry, price).abs();
             let ratio = actual / loss80;
             if ratio > 1.01 {
                 if price == max {
@@ -298,6 +314,7 @@ impl Option {
             }
             adj = adj.half();
         }
+        None
     }
 
     /// Compute the IV of the option at a given price
@@ -371,26 +388,32 @@ impl Option {
     /// Compute the "loss 80" which is the probability that the option will wind up
     /// so far ITM that even the premium is lost
     pub fn bs_loss80(&self, now: UtcTime, btc_price: Price, self_price: Price) -> f64 {
+        self.loss80_at(&self.bs_expiry(now, btc_price), self_price)
+    }
+
+    /// Precomputes the Black-Scholes terms shared by all options with this expiry
+    pub fn bs_expiry(&self, now: UtcTime, btc_price: Price) -> crate::local_bs::Expiry {
+        crate::local_bs::Expiry::new(
+            btc_price.to_approx_f64(),
+            0.04f64, // risk free rate
+            self.years_to_expiry(now),
+        )
+    }
+
+    /// Compute the "loss 80" using precomputed expiry terms
+    fn loss80_at(&self, expiry: &crate::local_bs::Expiry, self_price: Price) -> f64 {
         let vol = 0.8;
         match self.pc {
-            Call => {
-                crate::local_bs::call_dual_delta(
-                    btc_price.to_approx_f64(),
-                    self.strike.to_approx_f64() + self_price.to_approx_f64(),
-                    0.04f64, // risk free rate
-                    vol,
-                    self.years_to_expiry(now),
-                )
-            }
-            Put => {
-                crate::local_bs::put_dual_delta(
-                    btc_price.to_approx_f64(),
-                    self.strike.to_approx_f64() - self_price.to_approx_f64(),
-                    0.04f64, // risk free rate
-                    vol,
-                    self.years_to_expiry(now),
-                )
-            }
+            Call => expiry.dual_delta(
+                true,
+                self.strike.to_approx_f64() + self_price.to_approx_f64(),
+                vol,
+            ),
+            Put => expiry.dual_delta(
+                false,
+                self.strike.to_approx_f64() - self_price.to_approx_f64(),
+                vol,
+            ),
         }
     }
 
@@ -418,6 +441,39 @@ impl Option {
         }
     }
 
+    /// Compute the gamma of the option at a given price (per dollar move in BTC)
+    pub fn bs_gamma(&self, now: UtcTime, btc_price: Price, vol: f64) -> f64 {
+        crate::local_bs::gamma(
+            btc_price.to_approx_f64(),
+            self.strike.to_approx_f64(),
+            0.04f64, // risk free rate
+            vol,
+            self.years_to_expiry(now),
+        )
+    }
+
+    /// Compute the vega of the option at a given price (per unit of volatility)
+    pub fn bs_vega(&self, now: UtcTime, btc_price: Price, vol: f64) -> f64 {
+        crate::local_bs::vega(
+            btc_price.to_approx_f64(),
+            self.strike.to_approx_f64(),
+            0.04f64, // risk free rate
+            vol,
+            self.years_to_expiry(now),
+        )
+    }
+
+    /// Compute the vanna of the option at a given price (change in delta per unit of volatility)
+    pub fn bs_vanna(&self, now: UtcTime, btc_price: Price, vol: f64) -> f64 {
+        crate::local_bs::vanna(
+            btc_price.to_approx_f64(),
+            self.strike.to_approx_f64(),
+            0.04f64, // risk free rate
+            vol,
+            self.years_to_expiry(now),
+        )
+    }
+
     /// Print option data
     pub fn log_option_data<D: fmt::Display>(&self, prefix: D, now: UtcTime, btc_price: Price) {
         let dte = self.years_to_expiry(now) * 365.0;
@@ -482,9 +538,9 @@ impl Option {
             if let Some(size) = size {
                 let logsize = match size {
                     Quantity::Zero => f64::MIN,
-                    Quantity::Bitcoin(_) => unreachable!(),
+                    Quantity::Bitcoin(_) | Quantity::Ether(_) => unreachable!(),
                     Quantity::Cents(n) => (n as f64).log10(),
-                    Quantity::Contracts(n) => (n as f64).log10(),
+                    Quantity::Contracts(n) | Quantity::EthContracts(n) => (n as f64).log10(),
                 };
                 let total = self_price * size;
                 format!(
@@ -511,4 +567,144 @@ impl Option {
             theta_str,
         );
     }
+
+    /// Print black-scholes data for a range of prices, from half to one and a
+    /// half times a central price
+    ///
+    /// If no central price is given, uses the price at 75% volatility.
+    pub fn log_price_ladder(
+        &self,
+        now: UtcTime,
+        btc_price: Price,
+        center: std::option::Option<Price>,
+    ) {
+        let center = match center {
+            Some(price) => price,
+            None => self.bs_price(now, btc_price, 0.75),
+        };
+        let mut price = center.half();
+        while price - center <= center.half() {
+            self.log_order_data(
+                if price == center { "→" } else { " " },
+                now,
+                btc_price,
+                price,
+                None,
+            );
+            price += center.scale_ratio(1, 40);
+        }
+    }
+}
+
+/// Splits an order of `contracts` contracts across a ladder of prices from
+/// `low` up to `high`, each rung `step` (as a fraction) above the last
+///
+/// Rungs are rounded down to whole dollars. Contracts are spread as evenly as
+/// possible, with any remainder going to the lowest rungs; rungs which would
+/// get no contracts are left out. Returns the price and size of each rung.
+pub fn order_ladder(low: Price, high: Price, step: f64, contracts: i64) -> Vec<(Price, i64)> {
+    let mut prices = vec![];
+    let mut price = low;
+    while price <= high {
+        prices.push(price);
+        let next = price.scale_approx(1.0 + step).round_down_to(Price::ONE);
+        price = if next > price {
+            next
+        } else {
+            price + Price::ONE
+        };
+    }
+    let n_rungs = prices.len() as i64;
+    prices
+        .into_iter()
+        .enumerate()
+        .map(|(i, price)| {
+            let extra = if (i as i64) < contracts % n_rungs {
+                1
+            } else {
+                0
+            };
+            (price, contracts / n_rungs + extra)
+        })
+        .filter(|(_, size)| *size > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+    /// Whether a bisection result is on the grid of its finest step, i.e.
+    /// whether no rounding error crept into the bisection
+    fn on_grid(price: Price) -> bool {
+        price
+            .scale_ratio(1 << (BISECTION_TRIES - 25), 1)
+            .is_multiple_of(Price::ONE)
+    }
+
+    #[test]
+    fn bisections_exact() {
+        let now = UtcTime::parse_coinbase("2024-03-01T15:00:00Z").unwrap();
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let btc_price = Price::from_cents(rng.gen_range(1_000_000..15_000_000));
+            let strike = btc_price
+                .scale_ratio(rng.gen_range(50..150), 100)
+                .round_down_to(Price::ONE_THOUSAND);
+            let expiry = now + chrono::Duration::hours(rng.gen_range(24..24 * 400));
+            let opt = if rng.gen_bool(0.5) {
+                Option::new_call(strike, expiry)
+            } else {
+                Option::new_put(strike, expiry)
+            };
+
+            let arr = rng.gen_range(0.01..0.5);
+            if let Some(price) = opt.bs_arr_price(now, btc_price, arr) {
+                let ratio = opt.arr(now, btc_price, price) / arr;
+                assert!(
+                    (0.99..=1.01).contains(&ratio),
+                    "{}: ARR ratio {} at {}",
+                    opt,
+                    ratio,
+                    price
+                );
+                assert!(on_grid(price), "{}: ARR price {} off grid", opt, price);
+            }
+
+            let loss80 = rng.gen_range(0.01..0.5);
+            if let Some(price) = opt.bs_loss80_price(now, btc_price, loss80) {
+                if price > Price::ONE {
+                    let ratio = opt.bs_loss80(now, btc_price, price).abs() / loss80;
+                    assert!(
+                        (0.99..=1.01).contains(&ratio),
+                        "{}: loss80 ratio {} at {}",
+                        opt,
+                        ratio,
+                        price
+                    );
+                }
+                assert!(on_grid(price), "{}: loss80 price {} off grid", opt, price);
+            }
+        }
+    }
+
+    #[test]
+    fn ladder() {
+        let p = |s: &str| s.parse::<Price>().unwrap();
+        assert_eq!(
+            order_ladder(p("100"), p("130"), 0.1, 10),
+            vec![(p("100"), 4), (p("110"), 3), (p("121"), 3)],
+        );
+        // Fewer contracts than rungs; the top rungs are left out
+        assert_eq!(
+            order_ladder(p("100"), p("130"), 0.1, 2),
+            vec![(p("100"), 1), (p("110"), 1)],
+        );
+        // Tiny steps still make progress
+        assert_eq!(
+            order_ladder(p("5"), p("7"), 0.01, 3),
+            vec![(p("5"), 1), (p("6"), 1), (p("7"), 1)],
+        );
+    }
 }
@@ -16,15 +16,25 @@
 //!
 //! Functionality to keep track of historic price data
 //!
+//! Price data is stored on disk as one JSON file per month. Each file carries
+//! a version number; files written by older versions of this software are
+//! upgraded in memory when they are read, and rewritten in the current format
+//! the next time the price data is updated.
+//!
+//! While connected, live price samples are appended to the on-disk data by a
+//! [Recorder], so that it stays current without running `update-price-data`.
+//! Live prices from each feed are combined into a consensus by a [PriceBoard].
+//!
 
 use crate::units::{Price, UtcTime};
 use anyhow::Context;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::{
     fmt, fs,
     io::{self, BufRead},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
@@ -84,6 +94,512 @@ impl fmt::Display for BitcoinPrice {
     }
 }
 
+/// A live price reference, which tracks how long ago it was last updated
+///
+/// Strategy decisions should go through [`PriceReference::get`], which refuses
+/// to serve a price that has gone stale (e.g. because the price feed has
+/// disconnected and not come back).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PriceReference {
+    /// The most recent price
+    price: BitcoinPrice,
+    /// When we received the most recent price
+    received: UtcTime,
+    /// Age beyond which the price will not be served
+    max_age: chrono::Duration,
+}
+
+impl PriceReference {
+    /// Creates a new price reference, received now
+    pub fn new(price: BitcoinPrice, max_age: chrono::Duration) -> Self {
+        PriceReference {
+            price,
+            received: UtcTime::now(),
+            max_age,
+        }
+    }
+
+    /// Records a new price
+    pub fn update(&mut self, price: BitcoinPrice) {
+        self.price = price;
+        self.received = UtcTime::now();
+    }
+
+    /// The time since the price was last updated
+    pub fn age(&self) -> chrono::Duration {
+        UtcTime::now() - self.received
+    }
+
+    /// The most recent price, if it is not older than the configured limit
+    pub fn get(&self) -> anyhow::Result<BitcoinPrice> {
+        self.get_at(UtcTime::now())
+    }
+
+    /// The most recent price, if it was not older than the configured limit
+    /// as of the given time
+    pub fn get_at(&self, now: UtcTime) -> anyhow::Result<BitcoinPrice> {
+        let age = now - self.received;
+        if age > self.max_age {
+            Err(anyhow::Error::msg(format!(
+                "price reference {} is stale: last updated {:.1}s ago (limit {}s)",
+                self.price,
+                age.num_milliseconds() as f64 / 1000.0,
+                self.max_age.num_seconds(),
+            )))
+        } else {
+            Ok(self.price)
+        }
+    }
+
+    /// The most recent price, regardless of age
+    ///
+    /// Only use this for logging or monitoring, not for trading decisions.
+    pub fn last(&self) -> BitcoinPrice {
+        self.price
+    }
+}
+
+impl fmt::Display for PriceReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (age {:.1}s)",
+            self.price,
+            self.age().num_milliseconds() as f64 / 1000.0
+        )
+    }
+}
+
+/// Fewest price sources which must agree for a consensus to be served, once
+/// the sources have disagreed
+pub const MIN_AGREEING_SOURCES: usize = 2;
+
+/// How far one price source is from the consensus of all of them
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Divergence {
+    /// The source furthest from the consensus
+    pub source: &'static str,
+    /// Its distance from the consensus, in basis points
+    pub bps: u32,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} off by {}bps", self.source, self.bps)
+    }
+}
+
+/// The latest prices from each of several sources
+///
+/// The consensus price is the weighted median of the sources which are not
+/// stale, so that a single glitching feed cannot move it far. Sources have a
+/// weight of 1 unless set otherwise, e.g. to favor the deeper markets. Like [`PriceReference`],
+/// [`PriceBoard::get`] refuses to serve a price when every source is stale.
+/// When the sources disagree by more than the configured limit, the one
+/// furthest from the consensus is dropped, until they agree; if that would
+/// leave fewer than [`MIN_AGREEING_SOURCES`], no price is served, since then
+/// we do not know which of them to believe.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PriceBoard {
+    /// The latest price from each source, by name
+    sources: BTreeMap<&'static str, PriceReference>,
+    /// Weights of sources in the consensus, by name, if not 1
+    weights: BTreeMap<&'static str, u32>,
+    /// Age beyond which a source's price is left out of the consensus
+    max_age: chrono::Duration,
+    /// Divergence (in basis points) beyond which no consensus will be served
+    max_divergence_bps: u32,
+}
+
+impl PriceBoard {
+    /// Creates a new price board with an initial price from a single source
+    pub fn new(
+        source: &'static str,
+        price: BitcoinPrice,
+        max_age: chrono::Duration,
+        max_divergence_bps: u32,
+    ) -> Self {
+        let mut sources = BTreeMap::new();
+        sources.insert(source, PriceReference::new(price, max_age));
+        PriceBoard {
+            sources,
+            weights: BTreeMap::new(),
+            max_age,
+            max_divergence_bps,
+        }
+    }
+
+    /// Records a new price from the given source
+    pub fn update(&mut self, source: &'static str, price: BitcoinPrice) {
+        let max_age = self.max_age;
+        self.sources
+            .entry(source)
+            .and_modify(|price_ref| price_ref.update(price))
+            .or_insert_with(|| PriceReference::new(price, max_age));
+    }
+
+    /// Sets the weight of the given source in the consensus
+    ///
+    /// Weights must be positive; a source can be left out of the consensus
+    /// by not feeding it to the board at all.
+    pub fn set_weight(&mut self, source: &'static str, weight: u32) {
+        assert!(weight > 0, "price source {} given zero weight", source);
+        self.weights.insert(source, weight);
+    }
+
+    /// The weight of the given source in the consensus
+    fn weight(&self, source: &'static str) -> u32 {
+        self.weights.get(source).copied().unwrap_or(1)
+    }
+
+    /// The latest price from each source, regardless of age
+    pub fn sources(&self) -> impl Iterator<Item = (&'static str, &PriceReference)> {
+        self.sources
+            .iter()
+            .map(|(name, price_ref)| (*name, price_ref))
+    }
+
+    /// The prices of all sources which were not stale as of the given time
+    fn fresh_at(&self, now: UtcTime) -> Vec<(&'static str, BitcoinPrice)> {
+        self.sources
+            .iter()
+            .filter_map(|(name, price_ref)| price_ref.get_at(now).ok().map(|p| (*name, p)))
+            .collect()
+    }
+
+    /// The consensus price, if any source is fresh and they agree within the
+    /// configured limit
+    pub fn get(&self) -> anyhow::Result<BitcoinPrice> {
+        self.get_at(UtcTime::now())
+    }
+
+    /// The consensus price, if any source was fresh and enough of them agreed
+    /// within the configured limit as of the given time
+    pub fn get_at(&self, now: UtcTime) -> anyhow::Result<BitcoinPrice> {
+        let mut fresh = self.fresh_at(now);
+        if fresh.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "every price source is stale (limit {}s): {}",
+                self.max_age.num_seconds(),
+                self,
+            )));
+        }
+        loop {
+            let consensus = median(
+                fresh
+                    .iter()
+                    .map(|(name, price)| (*price, self.weight(name))),
+            );
+            match divergence(&fresh, consensus) {
+                Some(div) if div.bps > self.max_divergence_bps => {
+                    if fresh.len() <= MIN_AGREEING_SOURCES {
+                        return Err(anyhow::Error::msg(format!(
+                            "price sources diverge: {} (limit {}bps): {}",
+                            div, self.max_divergence_bps, self,
+                        )));
+                    }
+                    fresh.retain(|(name, _)| *name != div.source);
+                }
+                _ => return Ok(consensus),
+            }
+        }
+    }
+
+    /// The greatest divergence from the consensus among the sources which
+    /// were fresh as of the given time
+    pub fn divergence_at(&self, now: UtcTime) -> Option<Divergence> {
+        let fresh = self.fresh_at(now);
+        if fresh.is_empty() {
+            return None;
+        }
+        let consensus = median(
+            fresh
+                .iter()
+                .map(|(name, price)| (*price, self.weight(name))),
+        );
+        divergence(&fresh, consensus)
+    }
+
+    /// The weighted median of every source's latest price, regardless of age
+    /// or divergence
+    ///
+    /// Only use this for logging or monitoring, not for trading decisions.
+    pub fn last(&self) -> BitcoinPrice {
+        median(
+            self.sources
+                .iter()
+                .map(|(name, price_ref)| (price_ref.last(), self.weight(name))),
+        )
+    }
+}
+
+impl fmt::Display for PriceBoard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.last().btc_price)?;
+        for (name, price_ref) in self.sources() {
+            write!(
+                f,
+                " [{} {:.2} age {:.1}s",
+                name,
+                price_ref.last().btc_price,
+                price_ref.age().num_milliseconds() as f64 / 1000.0,
+            )?;
+            match self.weight(name) {
+                1 => f.write_str("]")?,
+                weight => write!(f, " weight {weight}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The weighted median of a nonempty set of prices, timestamped with the
+/// latest of them
+///
+/// If the weights split exactly in half between two prices, their midpoint
+/// is used, so that with equal weights this is the ordinary median.
+fn median<I: IntoIterator<Item = (BitcoinPrice, u32)>>(prices: I) -> BitcoinPrice {
+    let mut prices: Vec<(BitcoinPrice, u32)> = prices.into_iter().collect();
+    let timestamp = prices
+        .iter()
+        .map(|(price, _)| price.timestamp)
+        .max()
+        .expect("median of at least one price");
+    prices.sort_by_key(|(price, _)| price.btc_price);
+    let total: u32 = prices.iter().map(|(_, weight)| weight).sum();
+    let mut cumulative = 0;
+    let mut btc_price = prices[prices.len() - 1].0.btc_price;
+    for (n, (price, weight)) in prices.iter().enumerate() {
+        cumulative += weight;
+        if 2 * cumulative == total && n + 1 < prices.len() {
+            btc_price = price.btc_price.half() + prices[n + 1].0.btc_price.half();
+            break;
+        } else if 2 * cumulative >= total {
+            btc_price = price.btc_price;
+            break;
+        }
+    }
+    BitcoinPrice {
+        timestamp,
+        btc_price,
+    }
+}
+
+/// The source furthest from the consensus, if there are at least two
+fn divergence(
+    prices: &[(&'static str, BitcoinPrice)],
+    consensus: BitcoinPrice,
+) -> Option<Divergence> {
+    if prices.len() < 2 {
+        return None;
+    }
+    prices
+        .iter()
+        .map(|(source, price)| Divergence {
+            source,
+            bps: ((price.btc_price / consensus.btc_price - 1.0).abs() * 10_000.0).round() as u32,
+        })
+        .max_by_key(|div| div.bps)
+}
+
+/// The live price sources which `connect` can feed into its [`PriceBoard`]
+pub const LIVE_SOURCES: [&str; 3] = [
+    crate::bitstamp::PRICE_SOURCE,
+    crate::coinbase::PRICE_SOURCE,
+    crate::kraken::PRICE_SOURCE,
+];
+
+/// The weight of a live price source in the consensus
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SourceWeight {
+    /// The price source, one of [`LIVE_SOURCES`]
+    pub source: &'static str,
+    /// Its weight, which is positive
+    pub weight: u32,
+}
+
+impl fmt::Display for SourceWeight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.source.to_ascii_lowercase(), self.weight)
+    }
+}
+
+impl FromStr for SourceWeight {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. kraken:2
+        let (name, weight) = s
+            .split_once(':')
+            .ok_or_else(|| format!("bad source weight {s} (expected <source>:<weight>)"))?;
+        let source = LIVE_SOURCES
+            .iter()
+            .find(|source| source.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                format!(
+                    "unknown price source {name}; allowed values: {}",
+                    LIVE_SOURCES.join(", ").to_ascii_lowercase()
+                )
+            })?;
+        match weight.parse() {
+            Ok(0) | Err(_) => Err(format!(
+                "bad weight {weight} in {s} (expected a positive integer)"
+            )),
+            Ok(weight) => Ok(SourceWeight { source, weight }),
+        }
+    }
+}
+
+/// Current version of the on-disk price data format
+///
+/// Version 1 files were a bare JSON array of prices. Version 2 wraps this in
+/// an object which also records the version.
+pub const PRICE_DATA_VERSION: u32 = 2;
+
+/// Migrations between on-disk versions; entry `n` upgrades version `n + 1`
+/// to version `n + 2`
+static MIGRATIONS: &[fn(serde_json::Value) -> anyhow::Result<serde_json::Value>] =
+    &[migrate_v1_to_v2];
+
+/// Upgrades a version 1 file (a bare array) to version 2
+fn migrate_v1_to_v2(json: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    if !json.is_array() {
+        return Err(anyhow::Error::msg("version 1 price data was not an array"));
+    }
+    Ok(serde_json::json!({ "version": 2, "prices": json }))
+}
+
+/// A single month of price data, as stored on disk
+#[derive(Serialize, Deserialize)]
+struct PriceFile<T> {
+    version: u32,
+    prices: Vec<T>,
+}
+
+/// Parses a price data file of any known version, upgrading it to the current one
+///
+/// Returns the version the file was written in, along with its prices.
+fn parse_price_file(mut json: serde_json::Value) -> anyhow::Result<(u32, Vec<BitcoinPrice>)> {
+    let original_version = match json {
+        serde_json::Value::Array(..) => 1,
+        serde_json::Value::Object(ref map) => match map.get("version").and_then(|v| v.as_u64()) {
+            Some(v) => v as u32,
+            None => return Err(anyhow::Error::msg("price data file has no version")),
+        },
+        _ => {
+            return Err(anyhow::Error::msg(
+                "price data file is neither array nor object",
+            ))
+        }
+    };
+    if original_version == 0 || original_version > PRICE_DATA_VERSION {
+        return Err(anyhow::Error::msg(format!(
+            "price data has version {original_version}, but we only understand up to \
+             {PRICE_DATA_VERSION}; you may need to update this software"
+        )));
+    }
+
+    for (n, migrate) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(original_version as usize - 1)
+    {
+        json = migrate(json).with_context(|| format!("upgrading from version {}", n + 1))?;
+    }
+
+    let file: PriceFile<BitcoinPrice> = serde_json::from_value(json).context("decoding json")?;
+    Ok((original_version, file.prices))
+}
+
+/// Reads a single price data file from disk
+fn read_price_file(path: &Path) -> anyhow::Result<(u32, Vec<BitcoinPrice>)> {
+    let input = io::BufReader::new(fs::File::open(path).context("opening json file")?);
+    let json = serde_json::from_reader(input).context("decoding json")?;
+    parse_price_file(json)
+}
+
+/// Summary of the price data stored on disk
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DataInfo {
+    /// Number of files of each on-disk version
+    pub files_by_version: BTreeMap<u32, usize>,
+    /// Earliest recorded price
+    pub first: Option<UtcTime>,
+    /// Latest recorded price
+    pub last: Option<UtcTime>,
+    /// Number of price points in each year
+    pub points_by_year: BTreeMap<i32, usize>,
+}
+
+impl DataInfo {
+    /// Reads every price data file in the given directory and summarizes them
+    pub fn read<P: AsRef<Path>>(datadir: P) -> Result<Self, anyhow::Error> {
+        let mut ret = DataInfo::default();
+        for file in fs::read_dir(datadir).context("opening pricedata directory")? {
+            let filepath = file.context("getting file path")?.path();
+            if !is_price_file(&filepath) {
+                continue;
+            }
+            let (version, prices) = read_price_file(&filepath)
+                .with_context(|| format!("reading {}", filepath.display()))?;
+            *ret.files_by_version.entry(version).or_default() += 1;
+            for price in prices {
+                let time = price.timestamp;
+                ret.first = Some(ret.first.map_or(time, |first| first.min(time)));
+                ret.last = Some(ret.last.map_or(time, |last| last.max(time)));
+                *ret.points_by_year.entry(time.year()).or_default() += 1;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Logs the summary
+    pub fn log(&self) {
+        info!("Current price data format version: {}", PRICE_DATA_VERSION);
+        for (version, count) in &self.files_by_version {
+            info!("    {} file(s) in version {}", count, version);
+        }
+        if self
+            .files_by_version
+            .keys()
+            .any(|v| *v < PRICE_DATA_VERSION)
+        {
+            info!("Older files will be upgraded on the next `update-price-data`.");
+        }
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => info!("Coverage: {} to {}", first, last),
+            _ => info!("No price data recorded."),
+        }
+        for (year, count) in &self.points_by_year {
+            info!("    {}: {} points", year, count);
+        }
+    }
+}
+
+/// Number of malformed CSV lines which are logged individually
+const MAX_LOGGED_CSV_ERRORS: usize = 10;
+
+/// Options for reading price data from CSV
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CsvOptions {
+    /// Only record prices within this date range
+    pub range: crate::ledgerx::history::DateRange,
+    /// Total size of the input, in bytes, if known, for reporting progress
+    pub total_bytes: Option<u64>,
+}
+
+/// Summary of a CSV import
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CsvReport {
+    /// Number of lines read
+    pub lines: usize,
+    /// Number of prices recorded
+    pub recorded: usize,
+    /// Number of malformed lines skipped
+    pub skipped: usize,
+    /// Number of prices skipped because they were outside the date range
+    pub out_of_range: usize,
+}
+
 /// Historic price data
 #[derive(Default)]
 pub struct Historic {
@@ -106,6 +622,26 @@ impl Historic {
         *result.1
     }
 
+    /// Iterator over all recorded prices in the given time range, in time order
+    pub fn prices_between(
+        &self,
+        from: crate::units::UtcTime,
+        to: crate::units::UtcTime,
+    ) -> impl Iterator<Item = &BitcoinPrice> + '_ {
+        self.data
+            .iter()
+            .skip_while(move |(time, _)| *time < from)
+            .take_while(move |(time, _)| *time <= to)
+            .map(|(_, price)| price)
+    }
+
+    /// Times of the earliest and latest recorded prices, if any
+    pub fn first_last(&self) -> Option<(UtcTime, UtcTime)> {
+        let first = self.data.iter().next()?.0;
+        let last = self.data.iter().last()?.0;
+        Some((first, last))
+    }
+
     /// Number of price entries recorded
     pub fn len(&self) -> usize {
         self.data.len()
@@ -118,29 +654,70 @@ impl Historic {
 
     /// Reads a bunch of price records from CSV data, keeping only the most
     /// recent entry as of each half-hour
-    pub fn read_csv<R: io::Read>(&mut self, data: R) -> Result<(), anyhow::Error> {
+    ///
+    /// Malformed lines are logged and skipped, rather than aborting the whole
+    /// import; only I/O errors are fatal. Prices outside of `options.range` are
+    /// ignored, and since the bitcoincharts data is in chronological order, we
+    /// stop reading once we pass its end.
+    pub fn read_csv<R: io::Read>(
+        &mut self,
+        data: R,
+        options: &CsvOptions,
+    ) -> Result<CsvReport, anyhow::Error> {
+        let start = std::time::Instant::now();
+        let mut report = CsvReport::default();
+        let mut bytes_read = 0u64;
         let mut last_half_hour = 0;
         let mut last_price = None;
         for (lineno, entry) in io::BufReader::new(data).lines().enumerate() {
             let entry = entry.with_context(|| format!("reading line {lineno}"))?;
-            let price = BitcoinPrice::from_csv(&entry)
-                .with_context(|| format!("decoding price \"{entry}\" at {lineno}"))?;
-
-            let half_hour = 12 * price.timestamp.hour() + price.timestamp.minute() / 5;
-            if last_half_hour != half_hour {
-                last_half_hour = half_hour;
-                self.record(price);
-            }
+            report.lines += 1;
+            bytes_read += entry.len() as u64 + 1;
 
             if lineno % 1_000_000 == 0 && lineno > 0 {
+                let secs = start.elapsed().as_secs_f64().max(0.001);
+                let percent = match options.total_bytes {
+                    Some(total) if total > 0 => {
+                        format!(" ({:.1}%)", bytes_read as f64 * 100.0 / total as f64)
+                    }
+                    _ => String::new(),
+                };
                 info!(
-                    "Read {}M lines, recorded {} datapoints. Last trade {}",
+                    "Read {}M lines{}, {:.0} lines/s, recorded {} datapoints, skipped {}. Last trade {}",
                     lineno / 1_000_000,
+                    percent,
+                    report.lines as f64 / secs,
                     self.len(),
-                    price
+                    report.skipped,
+                    last_price.map(|p: BitcoinPrice| p.to_string()).unwrap_or_default(),
                 );
             }
 
+            let price = match BitcoinPrice::from_csv(&entry) {
+                Ok(price) => price,
+                Err(e) => {
+                    report.skipped += 1;
+                    if report.skipped <= MAX_LOGGED_CSV_ERRORS {
+                        warn!("Skipping line {} \"{}\": {}", lineno, entry, e);
+                    }
+                    continue;
+                }
+            };
+            if !options.range.contains(price.timestamp) {
+                if options.range.ends_before(price.timestamp) {
+                    info!("Passed end of date range at line {}; stopping.", lineno);
+                    break;
+                }
+                report.out_of_range += 1;
+                continue;
+            }
+
+            let half_hour = 12 * price.timestamp.hour() + price.timestamp.minute() / 5;
+            if last_half_hour != half_hour {
+                last_half_hour = half_hour;
+                self.record(price);
+                report.recorded += 1;
+            }
             last_price = Some(price);
         }
 
@@ -148,8 +725,23 @@ impl Historic {
         // this in real-time to price an option
         if let Some(price) = last_price {
             self.record(price);
+            report.recorded += 1;
         }
-        Ok(())
+        if report.skipped > MAX_LOGGED_CSV_ERRORS {
+            warn!(
+                "... and {} more malformed lines",
+                report.skipped - MAX_LOGGED_CSV_ERRORS
+            );
+        }
+        info!(
+            "Read {} lines in {:.1}s: recorded {} datapoints, skipped {} malformed and {} out-of-range lines.",
+            report.lines,
+            start.elapsed().as_secs_f64(),
+            report.recorded,
+            report.skipped,
+            report.out_of_range,
+        );
+        Ok(report)
     }
 
     /// Reads all price records from cache
@@ -168,11 +760,9 @@ impl Historic {
             let filepath = file.context("getting file path")?.path();
             let filename = filepath.to_string_lossy();
 
-            if filename.rsplit('/').next() >= Some(min_date) {
-                let input =
-                    io::BufReader::new(fs::File::open(filepath).context("opening json file")?);
-                let prices: Vec<BitcoinPrice> =
-                    serde_json::from_reader(input).context("decoding json")?;
+            if is_price_file(&filepath) && filename.rsplit('/').next() >= Some(min_date) {
+                let (_, prices) = read_price_file(&filepath)
+                    .with_context(|| format!("reading {}", filepath.display()))?;
                 for price in prices {
                     new.record(price);
                 }
@@ -188,17 +778,11 @@ impl Historic {
         let mut mo_entries = vec![];
         fs::create_dir_all(&datadir).context("creating pricedata directory")?;
         for entry in self.data.values() {
-            let year_mo = 100 * entry.timestamp.year() + entry.timestamp.month() as i32;
+            let year_mo = year_month(entry.timestamp);
             if last_year_mo != year_mo {
                 if last_year_mo > 0 {
                     datadir.push(format!("{last_year_mo:06}.json"));
-                    serde_json::to_writer(
-                        io::BufWriter::new(
-                            fs::File::create(&datadir).context("creating json file")?,
-                        ),
-                        &mo_entries,
-                    )
-                    .context("writing json")?;
+                    write_price_file(&datadir, &mo_entries)?;
                     datadir.pop();
                 }
                 mo_entries.clear();
@@ -210,14 +794,455 @@ impl Historic {
         // Record most recent month
         if last_year_mo > 0 {
             datadir.push(format!("{last_year_mo:06}.json"));
-            serde_json::to_writer(
-                io::BufWriter::new(fs::File::create(&datadir).context("creating json file")?),
-                &mo_entries,
-            )
-            .context("writing json")?;
+            write_price_file(&datadir, &mo_entries)?;
             datadir.pop();
         }
 
         Ok(())
     }
 }
+
+/// A source of historic BTC prices, used to mark events with the price at
+/// the time they happened
+pub trait PriceSource {
+    /// Name of the source, for output headers
+    fn name(&self) -> String;
+
+    /// Returns the most recent price as of a given time
+    fn price_at(&self, time: UtcTime) -> BitcoinPrice;
+}
+
+impl PriceSource for Historic {
+    fn name(&self) -> String {
+        "historic".into()
+    }
+
+    fn price_at(&self, time: UtcTime) -> BitcoinPrice {
+        Historic::price_at(self, time)
+    }
+}
+
+/// Prices read from a Bitcoincharts-format CSV file, without touching the
+/// on-disk price data
+pub struct CsvSource {
+    path: PathBuf,
+    prices: Historic,
+}
+
+impl CsvSource {
+    /// Reads all the prices in a CSV file
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let input = fs::File::open(path)
+            .with_context(|| format!("opening price data {}", path.display()))?;
+        let options = CsvOptions {
+            total_bytes: input.metadata().ok().map(|meta| meta.len()),
+            ..Default::default()
+        };
+        let mut prices = Historic::default();
+        prices
+            .read_csv(input, &options)
+            .with_context(|| format!("decoding CSV data from {}", path.display()))?;
+        if prices.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "no prices in {}",
+                path.display()
+            )));
+        }
+        Ok(CsvSource {
+            path: path.to_owned(),
+            prices,
+        })
+    }
+}
+
+impl PriceSource for CsvSource {
+    fn name(&self) -> String {
+        format!("csv:{}", self.path.display())
+    }
+
+    fn price_at(&self, time: UtcTime) -> BitcoinPrice {
+        self.prices.price_at(time)
+    }
+}
+
+/// Which price source to use, as selected on the command line
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub enum PriceSourceKind {
+    /// The on-disk historic price data
+    #[default]
+    Historic,
+    /// LX's own price references where available, falling back to the
+    /// historic price data
+    LedgerX,
+    /// A Bitcoincharts-format CSV file, e.g. a different exchange's trades
+    Csv(PathBuf),
+}
+
+impl fmt::Display for PriceSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PriceSourceKind::Historic => f.write_str("historic"),
+            PriceSourceKind::LedgerX => f.write_str("lx"),
+            PriceSourceKind::Csv(ref path) => write!(f, "csv:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for PriceSourceKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "historic" => Ok(PriceSourceKind::Historic),
+            "lx" => Ok(PriceSourceKind::LedgerX),
+            x => match x.strip_prefix("csv:") {
+                Some(path) if !path.is_empty() => Ok(PriceSourceKind::Csv(path.into())),
+                _ => Err(format!(
+                    "Invalid price source {x}; allowed values: historic, lx, csv:<file>"
+                )),
+            },
+        }
+    }
+}
+
+/// Number of live price samples to accumulate before writing them to disk
+const RECORD_BATCH_SIZE: usize = 15;
+
+/// Appends live price samples to the on-disk price data
+///
+/// Samples are kept at most once per `interval`, and written out in batches.
+/// Each batch is merged into the existing file for its month, which is then
+/// replaced atomically, so a crash loses at most one batch.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Recorder {
+    datadir: PathBuf,
+    interval: chrono::Duration,
+    last_sample: Option<UtcTime>,
+    pending: Vec<BitcoinPrice>,
+}
+
+impl Recorder {
+    /// Creates a new recorder writing into the given price data directory
+    pub fn new(datadir: PathBuf, interval: chrono::Duration) -> Self {
+        Recorder {
+            datadir,
+            interval,
+            last_sample: None,
+            pending: vec![],
+        }
+    }
+
+    /// Records a price sample, unless one was recorded too recently, writing
+    /// out the pending samples once a full batch has accumulated
+    pub fn sample(&mut self, price: BitcoinPrice) -> anyhow::Result<()> {
+        if let Some(last) = self.last_sample {
+            if price.timestamp - last < self.interval {
+                return Ok(());
+            }
+        }
+        self.last_sample = Some(price.timestamp);
+        self.pending.push(price);
+        if self.pending.len() >= RECORD_BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out all pending samples
+    ///
+    /// If this fails, the samples are kept and will be retried on the next
+    /// flush. Rewriting a month which was already updated is harmless, since
+    /// prices are deduplicated by timestamp.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.datadir).context("creating pricedata directory")?;
+
+        let mut by_month: BTreeMap<i32, Vec<BitcoinPrice>> = BTreeMap::new();
+        for price in &self.pending {
+            by_month
+                .entry(year_month(price.timestamp))
+                .or_default()
+                .push(*price);
+        }
+        for (year_mo, new_prices) in by_month {
+            let path = self.datadir.join(format!("{year_mo:06}.json"));
+            let mut prices = BTreeMap::new();
+            if path.exists() {
+                let (_, old_prices) = read_price_file(&path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                prices.extend(old_prices.into_iter().map(|p| (p.timestamp, p)));
+            }
+            prices.extend(new_prices.into_iter().map(|p| (p.timestamp, p)));
+            let prices: Vec<&BitcoinPrice> = prices.values().collect();
+            write_price_file(&path, &prices)
+                .with_context(|| format!("writing {}", path.display()))?;
+        }
+        log::debug!(
+            "Recorded {} price samples to {}",
+            self.pending.len(),
+            self.datadir.display()
+        );
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    /// Writes out any pending samples, so that they are not lost if the main
+    /// loop panics
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to record price samples: {:#}", e);
+        }
+    }
+}
+
+/// The year and month of a timestamp, as YYYYMM, which names its price data file
+fn year_month(time: UtcTime) -> i32 {
+    100 * time.year() + time.month() as i32
+}
+
+/// Whether a path in the price data directory is a price data file, rather than
+/// e.g. a temporary file left behind by an interrupted write
+fn is_price_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Rewrites every price data file in the given directory which was written by
+/// an older version, in the current format
+///
+/// Returns the number of files upgraded.
+pub fn upgrade_files(datadir: &Path) -> anyhow::Result<usize> {
+    let mut upgraded = 0;
+    for file in fs::read_dir(datadir).context("opening pricedata directory")? {
+        let filepath = file.context("getting file path")?.path();
+        if !is_price_file(&filepath) {
+            continue;
+        }
+        let (version, prices) = read_price_file(&filepath)
+            .with_context(|| format!("reading {}", filepath.display()))?;
+        if version < PRICE_DATA_VERSION {
+            let prices: Vec<&BitcoinPrice> = prices.iter().collect();
+            write_price_file(&filepath, &prices)
+                .with_context(|| format!("upgrading {}", filepath.display()))?;
+            upgraded += 1;
+        }
+    }
+    Ok(upgraded)
+}
+
+/// Writes a single month of price data to disk, in the current format
+fn write_price_file(path: &Path, prices: &[&BitcoinPrice]) -> anyhow::Result<()> {
+    let file = PriceFile {
+        version: PRICE_DATA_VERSION,
+        prices: prices.to_vec(),
+    };
+    crate::file::write_atomic(path, |w| {
+        serde_json::to_writer(w, &file).context("writing json")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn price_board_consensus() {
+        let price = |s: &str| BitcoinPrice::from_current(Price::from_str(s).unwrap());
+        let mut board = PriceBoard::new(
+            "Coinbase",
+            price("60000"),
+            chrono::Duration::seconds(300),
+            100,
+        );
+        let now = UtcTime::now();
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60000").btc_price
+        );
+        assert_eq!(board.divergence_at(now), None);
+
+        // Two sources: the consensus is their mean
+        board.update("Kraken", price("60200"));
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60100").btc_price
+        );
+        assert_eq!(board.divergence_at(now).unwrap().bps, 17);
+
+        // One bad source out of three is flagged, and dropped from the consensus
+        board.update("LX", price("66000"));
+        assert_eq!(board.last().btc_price, price("60200").btc_price);
+        let div = board.divergence_at(now).unwrap();
+        assert_eq!(div.source, "LX");
+        assert_eq!(div.bps, 963);
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60100").btc_price
+        );
+
+        // ...but with two bad sources, we cannot tell which to believe
+        board.update("Kraken", price("54000"));
+        assert!(board
+            .get_at(now)
+            .unwrap_err()
+            .to_string()
+            .contains("diverge"));
+        board.update("Kraken", price("60200"));
+
+        board.update("LX", price("60100"));
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60100").btc_price
+        );
+
+        // A heavily-weighted source can carry the consensus on its own
+        board.set_weight("Coinbase", 3);
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60000").btc_price
+        );
+        board.set_weight("Coinbase", 2);
+        assert_eq!(
+            board.get_at(now).unwrap().btc_price,
+            price("60050").btc_price
+        );
+
+        // Stale sources are not served
+        let later = now + chrono::Duration::seconds(600);
+        assert!(board
+            .get_at(later)
+            .unwrap_err()
+            .to_string()
+            .contains("stale"));
+        assert_eq!(board.divergence_at(later), None);
+    }
+
+    #[test]
+    fn source_weight() {
+        let weight = SourceWeight::from_str("Kraken:2").unwrap();
+        assert_eq!(weight.source, crate::kraken::PRICE_SOURCE);
+        assert_eq!(weight.weight, 2);
+        assert_eq!(weight.to_string(), "kraken:2");
+        assert!(SourceWeight::from_str("kraken:0").is_err());
+        assert!(SourceWeight::from_str("kraken").is_err());
+        assert!(SourceWeight::from_str("gemini:1").is_err());
+    }
+
+    #[test]
+    fn price_source_kind() {
+        for s in ["historic", "lx", "csv:/tmp/coinbase.csv"] {
+            let kind = PriceSourceKind::from_str(s).unwrap();
+            assert_eq!(kind.to_string(), s);
+        }
+        assert_eq!(
+            PriceSourceKind::from_str("csv:bitstamp.csv"),
+            Ok(PriceSourceKind::Csv("bitstamp.csv".into()))
+        );
+        assert!(PriceSourceKind::from_str("csv:").is_err());
+        assert!(PriceSourceKind::from_str("coinbase").is_err());
+    }
+
+    #[test]
+    fn migrate_versions() {
+        let v1 = serde_json::json!([{ "timestamp": 1700000000, "btc_price": "35000" }]);
+        let (version, prices) = parse_price_file(v1).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(prices.len(), 1);
+
+        let price = prices[0];
+        let v2 = serde_json::to_value(PriceFile {
+            version: PRICE_DATA_VERSION,
+            prices: vec![&price],
+        })
+        .unwrap();
+        assert_eq!(parse_price_file(v2).unwrap(), (2, prices));
+
+        let future = serde_json::json!({ "version": PRICE_DATA_VERSION + 1, "prices": [] });
+        assert!(parse_price_file(future).is_err());
+    }
+
+    #[test]
+    fn read_csv_recovery() {
+        let csv = "1700000000,35000.5,0.1\n\
+                   1700000600,35100,0.2\n\
+                   garbage\n\
+                   1700086400,36000,0.1,extra\n\
+                   1700090000,36500,0.3\n\
+                   1700200000,37000,0.3\n";
+        let mut hist = Historic::default();
+        let report = hist
+            .read_csv(csv.as_bytes(), &CsvOptions::default())
+            .unwrap();
+        assert_eq!(report.lines, 6);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(hist.len(), report.recorded);
+
+        // 2023-11-15 only; stops at the first line of 11-17
+        let options = CsvOptions {
+            range: crate::ledgerx::history::DateRange {
+                from: chrono::NaiveDate::from_ymd_opt(2023, 11, 15),
+                to: chrono::NaiveDate::from_ymd_opt(2023, 11, 15),
+            },
+            total_bytes: Some(csv.len() as u64),
+        };
+        let mut hist = Historic::default();
+        let report = hist.read_csv(csv.as_bytes(), &options).unwrap();
+        assert_eq!(report.out_of_range, 2);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.lines, 6);
+        assert_eq!(
+            hist.price_at(UtcTime::now()).btc_price,
+            Price::from_str("36500").unwrap()
+        );
+    }
+
+    #[test]
+    fn recorder() {
+        let dir = std::env::temp_dir().join(format!("tt-price-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let price = |secs: i64, dollars: &str| {
+            BitcoinPrice::from_csv(&format!("{secs},{dollars},0.1")).unwrap()
+        };
+
+        // An existing file for the month is merged into, not overwritten
+        let mut hist = Historic::default();
+        hist.record(price(1700000000, "35000"));
+        hist.write_out(&dir).unwrap();
+
+        let mut recorder = Recorder::new(dir.clone(), chrono::Duration::seconds(60));
+        for n in 0..(3 * RECORD_BATCH_SIZE as i64) {
+            // Several samples per minute, of which only one is kept
+            recorder
+                .sample(price(1700000100 + 20 * n, "36000"))
+                .unwrap();
+        }
+        let on_disk = Historic::read_json(&dir).unwrap();
+        assert_eq!(on_disk.len(), 1 + RECORD_BATCH_SIZE);
+        assert_eq!(recorder.pending.len(), 0);
+
+        recorder.sample(price(1700010000, "37000")).unwrap();
+        assert_eq!(
+            Historic::read_json(&dir).unwrap().len(),
+            1 + RECORD_BATCH_SIZE
+        );
+        recorder.flush().unwrap();
+        let on_disk = Historic::read_json(&dir).unwrap();
+        assert_eq!(on_disk.len(), 2 + RECORD_BATCH_SIZE);
+        assert_eq!(
+            on_disk.price_at(UtcTime::now()).btc_price,
+            Price::from_str("37000").unwrap()
+        );
+        // Pending samples are written out when the recorder is dropped
+        recorder.sample(price(1700020000, "38000")).unwrap();
+        drop(recorder);
+        assert_eq!(
+            Historic::read_json(&dir).unwrap().len(),
+            3 + RECORD_BATCH_SIZE
+        );
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,450 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Message Queue
+//!
+//! Multi-producer single-consumer queue used to feed the main loop. Unlike
+//! `std::sync::mpsc`, it is bounded, so that if the main loop stalls the
+//! queue does not grow without limit. Not all messages are equal though, so
+//! each one has a [`Priority`]:
+//!
+//!   * urgent messages (shutdowns, kill switches) jump the queue;
+//!   * normal messages (orders, price references) are never dropped, and may
+//!     exceed the bound, since losing them would corrupt our view of the book;
+//!   * droppable messages (uninteresting datafeed spam) are discarded once
+//!     the queue is full.
+//!
+//! Sending never blocks. The main loop sends messages to itself, so a
+//! blocking send could deadlock.
+//!
+//! In addition, a message may have a key, in which case it replaces any
+//! still-queued message with the same key, in that message's place, rather
+//! than being appended. We use this to coalesce price references from each
+//! source, however far back in the queue the previous one is. Each queue
+//! keeps an index of keyed messages, so this does not need a scan.
+//!
+
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::mpsc::{RecvError, SendError};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// How a message should be treated by the queue
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Priority {
+    /// Delivered before any other messages, and never dropped
+    Urgent,
+    /// Never dropped, even if the queue is full
+    Normal,
+    /// Dropped if the queue is full
+    Droppable,
+}
+
+/// A message which can be sent over the queue
+pub trait Prioritize {
+    /// Identifies messages which supersede each other
+    type Key: Hash + Eq;
+
+    /// How the queue should treat the message
+    fn priority(&self) -> Priority;
+
+    /// If set, the message makes any queued message of the same priority with
+    /// the same key redundant, so that it replaces it in its place in the queue
+    ///
+    /// A message which replaces another is never dropped, even if the queue
+    /// is full.
+    fn supersede_key(&self) -> Option<Self::Key> {
+        None
+    }
+}
+
+/// Statistics about the queue
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Stats {
+    /// Number of messages currently queued
+    pub depth: usize,
+    /// Largest number of messages that have been queued at once
+    pub max_depth: usize,
+    /// Number of messages dropped because the queue was full
+    pub dropped: usize,
+    /// Number of messages which replaced an earlier message
+    pub coalesced: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "depth {} (max {}), {} dropped, {} coalesced",
+            self.depth, self.max_depth, self.dropped, self.coalesced,
+        )
+    }
+}
+
+/// A FIFO queue of messages, with an index of the keyed ones
+struct Lane<T: Prioritize> {
+    messages: VecDeque<T>,
+    /// Number of messages ever popped, so that the message at index `i` has
+    /// the stable position `popped + i`
+    popped: u64,
+    /// Position of the queued message with each key
+    keyed: HashMap<T::Key, u64>,
+}
+
+impl<T: Prioritize> Lane<T> {
+    fn new() -> Self {
+        Lane {
+            messages: VecDeque::new(),
+            popped: 0,
+            keyed: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// The queued message with the given key, if any
+    fn keyed_mut(&mut self, key: &T::Key) -> Option<&mut T> {
+        let pos = *self.keyed.get(key)?;
+        let idx = pos.checked_sub(self.popped)?;
+        self.messages.get_mut(idx as usize)
+    }
+
+    fn push_back(&mut self, msg: T, key: Option<T::Key>) {
+        if let Some(key) = key {
+            self.keyed
+                .insert(key, self.popped + self.messages.len() as u64);
+        }
+        self.messages.push_back(msg);
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        let msg = self.messages.pop_front()?;
+        if let Some(key) = msg.supersede_key() {
+            if self.keyed.get(&key) == Some(&self.popped) {
+                self.keyed.remove(&key);
+            }
+        }
+        self.popped += 1;
+        Some(msg)
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.keyed.clear();
+    }
+}
+
+/// State shared between the senders and the receiver
+struct Inner<T: Prioritize> {
+    urgent: Lane<T>,
+    normal: Lane<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    stats: Stats,
+}
+
+impl<T: Prioritize> Inner<T> {
+    /// Takes the next message, urgent ones first
+    fn pop(&mut self) -> Option<T> {
+        let msg = match self.urgent.pop_front() {
+            Some(msg) => msg,
+            None => self.normal.pop_front()?,
+        };
+        self.stats.depth -= 1;
+        Some(msg)
+    }
+}
+
+struct Shared<T: Prioritize> {
+    inner: Mutex<Inner<T>>,
+    ready: Condvar,
+}
+
+/// Sending half of the queue
+pub struct Sender<T: Prioritize> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of the queue
+pub struct Receiver<T: Prioritize> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a new queue which holds at most `capacity` droppable messages
+pub fn bounded<T: Prioritize>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            urgent: Lane::new(),
+            normal: Lane::new(),
+            capacity,
+            senders: 1,
+            receiver_alive: true,
+            stats: Stats::default(),
+        }),
+        ready: Condvar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T: Prioritize> Sender<T> {
+    /// Queues a message, without blocking
+    ///
+    /// Returns an error only if the receiver has been dropped. A droppable
+    /// message which is dropped because the queue is full is not an error.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        let mut guard = self.shared.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if !inner.receiver_alive {
+            return Err(SendError(msg));
+        }
+        let priority = msg.priority();
+        let key = msg.supersede_key();
+        let capacity = inner.capacity;
+        let lane = if priority == Priority::Urgent {
+            &mut inner.urgent
+        } else {
+            &mut inner.normal
+        };
+        let full = lane.len() >= capacity;
+        match key.as_ref().and_then(|key| lane.keyed_mut(key)) {
+            Some(older) => {
+                *older = msg;
+                inner.stats.coalesced += 1;
+            }
+            _ if priority == Priority::Droppable && full => {
+                inner.stats.dropped += 1;
+                if inner.stats.dropped.is_power_of_two() {
+                    warn!(
+                        "Message queue full; dropped {} messages so far",
+                        inner.stats.dropped
+                    );
+                }
+                return Ok(());
+            }
+            _ => lane.push_back(msg, key),
+        }
+        inner.stats.depth = inner.urgent.len() + inner.normal.len();
+        inner.stats.max_depth = inner.stats.max_depth.max(inner.stats.depth);
+        drop(guard);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl<T: Prioritize> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Prioritize> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            drop(inner);
+            self.shared.ready.notify_all();
+        }
+    }
+}
+
+impl<T: Prioritize> Receiver<T> {
+    /// Waits for the next message, returning an error if the queue is empty
+    /// and all senders have been dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        loop {
+            if let Some(msg) = inner.pop() {
+                return Ok(msg);
+            }
+            if inner.senders == 0 {
+                return Err(RecvError);
+            }
+            inner = self.shared.ready.wait(inner).unwrap();
+        }
+    }
+
+    /// Returns the next message if one is queued, without waiting
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.inner.lock().unwrap().pop()
+    }
+
+    /// Iterates over received messages, until all senders have been dropped
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Current statistics about the queue
+    pub fn stats(&self) -> Stats {
+        self.shared.inner.lock().unwrap().stats
+    }
+}
+
+impl<T: Prioritize> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.receiver_alive = false;
+        // Drop any queued messages now rather than when the last sender goes
+        inner.urgent.clear();
+        inner.normal.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    enum Msg {
+        Shutdown(usize),
+        Data { thread: usize, seq: usize },
+        Price(usize),
+        Spam,
+    }
+
+    impl Prioritize for Msg {
+        type Key = ();
+
+        fn priority(&self) -> Priority {
+            match *self {
+                Msg::Shutdown(..) => Priority::Urgent,
+                Msg::Data { .. } | Msg::Price(..) => Priority::Normal,
+                Msg::Spam => Priority::Droppable,
+            }
+        }
+
+        fn supersede_key(&self) -> Option<()> {
+            matches!(self, Msg::Price(..)).then_some(())
+        }
+    }
+
+    #[test]
+    fn priority_and_coalescing() {
+        let (tx, rx) = bounded(2);
+        tx.send(Msg::Price(1)).unwrap();
+        tx.send(Msg::Price(2)).unwrap();
+        tx.send(Msg::Spam).unwrap();
+        tx.send(Msg::Price(3)).unwrap(); // replaces Price(2), past the spam
+        tx.send(Msg::Spam).unwrap(); // dropped
+        tx.send(Msg::Data { thread: 0, seq: 0 }).unwrap();
+        tx.send(Msg::Shutdown(0)).unwrap();
+        let stats = rx.stats();
+        assert_eq!(stats.depth, 4);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.coalesced, 2);
+        drop(tx);
+
+        let received: Vec<Msg> = rx.iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                Msg::Shutdown(0),
+                Msg::Price(3),
+                Msg::Spam,
+                Msg::Data { thread: 0, seq: 0 },
+            ],
+        );
+        assert_eq!(rx.stats().depth, 0);
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn coalescing_after_pop() {
+        let (tx, rx) = bounded(2);
+        tx.send(Msg::Price(1)).unwrap();
+        tx.send(Msg::Data { thread: 0, seq: 0 }).unwrap();
+        assert_eq!(rx.try_recv(), Some(Msg::Price(1)));
+        // Price(1) is gone, so there is nothing left to replace
+        tx.send(Msg::Price(2)).unwrap();
+        tx.send(Msg::Price(3)).unwrap(); // replaces Price(2)
+        assert_eq!(rx.stats().coalesced, 1);
+        assert_eq!(rx.try_recv(), Some(Msg::Data { thread: 0, seq: 0 }));
+        assert_eq!(rx.try_recv(), Some(Msg::Price(3)));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn multithreaded_continuity() {
+        const THREADS: usize = 4;
+        const MESSAGES: usize = 2000;
+
+        let (tx, rx) = bounded(16);
+        let mut handles = vec![];
+        for thread in 0..THREADS {
+            let tx = tx.clone();
+            handles.push(thread::spawn(move || {
+                for seq in 0..MESSAGES {
+                    tx.send(Msg::Data { thread, seq }).unwrap();
+                    tx.send(Msg::Spam).unwrap();
+                    if seq % 500 == 0 {
+                        tx.send(Msg::Shutdown(thread)).unwrap();
+                    }
+                }
+            }));
+        }
+        drop(tx);
+
+        let mut next_seq = [0; THREADS];
+        let mut shutdowns = 0;
+        let mut spam = 0;
+        for msg in rx.iter() {
+            match msg {
+                Msg::Data { thread, seq } => {
+                    // Normal messages from each thread arrive in order, with none lost
+                    assert_eq!(seq, next_seq[thread]);
+                    next_seq[thread] += 1;
+                }
+                Msg::Shutdown(..) => shutdowns += 1,
+                Msg::Spam => spam += 1,
+                Msg::Price(..) => unreachable!(),
+            }
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(next_seq, [MESSAGES; THREADS]);
+        assert_eq!(shutdowns, THREADS * MESSAGES / 500);
+        assert_eq!(spam + rx.stats().dropped, THREADS * MESSAGES);
+    }
+
+    #[test]
+    fn disconnection() {
+        let (tx, rx) = bounded::<Msg>(1);
+        let tx2 = tx.clone();
+        drop(tx);
+        tx2.send(Msg::Spam).unwrap();
+        drop(tx2);
+        assert_eq!(rx.recv(), Ok(Msg::Spam));
+        assert_eq!(rx.recv(), Err(RecvError));
+
+        let (tx, rx) = bounded::<Msg>(1);
+        drop(rx);
+        assert!(tx.send(Msg::Spam).is_err());
+    }
+}
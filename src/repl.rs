@@ -0,0 +1,495 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Interactive Prompt
+//!
+//! Ad-hoc questions -- what is this option worth at 60% vol, what did we do
+//! in March, where did this lot go -- otherwise mean remembering the right
+//! subcommand, and for anything about our history, re-running the whole LX
+//! import. The `repl` command loads the price history, the contract cache
+//! and (optionally) our LX history once, and then answers queries typed at
+//! a prompt until EOF or `quit`.
+//!
+//! Options may be given as e.g. `2025-06-27C100000` or by their LX label,
+//! e.g. `BTC-Mini-28JUN2024-60000-Call`, which is looked up in the contract
+//! cache. The "Mini" part of a label may be left off.
+//!
+
+use crate::cli::{Percent, UsdAmount, MAX_PLAUSIBLE_VOL};
+use crate::ledgerx::contract_cache::ContractCache;
+use crate::ledgerx::history::{self, lot, tax, DateRange, History};
+use crate::option;
+use crate::price::Historic;
+use crate::units::{Price, UtcTime};
+use anyhow::Context;
+use log::{info, warn};
+use std::io::{self, BufRead as _, Write as _};
+use std::str::FromStr;
+
+/// Volatility at which options are priced if none is given
+const DEFAULT_VOL: f64 = 0.5;
+
+/// List of queries, and their descriptions, for the `help` query
+const HELP: &[(&str, &str)] = &[
+    (
+        "btc [YYYY-MM-DD | <RFC 3339 time>]",
+        "BTC price now, or at a given time",
+    ),
+    (
+        "price <option> [vol=<volatility, e.g. 60%>] [btc=<usd>]",
+        "Black-Scholes price and Greeks of an option",
+    ),
+    (
+        "iv <option> [price=<usd>] [btc=<usd>]",
+        "implied volatility at a price, or at a range of prices",
+    ),
+    (
+        "events [YYYY[-MM[-DD]]]",
+        "LX history events in a year, month or day",
+    ),
+    ("lot <lot id>", "a tax lot, and every close of it"),
+    ("help", "this list"),
+    ("quit", "leave"),
+];
+
+/// A query typed at the prompt
+#[derive(Clone, PartialEq, Debug)]
+enum Query {
+    /// List the available queries
+    Help,
+    /// Leave the prompt
+    Quit,
+    /// BTC price at a given time, or now
+    Btc(Option<UtcTime>),
+    /// Price of an option at a given volatility
+    Price {
+        option: String,
+        vol: Option<f64>,
+        btc: Option<Price>,
+    },
+    /// Implied volatility of an option at a given price
+    Iv {
+        option: String,
+        price: Option<Price>,
+        btc: Option<Price>,
+    },
+    /// History events within a date range
+    Events(DateRange),
+    /// Opening and closes of a tax lot
+    Lot(lot::Id),
+}
+
+impl FromStr for Query {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut words = s.split_whitespace();
+        let cmd = words.next().unwrap_or("help");
+        let args: Vec<&str> = words.collect();
+        let (positional, params) = split_params(&args)?;
+        let allow = |allowed: &[&str]| -> Result<(), String> {
+            match params.iter().find(|(key, _)| !allowed.contains(key)) {
+                Some((key, _)) => Err(format!("{cmd} does not take a {key}= parameter")),
+                None => Ok(()),
+            }
+        };
+        let max_positional = |n: usize| -> Result<(), String> {
+            if positional.len() > n {
+                Err(format!("unexpected argument {}", positional[n]))
+            } else {
+                Ok(())
+            }
+        };
+        let param = |key: &str| params.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let usd = |key: &str| -> Result<Option<Price>, String> {
+            param(key)
+                .map(|v| UsdAmount::from_str(v).map(|amt| amt.0))
+                .transpose()
+        };
+        let option = || -> Result<String, String> {
+            positional
+                .first()
+                .map(|s| s.to_string())
+                .ok_or_else(|| format!("{cmd} needs an option"))
+        };
+
+        match cmd {
+            "help" | "?" => Ok(Query::Help),
+            "quit" | "exit" => Ok(Query::Quit),
+            "btc" => {
+                allow(&[])?;
+                max_positional(1)?;
+                positional
+                    .first()
+                    .map(|s| parse_time(s))
+                    .transpose()
+                    .map(Query::Btc)
+            }
+            "price" => {
+                allow(&["vol", "btc"])?;
+                max_positional(1)?;
+                let vol = match param("vol") {
+                    Some(v) => {
+                        // Bare numbers are fractions, as with `price -v`
+                        let vol = Percent::from_str(v)?.fraction(false);
+                        if vol <= 0.0 || vol > MAX_PLAUSIBLE_VOL {
+                            return Err(format!("volatility {v} is implausible"));
+                        }
+                        Some(vol)
+                    }
+                    None => None,
+                };
+                Ok(Query::Price {
+                    option: option()?,
+                    vol,
+                    btc: usd("btc")?,
+                })
+            }
+            "iv" => {
+                allow(&["price", "btc"])?;
+                max_positional(1)?;
+                Ok(Query::Iv {
+                    option: option()?,
+                    price: usd("price")?,
+                    btc: usd("btc")?,
+                })
+            }
+            "events" => {
+                allow(&[])?;
+                max_positional(1)?;
+                match positional.first() {
+                    Some(period) => parse_period(period).map(Query::Events),
+                    None => Ok(Query::Events(DateRange::default())),
+                }
+            }
+            "lot" => {
+                allow(&[])?;
+                max_positional(1)?;
+                match positional.first() {
+                    Some(id) => Ok(Query::Lot(lot::Id::from_str(id).unwrap())),
+                    None => Err("lot needs a lot ID".into()),
+                }
+            }
+            _ => Err(format!("unknown query {cmd}; type `help` for a list")),
+        }
+    }
+}
+
+/// A `key=value` parameter of a query
+type Param<'a> = (&'a str, &'a str);
+
+/// Splits arguments into positional ones and `key=value` parameters
+fn split_params<'a>(args: &[&'a str]) -> Result<(Vec<&'a str>, Vec<Param<'a>>), String> {
+    let mut positional = vec![];
+    let mut params = vec![];
+    for arg in args {
+        match arg.split_once('=') {
+            Some((key, _)) if params.iter().any(|(k, _)| *k == key) => {
+                return Err(format!("parameter {key} given twice"));
+            }
+            Some(param) => params.push(param),
+            None => positional.push(*arg),
+        }
+    }
+    Ok((positional, params))
+}
+
+/// Parses a time, either as a bare date (taken as midnight UTC) or in RFC 3339
+fn parse_time(s: &str) -> Result<UtcTime, String> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%F") {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        return UtcTime::from_unix_i64(midnight.timestamp()).map_err(|e| e.to_string());
+    }
+    UtcTime::parse_coinbase(s)
+        .map_err(|_| format!("invalid time {s}; accepted formats: YYYY-MM-DD, RFC 3339"))
+}
+
+/// Parses a year, month or day into the range of dates it covers
+fn parse_period(s: &str) -> Result<DateRange, String> {
+    let err = || format!("invalid period {s}; accepted formats: YYYY, YYYY-MM, YYYY-MM-DD");
+    let ymd = |y, m, d| chrono::NaiveDate::from_ymd_opt(y, m, d).ok_or_else(err);
+    let parts: Vec<&str> = s.split('-').collect();
+    let year = i32::from_str(parts[0]).map_err(|_| err())?;
+    let (from, to) = match parts[1..] {
+        [] => (ymd(year, 1, 1)?, ymd(year, 12, 31)?),
+        [month] => {
+            let month = u32::from_str(month).map_err(|_| err())?;
+            let from = ymd(year, month, 1)?;
+            let next = if month == 12 {
+                ymd(year + 1, 1, 1)?
+            } else {
+                ymd(year, month + 1, 1)?
+            };
+            (from, next.pred_opt().ok_or_else(err)?)
+        }
+        [_, _] => {
+            let date = chrono::NaiveDate::parse_from_str(s, "%F").map_err(|_| err())?;
+            (date, date)
+        }
+        _ => return Err(err()),
+    };
+    Ok(DateRange {
+        from: Some(from),
+        to: Some(to),
+    })
+}
+
+/// State of the prompt: everything loaded at startup
+struct Session<'a> {
+    prices: &'a Historic,
+    contracts: &'a ContractCache,
+    history: Option<&'a History>,
+    /// Tax events, computed the first time a lot is looked up
+    tax_events: Option<Vec<tax::Event>>,
+}
+
+impl Session<'_> {
+    /// The BTC price at a given time, if we have data for it
+    fn btc_price(&self, time: UtcTime) -> anyhow::Result<Price> {
+        match self.prices.first_last() {
+            Some((first, _)) if first <= time => Ok(self.prices.price_at(time).btc_price),
+            Some((first, last)) => Err(anyhow::Error::msg(format!(
+                "no price data at {time}; have data from {first} to {last}"
+            ))),
+            None => Err(anyhow::Error::msg(
+                "no price data loaded; run `update-price-data` first",
+            )),
+        }
+    }
+
+    /// Parses an option, either directly or by looking up its LX label
+    fn option(&self, s: &str) -> anyhow::Result<option::Option> {
+        if let Ok(opt) = option::Option::from_str(s) {
+            return Ok(opt);
+        }
+        let contract = self
+            .contracts
+            .find_label(s)
+            .with_context(|| format!("{s} is neither an option nor a cached LX label"))?;
+        contract
+            .as_option()
+            .with_context(|| format!("contract {contract} is not an option"))
+    }
+
+    /// Our LX history, if it was loaded
+    fn history(&self) -> anyhow::Result<&History> {
+        self.history
+            .context("no LX history loaded; pass an API key and config file")
+    }
+
+    /// Answers a single query
+    fn answer(&mut self, query: Query, now: UtcTime) -> anyhow::Result<()> {
+        match query {
+            Query::Help => {
+                for (query, desc) in HELP {
+                    info!("    {:60} {}", query, desc);
+                }
+            }
+            Query::Quit => {}
+            Query::Btc(time) => {
+                let time = time.unwrap_or(now);
+                self.btc_price(time)?;
+                info!("BTC: {}", self.prices.price_at(time));
+            }
+            Query::Price { option, vol, btc } => {
+                let option = self.option(&option)?;
+                let btc = match btc {
+                    Some(btc) => btc,
+                    None => self.btc_price(now)?,
+                };
+                let vol = vol.unwrap_or(DEFAULT_VOL);
+                option.log_option_data("", now, btc);
+                info!(
+                    "Vol: {:3.2}   Price ($): {:8.2}   Theta ($): {:5.2}  DDel: {:3.2}%  Del: {:3.2}%",
+                    vol,
+                    option.bs_price(now, btc, vol),
+                    option.bs_theta(now, btc, vol),
+                    option.bs_dual_delta(now, btc, vol) * 100.0,
+                    option.bs_delta(now, btc, vol) * 100.0,
+                );
+                info!(
+                    "Gamma: {:.8}/$  Vega: ${:.2}/vol pt  Vanna: {:.4}%/vol pt",
+                    option.bs_gamma(now, btc, vol),
+                    option.bs_vega(now, btc, vol) / 100.0,
+                    option.bs_vanna(now, btc, vol),
+                );
+            }
+            Query::Iv { option, price, btc } => {
+                let option = self.option(&option)?;
+                let btc = match btc {
+                    Some(btc) => btc,
+                    None => self.btc_price(now)?,
+                };
+                option.log_option_data("", now, btc);
+                match price {
+                    Some(price) => option.log_order_data("", now, btc, price, None),
+                    None => option.log_price_ladder(now, btc, None),
+                }
+            }
+            Query::Events(range) => {
+                let mut n = 0;
+                for (date, event) in self.history()?.events() {
+                    if range.contains(date) {
+                        info!("{}", crate::schema::Record::event(date, event));
+                        n += 1;
+                    }
+                }
+                info!("{} events ({}).", n, range);
+            }
+            Query::Lot(id) => {
+                if self.tax_events.is_none() {
+                    let history = self.history()?;
+                    info!("Running tax engine over LX history...");
+                    let events = history
+                        .tax_events(self.prices)
+                        .context("computing tax events")?;
+                    self.tax_events = Some(events);
+                }
+                let mut found = false;
+                for event in self.tax_events.as_deref().unwrap_or_default() {
+                    match event.open_close {
+                        tax::OpenClose::Open(ref lot) if *lot.id() == id => {
+                            info!("Opened {}", lot);
+                            found = true;
+                        }
+                        tax::OpenClose::Close(ref close) if *close.open_id() == id => {
+                            info!(
+                                "{} {} on {}: proceeds {} basis {} gain/loss {} ({})",
+                                close.ty(),
+                                close.quantity(),
+                                close.close_date(),
+                                close.proceeds(),
+                                close.basis(),
+                                close.gain_loss(),
+                                crate::csv::CsvPrinter(close.gain_loss_type()),
+                            );
+                            found = true;
+                        }
+                        _ => {}
+                    }
+                }
+                if !found {
+                    warn!("No lot {} in the tax history.", id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the prompt until EOF or `quit`
+pub fn run(
+    prices: &Historic,
+    contracts: &ContractCache,
+    history: Option<&history::History>,
+) -> anyhow::Result<()> {
+    let mut session = Session {
+        prices,
+        contracts,
+        history,
+        tax_events: None,
+    };
+    info!("Type `help` for a list of queries.");
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().context("writing prompt")?;
+        let line = match lines.next() {
+            Some(line) => line.context("reading from stdin")?,
+            None => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Query::from_str(&line) {
+            Ok(Query::Quit) => break,
+            Ok(query) => {
+                if let Err(e) = session.answer(query, UtcTime::now()) {
+                    warn!("{:#}", e);
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_queries() {
+        let q = |s: &str| Query::from_str(s);
+        let date = |s: &str| chrono::NaiveDate::parse_from_str(s, "%F").unwrap();
+
+        assert_eq!(q("help"), Ok(Query::Help));
+        assert_eq!(q("  quit "), Ok(Query::Quit));
+        assert_eq!(q("btc"), Ok(Query::Btc(None)));
+        assert_eq!(
+            q("btc 2024-03-01"),
+            Ok(Query::Btc(Some(
+                UtcTime::parse_coinbase("2024-03-01T00:00:00Z").unwrap()
+            ))),
+        );
+        assert_eq!(
+            q("price 2025-06-27C100000 vol=0.6"),
+            Ok(Query::Price {
+                option: "2025-06-27C100000".into(),
+                vol: Some(0.6),
+                btc: None,
+            }),
+        );
+        assert_eq!(
+            q("price 2025-06-27C100000 vol=60% btc=$65,000"),
+            Ok(Query::Price {
+                option: "2025-06-27C100000".into(),
+                vol: Some(0.6),
+                btc: Some(Price::from_str("65000").unwrap()),
+            }),
+        );
+        assert_eq!(
+            q("iv BTC-28JUN2024-60000-Call price=1234.56"),
+            Ok(Query::Iv {
+                option: "BTC-28JUN2024-60000-Call".into(),
+                price: Some(Price::from_str("1234.56").unwrap()),
+                btc: None,
+            }),
+        );
+        assert_eq!(
+            q("lot lx-btc-0142"),
+            Ok(Query::Lot("lx-btc-0142".parse().unwrap()))
+        );
+
+        let events = |from: &str, to: &str| {
+            Ok(Query::Events(DateRange {
+                from: Some(date(from)),
+                to: Some(date(to)),
+            }))
+        };
+        assert_eq!(q("events 2024"), events("2024-01-01", "2024-12-31"));
+        assert_eq!(q("events 2024-02"), events("2024-02-01", "2024-02-29"));
+        assert_eq!(q("events 2024-12"), events("2024-12-01", "2024-12-31"));
+        assert_eq!(q("events 2024-03-05"), events("2024-03-05", "2024-03-05"));
+        assert_eq!(q("events"), Ok(Query::Events(DateRange::default())));
+
+        assert!(q("events 2024-13").is_err());
+        assert!(q("price").is_err());
+        assert!(q("price 2025-06-27C100000 vol=6000%").is_err());
+        assert!(q("price 2025-06-27C100000 price=100").is_err());
+        assert!(q("iv 2025-06-27C100000 price=1 price=2").is_err());
+        assert!(q("lot a b").is_err());
+        assert!(q("frobnicate").is_err());
+    }
+}
@@ -0,0 +1,391 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Replay
+//!
+//! Rather than connecting to the live websockets, `connect --replay` reads
+//! back the LX datafeed and Coinbase logs written by an earlier session and
+//! feeds their messages to the main loop, so that changes to the algorithm
+//! can be tested against real market activity.
+//!
+//! The logs do not record when each message arrived, so we take the time
+//! from the messages themselves: LX orders and busts carry a timestamp, as
+//! do Coinbase tickers. Other messages are given the time of the message
+//! before them in the same log, and each log is kept in its own order even
+//! if its timestamps are not. The logs are then merged in time order.
+//!
+//! The main loop runs in simulated time, which is the time of the latest
+//! message replayed. Messages which the live loop would get from a timer
+//! (the clock thread's heartbeats, and delayed heartbeats) are instead
+//! scheduled on the simulated clock, and messages which the main loop sends
+//! itself are handled before the next replayed message, so that a replay is
+//! deterministic.
+//!
+
+use crate::coinbase;
+use crate::connect::{self, Message};
+use crate::ledgerx::datafeed;
+use crate::price::BitcoinPrice;
+use crate::queue;
+use crate::units::UtcTime;
+use anyhow::Context as _;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::{fmt, mem};
+
+/// Parses a line of a log, returning the message it contains and its time,
+/// if the message has one
+///
+/// Blank lines, and Coinbase messages other than tickers, give `None`.
+fn parse_line(line: &str) -> Result<Option<(Option<UtcTime>, Message)>, serde_json::Error> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(price) = coinbase::parse_message(line) {
+        return Ok(price.map(|price| {
+            (
+                Some(price.timestamp),
+                Message::PriceReference(coinbase::PRICE_SOURCE, price),
+            )
+        }));
+    }
+    let obj: datafeed::Object = serde_json::from_str(line)?;
+    let time = match obj {
+        datafeed::Object::Order(ref order) => Some(order.timestamp),
+        datafeed::Object::TradeBusted(ref bust) => Some(bust.timestamp),
+        _ => None,
+    };
+    Ok(Some((time, Message::LedgerX(obj))))
+}
+
+/// A single log being replayed
+struct Feed {
+    /// Name of the log, for error messages
+    name: String,
+    lines: io::Lines<Box<dyn BufRead>>,
+    /// Number of lines read so far
+    line_no: usize,
+    /// Time of the most recently read message
+    last_time: Option<UtcTime>,
+    /// Messages read, but not yet replayed, with their times
+    pending: VecDeque<(UtcTime, Message)>,
+    /// Messages read before any message with a time
+    untimed: Vec<Message>,
+}
+
+impl Feed {
+    /// Starts replaying a log
+    fn new(name: String, reader: Box<dyn BufRead>) -> Self {
+        Feed {
+            name,
+            lines: reader.lines(),
+            line_no: 0,
+            last_time: None,
+            pending: VecDeque::new(),
+            untimed: vec![],
+        }
+    }
+
+    /// Opens a log file, which may be gzipped (as rotated logs are)
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(BufReader::new(flate2::read::GzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        Ok(Feed::new(path.display().to_string(), reader))
+    }
+
+    /// Time of the next message, reading ahead as necessary; `None` once the
+    /// log is exhausted
+    fn peek_time(&mut self) -> Option<UtcTime> {
+        while self.pending.is_empty() {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => {
+                    warn!("Failed to read {}: {}", self.name, e);
+                    break;
+                }
+                None => break,
+            };
+            self.line_no += 1;
+            match parse_line(&line) {
+                Ok(Some((Some(time), msg))) => {
+                    // Keep the log in order, even if its timestamps are not
+                    let time = self.last_time.map_or(time, |last| last.max(time));
+                    self.last_time = Some(time);
+                    for untimed in mem::take(&mut self.untimed) {
+                        self.pending.push_back((time, untimed));
+                    }
+                    self.pending.push_back((time, msg));
+                }
+                Ok(Some((None, msg))) => match self.last_time {
+                    Some(time) => self.pending.push_back((time, msg)),
+                    None => self.untimed.push(msg),
+                },
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Skipping unparseable line {} of {}: {}",
+                    self.line_no, self.name, e
+                ),
+            }
+        }
+        if self.pending.is_empty() && !self.untimed.is_empty() {
+            warn!(
+                "Dropping {} messages from {}, which has no timestamps",
+                self.untimed.len(),
+                self.name
+            );
+            self.untimed.clear();
+        }
+        self.pending.front().map(|(time, _)| *time)
+    }
+}
+
+/// A replay of one or more logs in simulated time
+pub struct Replay {
+    feeds: Vec<Feed>,
+    /// Messages scheduled for later delivery, keyed by time and then by the
+    /// order in which they were scheduled
+    scheduled: BTreeMap<(UtcTime, usize), Message>,
+    /// Number of messages scheduled so far
+    n_scheduled: usize,
+    /// Time of the next heartbeat from the simulated clock thread
+    next_clock_heartbeat: Option<UtcTime>,
+    /// The current simulated time
+    now: Option<UtcTime>,
+    /// Number of logged messages replayed so far
+    n_replayed: usize,
+}
+
+impl Replay {
+    /// Opens a set of logs for replay
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> anyhow::Result<Self> {
+        let feeds = paths
+            .iter()
+            .map(|path| Feed::open(path.as_ref()))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Replay::from_feeds(feeds))
+    }
+
+    fn from_feeds(feeds: Vec<Feed>) -> Self {
+        Replay {
+            feeds,
+            scheduled: BTreeMap::new(),
+            n_scheduled: 0,
+            next_clock_heartbeat: None,
+            now: None,
+            n_replayed: 0,
+        }
+    }
+
+    /// The current simulated time, if the replay has started
+    pub fn now(&self) -> Option<UtcTime> {
+        self.now
+    }
+
+    /// Skips forward to the first price reference, which starts the replay
+    ///
+    /// Returns `None` if the logs contain no price references.
+    pub fn initial_price(&mut self) -> Option<(&'static str, BitcoinPrice)> {
+        let mut skipped = 0;
+        loop {
+            match self.next_logged()? {
+                Message::PriceReference(source, price) => {
+                    if skipped > 0 {
+                        info!("Skipped {} messages before the first price.", skipped);
+                    }
+                    self.next_clock_heartbeat = self
+                        .now
+                        .map(|now| now + chrono::Duration::minutes(connect::CLOCK_HEARTBEAT_MINS));
+                    return Some((source, price));
+                }
+                _ => skipped += 1,
+            }
+        }
+    }
+
+    /// Schedules a message to be delivered at the given simulated time
+    pub fn schedule(&mut self, time: UtcTime, msg: Message) {
+        self.scheduled.insert((time, self.n_scheduled), msg);
+        self.n_scheduled += 1;
+    }
+
+    /// Returns the next message for the main loop
+    ///
+    /// Messages which the main loop has sent itself come first, at the current
+    /// simulated time; except for delayed heartbeats, which are scheduled for
+    /// when they are due. Otherwise, returns whichever is earliest of the next
+    /// logged message, the next scheduled message and the next clock
+    /// heartbeat, advancing the simulated time to match. Returns `None` once
+    /// the logs are exhausted.
+    pub fn next_message(&mut self, rx: &queue::Receiver<Message>) -> Option<Message> {
+        while let Some(msg) = rx.try_recv() {
+            match msg {
+                Message::DelayedHeartbeat {
+                    delay_til,
+                    ready: false,
+                } => self.schedule(
+                    delay_til,
+                    Message::DelayedHeartbeat {
+                        delay_til,
+                        ready: true,
+                    },
+                ),
+                msg => return Some(msg),
+            }
+        }
+
+        let logged_time = self.peek_logged_time()?;
+        if let Some(&(time, n)) = self.scheduled.keys().next() {
+            let before_heartbeat = match self.next_clock_heartbeat {
+                Some(hb) => time <= hb,
+                None => true,
+            };
+            if time <= logged_time && before_heartbeat {
+                self.advance(time);
+                return self.scheduled.remove(&(time, n));
+            }
+        }
+        if let Some(time) = self.next_clock_heartbeat.filter(|hb| *hb <= logged_time) {
+            self.advance(time);
+            self.next_clock_heartbeat =
+                Some(time + chrono::Duration::minutes(connect::CLOCK_HEARTBEAT_MINS));
+            return Some(Message::Heartbeat);
+        }
+        self.next_logged()
+    }
+
+    /// Time of the next logged message, if any
+    fn peek_logged_time(&mut self) -> Option<UtcTime> {
+        self.feeds.iter_mut().filter_map(Feed::peek_time).min()
+    }
+
+    /// Takes the next logged message, advancing the simulated time to match
+    fn next_logged(&mut self) -> Option<Message> {
+        // Ties go to the log given first
+        let (time, idx) = self
+            .feeds
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(idx, feed)| Some((feed.peek_time()?, idx)))
+            .min()?;
+        let (_, msg) = self.feeds[idx].pending.pop_front()?;
+        self.advance(time);
+        self.n_replayed += 1;
+        if self.n_replayed.is_multiple_of(100_000) {
+            debug!("Replayed {} messages, up to {}", self.n_replayed, time);
+        }
+        Some(msg)
+    }
+
+    /// Moves the simulated time forward (never backward)
+    fn advance(&mut self, time: UtcTime) {
+        self.now = Some(self.now.map_or(time, |now| now.max(time)));
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages from {} logs",
+            self.n_replayed,
+            self.feeds.len()
+        )?;
+        if let Some(now) = self.now {
+            write!(f, ", up to {now}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_and_schedule() {
+        let time = |s: &str| UtcTime::parse_coinbase(&format!("2023-01-29T{s}Z")).unwrap();
+        let ticker = |s: &str, price: &str| {
+            format!(
+                r#"{{"type":"ticker","best_bid":"{price}","best_ask":"{price}","time":"2023-01-29T{s}Z"}}"#
+            )
+        };
+        // The datafeed runs from 14:06:55 to 14:07:26
+        let coinbase = [
+            r#"{"type":"subscriptions","channels":[{"name":"ticker","product_ids":["BTC-USD"]}]}"#
+                .to_string(),
+            ticker("14:06:50", "23000"),
+            ticker("14:07:00", "23010"),
+            "not json".to_string(),
+            ticker("16:30:00", "23100"),
+        ]
+        .join("\n");
+        let datafeed = Feed::open(Path::new("src/ledgerx/test-datafeed.json")).unwrap();
+        let coinbase = Feed::new(
+            "coinbase".into(),
+            Box::new(io::Cursor::new(coinbase.into_bytes())),
+        );
+        let mut replay = Replay::from_feeds(vec![datafeed, coinbase]);
+
+        let (source, price) = replay.initial_price().unwrap();
+        assert_eq!(source, coinbase::PRICE_SOURCE);
+        assert_eq!(price.timestamp, time("14:06:50"));
+        assert_eq!(replay.now(), Some(time("14:06:50")));
+
+        // Messages sent by the main loop come first; delayed heartbeats wait
+        let (tx, rx) = queue::bounded(10);
+        tx.send(Message::DelayedHeartbeat {
+            delay_til: time("14:07:10"),
+            ready: false,
+        })
+        .unwrap();
+        tx.send(Message::Heartbeat).unwrap();
+        assert!(matches!(replay.next_message(&rx), Some(Message::Heartbeat)));
+        assert_eq!(replay.now(), Some(time("14:06:50")));
+
+        let mut last = replay.now().unwrap();
+        let (mut n_lx, mut prices, mut heartbeats) = (0, vec![], vec![]);
+        while let Some(msg) = replay.next_message(&rx) {
+            let now = replay.now().unwrap();
+            assert!(now >= last);
+            last = now;
+            match msg {
+                Message::LedgerX(_) => n_lx += 1,
+                Message::PriceReference(_, price) => prices.push(price.timestamp),
+                Message::DelayedHeartbeat { ready: true, .. } => heartbeats.push(("delayed", now)),
+                Message::Heartbeat => heartbeats.push(("clock", now)),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+        // Every line of the datafeed is replayed
+        assert_eq!(n_lx, 4061);
+        assert_eq!(prices, [time("14:07:00"), time("16:30:00")]);
+        assert_eq!(
+            heartbeats,
+            [("delayed", time("14:07:10")), ("clock", time("16:06:50"))],
+        );
+        assert_eq!(replay.now(), Some(time("16:30:00")));
+        assert_eq!(
+            replay.to_string(),
+            "4064 messages from 2 logs, up to 2023-01-29 16:30:00 UTC"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Resimulation
+//!
+//! With `connect --replay ... --compare <record file>`, the current strategy
+//! is run over the logs of a past session, and the standing orders it would
+//! have placed are compared against those which the session actually placed,
+//! as given by its event stream. The result is a report of what changed in
+//! our behaviour: which orders we would no longer place, which we would newly
+//! place, and which we would place at a different size or price, along with
+//! how our heartbeat decisions differ.
+//!
+//! The simulated and actual heartbeats do not fall at exactly the same times,
+//! so we split the replay into windows at the actual session's heartbeats, and
+//! put each order, actual or simulated, into the window in which it was placed.
+//! Within each window, orders are matched up by contract and side.
+//!
+
+use crate::schema::{Body, DecisionKind, OrderAction, Record};
+use crate::units::{Price, UtcTime};
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Orders opened on one side of one contract, as (size, price)
+type Orders = Vec<(i64, Price)>;
+
+/// The start of a heartbeat window, a contract ID and a side
+type Key = (UtcTime, String, String);
+
+/// The orders opened and decisions made in a session, within some time span
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+struct Session {
+    /// Opened orders, keyed by heartbeat window, contract ID and side
+    orders: BTreeMap<Key, Orders>,
+    /// Number of heartbeats with each decision
+    decisions: BTreeMap<DecisionKind, usize>,
+}
+
+/// Times of the heartbeat decisions among the records between `start` and
+/// `end`, inclusive, in order
+fn heartbeat_times(
+    records: &[Record],
+    start: UtcTime,
+    end: UtcTime,
+) -> anyhow::Result<Vec<UtcTime>> {
+    let mut ret = vec![];
+    for record in records {
+        if let Body::Decision(_) = record.body {
+            let time = record.parse_time()?;
+            if time >= start && time <= end {
+                ret.push(time);
+            }
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+impl Session {
+    /// Collects the orders and decisions among the records between `start`
+    /// and `end`, inclusive, putting each order in the window starting at
+    /// the last of `windows` before it (or at `start`)
+    fn from_records(
+        records: &[Record],
+        start: UtcTime,
+        end: UtcTime,
+        windows: &[UtcTime],
+    ) -> anyhow::Result<Self> {
+        let mut ret = Session::default();
+        for record in records {
+            let time = record.parse_time()?;
+            if time < start || time > end {
+                continue;
+            }
+            let window = match windows.partition_point(|w| *w <= time) {
+                0 => start,
+                n => windows[n - 1],
+            };
+            match record.body {
+                Body::Decision(ref decision) => {
+                    *ret.decisions.entry(decision.decision).or_default() += 1;
+                }
+                Body::Order(ref order) if order.action == OrderAction::Opened => {
+                    if let (Some(cid), Some(side), Some(size), Some(price)) = (
+                        order.contract_id.as_ref(),
+                        order.side.as_ref(),
+                        order.size,
+                        order.price.as_ref(),
+                    ) {
+                        let price = Price::from_str(price).map_err(|e| {
+                            anyhow::Error::msg(format!("parsing order price {price}: {e:?}"))
+                        })?;
+                        ret.orders
+                            .entry((window, cid.clone(), side.clone()))
+                            .or_default()
+                            .push((size, price));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for orders in ret.orders.values_mut() {
+            orders.sort();
+        }
+        Ok(ret)
+    }
+}
+
+/// Describes a set of orders on one side of one contract
+fn describe(orders: &[(i64, Price)]) -> String {
+    let (min, max) = match (
+        orders.iter().map(|o| o.1).min(),
+        orders.iter().map(|o| o.1).max(),
+    ) {
+        (Some(min), Some(max)) => (min, max),
+        _ => return "none".into(),
+    };
+    let size: i64 = orders.iter().map(|o| o.0).sum();
+    let range = if min == max {
+        format!("${min}")
+    } else {
+        format!("${min}-${max}")
+    };
+    format!("{} orders, {} contracts at {}", orders.len(), size, range)
+}
+
+/// A difference between the orders actually placed on one side of one
+/// contract and the orders the current strategy would have placed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Difference {
+    /// Start of the heartbeat window in which the orders were placed
+    pub window: UtcTime,
+    /// The LX contract ID
+    pub contract_id: String,
+    /// "bid" or "ask"
+    pub side: String,
+    /// The orders actually placed, as (size, price); empty if none were
+    pub actual: Vec<(i64, Price)>,
+    /// The orders the current strategy would have placed
+    pub simulated: Vec<(i64, Price)>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = if self.actual.is_empty() {
+            "newly placed"
+        } else if self.simulated.is_empty() {
+            "no longer placed"
+        } else {
+            "changed"
+        };
+        write!(
+            f,
+            "window from {}: contract {} {} {}: actually {}; resimulated {}",
+            self.window,
+            self.contract_id,
+            self.side,
+            what,
+            describe(&self.actual),
+            describe(&self.simulated),
+        )
+    }
+}
+
+/// The changes in our behaviour between a past session and a resimulation
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    /// Start of the span compared
+    pub start: UtcTime,
+    /// End of the span compared
+    pub end: UtcTime,
+    /// Number of heartbeats with each decision, actually and resimulated
+    pub decisions: BTreeMap<DecisionKind, (usize, usize)>,
+    /// Number of contract sides, within each heartbeat window, on which
+    /// exactly the same orders were placed
+    pub unchanged: usize,
+    /// The contract sides and windows in which different orders were placed
+    pub differences: Vec<Difference>,
+}
+
+impl Report {
+    /// Compares the actual records of a session against those of its
+    /// resimulation, over the span of the resimulation
+    pub fn new(
+        actual: &[Record],
+        simulated: &[Record],
+        start: UtcTime,
+        end: UtcTime,
+    ) -> anyhow::Result<Self> {
+        let windows = heartbeat_times(actual, start, end)?;
+        let actual = Session::from_records(actual, start, end, &windows)?;
+        let simulated = Session::from_records(simulated, start, end, &windows)?;
+
+        let mut decisions = BTreeMap::new();
+        for (kind, n) in &actual.decisions {
+            decisions.entry(*kind).or_insert((0, 0)).0 = *n;
+        }
+        for (kind, n) in &simulated.decisions {
+            decisions.entry(*kind).or_insert((0, 0)).1 = *n;
+        }
+
+        let mut keys: Vec<&Key> = actual.orders.keys().collect();
+        keys.extend(simulated.orders.keys());
+        keys.sort();
+        keys.dedup();
+        let mut unchanged = 0;
+        let mut differences = vec![];
+        for key in keys {
+            let actual = actual.orders.get(key).cloned().unwrap_or_default();
+            let simulated = simulated.orders.get(key).cloned().unwrap_or_default();
+            if actual == simulated {
+                unchanged += 1;
+            } else {
+                differences.push(Difference {
+                    window: key.0,
+                    contract_id: key.1.clone(),
+                    side: key.2.clone(),
+                    actual,
+                    simulated,
+                });
+            }
+        }
+
+        Ok(Report {
+            start,
+            end,
+            decisions,
+            unchanged,
+            differences,
+        })
+    }
+
+    /// Outputs the report
+    pub fn log(&self) {
+        info!("Resimulation of {} to {}:", self.start, self.end);
+        for (kind, (actual, simulated)) in &self.decisions {
+            let marker = if actual == simulated {
+                ""
+            } else {
+                " (changed)"
+            };
+            info!(
+                "    {:?} heartbeats: actually {}, resimulated {}{}",
+                kind, actual, simulated, marker
+            );
+        }
+        info!(
+            "    {} contract sides and windows with identical orders, {} with differences",
+            self.unchanged,
+            self.differences.len()
+        );
+        for diff in &self.differences {
+            info!("    {}", diff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::HeartbeatDecision;
+    use crate::schema::{Order, SCHEMA_VERSION};
+
+    #[test]
+    fn compare_sessions() {
+        let time = |s: &str| UtcTime::parse_coinbase(&format!("2024-03-01T{s}Z")).unwrap();
+        let price = |s: &str| Price::from_str(s).unwrap();
+        let btc = price("61000");
+        let order = |t: &str, cid: usize, size: i64, p: &str| Record {
+            schema_version: SCHEMA_VERSION,
+            time: format!("2024-03-01T{t}Z"),
+            body: Body::Order(Order {
+                action: OrderAction::Opened,
+                contract_id: Some(cid.to_string()),
+                order_id: None,
+                side: Some("ask".into()),
+                size: Some(size),
+                price: Some(p.into()),
+            }),
+        };
+        let decision = |t: &str, decision| Record::decision(time(t), decision, btc, 0, None);
+
+        let actual = [
+            // Before the replay starts; ignored
+            order("13:00:00", 1, 5, "1000"),
+            decision("15:00:00", HeartbeatDecision::Traded),
+            order("15:00:01", 1, 5, "1250"),
+            order("15:00:01", 2, 3, "800"),
+            order("15:00:01", 3, 1, "400"),
+            decision("16:00:00", HeartbeatDecision::Traded),
+            order("16:00:01", 2, 3, "810"),
+        ];
+        let simulated = [
+            decision("15:05:00", HeartbeatDecision::Traded),
+            order("15:05:01", 1, 5, "1250"),
+            order("15:05:01", 2, 3, "800"),
+            order("15:05:01", 4, 2, "300"),
+            decision("16:05:00", HeartbeatDecision::LossLimit),
+        ];
+        let report = Report::new(&actual, &simulated, time("14:00:00"), time("17:00:00")).unwrap();
+
+        assert_eq!(report.unchanged, 2);
+        assert_eq!(report.decisions.get(&DecisionKind::Traded), Some(&(2, 1)));
+        assert_eq!(
+            report.decisions.get(&DecisionKind::LossLimit),
+            Some(&(0, 1))
+        );
+        let diffs: Vec<String> = report.differences.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diffs,
+            [
+                "window from 2024-03-01 15:00:00 UTC: contract 3 ask no longer placed: actually 1 orders, 1 contracts at $400.00; resimulated none",
+                "window from 2024-03-01 15:00:00 UTC: contract 4 ask newly placed: actually none; resimulated 1 orders, 2 contracts at $300.00",
+                "window from 2024-03-01 16:00:00 UTC: contract 2 ask no longer placed: actually 1 orders, 3 contracts at $810.00; resimulated none",
+            ],
+        );
+    }
+}
@@ -0,0 +1,786 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Machine-Readable Records
+//!
+//! Records written as newline-delimited JSON, for consumption by external
+//! scripts: the decisions, closes and fills logged by `connect` and `close`,
+//! the live updates streamed by `connect --emit-events`, and the account
+//! events output by `history --output-format json`. These are deliberately
+//! kept separate from our internal data structures, and use only strings and
+//! integers, so that refactoring the rest of the codebase does not change the
+//! output. Any change to the format of records must bump [`SCHEMA_VERSION`];
+//! the golden files in this directory exist to catch accidental changes.
+//!
+//! Version 2 added the `close`, `price`, `order`, `balances`, `alert` and
+//! `bust` records, the `arr_reference` field of decisions and the
+//! `executions` field of events. Every version 1 record is still a valid
+//! version 2 record.
+//!
+
+use crate::activity::HeartbeatDecision;
+use crate::ledgerx::close;
+use crate::ledgerx::history;
+use crate::ledgerx::interesting::ArrReference;
+use crate::ledgerx::json::CreateOrder;
+use crate::ledgerx::slippage;
+use crate::ledgerx::{datafeed, ContractId, MessageId};
+use crate::units::{DepositAsset, Price, Quantity, TaxAsset, UtcTime};
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+
+/// Version of the record format, included in every record
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// A single record, output as one line of JSON
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// Version of the format of this record
+    pub schema_version: u32,
+    /// Time the record refers to, in RFC 3339 format
+    pub time: String,
+    /// The record itself, tagged by a `type` field
+    #[serde(flatten)]
+    pub body: Body,
+}
+
+/// The contents of a record
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Body {
+    /// What the `connect` main loop decided to do on a heartbeat
+    Decision(Decision),
+    /// A decision to close a short position by taking offers
+    Close(Close),
+    /// One of our orders being filled
+    Fill(Fill),
+    /// An event in our account history
+    Event(Event),
+    /// A change in the BTC price reference
+    Price(PriceUpdate),
+    /// One of our orders being opened or cancelled
+    Order(Order),
+    /// A change in our available balances
+    Balances(Balances),
+    /// A notification sent to the phone
+    Alert(Alert),
+    /// A fill of one of our orders being busted (reversed) by LX
+    Bust(Bust),
+}
+
+/// What the `connect` main loop decided to do on a heartbeat
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Decision {
+    /// The decision
+    pub decision: DecisionKind,
+    /// BTC price reference at the time of the decision
+    pub btc_price: String,
+    /// Number of our orders open when the decision was made
+    pub open_orders: u64,
+    /// The time from which the returns of our standing asks were annualized,
+    /// e.g. last-friday, if we opened any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arr_reference: Option<String>,
+}
+
+/// The possible heartbeat decisions
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionKind {
+    /// Market was open and we (re)opened our orders
+    Traded,
+    /// Market was closed, so we did nothing
+    MarketClosed,
+    /// Market was open but the kill switch was engaged
+    KillSwitch,
+    /// Market was open but the exchange was degraded or halted
+    ExchangeDegraded,
+    /// Market was open but our balances had not been synced recently
+    StaleBalances,
+    /// Market was open but we were outside of our trading windows
+    OutsideTradingWindow,
+    /// Market was open but we had hit our daily loss limit
+    LossLimit,
+}
+
+impl From<HeartbeatDecision> for DecisionKind {
+    fn from(decision: HeartbeatDecision) -> Self {
+        match decision {
+            HeartbeatDecision::Traded => DecisionKind::Traded,
+            HeartbeatDecision::MarketClosed => DecisionKind::MarketClosed,
+            HeartbeatDecision::KillSwitch => DecisionKind::KillSwitch,
+            HeartbeatDecision::ExchangeDegraded => DecisionKind::ExchangeDegraded,
+            HeartbeatDecision::StaleBalances => DecisionKind::StaleBalances,
+            HeartbeatDecision::OutsideTradingWindow => DecisionKind::OutsideTradingWindow,
+            HeartbeatDecision::LossLimit => DecisionKind::LossLimit,
+        }
+    }
+}
+
+/// A decision to close a short position by taking offers
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Close {
+    /// The option being bought back, e.g. 2024-03-29P50000.00
+    pub option: String,
+    /// Number of contracts we were short
+    pub position: i64,
+    /// Number of contracts we decided to buy back
+    pub size: i64,
+    /// Model price of the option at the time of the decision
+    pub model_price: String,
+    /// The most we were willing to pay
+    pub max_price: String,
+    /// Total cost of the offers we decided to take
+    pub cost: String,
+    /// BTC price reference at the time of the decision
+    pub btc_price: String,
+}
+
+/// One of our orders being filled
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Fill {
+    /// The option which was traded, e.g. 2024-03-29P50000.00
+    pub option: String,
+    /// Number of contracts filled (negative for asks)
+    pub size: i64,
+    /// Limit price of the order
+    pub limit_price: String,
+    /// Price at which the order filled
+    pub fill_price: String,
+    /// Time at which the order was created, in RFC 3339 format
+    pub decision_time: String,
+    /// BTC price reference at order creation
+    pub decision_btc: String,
+    /// BTC price reference at fill time
+    pub fill_btc: String,
+}
+
+/// A change in the BTC price reference
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PriceUpdate {
+    /// The new BTC price
+    pub btc_price: String,
+}
+
+/// One of our orders being opened or cancelled
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Order {
+    /// What happened to the order
+    pub action: OrderAction,
+    /// The LX contract ID, unless all orders were cancelled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_id: Option<String>,
+    /// For cancellations of a single order, its message ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    /// For opened orders, "bid" or "ask"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<String>,
+    /// For opened orders, the number of contracts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+    /// For opened orders, the limit price
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
+}
+
+/// The possible things which can happen to our orders
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderAction {
+    /// An order was submitted
+    Opened,
+    /// A single order was cancelled
+    Cancelled,
+    /// All our orders were cancelled
+    CancelledAll,
+}
+
+/// A change in our available balances
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Balances {
+    /// Available USD
+    pub usd: String,
+    /// Available BTC
+    pub btc: String,
+}
+
+/// A fill of one of our orders being busted (reversed) by LX
+///
+/// LX may still report the fill among our trades, so the history uses these
+/// records to leave busted trades out.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Bust {
+    /// The LX contract ID
+    pub contract_id: String,
+    /// Message ID of the order which was filled
+    pub order_id: String,
+    /// Number of contracts whose fill was reversed, in LX's base units
+    /// (negative for asks)
+    pub size: i64,
+    /// Price at which the reversed fill happened
+    pub price: String,
+}
+
+/// A notification sent to the phone
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Alert {
+    /// The text of the notification
+    pub message: String,
+}
+
+/// An event in our account history
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Event {
+    /// The kind of event
+    pub kind: EventKind,
+    /// The asset moved by the event
+    pub asset: String,
+    /// Amount of the asset moved, in BTC, USD or contracts depending on the asset
+    pub size: String,
+    /// For trades, the price per unit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
+    /// For trades and assignments, the fee charged to us (negative for a rebate)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<String>,
+    /// For a merged fill, the raw trades it was merged from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executions: Option<Vec<Execution>>,
+}
+
+/// One of the raw trades making up a merged fill
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Execution {
+    /// Time of the trade, in RFC 3339 format
+    pub time: String,
+    /// Number of contracts (or BTC) traded
+    pub size: String,
+    /// The fee charged to us (negative for a rebate)
+    pub fee: String,
+}
+
+/// The possible kinds of account events
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A USD deposit
+    UsdDeposit,
+    /// A BTC deposit
+    BtcDeposit,
+    /// An ETH deposit
+    EthDeposit,
+    /// A withdrawal of any asset
+    Withdrawal,
+    /// A trade
+    Trade,
+    /// Assignment of an option at expiry
+    Assignment,
+    /// Expiry of an option out of the money
+    Expiry,
+}
+
+/// Formats a time as RFC 3339, the format used for all times in records
+fn time_str(time: UtcTime) -> String {
+    time.format("%FT%TZ").to_string()
+}
+
+/// Formats a quantity as a bare number, leaving the units to the asset
+fn quantity_str(qty: Quantity) -> String {
+    match qty {
+        Quantity::Bitcoin(btc) => btc.to_string_in(bitcoin::Denomination::Bitcoin),
+        Quantity::Contracts(n) | Quantity::EthContracts(n) => n.to_string(),
+        Quantity::Cents(n) => Price::from_cents(n).to_string(),
+        Quantity::Ether(n) => rust_decimal::Decimal::new(n, 9).normalize().to_string(),
+        Quantity::Zero => "0".into(),
+    }
+}
+
+impl Record {
+    /// Constructs a record of a heartbeat decision
+    ///
+    /// The ARR reference should be given whenever standing asks were priced.
+    pub fn decision(
+        time: UtcTime,
+        decision: HeartbeatDecision,
+        btc_price: Price,
+        open_orders: usize,
+        arr_reference: Option<ArrReference>,
+    ) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Decision(Decision {
+                decision: decision.into(),
+                btc_price: btc_price.to_string(),
+                open_orders: open_orders as u64,
+                arr_reference: arr_reference.map(|r| r.to_string()),
+            }),
+        }
+    }
+
+    /// Constructs a record of a decision to close a short position
+    pub fn close(time: UtcTime, plan: &close::Plan, btc_price: Price) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Close(Close {
+                option: plan.option.to_string(),
+                position: plan.position,
+                size: plan.size(),
+                model_price: plan.model_price.to_string(),
+                max_price: plan.max_price.to_string(),
+                cost: plan.cost().to_string(),
+                btc_price: btc_price.to_string(),
+            }),
+        }
+    }
+
+    /// Constructs a record of a fill
+    pub fn fill(fill: &slippage::Fill) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(fill.fill_time),
+            body: Body::Fill(Fill {
+                option: fill.option.to_string(),
+                size: fill.size,
+                limit_price: fill.limit_price.to_string(),
+                fill_price: fill.fill_price.to_string(),
+                decision_time: time_str(fill.decision_time),
+                decision_btc: fill.decision_btc.to_string(),
+                fill_btc: fill.fill_btc.to_string(),
+            }),
+        }
+    }
+
+    /// Constructs a record of an account event
+    pub fn event(time: UtcTime, event: &history::Event) -> Self {
+        let (kind, asset, size, price, fee) = match *event {
+            history::Event::UsdDeposit { amount, .. } => {
+                (EventKind::UsdDeposit, "USD".into(), amount, None, None)
+            }
+            history::Event::BtcDeposit { amount, .. }
+            | history::Event::BtcRedeposit { amount, .. } => (
+                EventKind::BtcDeposit,
+                "BTC".into(),
+                amount.into(),
+                None,
+                None,
+            ),
+            history::Event::EthDeposit { amount, .. } => {
+                (EventKind::EthDeposit, "ETH".into(), amount, None, None)
+            }
+            history::Event::Withdrawal { amount, asset, .. } => {
+                let asset = match asset {
+                    DepositAsset::Btc => "BTC",
+                    DepositAsset::Eth => "ETH",
+                    DepositAsset::Usd => "USD",
+                };
+                (EventKind::Withdrawal, asset.into(), amount, None, None)
+            }
+            history::Event::Trade {
+                asset,
+                price,
+                size,
+                fee,
+            } => (
+                EventKind::Trade,
+                asset.to_string(),
+                size,
+                Some(price.to_string()),
+                Some(fee.to_string()),
+            ),
+            history::Event::Assignment {
+                option,
+                underlying,
+                contract_size,
+                size,
+                ..
+            }
+            | history::Event::Expiry {
+                option,
+                underlying,
+                contract_size,
+                size,
+            } => {
+                let kind = if let history::Event::Expiry { .. } = event {
+                    EventKind::Expiry
+                } else {
+                    EventKind::Assignment
+                };
+                let asset = TaxAsset::Option {
+                    underlying,
+                    option,
+                    contract_size,
+                };
+                let fee = match *event {
+                    history::Event::Assignment { fee, .. } if fee != Price::ZERO => {
+                        Some(fee.to_string())
+                    }
+                    _ => None,
+                };
+                (kind, asset.to_string(), size, None, fee)
+            }
+        };
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Event(Event {
+                kind,
+                asset,
+                size: quantity_str(size),
+                price,
+                fee,
+                executions: None,
+            }),
+        }
+    }
+
+    /// Constructs a record of an event in the merged view of the history
+    ///
+    /// For a merged fill, the raw trades are listed in the record.
+    pub fn merged_event(entry: &history::fills::Entry) -> Self {
+        let mut record = Record::event(entry.date, &entry.event);
+        if entry.executions.is_empty() {
+            return record;
+        }
+        if let Body::Event(ref mut event) = record.body {
+            let executions = entry
+                .executions
+                .iter()
+                .filter_map(|(time, event)| match **event {
+                    history::Event::Trade { size, fee, .. } => Some(Execution {
+                        time: time_str(*time),
+                        size: quantity_str(size),
+                        fee: fee.to_string(),
+                    }),
+                    _ => None,
+                })
+                .collect();
+            event.executions = Some(executions);
+        }
+        record
+    }
+
+    /// Constructs a record of a change in the BTC price reference
+    pub fn price(time: UtcTime, btc_price: Price) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Price(PriceUpdate {
+                btc_price: btc_price.to_string(),
+            }),
+        }
+    }
+
+    /// Constructs a record of one of our orders being submitted
+    pub fn order_opened(time: UtcTime, order: &CreateOrder) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Order(Order {
+                action: OrderAction::Opened,
+                contract_id: Some(order.contract_id().to_string()),
+                order_id: None,
+                side: Some(if order.is_ask() { "ask" } else { "bid" }.into()),
+                size: Some(order.size()),
+                price: Some(order.price().to_string()),
+            }),
+        }
+    }
+
+    /// Constructs a record of one of our orders being cancelled, or all of
+    /// them if no order is given
+    pub fn order_cancelled(time: UtcTime, order: Option<(MessageId, ContractId)>) -> Self {
+        let (action, contract_id, order_id) = match order {
+            Some((message_id, contract_id)) => (
+                OrderAction::Cancelled,
+                Some(contract_id.to_string()),
+                Some(message_id.to_string()),
+            ),
+            None => (OrderAction::CancelledAll, None, None),
+        };
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Order(Order {
+                action,
+                contract_id,
+                order_id,
+                side: None,
+                size: None,
+                price: None,
+            }),
+        }
+    }
+
+    /// Constructs a record of our available balances
+    pub fn balances(time: UtcTime, usd: Price, btc: bitcoin::Amount) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Balances(Balances {
+                usd: usd.to_string(),
+                btc: btc.to_string_in(bitcoin::Denomination::Bitcoin),
+            }),
+        }
+    }
+
+    /// Constructs a record of a notification
+    pub fn alert(time: UtcTime, message: &str) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(time),
+            body: Body::Alert(Alert {
+                message: message.to_owned(),
+            }),
+        }
+    }
+
+    /// Constructs a record of a bust of one of our fills
+    pub fn bust(bust: &datafeed::Bust) -> Self {
+        Record {
+            schema_version: SCHEMA_VERSION,
+            time: time_str(bust.timestamp),
+            body: Body::Bust(Bust {
+                contract_id: bust.contract_id.to_string(),
+                order_id: bust.message_id.to_string(),
+                size: bust.size.to_i64(),
+                price: bust.price.to_string(),
+            }),
+        }
+    }
+
+    /// Appends the record, as a line of JSON, to a file
+    pub fn append_to(&self, path: &Path) -> anyhow::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening record file {}", path.display()))?;
+        writeln!(file, "{self}").with_context(|| format!("writing to {}", path.display()))
+    }
+
+    /// Reads a file of records, one line of JSON each, as written by
+    /// [`Record::append_to`] or the event stream
+    pub fn read_all(path: &Path) -> anyhow::Result<Vec<Record>> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("reading record file {}", path.display()))?;
+        data.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(n, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("parsing line {} of {}", n + 1, path.display()))
+            })
+            .collect()
+    }
+
+    /// Reads the records of a single type from a file of records
+    ///
+    /// Unlike [`Record::read_all`], lines which cannot be parsed are skipped
+    /// with a warning, unless they are of the type wanted.
+    pub fn read_type(path: &Path, ty: &str) -> anyhow::Result<Vec<Record>> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("reading record file {}", path.display()))?;
+        let mut ret = vec![];
+        for (n, line) in data.lines().enumerate() {
+            let json: serde_json::Value = match serde_json::from_str(line) {
+                Ok(json) => json,
+                Err(_) if line.trim().is_empty() => continue,
+                Err(e) => {
+                    warn!("Skipping line {} of {}: {}", n + 1, path.display(), e);
+                    continue;
+                }
+            };
+            if json.get("type").and_then(serde_json::Value::as_str) != Some(ty) {
+                continue;
+            }
+            let record = serde_json::from_value(json)
+                .with_context(|| format!("parsing line {} of {}", n + 1, path.display()))?;
+            ret.push(record);
+        }
+        Ok(ret)
+    }
+
+    /// Parses the time of the record
+    pub fn parse_time(&self) -> anyhow::Result<UtcTime> {
+        UtcTime::parse_coinbase(&self.time)
+            .with_context(|| format!("parsing record time {}", self.time))
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{ContractSize, Underlying};
+    use std::str::FromStr;
+
+    #[test]
+    fn golden() {
+        let time = |s: &str| UtcTime::parse_coinbase(s).unwrap();
+        let price = |s: &str| Price::from_str(s).unwrap();
+        let option = crate::option::Option::from_str("2024-03-29P50000").unwrap();
+
+        let fill = slippage::Fill {
+            option,
+            size: -3,
+            limit_price: price("1250"),
+            fill_price: price("1262.5"),
+            decision_time: time("2024-03-01T15:00:00Z"),
+            decision_btc: price("61000"),
+            fill_time: time("2024-03-01T15:02:30Z"),
+            fill_btc: price("60850.25"),
+        };
+        let split = |n: i64, fee: &str| history::Event::Trade {
+            asset: TaxAsset::Option {
+                underlying: Underlying::Btc,
+                option,
+                contract_size: ContractSize::Full,
+            },
+            price: price("1262.5"),
+            size: Quantity::Contracts(n),
+            fee: price(fee),
+        };
+        let (one, two) = (split(-1, "-0.25"), split(-2, "-0.50"));
+        let records = [
+            Record::decision(
+                time("2024-03-01T15:00:00Z"),
+                HeartbeatDecision::Traded,
+                price("61000"),
+                4,
+                None,
+            ),
+            Record::decision(
+                time("2024-03-02T15:00:00Z"),
+                HeartbeatDecision::MarketClosed,
+                price("62000"),
+                0,
+                None,
+            ),
+            Record::fill(&fill),
+            Record::event(
+                time("2024-02-01T12:00:00Z"),
+                &history::Event::UsdDeposit {
+                    amount: Quantity::Cents(1_000_000),
+                    status: None,
+                    reversal: None,
+                },
+            ),
+            Record::event(
+                time("2024-03-01T15:02:30Z"),
+                &history::Event::Trade {
+                    asset: TaxAsset::Option {
+                        underlying: Underlying::Btc,
+                        option,
+                        contract_size: ContractSize::Full,
+                    },
+                    price: price("1262.5"),
+                    size: Quantity::Contracts(-3),
+                    fee: price("-0.75"),
+                },
+            ),
+            Record::event(
+                time("2024-03-29T21:00:00Z"),
+                &history::Event::Expiry {
+                    option,
+                    underlying: Underlying::Btc,
+                    contract_size: ContractSize::Full,
+                    size: Quantity::Contracts(3),
+                },
+            ),
+            Record {
+                schema_version: SCHEMA_VERSION,
+                time: time_str(time("2024-03-28T16:00:00Z")),
+                body: Body::Close(Close {
+                    option: option.to_string(),
+                    position: 3,
+                    size: 2,
+                    model_price: price("40").to_string(),
+                    max_price: price("48").to_string(),
+                    cost: price("0.90").to_string(),
+                    btc_price: price("61500").to_string(),
+                }),
+            },
+            Record::price(time("2024-03-01T15:00:05Z"), price("61012.5")),
+            Record::order_cancelled(time("2024-03-01T15:00:06Z"), None),
+            Record::balances(
+                time("2024-03-01T15:00:07Z"),
+                price("25000"),
+                bitcoin::Amount::from_sat(150_000_000),
+            ),
+            Record::alert(time("2024-03-01T15:00:08Z"), "Kill switch engaged: test"),
+            Record::decision(
+                time("2024-03-04T15:00:00Z"),
+                HeartbeatDecision::Traded,
+                price("63000"),
+                2,
+                Some(ArrReference::LastFriday),
+            ),
+            Record::merged_event(&history::fills::Entry {
+                date: time("2024-03-01T15:02:30Z"),
+                event: history::Event::Trade {
+                    asset: TaxAsset::Option {
+                        underlying: Underlying::Btc,
+                        option,
+                        contract_size: ContractSize::Full,
+                    },
+                    price: price("1262.5"),
+                    size: Quantity::Contracts(-3),
+                    fee: price("-0.75"),
+                },
+                executions: vec![
+                    (time("2024-03-01T15:02:30Z"), &one),
+                    (time("2024-03-01T15:02:33Z"), &two),
+                ],
+            }),
+            Record::bust(&datafeed::Bust {
+                contract_id: ContractId::from(22256362),
+                customer_id: None,
+                message_id: MessageId::from([0x01; 16]),
+                size: crate::units::UnknownQuantity::from_i64(-3),
+                price: price("1264"),
+                timestamp: time("2024-03-01T15:10:00Z"),
+            }),
+        ];
+
+        let lines: Vec<&str> = include_str!("golden-v2.ndjson").lines().collect();
+        assert_eq!(lines.len(), records.len());
+        for (record, line) in records.iter().zip(lines) {
+            assert_eq!(record.to_string(), line);
+            assert_eq!(&serde_json::from_str::<Record>(line).unwrap(), record);
+        }
+
+        // Records written by version 1 still read back the same
+        let lines: Vec<&str> = include_str!("golden-v1.ndjson").lines().collect();
+        assert_eq!(lines.len(), 6);
+        for (record, line) in records.iter().zip(lines) {
+            let old = serde_json::from_str::<Record>(line).unwrap();
+            assert_eq!(old.schema_version, 1);
+            assert_eq!((&old.time, &old.body), (&record.time, &record.body));
+        }
+    }
+}
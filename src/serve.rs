@@ -0,0 +1,635 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Report Server
+//!
+//! The `serve` command runs a small HTTP server with read-only HTML views of
+//! our positions, the most interesting bids on the book, our net worth over
+//! time and the latest tax summary, so that they can be checked from a phone.
+//! Each view is computed from the same data as the corresponding CLI command.
+//! The scan and net-worth views, which need many requests to LedgerX, are
+//! cached for a few minutes.
+//!
+//! Every request must carry the access token, either as a `token` query
+//! parameter or as a bearer token. Only GET requests are served, and nothing
+//! here can place or cancel an order. Requests are handled one at a time.
+//!
+
+use crate::ledgerx::contract_cache::ContractCache;
+use crate::ledgerx::history::{self, summary, DateRange};
+use crate::ledgerx::moneyness::Distance;
+use crate::ledgerx::{close, json};
+use crate::price::Historic;
+use crate::units::{Price, Underlying, UtcTime};
+use anyhow::Context;
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io::{BufRead as _, BufReader, Read as _, Write as _};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::{fs, time};
+
+/// Address the server listens on if none is given
+pub const DEFAULT_BIND: &str = "127.0.0.1:8080";
+/// Prefix of the directories written by `tax-history`
+const TAX_OUTPUT_PREFIX: &str = "lx_tax_output_";
+/// Number of bids listed in the scan view
+const SCAN_ROWS: usize = 25;
+/// How long to wait for a client to send its request
+const READ_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Maximum size of a request's request line and headers, in bytes
+const MAX_REQUEST_LEN: u64 = 16 * 1024;
+/// How long the scan and net-worth views are served from the cache
+const CACHE_TIME: time::Duration = time::Duration::from_secs(300);
+
+/// Settings for the report server
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// Address to listen on
+    pub bind: String,
+    /// File containing the access token; if unset, a random one is generated
+    pub token_file: Option<PathBuf>,
+    /// Volatility at which open options are marked in the net-worth chart
+    pub iv: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            bind: DEFAULT_BIND.into(),
+            token_file: None,
+            iv: crate::ledgerx::interesting::STANDING_IV,
+        }
+    }
+}
+
+/// The parts of an HTTP request we look at
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Request {
+    /// The request method, e.g. GET
+    method: String,
+    /// The path, without any query string
+    path: String,
+    /// The access token, from the query string or `Authorization` header
+    token: Option<String>,
+}
+
+impl Request {
+    /// Parses the request line and headers of a request
+    fn parse<S: AsRef<str>>(lines: &[S]) -> Result<Self, String> {
+        let request_line = lines.first().ok_or("empty request")?.as_ref();
+        let mut words = request_line.split_whitespace();
+        let (method, target) = match (words.next(), words.next(), words.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                (method, target)
+            }
+            _ => return Err(format!("malformed request line {request_line}")),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut token = query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| *key == "token")
+            .and_then(|(_, value)| urlencoding::decode(value).ok())
+            .map(|value| value.into_owned());
+        for line in &lines[1..] {
+            if let Some((name, value)) = line.as_ref().split_once(':') {
+                if name.trim().eq_ignore_ascii_case("authorization") {
+                    if let Some(bearer) = value.trim().strip_prefix("Bearer ") {
+                        token = Some(bearer.trim().to_string());
+                    }
+                }
+            }
+        }
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            token,
+        })
+    }
+
+    /// Whether the request carries the access token
+    fn is_authorized(&self, token: &str) -> bool {
+        match self.token {
+            // Compare every byte, so that the time taken does not leak how
+            // much of a guess was right
+            Some(ref given) if given.len() == token.len() => {
+                given
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Escapes text for inclusion in HTML
+fn escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            _ => ret.push(ch),
+        }
+    }
+    ret
+}
+
+/// Builds an HTML table from a header and rows of already-escaped cells
+fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut ret = String::from("<table><tr>");
+    for cell in header {
+        let _ = write!(ret, "<th>{}</th>", escape(cell));
+    }
+    ret.push_str("</tr>");
+    for row in rows {
+        ret.push_str("<tr>");
+        for cell in row {
+            let _ = write!(ret, "<td>{cell}</td>");
+        }
+        ret.push_str("</tr>");
+    }
+    ret.push_str("</table>");
+    ret
+}
+
+/// The views served, as (path, title)
+const VIEWS: &[(&str, &str)] = &[
+    ("/portfolio", "Portfolio"),
+    ("/scan", "Scan"),
+    ("/networth", "Net worth"),
+    ("/tax", "Tax summary"),
+];
+
+/// Wraps the body of a view in a page, with links to the other views
+fn page(title: &str, token: &str, body: &str) -> String {
+    let token = urlencoding::encode(token);
+    let mut nav = String::new();
+    for (path, name) in VIEWS {
+        let _ = write!(nav, "<a href=\"{path}?token={token}\">{name}</a> ");
+    }
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>\
+         body {{ font-family: sans-serif; margin: 0.5em; }} \
+         table {{ border-collapse: collapse; }} \
+         td, th {{ border: 1px solid #ccc; padding: 0.2em 0.4em; text-align: right; }}\
+         </style></head><body><nav>{nav}</nav><h1>{title}</h1>{body}</body></html>\n",
+        title = escape(title),
+    )
+}
+
+/// Renders a net-worth series as an SVG line chart
+fn chart(marks: &[history::performance::Mark]) -> String {
+    const WIDTH: f64 = 800.0;
+    const HEIGHT: f64 = 300.0;
+    let values: Vec<f64> = marks.iter().map(|mark| mark.nlv.to_approx_f64()).collect();
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let span = if max > min { max - min } else { 1.0 };
+    let step = WIDTH / (values.len().max(2) - 1) as f64;
+    let points: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(n, v)| {
+            format!(
+                "{:.1},{:.1}",
+                n as f64 * step,
+                HEIGHT - (v - min) / span * HEIGHT
+            )
+        })
+        .collect();
+    format!(
+        "<svg viewBox=\"0 0 {WIDTH} {HEIGHT}\" width=\"100%\" preserveAspectRatio=\"none\">\
+         <polyline fill=\"none\" stroke=\"#2a6\" stroke-width=\"2\" points=\"{}\"/></svg>",
+        points.join(" "),
+    )
+}
+
+/// State of the server: everything needed to compute the views
+struct Server<'a> {
+    api_key: &'a str,
+    config_file: &'a Path,
+    prices: &'a Historic,
+    contract_cache: &'a mut ContractCache,
+    /// Busts of our fills, to leave out of the history
+    busts: &'a [history::BustedTrade],
+    iv: f64,
+    /// The last rendered scan view, and when it was rendered
+    scan_cache: Option<(time::Instant, String)>,
+    /// The last rendered net-worth view, and when it was rendered
+    networth_cache: Option<(time::Instant, String)>,
+}
+
+/// Returns a cached view if it is recent enough, otherwise renders and caches it
+fn cached(
+    cache: &mut Option<(time::Instant, String)>,
+    render: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<String> {
+    if let Some((rendered_at, ref body)) = *cache {
+        if rendered_at.elapsed() < CACHE_TIME {
+            return Ok(body.clone());
+        }
+    }
+    let body = render()?;
+    *cache = Some((time::Instant::now(), body.clone()));
+    Ok(body)
+}
+
+impl Server<'_> {
+    /// Our positions, balances and collateral, as for `funding-plan`
+    fn portfolio(&self, now: UtcTime) -> anyhow::Result<String> {
+        let btc_price = self.prices.price_at(now);
+        let portfolio = crate::fetch_portfolio(self.api_key)?;
+        let balances: json::GetBalancesResponse = crate::http::get_json_from_data_field(
+            "https://api.ledgerx.com/funds/balances",
+            Some(self.api_key),
+        )
+        .context("looking up current balances")?;
+
+        let mut positions: Vec<_> = portfolio.positions().collect();
+        positions.sort_by_key(|(opt, _)| (opt.expiry, opt.strike));
+        let rows: Vec<Vec<String>> = positions
+            .iter()
+            .map(|(opt, size)| {
+                vec![
+                    escape(&opt.to_string()),
+                    size.to_string(),
+                    format!("{:.1}", opt.years_to_expiry(now) * 365.0),
+                    escape(&Distance::to_strike(opt, btc_price.btc_price).to_string()),
+                ]
+            })
+            .collect();
+        let balance_rows = vec![
+            vec![
+                "USD".into(),
+                escape(&balances.usd.available_balance.to_string()),
+                escape(&balances.usd.position_locked.to_string()),
+            ],
+            vec![
+                "BTC".into(),
+                escape(&balances.btc.available_balance.to_string()),
+                escape(&balances.btc.position_locked.to_string()),
+            ],
+        ];
+        Ok(format!(
+            "<p>BTC price: {}</p><h2>Positions</h2>{}<p>Collateral required: {}</p>\
+             <h2>Balances</h2>{}",
+            escape(&btc_price.to_string()),
+            table(&["Option", "Position", "Days", "Distance"], &rows),
+            escape(&portfolio.requirement().to_string()),
+            table(&["Asset", "Available", "Locked"], &balance_rows),
+        ))
+    }
+
+    /// The best bids on out-of-the-money BTC options, ranked by the return of
+    /// selling into them
+    fn scan(&mut self, now: UtcTime) -> anyhow::Result<String> {
+        let btc_price = self.prices.price_at(now);
+        let btc = btc_price.btc_price;
+        let contracts = self
+            .contract_cache
+            .fetch_all_active()
+            .context("looking up active contracts")?;
+        if let Err(e) = self.contract_cache.save() {
+            warn!("Failed to save contract cache: {}", e);
+        }
+
+        let mut bids = vec![];
+        for contract in contracts {
+            if contract.underlying() != Underlying::Btc {
+                continue;
+            }
+            let opt = match contract.as_option() {
+                Some(opt) if opt.expiry > now && opt.intrinsic_value(btc) < Price::ZERO => opt,
+                _ => continue,
+            };
+            let book = close::fetch_book(self.api_key, &contract, now)
+                .with_context(|| format!("fetching book of {}", contract.label()))?;
+            let best = book
+                .bids()
+                .find(|bid| !bid.size.is_zero())
+                .map(|bid| (bid.price, bid.size));
+            if let Some((price, size)) = best {
+                let arr = opt.arr(now, btc, price);
+                bids.push((arr, opt, contract.label().to_string(), price, size));
+            }
+        }
+        bids.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let rows: Vec<Vec<String>> = bids
+            .iter()
+            .take(SCAN_ROWS)
+            .map(|(arr, opt, label, price, size)| {
+                let iv = match opt.bs_iv(now, btc, *price) {
+                    Ok(iv) => format!("{:.1}%", iv * 100.0),
+                    Err(_) => "n/a".into(),
+                };
+                vec![
+                    escape(label),
+                    escape(&price.to_string()),
+                    escape(&size.to_string()),
+                    iv,
+                    format!("{:.1}%", arr * 100.0),
+                    format!("{:.1}%", opt.bs_loss80(now, btc, *price) * 100.0),
+                ]
+            })
+            .collect();
+        Ok(format!(
+            "<p>BTC price: {} (stored price data; run update-price-data to refresh)</p>{}",
+            escape(&btc_price.to_string()),
+            table(&["Contract", "Bid", "Size", "IV", "ARR", "Loss80"], &rows),
+        ))
+    }
+
+    /// Daily net liquidation value of the account, as for `performance`
+    fn networth(&mut self) -> anyhow::Result<String> {
+        let (config_hash, config) = crate::parse_config_file(self.config_file)?;
+        let hist = history::History::from_api(
+            self.api_key,
+            &config,
+            config_hash,
+            self.contract_cache,
+            true,
+            self.busts,
+        )
+        .context("getting history from LX API")?;
+        let marks = hist
+            .nlv_series(self.prices, DateRange::default(), self.iv)
+            .context("computing account values")?;
+        let (first, last) = match (marks.first(), marks.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok("<p>No account history.</p>".into()),
+        };
+        let max = marks
+            .iter()
+            .map(|mark| mark.nlv)
+            .max()
+            .unwrap_or(Price::ZERO);
+        Ok(format!(
+            "<p>{} to {}; options marked at {:.0}% volatility.</p>{}\
+             <p>Current: {} (peak {})</p>",
+            first.date,
+            last.date,
+            self.iv * 100.0,
+            chart(&marks),
+            escape(&last.nlv.to_string()),
+            escape(&max.to_string()),
+        ))
+    }
+
+    /// The summary of the most recent `tax-history` run in the current directory
+    fn tax(&self) -> anyhow::Result<String> {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(".")
+            .context("listing current directory")?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(TAX_OUTPUT_PREFIX)
+            })
+            .map(|entry| entry.path().join("summary.json"))
+            .filter(|path| path.exists())
+            .collect();
+        // Directory names contain the time of the run, so sort chronologically
+        dirs.sort();
+        let path = match dirs.last() {
+            Some(path) => path,
+            None => return Ok("<p>No tax-history output found.</p>".into()),
+        };
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let summary: summary::Summary = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+        let rows: Vec<Vec<String>> = summary
+            .years
+            .iter()
+            .map(|(year, totals)| {
+                vec![
+                    year.to_string(),
+                    escape(&totals.lot_selection_strategy),
+                    totals.events.to_string(),
+                    escape(&totals.short_term.gain_loss),
+                    escape(&totals.long_term.gain_loss),
+                    escape(&totals.section_1256.gain_loss),
+                    escape(&totals.net_short_term),
+                    escape(&totals.net_long_term),
+                ]
+            })
+            .collect();
+        Ok(format!(
+            "<p>From {} (range: {}; {} warnings; code version {})</p>{}",
+            escape(&path.display().to_string()),
+            escape(&summary.range),
+            summary.warnings,
+            escape(&summary.code_version),
+            table(
+                &[
+                    "Year",
+                    "Strategy",
+                    "Events",
+                    "Short-term",
+                    "Long-term",
+                    "1256",
+                    "Net short-term",
+                    "Net long-term",
+                ],
+                &rows,
+            ),
+        ))
+    }
+
+    /// Computes the response to a request, as (status, HTML)
+    fn respond(&mut self, request: &Request, token: &str) -> (&'static str, String) {
+        if !request.is_authorized(token) {
+            return ("401 Unauthorized", "<p>Missing or wrong token.</p>".into());
+        }
+        if request.method != "GET" {
+            return ("405 Method Not Allowed", "<p>Read-only.</p>".into());
+        }
+        let now = UtcTime::now();
+        let result = match request.path.as_str() {
+            "/" => Ok(String::new()),
+            "/portfolio" => self.portfolio(now),
+            "/scan" => {
+                let mut cache = self.scan_cache.take();
+                let result = cached(&mut cache, || self.scan(now));
+                self.scan_cache = cache;
+                result
+            }
+            "/networth" => {
+                let mut cache = self.networth_cache.take();
+                let result = cached(&mut cache, || self.networth());
+                self.networth_cache = cache;
+                result
+            }
+            "/tax" => self.tax(),
+            _ => return ("404 Not Found", page("Not found", token, "")),
+        };
+        let title = VIEWS
+            .iter()
+            .find(|(path, _)| *path == request.path)
+            .map(|(_, title)| *title)
+            .unwrap_or("Reports");
+        match result {
+            Ok(body) => ("200 OK", page(title, token, &body)),
+            Err(e) => {
+                warn!("Failed to render {}: {:#}", request.path, e);
+                let body = format!("<p>Error: {}</p>", escape(&format!("{e:#}")));
+                ("500 Internal Server Error", page(title, token, &body))
+            }
+        }
+    }
+
+    /// Reads a single request from a connection and answers it
+    fn handle(&mut self, stream: TcpStream, token: &str) -> anyhow::Result<()> {
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .context("setting read timeout")?;
+        let peer = stream.peer_addr().context("getting peer address")?;
+        // Bound what we read, so a client cannot make us buffer an endless line
+        let mut lines = vec![];
+        let mut complete = false;
+        for line in BufReader::new((&stream).take(MAX_REQUEST_LEN)).lines() {
+            let line = line.context("reading request")?;
+            if line.is_empty() {
+                complete = true;
+                break;
+            }
+            lines.push(line);
+        }
+        let parsed = if complete {
+            Request::parse(&lines)
+        } else {
+            Err(format!(
+                "request headers incomplete or longer than {MAX_REQUEST_LEN} bytes"
+            ))
+        };
+        let (status, body) = match parsed {
+            Ok(request) => {
+                info!("{} {} from {}", request.method, request.path, peer);
+                self.respond(&request, token)
+            }
+            Err(e) => ("400 Bad Request", escape(&e)),
+        };
+        write!(
+            &stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+            body.len(),
+        )
+        .context("writing response")?;
+        Ok(())
+    }
+}
+
+/// Runs the server until it is killed
+pub fn run(
+    api_key: &str,
+    config_file: &Path,
+    prices: &Historic,
+    contract_cache: &mut ContractCache,
+    busts: &[history::BustedTrade],
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let token = match settings.token_file {
+        Some(ref path) => {
+            let token = fs::read_to_string(path)
+                .with_context(|| format!("reading token file {}", path.display()))?;
+            let token = token.trim().to_string();
+            if token.is_empty() {
+                return Err(anyhow::Error::msg(format!(
+                    "token file {} is empty",
+                    path.display()
+                )));
+            }
+            token
+        }
+        None => hex::encode(rand::random::<[u8; 16]>()),
+    };
+    let listener = TcpListener::bind(&settings.bind)
+        .with_context(|| format!("listening on {}", settings.bind))?;
+    info!("Serving reports on http://{}/", settings.bind);
+    if settings.token_file.is_none() {
+        // Print the generated token to the terminal only, never to the log file
+        println!("Access token: {token}");
+        println!("Open http://{}/portfolio?token={token}", settings.bind);
+    }
+
+    let mut server = Server {
+        api_key,
+        config_file,
+        prices,
+        contract_cache,
+        busts,
+        iv: settings.iv,
+        scan_cache: None,
+        networth_cache: None,
+    };
+    for stream in listener.incoming() {
+        let result = stream
+            .context("accepting connection")
+            .and_then(|stream| server.handle(stream, &token));
+        if let Err(e) = result {
+            warn!("{:#}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        let req = |lines: &[&str]| Request::parse(lines);
+
+        let get = req(&["GET /scan?token=abc%2Bd&x=1 HTTP/1.1", "Host: phone"]).unwrap();
+        assert_eq!(get.method, "GET");
+        assert_eq!(get.path, "/scan");
+        assert_eq!(get.token.as_deref(), Some("abc+d"));
+        assert!(get.is_authorized("abc+d"));
+        assert!(!get.is_authorized("abc+e"));
+        assert!(!get.is_authorized("abc"));
+
+        let bearer = req(&["POST /tax HTTP/1.1", "authorization: Bearer s3cret"]).unwrap();
+        assert_eq!(bearer.method, "POST");
+        assert_eq!(bearer.path, "/tax");
+        assert!(bearer.is_authorized("s3cret"));
+
+        let anon = req(&["GET / HTTP/1.0"]).unwrap();
+        assert_eq!(anon.token, None);
+        assert!(!anon.is_authorized(""));
+
+        assert!(req(&[]).is_err());
+        assert!(req(&["GET /"]).is_err());
+        assert!(req(&["hello there friend"]).is_err());
+
+        assert_eq!(
+            escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+}
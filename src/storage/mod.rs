@@ -0,0 +1,110 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Storage
+//!
+//! With `connect --database`, every order update from the LX data feed, every
+//! fill (and bust) of our own orders and every balance we see is written to a SQLite
+//! database, so that a session can be analyzed (or recovered after a crash)
+//! with SQL rather than by re-parsing gigabytes of log lines.
+//!
+//! Times are stored as UNIX nanoseconds, amounts of BTC in satoshis, and
+//! prices as exact decimal strings in dollars. Order sizes are in LX's base
+//! units for the contract, as they appear in the data feed.
+//!
+//! SQLite support is only compiled in with the `sqlite` feature, which is off
+//! by default since it builds a bundled copy of SQLite.
+//!
+
+use crate::units::Price;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{Storage, SCHEMA_VERSION};
+
+/// Where a balance came from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BalanceSource {
+    /// A collateral update pushed over the data feed (available balances only)
+    DataFeed,
+    /// A balance sync from the balances endpoint, on a heartbeat
+    Sync,
+}
+
+/// A balance snapshot
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Balances {
+    /// Available USD
+    pub usd_available: Price,
+    /// Available BTC
+    pub btc_available: bitcoin::Amount,
+    /// USD and BTC locked up by our positions, if known
+    pub locked: Option<(Price, bitcoin::Amount)>,
+}
+
+/// Stand-in for the database when built without the `sqlite` feature
+///
+/// It cannot be opened, so none of its methods can ever be called.
+#[cfg(not(feature = "sqlite"))]
+pub enum Storage {}
+
+#[cfg(not(feature = "sqlite"))]
+impl Storage {
+    /// Fails, since there is no SQLite support
+    pub fn open(_: &std::path::Path) -> anyhow::Result<Self> {
+        Err(anyhow::Error::msg(
+            "this build has no SQLite support; rebuild with `--features sqlite`",
+        ))
+    }
+
+    /// Records an order update from the data feed
+    pub fn record_order(
+        &self,
+        _: crate::units::UtcTime,
+        _: &crate::ledgerx::datafeed::Order,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    /// Records a fill of one of our own orders, with its slippage data if any
+    pub fn record_fill(
+        &self,
+        _: crate::units::UtcTime,
+        _: &crate::ledgerx::datafeed::Order,
+        _: Price,
+        _: Option<&crate::ledgerx::slippage::Fill>,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    /// Records a bust of a fill of one of our own orders
+    pub fn record_bust(
+        &self,
+        _: crate::units::UtcTime,
+        _: &crate::ledgerx::datafeed::Bust,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    /// Records a balance snapshot
+    pub fn record_balances(
+        &self,
+        _: crate::units::UtcTime,
+        _: BalanceSource,
+        _: &Balances,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
@@ -0,0 +1,416 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! SQLite Storage
+//!
+//! The database behind `connect --database`, available with the `sqlite`
+//! feature.
+//!
+
+use super::{BalanceSource, Balances};
+use crate::ledgerx::{datafeed, slippage, ContractId, CustomerId, MessageId};
+use crate::units::{Price, UnknownQuantity, UtcTime};
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension as _};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Version of the database layout, stored in SQLite's `user_version`
+///
+/// Version 2 added the `busts` table.
+pub const SCHEMA_VERSION: i64 = 2;
+
+/// Statements creating the database layout
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS orders (
+        id INTEGER PRIMARY KEY,
+        received_at INTEGER NOT NULL,
+        contract_id INTEGER NOT NULL,
+        message_id TEXT NOT NULL,
+        customer_id INTEGER,
+        size INTEGER NOT NULL,
+        price TEXT NOT NULL,
+        filled_size INTEGER NOT NULL,
+        filled_price TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        updated_timestamp INTEGER NOT NULL,
+        open_interest INTEGER,
+        clock INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS orders_by_time ON orders (received_at);
+    CREATE INDEX IF NOT EXISTS orders_by_message ON orders (message_id);
+    CREATE TABLE IF NOT EXISTS fills (
+        id INTEGER PRIMARY KEY,
+        received_at INTEGER NOT NULL,
+        contract_id INTEGER NOT NULL,
+        message_id TEXT NOT NULL,
+        filled_size INTEGER NOT NULL,
+        filled_price TEXT NOT NULL,
+        premium TEXT NOT NULL,
+        limit_price TEXT,
+        decision_btc TEXT,
+        fill_btc TEXT
+    );
+    CREATE TABLE IF NOT EXISTS busts (
+        id INTEGER PRIMARY KEY,
+        received_at INTEGER NOT NULL,
+        contract_id INTEGER NOT NULL,
+        message_id TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        price TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        id INTEGER PRIMARY KEY,
+        received_at INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        usd_available TEXT NOT NULL,
+        btc_available INTEGER NOT NULL,
+        usd_locked TEXT,
+        btc_locked INTEGER
+    );
+";
+
+impl BalanceSource {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            BalanceSource::DataFeed => "datafeed",
+            BalanceSource::Sync => "sync",
+        }
+    }
+}
+
+/// An open database
+pub struct Storage {
+    conn: Connection,
+}
+
+impl Storage {
+    /// Opens a database, creating it if it does not exist
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening database {}", path.display()))?;
+        Self::init(conn).with_context(|| format!("initializing database {}", path.display()))
+    }
+
+    /// Opens an in-memory database, which is discarded when dropped
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> anyhow::Result<Self> {
+        // We write one row per message, so trade a little durability (the
+        // last few rows on power loss, never corruption) for not syncing on
+        // every insert.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "database has version {version}, but we only understand up to {SCHEMA_VERSION}"
+            )));
+        }
+        conn.execute_batch(CREATE_TABLES)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Storage { conn })
+    }
+
+    /// Records an order update from the data feed
+    pub fn record_order(&self, now: UtcTime, order: &datafeed::Order) -> anyhow::Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO orders (received_at, contract_id, message_id, customer_id, size, \
+                 price, filled_size, filled_price, timestamp, updated_timestamp, open_interest, \
+                 clock) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    now.to_unix_nanos_i64(),
+                    usize::from(order.contract_id),
+                    order.message_id.to_string(),
+                    order.customer_id.map(usize::from),
+                    order.size.to_i64(),
+                    price_str(order.price),
+                    order.filled_size.to_i64(),
+                    price_str(order.filled_price),
+                    order.timestamp.to_unix_nanos_i64(),
+                    order.updated_timestamp.to_unix_nanos_i64(),
+                    order.open_interest,
+                    // SQLite integers are signed; clocks will not reach 2^63
+                    order.clock as i64,
+                ],
+            )
+            .with_context(|| format!("recording {order}"))?;
+        Ok(())
+    }
+
+    /// Records a fill of one of our own orders, with its slippage data if any
+    pub fn record_fill(
+        &self,
+        now: UtcTime,
+        order: &datafeed::Order,
+        premium: Price,
+        fill: Option<&slippage::Fill>,
+    ) -> anyhow::Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO fills (received_at, contract_id, message_id, filled_size, \
+                 filled_price, premium, limit_price, decision_btc, fill_btc) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    now.to_unix_nanos_i64(),
+                    usize::from(order.contract_id),
+                    order.message_id.to_string(),
+                    order.filled_size.to_i64(),
+                    price_str(order.filled_price),
+                    price_str(premium),
+                    fill.map(|fill| price_str(fill.limit_price)),
+                    fill.map(|fill| price_str(fill.decision_btc)),
+                    fill.map(|fill| price_str(fill.fill_btc)),
+                ],
+            )
+            .with_context(|| format!("recording fill of {order}"))?;
+        Ok(())
+    }
+
+    /// Records a bust of a fill of one of our own orders
+    pub fn record_bust(&self, now: UtcTime, bust: &datafeed::Bust) -> anyhow::Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO busts (received_at, contract_id, message_id, size, price, \
+                 timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    now.to_unix_nanos_i64(),
+                    usize::from(bust.contract_id),
+                    bust.message_id.to_string(),
+                    bust.size.to_i64(),
+                    price_str(bust.price),
+                    bust.timestamp.to_unix_nanos_i64(),
+                ],
+            )
+            .with_context(|| format!("recording {bust}"))?;
+        Ok(())
+    }
+
+    /// Records a balance snapshot
+    pub fn record_balances(
+        &self,
+        now: UtcTime,
+        source: BalanceSource,
+        balances: &Balances,
+    ) -> anyhow::Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO balances (received_at, source, usd_available, btc_available, \
+                 usd_locked, btc_locked) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    now.to_unix_nanos_i64(),
+                    source.as_str(),
+                    price_str(balances.usd_available),
+                    balances.btc_available.to_sat(),
+                    balances.locked.map(|(usd, _)| price_str(usd)),
+                    balances.locked.map(|(_, btc)| btc.to_sat()),
+                ],
+            )
+            .context("recording balances")?;
+        Ok(())
+    }
+
+    /// Reads back the order updates received in the given range of times, in
+    /// the order they were received
+    pub fn orders(
+        &self,
+        from: UtcTime,
+        to: UtcTime,
+    ) -> anyhow::Result<Vec<(UtcTime, datafeed::Order)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT received_at, contract_id, message_id, customer_id, size, price, \
+             filled_size, filled_price, timestamp, updated_timestamp, open_interest, clock \
+             FROM orders WHERE received_at >= ?1 AND received_at < ?2 ORDER BY id",
+        )?;
+        let mut rows = stmt.query(params![from.to_unix_nanos_i64(), to.to_unix_nanos_i64()])?;
+        let mut ret = vec![];
+        while let Some(row) = rows.next()? {
+            let time = |idx| -> anyhow::Result<UtcTime> {
+                Ok(UtcTime::from_unix_nanos_i64(row.get(idx)?)?)
+            };
+            let price = |idx| -> anyhow::Result<Price> { parse_price(&row.get::<_, String>(idx)?) };
+            let order = datafeed::Order {
+                contract_id: ContractId::from(row.get::<_, usize>(1)?),
+                message_id: parse_message_id(&row.get::<_, String>(2)?)?,
+                customer_id: row.get::<_, Option<usize>>(3)?.map(CustomerId::from),
+                size: UnknownQuantity::from_i64(row.get(4)?),
+                price: price(5)?,
+                filled_size: UnknownQuantity::from_i64(row.get(6)?),
+                filled_price: price(7)?,
+                timestamp: time(8)?,
+                updated_timestamp: time(9)?,
+                open_interest: row.get(10)?,
+                clock: row.get::<_, i64>(11)? as u64,
+            };
+            ret.push((time(0)?, order));
+        }
+        Ok(ret)
+    }
+
+    /// The most recent balance snapshot, if any, with the time it was received
+    pub fn last_balances(&self) -> anyhow::Result<Option<(UtcTime, Balances)>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT received_at, usd_available, btc_available, usd_locked, btc_locked \
+                 FROM balances ORDER BY id DESC LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, u64>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<u64>>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let (time, usd, btc, usd_locked, btc_locked) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let locked = match (usd_locked, btc_locked) {
+            (Some(usd), Some(btc)) => Some((parse_price(&usd)?, bitcoin::Amount::from_sat(btc))),
+            _ => None,
+        };
+        Ok(Some((
+            UtcTime::from_unix_nanos_i64(time)?,
+            Balances {
+                usd_available: parse_price(&usd)?,
+                btc_available: bitcoin::Amount::from_sat(btc),
+                locked,
+            },
+        )))
+    }
+}
+
+/// Formats a price exactly, unlike its `Display` implementation
+fn price_str(price: Price) -> String {
+    rust_decimal::Decimal::from(price).to_string()
+}
+
+fn parse_price(s: &str) -> anyhow::Result<Price> {
+    Price::from_str(s).with_context(|| format!("parsing price {s} from database"))
+}
+
+fn parse_message_id(s: &str) -> anyhow::Result<MessageId> {
+    let mut mid = [0; 16];
+    hex::decode_to_slice(s, &mut mid)
+        .with_context(|| format!("parsing message ID {s} from database"))?;
+    Ok(MessageId::from(mid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let storage = Storage::open_in_memory().unwrap();
+        let time = |s: &str| UtcTime::parse_coinbase(&format!("2024-03-01T15:{s}Z")).unwrap();
+        let order = datafeed::Order {
+            size: UnknownQuantity::from_i64(-300),
+            filled_size: UnknownQuantity::from_i64(-100),
+            filled_price: Price::from_str("1234.5678").unwrap(),
+            price: Price::from_str("1234.56").unwrap(),
+            contract_id: ContractId::from(22256323),
+            customer_id: Some(CustomerId::from(1234)),
+            message_id: MessageId::from([
+                0x8c, 0x1d, 0x4e, 0x93, 0x7e, 0x46, 0x4b, 0x16, 0x9f, 0x3e, 0x0e, 0x14, 0x53, 0x35,
+                0xcf, 0x79,
+            ]),
+            timestamp: time("00:00.123456789"),
+            updated_timestamp: time("00:01.5"),
+            open_interest: Some(17),
+            clock: 123_456,
+        };
+        let book_order = datafeed::Order {
+            customer_id: None,
+            open_interest: None,
+            ..order.clone()
+        };
+        storage.record_order(time("00:02"), &order).unwrap();
+        storage.record_order(time("00:03"), &book_order).unwrap();
+        storage.record_order(time("00:04"), &order).unwrap();
+        assert_eq!(
+            storage.orders(time("00:02"), time("00:04")).unwrap(),
+            vec![(time("00:02"), order.clone()), (time("00:03"), book_order)],
+        );
+        assert!(storage
+            .orders(time("00:05"), time("00:06"))
+            .unwrap()
+            .is_empty());
+
+        storage
+            .record_fill(
+                time("00:02"),
+                &order,
+                Price::from_str("12.34").unwrap(),
+                None,
+            )
+            .unwrap();
+        let (message_id, premium): (String, String) = storage
+            .conn
+            .query_row("SELECT message_id, premium FROM fills", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(message_id, "8c1d4e937e464b169f3e0e145335cf79");
+        assert_eq!(premium, "12.34");
+
+        let bust = datafeed::Bust {
+            contract_id: order.contract_id,
+            customer_id: order.customer_id,
+            message_id: order.message_id,
+            size: order.filled_size,
+            price: order.filled_price,
+            timestamp: time("00:05"),
+        };
+        storage.record_bust(time("00:06"), &bust).unwrap();
+        let (message_id, size): (String, i64) = storage
+            .conn
+            .query_row("SELECT message_id, size FROM busts", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(message_id, "8c1d4e937e464b169f3e0e145335cf79");
+        assert_eq!(size, -100);
+
+        assert_eq!(storage.last_balances().unwrap(), None);
+        let feed = Balances {
+            usd_available: Price::from_str("1000").unwrap(),
+            btc_available: bitcoin::Amount::from_sat(50_000_000),
+            locked: None,
+        };
+        let sync = Balances {
+            locked: Some((Price::from_str("20000").unwrap(), bitcoin::Amount::ONE_BTC)),
+            ..feed
+        };
+        storage
+            .record_balances(time("00:02"), BalanceSource::DataFeed, &feed)
+            .unwrap();
+        storage
+            .record_balances(time("00:03"), BalanceSource::Sync, &sync)
+            .unwrap();
+        assert_eq!(
+            storage.last_balances().unwrap(),
+            Some((time("00:03"), sync))
+        );
+    }
+}
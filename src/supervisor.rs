@@ -0,0 +1,204 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Thread Supervision
+//!
+//! The `connect` main loop depends on helper threads for its price reference,
+//! its clock and its book-state lookups. If one of these panics or hangs, the
+//! main loop would otherwise carry on blind. Instead, each helper is given a
+//! [`Heartbeat`] which it beats regularly, and the main loop periodically
+//! checks that every helper is alive and has beaten recently, replacing any
+//! which is not with a fresh thread.
+//!
+//! A hung thread cannot be killed, so it is instead retired: its heartbeat is
+//! marked as such, and the thread should exit as soon as it notices.
+//!
+
+use crate::units::UtcTime;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+/// Liveness indicator shared between a helper thread and its supervisor
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    /// UNIX time, in seconds, of the last beat
+    last: Arc<AtomicI64>,
+    /// Set when the thread has been replaced and should exit
+    retired: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    /// Creates a new heartbeat, which has just beaten
+    fn new(now: UtcTime) -> Self {
+        Heartbeat {
+            last: Arc::new(AtomicI64::new(now.to_unix_i64())),
+            retired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Creates a heartbeat for a thread which is not supervised, and so will
+    /// never be retired
+    pub fn unsupervised() -> Self {
+        Heartbeat::new(UtcTime::now())
+    }
+
+    /// Records that the thread is alive
+    pub fn beat(&self) {
+        self.last
+            .store(UtcTime::now().to_unix_i64(), Ordering::Relaxed);
+    }
+
+    /// Whether the thread has been replaced, and should exit
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Waits out a reconnection delay, beating meanwhile
+    ///
+    /// The thread is alive while it waits, just without a price, so there is no
+    /// point in the supervisor replacing it. Returns false if the thread was
+    /// retired while waiting.
+    pub fn wait(&self, delay: time::Duration) -> bool {
+        let until = time::Instant::now() + delay;
+        while time::Instant::now() < until {
+            if self.is_retired() {
+                return false;
+            }
+            self.beat();
+            thread::sleep(time::Duration::from_secs(1).min(until - time::Instant::now()));
+        }
+        true
+    }
+
+    /// Number of seconds since the last beat
+    fn silent_secs(&self, now: UtcTime) -> i64 {
+        now.to_unix_i64() - self.last.load(Ordering::Relaxed)
+    }
+}
+
+/// Function which starts a helper thread, returning its handle along with
+/// whatever the main loop needs to talk to it
+type SpawnFn<T> = Box<dyn FnMut(Heartbeat) -> (thread::JoinHandle<()>, T)>;
+
+/// A helper thread which is restarted if it dies or goes silent
+pub struct Supervised<T> {
+    name: &'static str,
+    max_silence_secs: i64,
+    spawn: SpawnFn<T>,
+    handle: thread::JoinHandle<()>,
+    heartbeat: Heartbeat,
+    value: T,
+    restarts: usize,
+}
+
+impl<T> Supervised<T> {
+    /// Starts a supervised thread
+    ///
+    /// `spawn` should start the thread, which must beat its heartbeat at least
+    /// every `max_silence_secs` seconds, and return its handle along with
+    /// anything the main loop needs to talk to it (e.g. a channel). It is
+    /// called again, with a new heartbeat, each time the thread is restarted.
+    pub fn spawn<F>(name: &'static str, max_silence_secs: i64, mut spawn: F) -> Self
+    where
+        F: FnMut(Heartbeat) -> (thread::JoinHandle<()>, T) + 'static,
+    {
+        let heartbeat = Heartbeat::new(UtcTime::now());
+        let (handle, value) = spawn(heartbeat.clone());
+        Supervised {
+            name,
+            max_silence_secs,
+            spawn: Box::new(spawn),
+            handle,
+            heartbeat,
+            value,
+            restarts: 0,
+        }
+    }
+
+    /// Accessor for the value returned when the current thread was started
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Checks that the thread is alive and has beaten recently, restarting it
+    /// if not
+    ///
+    /// Returns a description of the problem if the thread was restarted.
+    pub fn check(&mut self, now: UtcTime) -> Option<String> {
+        let silent_secs = self.heartbeat.silent_secs(now);
+        let problem = if self.handle.is_finished() {
+            format!("{} thread died", self.name)
+        } else if silent_secs > self.max_silence_secs {
+            format!("{} thread silent for {}s", self.name, silent_secs)
+        } else {
+            return None;
+        };
+        self.heartbeat.retired.store(true, Ordering::Relaxed);
+        self.heartbeat = Heartbeat::new(now);
+        let (handle, value) = (self.spawn)(self.heartbeat.clone());
+        self.handle = handle;
+        self.value = value;
+        self.restarts += 1;
+        Some(format!("{problem}; restarted (restart #{})", self.restarts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn restarts() {
+        let (tx, rx) = channel();
+        // A thread which panics when told to, and beats until retired
+        let mut sup = Supervised::spawn("test", 60, move |heartbeat| {
+            let (cmd_tx, cmd_rx) = channel::<bool>();
+            let tx = tx.clone();
+            let handle = thread::spawn(move || loop {
+                if heartbeat.is_retired() {
+                    tx.send("retired").unwrap();
+                    return;
+                }
+                heartbeat.beat();
+                if let Ok(true) = cmd_rx.recv_timeout(Duration::from_millis(10)) {
+                    panic!("told to panic");
+                }
+            });
+            (handle, cmd_tx)
+        });
+
+        let now = UtcTime::now();
+        assert_eq!(sup.check(now), None);
+
+        // A dead thread is restarted, with a fresh channel
+        sup.get().send(true).unwrap();
+        while !sup.handle.is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let problem = sup.check(now).unwrap();
+        assert!(problem.starts_with("test thread died"), "{}", problem);
+        assert_eq!(sup.check(now), None);
+        sup.get().send(false).unwrap();
+
+        // A silent thread is retired and replaced
+        let later = now + chrono::Duration::seconds(120);
+        let problem = sup.check(later).unwrap();
+        assert!(problem.starts_with("test thread silent"), "{}", problem);
+        assert!(problem.ends_with("(restart #2)"), "{}", problem);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("retired"));
+    }
+}
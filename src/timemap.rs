@@ -91,11 +91,21 @@ impl<V> TimeMap<V> {
         max_key_val.and_then(|(key, _)| self.map.remove(&key).map(|v| (key.0, v)))
     }
 
+    /// Pops the first element, in time order, for which the predicate returns true
+    ///
+    /// Like `pop_max`, this function is O(n).
+    pub fn pop_first_where<F>(&mut self, mut pred: F) -> Option<(UtcTime, V)>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let key = self.map.iter().find(|(_, v)| pred(v)).map(|(k, _)| *k)?;
+        self.map.remove(&key).map(|v| (key.0, v))
+    }
+
     /// Inserts a new element. Allows duplicates.
     ///
-    /// There is no way to replace or delete an element once it is added to the
-    /// time map. If you insert an element twice, even with the same timestamp,
-    /// it will just be in the map twice.
+    /// If you insert an element twice, even with the same timestamp, it will
+    /// just be in the map twice. Elements can only be removed with `retain`.
     pub fn insert(&mut self, time: UtcTime, item: V) {
         let idx = self.next_idx;
         // If this assertion fails it means we somehow used `idx` twice
@@ -111,6 +121,16 @@ impl<V> TimeMap<V> {
             .map(|((k, _), v)| (*k, v))
     }
 
+    /// Retains only the elements for which the predicate returns true
+    ///
+    /// The predicate is called on every element, in time order.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(UtcTime, &V) -> bool,
+    {
+        self.map.retain(|(time, _), v| f(*time, v));
+    }
+
     /// Constructs a borrowed iterator over the (time, value) pairs
     pub fn iter(&self) -> Iter<V> {
         Iter {
@@ -118,6 +138,15 @@ impl<V> TimeMap<V> {
         }
     }
 
+    /// Constructs a mutably borrowed iterator over the (time, value) pairs
+    ///
+    /// Values may be modified in place, but their timestamps cannot be changed.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V> {
+        IterMut {
+            iter: self.map.iter_mut(),
+        }
+    }
+
     /// Constructs a borrowed iterator over values in the map
     pub fn values(&self) -> Values<V> {
         Values {
@@ -151,6 +180,18 @@ impl<'a, V> Iterator for Iter<'a, V> {
     }
 }
 
+/// Mutably borrowed iterator over (timestamp, entry) pairs
+pub struct IterMut<'a, V> {
+    iter: btree_map::IterMut<'a, (UtcTime, usize), V>,
+}
+
+impl<'a, V> Iterator for IterMut<'a, V> {
+    type Item = (UtcTime, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|((time, _), v)| (*time, v))
+    }
+}
+
 impl<'a, V> iter::IntoIterator for &'a TimeMap<V> {
     type Item = (UtcTime, &'a V);
     type IntoIter = Iter<'a, V>;
@@ -0,0 +1,192 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Trading Windows
+//!
+//! Market hours decide when we capture data; trading windows narrow down
+//! when we actually open orders, e.g. to stay out of the open and close. A
+//! window is a range of New York times on some set of weekdays. Outside of
+//! every window, the main loop keeps tracking the market and logging
+//! interesting contracts, but opens nothing other than closes.
+//!
+
+use crate::units::UtcTime;
+use std::{fmt, str};
+
+/// A range of times, on some days of the week, during which we may trade
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TradingWindow {
+    /// Days on which the window applies, indexed from Monday
+    days: [bool; 7],
+    /// Start of the window, New York time
+    start: chrono::NaiveTime,
+    /// End of the window (exclusive), New York time
+    end: chrono::NaiveTime,
+}
+
+impl TradingWindow {
+    /// Whether the given time falls within the window
+    pub fn contains(&self, now: UtcTime) -> bool {
+        let day = now.new_york_weekday().num_days_from_monday() as usize;
+        let nyt = now.new_york_time();
+        self.days[day] && nyt >= self.start && nyt < self.end
+    }
+}
+
+/// Whether the given time falls within any of a list of windows
+///
+/// An empty list places no restriction on trading.
+pub fn any_contains(windows: &[TradingWindow], now: UtcTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+/// Whether we may open an order at the given time
+///
+/// Orders which only reduce one of our positions, e.g. ITM buy-backs, are
+/// allowed outside of the windows, which only limit when we take on risk.
+pub fn permits(windows: &[TradingWindow], now: UtcTime, reduces_position: bool) -> bool {
+    reduces_position || any_contains(windows, now)
+}
+
+impl fmt::Display for TradingWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.days != [true; 7] {
+            let mut day = chrono::Weekday::Mon;
+            let mut first = true;
+            for included in self.days {
+                if included {
+                    if !first {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", day.to_string().to_ascii_lowercase())?;
+                    first = false;
+                }
+                day = day.succ();
+            }
+            f.write_str("@")?;
+        }
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Parses a day of the week, or a range of them such as `mon-fri`
+fn parse_days(s: &str, days: &mut [bool; 7]) -> Result<(), String> {
+    let parse_day = |day: &str| {
+        day.parse::<chrono::Weekday>()
+            .map_err(|_| format!("unknown day of the week {day}"))
+    };
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+        None => (parse_day(s)?, parse_day(s)?),
+    };
+    let mut day = first;
+    loop {
+        days[day.num_days_from_monday() as usize] = true;
+        if day == last {
+            return Ok(());
+        }
+        day = day.succ();
+    }
+}
+
+impl str::FromStr for TradingWindow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // e.g. 09:45-15:45, mon-fri@10:00-12:00, mon,wed,fri@13:00-15:30
+        let (days_str, times) = match s.split_once('@') {
+            Some((days_str, times)) => (Some(days_str), times),
+            None => (None, s),
+        };
+        let days = match days_str {
+            Some(days_str) => {
+                let mut days = [false; 7];
+                for range in days_str.split(',') {
+                    parse_days(range, &mut days).map_err(|e| format!("{e} in {s}"))?;
+                }
+                days
+            }
+            None => [true; 7],
+        };
+        let parse_time = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("bad time {time} in {s} (expected HH:MM)"))
+        };
+        let (start, end) = match times.split_once('-') {
+            Some((start, end)) => (parse_time(start)?, parse_time(end)?),
+            None => {
+                return Err(format!(
+                    "bad trading window {s} (expected [<days>@]<HH:MM>-<HH:MM>)"
+                ))
+            }
+        };
+        if start >= end {
+            return Err(format!(
+                "trading window {s} ends before it starts (windows may not span midnight)"
+            ));
+        }
+        Ok(TradingWindow { days, start, end })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn trading_windows() {
+        let window = TradingWindow::from_str("mon-wed,fri@10:00-15:45").unwrap();
+        assert_eq!(window.to_string(), "mon,tue,wed,fri@10:00-15:45");
+        let daily = TradingWindow::from_str("09:45-10:15").unwrap();
+        assert_eq!(daily.to_string(), "09:45-10:15");
+        assert_eq!(
+            TradingWindow::from_str("sat-mon@10:00-11:00")
+                .unwrap()
+                .to_string(),
+            "mon,sat,sun@10:00-11:00",
+        );
+
+        assert!(TradingWindow::from_str("10:00").is_err());
+        assert!(TradingWindow::from_str("12:00-10:00").is_err());
+        assert!(TradingWindow::from_str("mon-frog@10:00-11:00").is_err());
+        assert!(TradingWindow::from_str("10:00-25:00").is_err());
+
+        // 2024-03-01 is a Friday, 2024-03-07 a Thursday; both are EST (UTC-5)
+        let time = |s: &str| UtcTime::parse_coinbase(s).unwrap();
+        assert!(window.contains(time("2024-03-01T15:00:00Z")));
+        assert!(!window.contains(time("2024-03-01T14:59:59Z")));
+        assert!(!window.contains(time("2024-03-01T20:45:00Z")));
+        assert!(!window.contains(time("2024-03-07T16:00:00Z")));
+        // Thursday evening in New York is already Friday in UTC
+        let evening = TradingWindow::from_str("thu@20:00-21:00").unwrap();
+        assert!(evening.contains(time("2024-03-08T01:30:00Z")));
+
+        assert!(any_contains(&[], time("2024-03-07T16:00:00Z")));
+        assert!(any_contains(&[window, daily], time("2024-03-07T14:50:00Z")));
+        assert!(!any_contains(
+            &[window, daily],
+            time("2024-03-07T16:00:00Z")
+        ));
+
+        // Outside of the windows, only position-reducing orders go through
+        assert!(permits(&[window], time("2024-03-07T16:00:00Z"), true));
+        assert!(!permits(&[window], time("2024-03-07T16:00:00Z"), false));
+        assert!(permits(&[window], time("2024-03-01T15:00:00Z"), false));
+    }
+}
@@ -16,9 +16,38 @@
 //!
 //! Utilities to manage Bitcoin Transactions
 //!
+//! Transactions can be provided as raw hex, or imported from wallet exports
+//! (Bitcoin Core, Electrum, or a simple CSV). Most wallet exports do not
+//! contain full transaction data, only information about individual outputs;
+//! these are stored separately and can be used to look up txouts and match
+//! deposits, but not to determine a deposit's inputs.
+//!
 
 use anyhow::Context;
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// A wallet export, embedded in the configuration file
+#[derive(Clone, PartialEq, Eq, Deserialize, Debug)]
+#[serde(tag = "format", content = "data")]
+pub enum WalletExport {
+    /// Output of Bitcoin Core's `listtransactions` (an array) or `gettransaction`
+    /// (an object, or an array of them)
+    #[serde(rename = "bitcoin-core")]
+    BitcoinCore(serde_json::Value),
+    /// Output of Electrum's `onchain_history --show_addresses`
+    ///
+    /// Electrum lists every output of each transaction, in order, and does not
+    /// give their indices; so outputs are numbered by position unless they have
+    /// an explicit `vout` field. Do not remove outputs from the list.
+    #[serde(rename = "electrum")]
+    Electrum(serde_json::Value),
+    /// Lines of the form `txid:vout,address,amount,timestamp` with the amount in BTC
+    #[serde(rename = "csv")]
+    Csv(Vec<String>),
+}
 
 /// Database of known transactions
 ///
@@ -26,6 +55,8 @@ use std::collections::HashMap;
 #[derive(Clone, PartialEq, Eq, Debug, Default)]
 pub struct Database {
     map: HashMap<bitcoin::Txid, bitcoin::Transaction>,
+    /// Individual outputs known from wallet exports, without full transaction data
+    outputs: HashMap<bitcoin::OutPoint, bitcoin::TxOut>,
 }
 
 impl Database {
@@ -33,11 +64,7 @@ impl Database {
     pub fn from_string_map(map: &HashMap<bitcoin::Txid, String>) -> anyhow::Result<Self> {
         let mut ret = HashMap::with_capacity(map.len());
         for (txid, s) in map {
-            let bytes: Vec<u8> = bitcoin::hashes::hex::FromHex::from_hex(s)
-                .with_context(|| format!("decoding string for {txid} as hex"))?;
-            let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&bytes)
-                .with_context(|| format!("decoding hex for {txid} as transaction"))?;
-
+            let tx = parse_tx_hex(s).with_context(|| format!("decoding data for {txid}"))?;
             if tx.txid() != *txid {
                 return Err(anyhow::Error::msg(format!(
                     "txid {txid} maps to transaction with txid {}",
@@ -47,37 +74,350 @@ impl Database {
             ret.insert(*txid, tx);
         }
 
-        Ok(Database { map: ret })
+        Ok(Database {
+            map: ret,
+            outputs: HashMap::new(),
+        })
+    }
+
+    /// Imports transaction data from a wallet export
+    pub fn import_wallet_export(&mut self, export: &WalletExport) -> anyhow::Result<()> {
+        match export {
+            WalletExport::BitcoinCore(json) => self.import_core_json(json),
+            WalletExport::Electrum(json) => self.import_electrum_json(json),
+            WalletExport::Csv(lines) => {
+                for line in lines {
+                    self.import_csv_line(line)
+                        .with_context(|| format!("parsing CSV line {line}"))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Imports a Bitcoin Core `listtransactions` or `gettransaction` dump
+    fn import_core_json(&mut self, json: &serde_json::Value) -> anyhow::Result<()> {
+        let entries = match json {
+            serde_json::Value::Array(ref arr) => &arr[..],
+            obj => std::slice::from_ref(obj),
+        };
+        for entry in entries {
+            // gettransaction output has the full transaction
+            if let Some(hex) = entry.get("hex").and_then(serde_json::Value::as_str) {
+                let tx = parse_tx_hex(hex).context("decoding gettransaction hex")?;
+                self.map.insert(tx.txid(), tx);
+                continue;
+            }
+            // listtransactions only has individual outputs
+            let txid = json_str(entry, "txid")?;
+            let vout = entry
+                .get("vout")
+                .and_then(json_vout)
+                .with_context(|| format!("missing or bad vout for {txid}"))?;
+            let address = json_str(entry, "address")?;
+            let amount = json_btc(entry, "amount")?;
+            self.insert_output(txid, vout, address, amount)?;
+        }
+        Ok(())
+    }
+
+    /// Imports an Electrum `onchain_history --show_addresses` dump
+    fn import_electrum_json(&mut self, json: &serde_json::Value) -> anyhow::Result<()> {
+        let txs = json
+            .get("transactions")
+            .unwrap_or(json)
+            .as_array()
+            .context("expected an array of transactions")?;
+        for tx in txs {
+            let txid = json_str(tx, "txid")?;
+            let outputs = tx
+                .get("outputs")
+                .and_then(serde_json::Value::as_array)
+                .with_context(|| {
+                    format!("no outputs for {txid} (was the export made with --show_addresses?)")
+                })?;
+            for (n, out) in outputs.iter().enumerate() {
+                let vout = match out.get("vout") {
+                    Some(vout) => {
+                        json_vout(vout).with_context(|| format!("bad vout {vout} for {txid}"))?
+                    }
+                    None => u32::try_from(n).context("too many outputs")?,
+                };
+                // Outputs without an address (e.g. OP_RETURN) cannot be deposits
+                if let Some(serde_json::Value::Null) = out.get("address") {
+                    continue;
+                }
+                let address = json_str(out, "address")?;
+                let amount = json_btc(out, "value")?;
+                self.insert_output(txid, vout, address, amount)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports a `txid:vout,address,amount,timestamp` CSV line
+    fn import_csv_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        if fields.len() != 4 {
+            return Err(anyhow::Error::msg(format!(
+                "expected 4 fields, got {}",
+                fields.len()
+            )));
+        }
+        let outpoint = bitcoin::OutPoint::from_str(fields[0])
+            .with_context(|| format!("parsing outpoint {}", fields[0]))?;
+        let amount = bitcoin::Amount::from_str_in(fields[2], bitcoin::Denomination::Bitcoin)
+            .with_context(|| format!("parsing amount {}", fields[2]))?;
+        // The timestamp is informational only, but check it's sane
+        crate::units::UtcTime::from_unix_str(fields[3])
+            .map_err(|e| anyhow::Error::msg(format!("parsing timestamp {}: {e}", fields[3])))?;
+        self.insert_output(&outpoint.txid.to_string(), outpoint.vout, fields[1], amount)
+    }
+
+    /// Inserts a single output into the database
+    fn insert_output(
+        &mut self,
+        txid: &str,
+        vout: u32,
+        address: &str,
+        value: bitcoin::Amount,
+    ) -> anyhow::Result<()> {
+        let txid = bitcoin::Txid::from_str(txid).with_context(|| format!("parsing txid {txid}"))?;
+        let addr = bitcoin::Address::from_str(address)
+            .with_context(|| format!("parsing BTC address {address}"))?
+            .require_network(bitcoin::Network::Bitcoin)
+            .with_context(|| format!("parsing address as BTC address {address}"))?;
+        let txout = bitcoin::TxOut {
+            value,
+            script_pubkey: addr.script_pubkey(),
+        };
+        let outpoint = bitcoin::OutPoint { txid, vout };
+        if let Some(existing) = self.find_txout(outpoint) {
+            if *existing != txout {
+                return Err(anyhow::Error::msg(format!(
+                    "conflicting data for output {outpoint}"
+                )));
+            }
+        }
+        self.outputs.insert(outpoint, txout);
+        Ok(())
     }
 
-    /// Look up a transaction matching a particular address/amount pair
+    /// Look up all transaction outputs matching a particular address/amount pair
     ///
     /// LX annoyingly does not provide any more information to identify transactions (well,
     /// there is also a timestamp but it's approximate). Furthermore, they dark-pattern
-    /// their users into reusing bitcoin addresses.
-    pub fn find_tx_for_deposit(
+    /// their users into reusing bitcoin addresses. So there may be several matches,
+    /// even within a single transaction, and it is up to the caller to choose.
+    ///
+    /// Returns the matching outpoints, in order, each with the full transaction if known.
+    pub fn deposit_candidates(
         &self,
         address: &bitcoin::Address<bitcoin::address::NetworkChecked>,
         amount: bitcoin::Amount,
-    ) -> Option<(&bitcoin::Transaction, u32)> {
+    ) -> Vec<(bitcoin::OutPoint, Option<&bitcoin::Transaction>)> {
+        let script_pubkey = address.script_pubkey();
+        let mut ret = BTreeMap::new();
+        for (outpoint, out) in &self.outputs {
+            if out.value == amount && out.script_pubkey == script_pubkey {
+                ret.insert(*outpoint, None);
+            }
+        }
+        // Full transactions take precedence over bare outputs from wallet exports
         for tx in self.map.values() {
             for (n, out) in tx.output.iter().enumerate() {
-                if out.value == amount && out.script_pubkey == address.script_pubkey() {
-                    return Some((tx, n as u32));
+                if out.value == amount && out.script_pubkey == script_pubkey {
+                    let outpoint = bitcoin::OutPoint {
+                        txid: tx.txid(),
+                        vout: n as u32,
+                    };
+                    ret.insert(outpoint, Some(tx));
                 }
             }
         }
-        None
+        ret.into_iter().collect()
     }
 
     /// Look up a specific txout
     pub fn find_txout(&self, outpoint: bitcoin::OutPoint) -> Option<&bitcoin::TxOut> {
-        self.map.get(&outpoint.txid).and_then(|tx| {
-            if tx.output.len() > outpoint.vout as usize {
-                Some(&tx.output[outpoint.vout as usize])
-            } else {
-                None
-            }
-        })
+        self.map
+            .get(&outpoint.txid)
+            .and_then(|tx| {
+                if tx.output.len() > outpoint.vout as usize {
+                    Some(&tx.output[outpoint.vout as usize])
+                } else {
+                    None
+                }
+            })
+            .or_else(|| self.outputs.get(&outpoint))
+    }
+}
+
+/// Decodes a hex-encoded raw transaction
+fn parse_tx_hex(s: &str) -> anyhow::Result<bitcoin::Transaction> {
+    let bytes: Vec<u8> =
+        bitcoin::hashes::hex::FromHex::from_hex(s).context("decoding string as hex")?;
+    bitcoin::consensus::deserialize(&bytes).context("decoding hex as transaction")
+}
+
+/// Extracts a string field from a JSON object
+fn json_str<'a>(obj: &'a serde_json::Value, field: &str) -> anyhow::Result<&'a str> {
+    obj.get(field)
+        .and_then(serde_json::Value::as_str)
+        .with_context(|| format!("missing string field {field} in {obj}"))
+}
+
+/// Extracts an (absolute) BTC amount from a JSON object, given as a number or string
+///
+/// JSON numbers are parsed as floats, which may be rendered with an exponent
+/// (Bitcoin Core gives small amounts as e.g. `1e-05`). A float holds any real
+/// BTC amount closely enough to recover it exactly, so we convert it directly.
+fn json_btc(obj: &serde_json::Value, field: &str) -> anyhow::Result<bitcoin::Amount> {
+    let amount = match obj.get(field) {
+        Some(serde_json::Value::String(s)) => {
+            bitcoin::SignedAmount::from_str_in(s, bitcoin::Denomination::Bitcoin)
+                .with_context(|| format!("parsing amount {s}"))?
+        }
+        Some(serde_json::Value::Number(n)) => n
+            .as_f64()
+            .and_then(|btc| bitcoin::SignedAmount::from_btc(btc).ok())
+            .with_context(|| format!("parsing amount {n}"))?,
+        _ => {
+            return Err(anyhow::Error::msg(format!(
+                "missing amount field {field} in {obj}"
+            )))
+        }
+    };
+    Ok(amount.abs().to_unsigned()?)
+}
+
+/// Extracts an output index from a JSON number
+fn json_vout(vout: &serde_json::Value) -> Option<u32> {
+    vout.as_u64().and_then(|n| u32::try_from(n).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn outpoint(vout: u32) -> bitcoin::OutPoint {
+        bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_str(TXID).unwrap(),
+            vout,
+        }
+    }
+
+    fn addr() -> bitcoin::Address {
+        bitcoin::Address::from_str(ADDR)
+            .unwrap()
+            .require_network(bitcoin::Network::Bitcoin)
+            .unwrap()
+    }
+
+    #[test]
+    fn import_csv() {
+        let mut db = Database::default();
+        db.import_wallet_export(&WalletExport::Csv(vec![format!(
+            "{TXID}:1,{ADDR},0.015,1672531200"
+        )]))
+        .unwrap();
+
+        let amount = bitcoin::Amount::from_sat(1_500_000);
+        assert_eq!(db.find_txout(outpoint(1)).unwrap().value, amount);
+        assert_eq!(
+            db.deposit_candidates(&addr(), amount),
+            vec![(outpoint(1), None)],
+        );
+        assert!(db
+            .deposit_candidates(&addr(), bitcoin::Amount::from_sat(1))
+            .is_empty());
+
+        // Bad lines are rejected
+        assert!(db
+            .import_wallet_export(&WalletExport::Csv(vec![format!("{TXID}:1,{ADDR},0.015")]))
+            .is_err());
+        // Conflicting data is rejected
+        assert!(db
+            .import_wallet_export(&WalletExport::Csv(vec![format!(
+                "{TXID}:1,{ADDR},0.016,1672531200"
+            )]))
+            .is_err());
+    }
+
+    #[test]
+    fn import_core() {
+        let json = serde_json::json!([
+            { "address": ADDR, "category": "send", "amount": -0.25, "vout": 0, "txid": TXID },
+            { "address": ADDR, "category": "receive", "amount": "0.00001", "vout": 2, "txid": TXID },
+        ]);
+        let mut db = Database::default();
+        db.import_wallet_export(&WalletExport::BitcoinCore(json))
+            .unwrap();
+        assert_eq!(
+            db.find_txout(outpoint(0)).unwrap().value,
+            bitcoin::Amount::from_sat(25_000_000)
+        );
+        assert_eq!(
+            db.find_txout(outpoint(2)).unwrap().value,
+            bitcoin::Amount::from_sat(1_000)
+        );
+
+        // Small numbers are rendered with an exponent, but still read exactly
+        let json = serde_json::json!([
+            { "address": ADDR, "category": "receive", "amount": 0.00000001, "vout": 3, "txid": TXID },
+            { "address": ADDR, "category": "receive", "amount": 1e-5, "vout": 4, "txid": TXID },
+        ]);
+        db.import_wallet_export(&WalletExport::BitcoinCore(json))
+            .unwrap();
+        assert_eq!(
+            db.find_txout(outpoint(3)).unwrap().value,
+            bitcoin::Amount::from_sat(1)
+        );
+        assert_eq!(
+            db.find_txout(outpoint(4)).unwrap().value,
+            bitcoin::Amount::from_sat(1_000)
+        );
+        // Output indices which do not fit are rejected
+        let json = serde_json::json!([
+            { "address": ADDR, "category": "receive", "amount": 1, "vout": 1u64 << 32, "txid": TXID },
+        ]);
+        assert!(db
+            .import_wallet_export(&WalletExport::BitcoinCore(json))
+            .is_err());
+    }
+
+    #[test]
+    fn import_electrum() {
+        let json = serde_json::json!({
+            "summary": {},
+            "transactions": [{
+                "txid": TXID,
+                "outputs": [
+                    { "address": ADDR, "value": "0.5" },
+                    { "address": ADDR, "value": "0.00012345" },
+                    { "address": null, "value": "0" },
+                    { "address": ADDR, "value": "0.001", "vout": 5 },
+                ],
+            }],
+        });
+        let mut db = Database::default();
+        db.import_wallet_export(&WalletExport::Electrum(json))
+            .unwrap();
+        assert_eq!(
+            db.find_txout(outpoint(0)).unwrap().value,
+            bitcoin::Amount::from_sat(50_000_000)
+        );
+        assert_eq!(
+            db.find_txout(outpoint(1)).unwrap().value,
+            bitcoin::Amount::from_sat(12_345)
+        );
+        assert_eq!(
+            db.find_txout(outpoint(5)).unwrap().value,
+            bitcoin::Amount::from_sat(100_000)
+        );
+        assert!(db.find_txout(outpoint(2)).is_none());
     }
 }
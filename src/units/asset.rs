@@ -45,6 +45,7 @@ pub enum Asset {
     Option {
         underlying: Underlying,
         option: crate::option::Option,
+        contract_size: ContractSize,
     },
     /// A future
     Future {
@@ -53,6 +54,48 @@ pub enum Asset {
     },
 }
 
+/// The size of an option contract
+///
+/// LX has only listed "mini" contracts since we started trading, but if it
+/// relists full-size contracts these must not be confused with minis of the
+/// same expiry and strike. Anything recorded before this distinction existed
+/// was a mini, which is therefore the default.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Default)]
+pub enum ContractSize {
+    /// A mini contract, for 1/100 BTC (or 1/10 ETH)
+    #[default]
+    Mini,
+    /// A full-size contract, for a whole coin
+    Full,
+}
+
+impl ContractSize {
+    /// Determines the contract size from the multiplier LX reports
+    pub fn from_multiplier(multiplier: usize) -> Self {
+        if multiplier > 1 {
+            ContractSize::Mini
+        } else {
+            ContractSize::Full
+        }
+    }
+
+    /// Number of mini contracts that one contract of this size is equivalent to
+    pub fn in_minis(&self) -> i64 {
+        match *self {
+            ContractSize::Mini => 1,
+            ContractSize::Full => 100,
+        }
+    }
+
+    /// The label used for this size in LX asset names, if any
+    fn label(&self) -> Option<&'static str> {
+        match *self {
+            ContractSize::Mini => Some("Mini"),
+            ContractSize::Full => None,
+        }
+    }
+}
+
 /// A kind of asset that can be deposited or withdrawn
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Deserialize)]
 pub enum DepositAsset {
@@ -71,7 +114,7 @@ impl From<DepositAsset> for Asset {
     fn from(dep: DepositAsset) -> Asset {
         match dep {
             DepositAsset::Btc => Asset::Btc,
-            DepositAsset::Eth => Asset::Btc,
+            DepositAsset::Eth => Asset::Eth,
             DepositAsset::Usd => Asset::Usd,
         }
     }
@@ -82,7 +125,9 @@ impl From<DepositAsset> for Asset {
 pub enum TaxAsset {
     /// Actual deposited BTC
     Bitcoin,
-    /// Next-Day Bitcoin
+    /// Actual deposited ETH
+    Ether,
+    /// Next-Day Bitcoin (or Ether)
     NextDay {
         underlying: Underlying,
         expiry: crate::units::UtcTime,
@@ -91,6 +136,7 @@ pub enum TaxAsset {
     Option {
         underlying: Underlying,
         option: crate::option::Option,
+        contract_size: ContractSize,
     },
 }
 
@@ -99,15 +145,25 @@ impl TaxAsset {
     pub fn is_bitcoin_like(&self) -> bool {
         match *self {
             TaxAsset::Bitcoin => true,
-            TaxAsset::NextDay { .. } => true,
+            TaxAsset::Ether => false,
+            TaxAsset::NextDay { underlying, .. } => underlying == Underlying::Btc,
             TaxAsset::Option { .. } => false,
         }
     }
 
+    /// Whether this asset is a coin held directly, rather than a contract on one
+    pub fn is_coin(&self) -> bool {
+        match *self {
+            TaxAsset::Bitcoin | TaxAsset::Ether => true,
+            TaxAsset::NextDay { .. } | TaxAsset::Option { .. } => false,
+        }
+    }
+
     /// Whether this asset gets sec. 1256 tax treatment
     pub fn is_1256(&self) -> bool {
         match *self {
             TaxAsset::Bitcoin => false,
+            TaxAsset::Ether => false,
             TaxAsset::NextDay { .. } => false,
             TaxAsset::Option { .. } => true,
         }
@@ -118,8 +174,17 @@ impl From<TaxAsset> for Asset {
     fn from(dep: TaxAsset) -> Asset {
         match dep {
             TaxAsset::Bitcoin => Asset::Btc,
+            TaxAsset::Ether => Asset::Eth,
             TaxAsset::NextDay { underlying, expiry } => Asset::NextDay { underlying, expiry },
-            TaxAsset::Option { underlying, option } => Asset::Option { underlying, option },
+            TaxAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => Asset::Option {
+                underlying,
+                option,
+                contract_size,
+            },
         }
     }
 }
@@ -128,12 +193,20 @@ impl fmt::Display for TaxAsset {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             TaxAsset::Bitcoin => f.write_str("BTC"),
-            TaxAsset::NextDay { .. } => f.write_str("BTC"),
-            TaxAsset::Option { underlying, option } => {
+            TaxAsset::Ether => f.write_str("ETH"),
+            TaxAsset::NextDay { underlying, .. } => fmt::Display::fmt(&underlying, f),
+            TaxAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => {
+                write!(f, "{underlying} ")?;
+                if let Some(label) = contract_size.label() {
+                    write!(f, "{label} ")?;
+                }
                 write!(
                     f,
-                    "{} Mini {} {} {:#}",
-                    underlying,
+                    "{} {} {:#}",
                     option.expiry.format("%F"),
                     option.pc.as_str(),
                     option.strike,
@@ -150,12 +223,20 @@ impl fmt::Display for TaxAsset2022 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.0 {
             TaxAsset::Bitcoin => f.write_str("BTC"),
-            TaxAsset::NextDay { .. } => f.write_str("BTC"),
-            TaxAsset::Option { underlying, option } => {
+            TaxAsset::Ether => f.write_str("ETH"),
+            TaxAsset::NextDay { underlying, .. } => fmt::Display::fmt(&underlying, f),
+            TaxAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => {
+                write!(f, "{underlying}-")?;
+                if let Some(label) = contract_size.label() {
+                    write!(f, "{label}-")?;
+                }
                 write!(
                     f,
-                    "{}-Mini-{:02}{}{}-{}-{}",
-                    underlying,
+                    "{:02}{}{}-{}-{}",
                     option.expiry.day(),
                     match option.expiry.month() {
                         1 => "JAN",
@@ -194,6 +275,7 @@ pub enum BudgetAsset {
     Option {
         underlying: Underlying,
         option: crate::option::Option,
+        contract_size: ContractSize,
     },
 }
 
@@ -201,8 +283,20 @@ impl From<TaxAsset> for BudgetAsset {
     fn from(tx: TaxAsset) -> BudgetAsset {
         match tx {
             TaxAsset::Bitcoin => BudgetAsset::Btc,
-            TaxAsset::NextDay { .. } => BudgetAsset::Btc,
-            TaxAsset::Option { underlying, option } => BudgetAsset::Option { underlying, option },
+            TaxAsset::Ether => BudgetAsset::Eth,
+            TaxAsset::NextDay { underlying, .. } => match underlying {
+                Underlying::Btc => BudgetAsset::Btc,
+                Underlying::Eth => BudgetAsset::Eth,
+            },
+            TaxAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => BudgetAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            },
         }
     }
 }
@@ -223,7 +317,15 @@ impl From<BudgetAsset> for Asset {
             BudgetAsset::Btc => Asset::Btc,
             BudgetAsset::Eth => Asset::Eth,
             BudgetAsset::Usd => Asset::Usd,
-            BudgetAsset::Option { underlying, option } => Asset::Option { underlying, option },
+            BudgetAsset::Option {
+                underlying,
+                option,
+                contract_size,
+            } => Asset::Option {
+                underlying,
+                option,
+                contract_size,
+            },
         }
     }
 }
@@ -239,6 +341,15 @@ pub enum Underlying {
     Eth,
 }
 
+impl From<Underlying> for TaxAsset {
+    fn from(dep: Underlying) -> TaxAsset {
+        match dep {
+            Underlying::Btc => TaxAsset::Bitcoin,
+            Underlying::Eth => TaxAsset::Ether,
+        }
+    }
+}
+
 impl From<Underlying> for Asset {
     fn from(dep: Underlying) -> Asset {
         match dep {
@@ -256,3 +367,32 @@ impl fmt::Display for Underlying {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn contract_size_display() {
+        let option = crate::option::Option::from_str("2023-12-29C25000").unwrap();
+        let mini = TaxAsset::Option {
+            underlying: Underlying::Btc,
+            option,
+            contract_size: ContractSize::from_multiplier(100),
+        };
+        let full = TaxAsset::Option {
+            underlying: Underlying::Btc,
+            option,
+            contract_size: ContractSize::from_multiplier(1),
+        };
+        assert_ne!(mini, full);
+        assert_eq!(mini.to_string(), "BTC Mini 2023-12-29 Call 25,000.00");
+        assert_eq!(full.to_string(), "BTC 2023-12-29 Call 25,000.00");
+        assert_eq!(
+            TaxAsset2022(mini).to_string(),
+            "BTC-Mini-29DEC2023-25000-Call"
+        );
+        assert_eq!(TaxAsset2022(full).to_string(), "BTC-29DEC2023-25000-Call");
+    }
+}
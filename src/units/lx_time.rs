@@ -0,0 +1,185 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! LX Timestamps
+//!
+//! LX returns timestamps in like a dozen different formats, depending on the
+//! endpoint and, it seems, the phase of the moon. Rather than have every
+//! deserializer guess at which one its endpoint uses, everything goes through
+//! the single tolerant parser in this module, which accepts all of them.
+//!
+//! Strings are RFC3339 or one of its many near-misses. Those without an
+//! offset are taken to be UTC, as are bare dates (at midnight). Numbers, or
+//! strings of digits, are UNIX timestamps, whose unit (seconds, milliseconds,
+//! microseconds or nanoseconds) is inferred from their magnitude. The file
+//! `lx-timestamps.txt` alongside this one has an example of each format.
+//!
+
+use super::utc_time::{Error, UtcTime};
+use serde::{de, Deserialize, Deserializer};
+
+/// Formats, with an offset, tried after RFC3339
+const OFFSET_FORMATS: &[&str] = &[
+    "%F %T%.f%z",
+    "%F %T%.f%:z",
+    "%FT%T%.f%z",
+    "%F %H:%M%z",
+    "%FT%H:%M%z",
+];
+
+/// Formats, without an offset (or with a literal Z), which are taken to be UTC
+const NAIVE_FORMATS: &[&str] = &["%F %T%.f", "%FT%T%.f", "%F %H:%M", "%FT%H:%M", "%FT%H:%MZ"];
+
+/// Parses a timestamp in any of the formats LX uses
+pub fn parse_lx_time(s: &str) -> Result<UtcTime, Error> {
+    let s = s.trim();
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        return match s.parse() {
+            Ok(n) => lx_time_from_unix(n),
+            Err(_) => Err(Error::UnknownFormat(s.to_owned())),
+        };
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    for format in OFFSET_FORMATS {
+        if let Ok(time) = chrono::DateTime::parse_from_str(s, format) {
+            return Ok(time.into());
+        }
+    }
+    for format in NAIVE_FORMATS {
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(s, format) {
+            return Ok(time.and_utc().into());
+        }
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%F") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().into());
+    }
+    Err(Error::UnknownFormat(s.to_owned()))
+}
+
+/// Interprets a UNIX timestamp whose unit is inferred from its magnitude
+///
+/// Anything from 10^17 is taken to be in nanoseconds, from 10^14 in
+/// microseconds, and from 10^11 in milliseconds; anything smaller is in
+/// seconds. In seconds, these thresholds are all thousands of years apart
+/// from the present, so there is no ambiguity in practice.
+pub fn lx_time_from_unix(n: i64) -> Result<UtcTime, Error> {
+    let nanos = match n.unsigned_abs() {
+        100_000_000_000_000_000.. => Some(n),
+        100_000_000_000_000.. => n.checked_mul(1_000),
+        100_000_000_000.. => n.checked_mul(1_000_000),
+        _ => n.checked_mul(1_000_000_000),
+    };
+    match nanos {
+        Some(nanos) => UtcTime::from_unix_nanos_i64(nanos),
+        None => Err(Error::UnixTimeOutOfRange(n)),
+    }
+}
+
+/// A timestamp as it appears in JSON
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Number(i64),
+    String(String),
+}
+
+impl Raw {
+    fn parse<E: de::Error>(self) -> Result<UtcTime, E> {
+        match self {
+            Raw::Number(n) => lx_time_from_unix(n).map_err(|_| {
+                de::Error::invalid_value(de::Unexpected::Signed(n), &"a valid UNIX timestamp")
+            }),
+            Raw::String(s) => parse_lx_time(&s).map_err(|_| {
+                de::Error::invalid_value(de::Unexpected::Str(&s), &"a timestamp in an LX format")
+            }),
+        }
+    }
+}
+
+/// Deserializes a timestamp in any of the formats LX uses
+pub fn deserialize_lx_time<'de, D>(deser: D) -> Result<UtcTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Raw::deserialize(deser)?.parse()
+}
+
+/// Deserializes an optional timestamp in any of the formats LX uses
+pub fn deserialize_lx_time_opt<'de, D>(deser: D) -> Result<Option<UtcTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Raw>::deserialize(deser)?
+        .map(Raw::parse)
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures() {
+        let mut n = 0;
+        for line in include_str!("lx-timestamps.txt").lines() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            let (input, expected) = line.split_once('\t').unwrap();
+            let expected = UtcTime::parse_coinbase(expected).unwrap();
+            assert_eq!(parse_lx_time(input).unwrap(), expected, "parsing {}", input);
+            n += 1;
+        }
+        assert!(n > 20);
+
+        for bad in [
+            "",
+            "yesterday",
+            "2023-13-01",
+            "2023-01-27T17",
+            "99999999999999999999",
+        ] {
+            assert!(parse_lx_time(bad).is_err(), "parsed {}", bad);
+        }
+    }
+
+    #[test]
+    fn deserialize() {
+        #[derive(Deserialize)]
+        struct Obj {
+            #[serde(deserialize_with = "deserialize_lx_time")]
+            time: UtcTime,
+            #[serde(default, deserialize_with = "deserialize_lx_time_opt")]
+            maybe: Option<UtcTime>,
+        }
+        let expected = UtcTime::parse_coinbase("2023-01-27T17:15:48.016616735Z").unwrap();
+        let obj: Obj = serde_json::from_str(r#"{"time":1674839748016616735}"#).unwrap();
+        assert_eq!(obj.time, expected);
+        assert_eq!(obj.maybe, None);
+        let obj: Obj = serde_json::from_str(
+            r#"{"time":"1674839748016616735","maybe":"2023-12-29 21:00:00+0000"}"#,
+        )
+        .unwrap();
+        assert_eq!(obj.time, expected);
+        assert_eq!(
+            obj.maybe,
+            Some(UtcTime::parse_coinbase("2023-12-29T21:00:00Z").unwrap())
+        );
+        let obj: Obj = serde_json::from_str(r#"{"time":"2023-01-27","maybe":null}"#).unwrap();
+        assert_eq!(obj.maybe, None);
+        assert!(serde_json::from_str::<Obj>(r#"{"time":"soon"}"#).is_err());
+    }
+}
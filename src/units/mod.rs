@@ -20,16 +20,20 @@
 //!
 
 mod asset;
+mod lx_time;
 mod price;
 mod quantity;
 mod utc_time;
 
-pub use asset::{Asset, BudgetAsset, DepositAsset, TaxAsset, TaxAsset2022, Underlying};
+pub use asset::{
+    Asset, BudgetAsset, ContractSize, DepositAsset, TaxAsset, TaxAsset2022, Underlying,
+};
+pub use lx_time::{deserialize_lx_time, deserialize_lx_time_opt, lx_time_from_unix, parse_lx_time};
 pub use price::{
     deserialize_cents, deserialize_cents_opt, deserialize_dollars, serialize_dollars, Price,
 };
-pub use quantity::{Quantity, UnknownQuantity};
-pub use utc_time::{deserialize_datetime, serde_ts_seconds, UtcTime};
+pub use quantity::{Quantity, UnknownQuantity, GWEI_PER_ETH};
+pub use utc_time::{serde_ts_seconds, UtcTime};
 
 macro_rules! impl_ops_0 {
     ($outer:ty, $op:ident, $opfn:ident) => {
@@ -16,6 +16,13 @@
 //!
 //! Prices in US dollars
 //!
+//! Prices are decimals with 28 significant digits, so addition, subtraction
+//! and multiplication by integers are exact for any realistic price. Where a
+//! helper may round, its documentation says in which direction. Division in
+//! `rust_decimal` rounds to the nearest representable value (ties to even)
+//! once the 28 digits are used up, which for dollar amounts is far below a
+//! cent but is not nothing.
+//!
 
 use super::Quantity;
 use rust_decimal::prelude::ToPrimitive;
@@ -46,6 +53,12 @@ impl Price {
     /// Some prices cannot be represented exactly (e.g. $0.10) in a binary
     /// representation such as IEEE floats. So this method will introduce
     /// a tiny error factor, which may be amplified by further computations.
+    ///
+    /// The result is rounded to the nearest float, so may be on either side
+    /// of the true value. Converting back with [`Price::from_approx_f64_or_zero`]
+    /// is not exact either: for whole-cent prices below $10^7 the round trip
+    /// is off by at most a billionth of a dollar, so rounding to the nearest
+    /// cent recovers the price (but truncating may not).
     pub fn to_approx_f64(&self) -> f64 {
         self.0.to_f64().unwrap()
     }
@@ -55,11 +68,20 @@ impl Price {
     /// If the conversion cannot be done, substitutes 0. This function is really
     /// meant for display/informational purposes only and should not be used for
     /// accounting.
+    ///
+    /// Excess binary digits are dropped, so that e.g. the float nearest to 0.1
+    /// becomes exactly $0.10, rather than the float's exact binary value. The
+    /// result may therefore be on either side of the float's value.
     pub fn from_approx_f64_or_zero(p: f64) -> Price {
         Price(Decimal::try_from(p).unwrap_or_default())
     }
 
     /// Computes a weighted average of two prices
+    ///
+    /// The weighted sum is exact; the division by the total quantity rounds
+    /// to nearest (ties to even) at the 28th significant digit, so the result
+    /// is always between the two prices but may differ from the exact average
+    /// in its last digit.
     pub fn average(self, self_qty: Quantity, other: Self, other_qty: Quantity) -> Self {
         let total = self_qty + other_qty;
         // Since qunatities are nonnegative, the only way total can be
@@ -83,54 +105,122 @@ impl Price {
         self.0.floor().into()
     }
 
+    /// Rounds up to the nearest multiple of `tick`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick` is not positive.
+    pub fn round_up_to(&self, tick: Price) -> Self {
+        assert!(tick > Price::ZERO, "rounding to non-positive tick {}", tick);
+        Price((self.0 / tick.0).ceil() * tick.0)
+    }
+
+    /// Rounds down to the nearest multiple of `tick`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick` is not positive.
+    pub fn round_down_to(&self, tick: Price) -> Self {
+        assert!(tick > Price::ZERO, "rounding to non-positive tick {}", tick);
+        Price((self.0 / tick.0).floor() * tick.0)
+    }
+
+    /// Whether the price is a whole multiple of `tick`
+    pub fn is_multiple_of(&self, tick: Price) -> bool {
+        tick > Price::ZERO && (self.0 % tick.0).is_zero()
+    }
+
     /// Multiplies the price by a given scaling factor
     ///
     /// Because this uses floating-point numbers it will not give an exact
     /// result. Extreme caution should be used whenever using this in an
-    /// accounting context.
+    /// accounting context. Where the factor is a ratio of integers, use
+    /// [`Price::scale_ratio`] instead.
+    ///
+    /// The factor is first converted to a decimal as by
+    /// [`Price::from_approx_f64_or_zero`], so e.g. a factor of 1.1 scales by
+    /// exactly 1.1, but a computed factor such as 1.0 / 3.0 is only accurate
+    /// to the 15 or so digits of the float, in either direction.
     pub fn scale_approx(&self, scale: f64) -> Price {
         Price(self.0 * Decimal::try_from(scale).expect("scaling by a finite float"))
     }
 
+    /// Multiplies the price by the ratio `numerator / denominator`
+    ///
+    /// The multiplication is exact; the division rounds to nearest (ties to
+    /// even) at the 28th significant digit, so the result is exact whenever
+    /// the scaled price has a terminating decimal expansion which fits, e.g.
+    /// for any denominator made up of 2s and 5s.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `denominator` is zero.
+    pub fn scale_ratio(&self, numerator: i64, denominator: i64) -> Price {
+        assert_ne!(denominator, 0, "scaling {} by a ratio over zero", self);
+        Price(self.0 * Decimal::from(numerator) / Decimal::from(denominator))
+    }
+
     /// Absolute value of the price
     pub fn abs(&self) -> Price {
         Price(self.0.abs())
     }
 
     /// Given a price, return 1/100 the same price
+    ///
+    /// Exact unless the price already uses all 28 significant digits.
     pub fn one_hundredth(&self) -> Price {
         Price(self.0 / Decimal::ONE_HUNDRED)
     }
 
     /// Given a price, double it
+    ///
+    /// Exact.
     pub fn double(&self) -> Price {
         Price(self.0 * Decimal::from(2))
     }
 
     /// Given a price, halve it
+    ///
+    /// Exact unless the price already uses all 28 significant digits, in
+    /// which case the last digit rounds to nearest (ties to even). Repeated
+    /// halving of a whole-dollar price is exact for at least 28 halvings.
     pub fn half(&self) -> Price {
         Price(self.0 / Decimal::from(2))
     }
 
     /// Given a price, return 40% of the price (used for 1256 tax calculations)
+    ///
+    /// Exact unless the price already uses all 28 significant digits.
     pub fn forty(&self) -> Price {
         Price(self.0 * Decimal::from(2) / Decimal::from(5))
     }
 
     /// Given a price, return 60% of the price (used for 1256 tax calculations)
+    ///
+    /// Exact unless the price already uses all 28 significant digits. Together
+    /// with [`Price::forty`], always sums to the original price.
     pub fn sixty(&self) -> Price {
         Price(self.0 * Decimal::from(3) / Decimal::from(5))
     }
 
     /// Convert the value to an integer, truncating any fractional part
+    ///
+    /// Rounds toward zero.
     pub fn to_int(&self) -> i64 {
         self.0.to_i64().unwrap()
     }
 
     /// Convert the value to an integer number of cents, truncating any sub-cent part
+    ///
+    /// Rounds toward zero.
     pub fn to_cents(&self) -> i64 {
         (self.0 * Decimal::ONE_HUNDRED).to_i64().unwrap()
     }
+
+    /// Construct a price from an integer number of cents
+    pub fn from_cents(cents: i64) -> Self {
+        Price(Decimal::new(cents, 2))
+    }
 }
 
 impl From<Decimal> for Price {
@@ -139,6 +229,12 @@ impl From<Decimal> for Price {
     }
 }
 
+impl From<Price> for Decimal {
+    fn from(p: Price) -> Decimal {
+        p.0
+    }
+}
+
 impl str::FromStr for Price {
     type Err = rust_decimal::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -219,6 +315,8 @@ impl ops::Mul<Quantity> for Price {
         match other {
             Quantity::Bitcoin(btc) => Price(self.0 * Decimal::new(btc.to_sat(), 8)),
             Quantity::Contracts(n) => Price(self.0 * Decimal::new(n, 2)),
+            Quantity::Ether(n) => Price(self.0 * Decimal::new(n, 9)),
+            Quantity::EthContracts(n) => Price(self.0 * Decimal::new(n, 1)),
             Quantity::Cents(_) => panic!(
                 "Tried to multiply price {} by dollar-quantity {}",
                 self, other
@@ -239,6 +337,8 @@ impl ops::Div<Quantity> for Price {
         match other {
             Quantity::Bitcoin(btc) => Price(self.0 / Decimal::new(btc.to_sat(), 8)),
             Quantity::Contracts(n) => Price(self.0 / Decimal::new(n, 2)),
+            Quantity::Ether(n) => Price(self.0 / Decimal::new(n, 9)),
+            Quantity::EthContracts(n) => Price(self.0 / Decimal::new(n, 1)),
             Quantity::Cents(_) => panic!(
                 "Tried to divide price {} by dollar-quantity {}",
                 self, other
@@ -305,6 +405,59 @@ mod tests {
         assert!("123xy".parse::<Price>().is_err());
     }
 
+    /// Randomized checks of the rounding behavior documented on each helper
+    #[test]
+    fn rounding() {
+        use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let p = Price::from_cents(rng.gen_range(-100_000_000_000_000..100_000_000_000_000));
+            let q = Price::from_cents(rng.gen_range(0..10_000_000_000));
+
+            // Exact helpers
+            assert_eq!(p.half().double(), p);
+            assert_eq!(p.double().half(), p);
+            assert_eq!(p.forty() + p.sixty(), p);
+            assert_eq!(p.one_hundredth().scale_ratio(100, 1), p);
+            assert_eq!(p.scale_ratio(3, 8).scale_ratio(8, 3), p);
+            assert_eq!(p.scale_ratio(10_025, 10_000), p + p.scale_ratio(25, 10_000));
+            assert_eq!(Price::from_cents(p.to_cents()), p);
+
+            // Float round-trips are within a billionth of a dollar below $10^7
+            let small = Price::from_cents(p.to_cents() % 1_000_000_000);
+            let round_trip = Price::from_approx_f64_or_zero(small.to_approx_f64());
+            assert!((round_trip - small).abs() <= Price(Decimal::new(1, 9)));
+            assert_eq!(Price(round_trip.0.round_dp(2)), small);
+
+            // Division by a non-terminating ratio rounds in the 28th digit
+            let third = p.scale_ratio(1, 3);
+            let err = (third.scale_ratio(3, 1) - p).abs();
+            assert!(
+                err <= Price(Decimal::new(1, 14)),
+                "{} / 3 * 3 off by {}",
+                p,
+                err
+            );
+
+            // Averages lie between their inputs
+            let n = rng.gen_range(1..1000);
+            let m = rng.gen_range(1..1000);
+            let avg = p.average(Quantity::Contracts(n), q, Quantity::Contracts(m));
+            assert!(
+                avg >= p.min(q) && avg <= p.max(q),
+                "{} avg {} = {}",
+                p,
+                q,
+                avg
+            );
+            assert_eq!(
+                p.average(Quantity::Contracts(n), p, Quantity::Contracts(m)),
+                p
+            );
+        }
+    }
+
     #[test]
     fn price_display() {
         assert_eq!(format!("{}", price!(123)), "123.00");
@@ -35,8 +35,18 @@ pub enum Quantity {
     Cents(i64),
     /// A (signed) number of contracts
     Contracts(i64),
+    /// An (signed) amount of ether, in gwei
+    Ether(i64),
+    /// A (signed) number of ETH option contracts, each for 1/10 ETH
+    EthContracts(i64),
 }
 
+/// Number of gwei in one ether
+pub const GWEI_PER_ETH: i64 = 1_000_000_000;
+
+/// Number of wei in one gwei
+const WEI_PER_GWEI: i64 = 1_000_000_000;
+
 impl Quantity {
     /// Constructs a quantity of contracts by multiplying a number of BTC by 100
     pub fn contracts_from_ratio(available: Price, price_per_100: Price) -> Quantity {
@@ -64,12 +74,19 @@ impl Quantity {
         Quantity::Contracts(n)
     }
 
+    /// Constructs a quantity of ether from a number of gwei
+    pub fn from_gwei(n: i64) -> Quantity {
+        Quantity::Ether(n)
+    }
+
     /// The absolute value of a quantity
     pub fn abs(&self) -> Quantity {
         match *self {
             Quantity::Bitcoin(btc) => Quantity::Bitcoin(btc.abs()),
             Quantity::Contracts(n) => Quantity::Contracts(n.abs()),
             Quantity::Cents(n) => Quantity::Cents(n.abs()),
+            Quantity::Ether(n) => Quantity::Ether(n.abs()),
+            Quantity::EthContracts(n) => Quantity::EthContracts(n.abs()),
             Quantity::Zero => Quantity::Zero,
         }
     }
@@ -80,10 +97,25 @@ impl Quantity {
             Quantity::Bitcoin(btc) => btc,
             Quantity::Contracts(n) => bitcoin::SignedAmount::from_sat(n * 1_000_000),
             Quantity::Cents(_) => panic!("tried to convert USD to Bitcoin"),
+            Quantity::Ether(_) | Quantity::EthContracts(_) => {
+                panic!("tried to convert ETH to Bitcoin")
+            }
             Quantity::Zero => bitcoin::SignedAmount::ZERO,
         }
     }
 
+    /// Returns the amount of the underlying coin, for a quantity of coins or contracts
+    ///
+    /// Contracts are converted to BTC or ETH according to their size.
+    pub fn coin_equivalent(&self) -> Quantity {
+        match *self {
+            Quantity::Contracts(_) => Quantity::Bitcoin(self.btc_equivalent()),
+            Quantity::EthContracts(n) => Quantity::Ether(n * GWEI_PER_ETH / 10),
+            Quantity::Cents(_) => panic!("tried to convert USD to a coin"),
+            Quantity::Bitcoin(_) | Quantity::Ether(_) | Quantity::Zero => *self,
+        }
+    }
+
     /// Shorthand for `qty.abs().btc_equivalent().to_unsigned().unwrap()`
     pub fn abs_btc_equivalent(&self) -> bitcoin::Amount {
         // unwrap OK since we are guaranteed to have a nonnegative amount
@@ -96,6 +128,7 @@ impl Quantity {
             Quantity::Bitcoin(btc) => !btc.is_negative(),
             Quantity::Contracts(n) => n >= 0,
             Quantity::Cents(n) => n >= 0,
+            Quantity::Ether(n) | Quantity::EthContracts(n) => n >= 0,
             Quantity::Zero => true,
         }
     }
@@ -106,6 +139,7 @@ impl Quantity {
             Quantity::Bitcoin(btc) => btc.is_negative(),
             Quantity::Contracts(n) => n < 0,
             Quantity::Cents(n) => n < 0,
+            Quantity::Ether(n) | Quantity::EthContracts(n) => n < 0,
             Quantity::Zero => false,
         }
     }
@@ -116,6 +150,7 @@ impl Quantity {
             Quantity::Bitcoin(btc) => btc.is_positive(),
             Quantity::Contracts(n) => n > 0,
             Quantity::Cents(n) => n > 0,
+            Quantity::Ether(n) | Quantity::EthContracts(n) => n > 0,
             Quantity::Zero => false,
         }
     }
@@ -126,6 +161,7 @@ impl Quantity {
             Quantity::Bitcoin(btc) => btc.to_sat() != 0,
             Quantity::Contracts(n) => n != 0,
             Quantity::Cents(n) => n != 0,
+            Quantity::Ether(n) | Quantity::EthContracts(n) => n != 0,
             Quantity::Zero => false,
         }
     }
@@ -156,6 +192,8 @@ impl Quantity {
             (Quantity::Bitcoin(_), Quantity::Bitcoin(_)) => true,
             (Quantity::Contracts(_), Quantity::Contracts(_)) => true,
             (Quantity::Cents(_), Quantity::Cents(_)) => true,
+            (Quantity::Ether(_), Quantity::Ether(_)) => true,
+            (Quantity::EthContracts(_), Quantity::EthContracts(_)) => true,
             _ => false,
         }
     }
@@ -202,6 +240,10 @@ impl Quantity {
             (Quantity::Contracts(n), Quantity::Contracts(other)) => {
                 Quantity::Contracts(cmp::min(n, other))
             }
+            (Quantity::Ether(n), Quantity::Ether(other)) => Quantity::Ether(cmp::min(n, other)),
+            (Quantity::EthContracts(n), Quantity::EthContracts(other)) => {
+                Quantity::EthContracts(cmp::min(n, other))
+            }
             _ => panic!("Cannot take minimum of {} and {}", self, other),
         }
     }
@@ -220,6 +262,14 @@ impl fmt::Display for Quantity {
                 fmt::Display::fmt(&(n / 100), f)?;
                 write!(f, ".{:02}", n % 100)
             }
+            Quantity::Ether(n) => {
+                fmt::Display::fmt(&rust_decimal::Decimal::new(*n, 9).normalize(), f)?;
+                f.write_str(" ETH")
+            }
+            Quantity::EthContracts(n) => {
+                fmt::Display::fmt(&n, f)?;
+                f.write_str(" ETH cts")
+            }
             Quantity::Zero => fmt::Display::fmt("ZERO", f),
         }
     }
@@ -243,12 +293,20 @@ impl cmp::PartialOrd for Quantity {
             (Quantity::Bitcoin(amt), Quantity::Bitcoin(other)) => amt.partial_cmp(other),
             (Quantity::Contracts(n), Quantity::Contracts(other)) => n.partial_cmp(other),
             (Quantity::Cents(n), Quantity::Cents(other)) => n.partial_cmp(other),
+            (Quantity::Ether(n), Quantity::Ether(other)) => n.partial_cmp(other),
+            (Quantity::EthContracts(n), Quantity::EthContracts(other)) => n.partial_cmp(other),
             (Quantity::Bitcoin(amt), Quantity::Zero) => amt.to_sat().partial_cmp(&0),
             (Quantity::Zero, Quantity::Bitcoin(amt)) => 0.partial_cmp(&amt.to_sat()),
             (Quantity::Contracts(n), Quantity::Zero) => n.partial_cmp(&0),
             (Quantity::Zero, Quantity::Contracts(n)) => 0.partial_cmp(n),
             (Quantity::Cents(n), Quantity::Zero) => n.partial_cmp(&0),
             (Quantity::Zero, Quantity::Cents(n)) => 0.partial_cmp(n),
+            (Quantity::Ether(n), Quantity::Zero) | (Quantity::EthContracts(n), Quantity::Zero) => {
+                n.partial_cmp(&0)
+            }
+            (Quantity::Zero, Quantity::Ether(n)) | (Quantity::Zero, Quantity::EthContracts(n)) => {
+                0.partial_cmp(n)
+            }
             _ => None,
         }
     }
@@ -265,6 +323,8 @@ impl ops::Neg for Quantity {
             ),
             Quantity::Contracts(n) => Quantity::Contracts(-n),
             Quantity::Cents(n) => Quantity::Cents(-n),
+            Quantity::Ether(n) => Quantity::Ether(-n),
+            Quantity::EthContracts(n) => Quantity::EthContracts(-n),
         }
     }
 }
@@ -283,6 +343,10 @@ impl ops::Add for Quantity {
                     Quantity::Contracts(n + other)
                 }
                 (Quantity::Cents(n), Quantity::Cents(other)) => Quantity::Cents(n + other),
+                (Quantity::Ether(n), Quantity::Ether(other)) => Quantity::Ether(n + other),
+                (Quantity::EthContracts(n), Quantity::EthContracts(other)) => {
+                    Quantity::EthContracts(n + other)
+                }
                 _ => panic!("Cannot add {} to {}", other, self),
             }
         }
@@ -345,21 +409,33 @@ impl UnknownQuantity {
         From::from(n)
     }
 
+    /// The quantity as an integer number of base units
+    pub fn to_i64(&self) -> i64 {
+        self.inner
+    }
+
     /// Whether this quantity is nonzero
     pub fn is_nonzero(&self) -> bool {
         self.inner != 0
     }
 
     /// Define the quantity based on a given asset
+    ///
+    /// Amounts of bitcoin are in satoshis, and amounts of ether in wei, which
+    /// are truncated to the gwei we track them in.
     pub fn with_asset(&self, asset: Asset) -> Quantity {
         match asset {
             Asset::Btc => Quantity::Bitcoin(bitcoin::SignedAmount::from_sat(self.inner)),
             Asset::NextDay { underlying, .. } => match underlying {
                 Underlying::Btc => Quantity::Bitcoin(bitcoin::SignedAmount::from_sat(self.inner)),
-                Underlying::Eth => unimplemented!("ethereum nextday quantity"),
+                Underlying::Eth => Quantity::Ether(self.inner / WEI_PER_GWEI),
             },
-            Asset::Eth => unimplemented!("ethereum quantity"),
+            Asset::Eth => Quantity::Ether(self.inner / WEI_PER_GWEI),
             Asset::Usd => Quantity::Cents(self.inner),
+            Asset::Option {
+                underlying: Underlying::Eth,
+                ..
+            } => Quantity::EthContracts(self.inner),
             Asset::Option { .. } => Quantity::Contracts(self.inner),
             Asset::Future { .. } => Quantity::Contracts(self.inner),
         }
@@ -376,10 +452,14 @@ impl UnknownQuantity {
                 Underlying::Btc => {
                     Quantity::Bitcoin(bitcoin::SignedAmount::from_sat(self.inner * 1_000_000))
                 }
-                Underlying::Eth => unimplemented!("ethereum nextday quantity"),
+                Underlying::Eth => Quantity::Ether(self.inner * GWEI_PER_ETH / 100),
             },
-            Asset::Eth => unimplemented!("ethereum quantity"),
+            Asset::Eth => Quantity::Ether(self.inner * GWEI_PER_ETH / 100),
             Asset::Usd => Quantity::Cents(self.inner),
+            Asset::Option {
+                underlying: Underlying::Eth,
+                ..
+            } => Quantity::EthContracts(self.inner),
             Asset::Option { .. } => Quantity::Contracts(self.inner),
             Asset::Future { .. } => Quantity::Contracts(self.inner),
         }
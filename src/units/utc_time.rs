@@ -22,12 +22,19 @@ use chrono::{DateTime, Datelike as _, ParseError, Timelike as _};
 use core::str::FromStr as _;
 use core::{fmt, num, ops};
 use serde::{de, Deserialize, Deserializer};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Correction, in milliseconds, added to the local clock by [`UtcTime::now`]
+static CLOCK_CORRECTION_MS: AtomicI64 = AtomicI64::new(0);
+/// If nonzero, the time (in UNIX nanoseconds) returned by [`UtcTime::now`]
+static SIMULATED_TIME_NS: AtomicI64 = AtomicI64::new(0);
 
 #[derive(Debug)]
 pub enum Error {
     Parse(ParseError),
     ParseNum(num::ParseIntError),
     UnixTimeOutOfRange(i64),
+    UnknownFormat(String),
 }
 
 impl From<ParseError> for Error {
@@ -44,6 +51,7 @@ impl fmt::Display for Error {
             Error::UnixTimeOutOfRange(n) => {
                 write!(f, "timestamp {n} out of range for UNIX timestamp")
             }
+            Error::UnknownFormat(ref s) => write!(f, "timestamp {s} in unknown format"),
         }
     }
 }
@@ -54,6 +62,7 @@ impl std::error::Error for Error {
             Error::Parse(ref e) => Some(e),
             Error::ParseNum(ref e) => Some(e),
             Error::UnixTimeOutOfRange(_) => None,
+            Error::UnknownFormat(_) => None,
         }
     }
 }
@@ -67,11 +76,39 @@ pub struct UtcTime {
 }
 
 impl UtcTime {
-    /// Returns the current time
+    /// Returns the current time, corrected for any known skew of the local clock
+    ///
+    /// If we are running in simulated time, returns the simulated time instead.
     pub fn now() -> Self {
+        match SIMULATED_TIME_NS.load(Ordering::Relaxed) {
+            0 => UtcTime::now_uncorrected() + UtcTime::clock_correction(),
+            n => UtcTime::from_unix_nanos_i64(n).expect("simulated time in range"),
+        }
+    }
+
+    /// Switches [`UtcTime::now`] to simulated time, which is frozen at the
+    /// given time until this is called again
+    ///
+    /// Used when replaying logs. There is no way back to the real clock.
+    pub fn set_simulated(time: UtcTime) {
+        SIMULATED_TIME_NS.store(time.to_unix_nanos_i64(), Ordering::Relaxed);
+    }
+
+    /// Returns the current time according to the local clock
+    pub fn now_uncorrected() -> Self {
         UtcTime { inner: Utc::now() }
     }
 
+    /// The correction currently applied to the local clock by [`UtcTime::now`]
+    pub fn clock_correction() -> chrono::Duration {
+        chrono::Duration::milliseconds(CLOCK_CORRECTION_MS.load(Ordering::Relaxed))
+    }
+
+    /// Sets the correction applied to the local clock by [`UtcTime::now`]
+    pub fn set_clock_correction(correction: chrono::Duration) {
+        CLOCK_CORRECTION_MS.store(correction.num_milliseconds(), Ordering::Relaxed);
+    }
+
     /// Parses a UNIX timestamp from an integer number of seconds
     pub fn from_unix_nanos_i64(n: i64) -> Result<Self, Error> {
         Ok(UtcTime {
@@ -105,6 +142,16 @@ impl UtcTime {
 
     /// Returns the current time in New York
     pub fn new_york_time(&self) -> chrono::NaiveTime {
+        self.new_york_datetime().time()
+    }
+
+    /// Returns the current day of the week in New York
+    pub fn new_york_weekday(&self) -> chrono::Weekday {
+        self.new_york_datetime().weekday()
+    }
+
+    /// Returns the current date and time in New York
+    fn new_york_datetime(&self) -> DateTime<chrono::offset::FixedOffset> {
         // Rather than dealing with a bunch of "2AM on the second sunday" bullshit,
         // we just assume that DST happens at midnight UTC (which is 9 or 10PM in
         // New York so the market is never open) and just fix the dates. Hopefully
@@ -146,24 +193,39 @@ impl UtcTime {
             2051 => panic!("you need to update the DST table in src/units/utc_time.rs"),
             _ => edt_tz,
         };
-        self.inner.with_timezone(&tz).time()
+        self.inner.with_timezone(&tz)
     }
 
     /// Finds the most recent Friday to the given date.
     ///
     /// On Friday, returns a week ago..
     pub fn last_friday(&self) -> Self {
+        self.last_weekday(chrono::Weekday::Fri)
+    }
+
+    /// Finds the most recent given day of the week before the given date.
+    ///
+    /// On that day itself, returns a week ago.
+    pub fn last_weekday(&self, day: chrono::Weekday) -> Self {
+        let today = self.inner.weekday().num_days_from_monday();
+        let offset = match (today + 7 - day.num_days_from_monday()) % 7 {
+            0 => 7,
+            n => n,
+        };
+        UtcTime {
+            inner: self.inner - chrono::Duration::days(offset.into()),
+        }
+    }
+
+    /// Finds the most recent weekday (Monday to Friday) before the given date.
+    pub fn last_trading_day(&self) -> Self {
         let offset = match self.inner.weekday() {
-            chrono::Weekday::Sat => 1,
             chrono::Weekday::Sun => 2,
             chrono::Weekday::Mon => 3,
-            chrono::Weekday::Tue => 4,
-            chrono::Weekday::Wed => 5,
-            chrono::Weekday::Thu => 6,
-            chrono::Weekday::Fri => 7,
+            _ => 1,
         };
         UtcTime {
-            inner: self.inner - chrono::Duration::days(offset.into()),
+            inner: self.inner - chrono::Duration::days(offset),
         }
     }
 
@@ -189,6 +251,20 @@ impl UtcTime {
         Self::from_unix_i64(i)
     }
 
+    /// Converts to a UNIX timestamp, as an integer number of seconds
+    pub fn to_unix_i64(&self) -> i64 {
+        self.inner.timestamp()
+    }
+
+    /// Converts to a UNIX timestamp, as an integer number of nanoseconds
+    ///
+    /// # Panics
+    ///
+    /// Panics for times after 2262, which do not fit.
+    pub fn to_unix_nanos_i64(&self) -> i64 {
+        self.inner.timestamp_nanos_opt().unwrap()
+    }
+
     /// Creates an object which can be given to a formatter
     pub fn format<'s>(&self, s: &'s str) -> impl fmt::Display + 's {
         self.inner.format(s)
@@ -273,16 +349,6 @@ impl ops::Sub for UtcTime {
     }
 }
 
-pub fn deserialize_datetime<'de, D>(deser: D) -> Result<UtcTime, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: &str = Deserialize::deserialize(deser)?;
-    UtcTime::parse_coinbase(s).map_err(|_| {
-        de::Error::invalid_value(de::Unexpected::Str(s), &"a datetime in %FT%T%z format")
-    })
-}
-
 pub mod serde_ts_seconds {
     use super::*;
 
@@ -304,4 +370,24 @@ pub mod serde_ts_seconds {
     {
         Serialize::serialize(&obj.inner.timestamp(), ser)
     }
+
+    pub fn serialize_opt<S>(obj: &Option<UtcTime>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Serialize::serialize(&obj.map(|t| t.inner.timestamp()), ser)
+    }
+
+    pub fn deserialize_opt<'de, D>(deser: D) -> Result<Option<UtcTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let n: Option<i64> = Deserialize::deserialize(deser)?;
+        n.map(|n| {
+            UtcTime::from_unix_i64(n).map_err(|_| {
+                de::Error::invalid_value(de::Unexpected::Signed(n), &"a valid UNIX timestamp")
+            })
+        })
+        .transpose()
+    }
 }
@@ -0,0 +1,96 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Reconnecting Websockets
+//!
+//! Our optional price feeds (Kraken and Bitstamp) are each only one of several
+//! price sources, so if one cannot be reached we log the failure and keep
+//! retrying with backoff, and the consensus carries on without it in the
+//! meantime. This module holds the reconnection loop they share; each feed
+//! supplies only how to connect and how to handle a message.
+//!
+
+use crate::supervisor::Heartbeat;
+use log::{info, warn};
+use std::ops::ControlFlow;
+use std::time;
+
+/// Delay before reconnecting after a failure; doubled for each failure after
+const RECONNECT_BACKOFF: time::Duration = time::Duration::from_secs(1);
+/// Longest delay between reconnection attempts
+const MAX_RECONNECT_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// A websocket connection, possibly over TLS
+pub type Socket = tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<std::net::TcpStream>>;
+
+/// Connects with `connect`, passing each text message received to `handle`,
+/// and reconnects whenever the connection drops or `handle` breaks, until the
+/// heartbeat is retired
+///
+/// Failures to connect, and connections which are dropped before giving us
+/// anything, are retried with exponential backoff, so that we do not hammer a
+/// feed while it is down.
+pub fn run_reconnecting<C, H>(name: &str, heartbeat: &Heartbeat, mut connect: C, mut handle: H)
+where
+    C: FnMut() -> anyhow::Result<Socket>,
+    H: FnMut(&str) -> ControlFlow<()>,
+{
+    let mut delay = RECONNECT_BACKOFF;
+    loop {
+        let mut sock = match connect() {
+            Ok(sock) => sock,
+            Err(e) => {
+                warn!(
+                    "Failed to connect to {}; retrying in {}s: {:#}",
+                    name,
+                    delay.as_secs(),
+                    e
+                );
+                if !heartbeat.wait(delay) {
+                    info!("{} thread replaced; exiting.", name);
+                    return;
+                }
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+
+        let mut received = false;
+        while let Ok(tungstenite::protocol::Message::Text(msg)) = sock.read_message() {
+            if heartbeat.is_retired() {
+                info!("{} thread replaced; exiting.", name);
+                return;
+            }
+            heartbeat.beat();
+            received = true;
+            delay = RECONNECT_BACKOFF;
+            if handle(&msg).is_break() {
+                break;
+            }
+        }
+        if !received {
+            warn!(
+                "{} connection closed without any messages; retrying in {}s",
+                name,
+                delay.as_secs()
+            );
+            if !heartbeat.wait(delay) {
+                info!("{} thread replaced; exiting.", name);
+                return;
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+        info!("Restarting connection to {}.", name);
+    }
+}
@@ -0,0 +1,161 @@
+// Trade Tracker
+// Written in 2024 by
+//   Andrew Poelstra <tradetracker@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! XLSX
+//!
+//! Writes reports as Excel workbooks. When a CSV file is opened in Excel, it
+//! "helpfully" reformats dates, strips leading zeros and rounds big integers.
+//! By writing the workbook ourselves we choose the type of every cell: only
+//! plain decimal numbers which Excel can represent exactly are written as
+//! numbers, and everything else (dates, lot IDs, etc) is written as text.
+//!
+//! The sheets are built from the same lines we write to the CSV files, so
+//! that the two formats cannot disagree.
+//!
+
+use anyhow::Context;
+use rust_xlsxwriter::{Format, Workbook};
+use std::path::Path;
+
+/// Maximum number of significant digits Excel will store exactly
+const MAX_DIGITS: usize = 15;
+
+/// A single cell of a sheet
+#[derive(Clone, PartialEq, Debug)]
+pub enum Cell {
+    Number(f64),
+    Text(String),
+}
+
+impl Cell {
+    /// Determines the type of a cell from its textual content
+    pub fn from_field(field: &str) -> Cell {
+        let digits = field.strip_prefix('-').unwrap_or(field);
+        let (int, frac) = match digits.split_once('.') {
+            Some((int, frac)) => (int, frac),
+            None => (digits, ""),
+        };
+        let is_number = !int.is_empty()
+            && int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+            && (digits.contains('.') != frac.is_empty())
+            && (int == "0" || !int.starts_with('0'))
+            && int.len() + frac.len() <= MAX_DIGITS;
+        match field.parse() {
+            Ok(n) if is_number => Cell::Number(n),
+            _ => Cell::Text(field.to_owned()),
+        }
+    }
+}
+
+/// Splits a line of CSV into cells, removing any quotes
+pub fn csv_row(line: &str) -> Vec<Cell> {
+    crate::csv::split_line(line)
+        .iter()
+        .map(|field| Cell::from_field(field))
+        .collect()
+}
+
+/// Splits a "label: value" line of text into cells, removing indentation
+pub fn summary_row(line: &str) -> Vec<Cell> {
+    match line.trim().split_once(": ") {
+        Some((label, value)) => vec![Cell::Text(label.to_owned()), Cell::from_field(value)],
+        None => vec![Cell::Text(line.trim().to_owned())],
+    }
+}
+
+/// A sheet of a workbook
+pub struct Sheet {
+    /// Name of the sheet, shown on its tab
+    pub name: String,
+    /// Whether the first row is a header, which is bolded and frozen
+    pub header: bool,
+    /// The rows of the sheet
+    pub rows: Vec<Vec<Cell>>,
+}
+
+/// Writes a workbook containing the given sheets
+pub fn write_workbook(path: &Path, sheets: &[Sheet]) -> anyhow::Result<()> {
+    let bold = Format::new().set_bold();
+    let mut workbook = Workbook::new();
+    for sheet in sheets {
+        let worksheet = workbook.add_worksheet();
+        worksheet
+            .set_name(&sheet.name)
+            .with_context(|| format!("naming sheet {}", sheet.name))?;
+        for (r, row) in sheet.rows.iter().enumerate() {
+            let r = r as u32;
+            for (c, cell) in row.iter().enumerate() {
+                let c = c as u16;
+                match *cell {
+                    Cell::Number(n) => worksheet.write_number(r, c, n),
+                    Cell::Text(ref s) if r == 0 && sheet.header => {
+                        worksheet.write_string_with_format(r, c, s, &bold)
+                    }
+                    Cell::Text(ref s) => worksheet.write_string(r, c, s),
+                }
+                .with_context(|| format!("writing cell {r},{c} of sheet {}", sheet.name))?;
+            }
+        }
+        if sheet.header {
+            worksheet
+                .set_freeze_panes(1, 0)
+                .with_context(|| format!("freezing header of sheet {}", sheet.name))?;
+        }
+        worksheet.autofit();
+    }
+    workbook
+        .save(path)
+        .with_context(|| format!("writing workbook {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_types() {
+        assert_eq!(Cell::from_field("1234.56"), Cell::Number(1234.56));
+        assert_eq!(Cell::from_field("-0.5"), Cell::Number(-0.5));
+        assert_eq!(Cell::from_field("0"), Cell::Number(0.0));
+        // Leading zeros, dates, huge integers and junk are all text
+        for text in [
+            "007",
+            "2024-01-05",
+            "2024-01-05T21:00:00Z",
+            "1234567890123456789",
+            "1.",
+            ".5",
+            "",
+            "inf",
+            "Short-term",
+        ]
+        .iter()
+        {
+            assert_eq!(Cell::from_field(text), Cell::Text(text.to_string()));
+        }
+
+        assert_eq!(
+            csv_row("\"BTC 2024-01-05 Put $40,000.00\",3,abc"),
+            vec![
+                Cell::Text("BTC 2024-01-05 Put $40,000.00".into()),
+                Cell::Number(3.0),
+                Cell::Text("abc".into()),
+            ],
+        );
+        assert_eq!(
+            summary_row("    Total LT gain/loss: 100.50"),
+            vec![Cell::Text("Total LT gain/loss".into()), Cell::Number(100.5)],
+        );
+    }
+}